@@ -289,6 +289,8 @@ pub enum SystemError {
     KVM_HVA_ERR_BAD = 519,
     /// 没有对应的ioctlcmd
     ENOIOCTLCMD = 520,
+    /// 驱动依赖的资源还没有就绪，需要稍后重新探测
+    EPROBE_DEFER = 521,
 }
 
 impl SystemError {
@@ -1,4 +1,5 @@
 use crate::driver::pci::pci_irq::TriggerMode;
+use crate::exception::IrqNumber;
 
 /// @brief 获得MSI Message Address
 /// @param processor 目标CPU ID号
@@ -6,6 +7,12 @@ use crate::driver::pci::pci_irq::TriggerMode;
 pub fn arch_msi_message_address(_processor: u16) -> u32 {
     unimplemented!("riscv64::arch_msi_message_address()")
 }
+/// @brief 从MSI Message Address中解析出目标CPU
+/// @param address MSI Message Address
+/// @return 目标CPU ID号
+pub fn arch_msi_message_processor(_address: u32) -> u16 {
+    unimplemented!("riscv64::arch_msi_message_processor()")
+}
 /// @brief 获得MSI Message Data
 /// @param vector 分配的中断向量号
 /// @param processor 目标CPU ID号
@@ -14,3 +21,16 @@ pub fn arch_msi_message_address(_processor: u16) -> u32 {
 pub fn arch_msi_message_data(_vector: u16, _processor: u16, _trigger: TriggerMode) -> u32 {
     unimplemented!("riscv64::arch_msi_message_data()")
 }
+/// @brief 从MSI Message Data中解析出触发模式
+/// @param data MSI Message Data
+/// @return 触发模式
+pub fn arch_msi_message_trigger(_data: u32) -> TriggerMode {
+    unimplemented!("riscv64::arch_msi_message_trigger()")
+}
+
+/// @brief 获得PCI设备INTx中断对应的中断号
+/// @param interrupt_line 配置空间中的Interrupt Line
+/// @return 中断号。riscv64暂不支持PCI INTx中断，返回None
+pub fn arch_pci_legacy_irq(_interrupt_line: u8) -> Option<IrqNumber> {
+    None
+}
@@ -1,10 +1,18 @@
+use super::driver::apic::ioapic::IoApic;
 use crate::driver::pci::pci_irq::TriggerMode;
+use crate::exception::IrqNumber;
 /// @brief 获得MSI Message Address
 /// @param processor 目标CPU ID号
 /// @return MSI Message Address
 pub fn arch_msi_message_address(processor: u16) -> u32 {
     0xfee00000 | ((processor as u32) << 12)
 }
+/// @brief 从MSI Message Address中解析出目标CPU
+/// @param address MSI Message Address
+/// @return 目标CPU ID号
+pub fn arch_msi_message_processor(address: u32) -> u16 {
+    ((address >> 12) & 0xff) as u16
+}
 /// @brief 获得MSI Message Data
 /// @param vector 分配的中断向量号
 /// @param processor 目标CPU ID号
@@ -17,3 +25,22 @@ pub fn arch_msi_message_data(vector: u16, _processor: u16, trigger: TriggerMode)
         TriggerMode::AssertLow => vector as u32 | 1 << 15,
     }
 }
+/// @brief 从MSI Message Data中解析出触发模式
+/// @param data MSI Message Data
+/// @return 触发模式
+pub fn arch_msi_message_trigger(data: u32) -> TriggerMode {
+    match (data & (1 << 15) != 0, data & (1 << 14) != 0) {
+        (false, _) => TriggerMode::EdgeTrigger,
+        (true, true) => TriggerMode::AssertHigh,
+        (true, false) => TriggerMode::AssertLow,
+    }
+}
+
+/// @brief 获得PCI设备INTx中断对应的中断号
+/// @param interrupt_line 配置空间中的Interrupt Line，对应IO APIC上的引脚号
+/// @return 中断号
+pub fn arch_pci_legacy_irq(interrupt_line: u8) -> Option<IrqNumber> {
+    Some(IrqNumber::new(
+        IoApic::VECTOR_BASE as u32 + interrupt_line as u32,
+    ))
+}
@@ -0,0 +1,72 @@
+use alloc::{
+    string::ToString,
+    sync::{Arc, Weak},
+};
+use system_error::SystemError;
+use unified_init::macros::unified_init;
+
+use crate::{
+    driver::base::{
+        class::{class_manager, Class},
+        device::sys_dev_block_kset,
+        kobject::KObject,
+        subsys::SubSysPrivate,
+    },
+    init::initcall::INITCALL_SUBSYS,
+};
+
+lazy_static! {
+    /// `/sys/class/block` 的 class 实例
+    ///
+    /// 实例在注册之前就已经存在，早于class注册的块设备也可以引用它，class注册时再被链接到class下
+    static ref CLASS_BLOCK_INSTANCE: Arc<BlockClass> = BlockClass::new();
+}
+
+/// 获取 `/sys/class/block` 的 class 实例
+#[inline(always)]
+pub fn sys_class_block_instance() -> &'static Arc<BlockClass> {
+    &CLASS_BLOCK_INSTANCE
+}
+
+/// 注册`/sys/class/block`
+#[unified_init(INITCALL_SUBSYS)]
+pub fn block_class_init() -> Result<(), SystemError> {
+    class_manager().class_register(&(sys_class_block_instance().clone() as Arc<dyn Class>))
+}
+
+/// `/sys/class/block` 类
+#[derive(Debug)]
+pub struct BlockClass {
+    subsystem: SubSysPrivate,
+}
+
+impl BlockClass {
+    const NAME: &'static str = "block";
+    pub fn new() -> Arc<Self> {
+        let r = Arc::new(Self {
+            subsystem: SubSysPrivate::new(Self::NAME.to_string(), None, None, &[]),
+        });
+        r.subsystem()
+            .set_class(Some(Arc::downgrade(&r) as Weak<dyn Class>));
+
+        return r;
+    }
+}
+
+impl Class for BlockClass {
+    fn name(&self) -> &'static str {
+        return Self::NAME;
+    }
+
+    fn dev_kobj(&self) -> Option<Arc<dyn KObject>> {
+        Some(sys_dev_block_kset() as Arc<dyn KObject>)
+    }
+
+    fn set_dev_kobj(&self, _kobj: Arc<dyn KObject>) {
+        unimplemented!("BlockClass::set_dev_kobj");
+    }
+
+    fn subsystem(&self) -> &SubSysPrivate {
+        return &self.subsystem;
+    }
+}
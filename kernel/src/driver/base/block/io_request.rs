@@ -0,0 +1,272 @@
+//! 块设备的异步请求
+//!
+//! 驱动提交请求后立即返回一个[`BlockIoRequest`]，请求完成时（通常在中断处理函数中）
+//! 由驱动调用[`BlockIoRequest::complete`]。调用者可以：
+//! - 注册完成回调，在请求完成时被调用（回调可能在中断上下文中执行，不能睡眠）
+//! - 调用[`BlockIoRequest::wait`]，让当前进程睡眠直到请求完成
+//! - 调用[`BlockIoRequest::is_done`]轮询请求的状态
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{boxed::Box, sync::Arc};
+use system_error::SystemError;
+
+use crate::{libs::spinlock::SpinLock, sched::completion::Completion, time::Duration};
+
+use super::block_device::BlockId;
+
+/// 请求完成时的回调，参数为请求的结果
+pub type BlockIoCallback = Box<dyn FnOnce(Result<(), SystemError>) + Send>;
+
+/// # 结构功能
+/// 设备的请求队列已满时，提交者的行为
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockIoQueueFull {
+    /// 立即返回SystemError::EAGAIN_OR_EWOULDBLOCK，请求没有被提交
+    NoWait,
+    /// 睡眠直到队列中有请求完成。参数为最多等待的时间，None表示一直等待，
+    /// 超时返回SystemError::ETIMEDOUT
+    Wait(Option<Duration>),
+}
+
+/// # 结构功能
+/// 一个已经提交给设备的块设备请求
+///
+/// 驱动可能把一个请求拆分成多个设备请求（部分），所有部分都完成之后，整个请求才算完成，
+/// 结果为第一个失败部分的错误
+pub struct BlockIoRequest {
+    /// 尚未完成的部分的数量
+    pending: AtomicUsize,
+    /// 第一个失败部分的错误
+    error: SpinLock<Option<SystemError>>,
+    /// 整个请求的结果，所有部分都完成之后才会被设置
+    result: SpinLock<Option<Result<(), SystemError>>>,
+    callback: SpinLock<Option<BlockIoCallback>>,
+    completion: Completion,
+}
+
+impl core::fmt::Debug for BlockIoRequest {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BlockIoRequest")
+            .field("pending", &self.pending.load(Ordering::SeqCst))
+            .field("result", &*self.result.lock_irqsave())
+            .finish()
+    }
+}
+
+#[allow(dead_code)]
+impl BlockIoRequest {
+    /// # 函数的功能
+    /// 创建一个由`parts`个部分组成的请求
+    ///
+    /// ## 参数
+    /// - `parts`: 请求被拆分成的部分的数量，至少为1
+    /// - `callback`: 整个请求完成时调用的回调
+    pub fn new(parts: usize, callback: Option<BlockIoCallback>) -> Arc<Self> {
+        Arc::new(Self {
+            pending: AtomicUsize::new(parts.max(1)),
+            error: SpinLock::new(None),
+            result: SpinLock::new(None),
+            callback: SpinLock::new(callback),
+            completion: Completion::new(),
+        })
+    }
+
+    /// # 函数的功能
+    /// 结束请求的一个部分
+    ///
+    /// 由驱动在设备完成请求（或者请求无法提交）时调用，可以在中断上下文中调用。
+    /// 最后一个部分完成时，设置整个请求的结果，调用完成回调并唤醒等待者
+    pub fn complete(&self, result: Result<(), SystemError>) {
+        if let Err(e) = result {
+            self.error.lock_irqsave().get_or_insert(e);
+        }
+
+        let prev = self.pending.fetch_sub(1, Ordering::SeqCst);
+        if prev != 1 {
+            // prev为0说明驱动多结束了一次，这里保持请求的最终结果不变
+            if prev == 0 {
+                self.pending.store(0, Ordering::SeqCst);
+            }
+            return;
+        }
+
+        let result = match self.error.lock_irqsave().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+        *self.result.lock_irqsave() = Some(result.clone());
+        // 回调在锁外执行，回调中可以再次访问当前请求
+        let callback = self.callback.lock_irqsave().take();
+        if let Some(callback) = callback {
+            callback(result);
+        }
+        self.completion.complete_all();
+    }
+
+    /// 整个请求是否已经完成
+    pub fn is_done(&self) -> bool {
+        self.result.lock_irqsave().is_some()
+    }
+
+    /// 整个请求的结果，请求尚未完成时返回None
+    pub fn result(&self) -> Option<Result<(), SystemError>> {
+        self.result.lock_irqsave().clone()
+    }
+
+    /// # 函数的功能
+    /// 睡眠等待请求完成，并返回请求的结果
+    ///
+    /// 只能在可以睡眠的上下文中调用。依赖轮询来回收请求的驱动应当使用[`BlockDeviceAsyncIo::wait`]
+    pub fn wait(&self) -> Result<(), SystemError> {
+        while !self.is_done() {
+            self.completion.wait_for_completion()?;
+        }
+        self.result().unwrap()
+    }
+}
+
+/// # trait功能
+/// 支持异步提交请求的块设备
+///
+/// 与`BlockDevice::read_at_sync`/`write_at_sync`不同，这里提交请求后立即返回，
+/// 调度器可以在请求处理期间运行其他任务
+pub trait BlockDeviceAsyncIo: Send + Sync {
+    /// # 函数的功能
+    /// 提交一个读请求
+    ///
+    /// ## 参数
+    /// - `lba_id_start`: 起始块号
+    /// - `count`: 块的数量
+    /// - `buf`: 数据缓冲区，长度至少为`count`个块
+    /// - `callback`: 请求完成时调用的回调，可能在中断上下文中执行
+    ///
+    /// ## Safety
+    ///
+    /// 调用者需要保证`buf`在请求完成之前一直有效，并且在此期间不会被其他人访问
+    unsafe fn submit_read(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &mut [u8],
+        callback: Option<BlockIoCallback>,
+    ) -> Result<Arc<BlockIoRequest>, SystemError>;
+
+    /// # 函数的功能
+    /// 提交一个写请求
+    ///
+    /// ## Safety
+    ///
+    /// 调用者需要保证`buf`在请求完成之前一直有效，并且在此期间不会被修改
+    unsafe fn submit_write(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &[u8],
+        callback: Option<BlockIoCallback>,
+    ) -> Result<Arc<BlockIoRequest>, SystemError>;
+
+    /// # 函数的功能
+    /// 提交一个读请求，设备的请求队列已满时按照`full`处理
+    ///
+    /// 默认实现与[`Self::submit_read`]相同，适用于请求队列没有上限的设备
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): 队列已满，并且`full`为[`BlockIoQueueFull::NoWait`]
+    /// - Err(SystemError::ETIMEDOUT): 等待队列空间超时
+    ///
+    /// ## Safety
+    ///
+    /// 与[`Self::submit_read`]相同
+    #[allow(dead_code)]
+    unsafe fn submit_read_with(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &mut [u8],
+        callback: Option<BlockIoCallback>,
+        _full: BlockIoQueueFull,
+    ) -> Result<Arc<BlockIoRequest>, SystemError> {
+        self.submit_read(lba_id_start, count, buf, callback)
+    }
+
+    /// # 函数的功能
+    /// 提交一个写请求，设备的请求队列已满时按照`full`处理，见[`Self::submit_read_with`]
+    ///
+    /// ## Safety
+    ///
+    /// 与[`Self::submit_write`]相同
+    #[allow(dead_code)]
+    unsafe fn submit_write_with(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &[u8],
+        callback: Option<BlockIoCallback>,
+        _full: BlockIoQueueFull,
+    ) -> Result<Arc<BlockIoRequest>, SystemError> {
+        self.submit_write(lba_id_start, count, buf, callback)
+    }
+
+    /// # 函数的功能
+    /// 阻塞等待请求完成
+    ///
+    /// 默认让当前进程睡眠直到请求完成。没有中断可用的设备需要重写此方法，在等待期间轮询设备
+    fn wait(&self, request: &BlockIoRequest) -> Result<(), SystemError> {
+        request.wait()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn recording_callback(
+        log: &Arc<SpinLock<Vec<Result<(), SystemError>>>>,
+    ) -> Option<BlockIoCallback> {
+        let log = log.clone();
+        Some(Box::new(move |r| log.lock_irqsave().push(r)))
+    }
+
+    #[test]
+    fn test_single_part() {
+        let log = Arc::new(SpinLock::new(Vec::new()));
+        let req = BlockIoRequest::new(1, recording_callback(&log));
+        assert!(!req.is_done());
+        assert_eq!(req.result(), None);
+
+        // 模拟中断处理函数结束请求
+        req.complete(Ok(()));
+        assert!(req.is_done());
+        assert_eq!(req.result(), Some(Ok(())));
+        assert_eq!(*log.lock_irqsave(), [Ok(())]);
+        assert_eq!(req.wait(), Ok(()));
+    }
+
+    #[test]
+    fn test_parts_report_first_error() {
+        let log = Arc::new(SpinLock::new(Vec::new()));
+        let req = BlockIoRequest::new(3, recording_callback(&log));
+        req.complete(Ok(()));
+        req.complete(Err(SystemError::EIO));
+        assert!(!req.is_done());
+        assert!(log.lock_irqsave().is_empty());
+
+        req.complete(Err(SystemError::ENODEV));
+        assert_eq!(req.result(), Some(Err(SystemError::EIO)));
+        assert_eq!(*log.lock_irqsave(), [Err(SystemError::EIO)]);
+    }
+
+    #[test]
+    fn test_extra_completion_is_ignored() {
+        let log = Arc::new(SpinLock::new(Vec::new()));
+        let req = BlockIoRequest::new(1, recording_callback(&log));
+        req.complete(Ok(()));
+        req.complete(Err(SystemError::EIO));
+        assert_eq!(req.result(), Some(Ok(())));
+        assert_eq!(log.lock_irqsave().len(), 1);
+    }
+}
@@ -137,8 +137,9 @@ impl BlockDevManager {
     pub fn unregister(&self, dev: &Arc<dyn BlockDevice>) {
         let mut inner = self.inner();
         inner.disks.remove(dev.dev_name());
-        // todo: 这里应该callback一下磁盘设备，但是现在还没实现热插拔，所以暂时没做这里
-        todo!("BlockDevManager: unregister disk")
+        drop(inner);
+        // 磁盘已经不可用，丢弃它的所有gendisk
+        dev.blkdev_meta().inner().gendisks.clear();
     }
 
     /// 通过路径查找gendisk
@@ -1,6 +1,8 @@
 pub mod block_device;
+pub mod class;
 pub mod disk_info;
 pub mod gendisk;
+pub mod io_request;
 pub mod manager;
 
 #[derive(Debug)]
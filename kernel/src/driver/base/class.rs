@@ -1,14 +1,18 @@
-use alloc::{string::ToString, sync::Arc};
+use alloc::{string::ToString, sync::Arc, vec::Vec};
 
 use core::fmt::Debug;
+use log::warn;
 
 use super::{
-    device::{sys_dev_char_kset, Device, DeviceMatchName, DeviceMatcher},
+    device::{device_manager, sys_dev_char_kset, Device, DeviceMatchName, DeviceMatcher},
     kobject::{KObjType, KObject},
     kset::KSet,
     subsys::SubSysPrivate,
 };
-use crate::filesystem::sysfs::{sysfs_instance, Attribute, AttributeGroup, SysFSOps};
+use crate::{
+    filesystem::sysfs::{sysfs_instance, Attribute, AttributeGroup, SysFSOps},
+    libs::spinlock::SpinLock,
+};
 use system_error::SystemError;
 
 /// `/sys/class`的kset
@@ -103,6 +107,9 @@ impl dyn Class {
     }
 }
 
+/// 添加时所属的class还没有注册的设备，等到class注册之后再链接到`/sys/class/<class>`下
+static CLASS_DEFERRED_DEVICES: SpinLock<Vec<Arc<dyn Device>>> = SpinLock::new(Vec::new());
+
 #[inline(always)]
 pub fn class_manager() -> &'static ClassManager {
     return &ClassManager;
@@ -134,9 +141,60 @@ impl ClassManager {
 
         sysfs_instance().create_groups(&(subsys as Arc<dyn KObject>), class.class_groups())?;
 
+        self.link_deferred_devices(class);
+
         return Ok(());
     }
 
+    /// 设备类是否已经注册到`/sys/class`下
+    pub fn class_registered(&self, class: &Arc<dyn Class>) -> bool {
+        class.subsystem().subsys().inode().is_some()
+    }
+
+    /// 记录一个所属的class还没有注册的设备，class注册时再把它链接到class下
+    pub(super) fn defer_device(&self, dev: &Arc<dyn Device>) {
+        let mut deferred = CLASS_DEFERRED_DEVICES.lock();
+        if !deferred.iter().any(|d| Arc::ptr_eq(d, dev)) {
+            deferred.push(dev.clone());
+        }
+    }
+
+    /// 设备在class注册之前就被移除时，把它从等待列表中删除
+    ///
+    /// ## 返回值
+    ///
+    /// 设备是否在等待列表中
+    pub(super) fn cancel_deferred_device(&self, dev: &Arc<dyn Device>) -> bool {
+        let mut deferred = CLASS_DEFERRED_DEVICES.lock();
+        let len = deferred.len();
+        deferred.retain(|d| !Arc::ptr_eq(d, dev));
+        return deferred.len() != len;
+    }
+
+    /// 把等待`class`注册的设备链接到class下
+    fn link_deferred_devices(&self, class: &Arc<dyn Class>) {
+        let devices: Vec<Arc<dyn Device>> = {
+            let mut deferred = CLASS_DEFERRED_DEVICES.lock();
+            let (matched, rest) = deferred.drain(..).partition(|dev| {
+                dev.class()
+                    .is_some_and(|dev_class| Arc::ptr_eq(&dev_class, class))
+            });
+            *deferred = rest;
+            matched
+        };
+
+        for dev in devices {
+            if let Err(e) = device_manager().class_add_deferred_device(&dev) {
+                warn!(
+                    "class '{}': failed to link device '{}': {:?}",
+                    class.name(),
+                    dev.name(),
+                    e
+                );
+            }
+        }
+    }
+
     /// 注销一个设备类
     #[allow(dead_code)]
     pub fn class_unregister(&self, class: &Arc<dyn Class>) {
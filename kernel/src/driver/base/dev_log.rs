@@ -0,0 +1,241 @@
+//! 设备驱动核心的日志
+//!
+//! 日志带有子系统的前缀(例如`[pci]`、`[virtio]`)以及设备的名称，并且按照调用位置限流：
+//! rescan时反复探测失败的设备不会刷屏，被丢弃的日志数量会在下一次打印时给出。
+//!
+//! ```ignore
+//! dev_warn_ratelimited!(DevLogCategory::Pci, dev, "probe failed: {:?}", e);
+//! // [pci] 0000:00:03.0: probe failed: EIO
+//! ```
+
+use core::fmt;
+
+use log::Level;
+
+use crate::{libs::spinlock::SpinLock, time::Instant};
+
+/// 限流的时间窗口(毫秒)
+pub const DEV_LOG_RATELIMIT_INTERVAL_MS: i64 = 5000;
+/// 每个时间窗口内最多打印的日志条数
+pub const DEV_LOG_RATELIMIT_BURST: usize = 10;
+
+/// 日志所属的子系统
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum DevLogCategory {
+    /// 设备驱动核心(设备与驱动的绑定、sysfs等)
+    Core,
+    Pci,
+    Virtio,
+    Platform,
+}
+
+impl DevLogCategory {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            DevLogCategory::Core => "[core]",
+            DevLogCategory::Pci => "[pci]",
+            DevLogCategory::Virtio => "[virtio]",
+            DevLogCategory::Platform => "[platform]",
+        }
+    }
+}
+
+/// # 结构功能
+/// 时间窗口限流器：每`interval_ms`毫秒内最多放行`burst`条日志
+///
+/// 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/lib/ratelimit.c#27
+#[derive(Debug)]
+pub struct RateLimitState {
+    interval_ms: i64,
+    burst: usize,
+    /// 当前时间窗口的起始时间
+    begin_ms: Option<i64>,
+    /// 当前时间窗口内已经放行的数量
+    printed: usize,
+    /// 还没有报告的被丢弃的数量
+    missed: usize,
+}
+
+impl RateLimitState {
+    pub const fn new(interval_ms: i64, burst: usize) -> Self {
+        Self {
+            interval_ms,
+            burst,
+            begin_ms: None,
+            printed: 0,
+            missed: 0,
+        }
+    }
+
+    /// # 函数的功能
+    /// 判断当前是否放行一条日志
+    ///
+    /// ## 参数
+    /// - `now_ms`: 当前时间(毫秒)
+    ///
+    /// ## 返回值
+    /// - Some(missed): 放行，`missed`为上一次放行之后被丢弃的日志数量
+    /// - None: 丢弃
+    pub fn check(&mut self, now_ms: i64) -> Option<usize> {
+        match self.begin_ms {
+            Some(begin) if now_ms - begin < self.interval_ms => {}
+            _ => {
+                self.begin_ms = Some(now_ms);
+                self.printed = 0;
+            }
+        }
+
+        if self.printed >= self.burst {
+            self.missed += 1;
+            return None;
+        }
+        self.printed += 1;
+        Some(core::mem::take(&mut self.missed))
+    }
+}
+
+/// 每个日志调用位置一个的限流器，由[`dev_log_ratelimited`]创建
+#[derive(Debug)]
+pub struct DevLogRateLimit {
+    state: SpinLock<RateLimitState>,
+}
+
+impl DevLogRateLimit {
+    pub const fn new() -> Self {
+        Self {
+            state: SpinLock::new(RateLimitState::new(
+                DEV_LOG_RATELIMIT_INTERVAL_MS,
+                DEV_LOG_RATELIMIT_BURST,
+            )),
+        }
+    }
+}
+
+impl Default for DevLogRateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[doc(hidden)]
+pub fn __dev_log(
+    ratelimit: &DevLogRateLimit,
+    level: Level,
+    category: DevLogCategory,
+    name: &str,
+    args: fmt::Arguments,
+) {
+    // 不会输出的日志不占用限流的配额
+    if level > log::max_level() {
+        return;
+    }
+    // 中断上下文中也可能打印日志
+    let r = ratelimit
+        .state
+        .lock_irqsave()
+        .check(Instant::now().total_millis());
+    let Some(missed) = r else {
+        return;
+    };
+
+    if missed > 0 {
+        log::log!(
+            level,
+            "{} {}: {} similar message(s) suppressed",
+            category.prefix(),
+            name,
+            missed
+        );
+    }
+    log::log!(level, "{} {}: {}", category.prefix(), name, args);
+}
+
+/// # 宏的功能
+/// 打印一条限流的设备日志，自动带上子系统前缀以及设备名称
+///
+/// ## 参数
+/// - `level`: [`log::Level`]
+/// - `category`: [`DevLogCategory`]
+/// - `dev`: 实现了`KObject`的设备，例如`&Arc<dyn Device>`
+/// - 之后是格式化字符串以及参数
+#[macro_export]
+macro_rules! dev_log_ratelimited {
+    ($level:expr, $category:expr, $dev:expr, $($arg:tt)+) => {{
+        #[allow(unused_imports)]
+        use $crate::driver::base::kobject::KObject as _;
+        static __DEV_LOG_RATELIMIT: $crate::driver::base::dev_log::DevLogRateLimit =
+            $crate::driver::base::dev_log::DevLogRateLimit::new();
+        $crate::driver::base::dev_log::__dev_log(
+            &__DEV_LOG_RATELIMIT,
+            $level,
+            $category,
+            &$dev.name(),
+            format_args!($($arg)+),
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! dev_err_ratelimited {
+    ($category:expr, $dev:expr, $($arg:tt)+) => {
+        $crate::dev_log_ratelimited!(log::Level::Error, $category, $dev, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! dev_warn_ratelimited {
+    ($category:expr, $dev:expr, $($arg:tt)+) => {
+        $crate::dev_log_ratelimited!(log::Level::Warn, $category, $dev, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! dev_info_ratelimited {
+    ($category:expr, $dev:expr, $($arg:tt)+) => {
+        $crate::dev_log_ratelimited!(log::Level::Info, $category, $dev, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! dev_debug_ratelimited {
+    ($category:expr, $dev:expr, $($arg:tt)+) => {
+        $crate::dev_log_ratelimited!(log::Level::Debug, $category, $dev, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_suppress() {
+        let mut rl = RateLimitState::new(1000, 3);
+        for t in 0..3 {
+            assert_eq!(rl.check(t), Some(0));
+        }
+        for t in 3..10 {
+            assert_eq!(rl.check(t), None);
+        }
+        // 新的时间窗口，报告被丢弃的数量
+        assert_eq!(rl.check(1000), Some(7));
+        assert_eq!(rl.check(1001), Some(0));
+        assert_eq!(rl.check(1002), Some(0));
+        assert_eq!(rl.check(1003), None);
+        assert_eq!(rl.check(2003), Some(1));
+    }
+
+    #[test]
+    fn test_window_boundary() {
+        let mut rl = RateLimitState::new(1000, 1);
+        assert_eq!(rl.check(500), Some(0));
+        assert_eq!(rl.check(1499), None);
+        assert_eq!(rl.check(1500), Some(1));
+    }
+
+    #[test]
+    fn test_category_prefix() {
+        assert_eq!(DevLogCategory::Pci.prefix(), "[pci]");
+        assert_eq!(DevLogCategory::Virtio.prefix(), "[virtio]");
+    }
+}
@@ -12,7 +12,7 @@ use crate::{
     filesystem::{
         sysfs::{
             file::sysfs_emit_str, sysfs_instance, Attribute, AttributeGroup, SysFSOps,
-            SysFSOpsSupport, SYSFS_ATTR_MODE_RW, SYSFS_ATTR_MODE_WO,
+            SysFSOpsSupport, SYSFS_ATTR_MODE_RO, SYSFS_ATTR_MODE_RW, SYSFS_ATTR_MODE_WO,
         },
         vfs::syscall::ModeType,
     },
@@ -129,6 +129,10 @@ pub trait Bus: Debug + Send + Sync {
     /// 检查设备是否可以被总线绑定，如果可以，就绑定它们。
     /// 绑定之后,device的driver字段会被设置为驱动实例。
     ///
+    /// 探测失败时，驱动必须撤销它对设备做出的所有修改（sysfs中的文件、中断、队列等），
+    /// 让设备保持探测之前的状态，之后的驱动还会继续尝试探测这个设备。
+    /// debug构建下，设备核心会检查设备的sysfs目录是否被恢复原状
+    ///
     /// ## 参数
     ///
     /// - `device` - 设备实例
@@ -139,6 +143,10 @@ pub trait Bus: Debug + Send + Sync {
     fn probe(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
         return Err(SystemError::ENOSYS);
     }
+
+    /// 驱动的probe()失败，设备核心完成清理之后调用。
+    /// 总线可以在这里把设备恢复到初始状态(例如复位设备)，让下一个驱动拿到一个干净的设备
+    fn probe_failed(&self, _device: &Arc<dyn Device>) {}
     fn remove(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError>;
     fn sync_state(&self, _device: &Arc<dyn Device>) {}
     fn shutdown(&self, _device: &Arc<dyn Device>);
@@ -171,6 +179,19 @@ pub trait Bus: Debug + Send + Sync {
 
     fn subsystem(&self) -> &SubSysPrivate;
 
+    /// # 函数的功能
+    /// 把用户给出的设备名称(例如写入bind/unbind的内容)转换为设备在总线上的名称
+    ///
+    /// 同一个设备可能有多种写法(例如pci设备的`00:04.0`与`0000:00:04.0`)，
+    /// 总线可以在这里把它们统一为设备的名称
+    ///
+    /// ## 默认实现
+    ///
+    /// 返回原名称
+    fn canonical_device_name(&self, name: &str) -> String {
+        name.to_string()
+    }
+
     /// 对当前总线操作的时候需要获取父级总线的锁
     fn need_parent_lock(&self) -> bool {
         false
@@ -257,6 +278,10 @@ impl BusManager {
     /// - 在bus和设备文件夹下，创建软链接
     /// - 把设备添加到它的总线的设备列表中
     ///
+    /// 设备目录下的subsystem链接指向/sys/bus/<bus>。属于某个class的设备，
+    /// 其subsystem链接已经在添加class链接时指向了class，这里不再创建。
+    /// 不在任何总线上的设备没有bus的subsystem链接
+    ///
     /// 参考： https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/bus.c?fi=bus_add_device#441
     ///
     /// ## 参数
@@ -279,16 +304,54 @@ impl BusManager {
                 &dev_kobj,
                 dev.name(),
             )?;
-            sysfs_instance().create_link(
-                Some(&dev_kobj),
-                &bus.subsystem().subsys().as_kobject(),
-                "subsystem".to_string(),
-            )?;
+            if dev.class().is_none() {
+                sysfs_instance()
+                    .create_link(
+                        Some(&dev_kobj),
+                        &bus.subsystem().subsys().as_kobject(),
+                        "subsystem".to_string(),
+                    )
+                    .inspect_err(|_| {
+                        sysfs_instance().remove_link(&bus_devices_kset.as_kobject(), dev.name());
+                    })?;
+            }
             bus.subsystem().add_device_to_vec(dev)?;
         }
         return Ok(());
     }
 
+    /// 把一个设备从总线上移除
+    ///
+    /// ## 描述
+    ///
+    /// - 删除bus和设备文件夹下的软链接
+    /// - 删除设备的与bus相关的属性
+    /// - 解除设备与驱动的绑定
+    /// - 把设备从它的总线的设备列表中移除
+    ///
+    /// 参考： https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/bus.c?fi=bus_remove_device#525
+    ///
+    /// ## 参数
+    ///
+    /// - `dev` - 要被移除的设备
+    pub fn remove_device(&self, dev: &Arc<dyn Device>) {
+        let bus = dev.bus().and_then(|bus| bus.upgrade());
+        if let Some(bus) = bus {
+            let dev_kobj = dev.clone() as Arc<dyn KObject>;
+            // 属于class的设备，subsystem链接由class负责删除
+            if dev.class().is_none() {
+                sysfs_instance().remove_link(&dev_kobj, "subsystem".to_string());
+            }
+            if let Some(bus_devices_kset) = bus.subsystem().devices_kset() {
+                sysfs_instance().remove_link(&bus_devices_kset.as_kobject(), dev.name());
+            }
+            device_manager().remove_groups(dev, bus.dev_groups());
+
+            device_manager().device_release_driver(dev);
+            bus.subsystem().remove_device_from_vec(dev);
+        }
+    }
+
     /// 在总线上添加一个驱动
     ///
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/bus.c?fi=bus_add_driver#590
@@ -329,6 +392,18 @@ impl BusManager {
             })
             .ok();
 
+        driver_manager()
+            .create_attr_file(driver, &DriverAttrPriority)
+            .map_err(|e| {
+                error!(
+                    "BusManager::add_driver: driver '{:?}' add priority file failed, err: '{:?}",
+                    driver.name(),
+                    e
+                );
+                e
+            })
+            .ok();
+
         if !driver.suppress_bind_attrs() {
             self.add_bind_files(driver)
                 .map_err(|e| {
@@ -382,8 +457,33 @@ impl BusManager {
         return Ok(());
     }
 
-    pub fn unregister(&self, _bus: Arc<dyn Bus>) -> Result<(), SystemError> {
-        todo!("bus_unregister")
+    /// 注销一个总线，删除它在/sys/bus下的目录
+    ///
+    /// 总线上的设备和驱动必须已经全部移除
+    ///
+    /// 参考： https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/bus.c#899
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EBUSY): 总线上仍然有设备或者驱动
+    pub fn unregister(&self, bus: Arc<dyn Bus>) -> Result<(), SystemError> {
+        let subsystem = bus.subsystem();
+        if !subsystem.devices().is_empty() || !subsystem.drivers().is_empty() {
+            return Err(SystemError::EBUSY);
+        }
+
+        let subsys_kset = subsystem.subsys();
+        self.kset_bus_map.write().remove(&subsys_kset);
+        self.remove_groups(&bus, bus.bus_groups());
+        self.remove_probe_files(&bus);
+        if let Some(drivers_kset) = subsystem.drivers_kset() {
+            drivers_kset.unregister();
+        }
+        if let Some(devices_kset) = subsystem.devices_kset() {
+            devices_kset.unregister();
+        }
+        subsys_kset.unregister();
+        subsystem.set_bus(None);
+        return Ok(());
     }
 
     fn add_probe_files(&self, bus: &Arc<dyn Bus>) -> Result<(), SystemError> {
@@ -396,7 +496,6 @@ impl BusManager {
         return r;
     }
 
-    #[allow(dead_code)]
     fn remove_probe_files(&self, bus: &Arc<dyn Bus>) {
         self.remove_file(bus, &BusAttrDriversAutoprobe);
         self.remove_file(bus, &BusAttrDriversProbe);
@@ -426,6 +525,12 @@ impl BusManager {
         return sysfs_instance().create_groups(&bus_kobj, groups);
     }
 
+    #[inline]
+    fn remove_groups(&self, bus: &Arc<dyn Bus>, groups: &'static [&'static dyn AttributeGroup]) {
+        let bus_kobj = bus.subsystem().subsys() as Arc<dyn KObject>;
+        sysfs_instance().remove_groups(&bus_kobj, groups);
+    }
+
     /// 根据bus的kset找到bus实例
     fn get_bus_by_kset(&self, kset: &Arc<KSet>) -> Option<Arc<dyn Bus>> {
         return self.kset_bus_map.read().get(kset).cloned();
@@ -464,6 +569,97 @@ impl BusManager {
         }
     }
 
+    /// # 函数的功能
+    /// 在指定总线上注册一个驱动，并立即为总线上尚未绑定驱动的设备尝试匹配该驱动
+    ///
+    /// 匹配通过总线的[`Bus::match_device`]完成，probe通过[`Bus::probe`]完成，
+    /// 因此任何总线只要实现了这两个方法，就可以复用这里的注册流程。
+    /// 与[`BusManager::register_device`]配合使用时，驱动与设备的注册顺序不会影响它们的绑定
+    ///
+    /// ## 参数
+    /// - `bus`: 驱动所属的总线
+    /// - `driver`: 要注册的驱动
+    ///
+    /// ## 返回值
+    /// - Ok(()): 驱动注册成功（单个设备probe失败不会导致注册失败）
+    /// - Err(e): 驱动注册失败
+    pub fn register_driver(
+        &self,
+        bus: &Arc<dyn Bus>,
+        driver: Arc<dyn Driver>,
+    ) -> Result<(), SystemError> {
+        driver.set_bus(Some(Arc::downgrade(bus)));
+        driver_manager().register(driver.clone())?;
+
+        // 总线开启了自动probe时，上面的注册过程已经完成了一轮匹配，这里只处理仍未绑定的设备
+        for dev in bus.subsystem().devices().iter() {
+            if dev.driver().is_some() {
+                continue;
+            }
+            driver_manager().driver_attach_device(&driver, dev);
+        }
+        return Ok(());
+    }
+
+    /// # 函数的功能
+    /// 从指定总线上注销一个驱动
+    ///
+    /// 驱动绑定的所有设备都会先经过总线的[`Bus::remove`]解除绑定，然后删除驱动在sysfs中的目录，
+    /// 并把驱动从总线的驱动列表中移除。之后这些设备处于未绑定状态，可以重新与其他驱动绑定
+    ///
+    /// ## 返回值
+    /// - Ok(()): 注销成功
+    /// - Err(SystemError::ENODEV): 驱动没有注册在该总线上
+    pub fn unregister_driver(
+        &self,
+        bus: &Arc<dyn Bus>,
+        driver: &Arc<dyn Driver>,
+    ) -> Result<(), SystemError> {
+        let registered = bus
+            .subsystem()
+            .drivers()
+            .iter()
+            .any(|d| Arc::ptr_eq(d, driver));
+        if !registered {
+            return Err(SystemError::ENODEV);
+        }
+
+        driver_manager().unregister(driver);
+        driver.set_bus(None);
+        return Ok(());
+    }
+
+    /// # 函数的功能
+    /// 在指定总线上注册一个设备，并为它尝试所有已经注册的驱动
+    ///
+    /// 需要特殊处理（例如设置默认的父设备）的总线，可以自行添加设备，
+    /// 然后调用[`BusManager::attach_device`]
+    ///
+    /// ## 返回值
+    /// - Ok(()): 设备注册成功（没有匹配的驱动不算失败）
+    /// - Err(e): 设备注册失败
+    pub fn register_device(
+        &self,
+        bus: &Arc<dyn Bus>,
+        dev: Arc<dyn Device>,
+    ) -> Result<(), SystemError> {
+        dev.set_bus(Some(Arc::downgrade(bus)));
+        device_manager().device_default_initialize(&dev);
+        device_manager().add_device(dev.clone())?;
+        return self.attach_device(&dev);
+    }
+
+    /// # 函数的功能
+    /// 为已经添加到总线上、但尚未绑定驱动的设备尝试所有已经注册的驱动
+    ///
+    /// 总线关闭了自动probe时，添加设备的过程不会进行匹配，需要通过这里完成绑定
+    pub fn attach_device(&self, dev: &Arc<dyn Device>) -> Result<(), SystemError> {
+        if dev.driver().is_none() {
+            device_manager().device_attach(dev)?;
+        }
+        return Ok(());
+    }
+
     /// 从总线上移除一个驱动
     ///
     /// Detach the driver from the devices it controls, and remove
@@ -475,8 +671,27 @@ impl BusManager {
     /// - `driver` - 驱动实例
     ///
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/bus.c?fi=bus_remove_driver#666
-    pub fn remove_driver(&self, _driver: &Arc<dyn Driver>) {
-        todo!("BusManager::remove_driver")
+    pub fn remove_driver(&self, driver: &Arc<dyn Driver>) {
+        let bus = match driver.bus().and_then(|bus| bus.upgrade()) {
+            Some(bus) => bus,
+            None => return,
+        };
+        debug!("bus '{}' remove driver '{}'", bus.name(), driver.name());
+
+        if !driver.suppress_bind_attrs() {
+            self.remove_bind_files(driver);
+        }
+        driver_manager().remove_attr_file(driver, &DriverAttrPriority);
+        driver_manager().remove_groups(driver, bus.drv_groups());
+
+        driver_manager().driver_detach(driver);
+        bus.subsystem().remove_driver_from_vec(driver);
+        KObjectManager::remove_kobj(driver.clone() as Arc<dyn KObject>);
+    }
+
+    fn remove_bind_files(&self, driver: &Arc<dyn Driver>) {
+        driver_manager().remove_attr_file(driver, &DriverAttrBind);
+        driver_manager().remove_attr_file(driver, &DriverAttrUnbind);
     }
 
     fn add_bind_files(&self, driver: &Arc<dyn Driver>) -> Result<(), SystemError> {
@@ -524,7 +739,6 @@ pub fn bus_register(bus: Arc<dyn Bus>) -> Result<(), SystemError> {
 /// @brief: 总线注销，并在sys/bus和sys/devices下删除文件夹
 /// @parameter bus: Bus设备实体
 /// @return: 成功:()   失败:SystemError
-#[allow(dead_code)]
 pub fn bus_unregister(bus: Arc<dyn Bus>) -> Result<(), SystemError> {
     return bus_manager().unregister(bus);
 }
@@ -576,6 +790,17 @@ pub fn bus_add_device(dev: &Arc<dyn Device>) -> Result<(), SystemError> {
     return bus_manager().add_device(dev);
 }
 
+/// 把设备从总线上移除
+///
+/// ## 参数
+///
+/// - `dev` - 要被移除的设备
+///
+/// 参考： https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/bus.c?fi=bus_remove_device#525
+pub fn bus_remove_device(dev: &Arc<dyn Device>) {
+    bus_manager().remove_device(dev);
+}
+
 /// 自动为设备在总线上寻找可用的驱动程序
 ///
 /// Automatically probe for a driver if the bus allows it.
@@ -613,12 +838,8 @@ impl Attribute for BusAttrDriversProbe {
             .get_bus_by_kset(&kset)
             .ok_or(SystemError::EINVAL)?;
 
-        let name = CStr::from_bytes_with_nul(buf)
-            .map_err(|_| SystemError::EINVAL)?
-            .to_str()
-            .map_err(|_| SystemError::EINVAL)?;
-
-        let device = bus.find_device_by_name(name).ok_or(SystemError::ENODEV)?;
+        let name = bus.canonical_device_name(bus_attr_device_name(buf)?);
+        let device = bus.find_device_by_name(&name).ok_or(SystemError::ENODEV)?;
 
         if rescan_devices_helper(&device).is_ok() {
             return Ok(buf.len());
@@ -738,6 +959,24 @@ impl SysFSOps for BusDriverSysFSOps {
     }
 }
 
+/// # 函数的功能
+/// 取出写入bind/unbind等属性的设备名称
+///
+/// 内容可以以'\0'结尾(来自内核)或者不以'\0'结尾(例如`echo`写入)，首尾的空白字符会被忽略
+fn bus_attr_device_name(buf: &[u8]) -> Result<&str, SystemError> {
+    let buf = match CStr::from_bytes_until_nul(buf) {
+        Ok(s) => s.to_bytes(),
+        Err(_) => buf,
+    };
+    let name = core::str::from_utf8(buf)
+        .map_err(|_| SystemError::EINVAL)?
+        .trim();
+    if name.is_empty() {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(name)
+}
+
 #[derive(Debug)]
 struct DriverAttrUnbind;
 
@@ -764,11 +1003,8 @@ impl Attribute for DriverAttrUnbind {
             .and_then(|bus| bus.upgrade())
             .ok_or(SystemError::ENODEV)?;
 
-        let s = CStr::from_bytes_with_nul(buf)
-            .map_err(|_| SystemError::EINVAL)?
-            .to_str()
-            .map_err(|_| SystemError::EINVAL)?;
-        let dev = bus.find_device_by_name(s).ok_or(SystemError::ENODEV)?;
+        let name = bus.canonical_device_name(bus_attr_device_name(buf)?);
+        let dev = bus.find_device_by_name(&name).ok_or(SystemError::ENODEV)?;
         let p = dev.driver().ok_or(SystemError::ENODEV)?;
         if Arc::ptr_eq(&p, &driver) {
             device_manager().device_driver_detach(&dev);
@@ -782,6 +1018,36 @@ impl Attribute for DriverAttrUnbind {
     }
 }
 
+/// 驱动的探测优先级，见[`Driver::probe_priority`]
+#[derive(Debug)]
+struct DriverAttrPriority;
+
+impl Attribute for DriverAttrPriority {
+    fn name(&self) -> &str {
+        "priority"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let driver = kobj.cast::<dyn Driver>().map_err(|kobj| {
+            error!(
+                "Intertrait casting not implemented for kobj: {}",
+                kobj.name()
+            );
+            SystemError::ENOSYS
+        })?;
+
+        return sysfs_emit_str(buf, &format!("{}\n", driver.probe_priority()));
+    }
+}
+
 #[derive(Debug)]
 struct DriverAttrBind;
 
@@ -812,14 +1078,8 @@ impl Attribute for DriverAttrBind {
             .bus()
             .and_then(|bus| bus.upgrade())
             .ok_or(SystemError::ENODEV)?;
-        let device = bus
-            .find_device_by_name(
-                CStr::from_bytes_with_nul(buf)
-                    .map_err(|_| SystemError::EINVAL)?
-                    .to_str()
-                    .map_err(|_| SystemError::EINVAL)?,
-            )
-            .ok_or(SystemError::ENODEV)?;
+        let name = bus.canonical_device_name(bus_attr_device_name(buf)?);
+        let device = bus.find_device_by_name(&name).ok_or(SystemError::ENODEV)?;
 
         if driver_manager().match_device(&driver, &device)? {
             device_manager().device_driver_attach(&driver, &device)?;
@@ -882,3 +1142,18 @@ impl SubSystemManager {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bus_attr_device_name() {
+        assert_eq!(bus_attr_device_name(b"0000:00:04.0\0"), Ok("0000:00:04.0"));
+        assert_eq!(bus_attr_device_name(b"0000:00:04.0\n"), Ok("0000:00:04.0"));
+        assert_eq!(bus_attr_device_name(b"00:04.0"), Ok("00:04.0"));
+        assert_eq!(bus_attr_device_name(b"\n"), Err(SystemError::EINVAL));
+        assert_eq!(bus_attr_device_name(b""), Err(SystemError::EINVAL));
+        assert_eq!(bus_attr_device_name(&[0xff, 0]), Err(SystemError::EINVAL));
+    }
+}
@@ -1,11 +1,16 @@
 use core::intrinsics::unlikely;
 
-use alloc::{string::ToString, sync::Arc};
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
 use intertrait::cast::CastArc;
 use log::{debug, error, warn};
 
 use crate::{
-    driver::base::kobject::KObject,
+    dev_debug_ratelimited, dev_warn_ratelimited,
+    driver::base::{dev_log::DevLogCategory, kobject::KObject},
     filesystem::{
         sysfs::{
             file::sysfs_emit_str, sysfs_instance, Attribute, SysFSOpsSupport, SYSFS_ATTR_MODE_WO,
@@ -17,7 +22,8 @@ use crate::{
 use system_error::SystemError;
 
 use super::{
-    bus::BusNotifyEvent,
+    bus::{Bus, BusNotifyEvent},
+    deferred_probe::deferred_probe_manager,
     device_manager,
     driver::{driver_manager, Driver, DriverManager},
     Device, DeviceManager,
@@ -28,7 +34,7 @@ static PROBE_WAIT_QUEUE: WaitQueue = WaitQueue::default();
 impl DeviceManager {
     /// 尝试把一个设备与一个驱动匹配
     ///
-    /// 当前函数会遍历整个bus的驱动列表，并且尝试把设备与每一个驱动进行匹配。
+    /// 当前函数会按照探测优先级从高到低的顺序遍历整个bus的驱动列表，并且尝试把设备与每一个驱动进行匹配。
     /// 一旦有一个驱动匹配成功，就会返回。
     ///
     /// ## 参数
@@ -66,7 +72,7 @@ impl DeviceManager {
             return Ok(false);
         }
 
-        warn!("do_device_attach: dev: '{}'", dev.name());
+        dev_debug_ratelimited!(DevLogCategory::Core, dev, "attaching");
 
         let mut do_async = false;
         let mut r = Ok(false);
@@ -94,7 +100,7 @@ impl DeviceManager {
                 .ok_or(SystemError::EINVAL)?;
             let mut data = DeviceAttachData::new(dev.clone(), allow_async, false);
             let mut flag = false;
-            for driver in bus.subsystem().drivers().iter() {
+            for driver in drivers_by_priority(&bus).iter() {
                 let r = self.do_device_attach_driver(driver, &mut data);
                 if unlikely(r.is_err()) {
                     flag = false;
@@ -172,9 +178,12 @@ impl DeviceManager {
             return Ok(false);
         }
 
-        return driver_manager()
-            .probe_device(driver, &data.dev)
-            .map(|_| true);
+        match driver_manager().probe_device(driver, &data.dev) {
+            Ok(_) => Ok(true),
+            // 驱动探测失败，设备已经被恢复原状，让下一个驱动继续尝试
+            Err(_) if data.dev.driver().is_none() && data.dev.is_registered() => Ok(false),
+            Err(e) => Err(e),
+        }
     }
 
     /// 检查设备是否绑定到驱动程序
@@ -226,6 +235,54 @@ impl DeviceManager {
         return r;
     }
 
+    /// 解除设备与其驱动的绑定
+    ///
+    /// 会删除绑定时在sysfs中创建的符号链接和属性文件，并通知总线上的监听者。
+    /// 如果设备没有绑定驱动，则什么也不做。
+    ///
+    /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c#1196
+    pub fn device_release_driver(&self, dev: &Arc<dyn Device>) {
+        let driver = match dev.driver() {
+            Some(driver) => driver,
+            None => return,
+        };
+
+        let bus = dev.bus().and_then(|bus| bus.upgrade());
+        if let Some(bus) = bus.as_ref() {
+            bus.subsystem().bus_notifier().call_chain(
+                BusNotifyEvent::UnbindDriver,
+                Some(dev),
+                None,
+            );
+        }
+
+        self.remove_file(dev, &DeviceAttrStateSynced);
+        self.remove_groups(dev, driver.dev_groups());
+        if let Some(bus) = bus.as_ref() {
+            if let Err(e) = bus.remove(dev) {
+                warn!(
+                    "device_release_driver: bus.remove() failed, dev: '{}', err: {:?}",
+                    dev.name(),
+                    e
+                );
+            }
+        }
+
+        driver_manager().remove_from_sysfs(dev);
+        driver.delete_device(dev);
+        self.unbind_cleanup(dev);
+
+        if let Some(bus) = bus.as_ref() {
+            bus.subsystem().bus_notifier().call_chain(
+                BusNotifyEvent::UnboundDriver,
+                Some(dev),
+                None,
+            );
+        }
+
+        // todo: 发送kobj unbind的uevent
+    }
+
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c?fi=driver_attach#528
     fn unbind_cleanup(&self, dev: &Arc<dyn Device>) {
         dev.set_driver(None);
@@ -233,6 +290,14 @@ impl DeviceManager {
     }
 }
 
+/// 设备的sysfs目录中的文件，设备还没有加入sysfs时为空
+fn device_sysfs_entries(device: &Arc<dyn Device>) -> Vec<String> {
+    device
+        .inode()
+        .map(|inode| inode.child_names())
+        .unwrap_or_default()
+}
+
 /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c#866
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -297,6 +362,43 @@ impl DriverManager {
         return Ok(());
     }
 
+    /// 尝试把单个设备绑定到指定的驱动上
+    ///
+    /// ## 返回值
+    ///
+    /// - true: 设备与驱动匹配，并且probe成功
+    /// - false: 不匹配，或者probe失败
+    pub fn driver_attach_device(&self, driver: &Arc<dyn Driver>, device: &Arc<dyn Device>) -> bool {
+        return self.do_driver_attach(device, driver);
+    }
+
+    /// 解除驱动与它所绑定的全部设备的绑定
+    ///
+    /// 每个设备都会经过总线的remove()，之后设备不再持有指向该驱动的引用，可以重新与其他驱动绑定
+    ///
+    /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c#1290
+    pub fn driver_detach(&self, driver: &Arc<dyn Driver>) {
+        let mut devices = driver.devices();
+        // 设备的driver字段指向该驱动，但不在驱动的设备列表中的情况，也需要处理
+        if let Some(bus) = driver.bus().and_then(|bus| bus.upgrade()) {
+            for dev in bus.subsystem().devices().iter() {
+                let bound = dev.driver().is_some_and(|d| Arc::ptr_eq(&d, driver));
+                if bound && !devices.iter().any(|d| Arc::ptr_eq(d, dev)) {
+                    devices.push(dev.clone());
+                }
+            }
+        }
+
+        for dev in devices.iter() {
+            if dev.driver().is_some_and(|d| Arc::ptr_eq(&d, driver)) {
+                device_manager().device_release_driver(dev);
+            } else {
+                // 设备已经绑定到其他驱动，只需要从当前驱动的列表中删除
+                driver.delete_device(dev);
+            }
+        }
+    }
+
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c?fi=driver_attach#1134
     #[inline(never)]
     fn do_driver_attach(&self, device: &Arc<dyn Device>, driver: &Arc<dyn Driver>) -> bool {
@@ -396,8 +498,24 @@ impl DriverManager {
             self.remove_from_sysfs(device);
         };
 
+        // 驱动已经probe成功，需要让驱动释放设备，而不是把设备从设备模型中删除
         let dev_groups_failed = || {
-            device_manager().remove(device);
+            if let Some(bus) = device.bus().and_then(|bus| bus.upgrade()) {
+                if let Err(e) = bus.remove(device) {
+                    warn!(
+                        "really_probe: bus.remove() failed, dev: '{}', err: {:?}",
+                        device.name(),
+                        e
+                    );
+                }
+            }
+        };
+
+        // 用于在探测失败之后检查驱动是否把设备恢复原状
+        let sysfs_before = if cfg!(debug_assertions) {
+            device_sysfs_entries(device)
+        } else {
+            Vec::new()
         };
 
         device.set_driver(Some(Arc::downgrade(driver)));
@@ -414,15 +532,22 @@ impl DriverManager {
         })?;
 
         self.call_driver_probe(device, driver).map_err(|e| {
-            error!(
-                "really_probe: call_driver_probe failed, dev: '{}', err: {:?}",
-                device.name(),
+            // rescan时会反复探测失败的设备，需要限流
+            dev_warn_ratelimited!(
+                DevLogCategory::Core,
+                device,
+                "probe with driver '{}' failed: {:?}",
+                driver.name(),
                 e
             );
 
             probe_failed();
             sysfs_failed();
             bind_failed();
+            self.probe_rollback(device, driver, &sysfs_before);
+            if e == SystemError::EPROBE_DEFER {
+                deferred_probe_manager().add(device, &driver.name(), e);
+            }
             e
         })?;
 
@@ -450,6 +575,7 @@ impl DriverManager {
                     device.name(),
                     e
                 );
+                device_manager().remove_groups(device, driver.dev_groups());
                 dev_groups_failed();
                 probe_failed();
                 sysfs_failed();
@@ -462,6 +588,42 @@ impl DriverManager {
         return Ok(());
     }
 
+    /// 驱动探测失败、设备核心完成清理之后，让总线把设备恢复到初始状态，
+    /// 并在debug构建下检查设备是否已经恢复原状，以便下一个驱动继续尝试
+    ///
+    /// ## 参数
+    ///
+    /// - `sysfs_before`: 探测之前设备的sysfs目录中的文件
+    fn probe_rollback(
+        &self,
+        device: &Arc<dyn Device>,
+        driver: &Arc<dyn Driver>,
+        sysfs_before: &[String],
+    ) {
+        if let Some(bus) = device.bus().and_then(|bus| bus.upgrade()) {
+            bus.probe_failed(device);
+        }
+
+        debug_assert!(
+            device.driver().is_none(),
+            "device '{}' is still bound after failed probe",
+            device.name()
+        );
+        debug_assert!(
+            !driver.devices().iter().any(|d| Arc::ptr_eq(d, device)),
+            "driver '{}' kept device '{}' after failed probe",
+            driver.name(),
+            device.name()
+        );
+        debug_assert_eq!(
+            sysfs_before,
+            device_sysfs_entries(device).as_slice(),
+            "driver '{}' did not undo its changes to device '{}' after failed probe",
+            driver.name(),
+            device.name()
+        );
+    }
+
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c?fi=driver_attach#434
     fn add_to_sysfs(&self, device: &Arc<dyn Device>) -> Result<(), SystemError> {
         let driver = device.driver().ok_or(SystemError::EINVAL)?;
@@ -500,8 +662,15 @@ impl DriverManager {
     }
 
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c?fi=driver_attach#469
-    fn remove_from_sysfs(&self, _device: &Arc<dyn Device>) {
-        todo!("remove_from_sysfs")
+    fn remove_from_sysfs(&self, device: &Arc<dyn Device>) {
+        if let Some(driver) = device.driver() {
+            let driver_kobj = driver as Arc<dyn KObject>;
+            let device_kobj = device.clone() as Arc<dyn KObject>;
+
+            sysfs_instance().remove_link(&driver_kobj, device.name());
+            sysfs_instance().remove_link(&device_kobj, "driver".to_string());
+            device_manager().remove_file(device, &DeviceAttrCoredump);
+        }
     }
 
     fn call_driver_probe(
@@ -538,6 +707,14 @@ impl DriverManager {
                 );
             }
 
+            SystemError::EPROBE_DEFER => {
+                debug!(
+                    "driver'{}': probe of {} deferred",
+                    driver.name(),
+                    device.name()
+                );
+            }
+
             _ => {
                 warn!(
                     "driver'{}': probe of {} failed with error {:?}",
@@ -570,6 +747,9 @@ impl DriverManager {
             );
         }
 
+        // 这个设备可能是其他设备等待的依赖
+        deferred_probe_manager().trigger();
+
         // todo: 发送kobj bind的uevent
     }
 
@@ -648,3 +828,14 @@ impl Attribute for DeviceAttrCoredump {
         return Ok(buf.len());
     }
 }
+
+/// # 函数的功能
+/// 按照探测优先级从高到低的顺序，取得总线上的驱动列表
+///
+/// 优先级相同的驱动保持注册的顺序。返回的是列表的副本，探测驱动时不持有总线的驱动列表锁
+fn drivers_by_priority(bus: &Arc<dyn Bus>) -> Vec<Arc<dyn Driver>> {
+    let mut drivers: Vec<Arc<dyn Driver>> = bus.subsystem().drivers().clone();
+    // sort_by_key是稳定排序
+    drivers.sort_by_key(|driver| core::cmp::Reverse(driver.probe_priority()));
+    drivers
+}
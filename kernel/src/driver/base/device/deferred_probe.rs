@@ -0,0 +1,394 @@
+//! 延迟探测(deferred probe)
+//!
+//! 驱动的probe()返回[`SystemError::EPROBE_DEFER`]表示它依赖的资源(例如另一个设备)还没有就绪。
+//! 这样的设备会被放入等待列表，由内核线程`deferred_probe`重新尝试匹配：
+//! 任何设备成功绑定驱动时会触发一次重试，此外定时器按照逐渐增大的间隔触发重试。
+//!
+//! 从第一次被延迟开始，超过`deferred_probe_timeout`秒(默认10秒)仍然没有绑定的设备不再重试，
+//! 日志中会报告这些设备以及它们最后一次探测的错误，启动过程不会因为缺失的依赖一直等待。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c#54
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use log::{debug, warn};
+use system_error::SystemError;
+use unified_init::macros::unified_init;
+
+use crate::{
+    init::{cmdline::KernelCmdlineParameter, initcall::INITCALL_LATE},
+    libs::{mutex::Mutex, spinlock::SpinLock, wait_queue::WaitQueue},
+    process::kthread::{KernelThreadClosure, KernelThreadMechanism},
+    time::{
+        timer::{next_n_ms_timer_jiffies, Timer, TimerFunction},
+        Duration, Instant,
+    },
+};
+
+use super::{device_manager, Device};
+
+/// 放弃重试之前等待的秒数，可以通过内核命令行参数`deferred_probe_timeout`覆盖
+const DEFERRED_PROBE_DEFAULT_TIMEOUT_SECS: u64 = 10;
+kernel_cmdline_param_kv!(DEFERRED_PROBE_TIMEOUT_PARAM, deferred_probe_timeout, "");
+/// 第一次定时重试之前等待的毫秒数，之后每次翻倍
+const DEFERRED_PROBE_INITIAL_DELAY_MS: u64 = 100;
+/// 定时重试的最大间隔(毫秒)
+const DEFERRED_PROBE_MAX_DELAY_MS: u64 = 2000;
+
+lazy_static! {
+    static ref DEFERRED_PROBE: DeferredProbeManager = DeferredProbeManager::new();
+}
+
+#[inline(always)]
+pub fn deferred_probe_manager() -> &'static DeferredProbeManager {
+    &DEFERRED_PROBE
+}
+
+/// # 结构功能
+/// 重试的间隔以及放弃重试的时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DeferredProbePolicy {
+    timeout: Duration,
+}
+
+impl DeferredProbePolicy {
+    fn from_cmdline() -> Self {
+        let secs = DEFERRED_PROBE_TIMEOUT_PARAM
+            .value_str()
+            .filter(|s| !s.is_empty())
+            .and_then(|value| {
+                let r = value.parse::<u64>().ok();
+                if r.is_none() {
+                    warn!(
+                        "deferred probe: ignoring invalid {}={}",
+                        DEFERRED_PROBE_TIMEOUT_PARAM.name(),
+                        value
+                    );
+                }
+                r
+            })
+            .unwrap_or(DEFERRED_PROBE_DEFAULT_TIMEOUT_SECS);
+        Self {
+            timeout: Duration::from_secs(secs),
+        }
+    }
+
+    /// 第`attempt`次定时重试之前等待的时间
+    fn delay(&self, attempt: u32) -> Duration {
+        let ms = DEFERRED_PROBE_INITIAL_DELAY_MS
+            .checked_shl(attempt)
+            .unwrap_or(u64::MAX)
+            .min(DEFERRED_PROBE_MAX_DELAY_MS);
+        Duration::from_millis(ms)
+    }
+
+    /// 从`since`开始被延迟的设备，到`now`时是否应当放弃
+    fn expired(&self, since: Instant, now: Instant) -> bool {
+        now >= since + self.timeout
+    }
+}
+
+/// 等待重试的设备
+struct DeferredProbeEntry {
+    dev: Arc<dyn Device>,
+    /// 第一次被延迟的时间
+    since: Instant,
+    /// 最后一次要求延迟的驱动
+    driver: String,
+    error: SystemError,
+    /// 最后一次被延迟时的重试轮次
+    pass: u64,
+}
+
+/// # 结构功能
+/// 放弃重试时，一个没有绑定驱动的设备的报告
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeferredProbeReport {
+    pub device: String,
+    /// 最后一次探测该设备的驱动
+    pub driver: String,
+    /// 最后一次探测的错误
+    pub error: SystemError,
+}
+
+struct InnerDeferredProbe {
+    pending: Vec<DeferredProbeEntry>,
+    never_bound: Vec<DeferredProbeReport>,
+    /// 已经开始的重试轮次
+    pass: u64,
+    /// 连续定时重试的次数，决定下一次重试的间隔
+    attempts: u32,
+    timer_armed: bool,
+    /// 需要进行一次重试
+    kicked: bool,
+}
+
+/// # 结构功能
+/// 管理延迟探测的设备
+pub struct DeferredProbeManager {
+    inner: SpinLock<InnerDeferredProbe>,
+    policy: DeferredProbePolicy,
+    /// 重试的过程中持有，同一时刻只进行一轮重试
+    retrying: Mutex<()>,
+    wait_queue: WaitQueue,
+}
+
+impl DeferredProbeManager {
+    fn new() -> Self {
+        Self {
+            inner: SpinLock::new(InnerDeferredProbe {
+                pending: Vec::new(),
+                never_bound: Vec::new(),
+                pass: 0,
+                attempts: 0,
+                timer_armed: false,
+                kicked: false,
+            }),
+            policy: DeferredProbePolicy::from_cmdline(),
+            retrying: Mutex::new(()),
+            wait_queue: WaitQueue::default(),
+        }
+    }
+
+    /// # 函数的功能
+    /// 驱动要求延迟探测时，把设备加入等待列表。已经在列表中的设备只更新最后一次的错误
+    pub(super) fn add(&self, dev: &Arc<dyn Device>, driver: &str, error: SystemError) {
+        let arm = {
+            let mut inner = self.inner.lock_irqsave();
+            let pass = inner.pass;
+            match inner.pending.iter_mut().find(|e| Arc::ptr_eq(&e.dev, dev)) {
+                Some(entry) => {
+                    entry.driver = driver.to_string();
+                    entry.error = error;
+                    entry.pass = pass;
+                }
+                None => {
+                    debug!(
+                        "deferred probe: device '{}' deferred by driver '{}'",
+                        dev.name(),
+                        driver
+                    );
+                    inner.pending.push(DeferredProbeEntry {
+                        dev: dev.clone(),
+                        since: Instant::now(),
+                        driver: driver.to_string(),
+                        error,
+                        pass,
+                    });
+                }
+            }
+            if inner.timer_armed {
+                None
+            } else {
+                inner.timer_armed = true;
+                Some(self.policy.delay(inner.attempts))
+            }
+        };
+        // 定时器的回调会获取inner的锁，释放锁之后才能启动定时器
+        if let Some(delay) = arm {
+            self.arm_timer(delay);
+        }
+    }
+
+    /// # 函数的功能
+    /// 设备被删除时，把它从等待列表以及报告中移除
+    pub(super) fn remove(&self, dev: &Arc<dyn Device>) {
+        let mut inner = self.inner.lock_irqsave();
+        inner.pending.retain(|e| !Arc::ptr_eq(&e.dev, dev));
+        let name = dev.name();
+        inner.never_bound.retain(|r| r.device != name);
+    }
+
+    /// # 函数的功能
+    /// 有设备绑定了驱动，它可能就是其他设备等待的依赖，唤醒内核线程重试
+    pub(super) fn trigger(&self) {
+        let mut inner = self.inner.lock_irqsave();
+        if inner.pending.is_empty() {
+            return;
+        }
+        inner.kicked = true;
+        drop(inner);
+        self.wait_queue.wakeup(None);
+    }
+
+    /// 设备是否在等待重试
+    #[allow(dead_code)]
+    pub fn is_pending(&self, dev: &Arc<dyn Device>) -> bool {
+        self.inner
+            .lock_irqsave()
+            .pending
+            .iter()
+            .any(|e| Arc::ptr_eq(&e.dev, dev))
+    }
+
+    /// # 函数的功能
+    /// 获取因为超时而放弃重试、一直没有绑定驱动的设备
+    #[allow(dead_code)]
+    pub fn never_bound(&self) -> Vec<DeferredProbeReport> {
+        self.inner.lock_irqsave().never_bound.clone()
+    }
+
+    /// # 函数的功能
+    /// 重试等待列表中的设备一次
+    ///
+    /// 在`now`时已经超时的设备不再重试，它们会被记录到[`Self::never_bound`]并输出到日志。
+    /// 本轮重试中没有再次被延迟的设备(绑定成功、被删除或者探测出现了其他错误)离开等待列表
+    ///
+    /// ## 返回值
+    /// 仍然在等待重试的设备数量
+    pub fn retry_pending(&self, now: Instant) -> usize {
+        let _retrying = self.retrying.lock();
+        let (pass, devices) = {
+            let mut inner = self.inner.lock_irqsave();
+            inner.pass += 1;
+            let (expired, pending): (Vec<_>, Vec<_>) = core::mem::take(&mut inner.pending)
+                .into_iter()
+                .partition(|e| self.policy.expired(e.since, now));
+            inner.pending = pending;
+            for entry in expired {
+                if entry.dev.is_dead() || entry.dev.driver().is_some() {
+                    continue;
+                }
+                warn!(
+                    "deferred probe: giving up on device '{}', never bound (last error {:?} from driver '{}')",
+                    entry.dev.name(),
+                    entry.error,
+                    entry.driver
+                );
+                inner.never_bound.push(DeferredProbeReport {
+                    device: entry.dev.name(),
+                    driver: entry.driver,
+                    error: entry.error,
+                });
+            }
+            let devices: Vec<Arc<dyn Device>> =
+                inner.pending.iter().map(|e| e.dev.clone()).collect();
+            (inner.pass, devices)
+        };
+
+        // 探测时不能持有inner的锁，驱动可能再次要求延迟
+        for dev in devices.iter() {
+            if dev.is_dead() || dev.driver().is_some() {
+                continue;
+            }
+            if let Err(e) = device_manager().device_attach(dev) {
+                debug!(
+                    "deferred probe: retry of device '{}' failed: {:?}",
+                    dev.name(),
+                    e
+                );
+            }
+        }
+
+        let mut inner = self.inner.lock_irqsave();
+        inner
+            .pending
+            .retain(|e| e.pass == pass && !e.dev.is_dead() && e.dev.driver().is_none());
+        inner.pending.len()
+    }
+
+    fn arm_timer(&self, delay: Duration) {
+        let timer = Timer::new(
+            Box::new(DeferredProbeTimer),
+            next_n_ms_timer_jiffies(delay.total_millis().max(1)),
+        );
+        timer.activate();
+    }
+
+    /// 定时器到期
+    fn timer_expired(&self) {
+        let mut inner = self.inner.lock_irqsave();
+        inner.timer_armed = false;
+        inner.kicked = true;
+        drop(inner);
+        self.wait_queue.wakeup(None);
+    }
+
+    /// 等待定时器或者设备绑定触发重试
+    fn wait_for_kick(&self) {
+        let inner = self.inner.lock_irqsave();
+        if !inner.kicked {
+            self.wait_queue.sleep_unlock_spinlock(inner);
+        }
+    }
+
+    /// 进行一轮重试，并根据剩余的设备安排下一次定时重试
+    fn run_once(&self) {
+        self.inner.lock_irqsave().kicked = false;
+        let remaining = self.retry_pending(Instant::now());
+
+        let arm = {
+            let mut inner = self.inner.lock_irqsave();
+            if remaining == 0 {
+                inner.attempts = 0;
+                None
+            } else if inner.timer_armed {
+                None
+            } else {
+                inner.attempts = inner.attempts.saturating_add(1);
+                inner.timer_armed = true;
+                Some(self.policy.delay(inner.attempts))
+            }
+        };
+        if let Some(delay) = arm {
+            self.arm_timer(delay);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DeferredProbeTimer;
+
+impl TimerFunction for DeferredProbeTimer {
+    fn run(&mut self) -> Result<(), SystemError> {
+        deferred_probe_manager().timer_expired();
+        Ok(())
+    }
+}
+
+#[unified_init(INITCALL_LATE)]
+fn deferred_probe_init() -> Result<(), SystemError> {
+    let closure =
+        KernelThreadClosure::StaticEmptyClosure((&(deferred_probe_thread as fn() -> i32), ()));
+    KernelThreadMechanism::create_and_run(closure, "deferred_probe".to_string())
+        .ok_or(SystemError::EPERM)?;
+    return Ok(());
+}
+
+fn deferred_probe_thread() -> i32 {
+    loop {
+        deferred_probe_manager().run_once();
+        deferred_probe_manager().wait_for_kick();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay_grows_and_caps() {
+        let policy = DeferredProbePolicy {
+            timeout: Duration::from_secs(10),
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_millis(2000));
+        assert_eq!(policy.delay(100), Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn test_expired() {
+        let policy = DeferredProbePolicy {
+            timeout: Duration::from_secs(10),
+        };
+        let since = Instant::from_secs(5);
+        assert!(!policy.expired(since, Instant::from_secs(14)));
+        assert!(policy.expired(since, Instant::from_secs(15)));
+    }
+}
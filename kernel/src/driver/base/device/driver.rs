@@ -14,7 +14,7 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use core::fmt::Debug;
+use core::{fmt::Debug, ops::ControlFlow};
 use log::error;
 use system_error::SystemError;
 
@@ -46,6 +46,9 @@ pub fn driver_manager() -> &'static DriverManager {
     &DriverManager
 }
 
+/// 驱动的默认探测优先级，见[`Driver::probe_priority`]
+pub const DRIVER_PROBE_PRIORITY_DEFAULT: i32 = 0;
+
 /// 驱动程序应当实现的trait
 ///
 /// ## 注意
@@ -63,8 +66,39 @@ pub trait Driver: Sync + Send + Debug + KObject {
     /// @return: 该驱动驱动唯一标识符
     fn id_table(&self) -> Option<IdTable>;
 
+    /// 获取绑定到当前驱动的设备列表的副本
+    ///
+    /// 副本中的设备可能正在被移除，使用之前需要检查`is_dead()`。
+    /// 只需要遍历时，请使用`for_each_device()`或`find_device()`，它们会跳过正在被移除的设备
     fn devices(&self) -> Vec<Arc<dyn Device>>;
 
+    /// 在持有驱动内部锁的情况下，依次对绑定到当前驱动的每个设备调用`f`，
+    /// 当`f`返回`ControlFlow::Break`时提前结束遍历
+    ///
+    /// 默认实现基于`devices()`，会克隆整个设备列表。管理大量设备的驱动应当重写此方法。
+    ///
+    /// ## 注意
+    ///
+    /// - 这是一个内部方法，请使用`for_each_device()`或`find_device()`
+    /// - `f`在锁内执行，不能再次访问当前驱动的设备列表，否则会死锁
+    fn __for_each_device(&self, f: &mut dyn FnMut(&Arc<dyn Device>) -> ControlFlow<()>) {
+        for dev in self.devices().iter() {
+            if f(dev).is_break() {
+                break;
+            }
+        }
+    }
+
+    /// 获取绑定到当前驱动的设备数量
+    fn device_count(&self) -> usize {
+        let mut count = 0;
+        self.__for_each_device(&mut |_| {
+            count += 1;
+            ControlFlow::Continue(())
+        });
+        count
+    }
+
     /// 把设备加入当前驱动管理的列表中
     fn add_device(&self, device: Arc<dyn Device>);
 
@@ -104,10 +138,22 @@ pub trait Driver: Sync + Send + Debug + KObject {
         &[]
     }
 
+    /// 驱动为它绑定的每个设备添加的属性组
+    ///
+    /// 设备与驱动绑定(probe成功)之后，这些属性组被创建在设备的sysfs目录中，解除绑定时被删除。
+    /// 与[`Device::attribute_groups`]不同，它们只描述驱动提供的功能，例如网卡驱动的"speed"
     fn dev_groups(&self) -> &'static [&'static dyn AttributeGroup] {
         &[]
     }
 
+    /// 驱动的探测优先级
+    ///
+    /// 多个驱动都能匹配同一个设备时，优先级高的驱动先尝试探测，优先级相同的驱动按照注册的顺序尝试。
+    /// 专用的驱动应当使用比通用驱动更高的优先级
+    fn probe_priority(&self) -> i32 {
+        DRIVER_PROBE_PRIORITY_DEFAULT
+    }
+
     /// 使用什么样的策略来探测设备
     fn probe_type(&self) -> DriverProbeType {
         DriverProbeType::DefaultStrategy
@@ -130,6 +176,15 @@ impl DriverCommonData {
     pub fn delete_device(&mut self, device: &Arc<dyn Device>) {
         self.devices.retain(|d| !Arc::ptr_eq(d, device));
     }
+
+    /// 供`Driver::__for_each_device()`的实现使用，不会克隆设备列表
+    pub fn for_each_device(&self, f: &mut dyn FnMut(&Arc<dyn Device>) -> ControlFlow<()>) {
+        for dev in self.devices.iter() {
+            if f(dev).is_break() {
+                break;
+            }
+        }
+    }
 }
 
 impl dyn Driver {
@@ -146,6 +201,40 @@ impl dyn Driver {
         }
     }
 
+    /// 遍历绑定到这个驱动的所有设备，跳过正在被移除的设备
+    ///
+    /// 与`devices()`不同，该方法不会克隆整个设备列表
+    ///
+    /// ## 注意
+    ///
+    /// `f`在驱动的锁内执行，不能在其中访问当前驱动的设备列表
+    pub fn for_each_device(&self, mut f: impl FnMut(&Arc<dyn Device>)) {
+        self.__for_each_device(&mut |dev| {
+            if !dev.is_dead() {
+                f(dev);
+            }
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// 寻找第一个满足`predicate`的、绑定到这个驱动的设备
+    ///
+    /// 找到后立即停止遍历，只克隆命中设备的Arc。正在被移除的设备不会被找到
+    pub fn find_device(
+        &self,
+        mut predicate: impl FnMut(&Arc<dyn Device>) -> bool,
+    ) -> Option<Arc<dyn Device>> {
+        let mut result = None;
+        self.__for_each_device(&mut |dev| {
+            if !dev.is_dead() && predicate(dev) {
+                result = Some(dev.clone());
+                return ControlFlow::Break(());
+            }
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
     /// 根据条件寻找一个绑定到这个驱动的设备(低效实现)
     ///
     /// ## 参数
@@ -161,9 +250,7 @@ impl dyn Driver {
         matcher: &dyn DeviceMatcher<T>,
         data: T,
     ) -> Option<Arc<dyn Device>> {
-        self.devices()
-            .into_iter()
-            .find(|dev| matcher.match_device(dev, data))
+        self.find_device(|dev| matcher.match_device(dev, data))
     }
 
     /// 根据设备名称查找绑定到驱动的设备
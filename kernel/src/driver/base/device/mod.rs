@@ -1,5 +1,5 @@
 use alloc::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     string::{String, ToString},
     sync::{Arc, Weak},
 };
@@ -31,25 +31,28 @@ use core::{any::Any, fmt::Debug};
 use system_error::SystemError;
 
 use self::{
-    bus::{bus_add_device, bus_probe_device, Bus},
+    bus::{bus_add_device, bus_probe_device, bus_remove_device, Bus},
     device_number::{DeviceNumber, Major},
     driver::Driver,
 };
 
 use super::{
-    class::{Class, ClassKObjbectType},
+    class::{class_manager, Class, ClassKObjbectType},
     kobject::{
         KObjType, KObject, KObjectCommonData, KObjectManager, KObjectState, LockedKObjectState,
     },
     kset::KSet,
+    power::{runtime::DevPmRuntime, sleep::dpm_manager, sysfs::DevPowerAttrGroup},
     swnode::software_node_notify,
 };
 
 pub mod bus;
 pub mod dd;
+pub mod deferred_probe;
 pub mod device_number;
 pub mod driver;
 pub mod init;
+pub mod registry;
 
 static mut DEVICE_MANAGER: Option<DeviceManager> = None;
 
@@ -184,6 +187,19 @@ pub trait Device: KObject {
     /// 当前设备是否已经挂掉了
     fn is_dead(&self) -> bool;
 
+    /// 设备的运行时电源管理状态，不支持运行时电源管理的设备返回None
+    ///
+    /// 返回Some的设备会有`power`目录
+    fn pm_runtime(&self) -> Option<&DevPmRuntime> {
+        None
+    }
+
+    /// 把设备标记为已挂掉
+    ///
+    /// 设备被移除时，会在从驱动的设备列表中删除它之前调用，之后遍历驱动设备的操作会跳过它。
+    /// 不会被移除的设备可以不实现这个方法
+    fn set_dead(&self, _dead: bool) {}
+
     /// 当前设备是否处于可以被匹配的状态
     ///
     /// The device has matched with a driver at least once or it is in
@@ -344,6 +360,22 @@ impl IdTable {
     pub fn device_number(&self) -> DeviceNumber {
         return self.id.unwrap_or_default();
     }
+
+    /// 是否带有(非UNNAMED_MAJOR的)设备号
+    ///
+    /// 只有带设备号的标识符才能唯一地确定一个设备，同一个驱动的多个设备可能使用相同的basename
+    pub fn has_device_number(&self) -> bool {
+        return self.id.is_some_and(|id| id.major() != Major::UNNAMED_MAJOR);
+    }
+}
+
+impl core::fmt::Display for IdTable {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "{}({}:{})", self.basename, id.major().data(), id.minor()),
+            None => write!(f, "{}", self.basename),
+        }
+    }
 }
 
 impl Default for IdTable {
@@ -468,7 +500,10 @@ impl SysFSOps for DeviceSysFSOps {
 
 /// @brief Device管理器
 #[derive(Debug)]
-pub struct DeviceManager;
+pub struct DeviceManager {
+    /// 已经添加的设备中，带有设备号的设备标识符
+    id_tables: SpinLock<BTreeSet<IdTable>>,
+}
 
 impl DeviceManager {
     /// @brief: 创建一个新的设备管理器
@@ -476,7 +511,38 @@ impl DeviceManager {
     /// @return: DeviceManager实体
     #[inline]
     const fn new() -> DeviceManager {
-        return Self;
+        return Self {
+            id_tables: SpinLock::new(BTreeSet::new()),
+        };
+    }
+
+    /// # 函数的功能
+    /// 占用设备的标识符，保证带有设备号的标识符不会被两个设备同时使用
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EEXIST): 已经有设备使用了相同的标识符
+    fn claim_id_table(&self, dev: &Arc<dyn Device>) -> Result<(), SystemError> {
+        let id_table = dev.id_table();
+        if !id_table.has_device_number() {
+            return Ok(());
+        }
+        if !self.id_tables.lock_irqsave().insert(id_table.clone()) {
+            error!(
+                "add device '{}' failed: id table '{}' is already in use",
+                dev.name(),
+                id_table
+            );
+            return Err(SystemError::EEXIST);
+        }
+        return Ok(());
+    }
+
+    /// 释放claim_id_table占用的标识符
+    fn release_id_table(&self, dev: &Arc<dyn Device>) {
+        let id_table = dev.id_table();
+        if id_table.has_device_number() {
+            self.id_tables.lock_irqsave().remove(&id_table);
+        }
     }
 
     pub fn register(&self, device: Arc<dyn Device>) -> Result<(), SystemError> {
@@ -491,10 +557,18 @@ impl DeviceManager {
     ///
     /// https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/core.c#3398
     ///
+    /// 带有设备号的标识符已经被其他设备使用时，返回EEXIST
+    ///
     /// todo: 完善错误处理逻辑：如果添加失败，需要将之前添加的内容全部回滚
     #[inline(never)]
     #[allow(dead_code)]
     pub fn add_device(&self, device: Arc<dyn Device>) -> Result<(), SystemError> {
+        self.claim_id_table(&device)?;
+        self.do_add_device(device.clone())
+            .inspect_err(|_| self.release_id_table(&device))
+    }
+
+    fn do_add_device(&self, device: Arc<dyn Device>) -> Result<(), SystemError> {
         // 在这里处理与parent相关的逻辑
         let deivce_parent = device.dev_parent().and_then(|x| x.upgrade());
         if let Some(ref dev) = deivce_parent {
@@ -525,11 +599,18 @@ impl DeviceManager {
 
         self.device_platform_notify(&device);
 
-        self.add_class_symlinks(&device)?;
+        // 所属的class还没有注册时，等到class注册之后再链接到class下
+        let class_registered = device
+            .class()
+            .is_some_and(|class| class_manager().class_registered(&class));
+        if class_registered {
+            self.add_class_symlinks(&device)?;
+        }
 
         self.add_attrs(&device)?;
 
         bus_add_device(&device)?;
+        dpm_manager().add(&device);
 
         if device.id_table().device_number().major() != Major::UNNAMED_MAJOR {
             self.create_file(&device, &DeviceAttrDev)?;
@@ -552,16 +633,34 @@ impl DeviceManager {
         bus_probe_device(&device);
 
         if let Some(class) = device.class() {
-            class.subsystem().add_device_to_vec(&device)?;
+            if class_registered {
+                class.subsystem().add_device_to_vec(&device)?;
 
-            for class_interface in class.subsystem().interfaces() {
-                class_interface.add_device(&device).ok();
+                for class_interface in class.subsystem().interfaces() {
+                    class_interface.add_device(&device).ok();
+                }
+            } else {
+                class_manager().defer_device(&device);
             }
         }
 
         return Ok(());
     }
 
+    /// 把所属class晚于设备注册的设备链接到class下，在class注册时调用
+    pub(super) fn class_add_deferred_device(
+        &self,
+        dev: &Arc<dyn Device>,
+    ) -> Result<(), SystemError> {
+        let class = dev.class().ok_or(SystemError::EINVAL)?;
+        self.add_class_symlinks(dev)?;
+        class.subsystem().add_device_to_vec(dev)?;
+        for class_interface in class.subsystem().interfaces() {
+            class_interface.add_device(dev).ok();
+        }
+        return Ok(());
+    }
+
     /// 用于创建并添加一个新的kset，表示一个设备类目录
     /// 参考：https://code.dragonos.org.cn/xref/linux-6.6.21/drivers/base/core.c#3159
     fn class_dir_create_and_add(
@@ -660,8 +759,66 @@ impl DeviceManager {
     }
 
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c?fi=driver_attach#542
-    pub fn remove(&self, _dev: &Arc<dyn Device>) {
-        todo!("DeviceManager::remove")
+    ///
+    /// 把设备从设备模型中移除（与add_device相反）
+    ///
+    /// 会解除设备与驱动的绑定，并删除设备在sysfs中的属性文件、符号链接以及目录
+    ///
+    /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/core.c#3699
+    ///
+    /// ## 移除的顺序
+    ///
+    /// 1. 把设备标记为已挂掉(`set_dead`)，之后不会再有驱动绑定到它，遍历驱动设备的操作也会跳过它
+    /// 2. 解除与驱动的绑定，此时才把设备从驱动的设备列表中删除。
+    ///    驱动在持有设备列表的锁的情况下遍历设备，删除需要等待正在进行的遍历结束
+    /// 3. 删除设备在sysfs中的文件和目录
+    ///
+    /// 通过`Driver::devices()`得到设备列表副本的调用者，在使用其中的设备之前需要检查`is_dead()`
+    ///
+    /// 设备在[`registry::DeviceRegistry`]中的条目以及它在dpm列表中的位置也会被删除
+    pub fn remove(&self, dev: &Arc<dyn Device>) {
+        dev.set_dead(true);
+        let bus = dev.bus().and_then(|bus| bus.upgrade());
+        if let Some(bus) = bus.as_ref() {
+            bus.subsystem().bus_notifier().call_chain(
+                bus::BusNotifyEvent::DelDevice,
+                Some(dev),
+                None,
+            );
+        }
+
+        deferred_probe::deferred_probe_manager().remove(dev);
+
+        if let Some(class) = dev.class() {
+            class_manager().cancel_deferred_device(dev);
+            self.remove_class_symlinks(dev, &class);
+            for class_interface in class.subsystem().interfaces() {
+                class_interface.remove_device(dev);
+            }
+            class.subsystem().remove_device_from_vec(dev);
+        }
+
+        if dev.id_table().device_number().major() != Major::UNNAMED_MAJOR {
+            self.remove_sys_dev_entry(dev);
+            self.remove_file(dev, &DeviceAttrDev);
+        }
+
+        self.remove_attrs(dev);
+        bus_remove_device(dev);
+
+        if let Some(bus) = bus.as_ref() {
+            bus.subsystem().bus_notifier().call_chain(
+                bus::BusNotifyEvent::RemovedDevice,
+                Some(dev),
+                None,
+            );
+        }
+
+        // todo: 发送uevent: KOBJ_REMOVE
+        KObjectManager::remove_kobj(dev.clone() as Arc<dyn KObject>);
+        self.release_id_table(dev);
+        registry::device_registry().unregister_device(dev);
+        dpm_manager().remove(dev);
     }
 
     /// @brief: 获取设备
@@ -719,6 +876,30 @@ impl DeviceManager {
         return Ok(());
     }
 
+    /// 删除add_class_symlinks创建的符号链接
+    fn remove_class_symlinks(&self, dev: &Arc<dyn Device>, class: &Arc<dyn Class>) {
+        let dev_kobj = dev.clone() as Arc<dyn KObject>;
+        let subsys_kobj = class.subsystem().subsys() as Arc<dyn KObject>;
+
+        sysfs_instance().remove_link(&subsys_kobj, dev.name());
+        sysfs_instance().remove_link(&dev_kobj, "device".to_string());
+        sysfs_instance().remove_link(&dev_kobj, "subsystem".to_string());
+    }
+
+    /// 删除add_attrs创建的属性文件
+    fn remove_attrs(&self, dev: &Arc<dyn Device>) {
+        if dev.pm_runtime().is_some() {
+            self.remove_groups(dev, &[&DevPowerAttrGroup]);
+        }
+        self.remove_groups(dev, dev.attribute_groups().unwrap_or(&[]));
+        if let Some(kobj_type) = dev.kobj_type() {
+            self.remove_groups(dev, kobj_type.attribute_groups().unwrap_or(&[]));
+        }
+        if let Some(class) = dev.class() {
+            self.remove_groups(dev, class.dev_groups());
+        }
+    }
+
     /// 在sysfs中，为指定的设备创建属性文件
     ///
     /// ## 参数
@@ -763,6 +944,16 @@ impl DeviceManager {
                 err_remove_class_groups(dev);
             })?;
 
+        // 支持运行时电源管理的设备，添加power目录
+        if dev.pm_runtime().is_some() {
+            self.add_groups(dev, &[&DevPowerAttrGroup])
+                .inspect_err(|_e| {
+                    self.remove_groups(dev, dev.attribute_groups().unwrap_or(&[]));
+                    err_remove_kobj_type_groups(dev);
+                    err_remove_class_groups(dev);
+                })?;
+        }
+
         return Ok(());
     }
 
@@ -831,6 +1022,17 @@ impl DeviceManager {
         return sysfs_instance().create_file(&kobj, attr);
     }
 
+    /// 删除设备在sysfs中的属性文件
+    ///
+    /// ## 参数
+    ///
+    /// - `dev`: 设备
+    /// - `attr`: 属性
+    pub fn remove_file(&self, dev: &Arc<dyn Device>, attr: &'static dyn Attribute) {
+        let kobj = dev.clone() as Arc<dyn KObject>;
+        sysfs_instance().remove_file(&kobj, attr);
+    }
+
     /// 在/sys/dev下，或者设备所属的class下，为指定的设备创建链接
     fn create_sys_dev_entry(&self, dev: &Arc<dyn Device>) -> Result<(), SystemError> {
         let target_kobj = self.device_to_dev_kobj(dev);
@@ -840,7 +1042,6 @@ impl DeviceManager {
     }
 
     /// Delete symlink for device in `/sys/dev` or `/sys/class/<class_name>`
-    #[allow(dead_code)]
     fn remove_sys_dev_entry(&self, dev: &Arc<dyn Device>) {
         let kobj = self.device_to_dev_kobj(dev);
         let name = dev.id_table().name();
@@ -871,6 +1072,7 @@ impl DeviceManager {
     pub fn device_default_initialize(&self, dev: &Arc<dyn Device>) {
         dev.set_kset(Some(sys_devices_kset()));
         dev.set_kobj_type(Some(&DeviceKObjType));
+        dev.kobj_state_mut().insert(KObjectState::INITIALIZED);
         return;
     }
 
@@ -884,8 +1086,8 @@ impl DeviceManager {
     }
 
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/dd.c?r=&mo=35401&fi=1313#1313
-    pub fn device_driver_detach(&self, _dev: &Arc<dyn Device>) {
-        todo!("device_driver_detach")
+    pub fn device_driver_detach(&self, dev: &Arc<dyn Device>) {
+        self.device_release_driver(dev);
     }
 }
 
@@ -1114,3 +1316,50 @@ impl KObject for ClassDir {
         *self.locked_kobj_state.write() = state;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_table_eq() {
+        let id0 = DeviceNumber::new(Major::new(240), 0);
+        let id1 = DeviceNumber::new(Major::new(240), 1);
+        let a = IdTable::new("vdev".to_string(), Some(id0));
+        assert_eq!(a, IdTable::new("vdev".to_string(), Some(id0)));
+        // 同一个驱动的不同实例
+        assert_ne!(a, IdTable::new("vdev".to_string(), Some(id1)));
+        assert_ne!(a, IdTable::new("vdev".to_string(), None));
+        assert_ne!(a, IdTable::new("other".to_string(), Some(id0)));
+
+        let mut set = BTreeSet::new();
+        assert!(set.insert(a.clone()));
+        assert!(set.insert(IdTable::new("vdev".to_string(), Some(id1))));
+        assert!(!set.insert(a));
+    }
+
+    #[test]
+    fn test_id_table_device_number() {
+        let unnamed = DeviceNumber::new(Major::UNNAMED_MAJOR, 3);
+        assert!(!IdTable::new("vdev".to_string(), None).has_device_number());
+        assert!(!IdTable::new("vdev".to_string(), Some(unnamed)).has_device_number());
+        assert!(IdTable::new(
+            "vdev".to_string(),
+            Some(DeviceNumber::new(Major::new(240), 0))
+        )
+        .has_device_number());
+    }
+
+    #[test]
+    fn test_id_table_display() {
+        let id = DeviceNumber::new(Major::new(240), 7);
+        assert_eq!(
+            format!("{}", IdTable::new("vdev".to_string(), Some(id))),
+            "vdev(240:7)"
+        );
+        assert_eq!(
+            format!("{}", IdTable::new("vdev".to_string(), None)),
+            "vdev"
+        );
+    }
+}
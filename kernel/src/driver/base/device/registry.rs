@@ -0,0 +1,85 @@
+//! 通过DeviceId查找设备
+//!
+//! 设备以[`DeviceId`]注册到全局的[`DeviceRegistry`]中，之后可以直接通过DeviceId(或者它的字符串形式，
+//! 例如virtio-pci设备的pci地址)找到设备，而不需要遍历所有的总线。
+//!
+//! 设备从设备模型中移除时(见[`super::DeviceManager::remove`])，它的所有条目都会被删除。
+
+use alloc::{string::ToString, sync::Arc, vec::Vec};
+use hashbrown::HashMap;
+use system_error::SystemError;
+
+use crate::libs::rwlock::RwLock;
+
+use super::{Device, DeviceId};
+
+lazy_static! {
+    static ref DEVICE_REGISTRY: DeviceRegistry = DeviceRegistry::new();
+}
+
+#[inline(always)]
+pub fn device_registry() -> &'static DeviceRegistry {
+    &DEVICE_REGISTRY
+}
+
+/// # 结构功能
+/// DeviceId到设备的映射
+#[derive(Debug)]
+pub struct DeviceRegistry {
+    map: RwLock<HashMap<Arc<DeviceId>, Arc<dyn Device>>>,
+}
+
+impl DeviceRegistry {
+    fn new() -> Self {
+        Self {
+            map: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// # 函数的功能
+    /// 以`id`注册设备
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EEXIST): `id`已经被其他设备注册
+    pub fn register(&self, id: Arc<DeviceId>, dev: Arc<dyn Device>) -> Result<(), SystemError> {
+        let mut map = self.map.write_irqsave();
+        if map.contains_key(&id) {
+            return Err(SystemError::EEXIST);
+        }
+        map.insert(id, dev);
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 删除`id`对应的条目
+    ///
+    /// ## 返回值
+    /// 被删除的条目对应的设备，`id`没有注册时为None
+    #[allow(dead_code)]
+    pub fn unregister(&self, id: &DeviceId) -> Option<Arc<dyn Device>> {
+        self.map.write_irqsave().remove(id)
+    }
+
+    /// 删除`dev`的所有条目，在设备从设备模型中移除时调用
+    pub fn unregister_device(&self, dev: &Arc<dyn Device>) {
+        self.map.write_irqsave().retain(|_, d| !Arc::ptr_eq(d, dev));
+    }
+
+    /// 查找`id`对应的设备
+    pub fn lookup(&self, id: &DeviceId) -> Option<Arc<dyn Device>> {
+        self.map.read_irqsave().get(id).cloned()
+    }
+
+    /// 通过DeviceId的字符串形式查找设备
+    #[allow(dead_code)]
+    pub fn lookup_by_name(&self, id: &str) -> Option<Arc<dyn Device>> {
+        let id = DeviceId::new(None, Some(id.to_string()))?;
+        self.lookup(&id)
+    }
+
+    /// 已经注册的所有DeviceId，用于调试
+    #[allow(dead_code)]
+    pub fn ids(&self) -> Vec<Arc<DeviceId>> {
+        self.map.read_irqsave().keys().cloned().collect()
+    }
+}
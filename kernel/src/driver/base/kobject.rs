@@ -66,6 +66,11 @@ impl dyn KObject {
         let mut state = self.kobj_state_mut();
         *state = (*state | insert) & !remove;
     }
+
+    /// kobject是否已经设置了`state`中的所有标志位
+    pub fn check_kobj_state(&self, state: KObjectState) -> bool {
+        self.kobj_state().contains(state)
+    }
 }
 
 impl DowncastArc for dyn KObject {
@@ -115,6 +120,21 @@ impl LockedKObjectState {
         let state = state.unwrap_or(KObjectState::empty());
         LockedKObjectState(RwLock::new(state))
     }
+
+    /// 设置`state`中的标志位，其余标志位保持不变
+    pub fn add_state(&self, state: KObjectState) {
+        self.0.write().insert(state);
+    }
+
+    /// 清除`state`中的标志位，其余标志位保持不变
+    pub fn remove_state(&self, state: KObjectState) {
+        self.0.write().remove(state);
+    }
+
+    /// 是否已经设置了`state`中的所有标志位
+    pub fn check_state(&self, state: KObjectState) -> bool {
+        self.0.read().contains(state)
+    }
 }
 
 impl Deref for LockedKObjectState {
@@ -189,6 +209,7 @@ impl KObjectManager {
 
     pub fn kobj_init(kobj: &Arc<dyn KObject>, kobj_type: Option<&'static dyn KObjType>) {
         kobj.set_kobj_type(kobj_type);
+        kobj.update_kobj_state(Some(KObjectState::INITIALIZED), None);
     }
 
     pub fn add_kobj(
@@ -218,10 +239,12 @@ impl KObjectManager {
             return Err(e);
         }
 
-        kobj.update_kobj_state(Some(KObjectState::IN_SYSFS), None);
         return Ok(());
     }
 
+    /// 创建kobject在sysfs中的目录以及默认属性
+    ///
+    /// IN_SYSFS标志位在目录创建之后由sysfs设置，因此之后创建属性文件时kobject已经处于sysfs中
     fn create_dir(kobj: Arc<dyn KObject>) -> Result<(), SystemError> {
         // create dir in sysfs
         sysfs_instance().create_dir(kobj.clone())?;
@@ -253,7 +276,6 @@ impl KObjectManager {
         // todo: 发送uevent: KOBJ_REMOVE
 
         sysfs_instance().remove_dir(&kobj);
-        kobj.update_kobj_state(None, Some(KObjectState::IN_SYSFS));
         let kset = kobj.kset();
         if let Some(kset) = kset {
             kset.leave(&kobj);
@@ -279,3 +301,38 @@ impl KObjType for DynamicKObjKType {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_remove_state() {
+        let state = LockedKObjectState::new(None);
+        assert!(!state.check_state(KObjectState::INITIALIZED));
+
+        state.add_state(KObjectState::INITIALIZED);
+        state.add_state(KObjectState::IN_SYSFS);
+        assert!(state.check_state(KObjectState::INITIALIZED | KObjectState::IN_SYSFS));
+
+        // 清除一个标志位不影响其他标志位
+        state.remove_state(KObjectState::IN_SYSFS);
+        assert!(state.check_state(KObjectState::INITIALIZED));
+        assert!(!state.check_state(KObjectState::IN_SYSFS));
+        assert!(!state.check_state(KObjectState::INITIALIZED | KObjectState::IN_SYSFS));
+
+        // 重复设置和清除是幂等的
+        state.add_state(KObjectState::INITIALIZED);
+        state.remove_state(KObjectState::IN_SYSFS);
+        assert_eq!(*state.read(), KObjectState::INITIALIZED);
+    }
+
+    #[test]
+    fn test_check_empty_state() {
+        let state = LockedKObjectState::new(Some(KObjectState::ADD_UEVENT_SENT));
+        assert!(state.check_state(KObjectState::empty()));
+        assert!(state.check_state(KObjectState::ADD_UEVENT_SENT));
+        state.remove_state(KObjectState::all());
+        assert_eq!(*state.read(), KObjectState::empty());
+    }
+}
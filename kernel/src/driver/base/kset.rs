@@ -94,7 +94,6 @@ impl KSet {
     }
 
     /// 注销一个kset
-    #[allow(dead_code)]
     pub fn unregister(&self) {
         KObjectManager::remove_kobj(self.self_ref.upgrade().unwrap());
     }
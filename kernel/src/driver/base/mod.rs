@@ -2,6 +2,7 @@ pub mod block;
 pub mod char;
 pub mod class;
 pub mod cpu;
+pub mod dev_log;
 pub mod device;
 pub mod firmware;
 pub mod hypervisor;
@@ -10,5 +11,6 @@ pub mod kobject;
 pub mod kset;
 pub mod map;
 pub mod platform;
+pub mod power;
 pub mod subsys;
 pub mod swnode;
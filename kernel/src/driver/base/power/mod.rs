@@ -0,0 +1,7 @@
+//! 设备的电源管理
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/power/
+
+pub mod runtime;
+pub mod sleep;
+pub mod sysfs;
@@ -0,0 +1,607 @@
+//! 设备的运行时电源管理(runtime PM)
+//!
+//! 设备空闲时可以由设备核心通过总线的`suspend()`挂起(例如让PCI设备进入D3hot)，
+//! 之后有新的操作时再通过总线的`resume()`恢复。
+//! 是否允许自动挂起由用户通过设备的`power/control`决定，默认为`on`，即不会自动挂起。
+//!
+//! 驱动在每次访问设备前后调用[`pm_runtime_get`]和[`pm_runtime_put`]，
+//! 使用计数回到0并且设备空闲超过`autosuspend_delay_ms`之后，内核线程`pm_runtime`挂起设备。
+//! 设备处于工作状态时，[`pm_runtime_get`]只修改原子变量，不获取锁，可以在关中断的上下文中调用。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/power/runtime.c
+
+use core::{
+    fmt::Display,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{
+    string::ToString,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use log::warn;
+use system_error::SystemError;
+use unified_init::macros::unified_init;
+
+use crate::{
+    arch::CurrentIrqArch,
+    driver::base::{device::Device, kobject::KObject},
+    exception::InterruptArch,
+    init::initcall::INITCALL_LATE,
+    libs::{mutex::Mutex, spinlock::SpinLock, wait_queue::WaitQueue},
+    process::kthread::{KernelThreadClosure, KernelThreadMechanism},
+    time::{sleep::nanosleep, Instant, PosixTimeSpec},
+};
+
+/// 默认的自动挂起延迟（毫秒）
+pub const RPM_DEFAULT_AUTOSUSPEND_DELAY_MS: u64 = 2000;
+
+/// 用户是否允许设备在空闲时被自动挂起
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpmControl {
+    /// 设备总是保持工作状态
+    On,
+    /// 设备空闲时可以被挂起
+    Auto,
+}
+
+impl Display for RpmControl {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RpmControl::On => write!(f, "on"),
+            RpmControl::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl FromStr for RpmControl {
+    type Err = SystemError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "on" => Ok(RpmControl::On),
+            "auto" => Ok(RpmControl::Auto),
+            _ => Err(SystemError::EINVAL),
+        }
+    }
+}
+
+/// 设备当前的运行时电源状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpmStatus {
+    Active,
+    Suspended,
+}
+
+impl Display for RpmStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RpmStatus::Active => write!(f, "active"),
+            RpmStatus::Suspended => write!(f, "suspended"),
+        }
+    }
+}
+
+/// # 结构功能
+/// 一个设备的运行时电源管理状态
+///
+/// 支持运行时电源管理的设备通过`Device::pm_runtime()`返回它
+#[derive(Debug)]
+pub struct DevPmRuntime {
+    /// 挂起与恢复可能睡眠(例如等待PCI设备切换电源状态)，因此使用互斥锁
+    inner: Mutex<InnerDevPmRuntime>,
+    /// 使用计数，不为0时设备不会被挂起
+    usage_count: AtomicUsize,
+    /// 设备已经挂起或者正在被挂起。为false时设备一定处于工作状态，恢复设备不需要获取`inner`
+    suspended: AtomicBool,
+    /// 使用计数回到0之后，需要空闲多久才会被挂起（毫秒）
+    autosuspend_delay_ms: AtomicU64,
+    /// 设备最近一次变为空闲的时间（微秒）
+    last_busy_us: AtomicU64,
+}
+
+#[derive(Debug)]
+struct InnerDevPmRuntime {
+    control: RpmControl,
+    status: RpmStatus,
+}
+
+#[allow(dead_code)]
+impl DevPmRuntime {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(InnerDevPmRuntime {
+                control: RpmControl::On,
+                status: RpmStatus::Active,
+            }),
+            usage_count: AtomicUsize::new(0),
+            suspended: AtomicBool::new(false),
+            autosuspend_delay_ms: AtomicU64::new(RPM_DEFAULT_AUTOSUSPEND_DELAY_MS),
+            last_busy_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn usage_count(&self) -> usize {
+        self.usage_count.load(Ordering::SeqCst)
+    }
+
+    pub fn autosuspend_delay_ms(&self) -> u64 {
+        self.autosuspend_delay_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_autosuspend_delay_ms(&self, ms: u64) {
+        self.autosuspend_delay_ms.store(ms, Ordering::Relaxed);
+    }
+
+    /// 增加使用计数，不恢复设备
+    pub fn get_noresume(&self) {
+        self.usage_count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// # 函数的功能
+    /// 减少使用计数，不挂起设备
+    ///
+    /// ## 返回值
+    /// 使用计数是否回到了0。计数已经为0时返回false
+    pub fn put_noidle(&self) -> bool {
+        self.usage_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| c.checked_sub(1))
+            .is_ok_and(|old| old == 1)
+    }
+
+    /// 记录设备在`now_us`时变为空闲
+    pub fn mark_last_busy(&self, now_us: u64) {
+        self.last_busy_us.store(now_us, Ordering::Relaxed);
+    }
+
+    /// # 函数的功能
+    /// 计算还要等待多久才能自动挂起设备
+    ///
+    /// ## 返回值
+    /// - Some(0): 现在就可以挂起
+    /// - Some(us): 还需要空闲的时间（微秒）
+    /// - None: 不允许自动挂起、设备已经挂起，或者设备正在被使用
+    pub fn autosuspend_remaining_us(&self, now_us: u64) -> Option<u64> {
+        {
+            let inner = self.inner.lock();
+            if inner.control != RpmControl::Auto || inner.status != RpmStatus::Active {
+                return None;
+            }
+        }
+        if self.usage_count() != 0 {
+            return None;
+        }
+        let deadline =
+            self.last_busy_us.load(Ordering::Relaxed) + self.autosuspend_delay_ms() * 1000;
+        Some(deadline.saturating_sub(now_us))
+    }
+
+    pub fn control(&self) -> RpmControl {
+        self.inner.lock().control
+    }
+
+    pub fn status(&self) -> RpmStatus {
+        self.inner.lock().status
+    }
+
+    /// 设备是否可能处于挂起状态，为false时访问设备之前不需要恢复它
+    pub fn may_be_suspended(&self) -> bool {
+        self.suspended.load(Ordering::SeqCst)
+    }
+
+    /// # 函数的功能
+    /// 设备空闲时，如果允许自动挂起，就调用`suspend`挂起设备
+    ///
+    /// ## 返回值
+    /// - Ok(true): 设备被挂起
+    /// - Ok(false): 不允许自动挂起，设备已经挂起，或者使用计数不为0
+    /// - Err(e): `suspend`失败，设备保持工作状态
+    pub fn idle_with(
+        &self,
+        suspend: impl FnOnce() -> Result<(), SystemError>,
+    ) -> Result<bool, SystemError> {
+        let mut inner = self.inner.lock();
+        if inner.control != RpmControl::Auto || inner.status != RpmStatus::Active {
+            return Ok(false);
+        }
+        // 先标记再检查使用计数：与get_noresume之后检查标记的使用者之间，至少有一方能看到对方。
+        // 看到标记的使用者会等待挂起完成，再恢复设备
+        self.suspended.store(true, Ordering::SeqCst);
+        if self.usage_count() != 0 {
+            self.suspended.store(false, Ordering::SeqCst);
+            return Ok(false);
+        }
+        if let Err(e) = suspend() {
+            self.suspended.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+        inner.status = RpmStatus::Suspended;
+        Ok(true)
+    }
+
+    /// # 函数的功能
+    /// 设备被挂起时，调用`resume`恢复设备
+    ///
+    /// ## 返回值
+    /// - Ok(true): 设备被恢复
+    /// - Ok(false): 设备本来就处于工作状态
+    /// - Err(e): `resume`失败，设备保持挂起状态
+    ///
+    /// 设备处于工作状态时不获取锁
+    pub fn resume_with(
+        &self,
+        resume: impl FnOnce() -> Result<(), SystemError>,
+    ) -> Result<bool, SystemError> {
+        if !self.may_be_suspended() {
+            return Ok(false);
+        }
+        let mut inner = self.inner.lock();
+        if inner.status != RpmStatus::Suspended {
+            return Ok(false);
+        }
+        resume()?;
+        inner.status = RpmStatus::Active;
+        self.suspended.store(false, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// # 函数的功能
+    /// 修改是否允许自动挂起。改为`on`时，如果设备已经挂起，就调用`resume`恢复设备
+    pub fn set_control_with(
+        &self,
+        control: RpmControl,
+        resume: impl FnOnce() -> Result<(), SystemError>,
+    ) -> Result<(), SystemError> {
+        if control == RpmControl::On {
+            self.inner.lock().control = RpmControl::On;
+            self.resume_with(resume)?;
+        } else {
+            self.inner.lock().control = control;
+        }
+        Ok(())
+    }
+}
+
+impl Default for DevPmRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 通过设备所在总线的`suspend()`挂起设备
+fn rpm_suspend(dev: &Arc<dyn Device>) -> Result<(), SystemError> {
+    let bus = dev
+        .bus()
+        .and_then(|bus| bus.upgrade())
+        .ok_or(SystemError::ENODEV)?;
+    bus.suspend(dev);
+    Ok(())
+}
+
+/// 通过设备所在总线的`resume()`恢复设备
+fn rpm_resume(dev: &Arc<dyn Device>) -> Result<(), SystemError> {
+    let bus = dev
+        .bus()
+        .and_then(|bus| bus.upgrade())
+        .ok_or(SystemError::ENODEV)?;
+    bus.resume(dev)
+}
+
+fn dev_pm_runtime(dev: &Arc<dyn Device>) -> Result<&DevPmRuntime, SystemError> {
+    dev.pm_runtime().ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+}
+
+/// # 函数的功能
+/// 驱动在设备空闲时调用，`power/control`为`auto`时挂起设备
+///
+/// ## 返回值
+/// - Ok(true): 设备被挂起
+/// - Ok(false): 不允许自动挂起，或者设备已经挂起
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 设备不支持运行时电源管理
+#[allow(dead_code)]
+pub fn pm_runtime_idle(dev: &Arc<dyn Device>) -> Result<bool, SystemError> {
+    dev_pm_runtime(dev)?.idle_with(|| rpm_suspend(dev))
+}
+
+/// # 函数的功能
+/// 驱动在访问设备之前调用，设备被挂起时恢复它
+///
+/// ## 返回值
+/// - Ok(true): 设备被恢复
+/// - Ok(false): 设备本来就处于工作状态
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 设备不支持运行时电源管理
+#[allow(dead_code)]
+pub fn pm_runtime_resume(dev: &Arc<dyn Device>) -> Result<bool, SystemError> {
+    dev_pm_runtime(dev)?.resume_with(|| rpm_resume(dev))
+}
+
+/// # 函数的功能
+/// 驱动在访问设备之前调用：增加使用计数，设备被挂起时恢复它
+///
+/// 不支持运行时电源管理的设备什么也不做。设备处于工作状态时只增加使用计数，不获取锁
+///
+/// ## 返回值
+/// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): 设备需要恢复，但是当前处于关中断的上下文
+///   （例如轮询设备），恢复设备可能睡眠，不能在这里进行
+/// - Err(e): 恢复设备失败
+///
+/// 返回错误时使用计数不变
+pub fn pm_runtime_get(dev: &Arc<dyn Device>) -> Result<(), SystemError> {
+    let Some(pm) = dev.pm_runtime() else {
+        return Ok(());
+    };
+    pm.get_noresume();
+    if !pm.may_be_suspended() {
+        return Ok(());
+    }
+    if !CurrentIrqArch::is_irq_enabled() {
+        pm.put_noidle();
+        return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+    }
+    if let Err(e) = pm.resume_with(|| rpm_resume(dev)) {
+        pm.put_noidle();
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// # 函数的功能
+/// 驱动在访问设备之后调用：减少使用计数，计数回到0时，设备空闲超过自动挂起延迟之后被挂起
+///
+/// 可以在中断上下文中调用，挂起由内核线程`pm_runtime`完成
+pub fn pm_runtime_put(dev: &Arc<dyn Device>) {
+    let Some(pm) = dev.pm_runtime() else {
+        return;
+    };
+    if pm.put_noidle() {
+        pm.mark_last_busy(Instant::now().total_micros() as u64);
+        RPM_AUTOSUSPEND.push(Arc::downgrade(dev));
+    }
+}
+
+/// # 结构功能
+/// 等待自动挂起的设备
+struct RpmAutosuspendQueue {
+    devices: SpinLock<Vec<Weak<dyn Device>>>,
+    wait_queue: WaitQueue,
+}
+
+lazy_static! {
+    static ref RPM_AUTOSUSPEND: RpmAutosuspendQueue = RpmAutosuspendQueue {
+        devices: SpinLock::new(Vec::new()),
+        wait_queue: WaitQueue::default(),
+    };
+}
+
+impl RpmAutosuspendQueue {
+    fn push(&self, dev: Weak<dyn Device>) {
+        let mut devices = self.devices.lock_irqsave();
+        if !devices.iter().any(|d| Weak::ptr_eq(d, &dev)) {
+            devices.push(dev);
+        }
+        drop(devices);
+        self.wait_queue.wakeup(None);
+    }
+
+    /// # 函数的功能
+    /// 挂起所有已经空闲足够久的设备
+    ///
+    /// ## 返回值
+    /// 距离下一个设备可以被挂起的时间（微秒），没有等待挂起的设备时为None
+    fn run(&self) -> Option<u64> {
+        let devices = core::mem::take(&mut *self.devices.lock_irqsave());
+        let now_us = Instant::now().total_micros() as u64;
+        let mut next = None;
+        let mut waiting = Vec::new();
+        for weak in devices {
+            let Some(dev) = weak.upgrade() else {
+                continue;
+            };
+            let Some(pm) = dev.pm_runtime() else {
+                continue;
+            };
+            // 设备重新被使用、或者不允许自动挂起时，不再等待。之后计数回到0时会被重新加入
+            match pm.autosuspend_remaining_us(now_us) {
+                None => {}
+                Some(0) => {
+                    if let Err(e) = pm.idle_with(|| rpm_suspend(&dev)) {
+                        warn!("pm_runtime: failed to suspend '{}': {:?}", dev.name(), e);
+                    }
+                }
+                Some(us) => {
+                    next = Some(next.map_or(us, |n: u64| n.min(us)));
+                    waiting.push(weak);
+                }
+            }
+        }
+        if !waiting.is_empty() {
+            let mut devices = self.devices.lock_irqsave();
+            for weak in waiting {
+                if !devices.iter().any(|d| Weak::ptr_eq(d, &weak)) {
+                    devices.push(weak);
+                }
+            }
+        }
+        next
+    }
+
+    /// 等待有设备被加入队列
+    fn wait_for_work(&self) {
+        let devices = self.devices.lock_irqsave();
+        if devices.is_empty() {
+            self.wait_queue.sleep_unlock_spinlock(devices);
+        }
+    }
+}
+
+#[unified_init(INITCALL_LATE)]
+fn pm_runtime_init() -> Result<(), SystemError> {
+    let closure =
+        KernelThreadClosure::StaticEmptyClosure((&(pm_runtime_thread as fn() -> i32), ()));
+    KernelThreadMechanism::create_and_run(closure, "pm_runtime".to_string())
+        .ok_or(SystemError::EPERM)?;
+    return Ok(());
+}
+
+fn pm_runtime_thread() -> i32 {
+    loop {
+        match RPM_AUTOSUSPEND.run() {
+            Some(us) => {
+                let us = us as i64;
+                nanosleep(PosixTimeSpec::new(us / 1_000_000, (us % 1_000_000) * 1000)).ok();
+            }
+            None => RPM_AUTOSUSPEND.wait_for_work(),
+        }
+    }
+}
+
+/// # 函数的功能
+/// 修改设备的`power/control`
+pub fn pm_runtime_set_control(
+    dev: &Arc<dyn Device>,
+    control: RpmControl,
+) -> Result<(), SystemError> {
+    let pm = dev_pm_runtime(dev)?;
+    pm.set_control_with(control, || rpm_resume(dev))?;
+    // 设备没有被使用时，从现在开始计算空闲时间
+    if control == RpmControl::Auto && pm.usage_count() == 0 {
+        pm.mark_last_busy(Instant::now().total_micros() as u64);
+        RPM_AUTOSUSPEND.push(Arc::downgrade(dev));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_control_parse() {
+        assert_eq!("on\n".parse::<RpmControl>(), Ok(RpmControl::On));
+        assert_eq!("auto".parse::<RpmControl>(), Ok(RpmControl::Auto));
+        assert_eq!("off".parse::<RpmControl>(), Err(SystemError::EINVAL));
+        assert_eq!(format!("{}", RpmControl::Auto), "auto");
+    }
+
+    #[test]
+    fn test_toggle_control() {
+        let pm = DevPmRuntime::new();
+        let suspends = Cell::new(0);
+        let resumes = Cell::new(0);
+        let suspend = || {
+            suspends.set(suspends.get() + 1);
+            Ok(())
+        };
+        let resume = || {
+            resumes.set(resumes.get() + 1);
+            Ok(())
+        };
+
+        // 默认为on，空闲时不会挂起
+        assert_eq!(pm.control(), RpmControl::On);
+        assert_eq!(pm.idle_with(suspend), Ok(false));
+        assert_eq!(suspends.get(), 0);
+
+        // 改为auto之后，空闲时挂起，有操作时恢复
+        pm.set_control_with(RpmControl::Auto, resume).unwrap();
+        assert_eq!(pm.idle_with(suspend), Ok(true));
+        assert_eq!(pm.status(), RpmStatus::Suspended);
+        assert_eq!(pm.idle_with(suspend), Ok(false));
+        assert_eq!(suspends.get(), 1);
+        assert_eq!(pm.resume_with(resume), Ok(true));
+        assert_eq!(pm.resume_with(resume), Ok(false));
+        assert_eq!(resumes.get(), 1);
+
+        // 挂起时改回on，设备被恢复
+        assert_eq!(pm.idle_with(suspend), Ok(true));
+        pm.set_control_with(RpmControl::On, resume).unwrap();
+        assert_eq!(pm.status(), RpmStatus::Active);
+        assert_eq!((suspends.get(), resumes.get()), (2, 2));
+    }
+
+    #[test]
+    fn test_no_autosuspend_while_in_use() {
+        let pm = DevPmRuntime::new();
+        pm.set_control_with(RpmControl::Auto, || Ok(())).unwrap();
+        pm.set_autosuspend_delay_ms(10);
+        let suspends = Cell::new(0);
+        let suspend = || {
+            suspends.set(suspends.get() + 1);
+            Ok(())
+        };
+
+        // 两个未完成的操作
+        pm.get_noresume();
+        pm.get_noresume();
+        pm.mark_last_busy(0);
+        assert_eq!(pm.autosuspend_remaining_us(1_000_000), None);
+        assert_eq!(pm.idle_with(suspend), Ok(false));
+        assert!(!pm.put_noidle());
+        assert_eq!(pm.autosuspend_remaining_us(1_000_000), None);
+        assert_eq!(pm.idle_with(suspend), Ok(false));
+        assert_eq!(suspends.get(), 0);
+
+        // 计数回到0之后，还要空闲超过延迟才会被挂起
+        assert!(pm.put_noidle());
+        assert_eq!(pm.usage_count(), 0);
+        pm.mark_last_busy(1_000_000);
+        assert_eq!(pm.autosuspend_remaining_us(1_004_000), Some(6_000));
+        assert_eq!(pm.autosuspend_remaining_us(1_010_000), Some(0));
+        assert_eq!(pm.idle_with(suspend), Ok(true));
+        assert_eq!(suspends.get(), 1);
+        assert_eq!(pm.autosuspend_remaining_us(2_000_000), None);
+
+        // 计数不会小于0
+        assert!(!pm.put_noidle());
+        assert_eq!(pm.usage_count(), 0);
+    }
+
+    #[test]
+    fn test_failed_callbacks() {
+        let pm = DevPmRuntime::new();
+        pm.set_control_with(RpmControl::Auto, || Ok(())).unwrap();
+        assert_eq!(
+            pm.idle_with(|| Err(SystemError::EBUSY)),
+            Err(SystemError::EBUSY)
+        );
+        assert_eq!(pm.status(), RpmStatus::Active);
+
+        pm.idle_with(|| Ok(())).unwrap();
+        assert_eq!(
+            pm.resume_with(|| Err(SystemError::EIO)),
+            Err(SystemError::EIO)
+        );
+        assert_eq!(pm.status(), RpmStatus::Suspended);
+        assert!(pm.may_be_suspended());
+    }
+
+    #[test]
+    fn test_resume_fast_path() {
+        let pm = DevPmRuntime::new();
+        // 设备处于工作状态时不需要获取锁
+        let guard = pm.inner.lock();
+        assert!(!pm.may_be_suspended());
+        assert_eq!(pm.resume_with(|| panic!("device is active")), Ok(false));
+        drop(guard);
+
+        pm.set_control_with(RpmControl::Auto, || Ok(())).unwrap();
+        assert_eq!(pm.idle_with(|| Ok(())), Ok(true));
+        assert!(pm.may_be_suspended());
+        assert_eq!(pm.resume_with(|| Ok(())), Ok(true));
+        assert!(!pm.may_be_suspended());
+
+        // 挂起失败、或者挂起时设备正在被使用，设备仍然走快速路径
+        assert_eq!(
+            pm.idle_with(|| Err(SystemError::EBUSY)),
+            Err(SystemError::EBUSY)
+        );
+        assert!(!pm.may_be_suspended());
+        pm.get_noresume();
+        assert_eq!(pm.idle_with(|| panic!("device is in use")), Ok(false));
+        assert!(!pm.may_be_suspended());
+    }
+}
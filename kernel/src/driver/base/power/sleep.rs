@@ -0,0 +1,148 @@
+//! 系统睡眠时所有设备的挂起与恢复
+//!
+//! 设备加入设备模型时被加入dpm列表。父设备总是先于子设备加入设备模型，
+//! 因此按照列表的逆序挂起设备，子设备总是在父设备之前被挂起；恢复时顺序相反，父设备先于子设备恢复。
+//!
+//! 挂起与恢复通过设备所在总线的`suspend()`/`resume()`完成，例如PCI总线会调用驱动的回调、
+//! 保存配置空间并切换设备的电源状态，virtio总线会让设备停止访问内存。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/power/main.c
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use log::warn;
+use system_error::SystemError;
+
+use crate::{
+    driver::base::device::Device,
+    libs::{mutex::Mutex, spinlock::SpinLock},
+};
+
+static DPM_MANAGER: DpmManager = DpmManager::new();
+
+#[inline(always)]
+pub fn dpm_manager() -> &'static DpmManager {
+    &DPM_MANAGER
+}
+
+/// # 结构功能
+/// 记录设备的加入顺序，并按照这个顺序挂起和恢复设备
+#[derive(Debug)]
+pub struct DpmManager {
+    /// 按照加入设备模型的顺序排列的设备
+    devices: SpinLock<Vec<Weak<dyn Device>>>,
+    /// 已经被挂起的设备，按照挂起的顺序排列。为None时系统没有处于挂起状态
+    ///
+    /// 挂起与恢复可能睡眠，因此使用互斥锁，它同时保证挂起与恢复不会同时进行
+    suspended: Mutex<Option<Vec<Arc<dyn Device>>>>,
+}
+
+impl DpmManager {
+    const fn new() -> Self {
+        Self {
+            devices: SpinLock::new(Vec::new()),
+            suspended: Mutex::new(None),
+        }
+    }
+
+    /// 设备加入设备模型时调用
+    pub fn add(&self, dev: &Arc<dyn Device>) {
+        self.devices.lock_irqsave().push(Arc::downgrade(dev));
+    }
+
+    /// 设备从设备模型中移除时调用。正在挂起的设备在恢复时会被跳过
+    pub fn remove(&self, dev: &Arc<dyn Device>) {
+        let dev = Arc::downgrade(dev);
+        self.devices
+            .lock_irqsave()
+            .retain(|d| d.strong_count() > 0 && !Weak::ptr_eq(d, &dev));
+    }
+
+    /// # 函数的功能
+    /// 挂起所有设备，子设备先于父设备被挂起
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EBUSY): 已经处于挂起状态
+    #[allow(dead_code)]
+    pub fn suspend(&self) -> Result<(), SystemError> {
+        self.suspend_matching(|_| true)
+    }
+
+    /// # 函数的功能
+    /// 按照与[`Self::suspend`]相同的顺序，只挂起满足`filter`的设备
+    ///
+    /// 测试可以用它只挂起自己注册的设备，而不影响正在使用的真实设备
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EBUSY): 已经处于挂起状态
+    pub fn suspend_matching<F>(&self, filter: F) -> Result<(), SystemError>
+    where
+        F: Fn(&Arc<dyn Device>) -> bool,
+    {
+        let mut suspended = self.suspended.lock();
+        if suspended.is_some() {
+            return Err(SystemError::EBUSY);
+        }
+        // 总线的回调中可能注册或者移除设备，不能持有列表的锁调用它们
+        let devices: Vec<Arc<dyn Device>> = self
+            .devices
+            .lock_irqsave()
+            .iter()
+            .filter_map(|d| d.upgrade())
+            .collect();
+
+        let mut list = Vec::new();
+        for dev in devices.into_iter().rev() {
+            if dev.is_dead() || !filter(&dev) {
+                continue;
+            }
+            let Some(bus) = dev.bus().and_then(|bus| bus.upgrade()) else {
+                continue;
+            };
+            bus.suspend(&dev);
+            list.push(dev);
+        }
+        *suspended = Some(list);
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 恢复被挂起的设备，父设备先于子设备恢复
+    ///
+    /// 某个设备恢复失败时继续恢复其他的设备
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): 没有处于挂起状态
+    /// - Err(e): 第一个恢复失败的设备的错误
+    pub fn resume(&self) -> Result<(), SystemError> {
+        // 恢复完成之前一直持有锁，否则并发的suspend会在设备恢复之前再次挂起它们
+        let mut suspended = self.suspended.lock();
+        let list = suspended.take().ok_or(SystemError::EINVAL)?;
+        let mut result = Ok(());
+        for dev in list.iter().rev() {
+            // 挂起期间被移除的设备
+            if dev.is_dead() {
+                continue;
+            }
+            let Some(bus) = dev.bus().and_then(|bus| bus.upgrade()) else {
+                continue;
+            };
+            if let Err(e) = bus.resume(dev) {
+                warn!("dpm: failed to resume device '{}': {:?}", dev.name(), e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        drop(suspended);
+        result
+    }
+
+    /// 是否处于挂起状态
+    #[allow(dead_code)]
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.lock().is_some()
+    }
+}
@@ -0,0 +1,102 @@
+//! 设备的`power`目录
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/base/power/sysfs.c
+
+use alloc::{format, sync::Arc};
+use intertrait::cast::CastArc;
+use system_error::SystemError;
+
+use crate::{
+    driver::base::{device::Device, kobject::KObject},
+    filesystem::{
+        sysfs::{
+            file::sysfs_emit_str, Attribute, AttributeGroup, SysFSOpsSupport, SYSFS_ATTR_MODE_RO,
+            SYSFS_ATTR_MODE_RW,
+        },
+        vfs::syscall::ModeType,
+    },
+};
+
+use super::runtime::{pm_runtime_set_control, RpmControl};
+
+/// 设备的`power`目录，只有支持运行时电源管理的设备才有
+#[derive(Debug)]
+pub struct DevPowerAttrGroup;
+
+impl AttributeGroup for DevPowerAttrGroup {
+    fn name(&self) -> Option<&str> {
+        Some("power")
+    }
+
+    fn attrs(&self) -> &[&'static dyn Attribute] {
+        &[&AttrControl, &AttrRuntimeUsage]
+    }
+
+    fn is_visible(
+        &self,
+        _kobj: Arc<dyn KObject>,
+        attr: &'static dyn Attribute,
+    ) -> Option<ModeType> {
+        Some(attr.mode())
+    }
+}
+
+fn kobj_to_device(kobj: Arc<dyn KObject>) -> Result<Arc<dyn Device>, SystemError> {
+    kobj.cast::<dyn Device>().map_err(|_| SystemError::EINVAL)
+}
+
+/// 是否允许设备空闲时被自动挂起，可以写入`on`或者`auto`
+#[derive(Debug)]
+struct AttrControl;
+
+impl Attribute for AttrControl {
+    fn name(&self) -> &str {
+        "control"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RW
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW | SysFSOpsSupport::ATTR_STORE
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj_to_device(kobj)?;
+        let pm = dev.pm_runtime().ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)?;
+        return sysfs_emit_str(buf, &format!("{}\n", pm.control()));
+    }
+
+    fn store(&self, kobj: Arc<dyn KObject>, buf: &[u8]) -> Result<usize, SystemError> {
+        let dev = kobj_to_device(kobj)?;
+        let s = core::str::from_utf8(buf).map_err(|_| SystemError::EINVAL)?;
+        let control = s.parse::<RpmControl>()?;
+        pm_runtime_set_control(&dev, control)?;
+        return Ok(buf.len());
+    }
+}
+
+/// 设备的运行时电源管理使用计数，不为0时设备不会被自动挂起
+#[derive(Debug)]
+struct AttrRuntimeUsage;
+
+impl Attribute for AttrRuntimeUsage {
+    fn name(&self) -> &str {
+        "runtime_usage"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj_to_device(kobj)?;
+        let pm = dev.pm_runtime().ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)?;
+        return sysfs_emit_str(buf, &format!("{}\n", pm.usage_count()));
+    }
+}
@@ -190,7 +190,6 @@ impl SubSysPrivate {
         return Ok(());
     }
 
-    #[allow(dead_code)]
     pub fn remove_device_from_vec(&self, device: &Arc<dyn Device>) {
         let mut devices = self.devices.write();
         let index = devices.iter().position(|d| Arc::ptr_eq(d, device));
@@ -1,2 +1,3 @@
 pub mod cache;
 pub mod virtio_blk;
+mod virtio_blk_queue;
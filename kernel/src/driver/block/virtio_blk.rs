@@ -1,23 +1,35 @@
-use core::{any::Any, fmt::Debug};
+use core::{
+    any::Any,
+    fmt::Debug,
+    hint::spin_loop,
+    mem::offset_of,
+    ops::ControlFlow,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 use alloc::{
-    collections::LinkedList,
+    boxed::Box,
+    collections::{BTreeMap, LinkedList},
     string::{String, ToString},
     sync::{Arc, Weak},
     vec::Vec,
 };
-use bitmap::traits::BitMapOps;
-use log::error;
+use log::{error, warn};
 use system_error::SystemError;
 use unified_init::macros::unified_init;
-use virtio_drivers::device::blk::{VirtIOBlk, SECTOR_SIZE};
+use virtio_drivers::{transport::Transport, Hal};
 
 use crate::{
+    arch::CurrentIrqArch,
     driver::{
         base::{
             block::{
                 block_device::{BlockDevName, BlockDevice, BlockId, GeneralBlockRange, LBA_SIZE},
+                class::sys_class_block_instance,
                 disk_info::Partition,
+                io_request::{
+                    BlockDeviceAsyncIo, BlockIoCallback, BlockIoQueueFull, BlockIoRequest,
+                },
                 manager::{block_dev_manager, BlockDevMeta},
             },
             class::Class,
@@ -28,22 +40,48 @@ use crate::{
             },
             kobject::{KObjType, KObject, KObjectCommonData, KObjectState, LockedKObjectState},
             kset::KSet,
+            power::runtime::{pm_runtime_get, pm_runtime_put, DevPmRuntime},
         },
         virtio::{
+            queue_full::{VirtQueueFullPolicy, VirtQueueFullWait},
+            reset::{virtio_reset_device, virtio_status_driver_ok},
+            ring::VirtQueueSizePolicy,
+            router::virtio_device_router,
             sysfs::{virtio_bus, virtio_device_manager, virtio_driver_manager},
-            transport::VirtIOTransport,
-            virtio_impl::HalImpl,
+            teardown::{
+                virtio_quiesce_queues, virtio_teardown_queues, VirtQueueOwner,
+                VIRTIO_TEARDOWN_MAX_POLLS,
+            },
+            transport::{VirtIOIrqType, VirtIOIsr, VirtIOTransport, VIRTIO_F_VERSION_1},
             VirtIODevice, VirtIODeviceIndex, VirtIODriver, VirtIODriverCommonData, VirtioDeviceId,
-            VIRTIO_VENDOR_ID,
+            VirtioDeviceType, VIRTIO_VENDOR_ID,
+        },
+    },
+    exception::{irqdesc::IrqReturn, InterruptArch, IrqNumber},
+    filesystem::{
+        kernfs::KernFSInode,
+        mbr::MbrDiskPartionTable,
+        sysfs::{
+            file::sysfs_emit_str, Attribute, AttributeGroup, SysFSOpsSupport, SYSFS_ATTR_MODE_RO,
         },
+        vfs::syscall::ModeType,
     },
-    exception::{irqdesc::IrqReturn, IrqNumber},
-    filesystem::{kernfs::KernFSInode, mbr::MbrDiskPartionTable},
-    init::initcall::INITCALL_POSTCORE,
+    init::{cmdline::KernelCmdlineParameter, initcall::INITCALL_POSTCORE},
     libs::{
+        ida::Ida,
+        mutex::Mutex,
         rwlock::{RwLockReadGuard, RwLockWriteGuard},
         spinlock::{SpinLock, SpinLockGuard},
+        wait_queue::WaitQueue,
     },
+    time::{
+        timer::{next_n_ms_timer_jiffies, schedule_timeout, Jiffies, Timer, TimerFunction},
+        Duration, Instant,
+    },
+};
+
+use super::virtio_blk_queue::{
+    VirtIOBlkQueue, VirtIOBlkReqBuf, VirtIOBlkRing, VirtIOBlkStatus, SECTOR_SIZE, VIRTIO_BLK_F_RO,
 };
 
 const VIRTIO_BLK_BASENAME: &str = "virtio_blk";
@@ -70,16 +108,17 @@ pub fn virtio_blk(
     transport: VirtIOTransport,
     dev_id: Arc<DeviceId>,
     dev_parent: Option<Arc<dyn Device>>,
-) {
-    let device = VirtIOBlkDevice::new(transport, dev_id);
-    if let Some(device) = device {
-        if let Some(dev_parent) = dev_parent {
-            device.set_dev_parent(Some(Arc::downgrade(&dev_parent)));
-        }
-        virtio_device_manager()
-            .device_add(device.clone() as Arc<dyn VirtIODevice>)
-            .expect("Add virtio blk failed");
-    }
+) -> Result<Arc<dyn Device>, SystemError> {
+    let device = VirtIOBlkDevice::new(transport, dev_id).ok_or(SystemError::EIO)?;
+    if let Some(dev_parent) = dev_parent {
+        device.set_dev_parent(Some(Arc::downgrade(&dev_parent)));
+    }
+    // 设备出现在/sys/class/block下，用户态通过它枚举块设备
+    device.set_class(Some(Arc::downgrade(
+        &(sys_class_block_instance().clone() as Arc<dyn Class>),
+    )));
+    virtio_device_manager().device_add(device.clone() as Arc<dyn VirtIODevice>)?;
+    Ok(device)
 }
 
 static mut VIRTIOBLK_MANAGER: Option<VirtIOBlkManager> = None;
@@ -98,11 +137,11 @@ fn virtioblk_manager_init() -> Result<(), SystemError> {
 }
 
 pub struct VirtIOBlkManager {
+    ida: Ida,
     inner: SpinLock<InnerVirtIOBlkManager>,
 }
 
 struct InnerVirtIOBlkManager {
-    id_bmp: bitmap::StaticBitmap<{ VirtIOBlkManager::MAX_DEVICES }>,
     devname: [Option<BlockDevName>; VirtIOBlkManager::MAX_DEVICES],
 }
 
@@ -111,8 +150,8 @@ impl VirtIOBlkManager {
 
     pub fn new() -> Self {
         Self {
+            ida: Ida::new(Self::MAX_DEVICES),
             inner: SpinLock::new(InnerVirtIOBlkManager {
-                id_bmp: bitmap::StaticBitmap::new(),
                 devname: [const { None }; Self::MAX_DEVICES],
             }),
         }
@@ -123,11 +162,9 @@ impl VirtIOBlkManager {
     }
 
     pub fn alloc_id(&self) -> Option<BlockDevName> {
-        let mut inner = self.inner();
-        let idx = inner.id_bmp.first_false_index()?;
-        inner.id_bmp.set(idx, true);
+        let idx = self.ida.alloc().ok()?;
         let name = Self::format_name(idx);
-        inner.devname[idx] = Some(name.clone());
+        self.inner().devname[idx] = Some(name.clone());
         Some(name)
     }
 
@@ -141,8 +178,8 @@ impl VirtIOBlkManager {
         if id >= Self::MAX_DEVICES {
             return;
         }
-        self.inner().id_bmp.set(id, false);
         self.inner().devname[id] = None;
+        self.ida.free(id);
     }
 }
 
@@ -153,7 +190,27 @@ impl VirtIOBlkManager {
 pub struct VirtIOBlkDevice {
     blkdev_meta: BlockDevMeta,
     dev_id: Arc<DeviceId>,
+    irq_type: VirtIOIrqType,
+    isr: Option<VirtIOIsr>,
+    limits: VirtIOBlkLimits,
+    retry_policy: VirtIOBlkRetryPolicy,
     inner: SpinLock<InnerVirtIOBlkDevice>,
+    /// virtqueue已满时，提交者在这里等待描述符被释放
+    queue_space_wait: WaitQueue,
+    /// virtqueue已满时，阻塞的提交者先自旋还是直接睡眠
+    queue_full_policy: SpinLock<VirtQueueFullPolicy>,
+    /// 设备已经被移除（例如被热拔出）
+    dead: AtomicBool,
+    /// 串行化复位以及重新初始化
+    reset_lock: Mutex<()>,
+    /// 正在复位设备，此时不再接收新的请求
+    quiescing: AtomicBool,
+    /// 挂起时设备被复位，恢复时需要重新初始化
+    suspend_reset: AtomicBool,
+    /// 中断处理时是否合并完成的请求，见[`virtio_blk_drain_used`]
+    irq_coalesce: bool,
+    irq_stats: VirtIOBlkIrqStats,
+    pm_runtime: DevPmRuntime,
     locked_kobj_state: LockedKObjectState,
     self_ref: Weak<Self>,
 }
@@ -162,24 +219,44 @@ unsafe impl Send for VirtIOBlkDevice {}
 unsafe impl Sync for VirtIOBlkDevice {}
 
 impl VirtIOBlkDevice {
-    pub fn new(transport: VirtIOTransport, dev_id: Arc<DeviceId>) -> Option<Arc<Self>> {
-        let devname = virtioblk_manager().alloc_id()?;
-        let irq = transport.irq().map(|irq| IrqNumber::new(irq.data()));
-        let device_inner = VirtIOBlk::<HalImpl, VirtIOTransport>::new(transport);
-        if let Err(e) = device_inner {
-            error!("VirtIOBlkDevice '{dev_id:?}' create failed: {:?}", e);
+    pub fn new(mut transport: VirtIOTransport, dev_id: Arc<DeviceId>) -> Option<Arc<Self>> {
+        let limits = VirtIOBlkLimits::from_transport(&mut transport);
+        if let Err(e) = limits.validate() {
+            error!(
+                "VirtIOBlkDevice '{:?}': unsupported request limits {:?}: {:?}",
+                dev_id, limits, e
+            );
             return None;
         }
-
-        let mut device_inner: VirtIOBlk<HalImpl, VirtIOTransport> = device_inner.unwrap();
-        device_inner.enable_interrupts();
+        let devname = virtioblk_manager().alloc_id()?;
+        let irq = transport.irq().map(|irq| IrqNumber::new(irq.data()));
+        let irq_type = transport.irq_type();
+        let isr = transport.isr();
+        let ctrl_transport = transport.try_clone();
+        let (device_inner, capacity) = Self::init_device(transport, &dev_id)?;
         let dev = Arc::new_cyclic(|self_ref| Self {
             blkdev_meta: BlockDevMeta::new(devname),
             self_ref: self_ref.clone(),
             dev_id,
+            irq_type,
+            isr,
+            limits,
+            retry_policy: VirtIOBlkRetryPolicy::from_cmdline(),
+            queue_space_wait: WaitQueue::default(),
+            queue_full_policy: SpinLock::new(VirtQueueFullPolicy::default()),
+            dead: AtomicBool::new(false),
             locked_kobj_state: LockedKObjectState::default(),
+            reset_lock: Mutex::new(()),
+            quiescing: AtomicBool::new(false),
+            suspend_reset: AtomicBool::new(false),
+            irq_coalesce: virtio_blk_param(&VIRTIO_BLK_IRQ_COALESCE_PARAM).unwrap_or(true),
+            irq_stats: VirtIOBlkIrqStats::new(),
+            pm_runtime: DevPmRuntime::new(),
             inner: SpinLock::new(InnerVirtIOBlkDevice {
-                device_inner,
+                device_inner: Some(device_inner),
+                ctrl_transport,
+                capacity,
+                requests: VirtIOBlkRequests::default(),
                 name: None,
                 virtio_index: None,
                 device_common: DeviceCommonData::default(),
@@ -191,8 +268,1131 @@ impl VirtIOBlkDevice {
         Some(dev)
     }
 
+    /// 初始化设备，返回设备的请求队列以及设备的容量（扇区数）
+    fn init_device(
+        transport: VirtIOTransport,
+        dev_id: &Arc<DeviceId>,
+    ) -> Option<(VirtIOBlkQueue, u64)> {
+        let Some(capacity) = virtio_blk_read_capacity(&transport) else {
+            error!("VirtIOBlkDevice '{dev_id:?}': failed to read capacity");
+            return None;
+        };
+        let device_inner = VirtIOBlkQueue::new(
+            transport,
+            VIRTIO_BLK_QUEUE,
+            &VIRTIO_BLK_QUEUE_POLICY,
+            VIRTIO_BLK_SUPPORTED_FEATURES,
+        )
+        .map_err(|e| {
+            error!("VirtIOBlkDevice '{dev_id:?}' create failed: {:?}", e);
+        })
+        .ok()?;
+        Some((device_inner, capacity))
+    }
+
+    /// 中断处理函数也会访问virtqueue，因此这里需要关中断加锁
     fn inner(&self) -> SpinLockGuard<InnerVirtIOBlkDevice> {
-        self.inner.lock()
+        self.inner.lock_irqsave()
+    }
+
+    /// 提交一个块设备请求，并等待它完成
+    ///
+    /// ## 参数
+    ///
+    /// - `block_id`: 起始扇区号
+    /// - `buf`: 数据缓冲区，在请求完成之前调用者会一直阻塞，因此缓冲区的生命周期覆盖整个请求
+    fn submit_and_wait(&self, block_id: usize, buf: VirtIOBlkBuf) -> Result<(), SystemError> {
+        let request = self.submit_request(block_id, buf, None, BlockIoQueueFull::Wait(None))?;
+        return self.wait_request(&request);
+    }
+
+    /// 提交一个块设备请求，不等待它完成
+    ///
+    /// 多个请求可以同时在virtqueue中处理，每个请求根据描述符链的头部索引被单独跟踪，
+    /// 由中断处理函数（或轮询）在请求完成后结束对应的[`BlockIoRequest`]。
+    ///
+    /// 超过设备单个请求限制（size_max/seg_max）的请求会被拆分成多个设备请求，
+    /// 所有分片完成后请求才算完成，结果为第一个失败分片的错误。
+    ///
+    /// virtqueue已满时，第一个分片按照`full`处理。之后的分片无法撤回已经提交的分片，
+    /// 因此`full`为[`BlockIoQueueFull::NoWait`]时，它们会一直等待
+    ///
+    /// ## 返回值
+    ///
+    /// - Ok(request): 至少有一个分片被提交，后续分片提交失败的错误通过request返回
+    /// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): virtqueue已满，并且`full`为NoWait
+    /// - Err(e): 没有任何分片被提交，此时不会调用`callback`
+    ///
+    /// 请求未完成期间持有设备的运行时电源管理使用计数，设备不会被自动挂起
+    fn submit_request(
+        &self,
+        block_id: usize,
+        buf: VirtIOBlkBuf,
+        callback: Option<BlockIoCallback>,
+        full: BlockIoQueueFull,
+    ) -> Result<Arc<BlockIoRequest>, SystemError> {
+        let dev = self.self_ref.upgrade().unwrap() as Arc<dyn Device>;
+        pm_runtime_get(&dev)?;
+        let pm_dev = dev.clone();
+        let callback: BlockIoCallback = Box::new(move |r| {
+            pm_runtime_put(&pm_dev);
+            if let Some(callback) = callback {
+                callback(r);
+            }
+        });
+
+        let chunks = virtio_blk_split_request(block_id, buf.len(), self.limits.max_request_bytes());
+        let request = BlockIoRequest::new(chunks.len(), Some(callback));
+        for (i, chunk) in chunks.iter().enumerate() {
+            let full = match full {
+                BlockIoQueueFull::NoWait if i > 0 => BlockIoQueueFull::Wait(None),
+                full => full,
+            };
+            let chunk_buf = buf.slice(chunk.offset, chunk.len);
+            let r = match full {
+                BlockIoQueueFull::NoWait => self.try_submit(chunk.block_id, chunk_buf, &request),
+                BlockIoQueueFull::Wait(timeout) => {
+                    self.submit(chunk.block_id, chunk_buf, &request, timeout)
+                }
+            };
+            if let Err(e) = r {
+                if i == 0 {
+                    // 请求不会完成，回调也不会被调用
+                    pm_runtime_put(&dev);
+                    return Err(e);
+                }
+                // 已提交的分片仍在使用调用者的缓冲区，请求要等它们完成后才能结束
+                for _ in i..chunks.len() {
+                    request.complete(Err(e.clone()));
+                }
+                break;
+            }
+        }
+        return Ok(request);
+    }
+
+    /// 尝试把一个设备请求放入virtqueue，virtqueue已满时不等待
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): virtqueue已满，有请求完成、描述符被释放之后可以重试
+    fn try_submit(
+        &self,
+        block_id: usize,
+        buf: VirtIOBlkBuf,
+        request: &Arc<BlockIoRequest>,
+    ) -> Result<(), SystemError> {
+        self.try_submit_locked(&mut self.inner(), block_id, buf, request)
+    }
+
+    fn try_submit_locked(
+        &self,
+        inner: &mut InnerVirtIOBlkDevice,
+        block_id: usize,
+        buf: VirtIOBlkBuf,
+        request: &Arc<BlockIoRequest>,
+    ) -> Result<(), SystemError> {
+        let device_inner = self.submit_target(&mut inner.device_inner)?;
+        // 请求头和状态字节放在堆上，保证在请求完成前地址不变
+        let io = Box::new(VirtIOBlkRequestIo {
+            block_id,
+            req: VirtIOBlkReqBuf::default(),
+            buf,
+        });
+        inner
+            .requests
+            .queue(device_inner, io, request, &self.dev_id)
+    }
+
+    /// 检查设备当前能否接收请求，返回用于提交请求的设备
+    fn submit_target<'a>(
+        &self,
+        device_inner: &'a mut Option<VirtIOBlkQueue>,
+    ) -> Result<&'a mut VirtIOBlkQueue, SystemError> {
+        if self.is_dead() {
+            return Err(SystemError::ENODEV);
+        }
+        if self.quiescing.load(Ordering::SeqCst) {
+            return Err(SystemError::EIO);
+        }
+        // 设备已经通过sysfs被复位时为None
+        device_inner.as_mut().ok_or(SystemError::EIO)
+    }
+
+    /// 重新提交等待时间已经到达的重试请求
+    ///
+    /// 由重试定时器调用。轮询设备时定时器不一定能运行（例如处于关中断上下文），
+    /// 因此回收请求时也会调用它
+    fn resubmit_retries(&self) {
+        let mut guard = self.inner();
+        let inner = &mut *guard;
+        let target = self.submit_target(&mut inner.device_inner);
+        let (failed, queue_full) = inner
+            .requests
+            .resubmit(target, Instant::now(), &self.dev_id);
+        drop(guard);
+
+        for (request, e) in failed {
+            request.complete(Err(e));
+        }
+        if queue_full {
+            // 有请求完成、描述符被释放之后再试
+            self.arm_retry_timer(Duration::from_millis(1));
+        }
+    }
+
+    /// 在`delay`之后重新提交等待重试的请求
+    ///
+    /// 定时器中会获取设备的锁，因此不能在持有设备的锁时调用
+    fn arm_retry_timer(&self, delay: Duration) {
+        let timer = Timer::new(
+            Box::new(VirtIOBlkRetryTimer(self.self_ref.clone())),
+            next_n_ms_timer_jiffies(delay.total_millis().max(1)),
+        );
+        timer.activate();
+    }
+
+    /// 把一个设备请求放入virtqueue，设备完成该请求后结束`request`的一个部分
+    ///
+    /// virtqueue已满时按照设备的[`VirtQueueFullPolicy`]先自旋或者直接睡眠，
+    /// 直到完成中断释放了描述符（没有中断可用时轮询设备）
+    ///
+    /// ## 参数
+    ///
+    /// - `timeout`: 最多等待virtqueue空间的时间，None表示一直等待
+    ///
+    /// ## 返回值
+    ///
+    /// - Err(SystemError::ETIMEDOUT): 等待超时，请求没有被提交
+    fn submit(
+        &self,
+        block_id: usize,
+        buf: VirtIOBlkBuf,
+        request: &Arc<BlockIoRequest>,
+        timeout: Option<Duration>,
+    ) -> Result<(), SystemError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut full_wait = VirtQueueFullWait::new(*self.queue_full_policy.lock_irqsave());
+        loop {
+            let mut inner = self.inner();
+            match self.try_submit_locked(&mut inner, block_id, buf, request) {
+                Err(SystemError::EAGAIN_OR_EWOULDBLOCK) => {}
+                r => return r,
+            }
+
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(SystemError::ETIMEDOUT);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            if self.use_polling(&inner) {
+                drop(inner);
+                self.reap_completions();
+                spin_loop();
+                continue;
+            }
+            if full_wait.should_spin(Instant::now().total_micros() as u64) {
+                // 释放锁，让完成中断的处理函数可以回收描述符
+                drop(inner);
+                spin_loop();
+                continue;
+            }
+            match remaining {
+                None => self
+                    .queue_space_wait
+                    .sleep_uninterruptible_unlock_spinlock(inner),
+                Some(remaining) => {
+                    // 释放锁之前加入等待队列，完成中断的唤醒不会丢失
+                    unsafe {
+                        self.queue_space_wait
+                            .sleep_without_schedule_uninterruptible()
+                    };
+                    drop(inner);
+                    schedule_timeout(Jiffies::from(remaining).data().max(1) as i64)?;
+                }
+            }
+        }
+    }
+
+    /// 将设备标记为已移除，并以ENODEV结束所有尚未完成的请求
+    ///
+    /// 设备被拔出之后不会再产生完成中断，如果不这样做，等待中的提交者会永远阻塞
+    fn mark_dead(&self) {
+        let inner = self.inner();
+        self.dead.store(true, Ordering::SeqCst);
+        self.fail_inflight(inner, SystemError::ENODEV);
+    }
+
+    /// 以指定的错误结束所有尚未完成的请求，并唤醒等待virtqueue空间的提交者
+    fn fail_inflight(&self, mut inner: SpinLockGuard<InnerVirtIOBlkDevice>, err: SystemError) {
+        let requests = core::mem::take(&mut inner.requests);
+        drop(inner);
+
+        for request in requests.into_requests() {
+            request.complete(Err(err.clone()));
+        }
+        self.queue_space_wait.wakeup_all(None);
+    }
+
+    /// 设备是否已经初始化完成，并且处于DRIVER_OK状态
+    fn driver_ok(&self) -> bool {
+        let inner = self.inner();
+        if inner.device_inner.is_none() {
+            return false;
+        }
+        inner
+            .ctrl_transport
+            .as_ref()
+            .map(|t| virtio_status_driver_ok(t))
+            .unwrap_or(true)
+    }
+
+    /// # 函数的功能
+    /// 复位设备并释放virtqueue
+    ///
+    /// 先停止接收新的请求，并等待已经提交的请求完成（超时后以EIO结束它们），
+    /// 然后按照virtio规范复位设备，最后释放virtqueue
+    fn disable(&self) -> Result<(), SystemError> {
+        let _guard = self.reset_lock.lock();
+        if self.inner().ctrl_transport.is_none() {
+            return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+        }
+        match self.teardown_locked() {
+            Err(SystemError::ETIMEDOUT) => {
+                warn!(
+                    "VirtIOBlkDevice '{:?}': requests did not complete before reset",
+                    self.dev_id
+                );
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    /// 停止接收新的请求并拆除virtqueue，调用者需要持有`reset_lock`
+    fn teardown_locked(&self) -> Result<(), SystemError> {
+        if self.inner().device_inner.is_none() {
+            return Ok(());
+        }
+
+        self.quiescing.store(true, Ordering::SeqCst);
+        // 队列满而等待的提交者醒来后会看到quiescing标志并返回
+        self.queue_space_wait.wakeup_all(None);
+        virtio_teardown_queues(&mut VirtIOBlkQueues(self), VIRTIO_TEARDOWN_MAX_POLLS)
+    }
+
+    /// # 函数的功能
+    /// 重新初始化已经被复位的设备
+    fn enable(&self) -> Result<(), SystemError> {
+        let _guard = self.reset_lock.lock();
+        let transport = {
+            let inner = self.inner();
+            if inner.device_inner.is_some() {
+                return Ok(());
+            }
+            inner
+                .ctrl_transport
+                .as_ref()
+                .and_then(|t| t.try_clone())
+                .ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)?
+        };
+
+        let (device_inner, capacity) =
+            Self::init_device(transport, &self.dev_id).ok_or(SystemError::EIO)?;
+        let mut inner = self.inner();
+        inner.capacity = capacity;
+        inner.device_inner = Some(device_inner);
+        return Ok(());
+    }
+
+    /// # 函数的功能
+    /// 挂起之前停止接收新的请求并等待已经提交的请求完成，`reset`为true时再复位设备
+    ///
+    /// 不能重新初始化的设备(mmio transport)不会被复位，只等待请求完成
+    fn quiesce_for_suspend(&self, reset: bool) -> Result<(), SystemError> {
+        if self.is_dead() {
+            return Err(SystemError::ENODEV);
+        }
+        let _guard = self.reset_lock.lock();
+        // 已经通过sysfs复位的设备没有正在处理的请求
+        if self.inner().device_inner.is_none() {
+            return Ok(());
+        }
+        let reset = reset && self.inner().ctrl_transport.is_some();
+
+        self.quiescing.store(true, Ordering::SeqCst);
+        self.queue_space_wait.wakeup_all(None);
+        let r = virtio_quiesce_queues(&mut VirtIOBlkQueues(self), VIRTIO_TEARDOWN_MAX_POLLS, reset);
+        if reset {
+            self.suspend_reset.store(true, Ordering::SeqCst);
+        }
+        r
+    }
+
+    /// # 函数的功能
+    /// 恢复之后重新接收请求，挂起时被复位的设备会被重新初始化
+    fn unquiesce_after_resume(&self) -> Result<(), SystemError> {
+        if self.suspend_reset.swap(false, Ordering::SeqCst) {
+            self.enable()?;
+        }
+        self.quiescing.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// 设备的容量（扇区数）
+    ///
+    /// 能够访问设备的配置空间时重新读取，以便得到设备被调整大小之后的容量
+    fn capacity(&self) -> u64 {
+        let mut inner = self.inner();
+        if let Some(capacity) = inner
+            .ctrl_transport
+            .as_ref()
+            .and_then(virtio_blk_read_capacity)
+        {
+            inner.capacity = capacity;
+        }
+        inner.capacity
+    }
+
+    /// # 函数的功能
+    /// 自检：读取第一个以及最后一个扇区，让请求在virtqueue中完成提交与完成的往返
+    ///
+    /// 第一个扇区会被读取两次，两次的内容必须相同，以此检查数据确实经过DMA写入了缓冲区。
+    /// 自检只读取设备，不会修改设备上的数据
+    fn selftest_read(&self) -> Result<(), SystemError> {
+        if self.is_dead() || !self.driver_ok() {
+            return Err(SystemError::ENODEV);
+        }
+        let capacity = self.capacity() as usize;
+        if capacity == 0 {
+            return Err(SystemError::ENOSPC);
+        }
+
+        let mut first = [0u8; LBA_SIZE];
+        let mut again = [0xffu8; LBA_SIZE];
+        self.read_at_sync(0, 1, &mut first)?;
+        self.read_at_sync(0, 1, &mut again)?;
+        if first != again {
+            error!(
+                "VirtIOBlkDevice '{:?}' selftest: sector 0 changed between two reads",
+                self.dev_id
+            );
+            return Err(SystemError::EIO);
+        }
+        self.read_at_sync(capacity - 1, 1, &mut again)?;
+        return Ok(());
+    }
+
+    /// 没有中断可用（或者当前处于关中断上下文）时，只能通过轮询来回收请求
+    fn use_polling(&self, inner: &InnerVirtIOBlkDevice) -> bool {
+        inner.irq.is_none() || !CurrentIrqArch::is_irq_enabled()
+    }
+
+    /// 等待请求完成并返回它的结果。没有中断可用时，在等待期间轮询设备
+    fn wait_request(&self, request: &BlockIoRequest) -> Result<(), SystemError> {
+        let polling = self.use_polling(&self.inner());
+        if !polling {
+            return request.wait();
+        }
+        while !request.is_done() {
+            self.reap_completions();
+            spin_loop();
+        }
+        return request.result().unwrap();
+    }
+
+    /// 从used ring中取出所有已完成的请求，记录结果并唤醒对应的提交者
+    fn reap_completions(&self) {
+        self.reap_completions_with(false);
+    }
+
+    /// # 函数的功能
+    /// 从used ring中取出所有已完成的请求
+    ///
+    /// ## 参数
+    /// - `coalesce`: 是否在回收期间关闭设备的中断，见[`virtio_blk_drain_used`]
+    ///
+    /// ## 返回值
+    /// 回收的请求数量
+    fn reap_completions_with(&self, coalesce: bool) -> usize {
+        let mut guard = self.inner();
+        if self.is_dead() {
+            return 0;
+        }
+        let inner = &mut *guard;
+        let Some(device_inner) = inner.device_inner.as_mut() else {
+            return 0;
+        };
+        device_inner.ack_interrupt();
+
+        let reaped = inner.requests.reap(
+            device_inner,
+            coalesce,
+            &self.retry_policy,
+            Instant::now(),
+            &self.dev_id,
+        );
+        let retry_pending = !inner.requests.retrying.is_empty();
+        drop(guard);
+
+        // 请求的回调可能再次访问设备，在释放设备的锁之后再结束请求
+        for (request, r) in reaped.finished {
+            request.complete(r);
+        }
+        if reaped.reaped > 0 {
+            self.queue_space_wait.wakeup_all(None);
+        }
+        if let Some(delay) = reaped.retry_delay {
+            self.arm_retry_timer(delay);
+        }
+        if retry_pending && self.use_polling(&self.inner()) {
+            self.resubmit_retries();
+        }
+        reaped.reaped
+    }
+}
+
+/// # trait功能
+/// 中断处理时回收完成的请求所需的队列操作，抽象出来之后可以用模拟的队列测试中断合并
+trait VirtIOBlkUsedRing {
+    /// used ring中下一个已完成的请求，不会取出它
+    fn peek_used(&mut self) -> Option<u16>;
+
+    /// 让设备在请求完成时不再产生中断
+    fn disable_interrupts(&mut self);
+
+    /// 让设备在请求完成时重新产生中断
+    fn enable_interrupts(&mut self);
+
+    /// 取出used ring中驱动不认识的请求`token`并释放它的描述符，不处理它的结果
+    fn discard_used(&mut self, token: u16);
+}
+
+impl<H: Hal> VirtIOBlkUsedRing for VirtIOBlkRing<H> {
+    fn peek_used(&mut self) -> Option<u16> {
+        VirtIOBlkRing::peek_used(self)
+    }
+
+    fn disable_interrupts(&mut self) {
+        self.set_interrupts(false)
+    }
+
+    fn enable_interrupts(&mut self) {
+        self.set_interrupts(true)
+    }
+
+    fn discard_used(&mut self, token: u16) {
+        let head = self.pop_used();
+        debug_assert_eq!(head, Some(token));
+    }
+}
+
+/// # 函数的功能
+/// 回收used ring中所有已完成的请求
+///
+/// 合并中断时，回收期间关闭设备的中断，回收完之后重新打开，并再检查一次used ring：
+/// 在两者之间完成的请求不会产生中断，需要在这里回收。因此一次中断可以处理一批请求
+///
+/// ## 参数
+/// - `q`: 设备的队列
+/// - `coalesce`: 是否合并中断
+/// - `complete`: 结束一个已完成的请求并把它从used ring中取出。请求未知时返回false，
+///   由这里取出并丢弃该表项，然后继续回收，否则它会一直停在used ring的头部，之后的请求都无法完成
+///
+/// ## 返回值
+/// 从used ring中取出的表项数量（包括被丢弃的未知请求）
+fn virtio_blk_drain_used<Q: VirtIOBlkUsedRing>(
+    q: &mut Q,
+    coalesce: bool,
+    mut complete: impl FnMut(&mut Q, u16) -> bool,
+) -> usize {
+    let mut reaped = 0;
+    loop {
+        if coalesce {
+            q.disable_interrupts();
+        }
+        while let Some(token) = q.peek_used() {
+            if !complete(q, token) {
+                q.discard_used(token);
+            }
+            reaped += 1;
+        }
+        if !coalesce {
+            return reaped;
+        }
+        q.enable_interrupts();
+        if q.peek_used().is_none() {
+            return reaped;
+        }
+    }
+}
+
+/// # 结构功能
+/// 中断处理的统计，用于确认中断合并的效果
+#[derive(Debug)]
+struct VirtIOBlkIrqStats {
+    interrupts: AtomicU64,
+    completions: AtomicU64,
+}
+
+impl VirtIOBlkIrqStats {
+    const fn new() -> Self {
+        Self {
+            interrupts: AtomicU64::new(0),
+            completions: AtomicU64::new(0),
+        }
+    }
+
+    /// 记录一次中断以及其中回收的请求数量
+    fn record(&self, completions: usize) {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+        self.completions
+            .fetch_add(completions as u64, Ordering::Relaxed);
+    }
+
+    /// 格式为"中断次数 回收的请求数 平均每次中断回收的请求数"，平均数保留两位小数
+    fn format(&self) -> String {
+        let interrupts = self.interrupts.load(Ordering::Relaxed);
+        let completions = self.completions.load(Ordering::Relaxed);
+        let avg = (completions * 100).checked_div(interrupts).unwrap_or(0);
+        format!(
+            "{} {} {}.{:02}\n",
+            interrupts,
+            completions,
+            avg / 100,
+            avg % 100
+        )
+    }
+}
+
+#[derive(Debug)]
+struct VirtIOBlkAttrGroup;
+
+impl AttributeGroup for VirtIOBlkAttrGroup {
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    fn attrs(&self) -> &[&'static dyn Attribute] {
+        &[&AttrCompletionsPerIrq]
+    }
+}
+
+/// 中断次数、中断中回收的请求数以及平均每次中断回收的请求数
+#[derive(Debug)]
+struct AttrCompletionsPerIrq;
+
+impl Attribute for AttrCompletionsPerIrq {
+    fn name(&self) -> &str {
+        "completions_per_irq"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .as_any_ref()
+            .downcast_ref::<VirtIOBlkDevice>()
+            .ok_or(SystemError::EINVAL)?;
+        sysfs_emit_str(buf, &dev.irq_stats.format())
+    }
+}
+
+/// 拆除队列时对设备的操作，见[`virtio_teardown_queues`]
+struct VirtIOBlkQueues<'a>(&'a VirtIOBlkDevice);
+
+impl VirtQueueOwner for VirtIOBlkQueues<'_> {
+    fn reap(&mut self) -> usize {
+        self.0.reap_completions();
+        let inner = self.0.inner();
+        inner.requests.len()
+    }
+
+    fn reset(&mut self) -> Result<(), SystemError> {
+        let mut inner = self.0.inner();
+        // mmio transport不支持复制，释放VirtIOBlkQueue时会禁用队列
+        let Some(transport) = inner.ctrl_transport.as_mut() else {
+            return Ok(());
+        };
+        virtio_reset_device(transport).inspect_err(|e| {
+            error!(
+                "VirtIOBlkDevice '{:?}' reset failed: {:?}",
+                self.0.dev_id, e
+            );
+        })
+    }
+
+    fn free_queues(&mut self) {
+        let mut inner = self.0.inner();
+        // 设备已经不会再访问virtqueue，可以释放它们
+        let device_inner = inner.device_inner.take();
+        self.0.quiescing.store(false, Ordering::SeqCst);
+        self.0.fail_inflight(inner, SystemError::EIO);
+        drop(device_inner);
+    }
+}
+
+/// 块设备请求的数据缓冲区
+///
+/// 缓冲区由提交者持有，并在请求完成前保持有效（同步接口会阻塞等待，异步接口由调用者保证），因此这里只记录裸指针。
+#[derive(Debug, Clone, Copy)]
+enum VirtIOBlkBuf {
+    Read(*mut u8, usize),
+    Write(*const u8, usize),
+}
+
+impl VirtIOBlkBuf {
+    fn len(&self) -> usize {
+        match self {
+            VirtIOBlkBuf::Read(_, len) | VirtIOBlkBuf::Write(_, len) => *len,
+        }
+    }
+
+    /// 取缓冲区中`[offset, offset+len)`的部分
+    fn slice(&self, offset: usize, len: usize) -> Self {
+        assert!(offset + len <= self.len());
+        match *self {
+            VirtIOBlkBuf::Read(ptr, _) => VirtIOBlkBuf::Read(unsafe { ptr.add(offset) }, len),
+            VirtIOBlkBuf::Write(ptr, _) => VirtIOBlkBuf::Write(unsafe { ptr.add(offset) }, len),
+        }
+    }
+}
+
+/// 请求队列的编号
+const VIRTIO_BLK_QUEUE: u16 = 0;
+/// 请求队列的大小，设备支持的队列更小时使用设备的大小
+const VIRTIO_BLK_QUEUE_SIZE: u16 = 16;
+const VIRTIO_BLK_QUEUE_POLICY: VirtQueueSizePolicy =
+    VirtQueueSizePolicy::new(VIRTIO_BLK_QUEUE_SIZE, VIRTIO_BLK_QUEUE_SIZE);
+
+/// 支持单个请求的最大段长度（size_max字段有效）
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+/// 支持单个请求的最大段数（seg_max字段有效）
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+/// 每个设备请求使用的数据段数量：数据缓冲区总是放在同一个描述符中
+const VIRTIO_BLK_DATA_SEGMENTS_PER_REQUEST: u32 = 1;
+/// 驱动支持的特性
+const VIRTIO_BLK_SUPPORTED_FEATURES: u64 =
+    VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_RO | VIRTIO_F_VERSION_1;
+
+/// virtio blk配置空间的开头部分
+///
+/// 参考 virtio spec 5.2.4 Device configuration layout
+///
+/// 只用于计算字段在配置空间中的偏移，字段通过[`VirtIOTransport::config_read`]读取
+#[allow(dead_code)]
+#[repr(C)]
+struct VirtIOBlkConfigHead {
+    capacity_low: u32,
+    capacity_high: u32,
+    size_max: u32,
+    seg_max: u32,
+}
+
+/// 读取设备的容量（扇区数）
+///
+/// capacity是8字节的字段，需要分两次读取，设备在读取期间调整大小会导致读到新旧两个值拼接而成的结果，
+/// 因此需要通过config_generation保证读取的一致性
+fn virtio_blk_read_capacity(transport: &VirtIOTransport) -> Option<u64> {
+    transport
+        .config_read::<u64>(offset_of!(VirtIOBlkConfigHead, capacity_low))
+        .ok()
+}
+
+/// 设备对单个请求的限制
+#[derive(Debug, Clone, Copy)]
+struct VirtIOBlkLimits {
+    /// 单个数据段的最大字节数，None表示没有限制
+    size_max: Option<u32>,
+    /// 单个请求的最大数据段数量，None表示没有限制
+    seg_max: Option<u32>,
+}
+
+impl VirtIOBlkLimits {
+    /// 在transport被交给virtio-drivers之前，从配置空间中读取限制
+    fn from_transport(transport: &mut VirtIOTransport) -> Self {
+        let features = transport.read_device_features();
+        let mut limits = Self {
+            size_max: None,
+            seg_max: None,
+        };
+        let read = |offset| {
+            transport
+                .config_read::<u32>(offset)
+                .ok()
+                .filter(|v| *v != 0)
+        };
+        if features & VIRTIO_BLK_F_SIZE_MAX != 0 {
+            limits.size_max = read(offset_of!(VirtIOBlkConfigHead, size_max));
+        }
+        if features & VIRTIO_BLK_F_SEG_MAX != 0 {
+            limits.seg_max = read(offset_of!(VirtIOBlkConfigHead, seg_max));
+        }
+        limits
+    }
+
+    /// # 函数的功能
+    /// 检查驱动能否在限制之内发出请求
+    ///
+    /// 请求的长度是扇区大小的整数倍，单个数据段小于一个扇区时，任何请求都会超过size_max
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): size_max小于扇区大小
+    fn validate(&self) -> Result<(), SystemError> {
+        match self.size_max {
+            Some(size_max) if (size_max as usize) < SECTOR_SIZE => Err(SystemError::EINVAL),
+            _ => Ok(()),
+        }
+    }
+
+    /// 单个设备请求最多能够传输的字节数，不小于一个扇区（见[`Self::validate`]）
+    fn max_request_bytes(&self) -> usize {
+        let segments = self
+            .seg_max
+            .map(|s| s.clamp(1, VIRTIO_BLK_DATA_SEGMENTS_PER_REQUEST))
+            .unwrap_or(VIRTIO_BLK_DATA_SEGMENTS_PER_REQUEST) as usize;
+        let seg_bytes = self.size_max.unwrap_or(u32::MAX) as usize;
+        segments * seg_bytes
+    }
+}
+
+/// 拆分后的一个设备请求
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct VirtIOBlkChunk {
+    block_id: usize,
+    /// 在原始缓冲区中的偏移
+    offset: usize,
+    len: usize,
+}
+
+/// 把一个逻辑请求按照单个设备请求的最大字节数拆分
+///
+/// 每个分片的长度都是扇区大小的整数倍（最后一个分片除外，它与原始请求的尾部对齐），
+/// 并且不超过`max_bytes`。`max_bytes`不能小于一个扇区，否则无法拆分
+fn virtio_blk_split_request(block_id: usize, len: usize, max_bytes: usize) -> Vec<VirtIOBlkChunk> {
+    assert!(
+        max_bytes >= SECTOR_SIZE,
+        "virtio-blk: request limit {} is smaller than a sector",
+        max_bytes
+    );
+    let max_bytes = max_bytes / SECTOR_SIZE * SECTOR_SIZE;
+    let mut chunks = Vec::with_capacity(len.div_ceil(max_bytes));
+    let mut offset = 0;
+    while offset < len {
+        let chunk_len = core::cmp::min(max_bytes, len - offset);
+        chunks.push(VirtIOBlkChunk {
+            block_id: block_id + offset / SECTOR_SIZE,
+            offset,
+            len: chunk_len,
+        });
+        offset += chunk_len;
+    }
+    chunks
+}
+
+/// 请求在设备处理期间需要保持有效的内存
+struct VirtIOBlkRequestIo {
+    /// 起始扇区号，重试时用它重新构造请求
+    block_id: usize,
+    req: VirtIOBlkReqBuf,
+    buf: VirtIOBlkBuf,
+}
+
+/// 一个已经提交到virtqueue、尚未被回收的请求
+struct VirtIOBlkInflight {
+    io: Box<VirtIOBlkRequestIo>,
+    request: Arc<BlockIoRequest>,
+    /// 设备返回IOERR之后已经重试的次数
+    retries: u32,
+}
+
+/// 设备返回IOERR、等待重新提交的请求
+struct VirtIOBlkRetry {
+    inflight: VirtIOBlkInflight,
+    retry_at: Instant,
+}
+
+/// 把请求的描述符链放入virtqueue，返回描述符链的头部索引
+///
+/// ## 返回值
+/// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): virtqueue已满
+/// - Err(SystemError::EROFS): 设备只读
+fn virtio_blk_queue_io<H: Hal, T: Transport>(
+    q: &mut VirtIOBlkQueue<H, T>,
+    io: &mut VirtIOBlkRequestIo,
+    dev_id: &DeviceId,
+) -> Result<u16, SystemError> {
+    let (write, ptr, len) = match io.buf {
+        VirtIOBlkBuf::Read(ptr, len) => (false, ptr, len),
+        // 设备只读取写请求的缓冲区
+        VirtIOBlkBuf::Write(ptr, len) => (true, ptr as *mut u8, len),
+    };
+    // 请求头在堆上，数据缓冲区由提交者保证在请求完成前有效
+    let r = unsafe { q.submit(&mut io.req, write, io.block_id as u64, ptr, len) };
+    r.inspect_err(|e| {
+        if *e != SystemError::EAGAIN_OR_EWOULDBLOCK {
+            error!(
+                "VirtIOBlkDevice '{:?}' submit request failed: {:?}",
+                dev_id, e
+            );
+        }
+    })
+}
+
+/// # 结构功能
+/// 已经提交到virtqueue的请求以及等待重试的请求
+///
+/// 请求以描述符链的链头为键，因此多个请求可以同时在virtqueue中处理、以任意顺序完成。
+/// 调用者需要持有设备的锁，结束请求（调用[`BlockIoRequest::complete`]）则要在释放锁之后进行
+#[derive(Default)]
+struct VirtIOBlkRequests {
+    /// 正在处理中的请求，以描述符链头部索引为键
+    inflight: BTreeMap<u16, VirtIOBlkInflight>,
+    /// 设备返回IOERR、等待重新提交的请求
+    retrying: Vec<VirtIOBlkRetry>,
+}
+
+/// 重新提交失败的请求以及失败的原因
+type VirtIOBlkFailed = (Arc<BlockIoRequest>, SystemError);
+
+/// 一次回收的结果
+struct VirtIOBlkReaped {
+    /// 从used ring中取出的表项数量（包括被丢弃的未知请求）
+    reaped: usize,
+    /// 已经结束的请求以及它们的结果
+    finished: Vec<(Arc<BlockIoRequest>, Result<(), SystemError>)>,
+    /// 有请求需要重试时，最早的一次重试距离现在的时间，据此设置重试定时器
+    retry_delay: Option<Duration>,
+}
+
+impl VirtIOBlkRequests {
+    /// 尚未结束的请求数量
+    fn len(&self) -> usize {
+        self.inflight.len() + self.retrying.len()
+    }
+
+    /// 取出所有尚未结束的请求
+    fn into_requests(self) -> impl Iterator<Item = Arc<BlockIoRequest>> {
+        self.inflight
+            .into_values()
+            .map(|inflight| inflight.request)
+            .chain(
+                self.retrying
+                    .into_iter()
+                    .map(|retry| retry.inflight.request),
+            )
+    }
+
+    /// # 函数的功能
+    /// 把一个设备请求放入virtqueue，并记录它属于哪个[`BlockIoRequest`]
+    ///
+    /// ## 返回值
+    /// - Err(e): 见[`virtio_blk_queue_io`]，请求没有被提交
+    fn queue<H: Hal, T: Transport>(
+        &mut self,
+        q: &mut VirtIOBlkQueue<H, T>,
+        mut io: Box<VirtIOBlkRequestIo>,
+        request: &Arc<BlockIoRequest>,
+        dev_id: &DeviceId,
+    ) -> Result<(), SystemError> {
+        let token = virtio_blk_queue_io(q, &mut io, dev_id)?;
+        self.inflight.insert(
+            token,
+            VirtIOBlkInflight {
+                io,
+                request: request.clone(),
+                retries: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 从used ring中取出所有已完成的请求，根据状态字节和重试策略决定它们结束还是重试
+    ///
+    /// ## 参数
+    /// - `coalesce`: 是否在回收期间关闭设备的中断，见[`virtio_blk_drain_used`]
+    /// - `now`: 当前时间，用于计算重试的时间
+    fn reap<H: Hal, T: Transport>(
+        &mut self,
+        q: &mut VirtIOBlkQueue<H, T>,
+        coalesce: bool,
+        policy: &VirtIOBlkRetryPolicy,
+        now: Instant,
+        dev_id: &DeviceId,
+    ) -> VirtIOBlkReaped {
+        let inflight_map = &mut self.inflight;
+        let retrying = &mut self.retrying;
+        let mut retry_delay = None;
+        let mut finished = Vec::new();
+        let reaped = virtio_blk_drain_used(q.ring(), coalesce, |ring, token| {
+            let Some(mut inflight) = inflight_map.remove(&token) else {
+                warn!(
+                    "VirtIOBlkDevice '{:?}': completion for unknown request {}, discarded",
+                    dev_id, token
+                );
+                return false;
+            };
+
+            ring.pop_used();
+            let status = inflight.io.req.status();
+            if status != VirtIOBlkStatus::Ok {
+                error!(
+                    "VirtIOBlkDevice '{:?}' request {} failed: {:?}",
+                    dev_id, token, status
+                );
+            }
+            match policy.on_complete(status, inflight.retries) {
+                VirtIOBlkCompletion::Done(r) => finished.push((inflight.request, r)),
+                VirtIOBlkCompletion::Retry(delay) => {
+                    inflight.retries += 1;
+                    retrying.push(VirtIOBlkRetry {
+                        inflight,
+                        retry_at: now + delay,
+                    });
+                    retry_delay = Some(retry_delay.map_or(delay, |d: Duration| d.min(delay)));
+                }
+            }
+            true
+        });
+        VirtIOBlkReaped {
+            reaped,
+            finished,
+            retry_delay,
+        }
+    }
+
+    /// # 函数的功能
+    /// 重新提交重试时间已经到达的请求
+    ///
+    /// ## 参数
+    /// - `target`: 用于提交请求的队列，设备当前不能接收请求时为错误，等待重试的请求都以该错误结束
+    /// - `now`: 当前时间
+    ///
+    /// ## 返回值
+    /// 重新提交失败、需要以错误结束的请求，以及virtqueue是否已满。
+    /// 队列满时剩余的请求继续等待，需要在有请求完成之后再试
+    fn resubmit<H: Hal, T: Transport>(
+        &mut self,
+        mut target: Result<&mut VirtIOBlkQueue<H, T>, SystemError>,
+        now: Instant,
+        dev_id: &DeviceId,
+    ) -> (Vec<VirtIOBlkFailed>, bool) {
+        let mut queue_full = false;
+        let mut failed = Vec::new();
+        for mut retry in core::mem::take(&mut self.retrying) {
+            if queue_full || retry.retry_at > now {
+                self.retrying.push(retry);
+                continue;
+            }
+            let r = match target.as_deref_mut() {
+                Ok(q) => virtio_blk_queue_io(q, &mut retry.inflight.io, dev_id),
+                Err(e) => Err(e.clone()),
+            };
+            match r {
+                Ok(token) => {
+                    self.inflight.insert(token, retry.inflight);
+                }
+                Err(SystemError::EAGAIN_OR_EWOULDBLOCK) => {
+                    queue_full = true;
+                    self.retrying.push(retry);
+                }
+                Err(e) => failed.push((retry.inflight.request, e)),
+            }
+        }
+        (failed, queue_full)
+    }
+}
+
+/// IOERR时默认的重试次数，可以通过内核命令行参数`virtio_blk_retries`覆盖，为0时不重试
+const VIRTIO_BLK_DEFAULT_RETRIES: u32 = 3;
+kernel_cmdline_param_kv!(VIRTIO_BLK_RETRIES_PARAM, virtio_blk_retries, "");
+/// 第一次重试之前等待的毫秒数，之后每次重试翻倍，可以通过内核命令行参数`virtio_blk_retry_delay_ms`覆盖
+const VIRTIO_BLK_DEFAULT_RETRY_DELAY_MS: u64 = 10;
+kernel_cmdline_param_kv!(VIRTIO_BLK_RETRY_DELAY_PARAM, virtio_blk_retry_delay_ms, "");
+/// 中断处理时是否合并完成的请求，默认开启，可以通过内核命令行参数`virtio_blk_irq_coalesce=false`关闭
+kernel_cmdline_param_kv!(VIRTIO_BLK_IRQ_COALESCE_PARAM, virtio_blk_irq_coalesce, "");
+/// 重试的等待时间最多翻倍的次数
+const VIRTIO_BLK_RETRY_MAX_SHIFT: u32 = 6;
+
+/// 读取一个数值类型的内核命令行参数，参数没有设置或者不合法时返回None
+fn virtio_blk_param<T: core::str::FromStr>(param: &KernelCmdlineParameter) -> Option<T> {
+    let value = param.value_str().filter(|s| !s.is_empty())?;
+    let r = value.parse::<T>().ok();
+    if r.is_none() {
+        warn!("virtio_blk: ignoring invalid {}={}", param.name(), value);
+    }
+    r
+}
+
+/// 设备完成一个请求之后的处理方式
+#[derive(Debug, PartialEq, Eq)]
+enum VirtIOBlkCompletion {
+    /// 请求结束，把结果交给提交者
+    Done(Result<(), SystemError>),
+    /// 设备端暂时性的错误，等待一段时间后重新提交
+    Retry(Duration),
+}
+
+/// 设备返回IOERR时重新提交请求的策略
+///
+/// IOERR通常是主机端暂时性的错误，重试有机会成功；UNSUPP等错误重试也不会成功，直接失败
+#[derive(Debug, Clone, Copy)]
+struct VirtIOBlkRetryPolicy {
+    /// 最多重试的次数，为0时不重试
+    max_retries: u32,
+    /// 第一次重试之前等待的毫秒数
+    delay_ms: u64,
+}
+
+impl VirtIOBlkRetryPolicy {
+    fn from_cmdline() -> Self {
+        Self {
+            max_retries: virtio_blk_param(&VIRTIO_BLK_RETRIES_PARAM)
+                .unwrap_or(VIRTIO_BLK_DEFAULT_RETRIES),
+            delay_ms: virtio_blk_param(&VIRTIO_BLK_RETRY_DELAY_PARAM)
+                .unwrap_or(VIRTIO_BLK_DEFAULT_RETRY_DELAY_MS),
+        }
+    }
+
+    /// # 函数的功能
+    /// 根据设备返回的结果以及请求已经重试的次数，决定请求是结束还是重试
+    ///
+    /// ## 参数
+    /// - `status`: 设备写入的请求状态
+    /// - `retries`: 请求已经重试的次数
+    fn on_complete(&self, status: VirtIOBlkStatus, retries: u32) -> VirtIOBlkCompletion {
+        match status {
+            VirtIOBlkStatus::Ok => VirtIOBlkCompletion::Done(Ok(())),
+            VirtIOBlkStatus::IoErr if retries < self.max_retries => {
+                let shift = retries.min(VIRTIO_BLK_RETRY_MAX_SHIFT);
+                VirtIOBlkCompletion::Retry(Duration::from_millis(self.delay_ms << shift))
+            }
+            _ => VirtIOBlkCompletion::Done(Err(SystemError::EIO)),
+        }
+    }
+}
+
+/// 重试定时器，到期后重新提交等待重试的请求
+#[derive(Debug)]
+struct VirtIOBlkRetryTimer(Weak<VirtIOBlkDevice>);
+
+impl TimerFunction for VirtIOBlkRetryTimer {
+    fn run(&mut self) -> Result<(), SystemError> {
+        if let Some(dev) = self.0.upgrade() {
+            dev.resubmit_retries();
+        }
+        Ok(())
     }
 }
 
@@ -206,9 +1406,7 @@ impl BlockDevice for VirtIOBlkDevice {
     }
 
     fn disk_range(&self) -> GeneralBlockRange {
-        let inner = self.inner();
-        let blocks = inner.device_inner.capacity() as usize * SECTOR_SIZE / LBA_SIZE;
-        drop(inner);
+        let blocks = self.capacity() as usize * SECTOR_SIZE / LBA_SIZE;
         log::debug!(
             "VirtIOBlkDevice '{:?}' disk_range: 0..{}",
             self.dev_name(),
@@ -223,18 +1421,17 @@ impl BlockDevice for VirtIOBlkDevice {
         count: usize,
         buf: &mut [u8],
     ) -> Result<usize, SystemError> {
-        let mut inner = self.inner();
-
-        inner
-            .device_inner
-            .read_blocks(lba_id_start, &mut buf[..count * LBA_SIZE])
-            .map_err(|e| {
-                error!(
-                    "VirtIOBlkDevice '{:?}' read_at_sync failed: {:?}",
-                    self.dev_id, e
-                );
-                SystemError::EIO
-            })?;
+        let buf = &mut buf[..count * LBA_SIZE];
+        self.submit_and_wait(
+            lba_id_start,
+            VirtIOBlkBuf::Read(buf.as_mut_ptr(), buf.len()),
+        )
+        .inspect_err(|e| {
+            error!(
+                "VirtIOBlkDevice '{:?}' read_at_sync failed: {:?}",
+                self.dev_id, e
+            );
+        })?;
 
         Ok(count)
     }
@@ -245,10 +1442,8 @@ impl BlockDevice for VirtIOBlkDevice {
         count: usize,
         buf: &[u8],
     ) -> Result<usize, SystemError> {
-        self.inner()
-            .device_inner
-            .write_blocks(lba_id_start, &buf[..count * LBA_SIZE])
-            .map_err(|_| SystemError::EIO)?;
+        let buf = &buf[..count * LBA_SIZE];
+        self.submit_and_wait(lba_id_start, VirtIOBlkBuf::Write(buf.as_ptr(), buf.len()))?;
         Ok(count)
     }
 
@@ -281,7 +1476,15 @@ impl BlockDevice for VirtIOBlkDevice {
 }
 
 struct InnerVirtIOBlkDevice {
-    device_inner: VirtIOBlk<HalImpl, VirtIOTransport>,
+    /// 设备通过sysfs被复位之后为None
+    device_inner: Option<VirtIOBlkQueue>,
+    /// 与device_inner指向同一设备的transport，用于复位以及重新初始化设备，
+    /// 为None时表示该transport不支持复位
+    ctrl_transport: Option<VirtIOTransport>,
+    /// 设备容量（以扇区为单位），复位期间仍然可以访问
+    capacity: u64,
+    /// 正在处理中以及等待重试的请求
+    requests: VirtIOBlkRequests,
     name: Option<String>,
     virtio_index: Option<VirtIODeviceIndex>,
     device_common: DeviceCommonData,
@@ -295,16 +1498,101 @@ impl Debug for InnerVirtIOBlkDevice {
     }
 }
 
+impl BlockDeviceAsyncIo for VirtIOBlkDevice {
+    unsafe fn submit_read(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &mut [u8],
+        callback: Option<BlockIoCallback>,
+    ) -> Result<Arc<BlockIoRequest>, SystemError> {
+        self.submit_read_with(
+            lba_id_start,
+            count,
+            buf,
+            callback,
+            BlockIoQueueFull::Wait(None),
+        )
+    }
+
+    unsafe fn submit_write(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &[u8],
+        callback: Option<BlockIoCallback>,
+    ) -> Result<Arc<BlockIoRequest>, SystemError> {
+        self.submit_write_with(
+            lba_id_start,
+            count,
+            buf,
+            callback,
+            BlockIoQueueFull::Wait(None),
+        )
+    }
+
+    unsafe fn submit_read_with(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &mut [u8],
+        callback: Option<BlockIoCallback>,
+        full: BlockIoQueueFull,
+    ) -> Result<Arc<BlockIoRequest>, SystemError> {
+        let buf = &mut buf[..count * LBA_SIZE];
+        self.submit_request(
+            lba_id_start,
+            VirtIOBlkBuf::Read(buf.as_mut_ptr(), buf.len()),
+            callback,
+            full,
+        )
+    }
+
+    unsafe fn submit_write_with(
+        &self,
+        lba_id_start: BlockId,
+        count: usize,
+        buf: &[u8],
+        callback: Option<BlockIoCallback>,
+        full: BlockIoQueueFull,
+    ) -> Result<Arc<BlockIoRequest>, SystemError> {
+        let buf = &buf[..count * LBA_SIZE];
+        self.submit_request(
+            lba_id_start,
+            VirtIOBlkBuf::Write(buf.as_ptr(), buf.len()),
+            callback,
+            full,
+        )
+    }
+
+    fn wait(&self, request: &BlockIoRequest) -> Result<(), SystemError> {
+        self.wait_request(request)
+    }
+}
+
 impl VirtIODevice for VirtIOBlkDevice {
     fn irq(&self) -> Option<IrqNumber> {
         self.inner().irq
     }
 
+    fn irq_type(&self) -> VirtIOIrqType {
+        self.irq_type
+    }
+
+    fn isr(&self) -> Option<VirtIOIsr> {
+        self.isr
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        vec![VIRTIO_BLK_QUEUE]
+    }
+
     fn handle_irq(
         &self,
         _irq: crate::exception::IrqNumber,
     ) -> Result<IrqReturn, system_error::SystemError> {
-        // todo: handle virtio blk irq
+        let reaped = self.reap_completions_with(self.irq_coalesce);
+        self.irq_stats.record(reaped);
         Ok(crate::exception::irqdesc::IrqReturn::Handled)
     }
 
@@ -338,6 +1626,51 @@ impl VirtIODevice for VirtIOBlkDevice {
     fn vendor(&self) -> u32 {
         VIRTIO_VENDOR_ID.into()
     }
+
+    fn enabled(&self) -> Result<bool, SystemError> {
+        Ok(self.driver_ok())
+    }
+
+    fn set_enabled(&self, enable: bool) -> Result<(), SystemError> {
+        if self.is_dead() {
+            return Err(SystemError::ENODEV);
+        }
+        if enable {
+            self.enable()
+        } else {
+            self.disable()
+        }
+    }
+
+    fn selftest(&self) -> Result<(), SystemError> {
+        self.selftest_read()
+    }
+
+    fn quiesce(&self, reset: bool) -> Result<(), SystemError> {
+        self.quiesce_for_suspend(reset)
+    }
+
+    fn unquiesce(&self) -> Result<(), SystemError> {
+        self.unquiesce_after_resume()
+    }
+
+    fn queue_full_policy(&self) -> VirtQueueFullPolicy {
+        *self.queue_full_policy.lock_irqsave()
+    }
+
+    fn set_queue_full_policy(&self, policy: VirtQueueFullPolicy) -> Result<(), SystemError> {
+        *self.queue_full_policy.lock_irqsave() = policy;
+        Ok(())
+    }
+
+    fn teardown_queues(&self) -> Result<(), SystemError> {
+        let _guard = self.reset_lock.lock();
+        let r = self.teardown_locked();
+        if let Some(transport) = self.inner().ctrl_transport.as_ref() {
+            transport.release_irq_vectors();
+        }
+        r
+    }
 }
 
 impl Device for VirtIOBlkDevice {
@@ -345,6 +1678,10 @@ impl Device for VirtIOBlkDevice {
         DeviceType::Net
     }
 
+    fn attribute_groups(&self) -> Option<&'static [&'static dyn AttributeGroup]> {
+        Some(&[&VirtIOBlkAttrGroup])
+    }
+
     fn id_table(&self) -> IdTable {
         IdTable::new(VIRTIO_BLK_BASENAME.to_string(), None)
     }
@@ -385,7 +1722,11 @@ impl Device for VirtIOBlkDevice {
     }
 
     fn is_dead(&self) -> bool {
-        false
+        self.dead.load(Ordering::SeqCst)
+    }
+
+    fn pm_runtime(&self) -> Option<&DevPmRuntime> {
+        Some(&self.pm_runtime)
     }
 
     fn can_match(&self) -> bool {
@@ -476,11 +1817,11 @@ fn virtio_blk_driver_init() -> Result<(), SystemError> {
     unsafe {
         VIRTIO_BLK_DRIVER = Some(driver);
     }
+    virtio_device_router().register(VirtioDeviceType::Block, virtio_blk)?;
 
     return Ok(());
 }
 
-#[derive(Debug)]
 #[cast_to([sync] VirtIODriver)]
 #[cast_to([sync] Driver)]
 struct VirtIOBlkDriver {
@@ -514,6 +1855,20 @@ impl VirtIOBlkDriver {
     }
 }
 
+/// 调试输出只尝试获取内部的锁，避免在持有锁时打印驱动而死锁
+impl Debug for VirtIOBlkDriver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut d = f.debug_struct("VirtIOBlkDriver");
+        match self.inner.try_lock() {
+            Ok(inner) => d
+                .field("id_table", &inner.virtio_driver_common.id_table)
+                .field("devices", &inner.driver_common.devices.len()),
+            Err(_) => d.field("inner", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
 #[derive(Debug)]
 struct InnerVirtIOBlkDriver {
     virtio_driver_common: VirtIODriverCommonData,
@@ -539,6 +1894,25 @@ impl VirtIODriver for VirtIOBlkDriver {
         return Ok(());
     }
 
+    fn remove(&self, device: &Arc<dyn VirtIODevice>) -> Result<(), SystemError> {
+        let dev = device
+            .clone()
+            .arc_any()
+            .downcast::<VirtIOBlkDevice>()
+            .map_err(|_| SystemError::EINVAL)?;
+
+        // 先让设备交还描述符并释放virtqueue，之后设备不会再通过DMA访问内存
+        if let Err(e) = dev.teardown_queues() {
+            warn!(
+                "VirtIOBlkDevice '{:?}': queue teardown failed: {:?}",
+                dev.dev_id, e
+            );
+        }
+        dev.mark_dead();
+        block_dev_manager().unregister(&(dev as Arc<dyn BlockDevice>));
+        return Ok(());
+    }
+
     fn virtio_id_table(&self) -> LinkedList<crate::driver::virtio::VirtioDeviceId> {
         self.inner().virtio_driver_common.id_table.clone()
     }
@@ -587,6 +1961,14 @@ impl Driver for VirtIOBlkDriver {
         self.inner().driver_common.devices.clone()
     }
 
+    fn __for_each_device(&self, f: &mut dyn FnMut(&Arc<dyn Device>) -> ControlFlow<()>) {
+        self.inner().driver_common.for_each_device(f);
+    }
+
+    fn device_count(&self) -> usize {
+        self.inner().driver_common.devices.len()
+    }
+
     fn bus(&self) -> Option<Weak<dyn Bus>> {
         Some(Arc::downgrade(&virtio_bus()) as Weak<dyn Bus>)
     }
@@ -653,3 +2035,560 @@ impl KObject for VirtIOBlkDriver {
         *self.kobj_state.write() = state;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::{
+        block::virtio_blk_queue::{VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP},
+        virtio::{
+            mock::{MockDesc, MockHal, MockTransport, MockVirtqDevice},
+            queue::VirtqSplit,
+        },
+    };
+    use alloc::{collections::VecDeque, vec};
+
+    /// 模拟的队列：驱动每查看一次used ring，设备就完成一个请求，打开中断时完成请求会产生一次中断
+    struct MockUsedRing {
+        /// 设备还没有完成的请求
+        pending: VecDeque<u16>,
+        used: VecDeque<u16>,
+        irq_enabled: bool,
+        interrupts: usize,
+    }
+
+    impl MockUsedRing {
+        fn new(requests: u16) -> Self {
+            Self {
+                pending: (0..requests).collect(),
+                used: VecDeque::new(),
+                irq_enabled: true,
+                interrupts: 0,
+            }
+        }
+
+        fn device_complete_one(&mut self) {
+            if let Some(token) = self.pending.pop_front() {
+                self.used.push_back(token);
+                if self.irq_enabled {
+                    self.interrupts += 1;
+                }
+            }
+        }
+    }
+
+    impl VirtIOBlkUsedRing for MockUsedRing {
+        fn peek_used(&mut self) -> Option<u16> {
+            self.device_complete_one();
+            self.used.front().copied()
+        }
+
+        fn disable_interrupts(&mut self) {
+            self.irq_enabled = false;
+        }
+
+        fn enable_interrupts(&mut self) {
+            self.irq_enabled = true;
+        }
+
+        fn discard_used(&mut self, token: u16) {
+            assert_eq!(self.used.pop_front(), Some(token));
+        }
+    }
+
+    fn drain(q: &mut MockUsedRing, coalesce: bool) -> usize {
+        virtio_blk_drain_used(q, coalesce, |q, token| {
+            assert_eq!(q.used.pop_front(), Some(token));
+            true
+        })
+    }
+
+    #[test]
+    fn test_single_interrupt_drains_batch() {
+        let mut q = MockUsedRing::new(64);
+        // 第一个请求完成，产生中断
+        q.device_complete_one();
+        assert_eq!(q.interrupts, 1);
+
+        let stats = VirtIOBlkIrqStats::new();
+        stats.record(drain(&mut q, true));
+        assert!(q.pending.is_empty() && q.used.is_empty());
+        // 回收期间完成的请求没有产生中断
+        assert_eq!(q.interrupts, 1);
+        assert!(q.irq_enabled);
+        assert_eq!(stats.format(), "1 64 64.00\n");
+
+        // 不合并时，回收期间完成的每个请求都会产生中断
+        let mut q = MockUsedRing::new(64);
+        q.device_complete_one();
+        assert_eq!(drain(&mut q, false), 64);
+        assert_eq!(q.interrupts, 64);
+    }
+
+    #[test]
+    fn test_drain_discards_unknown_request() {
+        let mut q = MockUsedRing::new(8);
+        let mut completed = Vec::new();
+        let n = virtio_blk_drain_used(&mut q, true, |q, token| {
+            if token == 3 {
+                return false;
+            }
+            assert_eq!(q.used.pop_front(), Some(token));
+            completed.push(token);
+            true
+        });
+        // 未知的请求被取出并丢弃，之后完成的请求仍然被回收
+        assert_eq!(n, 8);
+        assert_eq!(completed, vec![0, 1, 2, 4, 5, 6, 7]);
+        assert!(q.pending.is_empty() && q.used.is_empty());
+        assert!(q.irq_enabled);
+    }
+
+    type MockBlkQueue = VirtIOBlkQueue<MockHal, MockTransport>;
+
+    fn mock_queue(size: u16) -> MockBlkQueue {
+        let vq = VirtqSplit::new(VIRTIO_BLK_QUEUE, size, false).unwrap();
+        VirtIOBlkQueue::from_parts(
+            MockTransport::default(),
+            VirtIOBlkRing::new(vq, false, false),
+        )
+    }
+
+    fn test_dev_id() -> Arc<DeviceId> {
+        DeviceId::new(Some("virtio_blk_test"), None).unwrap()
+    }
+
+    /// 读取一个扇区到`data`的请求
+    fn read_io(block_id: usize, data: &mut [u8]) -> Box<VirtIOBlkRequestIo> {
+        Box::new(VirtIOBlkRequestIo {
+            block_id,
+            req: VirtIOBlkReqBuf::default(),
+            buf: VirtIOBlkBuf::Read(data.as_mut_ptr(), data.len()),
+        })
+    }
+
+    /// 模拟的设备中每个扇区的内容
+    fn sector_pattern(sector: u64) -> u8 {
+        (sector % 251) as u8
+    }
+
+    /// # 函数的功能
+    /// 模拟设备处理一个读请求：按照请求头中的扇区号填充数据，并写入`status`给出的状态字节
+    ///
+    /// ## 返回值
+    /// 设备写入的字节数
+    fn device_read(descs: &[MockDesc], status: impl Fn(u64) -> u8) -> u32 {
+        let header = unsafe { descs[0].buf() };
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        unsafe {
+            descs[1].buf().fill(sector_pattern(sector));
+            descs[2].buf()[0] = status(sector);
+        }
+        descs[1].len + 1
+    }
+
+    /// 多个读者共享的设备：真实的请求队列和请求跟踪，设备一侧由[`MockVirtqDevice`]模拟
+    struct StressDevice {
+        q: MockBlkQueue,
+        requests: VirtIOBlkRequests,
+        dev: MockVirtqDevice,
+        dev_id: Arc<DeviceId>,
+        /// 绕过请求跟踪直接放入队列的请求（例如复位之前提交的请求），驱动不认识它的完成
+        stray: Option<u16>,
+        stray_req: Box<VirtIOBlkReqBuf>,
+        stray_buf: Vec<u8>,
+        unknown: usize,
+    }
+
+    // 队列以及请求中的裸指针只在持有锁时访问
+    unsafe impl Send for StressDevice {}
+
+    impl StressDevice {
+        const QUEUE_SIZE: u16 = 32;
+
+        fn new() -> Self {
+            Self {
+                q: mock_queue(Self::QUEUE_SIZE),
+                requests: VirtIOBlkRequests::default(),
+                dev: MockVirtqDevice::new(),
+                dev_id: test_dev_id(),
+                stray: None,
+                stray_req: Box::default(),
+                stray_buf: vec![0; SECTOR_SIZE],
+                unknown: 0,
+            }
+        }
+
+        /// 扇区号为7的倍数的请求返回UNSUPP，其余成功
+        fn status_of(sector: u64) -> u8 {
+            if sector % 7 == 0 {
+                VIRTIO_BLK_S_UNSUPP
+            } else {
+                VIRTIO_BLK_S_OK
+            }
+        }
+
+        /// # 函数的功能
+        /// 设备处理available ring中所有的请求，以与提交相反的顺序完成它们，然后模拟中断处理
+        ///
+        /// ## 返回值
+        /// 已经结束的请求，需要在释放锁之后结束它们
+        fn device_pass(&mut self) -> Vec<(Arc<BlockIoRequest>, Result<(), SystemError>)> {
+            if self.stray.is_none() {
+                let ptr = self.stray_buf.as_mut_ptr();
+                self.stray = unsafe {
+                    self.q
+                        .ring()
+                        .submit(&mut self.stray_req, false, 0, ptr, SECTOR_SIZE)
+                }
+                .ok();
+            }
+
+            let mut batch = Vec::new();
+            while let Some((head, descs)) = self.dev.pop_avail(self.q.ring().vq()) {
+                batch.push((head, device_read(&descs, Self::status_of)));
+            }
+            for (head, len) in batch.into_iter().rev() {
+                if self.stray == Some(head) {
+                    self.stray = None;
+                    self.unknown += 1;
+                }
+                self.dev.push_used(self.q.ring().vq(), head, len);
+            }
+
+            let policy = VirtIOBlkRetryPolicy {
+                max_retries: 0,
+                delay_ms: 0,
+            };
+            let reaped = self.requests.reap(
+                &mut self.q,
+                true,
+                &policy,
+                Instant::from_millis(0),
+                &self.dev_id,
+            );
+            reaped.finished
+        }
+    }
+
+    #[test]
+    fn test_concurrent_reads_with_unknown_completions() {
+        use std::sync::Mutex;
+
+        const READERS: usize = 8;
+        const READS: usize = 200;
+        let dev = Arc::new(Mutex::new(StressDevice::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let device = {
+            let dev = dev.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let finished = dev.lock().unwrap().device_pass();
+                    for (request, r) in finished {
+                        request.complete(r);
+                    }
+                    std::thread::yield_now();
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..READERS)
+            .map(|reader| {
+                let dev = dev.clone();
+                std::thread::spawn(move || {
+                    for n in 0..READS {
+                        let sector = (reader * READS + n) as u64;
+                        let mut data = vec![0u8; SECTOR_SIZE];
+                        let request = BlockIoRequest::new(1, None);
+                        // 队列满时等待设备完成其他读者的请求
+                        loop {
+                            let mut guard = dev.lock().unwrap();
+                            let d = &mut *guard;
+                            let io = read_io(sector as usize, &mut data);
+                            match d.requests.queue(&mut d.q, io, &request, &d.dev_id) {
+                                Ok(()) => break,
+                                Err(SystemError::EAGAIN_OR_EWOULDBLOCK) => {}
+                                Err(e) => panic!("submit failed: {:?}", e),
+                            }
+                            drop(guard);
+                            std::thread::yield_now();
+                        }
+                        while !request.is_done() {
+                            std::thread::yield_now();
+                        }
+
+                        // 得到的是自己请求的数据和状态
+                        let expected = match StressDevice::status_of(sector) {
+                            VIRTIO_BLK_S_OK => Ok(()),
+                            _ => Err(SystemError::EIO),
+                        };
+                        assert_eq!(request.result(), Some(expected));
+                        assert!(data.iter().all(|b| *b == sector_pattern(sector)));
+                    }
+                })
+            })
+            .collect();
+        for t in readers {
+            t.join().unwrap();
+        }
+        stop.store(true, Ordering::SeqCst);
+        device.join().unwrap();
+
+        let mut d = dev.lock().unwrap();
+        assert!(d.device_pass().is_empty());
+        assert_eq!(d.requests.len(), 0);
+        // 未知的请求都被取出，没有卡住队列，描述符全部归还
+        assert!(d.unknown > 0);
+        assert_eq!(d.stray, None);
+        assert_eq!(
+            d.q.ring().vq().num_free(),
+            StressDevice::QUEUE_SIZE as usize
+        );
+    }
+
+    #[test]
+    fn test_retry_ioerr_then_success() {
+        let policy = VirtIOBlkRetryPolicy {
+            max_retries: 3,
+            delay_ms: 10,
+        };
+        // 设备两次返回IOERR，第三次成功
+        let results = [
+            VirtIOBlkStatus::IoErr,
+            VirtIOBlkStatus::IoErr,
+            VirtIOBlkStatus::Ok,
+        ];
+        let mut retries = 0;
+        let mut delays = Vec::new();
+        let mut done = None;
+        for r in results {
+            match policy.on_complete(r, retries) {
+                VirtIOBlkCompletion::Retry(delay) => {
+                    delays.push(delay);
+                    retries += 1;
+                }
+                VirtIOBlkCompletion::Done(r) => {
+                    done = Some(r);
+                    break;
+                }
+            }
+        }
+        assert_eq!(done, Some(Ok(())));
+        assert_eq!(
+            delays,
+            vec![Duration::from_millis(10), Duration::from_millis(20)]
+        );
+    }
+
+    #[test]
+    fn test_retry_permanent_errors_fail_fast() {
+        let policy = VirtIOBlkRetryPolicy {
+            max_retries: 3,
+            delay_ms: 10,
+        };
+        assert_eq!(
+            policy.on_complete(VirtIOBlkStatus::Unsupp, 0),
+            VirtIOBlkCompletion::Done(Err(SystemError::EIO))
+        );
+        // 重试次数用完
+        assert_eq!(
+            policy.on_complete(VirtIOBlkStatus::IoErr, 3),
+            VirtIOBlkCompletion::Done(Err(SystemError::EIO))
+        );
+        // 关闭重试
+        let policy = VirtIOBlkRetryPolicy {
+            max_retries: 0,
+            delay_ms: 10,
+        };
+        assert_eq!(
+            policy.on_complete(VirtIOBlkStatus::IoErr, 0),
+            VirtIOBlkCompletion::Done(Err(SystemError::EIO))
+        );
+    }
+
+    /// # 函数的功能
+    /// 通过真实的请求队列提交一个读请求，设备依次以`statuses`中的状态完成每一次提交。
+    /// 每次完成之后按照中断处理函数回收请求，需要重试时按照重试定时器的流程重新提交
+    ///
+    /// ## 返回值
+    /// (请求的结果, 设备收到请求的次数, 每次设置重试定时器的延迟)
+    fn retry_through_queue(
+        policy: VirtIOBlkRetryPolicy,
+        statuses: &[u8],
+    ) -> (Option<Result<(), SystemError>>, usize, Vec<Duration>) {
+        let dev_id = test_dev_id();
+        let mut q = mock_queue(8);
+        let mut dev = MockVirtqDevice::new();
+        let mut requests = VirtIOBlkRequests::default();
+        let mut data = vec![0u8; SECTOR_SIZE];
+        let request = BlockIoRequest::new(1, None);
+        requests
+            .queue(&mut q, read_io(3, &mut data), &request, &dev_id)
+            .unwrap();
+
+        let mut now = Instant::from_millis(0);
+        let mut submissions = 0;
+        let mut delays = Vec::new();
+        for &status in statuses {
+            let Some((head, descs)) = dev.pop_avail(q.ring().vq()) else {
+                break;
+            };
+            submissions += 1;
+            let len = device_read(&descs, |_| status);
+            dev.push_used(q.ring().vq(), head, len);
+
+            let reaped = requests.reap(&mut q, true, &policy, now, &dev_id);
+            assert_eq!(reaped.reaped, 1);
+            for (request, r) in reaped.finished {
+                request.complete(r);
+            }
+            let Some(delay) = reaped.retry_delay else {
+                continue;
+            };
+            delays.push(delay);
+
+            // 重试定时器到期之前不会重新提交
+            let (failed, queue_full) = requests.resubmit(Ok(&mut q), now, &dev_id);
+            assert!(failed.is_empty() && !queue_full);
+            assert!(dev.pop_avail(q.ring().vq()).is_none());
+            now += delay;
+            let (failed, queue_full) = requests.resubmit(Ok(&mut q), now, &dev_id);
+            assert!(failed.is_empty() && !queue_full);
+        }
+
+        assert_eq!(requests.len(), 0);
+        assert_eq!(q.ring().vq().num_free(), 8);
+        if request.result() == Some(Ok(())) {
+            assert!(data.iter().all(|b| *b == sector_pattern(3)));
+        }
+        (request.result(), submissions, delays)
+    }
+
+    #[test]
+    fn test_retry_resubmits_through_queue() {
+        let policy = VirtIOBlkRetryPolicy {
+            max_retries: 3,
+            delay_ms: 10,
+        };
+        // 设备两次返回IOERR，第三次成功
+        let (result, submissions, delays) = retry_through_queue(
+            policy,
+            &[VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK],
+        );
+        assert_eq!(result, Some(Ok(())));
+        // 第一次提交之后又重新提交了两次
+        assert_eq!(submissions, 3);
+        assert_eq!(
+            delays,
+            vec![Duration::from_millis(10), Duration::from_millis(20)]
+        );
+    }
+
+    #[test]
+    fn test_retry_permanent_ioerr_fails_after_budget() {
+        let policy = VirtIOBlkRetryPolicy {
+            max_retries: 3,
+            delay_ms: 10,
+        };
+        let (result, submissions, delays) = retry_through_queue(policy, &[VIRTIO_BLK_S_IOERR; 8]);
+        assert_eq!(result, Some(Err(SystemError::EIO)));
+        // 重试次数用完之后不再提交
+        assert_eq!(submissions, 4);
+        assert_eq!(delays.len(), 3);
+    }
+
+    #[test]
+    fn test_retry_fails_when_device_cannot_accept() {
+        let dev_id = test_dev_id();
+        let mut q = mock_queue(8);
+        let mut dev = MockVirtqDevice::new();
+        let mut requests = VirtIOBlkRequests::default();
+        let mut data = vec![0u8; SECTOR_SIZE];
+        let request = BlockIoRequest::new(1, None);
+        let policy = VirtIOBlkRetryPolicy {
+            max_retries: 3,
+            delay_ms: 10,
+        };
+        requests
+            .queue(&mut q, read_io(0, &mut data), &request, &dev_id)
+            .unwrap();
+        let (head, descs) = dev.pop_avail(q.ring().vq()).unwrap();
+        let len = device_read(&descs, |_| VIRTIO_BLK_S_IOERR);
+        dev.push_used(q.ring().vq(), head, len);
+        let now = Instant::from_millis(0);
+        let reaped = requests.reap(&mut q, true, &policy, now, &dev_id);
+        let delay = reaped.retry_delay.unwrap();
+
+        // 等待重试期间设备被移除，请求以设备的错误结束
+        let (failed, queue_full) = requests.resubmit::<MockHal, MockTransport>(
+            Err(SystemError::ENODEV),
+            now + delay,
+            &dev_id,
+        );
+        assert!(!queue_full);
+        assert_eq!(failed.len(), 1);
+        assert!(Arc::ptr_eq(&failed[0].0, &request));
+        assert_eq!(failed[0].1, SystemError::ENODEV);
+        assert_eq!(requests.len(), 0);
+    }
+
+    #[test]
+    fn test_split_request_within_limit() {
+        let chunks = virtio_blk_split_request(10, 4 * SECTOR_SIZE, 8 * SECTOR_SIZE);
+        assert_eq!(
+            chunks,
+            vec![VirtIOBlkChunk {
+                block_id: 10,
+                offset: 0,
+                len: 4 * SECTOR_SIZE
+            }]
+        );
+    }
+
+    #[test]
+    fn test_split_request_exceeding_limit() {
+        let limits = VirtIOBlkLimits {
+            size_max: Some(2 * SECTOR_SIZE as u32),
+            seg_max: Some(4),
+        };
+        let chunks = virtio_blk_split_request(100, 5 * SECTOR_SIZE, limits.max_request_bytes());
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].block_id, 100);
+        assert_eq!(chunks[1].block_id, 102);
+        assert_eq!(chunks[1].offset, 2 * SECTOR_SIZE);
+        assert_eq!(chunks[2].block_id, 104);
+        assert_eq!(chunks[2].len, SECTOR_SIZE);
+        let total: usize = chunks.iter().map(|c| c.len).sum();
+        assert_eq!(total, 5 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn test_split_request_unaligned_size_max() {
+        // size_max不是扇区大小的整数倍时，向下取整到扇区
+        let chunks = virtio_blk_split_request(0, 3 * SECTOR_SIZE, SECTOR_SIZE + 100);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| c.len == SECTOR_SIZE));
+    }
+
+    #[test]
+    fn test_limits_never_exceed_size_max() {
+        // 设备允许的段数多于驱动使用的段数时，单个请求仍然只有一个数据段
+        let limits = VirtIOBlkLimits {
+            size_max: Some(4 * SECTOR_SIZE as u32),
+            seg_max: Some(128),
+        };
+        assert_eq!(limits.validate(), Ok(()));
+        assert_eq!(limits.max_request_bytes(), 4 * SECTOR_SIZE);
+        let chunks = virtio_blk_split_request(0, 9 * SECTOR_SIZE, limits.max_request_bytes());
+        assert!(chunks.iter().all(|c| c.len <= 4 * SECTOR_SIZE));
+
+        // size_max小于一个扇区的设备无法使用
+        let limits = VirtIOBlkLimits {
+            size_max: Some(SECTOR_SIZE as u32 / 2),
+            seg_max: None,
+        };
+        assert_eq!(limits.validate(), Err(SystemError::EINVAL));
+    }
+}
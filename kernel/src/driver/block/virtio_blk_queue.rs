@@ -0,0 +1,429 @@
+//! virtio-blk的请求队列
+//!
+//! 每个块设备请求由三部分组成：设备只读的请求头、数据缓冲区以及设备可写的状态字节。
+//! 它们位于不同的地址，提交时通过[`VirtioSgList`]一次放入驱动自己管理的[`VirtqSplit`]，
+//! 设备完成请求之后，由请求的状态字节得到结果。
+//!
+//! 参考 virtio spec 5.2.6 Device Operation
+
+use core::mem::{offset_of, size_of};
+
+use system_error::SystemError;
+use virtio_drivers::{transport::Transport, Hal};
+
+use crate::driver::virtio::{
+    endian::{VirtIOEndian, VirtIOEndianField},
+    queue::VirtqSplit,
+    ring::VirtQueueSizePolicy,
+    sg::{VirtioSgDirection, VirtioSgList},
+    transport::VirtIOTransport,
+    virtio_impl::HalImpl,
+};
+
+/// 扇区大小，请求头中的扇区号以它为单位
+pub const SECTOR_SIZE: usize = 512;
+
+/// 设备只读
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+/// 读请求
+const VIRTIO_BLK_T_IN: u32 = 0;
+/// 写请求
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// 提交请求时写入状态字节的值，设备完成请求时会覆盖它
+const VIRTIO_BLK_S_PENDING: u8 = 0xff;
+
+/// 请求头的大小(struct virtio_blk_req中type、reserved和sector三个字段)
+const VIRTIO_BLK_REQ_HEADER_SIZE: usize = 16;
+
+/// 设备完成请求之后写入状态字节的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtIOBlkStatus {
+    Ok,
+    /// 设备或者主机端的IO错误
+    IoErr,
+    /// 设备不支持这个请求
+    Unsupp,
+    /// 规范之外的值，包括设备没有写入状态字节
+    Invalid(u8),
+}
+
+impl From<u8> for VirtIOBlkStatus {
+    fn from(status: u8) -> Self {
+        match status {
+            VIRTIO_BLK_S_OK => VirtIOBlkStatus::Ok,
+            VIRTIO_BLK_S_IOERR => VirtIOBlkStatus::IoErr,
+            VIRTIO_BLK_S_UNSUPP => VirtIOBlkStatus::Unsupp,
+            other => VirtIOBlkStatus::Invalid(other),
+        }
+    }
+}
+
+/// # 结构功能
+/// 请求在设备处理期间需要保持有效的请求头和状态字节，即struct virtio_blk_req中数据以外的部分
+///
+/// 请求头中的字段按照设备的字节序保存
+#[repr(C)]
+#[derive(Debug)]
+pub struct VirtIOBlkReqBuf {
+    type_: u32,
+    reserved: u32,
+    sector: u64,
+    status: u8,
+}
+
+impl Default for VirtIOBlkReqBuf {
+    fn default() -> Self {
+        Self {
+            type_: 0,
+            reserved: 0,
+            sector: 0,
+            status: VIRTIO_BLK_S_PENDING,
+        }
+    }
+}
+
+impl VirtIOBlkReqBuf {
+    /// 设备写入的请求结果
+    pub fn status(&self) -> VirtIOBlkStatus {
+        VirtIOBlkStatus::from(unsafe { core::ptr::read_volatile(&self.status) })
+    }
+}
+
+/// # 结构功能
+/// 在split virtqueue上构造和回收块设备请求，不访问设备的寄存器
+pub struct VirtIOBlkRing<H: Hal> {
+    vq: VirtqSplit<H>,
+    endian: VirtIOEndian,
+    readonly: bool,
+}
+
+impl<H: Hal> VirtIOBlkRing<H> {
+    pub fn new(vq: VirtqSplit<H>, legacy: bool, readonly: bool) -> Self {
+        Self {
+            vq,
+            endian: VirtIOEndian::new(legacy),
+            readonly,
+        }
+    }
+
+    pub fn vq(&self) -> &VirtqSplit<H> {
+        &self.vq
+    }
+
+    /// # 函数的功能
+    /// 把一个请求放入virtqueue，队列已满时不等待
+    ///
+    /// ## 参数
+    /// - `req`: 请求头和状态字节
+    /// - `write`: 是否为写请求
+    /// - `sector`: 起始扇区号
+    /// - `data`: 数据缓冲区，读请求时设备写入它，长度必须是扇区大小的整数倍
+    ///
+    /// ## 返回值
+    /// - Ok(head): 描述符链的链头，请求完成时由[`Self::pop_used`]返回
+    /// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): virtqueue已满
+    /// - Err(SystemError::EROFS): 设备只读
+    /// - Err(SystemError::EINVAL): 数据长度不合法
+    ///
+    /// ## Safety
+    /// 在请求被[`Self::pop_used`]返回或者设备被复位之前，`req`与`data`指向的内存必须一直有效，
+    /// 并且读请求的缓冲区在此期间不能被其他人访问
+    pub unsafe fn submit(
+        &mut self,
+        req: &mut VirtIOBlkReqBuf,
+        write: bool,
+        sector: u64,
+        data: *mut u8,
+        len: usize,
+    ) -> Result<u16, SystemError> {
+        if write && self.readonly {
+            return Err(SystemError::EROFS);
+        }
+        if len == 0 || len % SECTOR_SIZE != 0 {
+            return Err(SystemError::EINVAL);
+        }
+
+        let type_ = if write {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_IN
+        };
+        req.type_ = type_.to_dev(self.endian);
+        req.reserved = 0;
+        req.sector = sector.to_dev(self.endian);
+        req.status = VIRTIO_BLK_S_PENDING;
+
+        let base = req as *mut VirtIOBlkReqBuf as usize;
+        let direction = if write {
+            VirtioSgDirection::DeviceReadable
+        } else {
+            VirtioSgDirection::DeviceWritable
+        };
+        let mut sg = VirtioSgList::new();
+        sg.push_raw(
+            base,
+            VIRTIO_BLK_REQ_HEADER_SIZE,
+            VirtioSgDirection::DeviceReadable,
+        );
+        sg.push_raw(data as usize, len, direction);
+        sg.push_raw(
+            base + offset_of!(VirtIOBlkReqBuf, status),
+            size_of::<u8>(),
+            VirtioSgDirection::DeviceWritable,
+        );
+        self.vq.try_add(&sg)
+    }
+
+    /// 提交请求之后是否需要通知设备
+    pub fn should_notify(&self) -> bool {
+        self.vq.should_notify()
+    }
+
+    /// used ring中下一个已完成的请求，不会取出它
+    pub fn peek_used(&self) -> Option<u16> {
+        self.vq.peek_used()
+    }
+
+    /// 取出一个已完成的请求并释放它的描述符，之后可以读取请求的状态字节
+    pub fn pop_used(&mut self) -> Option<u16> {
+        self.vq.pop_used().map(|(head, _)| head)
+    }
+
+    /// 设置设备在完成请求时是否产生中断
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        self.vq.set_interrupts(enabled)
+    }
+}
+
+/// # 结构功能
+/// 已经初始化完成的virtio-blk设备：设备的transport以及唯一的请求队列
+///
+/// 被释放时先禁用队列，再释放队列的内存
+pub struct VirtIOBlkQueue<H: Hal = HalImpl, T: Transport = VirtIOTransport> {
+    transport: T,
+    ring: VirtIOBlkRing<H>,
+}
+
+impl VirtIOBlkQueue {
+    /// # 函数的功能
+    /// 按照virtio spec 3.1.1初始化设备并设置请求队列
+    ///
+    /// ## 参数
+    /// - `transport`: 设备的transport
+    /// - `queue`: 请求队列的编号
+    /// - `policy`: 请求队列的大小
+    /// - `supported`: 驱动支持的特性
+    ///
+    /// ## 返回值
+    /// - Err(e): 见[`VirtIOTransport::negotiate_features`]以及[`VirtIOTransport::negotiate_queue_size`]
+    pub fn new(
+        mut transport: VirtIOTransport,
+        queue: u16,
+        policy: &VirtQueueSizePolicy,
+        supported: u64,
+    ) -> Result<Self, SystemError> {
+        let features = transport.negotiate_features(supported, 0)?;
+        let legacy = transport.requires_legacy_layout();
+        let vq = transport
+            .negotiate_queue_size(queue, policy)
+            .and_then(|size| VirtqSplit::new(queue, size, legacy))
+            .and_then(|vq| vq.attach(&mut transport).map(|_| vq));
+        let vq = match vq {
+            Ok(vq) => vq,
+            Err(e) => {
+                transport.fail_init();
+                return Err(e);
+            }
+        };
+        let mut ring = VirtIOBlkRing::new(vq, legacy, features & VIRTIO_BLK_F_RO != 0);
+        ring.set_interrupts(true);
+        transport.finish_init();
+        Ok(Self { transport, ring })
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOBlkQueue<H, T> {
+    /// 用已经设置好的队列构造，测试时搭配模拟的transport和DMA使用
+    #[cfg(test)]
+    pub fn from_parts(transport: T, ring: VirtIOBlkRing<H>) -> Self {
+        Self { transport, ring }
+    }
+
+    /// # 函数的功能
+    /// 把一个请求放入virtqueue，并在需要时通知设备
+    ///
+    /// 参数、返回值以及安全性要求见[`VirtIOBlkRing::submit`]
+    pub unsafe fn submit(
+        &mut self,
+        req: &mut VirtIOBlkReqBuf,
+        write: bool,
+        sector: u64,
+        data: *mut u8,
+        len: usize,
+    ) -> Result<u16, SystemError> {
+        let head = self.ring.submit(req, write, sector, data, len)?;
+        if self.ring.should_notify() {
+            self.transport.notify(self.ring.vq().queue_idx());
+        }
+        Ok(head)
+    }
+
+    /// 确认设备的中断，返回中断是否由这个设备产生
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    pub fn ring(&mut self) -> &mut VirtIOBlkRing<H> {
+        &mut self.ring
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOBlkQueue<H, T> {
+    fn drop(&mut self) {
+        // 队列的内存在这之后才被释放
+        self.transport.queue_unset(self.ring.vq().queue_idx());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::virtio::mock::{MockHal, MockVirtqDevice};
+
+    use super::*;
+
+    fn ring(size: u16, readonly: bool) -> VirtIOBlkRing<MockHal> {
+        VirtIOBlkRing::new(VirtqSplit::new(0, size, false).unwrap(), false, readonly)
+    }
+
+    #[test]
+    fn test_request_layout() {
+        assert_eq!(
+            offset_of!(VirtIOBlkReqBuf, status),
+            VIRTIO_BLK_REQ_HEADER_SIZE
+        );
+    }
+
+    #[test]
+    fn test_read_request() {
+        let mut ring = ring(8, false);
+        let mut dev = MockVirtqDevice::new();
+        let mut req = VirtIOBlkReqBuf::default();
+        let mut data = [0u8; 2 * SECTOR_SIZE];
+        let head =
+            unsafe { ring.submit(&mut req, false, 7, data.as_mut_ptr(), data.len()) }.unwrap();
+
+        // 请求头、数据、状态三个描述符，只有请求头是设备只读的
+        let (avail_head, descs) = dev.pop_avail(ring.vq()).unwrap();
+        assert_eq!(avail_head, head);
+        assert_eq!(descs.len(), 3);
+        assert_eq!(descs[0].len as usize, VIRTIO_BLK_REQ_HEADER_SIZE);
+        assert!(!descs[0].writable());
+        let header = unsafe { descs[0].buf() };
+        assert_eq!(header[..4], VIRTIO_BLK_T_IN.to_le_bytes());
+        assert_eq!(header[8..16], 7u64.to_le_bytes());
+        assert_eq!(descs[1].addr, data.as_ptr() as u64);
+        assert_eq!(descs[1].len as usize, data.len());
+        assert!(descs[1].writable());
+        assert_eq!(descs[2].len, 1);
+        assert!(descs[2].writable());
+
+        // 设备写入数据和状态
+        unsafe {
+            descs[1].buf().fill(0xab);
+            descs[2].buf()[0] = VIRTIO_BLK_S_OK;
+        }
+        assert_eq!(ring.peek_used(), None);
+        dev.push_used(ring.vq(), head, data.len() as u32 + 1);
+        assert_eq!(ring.peek_used(), Some(head));
+        assert_eq!(ring.pop_used(), Some(head));
+        assert_eq!(ring.pop_used(), None);
+        assert_eq!(req.status(), VirtIOBlkStatus::Ok);
+        assert!(data.iter().all(|b| *b == 0xab));
+        assert_eq!(ring.vq().num_free(), 8);
+    }
+
+    #[test]
+    fn test_write_request() {
+        let mut ring = ring(8, false);
+        let mut dev = MockVirtqDevice::new();
+        let mut req = VirtIOBlkReqBuf::default();
+        let mut data = [0x5au8; SECTOR_SIZE];
+        let head =
+            unsafe { ring.submit(&mut req, true, 1, data.as_mut_ptr(), data.len()) }.unwrap();
+
+        let (_, descs) = dev.pop_avail(ring.vq()).unwrap();
+        assert_eq!(
+            unsafe { &descs[0].buf()[..4] },
+            VIRTIO_BLK_T_OUT.to_le_bytes()
+        );
+        // 写请求的数据是设备只读的
+        assert!(!descs[1].writable());
+        assert!(descs[2].writable());
+
+        // 设备还没有写入状态字节
+        assert_eq!(req.status(), VirtIOBlkStatus::Invalid(VIRTIO_BLK_S_PENDING));
+        unsafe { descs[2].buf()[0] = VIRTIO_BLK_S_IOERR };
+        dev.push_used(ring.vq(), head, 1);
+        assert_eq!(ring.pop_used(), Some(head));
+        assert_eq!(req.status(), VirtIOBlkStatus::IoErr);
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let mut ring = ring(8, true);
+        let mut req = VirtIOBlkReqBuf::default();
+        let mut data = [0u8; SECTOR_SIZE];
+        let p = data.as_mut_ptr();
+        assert_eq!(
+            unsafe { ring.submit(&mut req, true, 0, p, SECTOR_SIZE) },
+            Err(SystemError::EROFS)
+        );
+        assert_eq!(
+            unsafe { ring.submit(&mut req, false, 0, p, SECTOR_SIZE - 1) },
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            unsafe { ring.submit(&mut req, false, 0, p, 0) },
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(ring.vq().num_free(), 8);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        // 每个请求占用3个描述符
+        let mut ring = ring(4, false);
+        let mut reqs = [VirtIOBlkReqBuf::default(), VirtIOBlkReqBuf::default()];
+        let mut data = [0u8; SECTOR_SIZE];
+        let p = data.as_mut_ptr();
+        unsafe { ring.submit(&mut reqs[0], false, 0, p, SECTOR_SIZE) }.unwrap();
+        assert_eq!(
+            unsafe { ring.submit(&mut reqs[1], false, 0, p, SECTOR_SIZE) },
+            Err(SystemError::EAGAIN_OR_EWOULDBLOCK)
+        );
+    }
+
+    #[test]
+    fn test_interrupt_suppression() {
+        let mut ring = ring(4, false);
+        let dev = MockVirtqDevice::new();
+        assert!(!dev.interrupts_suppressed(ring.vq()));
+        ring.set_interrupts(false);
+        assert!(dev.interrupts_suppressed(ring.vq()));
+        ring.set_interrupts(true);
+        assert!(!dev.interrupts_suppressed(ring.vq()));
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(VirtIOBlkStatus::from(0), VirtIOBlkStatus::Ok);
+        assert_eq!(VirtIOBlkStatus::from(1), VirtIOBlkStatus::IoErr);
+        assert_eq!(VirtIOBlkStatus::from(2), VirtIOBlkStatus::Unsupp);
+        assert_eq!(VirtIOBlkStatus::from(3), VirtIOBlkStatus::Invalid(3));
+    }
+}
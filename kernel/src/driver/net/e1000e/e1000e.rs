@@ -47,7 +47,7 @@ const E1000E_DEVICE_ID: [u16; 14] = [
 
 // e1000e网卡与BAR有关的常量
 // BAR0空间大小(128KB)
-const E1000E_BAR_REG_SIZE: u32 = 128 * 1024;
+const E1000E_BAR_REG_SIZE: u64 = 128 * 1024;
 // BAR0空间对齐(64bit)
 #[allow(dead_code)]
 const E1000E_BAR_REG_ALIGN: u8 = 64;
@@ -533,6 +533,11 @@ impl NetDevice for LoopbackInterface {
     fn set_operstate(&self, state: Operstate) {
         self.inner().netdevice_common.operstate = state;
     }
+
+    fn mtu(&self) -> usize {
+        // capabilities中的最大传输单元包含以太网帧头
+        65535 - 14
+    }
 }
 
 pub fn loopback_probe() {
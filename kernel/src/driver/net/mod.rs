@@ -3,6 +3,7 @@ use smoltcp::{
     iface,
     wire::{self, EthernetAddress},
 };
+use stats::NetDeviceStats;
 use sysfs::netdev_register_kobject;
 
 use super::base::device::Device;
@@ -14,8 +15,13 @@ mod dma;
 pub mod e1000e;
 pub mod irq_handle;
 pub mod loopback;
+mod mrg_rxbuf;
+mod rx_refill;
+pub mod stats;
 pub mod sysfs;
 pub mod virtio_net;
+pub mod virtio_net_ctrl;
+mod virtio_net_queue;
 
 bitflags! {
     pub struct NetDeivceState: u16 {
@@ -51,6 +57,43 @@ pub enum Operstate {
     IF_OPER_UP = 6,
 }
 
+/// 网卡的双工模式
+///
+/// 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/include/uapi/linux/ethtool.h#1906
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum NetDuplex {
+    Half,
+    Full,
+    #[default]
+    Unknown,
+}
+
+impl core::fmt::Display for NetDuplex {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let s = match self {
+            NetDuplex::Half => "half",
+            NetDuplex::Full => "full",
+            NetDuplex::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 网卡的链路速度和双工模式
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct NetLinkSettings {
+    /// 链路速度(Mbps)，None表示未知
+    pub speed: Option<u32>,
+    pub duplex: NetDuplex,
+}
+
+impl NetLinkSettings {
+    /// sysfs中speed属性的值，速度未知时为-1，与Linux的SPEED_UNKNOWN一致
+    pub fn speed_mbps(&self) -> i64 {
+        self.speed.map_or(-1, i64::from)
+    }
+}
+
 #[allow(dead_code)]
 pub trait NetDevice: Device {
     /// @brief 获取网卡的MAC地址
@@ -80,8 +123,70 @@ pub trait NetDevice: Device {
     fn operstate(&self) -> Operstate;
 
     fn set_operstate(&self, state: Operstate);
+
+    /// 获取网卡的MTU(不含以太网帧头)
+    fn mtu(&self) -> usize {
+        ETH_DATA_LEN
+    }
+
+    /// # 函数的功能
+    /// 修改网卡的MTU
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): MTU超出网卡支持的范围
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 网卡不支持修改MTU
+    fn set_mtu(&self, _mtu: usize) -> Result<(), SystemError> {
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+
+    /// # 函数的功能
+    /// 打开或关闭混杂模式，例如网卡被加入网桥时
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 网卡不支持修改接收模式
+    fn set_promisc(&self, _on: bool) -> Result<(), SystemError> {
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+
+    /// # 函数的功能
+    /// 打开或关闭接收所有多播帧
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 网卡不支持修改接收模式
+    fn set_allmulti(&self, _on: bool) -> Result<(), SystemError> {
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+
+    /// # 函数的功能
+    /// 修改网卡的MAC地址，成功后网络接口使用新的地址
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 网卡不支持修改MAC地址
+    fn set_mac(&self, _mac: EthernetAddress) -> Result<(), SystemError> {
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+
+    /// # 函数的功能
+    /// 返回网卡的链路速度和双工模式
+    ///
+    /// 不能查询链路速度的网卡返回未知
+    fn link_settings(&self) -> NetLinkSettings {
+        NetLinkSettings::default()
+    }
+
+    /// # 函数的功能
+    /// 返回网卡的收发统计
+    ///
+    /// ## 返回值
+    /// - None: 网卡不记录收发统计
+    fn stats(&self) -> Option<&NetDeviceStats> {
+        None
+    }
 }
 
+/// 以太网的标准MTU
+pub const ETH_DATA_LEN: usize = 1500;
+
 /// 网络设备的公共数据
 #[derive(Debug)]
 pub struct NetDeviceCommonData {
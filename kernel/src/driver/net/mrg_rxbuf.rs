@@ -0,0 +1,251 @@
+//! virtio-net的可合并接收缓冲区(VIRTIO_NET_F_MRG_RXBUF)
+//!
+//! 协商了该特性之后，设备可以把一个帧分散放到多个较小的接收缓冲区中，
+//! 第一个缓冲区开头的virtio_net_hdr中的num_buffers字段表示这个帧一共占用了多少个缓冲区。
+//! 这样接收队列中只需要放置较小的缓冲区，而不必让每个缓冲区都能容纳最大的帧。
+//!
+//! 参考 virtio spec 5.1.6.3.2 Device Requirements: Setting Up Receive Buffers
+
+use alloc::vec::Vec;
+use system_error::SystemError;
+
+/// 设备可以把一个帧放到多个接收缓冲区中
+pub const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+/// virtio_net_hdr_mrg_rxbuf的长度
+#[allow(dead_code)]
+pub const VIRTIO_NET_MRG_HDR_LEN: usize = 12;
+/// num_buffers字段在virtio_net_hdr_mrg_rxbuf中的偏移量
+#[allow(dead_code)]
+const VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
+/// 可合并接收缓冲区的大小，能够容纳一个标准以太网帧(含VLAN标签)和头部
+#[allow(dead_code)]
+pub const VIRTIO_NET_MRG_RX_BUF_LEN: usize = 1536;
+
+/// 接收队列，由具体的virtqueue实现
+#[allow(dead_code)]
+pub trait MrgRxQueue {
+    type Buffer: AsRef<[u8]>;
+
+    /// # 函数的功能
+    /// 取出一个设备已经写入数据的缓冲区
+    ///
+    /// ## 返回值
+    /// - Some((buf, len)): 缓冲区以及设备写入的字节数
+    /// - None: 没有已经使用的缓冲区
+    fn pop_used(&mut self) -> Option<(Self::Buffer, usize)>;
+
+    /// 把缓冲区重新提交给设备
+    fn post(&mut self, buf: Self::Buffer) -> Result<(), SystemError>;
+}
+
+/// # 函数的功能
+/// 计算在接收缓冲区大小为`buf_len`时，一个最大的帧需要占用多少个缓冲区
+///
+/// ## 参数
+/// - `frame_len`: 最大的以太网帧长度
+/// - `buf_len`: 每个接收缓冲区的大小，包含第一个缓冲区中的头部
+#[allow(dead_code)]
+pub fn mrg_rx_buffers_per_frame(frame_len: usize, buf_len: usize) -> usize {
+    (frame_len + VIRTIO_NET_MRG_HDR_LEN).div_ceil(buf_len)
+}
+
+/// # 结构功能
+/// 把分散在多个接收缓冲区中的帧重新组装起来
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct MrgRxAssembler {
+    /// 允许接收的最大帧长度，超过的帧会被丢弃
+    max_frame_len: usize,
+}
+
+#[allow(dead_code)]
+impl MrgRxAssembler {
+    pub fn new(max_frame_len: usize) -> Self {
+        Self { max_frame_len }
+    }
+
+    /// # 函数的功能
+    /// 从接收队列中取出一个完整的帧
+    ///
+    /// 帧占用的所有缓冲区在复制数据之后都会重新提交给设备，出错时也不例外
+    ///
+    /// ## 返回值
+    /// - None: 没有收到新的帧
+    /// - Some(Ok(frame)): 组装好的以太网帧，不含virtio_net_hdr
+    /// - Some(Err(SystemError::EINVAL)): 头部不完整，或者num_buffers为0
+    /// - Some(Err(SystemError::EIO)): 设备给出的缓冲区数量少于num_buffers
+    /// - Some(Err(SystemError::EMSGSIZE)): 帧超过了允许的最大长度，已被丢弃
+    pub fn receive<Q: MrgRxQueue>(
+        &mut self,
+        queue: &mut Q,
+    ) -> Option<Result<Vec<u8>, SystemError>> {
+        let (first, len) = queue.pop_used()?;
+        Some(self.assemble(queue, first, len))
+    }
+
+    fn assemble<Q: MrgRxQueue>(
+        &mut self,
+        queue: &mut Q,
+        first: Q::Buffer,
+        len: usize,
+    ) -> Result<Vec<u8>, SystemError> {
+        let data = &first.as_ref()[..len.min(first.as_ref().len())];
+        if data.len() < VIRTIO_NET_MRG_HDR_LEN {
+            queue.post(first)?;
+            return Err(SystemError::EINVAL);
+        }
+        let num_buffers = u16::from_le_bytes([
+            data[VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET],
+            data[VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET + 1],
+        ]);
+        if num_buffers == 0 {
+            queue.post(first)?;
+            return Err(SystemError::EINVAL);
+        }
+
+        let mut frame = Vec::new();
+        let mut oversized = false;
+        let mut append = |frame: &mut Vec<u8>, data: &[u8]| {
+            if oversized || frame.len() + data.len() > self.max_frame_len {
+                oversized = true;
+                return;
+            }
+            frame.extend_from_slice(data);
+        };
+        append(&mut frame, &data[VIRTIO_NET_MRG_HDR_LEN..]);
+        queue.post(first)?;
+
+        for _ in 1..num_buffers {
+            let (buf, len) = queue.pop_used().ok_or(SystemError::EIO)?;
+            append(&mut frame, &buf.as_ref()[..len.min(buf.as_ref().len())]);
+            queue.post(buf)?;
+        }
+
+        if oversized {
+            return Err(SystemError::EMSGSIZE);
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+
+    use super::*;
+
+    /// 模拟设备一侧的接收队列：把帧按照缓冲区大小切开，放到已使用的缓冲区中
+    struct MockRxQueue {
+        buf_len: usize,
+        used: VecDeque<(Vec<u8>, usize)>,
+        posted: usize,
+    }
+
+    impl MockRxQueue {
+        fn new(buf_len: usize) -> Self {
+            Self {
+                buf_len,
+                used: VecDeque::new(),
+                posted: 0,
+            }
+        }
+
+        /// 以设备的方式接收一个帧，返回占用的缓冲区数量
+        fn deliver(&mut self, frame: &[u8]) -> usize {
+            let mut data = vec![0u8; VIRTIO_NET_MRG_HDR_LEN];
+            data.extend_from_slice(frame);
+            let chunks: Vec<&[u8]> = data.chunks(self.buf_len).collect();
+            let n = chunks.len();
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                let mut buf = vec![0xaa; self.buf_len];
+                buf[..chunk.len()].copy_from_slice(chunk);
+                if i == 0 {
+                    buf[VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET..VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET + 2]
+                        .copy_from_slice(&(n as u16).to_le_bytes());
+                }
+                self.used.push_back((buf, chunk.len()));
+            }
+            n
+        }
+    }
+
+    impl MrgRxQueue for MockRxQueue {
+        type Buffer = Vec<u8>;
+
+        fn pop_used(&mut self) -> Option<(Self::Buffer, usize)> {
+            self.used.pop_front()
+        }
+
+        fn post(&mut self, buf: Self::Buffer) -> Result<(), SystemError> {
+            assert_eq!(buf.len(), self.buf_len);
+            self.posted += 1;
+            Ok(())
+        }
+    }
+
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_reassemble_multi_buffer_frame() {
+        let mut queue = MockRxQueue::new(256);
+        let mut assembler = MrgRxAssembler::new(9018);
+        let big = frame(1000);
+        let small = frame(60);
+        assert_eq!(queue.deliver(&big), 4);
+        assert_eq!(queue.deliver(&small), 1);
+
+        assert_eq!(assembler.receive(&mut queue), Some(Ok(big)));
+        assert_eq!(assembler.receive(&mut queue), Some(Ok(small)));
+        assert_eq!(assembler.receive(&mut queue), None);
+        // 所有缓冲区都被重新提交
+        assert_eq!(queue.posted, 5);
+    }
+
+    #[test]
+    fn test_oversized_frame_dropped() {
+        let mut queue = MockRxQueue::new(256);
+        let mut assembler = MrgRxAssembler::new(500);
+        let n = queue.deliver(&frame(1000));
+        queue.deliver(&frame(100));
+
+        assert_eq!(
+            assembler.receive(&mut queue),
+            Some(Err(SystemError::EMSGSIZE))
+        );
+        assert_eq!(queue.posted, n);
+        // 被丢弃的帧不影响后续的帧
+        assert_eq!(assembler.receive(&mut queue), Some(Ok(frame(100))));
+    }
+
+    #[test]
+    fn test_malformed_header() {
+        let mut queue = MockRxQueue::new(256);
+        let mut assembler = MrgRxAssembler::new(1514);
+        queue.used.push_back((vec![0; 256], 8));
+        queue.used.push_back((vec![0; 256], 64));
+        assert_eq!(
+            assembler.receive(&mut queue),
+            Some(Err(SystemError::EINVAL))
+        );
+        assert_eq!(
+            assembler.receive(&mut queue),
+            Some(Err(SystemError::EINVAL))
+        );
+        assert_eq!(queue.posted, 2);
+
+        // num_buffers超过设备实际给出的缓冲区数量
+        queue.deliver(&frame(1000));
+        queue.used.pop_back();
+        assert_eq!(assembler.receive(&mut queue), Some(Err(SystemError::EIO)));
+    }
+
+    #[test]
+    fn test_buffers_per_frame() {
+        assert_eq!(mrg_rx_buffers_per_frame(1514, VIRTIO_NET_MRG_RX_BUF_LEN), 1);
+        assert_eq!(mrg_rx_buffers_per_frame(9014, VIRTIO_NET_MRG_RX_BUF_LEN), 6);
+        assert_eq!(mrg_rx_buffers_per_frame(1524, VIRTIO_NET_MRG_RX_BUF_LEN), 1);
+        assert_eq!(mrg_rx_buffers_per_frame(1525, VIRTIO_NET_MRG_RX_BUF_LEN), 2);
+    }
+}
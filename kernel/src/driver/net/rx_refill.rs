@@ -0,0 +1,233 @@
+//! 网卡接收缓冲区的补充策略
+//!
+//! 接收队列中的缓冲区被设备填满并取走之后，需要重新提交新的缓冲区，否则队列会逐渐耗尽，
+//! 网卡就再也收不到数据包了。内存紧张的时候获取缓冲区可能失败，此时不应当panic，
+//! 而是先提交已经拿到的缓冲区，打印(限流的)警告，在下一次中断/轮询时再重试。
+
+use log::warn;
+use system_error::SystemError;
+
+/// 两次补充失败警告之间的最小间隔(毫秒)
+const RX_REFILL_WARN_INTERVAL_MS: i64 = 5000;
+
+/// 接收缓冲区的来源，由具体的网卡驱动实现
+pub trait RxBufferSource {
+    type Buffer;
+
+    /// # 函数的功能
+    /// 获取一个可以提交给设备的接收缓冲区
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOMEM): 内存不足或暂时没有可用的缓冲区
+    fn alloc_rx_buffer(&mut self) -> Result<Self::Buffer, SystemError>;
+
+    /// 把缓冲区提交到设备的接收队列
+    fn post_rx_buffer(&mut self, buf: Self::Buffer) -> Result<(), SystemError>;
+}
+
+/// # 结构功能
+/// 记录接收队列中已提交的缓冲区数量，并在数量低于水位线时补充缓冲区
+///
+/// 水位线让驱动在队列完全耗尽之前就开始补充，避免突发流量时出现丢包
+#[derive(Debug)]
+pub struct RxRefill {
+    name: &'static str,
+    /// 接收队列能容纳的缓冲区数量
+    capacity: usize,
+    /// 当前已提交给设备的缓冲区数量
+    posted: usize,
+    /// 已提交的缓冲区数量不超过这个值时需要补充
+    low_watermark: usize,
+    /// 上一次补充失败时是否没有补满，是的话下次中断时需要重试
+    starved: bool,
+    last_warn_ms: Option<i64>,
+    suppressed_warns: usize,
+}
+
+impl RxRefill {
+    /// # 函数的功能
+    /// 创建补充器。假定创建时接收队列已经被填满
+    ///
+    /// ## 参数
+    /// - `name`: 打印日志时使用的设备名称
+    /// - `capacity`: 接收队列能容纳的缓冲区数量
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            name,
+            capacity,
+            posted: capacity,
+            low_watermark: (capacity / 4).max(1),
+            starved: false,
+            last_warn_ms: None,
+            suppressed_warns: 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn posted(&self) -> usize {
+        self.posted
+    }
+
+    #[allow(dead_code)]
+    pub fn low_watermark(&self) -> usize {
+        self.low_watermark
+    }
+
+    /// 设备交回了一个装有数据包的缓冲区
+    pub fn on_buffer_used(&mut self) {
+        self.posted = self.posted.saturating_sub(1);
+    }
+
+    /// 已提交的缓冲区数量降到水位线，或者上一次补充没有补满时，需要补充
+    pub fn needs_refill(&self) -> bool {
+        self.posted < self.capacity && (self.starved || self.posted <= self.low_watermark)
+    }
+
+    /// # 函数的功能
+    /// 补充接收缓冲区，直到队列被填满或者获取缓冲区失败
+    ///
+    /// 获取失败时保留已经提交的缓冲区，打印限流的警告，等待下一次调用时重试
+    ///
+    /// ## 参数
+    /// - `source`: 缓冲区的来源
+    /// - `now_ms`: 当前时间(毫秒)，用于警告限流
+    ///
+    /// ## 返回值
+    /// 本次提交的缓冲区数量
+    pub fn refill<S: RxBufferSource>(&mut self, source: &mut S, now_ms: i64) -> usize {
+        let mut added = 0;
+        while self.posted < self.capacity {
+            let result = source
+                .alloc_rx_buffer()
+                .and_then(|buf| source.post_rx_buffer(buf));
+            if let Err(e) = result {
+                self.starved = true;
+                if self.should_warn(now_ms) {
+                    warn!(
+                        "{}: rx refill failed: {:?}, {}/{} buffers posted ({} similar warnings suppressed)",
+                        self.name, e, self.posted, self.capacity, self.suppressed_warns
+                    );
+                    self.suppressed_warns = 0;
+                }
+                return added;
+            }
+            self.posted += 1;
+            added += 1;
+        }
+
+        self.starved = false;
+        added
+    }
+
+    /// 距离上一次警告超过间隔时才允许再次警告，否则记为被抑制
+    fn should_warn(&mut self, now_ms: i64) -> bool {
+        match self.last_warn_ms {
+            Some(last) if now_ms - last < RX_REFILL_WARN_INTERVAL_MS => {
+                self.suppressed_warns += 1;
+                false
+            }
+            _ => {
+                self.last_warn_ms = Some(now_ms);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 模拟内存紧张的缓冲区来源：分配`budget`个缓冲区之后，后续分配都会失败
+    struct MockRxSource {
+        budget: usize,
+        posted: usize,
+    }
+
+    impl MockRxSource {
+        fn new(budget: usize) -> Self {
+            Self { budget, posted: 0 }
+        }
+    }
+
+    impl RxBufferSource for MockRxSource {
+        type Buffer = [u8; 16];
+
+        fn alloc_rx_buffer(&mut self) -> Result<Self::Buffer, SystemError> {
+            if self.budget == 0 {
+                return Err(SystemError::ENOMEM);
+            }
+            self.budget -= 1;
+            Ok([0; 16])
+        }
+
+        fn post_rx_buffer(&mut self, _buf: Self::Buffer) -> Result<(), SystemError> {
+            self.posted += 1;
+            Ok(())
+        }
+    }
+
+    fn drain(refill: &mut RxRefill, n: usize) {
+        for _ in 0..n {
+            refill.on_buffer_used();
+        }
+    }
+
+    #[test]
+    fn test_watermark() {
+        let mut refill = RxRefill::new("test", 8);
+        assert_eq!(refill.low_watermark(), 2);
+        assert!(!refill.needs_refill());
+
+        drain(&mut refill, 5);
+        assert!(!refill.needs_refill());
+        drain(&mut refill, 1);
+        assert!(refill.needs_refill());
+
+        let mut source = MockRxSource::new(usize::MAX);
+        assert_eq!(refill.refill(&mut source, 0), 6);
+        assert_eq!(refill.posted(), 8);
+        assert!(!refill.needs_refill());
+    }
+
+    #[test]
+    fn test_alloc_failure_keeps_partial_refill() {
+        let mut refill = RxRefill::new("test", 8);
+        drain(&mut refill, 8);
+
+        // 只能分配3个缓冲区，已提交的缓冲区要保留
+        let mut source = MockRxSource::new(3);
+        assert_eq!(refill.refill(&mut source, 0), 3);
+        assert_eq!(refill.posted(), 3);
+        assert_eq!(source.posted, 3);
+
+        // 虽然高于水位线，但没有补满，下次中断时仍然需要重试
+        assert!(refill.needs_refill());
+        assert_eq!(refill.refill(&mut source, 10), 0);
+
+        // 内存恢复之后补满
+        source.budget = usize::MAX;
+        assert_eq!(refill.refill(&mut source, 20), 5);
+        assert_eq!(refill.posted(), 8);
+        assert!(!refill.needs_refill());
+    }
+
+    #[test]
+    fn test_warning_rate_limited() {
+        let mut refill = RxRefill::new("test", 4);
+        drain(&mut refill, 4);
+        let mut source = MockRxSource::new(0);
+
+        refill.refill(&mut source, 0);
+        assert_eq!(refill.last_warn_ms, Some(0));
+        for t in [1, 100, RX_REFILL_WARN_INTERVAL_MS - 1] {
+            refill.refill(&mut source, t);
+        }
+        assert_eq!(refill.suppressed_warns, 3);
+        assert_eq!(refill.last_warn_ms, Some(0));
+
+        refill.refill(&mut source, RX_REFILL_WARN_INTERVAL_MS);
+        assert_eq!(refill.last_warn_ms, Some(RX_REFILL_WARN_INTERVAL_MS));
+        assert_eq!(refill.suppressed_warns, 0);
+    }
+}
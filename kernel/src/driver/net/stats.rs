@@ -0,0 +1,153 @@
+//! 网卡的收发统计
+//!
+//! 计数器由顺序锁保护，收发路径(包括中断上下文)可以在不持有设备锁的情况下更新它们，
+//! 读者总能得到一组一致的值，例如数据包数和字节数来自同一时刻。
+//! 统计信息通过`/sys/class/net/<iface>/statistics/`导出
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/include/uapi/linux/if_link.h#42
+
+use crate::libs::seqlock::SeqLock;
+
+/// # 结构功能
+/// 网卡的收发计数器
+#[derive(Debug, Default)]
+pub struct NetDeviceStats {
+    inner: SeqLock<NetDeviceStatsSnapshot>,
+}
+
+/// 某一时刻的统计信息
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetDeviceStatsSnapshot {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_dropped: u64,
+    pub tx_errors: u64,
+}
+
+impl NetDeviceStats {
+    pub const fn new() -> Self {
+        Self {
+            inner: SeqLock::new(NetDeviceStatsSnapshot {
+                rx_packets: 0,
+                tx_packets: 0,
+                rx_bytes: 0,
+                tx_bytes: 0,
+                rx_dropped: 0,
+                tx_errors: 0,
+            }),
+        }
+    }
+
+    /// 协议栈收到了一个长度为`len`的数据包
+    pub fn record_rx(&self, len: usize) {
+        self.inner.write(|s| {
+            s.rx_packets += 1;
+            s.rx_bytes += len as u64;
+        });
+    }
+
+    /// 成功发送了一个长度为`len`的数据包
+    pub fn record_tx(&self, len: usize) {
+        self.inner.write(|s| {
+            s.tx_packets += 1;
+            s.tx_bytes += len as u64;
+        });
+    }
+
+    /// 收到的数据包没有交给协议栈就被丢弃了
+    pub fn record_rx_dropped(&self) {
+        self.inner.write(|s| s.rx_dropped += 1);
+    }
+
+    /// 发送数据包失败
+    pub fn record_tx_error(&self) {
+        self.inner.write(|s| s.tx_errors += 1);
+    }
+
+    /// 读取所有计数器，得到的各个值来自同一时刻
+    pub fn snapshot(&self) -> NetDeviceStatsSnapshot {
+        self.inner.read()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::VecDeque, vec::Vec};
+
+    use super::*;
+
+    /// 模拟的网卡：发送的数据包环回到接收队列，发送队列满时发送失败
+    struct MockNic {
+        stats: NetDeviceStats,
+        rx_queue: VecDeque<Vec<u8>>,
+        tx_capacity: usize,
+    }
+
+    impl MockNic {
+        fn new(tx_capacity: usize) -> Self {
+            Self {
+                stats: NetDeviceStats::new(),
+                rx_queue: VecDeque::new(),
+                tx_capacity,
+            }
+        }
+
+        fn transmit(&mut self, packet: &[u8]) -> bool {
+            if self.rx_queue.len() >= self.tx_capacity {
+                self.stats.record_tx_error();
+                return false;
+            }
+            self.rx_queue.push_back(packet.to_vec());
+            self.stats.record_tx(packet.len());
+            true
+        }
+
+        /// 接收一个数据包，`consume`为false时模拟协议栈丢弃了它
+        fn receive(&mut self, consume: bool) -> Option<usize> {
+            let packet = self.rx_queue.pop_front()?;
+            if consume {
+                self.stats.record_rx(packet.len());
+            } else {
+                self.stats.record_rx_dropped();
+            }
+            Some(packet.len())
+        }
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut nic = MockNic::new(3);
+        for len in [60, 1514, 100] {
+            assert!(nic.transmit(&vec![0u8; len]));
+        }
+        // 队列已满
+        assert!(!nic.transmit(&[0u8; 64]));
+
+        assert_eq!(nic.receive(true), Some(60));
+        assert_eq!(nic.receive(false), Some(1514));
+        assert_eq!(nic.receive(true), Some(100));
+        assert_eq!(nic.receive(true), None);
+
+        assert_eq!(
+            nic.stats.snapshot(),
+            NetDeviceStatsSnapshot {
+                rx_packets: 2,
+                tx_packets: 3,
+                rx_bytes: 160,
+                tx_bytes: 1674,
+                rx_dropped: 1,
+                tx_errors: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_default_is_zero() {
+        assert_eq!(
+            NetDeviceStats::default().snapshot(),
+            NetDeviceStatsSnapshot::default()
+        );
+    }
+}
@@ -17,7 +17,11 @@ use intertrait::cast::CastArc;
 use log::error;
 use system_error::SystemError;
 
-use super::{class::sys_class_net_instance, NetDeivceState, NetDevice, Operstate};
+use super::{
+    class::sys_class_net_instance,
+    stats::{NetDeviceStats, NetDeviceStatsSnapshot},
+    NetDeivceState, NetDevice, Operstate,
+};
 
 /// 将设备注册到`/sys/class/net`目录下
 /// 参考：https://code.dragonos.org.cn/xref/linux-2.6.39/net/core/net-sysfs.c?fi=netdev_register_kobject#1311
@@ -409,8 +413,13 @@ impl Attribute for AttrSpeed {
         SysFSOpsSupport::ATTR_SHOW
     }
 
-    fn show(&self, _kobj: Arc<dyn KObject>, _buf: &mut [u8]) -> Result<usize, SystemError> {
-        todo!("AttrSpeed::show")
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let net_device = kobj.cast::<dyn NetDevice>().map_err(|_| {
+            error!("AttrSpeed::show() failed: kobj is not a NetDevice");
+            SystemError::EINVAL
+        })?;
+        let speed = net_device.link_settings().speed_mbps();
+        sysfs_emit_str(buf, &format!("{}\n", speed))
     }
 }
 
@@ -434,8 +443,13 @@ impl Attribute for AttrDuplex {
         SysFSOpsSupport::ATTR_SHOW
     }
 
-    fn show(&self, _kobj: Arc<dyn KObject>, _buf: &mut [u8]) -> Result<usize, SystemError> {
-        todo!("AttrDuplex::show")
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let net_device = kobj.cast::<dyn NetDevice>().map_err(|_| {
+            error!("AttrDuplex::show() failed: kobj is not a NetDevice");
+            SystemError::EINVAL
+        })?;
+        let duplex = net_device.link_settings().duplex;
+        sysfs_emit_str(buf, &format!("{}\n", duplex))
     }
 }
 
@@ -524,19 +538,33 @@ impl Attribute for AttrMtu {
     }
 
     fn mode(&self) -> ModeType {
-        SYSFS_ATTR_MODE_RO
+        SYSFS_ATTR_MODE_RW
     }
 
     fn support(&self) -> SysFSOpsSupport {
-        SysFSOpsSupport::ATTR_SHOW
+        SysFSOpsSupport::ATTR_SHOW | SysFSOpsSupport::ATTR_STORE
     }
 
-    fn show(&self, _kobj: Arc<dyn KObject>, _buf: &mut [u8]) -> Result<usize, SystemError> {
-        todo!("AttrMtu::show")
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let net_device = kobj.cast::<dyn NetDevice>().map_err(|_| {
+            error!("AttrMtu::show() failed: kobj is not a NetDevice");
+            SystemError::EINVAL
+        })?;
+        sysfs_emit_str(buf, &format!("{}\n", net_device.mtu()))
     }
 
-    fn store(&self, _kobj: Arc<dyn KObject>, _buf: &[u8]) -> Result<usize, SystemError> {
-        todo!("AttrMtu::store")
+    fn store(&self, kobj: Arc<dyn KObject>, buf: &[u8]) -> Result<usize, SystemError> {
+        let net_device = kobj.cast::<dyn NetDevice>().map_err(|_| {
+            error!("AttrMtu::store() failed: kobj is not a NetDevice");
+            SystemError::EINVAL
+        })?;
+        let mtu = core::str::from_utf8(buf)
+            .map_err(|_| SystemError::EINVAL)?
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| SystemError::EINVAL)?;
+        net_device.set_mtu(mtu)?;
+        return Ok(buf.len());
     }
 }
 
@@ -633,3 +661,64 @@ impl Attribute for AttrNetdevGroup {
         todo!("AttrNetdevGroup::store")
     }
 }
+
+/// 网卡的收发统计，位于`statistics`目录下
+///
+/// 参考：https://code.dragonos.org.cn/xref/linux-6.6.21/net/core/net-sysfs.c#624
+#[derive(Debug)]
+pub struct NetStatsAttrGroup;
+
+impl AttributeGroup for NetStatsAttrGroup {
+    fn name(&self) -> Option<&str> {
+        Some("statistics")
+    }
+
+    fn attrs(&self) -> &[&'static dyn Attribute] {
+        &[
+            &ATTR_RX_PACKETS,
+            &ATTR_TX_PACKETS,
+            &ATTR_RX_BYTES,
+            &ATTR_TX_BYTES,
+            &ATTR_RX_DROPPED,
+            &ATTR_TX_ERRORS,
+        ]
+    }
+}
+
+static ATTR_RX_PACKETS: AttrStat = AttrStat("rx_packets", |s| s.rx_packets);
+static ATTR_TX_PACKETS: AttrStat = AttrStat("tx_packets", |s| s.tx_packets);
+static ATTR_RX_BYTES: AttrStat = AttrStat("rx_bytes", |s| s.rx_bytes);
+static ATTR_TX_BYTES: AttrStat = AttrStat("tx_bytes", |s| s.tx_bytes);
+static ATTR_RX_DROPPED: AttrStat = AttrStat("rx_dropped", |s| s.rx_dropped);
+static ATTR_TX_ERRORS: AttrStat = AttrStat("tx_errors", |s| s.tx_errors);
+
+/// # 收发统计中的一个计数器
+/// 不记录收发统计的网卡，所有计数器都为0
+#[derive(Debug)]
+struct AttrStat(&'static str, fn(&NetDeviceStatsSnapshot) -> u64);
+
+impl Attribute for AttrStat {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let net_device = kobj.cast::<dyn NetDevice>().map_err(|_| {
+            error!("AttrStat::show() failed: kobj is not a NetDevice");
+            SystemError::EINVAL
+        })?;
+        let stats = net_device
+            .stats()
+            .map(NetDeviceStats::snapshot)
+            .unwrap_or_default();
+        sysfs_emit_str(buf, &format!("{}\n", (self.1)(&stats)))
+    }
+}
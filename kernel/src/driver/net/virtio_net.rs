@@ -2,21 +2,37 @@ use core::{
     any::Any,
     cell::UnsafeCell,
     fmt::Debug,
-    ops::{Deref, DerefMut},
+    mem::{offset_of, size_of},
+    ops::{ControlFlow, Deref, DerefMut},
 };
 
 use alloc::{
     collections::LinkedList,
     string::{String, ToString},
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
-use log::{debug, error};
+use log::{debug, error, warn};
 use smoltcp::{iface, phy, wire};
 use unified_init::macros::unified_init;
-use virtio_drivers::device::net::VirtIONet;
-
-use super::{NetDeivceState, NetDevice, NetDeviceCommonData, Operstate};
+use virtio_drivers::transport::Transport;
+
+use super::{
+    mrg_rxbuf::VIRTIO_NET_F_MRG_RXBUF,
+    rx_refill::{RxBufferSource, RxRefill},
+    stats::NetDeviceStats,
+    virtio_net_ctrl::{
+        virtio_net_mac_addr_cmd, virtio_net_mac_table_plan, virtio_net_rx_mode_cmds,
+        VirtIONetCtrlCmd, VirtIONetRxMode,
+    },
+    virtio_net_queue::{
+        VirtIONetQueue, VirtIONetRxBuf, VIRTIO_NET_F_MAC, VIRTIO_NET_HDR_LEN, VIRTIO_NET_RX_QUEUE,
+        VIRTIO_NET_TX_QUEUE,
+    },
+    NetDeivceState, NetDevice, NetDeviceCommonData, NetDuplex, NetLinkSettings, Operstate,
+    ETH_DATA_LEN,
+};
 use crate::{
     arch::rand::rand,
     driver::{
@@ -32,12 +48,16 @@ use crate::{
         },
         net::register_netdevice,
         virtio::{
+            config::{virtio_config_read, VirtIOConfigAccess},
             irq::virtio_irq_manager,
+            reset::{virtio_reset_device, VIRTIO_F_RING_RESET},
+            ring::VirtQueueSizePolicy,
+            router::virtio_device_router,
             sysfs::{virtio_bus, virtio_device_manager, virtio_driver_manager},
-            transport::VirtIOTransport,
-            virtio_impl::HalImpl,
+            teardown::{virtio_teardown_queues, VirtQueueOwner, VIRTIO_TEARDOWN_MAX_POLLS},
+            transport::{VirtIOIrqType, VirtIOIsr, VirtIOTransport, VIRTIO_F_VERSION_1},
             VirtIODevice, VirtIODeviceIndex, VirtIODriver, VirtIODriverCommonData, VirtioDeviceId,
-            VIRTIO_VENDOR_ID,
+            VirtioDeviceType, VIRTIO_VENDOR_ID,
         },
     },
     exception::{irqdesc::IrqReturn, IrqNumber},
@@ -56,6 +76,28 @@ static mut VIRTIO_NET_DRIVER: Option<Arc<VirtIONetDriver>> = None;
 
 const VIRTIO_NET_BASENAME: &str = "virtio_net";
 
+/// 接收队列和发送队列的编号
+const VIRTIO_NET_QUEUES: [u16; 2] = [VIRTIO_NET_RX_QUEUE, VIRTIO_NET_TX_QUEUE];
+/// 收发队列的大小
+const VIRTIO_NET_QUEUE_POLICY: VirtQueueSizePolicy = VirtQueueSizePolicy::new(16, 256);
+/// 驱动支持的特性
+const VIRTIO_NET_SUPPORTED_FEATURES: u64 = VIRTIO_NET_F_MAC
+    | VIRTIO_NET_F_STATUS
+    | VIRTIO_NET_F_MTU
+    | VIRTIO_NET_F_SPEED_DUPLEX
+    | VIRTIO_F_VERSION_1
+    | VIRTIO_F_RING_RESET;
+
+/// 设备没有提供MTU时使用的默认MTU，可以通过内核命令行参数`virtio_net_mtu`覆盖
+const VIRTIO_NET_DEFAULT_MTU: u16 = 1500;
+kernel_cmdline_param_kv!(VIRTIO_NET_MTU_PARAM, virtio_net_mtu, "");
+/// 以太网允许的最小MTU
+const VIRTIO_NET_MIN_MTU: u16 = 68;
+/// 设备没有提供MTU时允许设置的最大MTU
+const VIRTIO_NET_MAX_MTU: u16 = u16::MAX;
+/// 带一个VLAN标签的以太网帧头长度
+const VIRTIO_NET_VLAN_ETH_HLEN: usize = 18;
+
 #[inline(always)]
 #[allow(dead_code)]
 fn virtio_net_driver() -> Arc<VirtIONetDriver> {
@@ -68,6 +110,8 @@ fn virtio_net_driver() -> Arc<VirtIONetDriver> {
 #[cast_to([sync] Device)]
 pub struct VirtIONetDevice {
     dev_id: Arc<DeviceId>,
+    irq_type: VirtIOIrqType,
+    isr: Option<VirtIOIsr>,
     inner: SpinLock<InnerVirtIONetDevice>,
     locked_kobj_state: LockedKObjectState,
 }
@@ -77,6 +121,14 @@ unsafe impl Sync for VirtIONetDevice {}
 
 struct InnerVirtIONetDevice {
     device_inner: VirtIONicDeviceInner,
+    /// 指向同一设备的transport，用于读取配置空间。mmio transport不支持复制，此时为None
+    ctrl_transport: Option<VirtIOTransport>,
+    /// 当前的接收模式
+    rx_mode: VirtIONetRxMode,
+    /// MAC地址过滤表放不下时额外打开的接收模式，设备实际的接收模式为`rx_mode | mac_overflow`
+    mac_overflow: VirtIONetRxMode,
+    /// 设备通过VIRTIO_NET_F_SPEED_DUPLEX提供的链路速度和双工模式，配置变化时更新
+    link_settings: NetLinkSettings,
     name: Option<String>,
     virtio_index: Option<VirtIODeviceIndex>,
     kobj_common: KObjectCommonData,
@@ -90,23 +142,49 @@ impl Debug for InnerVirtIONetDevice {
 }
 
 impl VirtIONetDevice {
-    pub fn new(transport: VirtIOTransport, dev_id: Arc<DeviceId>) -> Option<Arc<Self>> {
-        let driver_net: VirtIONet<HalImpl, VirtIOTransport, 2> =
-            match VirtIONet::<HalImpl, VirtIOTransport, 2>::new(transport, 4096) {
-                Ok(net) => net,
-                Err(_) => {
-                    error!("VirtIONet init failed");
-                    return None;
-                }
-            };
+    pub fn new(mut transport: VirtIOTransport, dev_id: Arc<DeviceId>) -> Option<Arc<Self>> {
+        let irq_type = transport.irq_type();
+        let isr = transport.isr();
+        let ctrl_transport = transport.try_clone();
+        let device_features = transport.read_device_features();
+        let link_settings = virtio_net_link_settings(&transport, device_features);
+        let mtu = VirtIONetMtu::new(
+            virtio_net_default_mtu(),
+            virtio_net_device_mtu(&mut transport),
+        );
+        if transport.read_device_features() & VIRTIO_NET_F_MRG_RXBUF != 0 {
+            // 目前不协商可合并接收缓冲区，因此每个接收缓冲区仍然要能容纳一个完整的帧
+            debug!(
+                "VirtIONetDevice '{:?}': device offers MRG_RXBUF, using full-size rx buffers",
+                dev_id
+            );
+        }
+        let driver_net = match VirtIONetQueue::new(
+            transport,
+            mtu.rx_buf_len(),
+            &VIRTIO_NET_QUEUE_POLICY,
+            VIRTIO_NET_SUPPORTED_FEATURES,
+        ) {
+            Ok(net) => net,
+            Err(e) => {
+                error!("VirtIONetDevice '{:?}' init failed: {:?}", dev_id, e);
+                return None;
+            }
+        };
         let mac = wire::EthernetAddress::from_bytes(&driver_net.mac_address());
-        debug!("VirtIONetDevice mac: {:?}", mac);
-        let device_inner = VirtIONicDeviceInner::new(driver_net);
+        debug!("VirtIONetDevice mac: {:?}, mtu: {:?}", mac, mtu);
+        let device_inner = VirtIONicDeviceInner::new(driver_net, mtu);
 
         let dev = Arc::new(Self {
             dev_id,
+            irq_type,
+            isr,
             inner: SpinLock::new(InnerVirtIONetDevice {
                 device_inner,
+                ctrl_transport,
+                rx_mode: VirtIONetRxMode::empty(),
+                mac_overflow: VirtIONetRxMode::empty(),
+                link_settings,
                 name: None,
                 virtio_index: None,
                 kobj_common: KObjectCommonData::default(),
@@ -123,6 +201,351 @@ impl VirtIONetDevice {
     fn inner(&self) -> SpinLockGuard<InnerVirtIONetDevice> {
         return self.inner.lock();
     }
+
+    /// # 函数的功能
+    /// 读取设备配置空间中的链路状态
+    ///
+    /// ## 返回值
+    /// - Some(true): 链路已连接
+    /// - Some(false): 链路已断开
+    /// - None: 设备不支持VIRTIO_NET_F_STATUS，或者无法访问配置空间
+    pub fn link_up(&self) -> Option<bool> {
+        let mut inner = self.inner();
+        // 重新初始化失败之后设备不能收发数据包，视为链路断开
+        if !inner.device_inner.inner.lock_irqsave().is_ready() {
+            return Some(false);
+        }
+        let transport = inner.ctrl_transport.as_mut()?;
+        // 驱动会协商设备提供的VIRTIO_NET_F_STATUS
+        if transport.read_device_features() & VIRTIO_NET_F_STATUS == 0 {
+            return None;
+        }
+
+        let status = transport
+            .config_read::<u16>(offset_of!(VirtIONetConfigHead, status))
+            .ok()?;
+        Some(status & VIRTIO_NET_S_LINK_UP != 0)
+    }
+
+    /// 设备的链路速度和双工模式，设备没有提供时为未知
+    pub fn link_settings(&self) -> NetLinkSettings {
+        self.inner().link_settings
+    }
+
+    /// 重新读取配置空间中的链路速度和双工模式
+    fn update_link_settings(&self) {
+        let mut inner = self.inner();
+        let Some(transport) = inner.ctrl_transport.as_mut() else {
+            return;
+        };
+        let device_features = transport.read_device_features();
+        let link_settings = virtio_net_link_settings(&*transport, device_features);
+        inner.link_settings = link_settings;
+    }
+
+    /// # 函数的功能
+    /// 修改MTU，并按照新的MTU重新分配接收缓冲区
+    ///
+    /// 接收缓冲区属于virtqueue，因此需要复位并重新初始化设备。
+    /// 协议栈手里属于旧队列的接收缓冲区在归还时会被丢弃
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): MTU超出范围。设备提供了MTU时，它就是上限
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): transport不支持复制，无法重新初始化设备
+    /// - Err(SystemError::EIO): 重新初始化设备失败。如果也无法恢复原来的MTU，
+    ///   设备将不能收发数据包，链路视为断开，直到再次成功修改MTU
+    pub fn set_mtu(&self, mtu: u16) -> Result<(), SystemError> {
+        let mut inner = self.inner();
+        let nic = inner.device_inner.clone();
+        let mut net = nic.inner.lock_irqsave();
+        let old_mtu = net.mtu;
+        let new_mtu = old_mtu.with_mtu(mtu)?;
+        if new_mtu == old_mtu {
+            return Ok(());
+        }
+        let ctrl_transport = inner
+            .ctrl_transport
+            .as_mut()
+            .ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)?;
+
+        if let Err(e) = net.reinit(ctrl_transport, new_mtu) {
+            error!(
+                "VirtIONetDevice '{:?}': failed to change mtu to {}: {:?}",
+                self.dev_id, mtu, e
+            );
+            // 尽量恢复到原来的MTU，让网卡继续可用
+            if let Err(e) = net.reinit(ctrl_transport, old_mtu) {
+                error!(
+                    "VirtIONetDevice '{:?}': failed to restore mtu {}, device is down: {:?}",
+                    self.dev_id,
+                    old_mtu.mtu(),
+                    e
+                );
+            }
+            return Err(SystemError::EIO);
+        }
+        return Ok(());
+    }
+
+    /// # 函数的功能
+    /// 修改网卡的接收模式，通过控制队列发送VIRTIO_NET_CTRL_RX命令
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商VIRTIO_NET_F_CTRL_VQ以及需要的
+    ///   VIRTIO_NET_F_CTRL_RX特性，或者transport无法读回协商的特性
+    /// - Err(SystemError::EIO): 设备执行命令失败
+    pub fn set_rx_mode(&self, mode: VirtIONetRxMode) -> Result<(), SystemError> {
+        let mut inner = self.inner();
+        let features = Self::negotiated_features(&mut inner)?;
+        let overflow = inner.mac_overflow;
+        let cmds = virtio_net_rx_mode_cmds(features, inner.rx_mode | overflow, mode | overflow)?;
+        for cmd in cmds.iter() {
+            self.send_ctrl_cmd(cmd)?;
+        }
+        inner.rx_mode = mode;
+        return Ok(());
+    }
+
+    /// 打开或关闭混杂模式
+    pub fn set_promisc(&self, on: bool) -> Result<(), SystemError> {
+        let mut mode = self.inner().rx_mode;
+        mode.set(VirtIONetRxMode::PROMISC, on);
+        self.set_rx_mode(mode)
+    }
+
+    /// 打开或关闭接收所有多播帧
+    pub fn set_allmulti(&self, on: bool) -> Result<(), SystemError> {
+        let mut mode = self.inner().rx_mode;
+        mode.set(VirtIONetRxMode::ALLMULTI, on);
+        self.set_rx_mode(mode)
+    }
+
+    /// # 函数的功能
+    /// 设置MAC地址过滤表，通过控制队列发送VIRTIO_NET_CTRL_MAC_TABLE_SET命令
+    ///
+    /// 某类地址超过过滤表的容量时，改为接收这类所有的帧(见[`virtio_net_mac_table_plan`])，
+    /// 之后过滤表放得下时再恢复
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商VIRTIO_NET_F_CTRL_VQ以及VIRTIO_NET_F_CTRL_RX，
+    ///   或者transport无法读回协商的特性
+    /// - Err(SystemError::EIO): 设备执行命令失败
+    #[allow(dead_code)]
+    pub fn set_mac_table(
+        &self,
+        unicast: &[wire::EthernetAddress],
+        multicast: &[wire::EthernetAddress],
+    ) -> Result<(), SystemError> {
+        let mut inner = self.inner();
+        let features = Self::negotiated_features(&mut inner)?;
+        let unicast: Vec<[u8; 6]> = unicast.iter().map(|mac| mac.0).collect();
+        let multicast: Vec<[u8; 6]> = multicast.iter().map(|mac| mac.0).collect();
+        let plan = virtio_net_mac_table_plan(features, &unicast, &multicast)?;
+        let cmds = virtio_net_rx_mode_cmds(
+            features,
+            inner.rx_mode | inner.mac_overflow,
+            inner.rx_mode | plan.overflow,
+        )?;
+
+        self.send_ctrl_cmd(&plan.cmd)?;
+        for cmd in cmds.iter() {
+            self.send_ctrl_cmd(cmd)?;
+        }
+        inner.mac_overflow = plan.overflow;
+        return Ok(());
+    }
+
+    /// # 函数的功能
+    /// 修改网卡的MAC地址，通过控制队列发送VIRTIO_NET_CTRL_MAC_ADDR_SET命令
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商VIRTIO_NET_F_CTRL_VQ以及VIRTIO_NET_F_CTRL_MAC_ADDR，
+    ///   或者transport无法读回协商的特性
+    /// - Err(SystemError::EIO): 设备执行命令失败
+    pub fn set_mac_address(&self, mac: wire::EthernetAddress) -> Result<(), SystemError> {
+        let mut inner = self.inner();
+        let features = Self::negotiated_features(&mut inner)?;
+        let cmd = virtio_net_mac_addr_cmd(features, mac.0)?;
+        self.send_ctrl_cmd(&cmd)
+    }
+
+    /// 读回驱动与设备协商的特性
+    fn negotiated_features(inner: &mut InnerVirtIONetDevice) -> Result<u64, SystemError> {
+        inner
+            .ctrl_transport
+            .as_mut()
+            .and_then(|transport| transport.negotiated_features())
+            .ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+
+    /// # 函数的功能
+    /// 通过控制队列发送一条命令，并检查设备写入的结果
+    ///
+    /// 驱动目前不协商VIRTIO_NET_F_CTRL_VQ，也不会创建控制队列，
+    /// 因此目前发送命令的函数在检查协商的特性时就会返回错误，不会走到这里
+    fn send_ctrl_cmd(&self, cmd: &VirtIONetCtrlCmd) -> Result<(), SystemError> {
+        debug!(
+            "VirtIONetDevice '{:?}': no control virtqueue for {:?}",
+            self.dev_id, cmd
+        );
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+}
+
+/// # 函数的功能
+/// 没有设备提供的MTU时使用的默认MTU
+///
+/// 内核命令行参数`virtio_net_mtu`不合法时会被忽略
+fn virtio_net_default_mtu() -> u16 {
+    let Some(param) = VIRTIO_NET_MTU_PARAM.value_str().filter(|s| !s.is_empty()) else {
+        return VIRTIO_NET_DEFAULT_MTU;
+    };
+    match param.parse::<u16>() {
+        Ok(mtu) if mtu >= VIRTIO_NET_MIN_MTU => mtu,
+        _ => {
+            warn!("virtio_net: ignoring invalid virtio_net_mtu={}", param);
+            VIRTIO_NET_DEFAULT_MTU
+        }
+    }
+}
+
+/// # 函数的功能
+/// 读取设备通过VIRTIO_NET_F_MTU提供的MTU
+///
+/// ## 返回值
+/// - Some(mtu): 设备提供的MTU
+/// - None: 设备没有提供VIRTIO_NET_F_MTU，或者提供的值小于以太网允许的最小MTU
+fn virtio_net_device_mtu(transport: &mut VirtIOTransport) -> Option<u16> {
+    if transport.read_device_features() & VIRTIO_NET_F_MTU == 0 {
+        return None;
+    }
+    let mtu = transport
+        .config_read::<u16>(VIRTIO_NET_CONFIG_MTU_OFFSET)
+        .ok()?;
+    if mtu < VIRTIO_NET_MIN_MTU {
+        warn!(
+            "virtio_net: device advertised invalid mtu {}, ignoring",
+            mtu
+        );
+        return None;
+    }
+    Some(mtu)
+}
+
+/// # 结构功能
+/// virtio-net的MTU，以及允许设置的上限
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct VirtIONetMtu {
+    mtu: u16,
+    max_mtu: u16,
+}
+
+impl VirtIONetMtu {
+    /// # 函数的功能
+    /// 确定初始的MTU
+    ///
+    /// ## 参数
+    /// - `default_mtu`: 设备没有提供MTU时使用的MTU
+    /// - `device_mtu`: 设备通过VIRTIO_NET_F_MTU提供的MTU，它同时是允许设置的上限
+    fn new(default_mtu: u16, device_mtu: Option<u16>) -> Self {
+        match device_mtu {
+            Some(mtu) => Self { mtu, max_mtu: mtu },
+            None => Self {
+                mtu: default_mtu.clamp(VIRTIO_NET_MIN_MTU, VIRTIO_NET_MAX_MTU),
+                max_mtu: VIRTIO_NET_MAX_MTU,
+            },
+        }
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// # 函数的功能
+    /// 得到修改MTU之后的结果
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): MTU小于以太网允许的最小值，或者超过上限
+    fn with_mtu(&self, mtu: u16) -> Result<Self, SystemError> {
+        if mtu < VIRTIO_NET_MIN_MTU || mtu > self.max_mtu {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(Self { mtu, ..*self })
+    }
+
+    /// 包含以太网帧头在内的最大帧长度，即smoltcp所说的MTU
+    fn frame_len(&self) -> usize {
+        self.mtu as usize + 14
+    }
+
+    /// # 函数的功能
+    /// 接收缓冲区的大小，需要容纳virtio_net_hdr和一个完整的以太网帧
+    ///
+    /// 没有协商VIRTIO_NET_F_MTU时，设备仍可能发来1500字节的帧，
+    /// 因此缓冲区不会小于标准MTU所需的大小
+    fn rx_buf_len(&self) -> usize {
+        let mtu = (self.mtu as usize).max(ETH_DATA_LEN);
+        (VIRTIO_NET_HDR_LEN + VIRTIO_NET_VLAN_ETH_HLEN + mtu).next_multiple_of(size_of::<usize>())
+    }
+}
+
+/// 设备会在配置空间中提供链路状态
+///
+/// 参考 virtio spec 5.1.3 Feature bits
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+/// 设备会在配置空间中提供它支持的最大MTU
+const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+/// 配置空间中mtu字段的偏移量
+const VIRTIO_NET_CONFIG_MTU_OFFSET: usize = 10;
+/// 配置空间status字段中表示链路已连接的位
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+/// 设备会在配置空间中提供链路速度和双工模式
+const VIRTIO_NET_F_SPEED_DUPLEX: u64 = 1 << 63;
+/// 配置空间中speed字段的偏移量
+const VIRTIO_NET_CONFIG_SPEED_OFFSET: usize = 12;
+/// 配置空间中duplex字段的偏移量
+const VIRTIO_NET_CONFIG_DUPLEX_OFFSET: usize = 16;
+/// duplex字段：半双工
+const VIRTIO_NET_DUPLEX_HALF: u8 = 0;
+/// duplex字段：全双工
+const VIRTIO_NET_DUPLEX_FULL: u8 = 1;
+
+/// # 函数的功能
+/// 读取设备通过VIRTIO_NET_F_SPEED_DUPLEX提供的链路速度和双工模式
+///
+/// 与Linux的virtnet_update_settings一致，超过i32::MAX的速度(包括表示未知的0xffffffff)视为未知
+///
+/// ## 参数
+/// - `dev`: 设备的配置空间
+/// - `device_features`: 设备提供的特性
+fn virtio_net_link_settings<D: VirtIOConfigAccess + ?Sized>(
+    dev: &D,
+    device_features: u64,
+) -> NetLinkSettings {
+    if device_features & VIRTIO_NET_F_SPEED_DUPLEX == 0 {
+        return NetLinkSettings::default();
+    }
+    let speed = virtio_config_read::<D, u32>(dev, VIRTIO_NET_CONFIG_SPEED_OFFSET)
+        .ok()
+        .filter(|speed| *speed <= i32::MAX as u32);
+    let duplex = match virtio_config_read::<D, u8>(dev, VIRTIO_NET_CONFIG_DUPLEX_OFFSET) {
+        Ok(VIRTIO_NET_DUPLEX_HALF) => NetDuplex::Half,
+        Ok(VIRTIO_NET_DUPLEX_FULL) => NetDuplex::Full,
+        _ => NetDuplex::Unknown,
+    };
+    NetLinkSettings { speed, duplex }
+}
+
+/// virtio net配置空间的开头部分
+///
+/// 参考 virtio spec 5.1.4 Device configuration layout
+///
+/// 只用于计算字段在配置空间中的偏移，字段通过[`VirtIOTransport::config_read`]读取
+#[allow(dead_code)]
+#[repr(C)]
+struct VirtIONetConfigHead {
+    mac: [u8; 6],
+    status: u16,
 }
 
 impl KObject for VirtIONetDevice {
@@ -253,10 +676,19 @@ impl Device for VirtIONetDevice {
 
 impl VirtIODevice for VirtIONetDevice {
     fn handle_irq(&self, _irq: IrqNumber) -> Result<IrqReturn, SystemError> {
+        // 上一次补充接收缓冲区失败的话，在这里重试。拿不到锁说明有人正在收发，下次中断再试
+        let nic = self.inner().device_inner.clone();
+        if let Ok(mut net) = nic.inner.try_lock_irqsave() {
+            net.refill_rx();
+        }
         poll_ifaces_try_lock_onetime().ok();
         return Ok(IrqReturn::Handled);
     }
 
+    fn handle_config_change(&self) {
+        self.update_link_settings();
+    }
+
     fn dev_id(&self) -> &Arc<DeviceId> {
         return &self.dev_id;
     }
@@ -291,28 +723,254 @@ impl VirtIODevice for VirtIONetDevice {
     fn irq(&self) -> Option<IrqNumber> {
         None
     }
+
+    fn irq_type(&self) -> VirtIOIrqType {
+        self.irq_type
+    }
+
+    fn isr(&self) -> Option<VirtIOIsr> {
+        self.isr
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        VIRTIO_NET_QUEUES.to_vec()
+    }
+
+    fn teardown_queues(&self) -> Result<(), SystemError> {
+        let mut guard = self.inner();
+        let inner = &mut *guard;
+        let r = inner
+            .device_inner
+            .inner
+            .lock_irqsave()
+            .teardown(inner.ctrl_transport.as_mut());
+        if let Some(transport) = inner.ctrl_transport.as_ref() {
+            transport.release_irq_vectors();
+        }
+        r
+    }
+}
+
+/// 拆除队列时对设备的操作，见[`virtio_teardown_queues`]
+struct VirtIONetQueues<'a> {
+    net: &'a mut VirtIoNetImpl,
+    ctrl_transport: Option<&'a mut VirtIOTransport>,
+}
+
+impl VirtQueueOwner for VirtIONetQueues<'_> {
+    /// 等待设备发送完已经提交的数据包。接收缓冲区一直由设备持有、在复位时回收，不需要等待
+    fn reap(&mut self) -> usize {
+        match self.net.inner.as_mut() {
+            Some(net) => {
+                let ring = net.ring();
+                ring.reclaim_tx();
+                ring.tx_inflight()
+            }
+            None => 0,
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), SystemError> {
+        match self.ctrl_transport.as_deref_mut() {
+            Some(transport) => virtio_reset_device(transport),
+            None => Ok(()),
+        }
+    }
+
+    fn free_queues(&mut self) {
+        drop(self.net.inner.take());
+        self.net.rx_spare.clear();
+        self.net.generation += 1;
+    }
 }
 
 pub struct VirtIoNetImpl {
-    inner: VirtIONet<HalImpl, VirtIOTransport, 2>,
+    /// 队列被拆除，或者重新初始化失败时为None，此时不能收发数据包
+    inner: Option<VirtIONetQueue>,
+    /// 设备的MAC地址，设备不可用时仍然可以读取
+    mac: [u8; 6],
+    /// 协议栈已经用完、还没有重新提交给设备的接收缓冲区
+    rx_spare: Vec<VirtIONetRxBuf>,
+    rx_refill: RxRefill,
+    /// 每次重新初始化设备都会递增，用于识别属于旧队列的接收缓冲区
+    generation: u64,
+    mtu: VirtIONetMtu,
 }
 
 impl VirtIoNetImpl {
-    const fn new(inner: VirtIONet<HalImpl, VirtIOTransport, 2>) -> Self {
-        Self { inner }
+    fn new(mut inner: VirtIONetQueue, mtu: VirtIONetMtu) -> Self {
+        let capacity = inner.ring().rx_capacity();
+        Self {
+            mac: inner.mac_address(),
+            inner: Some(inner),
+            // 预留全部容量，归还缓冲区时不需要再分配内存
+            rx_spare: Vec::with_capacity(capacity),
+            rx_refill: RxRefill::new(VIRTIO_NET_BASENAME, capacity),
+            generation: 0,
+            mtu,
+        }
     }
-}
 
-impl Deref for VirtIoNetImpl {
-    type Target = VirtIONet<HalImpl, VirtIOTransport, 2>;
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    /// # 函数的功能
+    /// 复位设备，并按照新的MTU重新创建virtqueue和接收缓冲区
+    ///
+    /// ## 参数
+    /// - `ctrl_transport`: 用于复位设备的transport，新的队列使用它的副本
+    /// - `mtu`: 新的MTU
+    fn reinit(
+        &mut self,
+        ctrl_transport: &mut VirtIOTransport,
+        mtu: VirtIONetMtu,
+    ) -> Result<(), SystemError> {
+        let mut transport = ctrl_transport
+            .try_clone()
+            .ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)?;
+
+        // 设备复位之后不会再访问virtqueue，可以释放它们。
+        // pci transport被drop时也会复位设备，所以必须先释放旧的队列，再初始化新的
+        virtio_reset_device(ctrl_transport)?;
+        drop(self.inner.take());
+        self.rx_spare.clear();
+        self.generation += 1;
+
+        let mut net = VirtIONetQueue::new(
+            transport,
+            mtu.rx_buf_len(),
+            &VIRTIO_NET_QUEUE_POLICY,
+            VIRTIO_NET_SUPPORTED_FEATURES,
+        )
+        .map_err(|_| SystemError::EIO)?;
+        let capacity = net.ring().rx_capacity();
+        self.inner = Some(net);
+        self.rx_spare = Vec::with_capacity(capacity);
+        self.rx_refill = RxRefill::new(VIRTIO_NET_BASENAME, capacity);
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 复位设备并释放virtqueue，之后设备不再可用
+    ///
+    /// ## 参数
+    /// - `ctrl_transport`: 用于复位设备的transport，为None时在释放[`VirtIONetQueue`]时禁用队列
+    fn teardown(
+        &mut self,
+        ctrl_transport: Option<&mut VirtIOTransport>,
+    ) -> Result<(), SystemError> {
+        virtio_teardown_queues(
+            &mut VirtIONetQueues {
+                net: self,
+                ctrl_transport,
+            },
+            VIRTIO_TEARDOWN_MAX_POLLS,
+        )
+    }
+
+    /// 设备是否可以收发数据包
+    fn is_ready(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn can_send(&mut self) -> bool {
+        let now_ms = Instant::now().total_millis();
+        self.inner.as_mut().is_some_and(|net| net.can_send(now_ms))
+    }
+
+    /// # 函数的功能
+    /// 发送一个长度为`len`的数据包，数据包的内容由`f`填写
+    ///
+    /// ## 返回值
+    /// - `f`的返回值，以及发送的结果。设备不可用时`f`填写的数据包被丢弃，结果为Err(SystemError::ENODEV)
+    fn send_packet<R>(
+        &mut self,
+        len: usize,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> (R, Result<(), SystemError>) {
+        let Some(net) = self.inner.as_mut() else {
+            let mut packet = vec![0u8; len];
+            return (f(&mut packet), Err(SystemError::ENODEV));
+        };
+        let mut tx_buf = net.new_tx_buffer(len);
+        let hdr_len = net.ring().hdr_len();
+        let result = f(&mut tx_buf[hdr_len..]);
+        let r = net.send(tx_buf).map_err(|e| {
+            warn!("virtio_net: failed to send packet: {:?}", e);
+            SystemError::EIO
+        });
+        (result, r)
+    }
+
+    /// # 函数的功能
+    /// 从设备取出一个收到的数据包
+    ///
+    /// ## 返回值
+    /// - Some(VirtIONetRxBuf): 装有数据包的缓冲区，用完之后需要通过`recycle_rx`归还
+    /// - None: 没有收到数据包
+    fn receive_packet(&mut self) -> Option<VirtIONetRxBuf> {
+        let buf = self.inner.as_mut()?.ring().pop_rx()?;
+        self.rx_refill.on_buffer_used();
+        Some(buf)
+    }
+
+    /// 归还协议栈用完的接收缓冲区，并在接收队列低于水位线时补充
+    fn recycle_rx(&mut self, buf: VirtIONetRxBuf, generation: u64) {
+        if generation != self.generation {
+            // 缓冲区属于重新初始化之前的队列，直接丢弃
+            return;
+        }
+        if self.rx_spare.len() < self.rx_spare.capacity() {
+            self.rx_spare.push(buf);
+        }
+        self.refill_rx();
+    }
+
+    /// 按需补充接收队列。补充失败时保留已提交的缓冲区，等下一次中断时重试
+    fn refill_rx(&mut self) {
+        if !self.rx_refill.needs_refill() {
+            return;
+        }
+        let Some(net) = self.inner.as_mut() else {
+            return;
+        };
+        let mut source = VirtIoNetRxSource {
+            net,
+            spare: &mut self.rx_spare,
+            buf_len: self.mtu.rx_buf_len(),
+        };
+        self.rx_refill
+            .refill(&mut source, Instant::now().total_millis());
     }
 }
 
-impl DerefMut for VirtIoNetImpl {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+/// virtio-net接收缓冲区的来源
+///
+/// 优先重新提交协议栈归还的缓冲区，没有可用的缓冲区时再分配新的
+struct VirtIoNetRxSource<'a> {
+    net: &'a mut VirtIONetQueue,
+    spare: &'a mut Vec<VirtIONetRxBuf>,
+    /// 新分配的接收缓冲区的大小
+    buf_len: usize,
+}
+
+impl RxBufferSource for VirtIoNetRxSource<'_> {
+    type Buffer = VirtIONetRxBuf;
+
+    fn alloc_rx_buffer(&mut self) -> Result<Self::Buffer, SystemError> {
+        match self.spare.pop() {
+            Some(buf) => Ok(buf),
+            None => VirtIONetRxBuf::new(self.buf_len),
+        }
+    }
+
+    fn post_rx_buffer(&mut self, buf: Self::Buffer) -> Result<(), SystemError> {
+        self.net.post_rx(buf).map_err(|e| {
+            error!("virtio_net: failed to post rx buffer: {:?}", e);
+            SystemError::EIO
+        })
     }
 }
 
@@ -346,12 +1004,15 @@ impl VirtIONicDeviceInnerWrapper {
 /// Virtio网络设备驱动(加锁)
 pub struct VirtIONicDeviceInner {
     pub inner: Arc<SpinLock<VirtIoNetImpl>>,
+    /// 收发统计，不需要持有设备锁就可以更新
+    stats: Arc<NetDeviceStats>,
 }
 
 impl Clone for VirtIONicDeviceInner {
     fn clone(&self) -> Self {
         return VirtIONicDeviceInner {
             inner: self.inner.clone(),
+            stats: self.stats.clone(),
         };
     }
 }
@@ -392,6 +1053,14 @@ impl core::fmt::Debug for VirtioInterface {
 }
 
 impl VirtioInterface {
+    /// 网络接口所在的virtio设备
+    fn virtio_net_device(&self) -> Result<Arc<VirtIONetDevice>, SystemError> {
+        self.dev_parent()
+            .and_then(|p| p.upgrade())
+            .and_then(|p| p.arc_any().downcast::<VirtIONetDevice>().ok())
+            .ok_or(SystemError::ENODEV)
+    }
+
     pub fn new(mut device_inner: VirtIONicDeviceInner) -> Arc<Self> {
         let iface_id = generate_iface_id();
         let mut iface_config = iface::Config::new(wire::HardwareAddress::Ethernet(
@@ -505,30 +1174,53 @@ impl Device for VirtioInterface {
 }
 
 impl VirtIONicDeviceInner {
-    pub fn new(driver_net: VirtIONet<HalImpl, VirtIOTransport, 2>) -> Self {
+    fn new(driver_net: VirtIONetQueue, mtu: VirtIONetMtu) -> Self {
         let mut iface_config = iface::Config::new(wire::HardwareAddress::Ethernet(
             wire::EthernetAddress(driver_net.mac_address()),
         ));
 
         iface_config.random_seed = rand() as u64;
 
-        let inner = Arc::new(SpinLock::new(VirtIoNetImpl::new(driver_net)));
-        let result = VirtIONicDeviceInner { inner };
+        let inner = Arc::new(SpinLock::new(VirtIoNetImpl::new(driver_net, mtu)));
+        let result = VirtIONicDeviceInner {
+            inner,
+            stats: Arc::new(NetDeviceStats::new()),
+        };
         return result;
     }
 }
 
 pub struct VirtioNetToken {
     driver: VirtIONicDeviceInner,
-    rx_buffer: Option<virtio_drivers::device::net::RxBuffer>,
+    rx_buffer: Option<VirtIONetRxBuf>,
+    /// 接收缓冲区所属队列的代数
+    rx_generation: u64,
 }
 
 impl VirtioNetToken {
     pub fn new(
         driver: VirtIONicDeviceInner,
-        rx_buffer: Option<virtio_drivers::device::net::RxBuffer>,
+        rx_buffer: Option<VirtIONetRxBuf>,
+        rx_generation: u64,
     ) -> Self {
-        return Self { driver, rx_buffer };
+        return Self {
+            driver,
+            rx_buffer,
+            rx_generation,
+        };
+    }
+}
+
+impl Drop for VirtioNetToken {
+    fn drop(&mut self) {
+        // 协议栈可能没有消费接收令牌就把它丢弃了，此时也要归还缓冲区，否则接收队列会逐渐耗尽
+        if let Some(rx_buf) = self.rx_buffer.take() {
+            self.driver.stats.record_rx_dropped();
+            self.driver
+                .inner
+                .lock()
+                .recycle_rx(rx_buf, self.rx_generation);
+        }
     }
 }
 
@@ -540,21 +1232,21 @@ impl phy::Device for VirtIONicDeviceInner {
         &mut self,
         _timestamp: smoltcp::time::Instant,
     ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        match self.inner.lock().receive() {
-            Ok(buf) => Some((
-                VirtioNetToken::new(self.clone(), Some(buf)),
-                VirtioNetToken::new(self.clone(), None),
-            )),
-            Err(virtio_drivers::Error::NotReady) => None,
-            Err(err) => panic!("VirtIO receive failed: {}", err),
-        }
+        let (buf, generation) = {
+            let mut net = self.inner.lock();
+            (net.receive_packet()?, net.generation)
+        };
+        Some((
+            VirtioNetToken::new(self.clone(), Some(buf), generation),
+            VirtioNetToken::new(self.clone(), None, generation),
+        ))
     }
 
     fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
         // debug!("VirtioNet: transmit");
         if self.inner.lock_irqsave().can_send() {
             // debug!("VirtioNet: can send");
-            return Some(VirtioNetToken::new(self.clone(), None));
+            return Some(VirtioNetToken::new(self.clone(), None, 0));
         } else {
             // debug!("VirtioNet: can not send");
             return None;
@@ -564,7 +1256,7 @@ impl phy::Device for VirtIONicDeviceInner {
     fn capabilities(&self) -> phy::DeviceCapabilities {
         let mut caps = phy::DeviceCapabilities::default();
         // 网卡的最大传输单元. 请与IP层的MTU进行区分。这个值应当是网卡的最大传输单元，而不是IP层的MTU。
-        caps.max_transmission_unit = 2000;
+        caps.max_transmission_unit = self.inner.lock_irqsave().mtu.frame_len();
         /*
            Maximum burst size, in terms of MTU.
            The network device is unable to send or receive bursts large than the value returned by this function.
@@ -581,27 +1273,28 @@ impl phy::TxToken for VirtioNetToken {
         F: FnOnce(&mut [u8]) -> R,
     {
         // // 为了线程安全，这里需要对VirtioNet进行加【写锁】，以保证对设备的互斥访问。
-        let mut driver_net = self.driver.inner.lock();
-        let mut tx_buf = driver_net.new_tx_buffer(len);
-        let result = f(tx_buf.packet_mut());
-        driver_net.send(tx_buf).expect("virtio_net send failed");
+        let (result, r) = self.driver.inner.lock().send_packet(len, f);
+        match r {
+            Ok(_) => self.driver.stats.record_tx(len),
+            Err(_) => self.driver.stats.record_tx_error(),
+        }
         return result;
     }
 }
 
 impl phy::RxToken for VirtioNetToken {
-    fn consume<R, F>(self, f: F) -> R
+    fn consume<R, F>(mut self, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
         // 为了线程安全，这里需要对VirtioNet进行加【写锁】，以保证对设备的互斥访问。
-        let mut rx_buf = self.rx_buffer.unwrap();
+        let mut rx_buf = self.rx_buffer.take().unwrap();
+        self.driver.stats.record_rx(rx_buf.packet().len());
         let result = f(rx_buf.packet_mut());
         self.driver
             .inner
             .lock()
-            .recycle_rx_buffer(rx_buf)
-            .expect("virtio_net recv failed");
+            .recycle_rx(rx_buf, self.rx_generation);
         result
     }
 }
@@ -611,17 +1304,14 @@ pub fn virtio_net(
     transport: VirtIOTransport,
     dev_id: Arc<DeviceId>,
     dev_parent: Option<Arc<dyn Device>>,
-) {
-    let virtio_net_deivce = VirtIONetDevice::new(transport, dev_id);
-    if let Some(virtio_net_deivce) = virtio_net_deivce {
-        debug!("VirtIONetDevice '{:?}' created", virtio_net_deivce.dev_id);
-        if let Some(dev_parent) = dev_parent {
-            virtio_net_deivce.set_dev_parent(Some(Arc::downgrade(&dev_parent)));
-        }
-        virtio_device_manager()
-            .device_add(virtio_net_deivce.clone() as Arc<dyn VirtIODevice>)
-            .expect("Add virtio net failed");
-    }
+) -> Result<Arc<dyn Device>, SystemError> {
+    let virtio_net_deivce = VirtIONetDevice::new(transport, dev_id).ok_or(SystemError::EIO)?;
+    debug!("VirtIONetDevice '{:?}' created", virtio_net_deivce.dev_id);
+    if let Some(dev_parent) = dev_parent {
+        virtio_net_deivce.set_dev_parent(Some(Arc::downgrade(&dev_parent)));
+    }
+    virtio_device_manager().device_add(virtio_net_deivce.clone() as Arc<dyn VirtIODevice>)?;
+    Ok(virtio_net_deivce)
 }
 
 impl NetDevice for VirtioInterface {
@@ -703,6 +1393,41 @@ impl NetDevice for VirtioInterface {
     fn set_operstate(&self, state: Operstate) {
         self.inner().netdevice_common.operstate = state;
     }
+
+    fn mtu(&self) -> usize {
+        self.device_inner.inner.lock_irqsave().mtu.mtu() as usize
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<(), SystemError> {
+        let mtu = u16::try_from(mtu).map_err(|_| SystemError::EINVAL)?;
+        self.virtio_net_device()?.set_mtu(mtu)
+    }
+
+    fn set_promisc(&self, on: bool) -> Result<(), SystemError> {
+        self.virtio_net_device()?.set_promisc(on)
+    }
+
+    fn link_settings(&self) -> NetLinkSettings {
+        self.virtio_net_device()
+            .map(|dev| dev.link_settings())
+            .unwrap_or_default()
+    }
+
+    fn set_allmulti(&self, on: bool) -> Result<(), SystemError> {
+        self.virtio_net_device()?.set_allmulti(on)
+    }
+
+    fn set_mac(&self, mac: wire::EthernetAddress) -> Result<(), SystemError> {
+        self.virtio_net_device()?.set_mac_address(mac)?;
+        self.inner_iface()
+            .lock()
+            .set_hardware_addr(wire::HardwareAddress::Ethernet(mac));
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<&NetDeviceStats> {
+        Some(&self.device_inner.stats)
+    }
 }
 
 impl KObject for VirtioInterface {
@@ -772,11 +1497,11 @@ fn virtio_net_driver_init() -> Result<(), SystemError> {
     unsafe {
         VIRTIO_NET_DRIVER = Some(driver);
     }
+    virtio_device_router().register(VirtioDeviceType::Network, virtio_net)?;
 
     return Ok(());
 }
 
-#[derive(Debug)]
 #[cast_to([sync] VirtIODriver)]
 #[cast_to([sync] Driver)]
 struct VirtIONetDriver {
@@ -810,6 +1535,20 @@ impl VirtIONetDriver {
     }
 }
 
+/// 调试输出只尝试获取内部的锁，避免在持有锁时打印驱动而死锁
+impl Debug for VirtIONetDriver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut d = f.debug_struct("VirtIONetDriver");
+        match self.inner.try_lock() {
+            Ok(inner) => d
+                .field("id_table", &inner.virtio_driver_common.id_table)
+                .field("devices", &inner.driver_common.devices.len()),
+            Err(_) => d.field("inner", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
 #[derive(Debug)]
 struct InnerVirtIODriver {
     virtio_driver_common: VirtIODriverCommonData,
@@ -840,6 +1579,13 @@ impl VirtIODriver for VirtIONetDriver {
         iface.set_dev_parent(Some(Arc::downgrade(&virtio_net_device) as Weak<dyn Device>));
         // 在sysfs中注册iface
         register_netdevice(iface.clone() as Arc<dyn NetDevice>)?;
+        if virtio_net_device.link_up() == Some(false) {
+            debug!(
+                "VirtIONetDevice '{:?}': link is down",
+                virtio_net_device.dev_id
+            );
+            iface.set_operstate(Operstate::IF_OPER_DOWN);
+        }
 
         // 将网卡的接口信息注册到全局的网卡接口信息表中
         NET_DEVICES
@@ -853,6 +1599,18 @@ impl VirtIODriver for VirtIONetDriver {
         return Ok(());
     }
 
+    fn remove(&self, device: &Arc<dyn VirtIODevice>) -> Result<(), SystemError> {
+        // 停止设备对virtqueue的访问，之后设备不会再通过DMA写入已经释放的接收缓冲区
+        if let Err(e) = device.teardown_queues() {
+            warn!(
+                "VirtIONetDriver::remove(): queue teardown of '{}' failed: {:?}",
+                device.device_name(),
+                e
+            );
+        }
+        return Ok(());
+    }
+
     fn virtio_id_table(&self) -> LinkedList<VirtioDeviceId> {
         self.inner().virtio_driver_common.id_table.clone()
     }
@@ -901,6 +1659,14 @@ impl Driver for VirtIONetDriver {
         self.inner().driver_common.devices.clone()
     }
 
+    fn __for_each_device(&self, f: &mut dyn FnMut(&Arc<dyn Device>) -> ControlFlow<()>) {
+        self.inner().driver_common.for_each_device(f);
+    }
+
+    fn device_count(&self) -> usize {
+        self.inner().driver_common.devices.len()
+    }
+
     fn bus(&self) -> Option<Weak<dyn Bus>> {
         Some(Arc::downgrade(&virtio_bus()) as Weak<dyn Bus>)
     }
@@ -967,3 +1733,113 @@ impl KObject for VirtIONetDriver {
         *self.kobj_state.write() = state;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::virtio::config::VirtIOConfigGeneration;
+
+    #[test]
+    fn test_default_mtu() {
+        let mtu = VirtIONetMtu::new(VIRTIO_NET_DEFAULT_MTU, None);
+        assert_eq!(mtu.mtu(), 1500);
+        assert_eq!(mtu.frame_len(), 1514);
+        // 没有设备提供的上限时，可以调大到协议允许的最大值
+        assert_eq!(mtu.with_mtu(9000).unwrap().mtu(), 9000);
+        assert_eq!(mtu.with_mtu(u16::MAX).unwrap().mtu(), u16::MAX);
+        assert_eq!(mtu.with_mtu(67), Err(SystemError::EINVAL));
+    }
+
+    #[test]
+    fn test_negotiated_mtu() {
+        // 设备提供的MTU优先于默认值，并且是上限
+        let mtu = VirtIONetMtu::new(VIRTIO_NET_DEFAULT_MTU, Some(4000));
+        assert_eq!(mtu.mtu(), 4000);
+        assert_eq!(mtu.with_mtu(4000).unwrap(), mtu);
+        assert_eq!(mtu.with_mtu(1280).unwrap().mtu(), 1280);
+        assert_eq!(mtu.with_mtu(4001), Err(SystemError::EINVAL));
+        // 调小之后上限不变
+        let smaller = mtu.with_mtu(1280).unwrap();
+        assert_eq!(smaller.with_mtu(4000).unwrap().mtu(), 4000);
+        assert_eq!(smaller.with_mtu(9000), Err(SystemError::EINVAL));
+    }
+
+    #[test]
+    fn test_rx_buf_len() {
+        let len = |mtu: u16| VirtIONetMtu::new(mtu, None).rx_buf_len();
+        // 缓冲区至少能容纳标准MTU的帧
+        assert_eq!(len(576), len(1500));
+        assert!(len(1500) >= VIRTIO_NET_HDR_LEN + VIRTIO_NET_VLAN_ETH_HLEN + 1500);
+        assert!(len(9000) >= VIRTIO_NET_HDR_LEN + VIRTIO_NET_VLAN_ETH_HLEN + 9000);
+        assert_eq!(len(9000) % size_of::<usize>(), 0);
+    }
+
+    /// virtio net设备的配置空间，字段使用小端序
+    struct MockNetConfig {
+        bytes: [u8; 20],
+    }
+
+    impl MockNetConfig {
+        fn new(speed: u32, duplex: u8) -> Self {
+            let mut bytes = [0u8; 20];
+            bytes[VIRTIO_NET_CONFIG_SPEED_OFFSET..VIRTIO_NET_CONFIG_SPEED_OFFSET + 4]
+                .copy_from_slice(&speed.to_le_bytes());
+            bytes[VIRTIO_NET_CONFIG_DUPLEX_OFFSET] = duplex;
+            Self { bytes }
+        }
+    }
+
+    impl VirtIOConfigGeneration for MockNetConfig {
+        fn config_generation(&self) -> u32 {
+            0
+        }
+    }
+
+    impl VirtIOConfigAccess for MockNetConfig {
+        fn config_space_len(&self) -> usize {
+            self.bytes.len()
+        }
+
+        unsafe fn config_read_raw(&self, offset: usize, width: usize) -> u32 {
+            let mut v = [0u8; 4];
+            v[..width].copy_from_slice(&self.bytes[offset..offset + width]);
+            u32::from_le_bytes(v)
+        }
+
+        unsafe fn config_write_raw(&self, _offset: usize, _width: usize, _value: u32) {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_link_settings() {
+        let dev = MockNetConfig::new(10000, VIRTIO_NET_DUPLEX_FULL);
+        let settings = virtio_net_link_settings(&dev, VIRTIO_NET_F_SPEED_DUPLEX);
+        assert_eq!(settings.speed, Some(10000));
+        assert_eq!(settings.duplex, NetDuplex::Full);
+        // sysfs中speed和duplex属性的内容
+        assert_eq!(format!("{}\n", settings.speed_mbps()), "10000\n");
+        assert_eq!(format!("{}\n", settings.duplex), "full\n");
+
+        // 设备没有提供VIRTIO_NET_F_SPEED_DUPLEX时不读取配置空间
+        let settings = virtio_net_link_settings(&dev, 0);
+        assert_eq!(settings, NetLinkSettings::default());
+        assert_eq!(settings.speed_mbps(), -1);
+        assert_eq!(format!("{}", settings.duplex), "unknown");
+    }
+
+    #[test]
+    fn test_link_settings_unknown() {
+        // 0xffffffff表示速度未知，0xff表示双工模式未知
+        let dev = MockNetConfig::new(u32::MAX, 0xff);
+        let settings = virtio_net_link_settings(&dev, VIRTIO_NET_F_SPEED_DUPLEX);
+        assert_eq!(settings.speed_mbps(), -1);
+        assert_eq!(settings.duplex, NetDuplex::Unknown);
+
+        // 超过i32::MAX的速度同样视为未知
+        let dev = MockNetConfig::new(0x8000_0000, VIRTIO_NET_DUPLEX_HALF);
+        let settings = virtio_net_link_settings(&dev, VIRTIO_NET_F_SPEED_DUPLEX);
+        assert_eq!(settings.speed, None);
+        assert_eq!(settings.duplex, NetDuplex::Half);
+    }
+}
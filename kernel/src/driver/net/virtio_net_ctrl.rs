@@ -0,0 +1,388 @@
+//! virtio-net控制队列中的接收模式命令(VIRTIO_NET_CTRL_RX)
+//!
+//! 协商了VIRTIO_NET_F_CTRL_VQ以及VIRTIO_NET_F_CTRL_RX之后，驱动可以通过控制队列在运行时
+//! 打开或关闭混杂模式、接收所有多播帧等。每条命令由设备只读的`class`、`command`以及命令的数据
+//! 组成，设备处理完成后在设备可写的一个字节中写入结果。
+//!
+//! MAC地址过滤命令(VIRTIO_NET_CTRL_MAC)用于设置单播、多播地址的过滤表以及网卡的MAC地址。
+//!
+//! 参考 virtio spec 5.1.6.5 Control Virtqueue
+
+use alloc::vec::Vec;
+use system_error::SystemError;
+
+/// 设备有控制队列
+pub const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+/// 设备支持VIRTIO_NET_CTRL_RX中的PROMISC以及ALLMULTI命令
+pub const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+/// 设备支持VIRTIO_NET_CTRL_RX中的ALLUNI、NOMULTI、NOUNI以及NOBCAST命令
+pub const VIRTIO_NET_F_CTRL_RX_EXTRA: u64 = 1 << 20;
+
+/// 设备支持通过控制队列设置MAC地址
+pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u64 = 1 << 23;
+
+/// 接收模式命令的class
+pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+/// MAC地址过滤命令的class
+pub const VIRTIO_NET_CTRL_MAC: u8 = 1;
+/// 设置单播、多播MAC地址过滤表
+pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+/// 设置网卡的MAC地址
+pub const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+
+/// 过滤表中每类地址的最大数量，超过时不再使用过滤表，改为接收所有单播/多播帧
+pub const VIRTIO_NET_MAC_TABLE_ENTRIES: usize = 64;
+
+/// 命令执行成功
+pub const VIRTIO_NET_OK: u8 = 0;
+/// 命令执行失败
+#[allow(dead_code)]
+pub const VIRTIO_NET_ERR: u8 = 1;
+
+bitflags! {
+    /// 网卡的接收模式，第i位对应VIRTIO_NET_CTRL_RX中编号为i的命令
+    pub struct VirtIONetRxMode: u8 {
+        /// 混杂模式：接收所有帧
+        const PROMISC = 1 << 0;
+        /// 接收所有多播帧
+        const ALLMULTI = 1 << 1;
+        /// 接收所有单播帧
+        const ALLUNI = 1 << 2;
+        /// 不接收多播帧
+        const NOMULTI = 1 << 3;
+        /// 不接收单播帧
+        const NOUNI = 1 << 4;
+        /// 不接收广播帧
+        const NOBCAST = 1 << 5;
+    }
+}
+
+impl VirtIONetRxMode {
+    /// 需要VIRTIO_NET_F_CTRL_RX_EXTRA的模式
+    const EXTRA: Self = Self::from_bits_truncate(
+        Self::ALLUNI.bits() | Self::NOMULTI.bits() | Self::NOUNI.bits() | Self::NOBCAST.bits(),
+    );
+}
+
+/// # 结构功能
+/// 控制队列中的一条命令
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtIONetCtrlCmd {
+    pub class: u8,
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
+impl VirtIONetCtrlCmd {
+    /// # 函数的功能
+    /// 构造一条VIRTIO_NET_CTRL_RX命令
+    ///
+    /// ## 参数
+    /// - `command`: 命令编号
+    /// - `on`: 打开还是关闭这个模式
+    pub fn rx(command: u8, on: bool) -> Self {
+        Self {
+            class: VIRTIO_NET_CTRL_RX,
+            command,
+            data: vec![on as u8],
+        }
+    }
+
+    /// 命令中设备只读的部分：`class`、`command`以及命令的数据
+    #[allow(dead_code)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + self.data.len());
+        bytes.push(self.class);
+        bytes.push(self.command);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+/// # 函数的功能
+/// 计算把接收模式从`old`修改为`new`需要发送的命令，每个变化的模式一条命令
+///
+/// ## 参数
+/// - `features`: 驱动与设备协商得到的特性
+///
+/// ## 返回值
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商控制队列或者需要的VIRTIO_NET_F_CTRL_RX特性
+pub fn virtio_net_rx_mode_cmds(
+    features: u64,
+    old: VirtIONetRxMode,
+    new: VirtIONetRxMode,
+) -> Result<Vec<VirtIONetCtrlCmd>, SystemError> {
+    let changed = old ^ new;
+    if changed.is_empty() {
+        return Ok(Vec::new());
+    }
+    let required = VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_RX;
+    if features & required != required
+        || (changed.intersects(VirtIONetRxMode::EXTRA)
+            && features & VIRTIO_NET_F_CTRL_RX_EXTRA == 0)
+    {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    Ok((0..8u8)
+        .filter(|bit| changed.bits() & (1 << bit) != 0)
+        .map(|bit| VirtIONetCtrlCmd::rx(bit, new.bits() & (1 << bit) != 0))
+        .collect())
+}
+
+/// # 结构功能
+/// 设置MAC地址过滤表的计划
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtIONetMacTablePlan {
+    /// VIRTIO_NET_CTRL_MAC_TABLE_SET命令
+    pub cmd: VirtIONetCtrlCmd,
+    /// 过滤表放不下时需要额外打开的接收模式
+    pub overflow: VirtIONetRxMode,
+}
+
+/// 按照virtio_net_ctrl_mac的格式写入一类地址：le32的地址数量，然后是每个地址
+fn virtio_net_push_mac_list(data: &mut Vec<u8>, macs: &[[u8; 6]]) {
+    data.extend_from_slice(&(macs.len() as u32).to_le_bytes());
+    for mac in macs {
+        data.extend_from_slice(mac);
+    }
+}
+
+/// # 函数的功能
+/// 构造设置MAC地址过滤表的命令
+///
+/// 某类地址超过[`VIRTIO_NET_MAC_TABLE_ENTRIES`]个时，这类地址的表为空，
+/// 改为接收这类所有的帧：多播使用ALLMULTI；单播在支持VIRTIO_NET_F_CTRL_RX_EXTRA时使用ALLUNI，否则使用PROMISC
+///
+/// ## 参数
+/// - `features`: 驱动与设备协商得到的特性
+/// - `unicast`: 需要接收的单播地址
+/// - `multicast`: 需要接收的多播地址
+///
+/// ## 返回值
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商控制队列或者VIRTIO_NET_F_CTRL_RX
+pub fn virtio_net_mac_table_plan(
+    features: u64,
+    unicast: &[[u8; 6]],
+    multicast: &[[u8; 6]],
+) -> Result<VirtIONetMacTablePlan, SystemError> {
+    let required = VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_RX;
+    if features & required != required {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    let mut overflow = VirtIONetRxMode::empty();
+    let unicast = if unicast.len() > VIRTIO_NET_MAC_TABLE_ENTRIES {
+        if features & VIRTIO_NET_F_CTRL_RX_EXTRA != 0 {
+            overflow |= VirtIONetRxMode::ALLUNI;
+        } else {
+            overflow |= VirtIONetRxMode::PROMISC;
+        }
+        &[]
+    } else {
+        unicast
+    };
+    let multicast = if multicast.len() > VIRTIO_NET_MAC_TABLE_ENTRIES {
+        overflow |= VirtIONetRxMode::ALLMULTI;
+        &[]
+    } else {
+        multicast
+    };
+
+    let mut data = Vec::with_capacity(8 + 6 * (unicast.len() + multicast.len()));
+    virtio_net_push_mac_list(&mut data, unicast);
+    virtio_net_push_mac_list(&mut data, multicast);
+    Ok(VirtIONetMacTablePlan {
+        cmd: VirtIONetCtrlCmd {
+            class: VIRTIO_NET_CTRL_MAC,
+            command: VIRTIO_NET_CTRL_MAC_TABLE_SET,
+            data,
+        },
+        overflow,
+    })
+}
+
+/// # 函数的功能
+/// 构造设置网卡MAC地址的命令
+///
+/// ## 返回值
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商控制队列或者VIRTIO_NET_F_CTRL_MAC_ADDR
+pub fn virtio_net_mac_addr_cmd(
+    features: u64,
+    mac: [u8; 6],
+) -> Result<VirtIONetCtrlCmd, SystemError> {
+    let required = VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_MAC_ADDR;
+    if features & required != required {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    Ok(VirtIONetCtrlCmd {
+        class: VIRTIO_NET_CTRL_MAC,
+        command: VIRTIO_NET_CTRL_MAC_ADDR_SET,
+        data: mac.to_vec(),
+    })
+}
+
+/// 把设备写入的结果转换为错误码
+#[allow(dead_code)]
+pub fn virtio_net_ctrl_status(ack: u8) -> Result<(), SystemError> {
+    match ack {
+        VIRTIO_NET_OK => Ok(()),
+        _ => Err(SystemError::EIO),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CTRL_RX: u64 = VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_RX;
+
+    #[test]
+    fn test_rx_cmd_payload() {
+        let cmd = VirtIONetCtrlCmd::rx(0, true);
+        assert_eq!(cmd.to_bytes(), [VIRTIO_NET_CTRL_RX, 0, 1]);
+        let cmd = VirtIONetCtrlCmd::rx(1, false);
+        assert_eq!(cmd.to_bytes(), [VIRTIO_NET_CTRL_RX, 1, 0]);
+    }
+
+    #[test]
+    fn test_promisc_allmulti() {
+        let cmds = virtio_net_rx_mode_cmds(
+            CTRL_RX,
+            VirtIONetRxMode::empty(),
+            VirtIONetRxMode::PROMISC | VirtIONetRxMode::ALLMULTI,
+        )
+        .unwrap();
+        assert_eq!(
+            cmds,
+            [VirtIONetCtrlCmd::rx(0, true), VirtIONetCtrlCmd::rx(1, true)]
+        );
+
+        // 只发送变化的模式
+        let cmds = virtio_net_rx_mode_cmds(
+            CTRL_RX,
+            VirtIONetRxMode::PROMISC | VirtIONetRxMode::ALLMULTI,
+            VirtIONetRxMode::ALLMULTI,
+        )
+        .unwrap();
+        assert_eq!(cmds, [VirtIONetCtrlCmd::rx(0, false)]);
+        assert!(
+            virtio_net_rx_mode_cmds(0, VirtIONetRxMode::PROMISC, VirtIONetRxMode::PROMISC)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_feature_gating() {
+        let promisc = VirtIONetRxMode::PROMISC;
+        assert_eq!(
+            virtio_net_rx_mode_cmds(0, VirtIONetRxMode::empty(), promisc),
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
+        assert_eq!(
+            virtio_net_rx_mode_cmds(VIRTIO_NET_F_CTRL_VQ, VirtIONetRxMode::empty(), promisc),
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
+        // NOBCAST需要VIRTIO_NET_F_CTRL_RX_EXTRA
+        assert_eq!(
+            virtio_net_rx_mode_cmds(CTRL_RX, VirtIONetRxMode::empty(), VirtIONetRxMode::NOBCAST),
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
+        assert_eq!(
+            virtio_net_rx_mode_cmds(
+                CTRL_RX | VIRTIO_NET_F_CTRL_RX_EXTRA,
+                VirtIONetRxMode::empty(),
+                VirtIONetRxMode::NOBCAST
+            )
+            .unwrap(),
+            [VirtIONetCtrlCmd::rx(5, true)]
+        );
+    }
+
+    fn mac(last: u8) -> [u8; 6] {
+        [0x52, 0x54, 0x00, 0x12, 0x34, last]
+    }
+
+    #[test]
+    fn test_mac_table_layout() {
+        let plan =
+            virtio_net_mac_table_plan(CTRL_RX, &[mac(1)], &[[0x01, 0, 0x5e, 0, 0, 0xfb], mac(2)])
+                .unwrap();
+        assert!(plan.overflow.is_empty());
+        let mut expected = vec![VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET];
+        expected.extend_from_slice(&[1, 0, 0, 0]);
+        expected.extend_from_slice(&mac(1));
+        expected.extend_from_slice(&[2, 0, 0, 0]);
+        expected.extend_from_slice(&[0x01, 0, 0x5e, 0, 0, 0xfb]);
+        expected.extend_from_slice(&mac(2));
+        assert_eq!(plan.cmd.to_bytes(), expected);
+
+        // 空表也要写入数量
+        let plan = virtio_net_mac_table_plan(CTRL_RX, &[], &[]).unwrap();
+        assert_eq!(plan.cmd.data, [0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_mac_table_overflow() {
+        let many: Vec<[u8; 6]> = (0..=VIRTIO_NET_MAC_TABLE_ENTRIES as u8).map(mac).collect();
+
+        // 多播地址放不下：多播表为空，打开ALLMULTI
+        let plan = virtio_net_mac_table_plan(CTRL_RX, &[mac(1)], &many).unwrap();
+        assert_eq!(plan.overflow, VirtIONetRxMode::ALLMULTI);
+        assert_eq!(&plan.cmd.data[..4], &[1, 0, 0, 0]);
+        assert_eq!(&plan.cmd.data[10..], &[0, 0, 0, 0]);
+
+        // 单播地址放不下：没有ALLUNI时使用混杂模式
+        let plan = virtio_net_mac_table_plan(CTRL_RX, &many, &[]).unwrap();
+        assert_eq!(plan.overflow, VirtIONetRxMode::PROMISC);
+        assert_eq!(plan.cmd.data, [0, 0, 0, 0, 0, 0, 0, 0]);
+        let plan =
+            virtio_net_mac_table_plan(CTRL_RX | VIRTIO_NET_F_CTRL_RX_EXTRA, &many, &many).unwrap();
+        assert_eq!(
+            plan.overflow,
+            VirtIONetRxMode::ALLUNI | VirtIONetRxMode::ALLMULTI
+        );
+
+        // 刚好放得下时不需要额外的接收模式
+        let plan =
+            virtio_net_mac_table_plan(CTRL_RX, &many[..VIRTIO_NET_MAC_TABLE_ENTRIES], &[]).unwrap();
+        assert!(plan.overflow.is_empty());
+        assert_eq!(plan.cmd.data.len(), 8 + 6 * VIRTIO_NET_MAC_TABLE_ENTRIES);
+    }
+
+    #[test]
+    fn test_mac_feature_gating() {
+        assert_eq!(
+            virtio_net_mac_table_plan(VIRTIO_NET_F_CTRL_VQ, &[], &[]).err(),
+            Some(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
+        assert_eq!(
+            virtio_net_mac_addr_cmd(CTRL_RX, mac(1)).err(),
+            Some(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
+        let cmd =
+            virtio_net_mac_addr_cmd(VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_MAC_ADDR, mac(1))
+                .unwrap();
+        assert_eq!(
+            cmd.to_bytes(),
+            [
+                VIRTIO_NET_CTRL_MAC,
+                VIRTIO_NET_CTRL_MAC_ADDR_SET,
+                0x52,
+                0x54,
+                0x00,
+                0x12,
+                0x34,
+                1
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ctrl_status() {
+        assert_eq!(virtio_net_ctrl_status(VIRTIO_NET_OK), Ok(()));
+        assert_eq!(
+            virtio_net_ctrl_status(VIRTIO_NET_ERR),
+            Err(SystemError::EIO)
+        );
+    }
+}
@@ -0,0 +1,531 @@
+//! virtio-net的收发队列
+//!
+//! 接收队列中放置设备可写的接收缓冲区，设备在缓冲区开头写入virtio_net_hdr，之后是一个完整的帧；
+//! 发送时把virtio_net_hdr和帧放在同一个缓冲区中，作为一个设备只读的描述符提交。
+//! 两个队列都是驱动自己管理的[`VirtqSplit`]，缓冲区在设备使用期间由这里持有。
+//!
+//! 设备长时间不处理发送队列时，如果协商了VIRTIO_F_RING_RESET，就单独复位发送队列，
+//! 丢弃其中的数据包，接收队列不受影响。
+//!
+//! 参考 virtio spec 5.1.6 Device Operation
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+use log::warn;
+use system_error::SystemError;
+use virtio_drivers::{transport::Transport, Hal};
+
+use crate::driver::virtio::{
+    queue::VirtqSplit,
+    ring::VirtQueueSizePolicy,
+    sg::VirtioSgList,
+    transport::{VirtIOTransport, VIRTIO_F_VERSION_1},
+    virtio_impl::HalImpl,
+};
+
+use super::mrg_rxbuf::VIRTIO_NET_F_MRG_RXBUF;
+
+/// 接收缓冲区开头的virtio_net_hdr的最大长度(包含num_buffers字段)
+pub const VIRTIO_NET_HDR_LEN: usize = 12;
+/// legacy设备在没有协商VIRTIO_NET_F_MRG_RXBUF时，virtio_net_hdr没有num_buffers字段
+const VIRTIO_NET_LEGACY_HDR_LEN: usize = 10;
+
+/// 设备会在配置空间中提供MAC地址
+pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// 接收队列的编号
+pub const VIRTIO_NET_RX_QUEUE: u16 = 0;
+/// 发送队列的编号
+pub const VIRTIO_NET_TX_QUEUE: u16 = 1;
+
+/// 配置空间中mac字段的偏移量
+const VIRTIO_NET_CONFIG_MAC_OFFSET: usize = 0;
+
+/// 发送队列持续已满超过这个时间(毫秒)，视为设备不再处理发送队列
+pub const VIRTIO_NET_TX_TIMEOUT_MS: i64 = 5000;
+
+/// # 函数的功能
+/// 根据协商的特性得到virtio_net_hdr的长度
+///
+/// 参考 virtio spec 5.1.6.1 Legacy Interface: Device Operation
+pub fn virtio_net_hdr_len(features: u64) -> usize {
+    if features & (VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MRG_RXBUF) != 0 {
+        VIRTIO_NET_HDR_LEN
+    } else {
+        VIRTIO_NET_LEGACY_HDR_LEN
+    }
+}
+
+/// # 结构功能
+/// 一个接收缓冲区，设备写入的数据由virtio_net_hdr和帧组成
+#[derive(Debug)]
+pub struct VirtIONetRxBuf {
+    buf: Vec<u8>,
+    hdr_len: usize,
+    /// 设备写入的字节数
+    used: usize,
+}
+
+impl VirtIONetRxBuf {
+    /// # 函数的功能
+    /// 分配一个大小为`len`的接收缓冲区
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOMEM): 内存不足
+    pub fn new(len: usize) -> Result<Self, SystemError> {
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len)
+            .map_err(|_| SystemError::ENOMEM)?;
+        buf.resize(len, 0);
+        Ok(Self {
+            buf,
+            hdr_len: 0,
+            used: 0,
+        })
+    }
+
+    /// 收到的帧，不包括virtio_net_hdr
+    pub fn packet(&self) -> &[u8] {
+        &self.buf[self.hdr_len..self.used]
+    }
+
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.hdr_len..self.used]
+    }
+}
+
+/// # 结构功能
+/// 接收队列和发送队列中的缓冲区，不访问设备的寄存器
+pub struct VirtIONetRing<H: Hal> {
+    rx: VirtqSplit<H>,
+    tx: VirtqSplit<H>,
+    /// 设备持有的接收缓冲区，以描述符链头为键
+    rx_bufs: BTreeMap<u16, VirtIONetRxBuf>,
+    /// 设备还没有发送完的数据包，以描述符链头为键
+    tx_bufs: BTreeMap<u16, Vec<u8>>,
+    /// 发送队列从什么时候开始一直是满的(毫秒)
+    tx_full_since_ms: Option<i64>,
+    hdr_len: usize,
+}
+
+impl<H: Hal> VirtIONetRing<H> {
+    pub fn new(rx: VirtqSplit<H>, tx: VirtqSplit<H>, hdr_len: usize) -> Self {
+        Self {
+            rx,
+            tx,
+            rx_bufs: BTreeMap::new(),
+            tx_bufs: BTreeMap::new(),
+            tx_full_since_ms: None,
+            hdr_len,
+        }
+    }
+
+    pub fn rx(&self) -> &VirtqSplit<H> {
+        &self.rx
+    }
+
+    pub fn tx(&self) -> &VirtqSplit<H> {
+        &self.tx
+    }
+
+    pub fn hdr_len(&self) -> usize {
+        self.hdr_len
+    }
+
+    /// 接收队列最多能放置的缓冲区数量
+    pub fn rx_capacity(&self) -> usize {
+        self.rx.size() as usize
+    }
+
+    /// # 函数的功能
+    /// 把一个接收缓冲区放入接收队列
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): 接收队列已满
+    /// - Err(SystemError::EINVAL): 缓冲区放不下virtio_net_hdr
+    pub fn post_rx(&mut self, mut buf: VirtIONetRxBuf) -> Result<(), SystemError> {
+        if buf.buf.len() <= self.hdr_len {
+            return Err(SystemError::EINVAL);
+        }
+        let head = {
+            let mut sg = VirtioSgList::new();
+            sg.push_writable(&mut buf.buf);
+            self.rx.try_add(&sg)?
+        };
+        // Vec的数据在堆上，移动缓冲区不会改变设备看到的地址
+        self.rx_bufs.insert(head, buf);
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 取出一个设备已经写入数据的接收缓冲区
+    ///
+    /// ## 返回值
+    /// - Some(buf): 收到的帧，用完之后可以通过[`Self::post_rx`]重新放入接收队列
+    /// - None: 没有收到新的帧
+    pub fn pop_rx(&mut self) -> Option<VirtIONetRxBuf> {
+        loop {
+            let (head, len) = self.rx.pop_used()?;
+            let Some(mut buf) = self.rx_bufs.remove(&head) else {
+                // VirtqSplit已经为未知的描述符打印了警告
+                continue;
+            };
+            buf.hdr_len = self.hdr_len;
+            buf.used = (len as usize).clamp(self.hdr_len, buf.buf.len());
+            return Some(buf);
+        }
+    }
+
+    /// 回收设备已经发送完的数据包，返回回收的数量
+    pub fn reclaim_tx(&mut self) -> usize {
+        let mut reclaimed = 0;
+        while let Some((head, _)) = self.tx.pop_used() {
+            self.tx_bufs.remove(&head);
+            reclaimed += 1;
+        }
+        reclaimed
+    }
+
+    /// 设备还没有发送完的数据包数量
+    pub fn tx_inflight(&self) -> usize {
+        self.tx_bufs.len()
+    }
+
+    /// 发送队列中是否还有空间
+    pub fn can_send(&mut self) -> bool {
+        self.reclaim_tx();
+        self.tx.num_free() > 0
+    }
+
+    /// # 函数的功能
+    /// 检查发送队列是否已经持续满了[`VIRTIO_NET_TX_TIMEOUT_MS`]
+    ///
+    /// 返回true之后重新开始计时，因此每个超时周期最多返回一次true
+    ///
+    /// ## 参数
+    /// - `now_ms`: 当前时间(毫秒)
+    pub fn tx_timed_out(&mut self, now_ms: i64) -> bool {
+        if self.can_send() {
+            self.tx_full_since_ms = None;
+            return false;
+        }
+        let since = *self.tx_full_since_ms.get_or_insert(now_ms);
+        if now_ms - since < VIRTIO_NET_TX_TIMEOUT_MS {
+            return false;
+        }
+        self.tx_full_since_ms = Some(now_ms);
+        true
+    }
+
+    /// # 函数的功能
+    /// 设备停止使用发送队列之后，丢弃其中所有的数据包，让发送队列回到刚创建时的状态
+    ///
+    /// ## 返回值
+    /// 被丢弃的数据包数量
+    pub fn reset_tx(&mut self) -> usize {
+        let dropped = self.tx.reclaim_all().len();
+        self.tx_bufs.clear();
+        self.tx_full_since_ms = None;
+        dropped
+    }
+
+    /// # 函数的功能
+    /// 把一个数据包放入发送队列，设备发送完之后由[`Self::reclaim_tx`]释放
+    ///
+    /// ## 参数
+    /// - `packet`: 开头预留了virtio_net_hdr的数据包，见[`VirtIONetQueue::new_tx_buffer`]
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): 发送队列已满
+    pub fn add_tx(&mut self, mut packet: Vec<u8>) -> Result<(), SystemError> {
+        if packet.len() <= self.hdr_len {
+            return Err(SystemError::EINVAL);
+        }
+        // 没有协商任何卸载特性，virtio_net_hdr全部为0
+        packet[..self.hdr_len].fill(0);
+        let head = {
+            let mut sg = VirtioSgList::new();
+            sg.push_readable(&packet);
+            self.tx.try_add(&sg)?
+        };
+        self.tx_bufs.insert(head, packet);
+        Ok(())
+    }
+}
+
+/// # 结构功能
+/// 已经初始化完成的virtio-net设备：设备的transport、MAC地址以及收发队列
+///
+/// 被释放时先禁用队列，再释放队列以及其中的缓冲区
+pub struct VirtIONetQueue {
+    transport: VirtIOTransport,
+    ring: VirtIONetRing<HalImpl>,
+    mac: [u8; 6],
+}
+
+impl VirtIONetQueue {
+    /// # 函数的功能
+    /// 按照virtio spec 3.1.1初始化设备，设置收发队列，并填满接收队列
+    ///
+    /// ## 参数
+    /// - `transport`: 设备的transport
+    /// - `rx_buf_len`: 接收缓冲区的大小，包括virtio_net_hdr
+    /// - `policy`: 收发队列的大小
+    /// - `supported`: 驱动支持的特性
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOMEM): 无法分配接收缓冲区
+    /// - Err(e): 见[`VirtIOTransport::negotiate_features`]以及[`VirtIOTransport::negotiate_queue_size`]
+    pub fn new(
+        mut transport: VirtIOTransport,
+        rx_buf_len: usize,
+        policy: &VirtQueueSizePolicy,
+        supported: u64,
+    ) -> Result<Self, SystemError> {
+        let features = transport.negotiate_features(supported, 0)?;
+        let mac = if features & VIRTIO_NET_F_MAC != 0 {
+            transport.config_read::<[u8; 6]>(VIRTIO_NET_CONFIG_MAC_OFFSET)
+        } else {
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        };
+        let queues = mac.and_then(|mac| {
+            let rx = Self::create_queue(&mut transport, VIRTIO_NET_RX_QUEUE, policy)?;
+            let tx = Self::create_queue(&mut transport, VIRTIO_NET_TX_QUEUE, policy)?;
+            Ok((mac, rx, tx))
+        });
+        let (mac, rx, tx) = match queues {
+            Ok(queues) => queues,
+            Err(e) => {
+                transport.fail_init();
+                return Err(e);
+            }
+        };
+        transport.finish_init();
+
+        let mut queue = Self {
+            transport,
+            ring: VirtIONetRing::new(rx, tx, virtio_net_hdr_len(features)),
+            mac,
+        };
+        // 设备进入DRIVER_OK之后才能通知它
+        for _ in 0..queue.ring.rx_capacity() {
+            queue.post_rx(VirtIONetRxBuf::new(rx_buf_len)?)?;
+        }
+        Ok(queue)
+    }
+
+    fn create_queue(
+        transport: &mut VirtIOTransport,
+        queue: u16,
+        policy: &VirtQueueSizePolicy,
+    ) -> Result<VirtqSplit<HalImpl>, SystemError> {
+        let size = transport.negotiate_queue_size(queue, policy)?;
+        let vq = VirtqSplit::new(queue, size, transport.requires_legacy_layout())?;
+        vq.attach(transport)?;
+        Ok(vq)
+    }
+
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn ring(&mut self) -> &mut VirtIONetRing<HalImpl> {
+        &mut self.ring
+    }
+
+    /// 分配一个发送缓冲区，数据包从第[`VirtIONetRing::hdr_len`]个字节开始
+    pub fn new_tx_buffer(&self, len: usize) -> Vec<u8> {
+        vec![0u8; self.ring.hdr_len() + len]
+    }
+
+    /// 放入一个接收缓冲区，并在需要时通知设备
+    pub fn post_rx(&mut self, buf: VirtIONetRxBuf) -> Result<(), SystemError> {
+        self.ring.post_rx(buf)?;
+        if self.ring.rx().should_notify() {
+            self.transport.notify(VIRTIO_NET_RX_QUEUE);
+        }
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 发送队列是否还有空间
+    ///
+    /// 设备长时间不处理发送队列时，尝试单独复位发送队列，见[`VirtIONetRing::tx_timed_out`]
+    ///
+    /// ## 参数
+    /// - `now_ms`: 当前时间(毫秒)
+    pub fn can_send(&mut self, now_ms: i64) -> bool {
+        if self.ring.tx_timed_out(now_ms) {
+            let ring = &mut self.ring;
+            let mut dropped = 0;
+            let r = self.transport.reset_queue(VIRTIO_NET_TX_QUEUE, &mut || {
+                dropped = ring.reset_tx();
+                ring.tx().config()
+            });
+            match r {
+                Ok(()) => warn!(
+                    "virtio_net: tx queue stalled, reset it and dropped {} packets",
+                    dropped
+                ),
+                Err(e) => warn!("virtio_net: tx queue stalled, cannot reset it: {:?}", e),
+            }
+        }
+        self.ring.can_send()
+    }
+
+    /// 发送一个数据包，并在需要时通知设备。不等待设备发送完成
+    pub fn send(&mut self, packet: Vec<u8>) -> Result<(), SystemError> {
+        self.ring.reclaim_tx();
+        self.ring.add_tx(packet)?;
+        if self.ring.tx().should_notify() {
+            self.transport.notify(VIRTIO_NET_TX_QUEUE);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtIONetQueue {
+    fn drop(&mut self) {
+        // 缓冲区在这之后才被释放
+        self.transport.queue_unset(VIRTIO_NET_RX_QUEUE);
+        self.transport.queue_unset(VIRTIO_NET_TX_QUEUE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::virtio::mock::{MockHal, MockVirtqDevice};
+
+    use super::*;
+
+    fn ring(size: u16) -> VirtIONetRing<MockHal> {
+        VirtIONetRing::new(
+            VirtqSplit::new(VIRTIO_NET_RX_QUEUE, size, false).unwrap(),
+            VirtqSplit::new(VIRTIO_NET_TX_QUEUE, size, false).unwrap(),
+            VIRTIO_NET_HDR_LEN,
+        )
+    }
+
+    #[test]
+    fn test_hdr_len() {
+        assert_eq!(virtio_net_hdr_len(VIRTIO_F_VERSION_1), 12);
+        assert_eq!(virtio_net_hdr_len(VIRTIO_NET_F_MRG_RXBUF), 12);
+        assert_eq!(virtio_net_hdr_len(0), 10);
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut ring = ring(2);
+        let mut dev = MockVirtqDevice::new();
+        ring.post_rx(VirtIONetRxBuf::new(64).unwrap()).unwrap();
+        ring.post_rx(VirtIONetRxBuf::new(64).unwrap()).unwrap();
+        assert_eq!(
+            ring.post_rx(VirtIONetRxBuf::new(64).unwrap()).err(),
+            Some(SystemError::EAGAIN_OR_EWOULDBLOCK)
+        );
+        assert!(ring.pop_rx().is_none());
+
+        // 设备写入virtio_net_hdr和一个20字节的帧
+        let (head, descs) = dev.pop_avail(ring.rx()).unwrap();
+        assert_eq!(descs.len(), 1);
+        assert!(descs[0].writable());
+        assert_eq!(descs[0].len, 64);
+        let data = unsafe { descs[0].buf() };
+        data[VIRTIO_NET_HDR_LEN..VIRTIO_NET_HDR_LEN + 20].fill(0xee);
+        dev.push_used(ring.rx(), head, (VIRTIO_NET_HDR_LEN + 20) as u32);
+
+        let mut buf = ring.pop_rx().unwrap();
+        assert_eq!(buf.packet(), &[0xee; 20]);
+        assert!(ring.pop_rx().is_none());
+
+        // 缓冲区可以被重新放入接收队列
+        buf.packet_mut().fill(0);
+        ring.post_rx(buf).unwrap();
+        assert_eq!(ring.rx().num_free(), 0);
+    }
+
+    #[test]
+    fn test_short_rx_completion() {
+        let mut ring = ring(2);
+        let mut dev = MockVirtqDevice::new();
+        ring.post_rx(VirtIONetRxBuf::new(64).unwrap()).unwrap();
+        let (head, _) = dev.pop_avail(ring.rx()).unwrap();
+        // 设备写入的长度比virtio_net_hdr还短，得到一个空的帧而不是越界
+        dev.push_used(ring.rx(), head, 4);
+        assert!(ring.pop_rx().unwrap().packet().is_empty());
+    }
+
+    #[test]
+    fn test_tx_timeout_and_reset() {
+        let mut ring = ring(2);
+        let mut dev = MockVirtqDevice::new();
+        ring.add_tx(vec![0u8; VIRTIO_NET_HDR_LEN + 1]).unwrap();
+        assert!(!ring.tx_timed_out(0));
+        ring.add_tx(vec![0u8; VIRTIO_NET_HDR_LEN + 1]).unwrap();
+
+        // 发送队列满了之后开始计时
+        assert!(!ring.tx_timed_out(1000));
+        assert!(!ring.tx_timed_out(1000 + VIRTIO_NET_TX_TIMEOUT_MS - 1));
+        assert!(ring.tx_timed_out(1000 + VIRTIO_NET_TX_TIMEOUT_MS));
+        // 重新开始计时
+        assert!(!ring.tx_timed_out(1000 + VIRTIO_NET_TX_TIMEOUT_MS + 1));
+
+        // 设备处理了一个数据包，计时停止
+        let (head, _) = dev.pop_avail(ring.tx()).unwrap();
+        dev.push_used(ring.tx(), head, 0);
+        assert!(!ring.tx_timed_out(100_000));
+        ring.add_tx(vec![0u8; VIRTIO_NET_HDR_LEN + 1]).unwrap();
+        assert!(!ring.tx_timed_out(100_000));
+        assert!(ring.tx_timed_out(100_000 + VIRTIO_NET_TX_TIMEOUT_MS));
+
+        // 复位发送队列丢弃其中的数据包，接收队列不受影响
+        ring.post_rx(VirtIONetRxBuf::new(64).unwrap()).unwrap();
+        assert_eq!(ring.reset_tx(), 2);
+        assert_eq!(ring.tx_inflight(), 0);
+        assert_eq!(ring.tx().num_free(), 2);
+        assert_eq!(ring.rx().num_free(), 1);
+        assert!(ring.can_send());
+
+        // 设备从头开始读取发送队列
+        let mut dev = MockVirtqDevice::new();
+        ring.add_tx(vec![0u8; VIRTIO_NET_HDR_LEN + 1]).unwrap();
+        let (head, _) = dev.pop_avail(ring.tx()).unwrap();
+        dev.push_used(ring.tx(), head, 0);
+        assert_eq!(ring.reclaim_tx(), 1);
+    }
+
+    #[test]
+    fn test_send() {
+        let mut ring = ring(2);
+        let mut dev = MockVirtqDevice::new();
+        let mut packet = vec![0xffu8; VIRTIO_NET_HDR_LEN + 30];
+        packet[VIRTIO_NET_HDR_LEN..].fill(0x11);
+        ring.add_tx(packet).unwrap();
+        assert_eq!(ring.tx_inflight(), 1);
+
+        // 设备看到一个只读的描述符，virtio_net_hdr被清零
+        let (head, descs) = dev.pop_avail(ring.tx()).unwrap();
+        assert_eq!(descs.len(), 1);
+        assert!(!descs[0].writable());
+        let data = unsafe { descs[0].buf() };
+        assert!(data[..VIRTIO_NET_HDR_LEN].iter().all(|b| *b == 0));
+        assert!(data[VIRTIO_NET_HDR_LEN..].iter().all(|b| *b == 0x11));
+
+        assert!(ring.can_send());
+        ring.add_tx(vec![0u8; VIRTIO_NET_HDR_LEN + 1]).unwrap();
+        assert!(!ring.can_send());
+        assert_eq!(
+            ring.add_tx(vec![0u8; VIRTIO_NET_HDR_LEN + 1]),
+            Err(SystemError::EAGAIN_OR_EWOULDBLOCK)
+        );
+
+        // 发送完成之后描述符和缓冲区被回收
+        dev.push_used(ring.tx(), head, 0);
+        assert!(ring.can_send());
+        assert_eq!(ring.tx_inflight(), 1);
+        assert_eq!(
+            ring.add_tx(vec![0u8; VIRTIO_NET_HDR_LEN]),
+            Err(SystemError::EINVAL)
+        );
+    }
+}
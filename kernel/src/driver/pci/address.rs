@@ -0,0 +1,178 @@
+//! PCI设备的地址
+//!
+//! 地址的字符串形式为`dddd:bb:dd.f`（域号:总线号:设备号.功能号，前三项为十六进制），
+//! 例如`0000:00:04.0`，它也是PCI设备在sysfs中的目录名称。
+
+use core::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use system_error::SystemError;
+
+use super::pci::BusDeviceFunction;
+
+/// 一条总线上最多的设备数量
+const PCI_MAX_DEVICES: u8 = 32;
+/// 一个设备最多的功能数量
+const PCI_MAX_FUNCTIONS: u8 = 8;
+
+/// # 结构功能
+/// PCI设备的地址，作为PCI设备的唯一标识
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    /// 域号（即ECAM的段组号）
+    pub domain: u16,
+    pub bus: u8,
+    /// 设备号，0~31
+    pub device: u8,
+    /// 功能号，0~7
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// # 函数的功能
+    /// 创建PCI地址
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): 设备号或者功能号超出范围
+    pub fn new(domain: u16, bus: u8, device: u8, function: u8) -> Result<Self, SystemError> {
+        if device >= PCI_MAX_DEVICES || function >= PCI_MAX_FUNCTIONS {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(Self {
+            domain,
+            bus,
+            device,
+            function,
+        })
+    }
+
+    /// 在域内的位置
+    pub fn bdf(&self) -> BusDeviceFunction {
+        BusDeviceFunction {
+            bus: self.bus,
+            device: self.device,
+            function: self.function,
+        }
+    }
+}
+
+impl From<BusDeviceFunction> for PciAddress {
+    /// 目前只支持域0
+    fn from(bdf: BusDeviceFunction) -> Self {
+        Self {
+            domain: 0,
+            bus: bdf.bus,
+            device: bdf.device,
+            function: bdf.function,
+        }
+    }
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.domain, self.bus, self.device, self.function
+        )
+    }
+}
+
+impl FromStr for PciAddress {
+    type Err = SystemError;
+
+    /// # 函数的功能
+    /// 解析`dddd:bb:dd.f`形式的地址，域号可以省略（`bb:dd.f`），此时为域0
+    ///
+    /// 十六进制数不区分大小写，首尾的空白字符会被忽略
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (rest, function) = s.rsplit_once('.').ok_or(SystemError::EINVAL)?;
+        let mut parts = rest.rsplitn(3, ':');
+        let device = parts.next().ok_or(SystemError::EINVAL)?;
+        let bus = parts.next().ok_or(SystemError::EINVAL)?;
+        let domain = parts.next();
+
+        fn hex<T: TryFrom<u32>>(s: &str, max_len: usize) -> Result<T, SystemError> {
+            if s.is_empty() || s.len() > max_len || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(SystemError::EINVAL);
+            }
+            let v = u32::from_str_radix(s, 16).map_err(|_| SystemError::EINVAL)?;
+            T::try_from(v).map_err(|_| SystemError::EINVAL)
+        }
+
+        let domain = match domain {
+            Some(d) => hex(d, 4)?,
+            None => 0,
+        };
+        if function.len() != 1 {
+            return Err(SystemError::EINVAL);
+        }
+        Self::new(domain, hex(bus, 2)?, hex(device, 2)?, hex(function, 1)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let addr = PciAddress::new(0, 0, 4, 0).unwrap();
+        assert_eq!(addr.to_string(), "0000:00:04.0");
+        let addr = PciAddress::new(0x10, 0xab, 0x1f, 7).unwrap();
+        assert_eq!(addr.to_string(), "0010:ab:1f.7");
+    }
+
+    #[test]
+    fn test_parse() {
+        let addr = PciAddress::new(0, 0, 4, 0).unwrap();
+        assert_eq!("0000:00:04.0".parse(), Ok(addr));
+        assert_eq!("00:04.0".parse(), Ok(addr));
+        assert_eq!(" 0000:00:04.0\n".parse(), Ok(addr));
+        assert_eq!(
+            "000A:FF:1F.7".parse(),
+            Ok(PciAddress::new(0xa, 0xff, 0x1f, 7).unwrap())
+        );
+
+        // 解析得到的地址格式化之后与原字符串相同
+        for s in ["0000:00:00.0", "0001:02:03.4", "ffff:ff:1f.7"] {
+            assert_eq!(s.parse::<PciAddress>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for s in [
+            "",
+            "0000:00:04",
+            "0000:00:20.0",
+            "0000:00:04.8",
+            "0000:00:04.10",
+            "00000:00:04.0",
+            "0000:100:04.0",
+            "0000:00:04.-1",
+            "0:0:0:0.0",
+            "0000:0g:04.0",
+            "virtio0",
+        ] {
+            assert_eq!(s.parse::<PciAddress>(), Err(SystemError::EINVAL), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_bdf_round_trip() {
+        let bdf = BusDeviceFunction {
+            bus: 3,
+            device: 2,
+            function: 1,
+        };
+        let addr = PciAddress::from(bdf);
+        assert_eq!(addr.to_string(), "0000:03:02.1");
+        assert_eq!(addr.bdf(), bdf);
+    }
+}
@@ -1,4 +1,7 @@
-use alloc::sync::Arc;
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+};
 use intertrait::cast::CastArc;
 use log::warn;
 use system_error::SystemError;
@@ -11,9 +14,30 @@ use crate::{
         },
         vfs::syscall::ModeType,
     },
+    smp::cpu::smp_cpu_manager,
 };
 
-use super::device::PciDevice;
+use super::{
+    device::PciDevice,
+    ids::pci_device_description,
+    irq_stats::{
+        format_pci_irq_vector_stats, pci_irq_stats_total, pci_irq_vector_stats, PciIrqVectorStat,
+    },
+    numa::{pci_local_cpus, pci_numa_node, NUMA_NO_NODE},
+    pci::{format_pci_resource, PCI_DEVICE_LINKEDLIST},
+    pci_irq::pci_irq_affinity,
+    pcie::{pcie_device_control, PcieDeviceControl},
+    pm::pci_power_state,
+    stats::PciMatchStats,
+};
+const MATCH_STATS_ATTRS: [&str; 5] = [
+    "drivers_tried",
+    "bind_failures",
+    "last_probe_error",
+    "driver_bound_time",
+    "stats",
+];
+
 #[derive(Debug)]
 pub struct BasicPciReadOnlyAttrs;
 
@@ -23,14 +47,33 @@ impl AttributeGroup for BasicPciReadOnlyAttrs {
     }
 
     fn attrs(&self) -> &[&'static dyn Attribute] {
-        &[&Vendor, &DeviceID, &SubsystemVendor, &SubsystemDevice]
+        &[
+            &Vendor,
+            &DeviceID,
+            &DeviceName,
+            &SubsystemVendor,
+            &SubsystemDevice,
+            &Resource,
+            &PowerState,
+            &MaxPayloadSize,
+            &MaxReadRequestSize,
+            &MsixAffinity,
+            &NumaNode,
+            &LocalCpus,
+            &DriversTried,
+            &BindFailures,
+            &LastProbeError,
+            &DriverBoundTime,
+            &MatchStatsAttr,
+        ]
     }
 
-    fn is_visible(
-        &self,
-        _kobj: Arc<dyn KObject>,
-        attr: &'static dyn Attribute,
-    ) -> Option<ModeType> {
+    fn is_visible(&self, kobj: Arc<dyn KObject>, attr: &'static dyn Attribute) -> Option<ModeType> {
+        if MATCH_STATS_ATTRS.contains(&attr.name()) {
+            // 不记录匹配统计的设备不显示这些属性
+            let dev = kobj.cast::<dyn PciDevice>().ok()?;
+            dev.match_stats()?;
+        }
         return Some(attr.mode());
     }
 }
@@ -97,6 +140,40 @@ impl Attribute for DeviceID {
     }
 }
 
+/// 设备的可读名称，查询不到时显示"vendor:device"形式的十六进制id
+#[derive(Debug)]
+pub struct DeviceName;
+
+impl Attribute for DeviceName {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "device_name"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .cast::<dyn PciDevice>()
+            .map_err(|e: Arc<dyn KObject>| {
+                warn!("device:{:?} is not a pci device!", e);
+                SystemError::EINVAL
+            })?;
+        return sysfs_emit_str(
+            buf,
+            &format!(
+                "{}\n",
+                pci_device_description(dev.vendor(), dev.device_id())
+            ),
+        );
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
 #[derive(Debug)]
 pub struct SubsystemVendor;
 
@@ -158,3 +235,436 @@ impl Attribute for SubsystemDevice {
         SysFSOpsSupport::ATTR_SHOW
     }
 }
+
+/// 设备的BAR，每行为一个BAR的`<起始地址> <结束地址> <标志位>`
+///
+/// 使用设备的驱动映射BAR时记录的结果，没有被映射的BAR为全0
+#[derive(Debug)]
+pub struct Resource;
+
+impl Attribute for Resource {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "resource"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .cast::<dyn PciDevice>()
+            .map_err(|e: Arc<dyn KObject>| {
+                warn!("device:{:?} is not a pci device!", e);
+                SystemError::EINVAL
+            })?;
+        let bdf = dev.bus_device_function().ok_or(SystemError::ENODEV)?;
+        let bars = PCI_DEVICE_LINKEDLIST
+            .with_device(bdf, |d| {
+                d.as_standard_device()
+                    .map(|d| d.standard_device_bar.regions())
+            })
+            .ok_or(SystemError::ENODEV)?
+            .unwrap_or_default();
+        return sysfs_emit_str(buf, &format_pci_resource(&bars));
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 设备当前的电源状态（D0/D1/D2/D3hot）
+#[derive(Debug)]
+pub struct PowerState;
+
+impl Attribute for PowerState {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "power_state"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .cast::<dyn PciDevice>()
+            .map_err(|e: Arc<dyn KObject>| {
+                warn!("device:{:?} is not a pci device!", e);
+                SystemError::EINVAL
+            })?;
+        let bdf = dev.bus_device_function().ok_or(SystemError::ENODEV)?;
+        return sysfs_emit_str(buf, &format!("{}\n", pci_power_state(bdf)));
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+fn pcie_show(
+    kobj: Arc<dyn KObject>,
+    buf: &mut [u8],
+    f: impl FnOnce(&PcieDeviceControl) -> u16,
+) -> Result<usize, SystemError> {
+    let dev = kobj
+        .cast::<dyn PciDevice>()
+        .map_err(|e: Arc<dyn KObject>| {
+            warn!("device:{:?} is not a pci device!", e);
+            SystemError::EINVAL
+        })?;
+    let bdf = dev.bus_device_function().ok_or(SystemError::ENODEV)?;
+    let ctl = pcie_device_control(bdf)?;
+    return sysfs_emit_str(buf, &format!("{}\n", f(&ctl)));
+}
+
+/// 当前的Max Payload Size(字节)，不是PCIe设备时读取失败
+#[derive(Debug)]
+pub struct MaxPayloadSize;
+
+impl Attribute for MaxPayloadSize {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "max_payload_size"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        pcie_show(kobj, buf, PcieDeviceControl::max_payload)
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 当前的Max Read Request Size(字节)，不是PCIe设备时读取失败
+#[derive(Debug)]
+pub struct MaxReadRequestSize;
+
+impl Attribute for MaxReadRequestSize {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "max_read_request_size"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        pcie_show(kobj, buf, PcieDeviceControl::max_read_request)
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 每个MSI-X中断被投递到的CPU，每行的格式为“<中断在MSI-X表中的位置> <CPU>”
+#[derive(Debug)]
+pub struct MsixAffinity;
+
+impl Attribute for MsixAffinity {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "msix_affinity"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .cast::<dyn PciDevice>()
+            .map_err(|e: Arc<dyn KObject>| {
+                warn!("device:{:?} is not a pci device!", e);
+                SystemError::EINVAL
+            })?;
+        let bdf = dev.bus_device_function().ok_or(SystemError::ENODEV)?;
+        let affinity = pci_irq_affinity(bdf)?;
+        let mut s = String::new();
+        for (index, cpu) in affinity.iter().enumerate() {
+            s.push_str(&format!("{} {}\n", index, cpu.data()));
+        }
+        return sysfs_emit_str(buf, &s);
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 设备所在的NUMA节点，未知时为-1
+#[derive(Debug)]
+pub struct NumaNode;
+
+impl Attribute for NumaNode {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "numa_node"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .cast::<dyn PciDevice>()
+            .map_err(|e: Arc<dyn KObject>| {
+                warn!("device:{:?} is not a pci device!", e);
+                SystemError::EINVAL
+            })?;
+        let node = dev
+            .bus_device_function()
+            .map(pci_numa_node)
+            .unwrap_or(NUMA_NO_NODE);
+        return sysfs_emit_str(buf, &format!("{}\n", node));
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 与设备位于同一NUMA节点的CPU，以十六进制的cpumask表示。设备的中断默认只分散到这些CPU上
+#[derive(Debug)]
+pub struct LocalCpus;
+
+impl Attribute for LocalCpus {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "local_cpus"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .cast::<dyn PciDevice>()
+            .map_err(|e: Arc<dyn KObject>| {
+                warn!("device:{:?} is not a pci device!", e);
+                SystemError::EINVAL
+            })?;
+        let mask = pci_local_cpus(dev.bus_device_function());
+        let nr_cpus = smp_cpu_manager().possible_cpus_count() as usize;
+        return sysfs_emit_str(buf, &format!("{}\n", mask.to_hex_string(nr_cpus)));
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+fn match_stats_show(
+    kobj: Arc<dyn KObject>,
+    buf: &mut [u8],
+    f: impl FnOnce(&PciMatchStats) -> String,
+) -> Result<usize, SystemError> {
+    let dev = kobj
+        .cast::<dyn PciDevice>()
+        .map_err(|e: Arc<dyn KObject>| {
+            warn!("device:{:?} is not a pci device!", e);
+            SystemError::EINVAL
+        })?;
+    let stats = dev.match_stats().ok_or(SystemError::ENODEV)?;
+    return sysfs_emit_str(buf, &f(stats));
+}
+
+/// 尝试与该设备匹配的驱动数量
+#[derive(Debug)]
+pub struct DriversTried;
+
+impl Attribute for DriversTried {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "drivers_tried"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        match_stats_show(kobj, buf, |stats| format!("{}\n", stats.drivers_tried()))
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 驱动probe该设备失败的次数
+#[derive(Debug)]
+pub struct BindFailures;
+
+impl Attribute for BindFailures {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "bind_failures"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        match_stats_show(kobj, buf, |stats| format!("{}\n", stats.bind_failures()))
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 最近一次probe失败的错误码
+#[derive(Debug)]
+pub struct LastProbeError;
+
+impl Attribute for LastProbeError {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "last_probe_error"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        match_stats_show(kobj, buf, |stats| match stats.last_error() {
+            Some(e) => format!("{:?}\n", e),
+            None => "none\n".to_string(),
+        })
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 最近一次驱动probe该设备花费的时间（微秒）
+#[derive(Debug)]
+pub struct DriverBoundTime;
+
+impl Attribute for DriverBoundTime {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "driver_bound_time"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        match_stats_show(kobj, buf, |stats| format!("{}\n", stats.probe_time_us()))
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 同一时刻的所有匹配统计信息，每行为`<名称> <值>`
+#[derive(Debug)]
+pub struct MatchStatsAttr;
+
+impl Attribute for MatchStatsAttr {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        match_stats_show(kobj, buf, |stats| stats.snapshot().format())
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 设备的`msi_irqs`目录：每个中断向量的中断次数
+#[derive(Debug)]
+pub struct PciMsiIrqsAttrGroup;
+
+impl AttributeGroup for PciMsiIrqsAttrGroup {
+    fn name(&self) -> Option<&str> {
+        Some("msi_irqs")
+    }
+
+    fn attrs(&self) -> &[&'static dyn Attribute] {
+        &[&MsiIrqCounts, &MsiIrqTotal]
+    }
+
+    fn is_visible(&self, kobj: Arc<dyn KObject>, attr: &'static dyn Attribute) -> Option<ModeType> {
+        // 不在PCI设备链表中的设备(例如测试设备)没有中断向量
+        kobj.cast::<dyn PciDevice>().ok()?.bus_device_function()?;
+        return Some(attr.mode());
+    }
+}
+
+fn msi_irqs_show(
+    kobj: Arc<dyn KObject>,
+    buf: &mut [u8],
+    f: impl FnOnce(&[PciIrqVectorStat]) -> String,
+) -> Result<usize, SystemError> {
+    let dev = kobj
+        .cast::<dyn PciDevice>()
+        .map_err(|e: Arc<dyn KObject>| {
+            warn!("device:{:?} is not a pci device!", e);
+            SystemError::EINVAL
+        })?;
+    let bdf = dev.bus_device_function().ok_or(SystemError::ENODEV)?;
+    let stats = pci_irq_vector_stats(bdf)?;
+    return sysfs_emit_str(buf, &f(&stats));
+}
+
+/// 每个中断向量的中断次数，每行为`<位置> <中断号> <次数>`
+#[derive(Debug)]
+pub struct MsiIrqCounts;
+
+impl Attribute for MsiIrqCounts {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "counts"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        msi_irqs_show(kobj, buf, format_pci_irq_vector_stats)
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+/// 所有中断向量的中断次数之和
+#[derive(Debug)]
+pub struct MsiIrqTotal;
+
+impl Attribute for MsiIrqTotal {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "total"
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        msi_irqs_show(kobj, buf, |stats| {
+            format!("{}\n", pci_irq_stats_total(stats))
+        })
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
@@ -0,0 +1,241 @@
+//! PCI-PCI桥在设备模型中对应的设备
+//!
+//! 桥下游总线上的设备以桥为父设备，因此它们在sysfs中的目录位于桥的目录之下，例如
+//! `/sys/devices/pci/0000:00:1c.0/0000:01:00.0`，与硬件的层次结构一致。
+//! 移除桥时，它下游的设备会先被移除，见[`super::device::PciDeviceManager::device_remove`]
+
+use core::any::Any;
+
+use alloc::{
+    string::{String, ToString},
+    sync::{Arc, Weak},
+};
+
+use crate::{
+    driver::base::{
+        class::Class,
+        device::{bus::Bus, driver::Driver, Device, DeviceCommonData, DeviceType, IdTable},
+        kobject::{KObjType, KObject, KObjectCommonData, KObjectState, LockedKObjectState},
+        kset::KSet,
+    },
+    filesystem::{kernfs::KernFSInode, sysfs::AttributeGroup},
+    libs::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use super::{
+    attr::BasicPciReadOnlyAttrs,
+    dev_id::PciDeviceID,
+    device::PciDevice,
+    pci::{BusDeviceFunction, PciDeviceStructure, PciDeviceStructurePciToPciBridge},
+    pm::PciPmState,
+    stats::PciMatchStats,
+};
+
+/// # 结构功能
+/// 枚举时发现的PCI-PCI桥
+#[derive(Debug)]
+#[cast_to([sync] Device)]
+#[cast_to([sync] PciDevice)]
+pub struct PciBridgeDevice {
+    inner: RwLock<InnerPciBridgeDevice>,
+    kobj_state: LockedKObjectState,
+    dev_id: PciDeviceID,
+    header: Arc<PciDeviceStructurePciToPciBridge>,
+    match_stats: PciMatchStats,
+    pm_state: PciPmState,
+}
+
+#[derive(Debug)]
+struct InnerPciBridgeDevice {
+    name: Option<String>,
+    kobject_common: KObjectCommonData,
+    device_common: DeviceCommonData,
+}
+
+impl From<&PciDeviceStructurePciToPciBridge> for PciBridgeDevice {
+    fn from(value: &PciDeviceStructurePciToPciBridge) -> Self {
+        let value = Arc::new(value.clone());
+        let name = value.address().to_string();
+        let res = Self {
+            inner: RwLock::new(InnerPciBridgeDevice {
+                name: None,
+                kobject_common: KObjectCommonData::default(),
+                device_common: DeviceCommonData::default(),
+            }),
+            kobj_state: LockedKObjectState::new(None),
+            dev_id: PciDeviceID::dummpy(),
+            header: value,
+            match_stats: PciMatchStats::new(),
+            pm_state: PciPmState::new(),
+        };
+        res.set_name(name);
+        res
+    }
+}
+
+impl PciDevice for PciBridgeDevice {
+    fn dynid(&self) -> PciDeviceID {
+        self.dev_id
+    }
+
+    fn vendor(&self) -> u16 {
+        self.header.common_header.vendor_id
+    }
+
+    fn device_id(&self) -> u16 {
+        self.header.common_header.device_id
+    }
+
+    /// 桥的配置空间头部中没有subsystem ID
+    fn subsystem_vendor(&self) -> u16 {
+        0
+    }
+
+    fn subsystem_device(&self) -> u16 {
+        0
+    }
+
+    fn bus_device_function(&self) -> Option<BusDeviceFunction> {
+        Some(self.header.common_header.bus_device_function)
+    }
+
+    fn match_stats(&self) -> Option<&PciMatchStats> {
+        Some(&self.match_stats)
+    }
+
+    fn pm_state(&self) -> Option<&PciPmState> {
+        Some(&self.pm_state)
+    }
+
+    fn secondary_bus(&self) -> Option<u8> {
+        Some(self.header.secondary_bus_number)
+    }
+}
+
+impl Device for PciBridgeDevice {
+    fn attribute_groups(&self) -> Option<&'static [&'static dyn AttributeGroup]> {
+        Some(&[&BasicPciReadOnlyAttrs])
+    }
+
+    fn bus(&self) -> Option<Weak<dyn Bus>> {
+        self.inner.read().device_common.bus.clone()
+    }
+
+    fn class(&self) -> Option<Arc<dyn Class>> {
+        let mut guard = self.inner.write();
+        let r = guard.device_common.class.clone()?.upgrade();
+        if r.is_none() {
+            guard.device_common.class = None;
+        }
+
+        return r;
+    }
+
+    fn driver(&self) -> Option<Arc<dyn Driver>> {
+        self.inner.read().device_common.driver.clone()?.upgrade()
+    }
+
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::Pci
+    }
+
+    fn id_table(&self) -> IdTable {
+        IdTable::new("testPci".to_string(), None)
+    }
+
+    fn can_match(&self) -> bool {
+        true
+    }
+
+    fn is_dead(&self) -> bool {
+        self.inner.read().device_common.dead
+    }
+
+    fn set_dead(&self, dead: bool) {
+        self.inner.write().device_common.dead = dead;
+    }
+
+    fn set_bus(&self, bus: Option<Weak<dyn Bus>>) {
+        self.inner.write().device_common.bus = bus;
+    }
+
+    fn set_can_match(&self, _can_match: bool) {}
+
+    fn set_class(&self, class: Option<Weak<dyn Class>>) {
+        self.inner.write().device_common.class = class;
+    }
+
+    fn set_driver(&self, driver: Option<Weak<dyn Driver>>) {
+        self.inner.write().device_common.driver = driver
+    }
+
+    fn state_synced(&self) -> bool {
+        true
+    }
+
+    fn dev_parent(&self) -> Option<Weak<dyn Device>> {
+        self.inner.write().device_common.parent.clone()
+    }
+
+    fn set_dev_parent(&self, dev_parent: Option<Weak<dyn Device>>) {
+        self.inner.write().device_common.parent = dev_parent;
+    }
+}
+
+impl KObject for PciBridgeDevice {
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_inode(&self, inode: Option<Arc<KernFSInode>>) {
+        self.inner.write().kobject_common.kern_inode = inode;
+    }
+
+    fn inode(&self) -> Option<Arc<KernFSInode>> {
+        self.inner.read().kobject_common.kern_inode.clone()
+    }
+
+    fn parent(&self) -> Option<Weak<dyn KObject>> {
+        self.inner.read().kobject_common.parent.clone()
+    }
+
+    fn set_parent(&self, parent: Option<Weak<dyn KObject>>) {
+        self.inner.write().kobject_common.parent = parent;
+    }
+
+    fn kset(&self) -> Option<Arc<KSet>> {
+        self.inner.read().kobject_common.kset.clone()
+    }
+
+    fn set_kset(&self, kset: Option<Arc<KSet>>) {
+        self.inner.write().kobject_common.kset = kset;
+    }
+
+    fn kobj_type(&self) -> Option<&'static dyn KObjType> {
+        self.inner.read().kobject_common.kobj_type
+    }
+
+    fn set_kobj_type(&self, ktype: Option<&'static dyn KObjType>) {
+        self.inner.write().kobject_common.kobj_type = ktype;
+    }
+
+    fn name(&self) -> String {
+        self.inner.read().name.clone().unwrap()
+    }
+
+    fn set_name(&self, name: String) {
+        self.inner.write().name = Some(name);
+    }
+
+    fn kobj_state(&self) -> RwLockReadGuard<KObjectState> {
+        self.kobj_state.read()
+    }
+
+    fn kobj_state_mut(&self) -> RwLockWriteGuard<KObjectState> {
+        self.kobj_state.write()
+    }
+
+    fn set_kobj_state(&self, state: KObjectState) {
+        *self.kobj_state.write() = state;
+    }
+}
@@ -0,0 +1,578 @@
+//! pci配置空间的解析
+//!
+//! 配置空间的内容由设备(虚拟机中由host)提供，有bug或者恶意的设备可以给出任意的内容，
+//! 例如首尾相连的capability链表、指向配置空间之外的指针。这里的解析函数都通过[`PciConfigSource`]读取配置空间：
+//! - 每次读取都检查是否越界、是否按宽度对齐，越界时返回错误而不是访问其他的寄存器
+//! - 遍历capability链表时记录已经访问过的位置，并限制遍历的次数，出现环时返回错误
+//!
+//! 同一套解析函数既可以读取真实设备的配置空间，也可以读取一段字节缓冲区，
+//! 后者用于测试以及对解析函数进行模糊测试(见[`pci_config_fuzz_one`])。
+
+use alloc::vec::Vec;
+
+use super::{
+    pci::{
+        pci_decode_bars, BusDeviceFunction, CapabilityInfo, ExternalCapabilityInfo, PciRoot,
+        PCI_CAP_ID_MSI, PCI_CAP_ID_MSIX,
+    },
+    pci_irq::PciMsixLayout,
+    root::pci_root_0,
+};
+
+/// 传统的配置空间大小
+pub const PCI_CFG_SPACE_SIZE: u16 = 256;
+/// PCIe的扩展配置空间大小
+pub const PCI_CFG_SPACE_EXP_SIZE: u16 = 4096;
+/// 标准capability的最小偏移，之前是配置空间的header
+const PCI_STD_CAP_START: u16 = 0x40;
+/// 扩展capability的起始偏移
+const PCI_EXT_CAP_START: u16 = 0x100;
+/// 标准capability链表最多的项数，与Linux的PCI_FIND_CAP_TTL相同
+const PCI_FIND_CAP_TTL: usize = 48;
+/// 扩展capability链表最多的项数，每项至少8字节
+const PCI_FIND_EXT_CAP_TTL: usize = ((PCI_CFG_SPACE_EXP_SIZE - PCI_CFG_SPACE_SIZE) / 8) as usize;
+/// MSI-X capability的长度
+pub const PCI_MSIX_CAP_LEN: u16 = 12;
+
+/// 解析配置空间时发现的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciConfigError {
+    /// 读取超出了配置空间，值为读取的偏移
+    OutOfBounds(u16),
+    /// 读取没有按宽度对齐
+    Misaligned(u16),
+    /// capability指针指向了header、没有对齐，或者capability超出了配置空间
+    InvalidCapabilityPointer(u16),
+    /// capability链表中有环，或者项数超过了上限
+    CapabilityLoop,
+}
+
+/// # trait功能
+/// 可以按dword读取的配置空间
+///
+/// 解析函数通过provided方法读取，它们会在调用[`PciConfigSource::read_dword`]之前检查边界
+pub trait PciConfigSource {
+    /// 配置空间的长度(字节)，为4的倍数
+    fn config_len(&self) -> u16;
+
+    /// 读取偏移为`offset`的dword，调用者保证`offset`按4对齐并且没有越界
+    fn read_dword(&self, offset: u16) -> u32;
+
+    /// 检查从`offset`开始读取`width`字节是否对齐、是否越界
+    fn check_access(&self, offset: u16, width: u16) -> Result<(), PciConfigError> {
+        if offset % width != 0 {
+            return Err(PciConfigError::Misaligned(offset));
+        }
+        if u32::from(offset) + u32::from(width) > u32::from(self.config_len()) {
+            return Err(PciConfigError::OutOfBounds(offset));
+        }
+        Ok(())
+    }
+
+    fn read_u8(&self, offset: u16) -> Result<u8, PciConfigError> {
+        self.check_access(offset, 1)?;
+        Ok((self.read_dword(offset & !3) >> ((offset & 3) * 8)) as u8)
+    }
+
+    fn read_u16(&self, offset: u16) -> Result<u16, PciConfigError> {
+        self.check_access(offset, 2)?;
+        Ok((self.read_dword(offset & !3) >> ((offset & 3) * 8)) as u16)
+    }
+
+    fn read_u32(&self, offset: u16) -> Result<u32, PciConfigError> {
+        self.check_access(offset, 4)?;
+        Ok(self.read_dword(offset))
+    }
+}
+
+/// # trait功能
+/// 可以按dword读写的配置空间，用于挂起时保存、恢复时写回配置空间
+pub trait PciConfigAccess: PciConfigSource {
+    /// 写入偏移为`offset`的dword，调用者保证`offset`按4对齐并且没有越界
+    fn write_dword(&self, offset: u16, value: u32);
+}
+
+impl<T: PciConfigSource + ?Sized> PciConfigSource for &T {
+    fn config_len(&self) -> u16 {
+        (**self).config_len()
+    }
+
+    fn read_dword(&self, offset: u16) -> u32 {
+        (**self).read_dword(offset)
+    }
+}
+
+/// 字节缓冲区形式的配置空间，超过4096字节的部分以及末尾不足一个dword的部分被忽略
+impl PciConfigSource for [u8] {
+    fn config_len(&self) -> u16 {
+        (self.len().min(PCI_CFG_SPACE_EXP_SIZE as usize) & !3) as u16
+    }
+
+    fn read_dword(&self, offset: u16) -> u32 {
+        let offset = offset as usize;
+        match self.get(offset..offset + 4) {
+            Some(b) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            None => u32::MAX,
+        }
+    }
+}
+
+/// 通过pci_root_0读取的设备配置空间的前256字节
+#[derive(Debug, Clone, Copy)]
+pub struct PciFunctionConfig {
+    bus_device_function: BusDeviceFunction,
+}
+
+impl PciFunctionConfig {
+    pub fn new(bus_device_function: BusDeviceFunction) -> Self {
+        Self {
+            bus_device_function,
+        }
+    }
+}
+
+impl PciConfigSource for PciFunctionConfig {
+    fn config_len(&self) -> u16 {
+        PCI_CFG_SPACE_SIZE
+    }
+
+    fn read_dword(&self, offset: u16) -> u32 {
+        pci_root_0().read_config(self.bus_device_function, offset)
+    }
+}
+
+impl PciConfigAccess for PciFunctionConfig {
+    fn write_dword(&self, offset: u16, value: u32) {
+        pci_root_0().write_config(self.bus_device_function, offset, value);
+    }
+}
+
+/// 通过指定的PciRoot读取的设备的扩展配置空间
+#[derive(Debug, Clone, Copy)]
+pub struct PciRootConfig<'a> {
+    root: &'a PciRoot,
+    bus_device_function: BusDeviceFunction,
+}
+
+impl<'a> PciRootConfig<'a> {
+    pub fn new(root: &'a PciRoot, bus_device_function: BusDeviceFunction) -> Self {
+        Self {
+            root,
+            bus_device_function,
+        }
+    }
+}
+
+impl PciConfigSource for PciRootConfig<'_> {
+    fn config_len(&self) -> u16 {
+        PCI_CFG_SPACE_EXP_SIZE
+    }
+
+    fn read_dword(&self, offset: u16) -> u32 {
+        self.root.read_config(self.bus_device_function, offset)
+    }
+}
+
+/// 记录capability链表中已经访问过的位置，每个dword一位
+#[derive(Debug, Clone)]
+struct PciCapVisited([u64; (PCI_CFG_SPACE_EXP_SIZE / 4 / 64) as usize]);
+
+impl PciCapVisited {
+    fn new() -> Self {
+        Self([0; (PCI_CFG_SPACE_EXP_SIZE / 4 / 64) as usize])
+    }
+
+    /// 标记`offset`已经访问过，返回之前是否访问过
+    fn test_and_set(&mut self, offset: u16) -> bool {
+        let index = (offset / 4) as usize;
+        let (word, bit) = (index / 64, index % 64);
+        let Some(w) = self.0.get_mut(word) else {
+            return true;
+        };
+        let visited = *w & (1 << bit) != 0;
+        *w |= 1 << bit;
+        visited
+    }
+}
+
+/// # 结构功能
+/// 遍历标准capability链表，每一项为`Ok(capability)`，遇到错误时给出`Err`并结束遍历
+#[derive(Debug, Clone)]
+pub struct PciCapabilityWalk<S: PciConfigSource> {
+    source: S,
+    next: Option<u16>,
+    visited: PciCapVisited,
+    ttl: usize,
+}
+
+impl<S: PciConfigSource> PciCapabilityWalk<S> {
+    /// # 函数的功能
+    /// 从`first`(配置空间0x34处的指针)开始遍历，`first`为None表示设备没有capability
+    pub fn new(source: S, first: Option<u8>) -> Self {
+        Self {
+            source,
+            next: first.filter(|p| *p != 0).map(u16::from),
+            visited: PciCapVisited::new(),
+            ttl: PCI_FIND_CAP_TTL,
+        }
+    }
+
+    fn read_next(&mut self, offset: u16) -> Result<CapabilityInfo, PciConfigError> {
+        // 指针的低两位是保留位，与pci_first_capability一样先去掉
+        let offset = offset & !3;
+        if offset < PCI_STD_CAP_START || offset >= PCI_CFG_SPACE_SIZE {
+            return Err(PciConfigError::InvalidCapabilityPointer(offset));
+        }
+        if self.ttl == 0 || self.visited.test_and_set(offset) {
+            return Err(PciConfigError::CapabilityLoop);
+        }
+        self.ttl -= 1;
+
+        let header = self.source.read_u32(offset)?;
+        let next = (header >> 8) as u8;
+        self.next = if next == 0 { None } else { Some(next.into()) };
+        Ok(CapabilityInfo {
+            offset: offset as u8,
+            id: header as u8,
+            private_header: (header >> 16) as u16,
+        })
+    }
+}
+
+impl<S: PciConfigSource> Iterator for PciCapabilityWalk<S> {
+    type Item = Result<CapabilityInfo, PciConfigError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.next.take()?;
+        Some(self.read_next(offset))
+    }
+}
+
+/// # 结构功能
+/// 遍历PCIe扩展capability链表，行为与[`PciCapabilityWalk`]相同
+#[derive(Debug, Clone)]
+pub struct PciExtCapabilityWalk<S: PciConfigSource> {
+    source: S,
+    next: Option<u16>,
+    visited: PciCapVisited,
+    ttl: usize,
+}
+
+impl<S: PciConfigSource> PciExtCapabilityWalk<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            next: Some(PCI_EXT_CAP_START),
+            visited: PciCapVisited::new(),
+            ttl: PCI_FIND_EXT_CAP_TTL,
+        }
+    }
+
+    fn read_next(&mut self, offset: u16) -> Option<Result<ExternalCapabilityInfo, PciConfigError>> {
+        let offset = offset & !3;
+        if offset < PCI_EXT_CAP_START {
+            return Some(Err(PciConfigError::InvalidCapabilityPointer(offset)));
+        }
+        if self.ttl == 0 || self.visited.test_and_set(offset) {
+            return Some(Err(PciConfigError::CapabilityLoop));
+        }
+        self.ttl -= 1;
+
+        let header = match self.source.read_u32(offset) {
+            Ok(header) => header,
+            Err(e) => return Some(Err(e)),
+        };
+        // 没有扩展capability的设备在0x100处读到0，不支持扩展配置空间的设备读到全1
+        if offset == PCI_EXT_CAP_START && (header == 0 || header == u32::MAX) {
+            return None;
+        }
+        let next = (header >> 20) as u16;
+        self.next = if next == 0 { None } else { Some(next) };
+        Some(Ok(ExternalCapabilityInfo {
+            offset,
+            id: header as u16,
+            capability_version: ((header >> 16) & 0xf) as u8,
+        }))
+    }
+}
+
+impl<S: PciConfigSource> Iterator for PciExtCapabilityWalk<S> {
+    type Item = Result<ExternalCapabilityInfo, PciConfigError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.next.take()?;
+        self.read_next(offset)
+    }
+}
+
+/// # 函数的功能
+/// 偏移为`offset`、长度为`len`字节的capability是否完整地位于配置空间的前256字节中
+///
+/// 访问capability中的字段之前需要先检查，否则`offset + 字段偏移`可能溢出
+pub fn pci_capability_fits(offset: u8, len: u16) -> bool {
+    u16::from(offset) + len <= PCI_CFG_SPACE_SIZE
+}
+
+/// # 函数的功能
+/// 根据MSI capability的Message Control计算capability的长度
+///
+/// 32位地址时数据位于偏移8，64位地址时位于偏移12；支持屏蔽时之后还有Mask Bits和Pending Bits
+pub fn pci_msi_capability_len(message_control: u16) -> u16 {
+    let address_64 = message_control & 0x0080 != 0;
+    let maskable = message_control & 0x0100 != 0;
+    let data_end = if address_64 { 16 } else { 12 };
+    if maskable {
+        data_end + 8
+    } else {
+        data_end
+    }
+}
+
+/// # 函数的功能
+/// 从配置空间读取并解析偏移为`offset`的MSI-X capability
+///
+/// ## 返回值
+/// - Err(PciConfigError::InvalidCapabilityPointer): capability超出了配置空间
+pub fn pci_read_msix_layout<S: PciConfigSource + ?Sized>(
+    source: &S,
+    offset: u8,
+) -> Result<PciMsixLayout, PciConfigError> {
+    if !pci_capability_fits(offset, PCI_MSIX_CAP_LEN) {
+        return Err(PciConfigError::InvalidCapabilityPointer(offset.into()));
+    }
+    let offset = u16::from(offset);
+    Ok(PciMsixLayout::parse([
+        source.read_u32(offset)?,
+        source.read_u32(offset + 4)?,
+        source.read_u32(offset + 8)?,
+    ]))
+}
+
+/// # 函数的功能
+/// 用任意内容的配置空间运行所有的解析函数，供模糊测试使用
+///
+/// `data`的前256字节(或者更长的扩展配置空间)作为配置空间，BAR的大小掩码取自配置空间之后的24字节(不足时为0)。
+/// 对于任何输入，这个函数都应当在有限的时间内返回，并且不会panic
+///
+/// ## 返回值
+/// 解析出的标准capability与扩展capability的数量
+pub fn pci_config_fuzz_one(data: &[u8]) -> (usize, usize) {
+    let cfg: &[u8] = data;
+    let first = cfg.read_u8(0x34).ok();
+    let caps: Vec<_> = PciCapabilityWalk::new(cfg, first)
+        .filter_map(Result::ok)
+        .collect();
+    for cap in caps.iter() {
+        match cap.id {
+            PCI_CAP_ID_MSIX => {
+                let _ = pci_read_msix_layout(cfg, cap.offset);
+            }
+            PCI_CAP_ID_MSI => {
+                let _ = pci_capability_fits(cap.offset, pci_msi_capability_len(cap.private_header));
+            }
+            _ => {}
+        }
+    }
+    let ext_caps = PciExtCapabilityWalk::new(cfg)
+        .take_while(Result::is_ok)
+        .count();
+
+    let mut orig = [0u32; 6];
+    let mut size_mask = [0u32; 6];
+    for i in 0..6u16 {
+        orig[i as usize] = cfg.read_u32(0x10 + i * 4).unwrap_or(0);
+        size_mask[i as usize] = data
+            .get(cfg.config_len() as usize..)
+            .and_then(|mask| mask.read_u32(i * 4).ok())
+            .unwrap_or(0);
+    }
+    if let Ok(bars) = pci_decode_bars(&orig, &size_mask) {
+        for bar in bars.iter().flatten() {
+            let _ = (bar.start(), bar.size(), bar.resource_flags());
+        }
+        if let Some(cap) = caps.iter().find(|c| c.id == PCI_CAP_ID_MSIX) {
+            if let Ok(layout) = pci_read_msix_layout(cfg, cap.offset) {
+                let _ = layout.validate(&bars);
+            }
+        }
+    }
+    (caps.len(), ext_caps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造一个256字节的配置空间，`caps`为(偏移, id, next)
+    fn config_with_caps(first: u8, caps: &[(u8, u8, u8)]) -> Vec<u8> {
+        let mut cfg = vec![0u8; 256];
+        cfg[0x34] = first;
+        for &(offset, id, next) in caps {
+            cfg[offset as usize] = id;
+            cfg[offset as usize + 1] = next;
+        }
+        cfg
+    }
+
+    fn walk(cfg: &[u8]) -> Vec<Result<CapabilityInfo, PciConfigError>> {
+        PciCapabilityWalk::new(cfg, cfg.read_u8(0x34).ok()).collect()
+    }
+
+    #[test]
+    fn test_checked_reads() {
+        let mut cfg = vec![0u8; 256];
+        cfg[0xfc..].copy_from_slice(&[0x11, 0x22, 0x33, 0x44]);
+        let cfg = cfg.as_slice();
+        assert_eq!(cfg.read_u32(0xfc), Ok(0x4433_2211));
+        assert_eq!(cfg.read_u16(0xfe), Ok(0x4433));
+        assert_eq!(cfg.read_u8(0xfd), Ok(0x22));
+        assert_eq!(cfg.read_u32(0x100), Err(PciConfigError::OutOfBounds(0x100)));
+        assert_eq!(
+            cfg.read_u8(0xffff),
+            Err(PciConfigError::OutOfBounds(0xffff))
+        );
+        assert_eq!(cfg.read_u32(0xfe), Err(PciConfigError::Misaligned(0xfe)));
+        assert_eq!(cfg.read_u16(0x41), Err(PciConfigError::Misaligned(0x41)));
+
+        // 末尾不足一个dword的部分不可读
+        let short = [0u8; 7];
+        assert_eq!(short.as_slice().config_len(), 4);
+        assert_eq!(
+            short.as_slice().read_u8(5),
+            Err(PciConfigError::OutOfBounds(5))
+        );
+    }
+
+    #[test]
+    fn test_walk_chain() {
+        let cfg = config_with_caps(0x40, &[(0x40, 0x01, 0x50), (0x50, 0x11, 0x00)]);
+        let caps: Vec<_> = walk(&cfg).into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(caps.len(), 2);
+        assert_eq!((caps[0].offset, caps[0].id), (0x40, 0x01));
+        assert_eq!((caps[1].offset, caps[1].id), (0x50, 0x11));
+
+        // 没有capability
+        let cfg = config_with_caps(0, &[]);
+        assert!(walk(&cfg).is_empty());
+    }
+
+    #[test]
+    fn test_walk_self_loop() {
+        // 指向自己的capability，修改之前会无限循环
+        let cfg = config_with_caps(0x40, &[(0x40, 0x09, 0x40)]);
+        let r = walk(&cfg);
+        assert_eq!(r.len(), 2);
+        assert!(r[0].is_ok());
+        assert_eq!(r[1], Err(PciConfigError::CapabilityLoop));
+    }
+
+    #[test]
+    fn test_walk_longer_loop() {
+        let cfg = config_with_caps(
+            0x40,
+            &[(0x40, 0x09, 0x80), (0x80, 0x09, 0xc0), (0xc0, 0x09, 0x80)],
+        );
+        let r = walk(&cfg);
+        assert_eq!(r.len(), 4);
+        assert_eq!(r[3], Err(PciConfigError::CapabilityLoop));
+    }
+
+    #[test]
+    fn test_walk_invalid_pointers() {
+        // 指向header
+        let cfg = config_with_caps(0x40, &[(0x40, 0x09, 0x10)]);
+        assert_eq!(
+            walk(&cfg)[1],
+            Err(PciConfigError::InvalidCapabilityPointer(0x10))
+        );
+        // 保留的低两位被忽略
+        let cfg = config_with_caps(0x43, &[(0x40, 0x09, 0x52), (0x50, 0x11, 0)]);
+        let caps: Vec<_> = walk(&cfg).into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!((caps[0].offset, caps[0].id), (0x40, 0x09));
+        assert_eq!((caps[1].offset, caps[1].id), (0x50, 0x11));
+        // 去掉低两位之后仍然指向header
+        let cfg = config_with_caps(0x3f, &[]);
+        assert_eq!(
+            walk(&cfg),
+            [Err(PciConfigError::InvalidCapabilityPointer(0x3c))]
+        );
+        // 配置空间被截断，capability头部不完整
+        let cfg = config_with_caps(0x40, &[(0x40, 0x09, 0xf0)]);
+        let r: Vec<_> = PciCapabilityWalk::new(&cfg[..0xf2], Some(0x40)).collect();
+        assert_eq!(r[1], Err(PciConfigError::OutOfBounds(0xf0)));
+    }
+
+    #[test]
+    fn test_ext_walk() {
+        let mut cfg = vec![0u8; 4096];
+        let mut put = |offset: usize, id: u16, next: u16| {
+            let header = u32::from(id) | (1 << 16) | (u32::from(next) << 20);
+            cfg[offset..offset + 4].copy_from_slice(&header.to_le_bytes());
+        };
+        put(0x100, 0x0001, 0x140);
+        put(0x140, 0x000b, 0x100);
+        let r: Vec<_> = PciExtCapabilityWalk::new(cfg.as_slice()).collect();
+        assert_eq!(r.len(), 3);
+        assert_eq!(r[1].unwrap().id, 0x000b);
+        assert_eq!(r[2], Err(PciConfigError::CapabilityLoop));
+
+        // 保留的低两位被忽略
+        let mut unaligned = vec![0u8; 4096];
+        unaligned[0x100..0x104]
+            .copy_from_slice(&(0x0001u32 | (1 << 16) | (0x142 << 20)).to_le_bytes());
+        unaligned[0x140..0x144].copy_from_slice(&(0x000bu32 | (1 << 16)).to_le_bytes());
+        let r: Vec<_> = PciExtCapabilityWalk::new(unaligned.as_slice()).collect();
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[1].unwrap().offset, 0x140);
+
+        // 只有256字节的配置空间
+        let r: Vec<_> = PciExtCapabilityWalk::new(&cfg[..256]).collect();
+        assert_eq!(r, [Err(PciConfigError::OutOfBounds(0x100))]);
+
+        // 没有扩展capability
+        let empty = vec![0u8; 4096];
+        assert_eq!(PciExtCapabilityWalk::new(empty.as_slice()).count(), 0);
+    }
+
+    #[test]
+    fn test_msix_at_end_of_config() {
+        // 位于0xf8的MSI-X capability放不下12字节，修改之前读取cap_offset + 8会溢出
+        let cfg = config_with_caps(0xf8, &[(0xf8, PCI_CAP_ID_MSIX, 0)]);
+        let caps: Vec<_> = walk(&cfg).into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(
+            pci_read_msix_layout(cfg.as_slice(), caps[0].offset),
+            Err(PciConfigError::InvalidCapabilityPointer(0xf8))
+        );
+        assert!(pci_read_msix_layout(cfg.as_slice(), 0xf4).is_ok());
+
+        assert_eq!(pci_msi_capability_len(0x0000), 12);
+        assert_eq!(pci_msi_capability_len(0x0180), 24);
+        assert!(!pci_capability_fits(0xf0, pci_msi_capability_len(0x0180)));
+        assert!(pci_capability_fits(0xf4, pci_msi_capability_len(0x0000)));
+    }
+
+    #[test]
+    fn test_fuzz_inputs() {
+        // 全1、全0以及伪随机内容的配置空间都不会panic或者死循环
+        assert_eq!(pci_config_fuzz_one(&[0xff; 4096 + 24]).1, 0);
+        assert_eq!(pci_config_fuzz_one(&[0; 256]), (0, 0));
+        assert_eq!(pci_config_fuzz_one(&[]), (0, 0));
+
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for len in [3usize, 64, 255, 256, 280, 4096, 4120] {
+            for _ in 0..64 {
+                let data: Vec<u8> = (0..len)
+                    .map(|_| {
+                        seed ^= seed << 13;
+                        seed ^= seed >> 7;
+                        seed ^= seed << 17;
+                        seed as u8
+                    })
+                    .collect();
+                let (caps, ext_caps) = pci_config_fuzz_one(&data);
+                assert!(caps <= PCI_FIND_CAP_TTL);
+                assert!(ext_caps <= PCI_FIND_EXT_CAP_TTL);
+            }
+        }
+    }
+}
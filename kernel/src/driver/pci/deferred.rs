@@ -0,0 +1,130 @@
+//! 延迟添加pci设备
+//!
+//! 默认情况下，枚举时发现的设备会立即通过[`PciDeviceManager::device_add`]加入设备模型，
+//! 匹配驱动以及probe都在枚举的过程中串行完成。使用内核命令行参数`pci_async_probe`之后，
+//! 枚举只把设备放入队列，由内核线程`pci_deferred_add`在枚举之后添加它们。
+//!
+//! 设备总是先完整地添加(包括sysfs目录)，然后才会被绑定，这与同步添加相同。
+
+use alloc::{collections::VecDeque, string::ToString, sync::Arc};
+use log::error;
+use system_error::SystemError;
+use unified_init::macros::unified_init;
+
+use crate::{
+    init::initcall::INITCALL_LATE,
+    libs::{mutex::Mutex, spinlock::SpinLock, wait_queue::WaitQueue},
+    process::kthread::{KernelThreadClosure, KernelThreadMechanism},
+};
+
+use super::device::{pci_device_manager, PciDevice, PciDeviceManager};
+
+kernel_cmdline_param_arg!(PCI_ASYNC_PROBE_PARAM, pci_async_probe, false, false);
+
+lazy_static! {
+    static ref PCI_DEFERRED_ADDS: PciDeferredAdds = PciDeferredAdds::new();
+}
+
+/// 枚举时是否延迟添加设备
+pub fn pci_async_probe_enabled() -> bool {
+    PCI_ASYNC_PROBE_PARAM.value_bool().unwrap_or(false)
+}
+
+/// # 结构功能
+/// 等待添加的pci设备
+struct PciDeferredAdds {
+    queue: SpinLock<VecDeque<Arc<dyn PciDevice>>>,
+    /// 添加设备的过程中持有，保证flush返回时之前排队的设备都已经添加完成
+    adding: Mutex<()>,
+    wait_queue: WaitQueue,
+}
+
+impl PciDeferredAdds {
+    fn new() -> Self {
+        Self {
+            queue: SpinLock::new(VecDeque::new()),
+            adding: Mutex::new(()),
+            wait_queue: WaitQueue::default(),
+        }
+    }
+
+    fn push(&self, pci_dev: Arc<dyn PciDevice>) {
+        self.queue.lock().push_back(pci_dev);
+        self.wait_queue.wakeup(None);
+    }
+
+    fn pending(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    /// 添加队列中所有的设备，返回添加的设备数量
+    fn drain(&self) -> usize {
+        let _adding = self.adding.lock();
+        let mut count = 0;
+        loop {
+            // 添加设备时不能持有队列的锁，probe可能会再向队列中加入设备
+            let Some(pci_dev) = self.queue.lock().pop_front() else {
+                break;
+            };
+            if let Err(e) = pci_device_manager().device_add(pci_dev.clone()) {
+                error!(
+                    "pci: deferred add of device '{}' failed: {:?}",
+                    pci_dev.name(),
+                    e
+                );
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// 等待队列中有新的设备
+    fn wait_for_work(&self) {
+        let queue = self.queue.lock();
+        if queue.is_empty() {
+            self.wait_queue.sleep_unlock_spinlock(queue);
+        }
+    }
+}
+
+impl PciDeviceManager {
+    /// # 函数的功能
+    /// 把pci设备放入延迟添加的队列，由内核线程调用[`PciDeviceManager::device_add`]添加
+    ///
+    /// ## 参数：
+    /// - 'pci_dev':需要添加的pci设备
+    pub fn device_add_deferred(&self, pci_dev: Arc<dyn PciDevice>) {
+        PCI_DEFERRED_ADDS.push(pci_dev);
+    }
+
+    /// # 函数的功能
+    /// 立即添加所有排队的设备，返回时之前排队的设备都已经添加完成
+    ///
+    /// ## 返回值
+    /// 由本次调用添加的设备数量
+    pub fn flush_deferred_adds(&self) -> usize {
+        PCI_DEFERRED_ADDS.drain()
+    }
+
+    /// 等待添加的设备数量
+    #[allow(dead_code)]
+    pub fn deferred_adds_pending(&self) -> usize {
+        PCI_DEFERRED_ADDS.pending()
+    }
+}
+
+#[unified_init(INITCALL_LATE)]
+fn pci_deferred_add_init() -> Result<(), SystemError> {
+    let closure =
+        KernelThreadClosure::StaticEmptyClosure((&(pci_deferred_add_thread as fn() -> i32), ()));
+    KernelThreadMechanism::create_and_run(closure, "pci_deferred_add".to_string())
+        .ok_or(SystemError::EPERM)?;
+    return Ok(());
+}
+
+fn pci_deferred_add_thread() -> i32 {
+    loop {
+        PCI_DEFERRED_ADDS.drain();
+        PCI_DEFERRED_ADDS.wait_for_work();
+    }
+}
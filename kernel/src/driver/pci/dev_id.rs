@@ -10,6 +10,9 @@ const PCI_ANY_ID: u32 = 0xffff_ffff;
 pub struct PciDeviceID {
     vendor: u32,
     device_id: u32,
+    /// 匹配的设备ID范围的上界(包含)，匹配单个设备ID时与`device_id`相同。
+    /// `device_id`为PCI_ANY_ID时不使用
+    device_id_last: u32,
     subvendor: u32,
     subdevice: u32,
     class: u32,
@@ -28,10 +31,69 @@ impl PciDeviceID {
         self.special_data = Some(data);
     }
 
+    /// 创建一个只匹配指定vendor/device的ID，其余字段匹配任意值
+    pub const fn new(vendor: u16, device_id: u16) -> Self {
+        return Self::new_range(vendor, device_id, device_id);
+    }
+
+    /// # 函数的功能
+    /// 创建一个匹配指定vendor下一段连续的设备ID的ID，其余字段匹配任意值
+    ///
+    /// 例如virtio的transitional设备使用0x1000~0x103f，用一项就可以匹配全部
+    ///
+    /// ## 参数
+    /// - `first`: 范围的下界(包含)
+    /// - `last`: 范围的上界(包含)，不能小于`first`
+    pub const fn new_range(vendor: u16, first: u16, last: u16) -> Self {
+        assert!(first <= last);
+        return Self {
+            vendor: vendor as u32,
+            device_id: first as u32,
+            device_id_last: last as u32,
+            subvendor: PCI_ANY_ID,
+            subdevice: PCI_ANY_ID,
+            class: 0,
+            class_mask: 0,
+            _driver_data: 0,
+            _override_only: PCI_ANY_ID,
+            special_data: None,
+        };
+    }
+
+    /// # 函数的功能
+    /// 创建一个vendor/device可以为通配符的ID，其余字段匹配任意值
+    ///
+    /// ## 参数
+    /// - `vendor`: 厂商号，None表示匹配任意厂商
+    /// - `device_id`: 设备号，None表示匹配任意设备
+    pub const fn new_wildcard(vendor: Option<u16>, device_id: Option<u16>) -> Self {
+        let vendor = match vendor {
+            Some(vendor) => vendor as u32,
+            None => PCI_ANY_ID,
+        };
+        let device_id = match device_id {
+            Some(device_id) => device_id as u32,
+            None => PCI_ANY_ID,
+        };
+        return Self {
+            vendor,
+            device_id,
+            device_id_last: device_id,
+            subvendor: PCI_ANY_ID,
+            subdevice: PCI_ANY_ID,
+            class: 0,
+            class_mask: 0,
+            _driver_data: 0,
+            _override_only: PCI_ANY_ID,
+            special_data: None,
+        };
+    }
+
     pub fn dummpy() -> Self {
         return Self {
             vendor: PCI_ANY_ID,
             device_id: PCI_ANY_ID,
+            device_id_last: PCI_ANY_ID,
             subvendor: PCI_ANY_ID,
             subdevice: PCI_ANY_ID,
             class: PCI_ANY_ID,
@@ -56,7 +118,7 @@ impl PciDeviceID {
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/pci.h?fi=pci_match_one_device#195
     pub fn general_match(&self, id: PciDeviceID) -> bool {
         if (self.vendor == id.vendor() || self.vendor == PCI_ANY_ID)
-            && (self.device_id == id.device_id() || self.device_id == PCI_ANY_ID)
+            && self.device_id_check(id.device_id())
             && (self.subvendor == id.subvendor() || self.subvendor == PCI_ANY_ID)
             && (self.subdevice == id.subdevice() || self.subdevice == PCI_ANY_ID)
             && self.class_check(&id)
@@ -66,6 +128,14 @@ impl PciDeviceID {
         return false;
     }
 
+    /// 设备ID是否在本ID匹配的范围内
+    pub fn device_id_check(&self, device_id: u32) -> bool {
+        if self.device_id == PCI_ANY_ID {
+            return true;
+        }
+        return (self.device_id..=self.device_id_last).contains(&device_id);
+    }
+
     pub fn class_check(&self, id: &Self) -> bool {
         return (self.class ^ id.class()) & self.class_mask == 0;
     }
@@ -78,6 +148,12 @@ impl PciDeviceID {
         self.device_id
     }
 
+    /// 匹配的设备ID范围的上界(包含)
+    #[allow(dead_code)]
+    pub fn device_id_last(&self) -> u32 {
+        self.device_id_last
+    }
+
     pub fn subvendor(&self) -> u32 {
         self.subvendor
     }
@@ -107,3 +183,45 @@ impl PciSpecifiedData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_match() {
+        let id = PciDeviceID::new(0x1af4, 0x1001);
+        assert!(id.general_match(PciDeviceID::new(0x1af4, 0x1001)));
+        assert!(!id.general_match(PciDeviceID::new(0x1af4, 0x1000)));
+        assert!(!id.general_match(PciDeviceID::new(0x1af4, 0x1002)));
+        assert!(!id.general_match(PciDeviceID::new(0x8086, 0x1001)));
+    }
+
+    #[test]
+    fn test_range_match() {
+        let id = PciDeviceID::new_range(0x1af4, 0x1000, 0x103f);
+        for device in [0x1000, 0x1001, 0x1020, 0x103f] {
+            assert!(
+                id.general_match(PciDeviceID::new(0x1af4, device)),
+                "{:#x}",
+                device
+            );
+        }
+        for device in [0x0fff, 0x1040, 0x107f, 0xffff] {
+            assert!(
+                !id.general_match(PciDeviceID::new(0x1af4, device)),
+                "{:#x}",
+                device
+            );
+        }
+        // 范围只作用于设备ID
+        assert!(!id.general_match(PciDeviceID::new(0x8086, 0x1000)));
+    }
+
+    #[test]
+    fn test_any_match() {
+        let id = PciDeviceID::dummpy();
+        assert!(id.device_id_check(0));
+        assert!(id.device_id_check(0xffff));
+    }
+}
@@ -1,7 +1,12 @@
 use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
     string::{String, ToString},
     sync::{Arc, Weak},
+    vec::Vec,
 };
+use intertrait::cast::CastArc;
+use log::warn;
 use system_error::SystemError;
 
 use crate::{
@@ -20,10 +25,19 @@ use crate::{
 };
 
 use super::{
+    address::PciAddress,
+    config_space::{PciConfigAccess, PciFunctionConfig},
     dev_id::PciDeviceID,
+    pci::BusDeviceFunction,
+    pm::PciPmState,
+    stats::PciMatchStats,
     subsys::{pci_bus, pci_bus_device},
 };
 
+/// 已经加入设备模型的pci设备，按地址索引，用于拒绝同一个地址上的设备被重复注册
+static PCI_DEVICE_ADDRESSES: SpinLock<BTreeMap<PciAddress, Weak<dyn PciDevice>>> =
+    SpinLock::new(BTreeMap::new());
+
 /// # 结构功能
 /// 该结构为Pci设备的管理器，使用该结构可以将pci设备添加到sysfs中
 pub struct PciDeviceManager;
@@ -41,27 +55,149 @@ impl PciDeviceManager {
     ///
     /// ## 返回值：
     /// - OK(()) :表示成功
+    /// - Err(SystemError::EEXIST) :该地址上已经注册了设备(例如重新扫描与枚举发生了竞争)，或者设备已经注册
     /// - Err(e) :失败原因
     pub fn device_add(&self, pci_dev: Arc<dyn PciDevice>) -> Result<(), SystemError> {
-        // pci设备一般放置在/sys/device/pci:xxxx下
+        let address = self.claim_address(&pci_dev)?;
+        let r = self.do_device_add(pci_dev.clone());
+        if r.is_err() {
+            if let Some(address) = address {
+                PCI_DEVICE_ADDRESSES.lock_irqsave().remove(&address);
+            }
+        }
+        r
+    }
+
+    fn do_device_add(&self, pci_dev: Arc<dyn PciDevice>) -> Result<(), SystemError> {
+        // pci设备一般放置在/sys/device/pci:xxxx下，桥下游的设备放置在桥的目录下
         if pci_dev.dev_parent().is_none() {
-            pci_dev.set_dev_parent(Some(Arc::downgrade(&(pci_bus_device() as Arc<dyn Device>))));
+            let parent = match self.upstream_bridge(&pci_dev) {
+                Some(bridge) => bridge as Arc<dyn Device>,
+                None => pci_bus_device() as Arc<dyn Device>,
+            };
+            pci_dev.set_dev_parent(Some(Arc::downgrade(&parent)));
         }
         // 设置设备的总线
         pci_dev.set_bus(Some(Arc::downgrade(&(pci_bus() as Arc<dyn Bus>))));
         // 对设备进行默认的初始化
         device_manager().device_default_initialize(&(pci_dev.clone() as Arc<dyn Device>));
+        if let Some(stats) = pci_dev.match_stats() {
+            stats.reset();
+        }
         // 使用设备管理器注册设备，当设备被注册后，会根据它的总线字段，在对应的总线上扫描驱动，并尝试进行匹配
         let r = device_manager().add_device(pci_dev.clone() as Arc<dyn Device>);
 
         if r.is_ok() {
             //todo:这里可能还要处理一些设置成功后设备状态的变化
+            pci_bus().notify_device_add(&pci_dev);
             return Ok(());
         } else {
             //todo:这里可能有一些添加失败的处理
             return r;
         }
     }
+
+    /// # 函数的功能
+    /// 将pci设备从sysfs中移除（与device_add相反）
+    ///
+    /// 先通知总线上的通知接收者，然后解除设备与驱动的绑定，并删除设备在sysfs中的目录。
+    /// 移除桥时，先移除它下游的设备
+    ///
+    /// ## 参数：
+    /// - 'pci_dev':需要移除的pci设备
+    #[allow(dead_code)]
+    pub fn device_remove(&self, pci_dev: &Arc<dyn PciDevice>) {
+        for child in self.children(pci_dev) {
+            self.device_remove(&child);
+        }
+        pci_bus().notify_device_remove(pci_dev);
+        device_manager().remove(&(pci_dev.clone() as Arc<dyn Device>));
+        self.release_address(pci_dev);
+    }
+
+    /// # 函数的功能
+    /// 查找已经注册在`address`上的设备
+    pub fn find_by_address(&self, address: PciAddress) -> Option<Arc<dyn PciDevice>> {
+        PCI_DEVICE_ADDRESSES
+            .lock_irqsave()
+            .get(&address)
+            .and_then(|dev| dev.upgrade())
+    }
+
+    /// # 函数的功能
+    /// 占用设备的地址，不对应真实的PCI function的设备不占用地址
+    ///
+    /// ## 返回值
+    /// - Ok(Some(address)): 占用的地址
+    /// - Err(SystemError::EEXIST): 该地址上已经有设备
+    fn claim_address(
+        &self,
+        pci_dev: &Arc<dyn PciDevice>,
+    ) -> Result<Option<PciAddress>, SystemError> {
+        let Some(bdf) = pci_dev.bus_device_function() else {
+            return Ok(None);
+        };
+        let address = PciAddress::from(bdf);
+        let mut addresses = PCI_DEVICE_ADDRESSES.lock_irqsave();
+        if let Some(existing) = addresses.get(&address).and_then(|dev| dev.upgrade()) {
+            warn!(
+                "pci {}: device '{}' is already registered, refusing to add '{}'",
+                address,
+                existing.name(),
+                pci_dev.name()
+            );
+            return Err(SystemError::EEXIST);
+        }
+        addresses.insert(address, Arc::downgrade(pci_dev));
+        Ok(Some(address))
+    }
+
+    /// 释放设备占用的地址，地址已经被其他设备占用时不做任何事
+    fn release_address(&self, pci_dev: &Arc<dyn PciDevice>) {
+        let Some(bdf) = pci_dev.bus_device_function() else {
+            return;
+        };
+        let address = PciAddress::from(bdf);
+        let weak = Arc::downgrade(pci_dev);
+        let mut addresses = PCI_DEVICE_ADDRESSES.lock_irqsave();
+        if addresses
+            .get(&address)
+            .is_some_and(|dev| Weak::ptr_eq(dev, &weak))
+        {
+            addresses.remove(&address);
+        }
+    }
+
+    /// # 函数的功能
+    /// 查找设备上游的桥，即下游总线号与设备所在的总线相同的桥
+    ///
+    /// ## 返回值
+    /// - None: 设备位于根总线上、不对应真实的PCI function，或者它上游的桥还没有加入设备模型
+    pub fn upstream_bridge(&self, pci_dev: &Arc<dyn PciDevice>) -> Option<Arc<dyn PciDevice>> {
+        let bus = pci_dev.bus_device_function()?.bus;
+        pci_bus()
+            .subsystem()
+            .devices()
+            .iter()
+            .filter_map(|dev| dev.clone().cast::<dyn PciDevice>().ok())
+            .find(|dev| dev.secondary_bus() == Some(bus))
+    }
+
+    /// 以`pci_dev`为父设备的pci设备
+    fn children(&self, pci_dev: &Arc<dyn PciDevice>) -> Vec<Arc<dyn PciDevice>> {
+        let parent = pci_dev.clone() as Arc<dyn Device>;
+        pci_bus()
+            .subsystem()
+            .devices()
+            .iter()
+            .filter(|dev| {
+                dev.dev_parent()
+                    .and_then(|p| p.upgrade())
+                    .is_some_and(|p| Arc::ptr_eq(&p, &parent))
+            })
+            .filter_map(|dev| dev.clone().cast::<dyn PciDevice>().ok())
+            .collect()
+    }
 }
 
 /// #trait功能
@@ -83,6 +219,52 @@ pub trait PciDevice: Device {
     fn device_id(&self) -> u16;
     fn subsystem_vendor(&self) -> u16;
     fn subsystem_device(&self) -> u16;
+
+    /// # 函数的功能
+    /// 返回本设备在PCI总线上的地址
+    ///
+    /// ## 返回值
+    /// - None :该设备不对应真实的PCI function（例如测试设备）
+    fn bus_device_function(&self) -> Option<BusDeviceFunction> {
+        None
+    }
+
+    /// # 函数的功能
+    /// 返回本设备在驱动匹配过程中的统计信息
+    ///
+    /// ## 返回值
+    /// - None :该设备不记录匹配统计
+    fn match_stats(&self) -> Option<&PciMatchStats> {
+        None
+    }
+
+    /// # 函数的功能
+    /// 返回PCI-PCI桥的下游总线号，下游总线上的设备以这个桥为父设备
+    ///
+    /// ## 返回值
+    /// - None :该设备不是桥
+    fn secondary_bus(&self) -> Option<u8> {
+        None
+    }
+
+    /// # 函数的功能
+    /// 返回本设备挂起时保存配置空间的位置
+    ///
+    /// ## 返回值
+    /// - None :挂起时不保存本设备的配置空间
+    fn pm_state(&self) -> Option<&PciPmState> {
+        None
+    }
+
+    /// # 函数的功能
+    /// 返回访问本设备配置空间的接口，默认通过设备的地址访问
+    ///
+    /// ## 返回值
+    /// - None :该设备没有可以访问的配置空间
+    fn config_space(&self) -> Option<Box<dyn PciConfigAccess>> {
+        self.bus_device_function()
+            .map(|bdf| Box::new(PciFunctionConfig::new(bdf)) as Box<dyn PciConfigAccess>)
+    }
 }
 
 /// #结构功能
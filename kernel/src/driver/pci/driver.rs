@@ -1,12 +1,10 @@
 use alloc::{sync::Arc, vec::Vec};
+use log::warn;
 use system_error::SystemError;
 
-use crate::driver::base::device::{
-    bus::Bus,
-    driver::{driver_manager, Driver},
-};
+use crate::driver::base::device::driver::Driver;
 
-use super::{dev_id::PciDeviceID, device::PciDevice, subsys::pci_bus};
+use super::{dev_id::PciDeviceID, device::PciDevice, stats::PciDriverProbeStats, subsys::pci_bus};
 
 /// # trait功能
 /// Pci驱动应该实现的trait
@@ -63,22 +61,69 @@ pub trait PciDriver: Driver {
         }
         return None;
     }
+
+    /// # 函数的功能
+    /// 返回本驱动probe成功/失败的统计信息
+    ///
+    /// ## 返回值
+    /// - None :该驱动不记录probe统计
+    fn probe_stats(&self) -> Option<&PciDriverProbeStats> {
+        None
+    }
 }
 
+#[allow(dead_code)]
 pub struct PciDriverManager;
 
+#[allow(dead_code)]
 pub fn pci_driver_manager() -> &'static PciDriverManager {
     &PciDriverManager
 }
 
 impl PciDriverManager {
+    #[allow(dead_code)]
     pub fn register(&self, driver: Arc<dyn PciDriver>) -> Result<(), SystemError> {
-        driver.set_bus(Some(Arc::downgrade(&(pci_bus() as Arc<dyn Bus>))));
-        return driver_manager().register(driver as Arc<dyn Driver>);
+        return register_pci_driver(driver);
     }
 
     #[allow(dead_code)]
     pub fn unregister(&self, driver: &Arc<dyn PciDriver>) {
-        driver_manager().unregister(&(driver.clone() as Arc<dyn Driver>));
+        if let Err(e) = unregister_pci_driver(driver) {
+            warn!(
+                "PciDriverManager::unregister(): failed to unregister driver '{}': {:?}",
+                driver.name(),
+                e
+            );
+        }
     }
 }
+
+/// # 函数的功能
+/// 把一个Pci驱动注册到Pci总线上
+///
+/// 这是驱动模块注册Pci驱动的入口，驱动只需要实现`PciDriver`并填好自己支持的ID，
+/// 注册之后总线会用它去匹配已经存在的设备，之后加入的设备也会和它匹配，
+/// 驱动不需要关心设备是怎么被枚举出来的
+///
+/// ## 参数
+/// - `driver`: 要注册的驱动
+///
+/// ## 返回值
+/// - Ok(()): 注册成功（单个设备probe失败不会导致注册失败）
+/// - Err(e): 驱动注册失败
+pub fn register_pci_driver(driver: Arc<dyn PciDriver>) -> Result<(), SystemError> {
+    return pci_bus().driver_register(driver);
+}
+
+/// # 函数的功能
+/// 把一个Pci驱动从Pci总线上注销，它绑定的设备都会先被解除绑定
+///
+/// ## 参数
+/// - `driver`: 之前通过`register_pci_driver`注册的驱动
+///
+/// ## 返回值
+/// - Ok(()): 注销成功
+/// - Err(SystemError::ENODEV): 驱动没有注册在Pci总线上
+pub fn unregister_pci_driver(driver: &Arc<dyn PciDriver>) -> Result<(), SystemError> {
+    return pci_bus().driver_unregister(driver);
+}
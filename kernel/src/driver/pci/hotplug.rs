@@ -0,0 +1,59 @@
+//! 检测pci设备是否已被拔出（surprise removal）
+//!
+//! 设备被拔出后，对其配置空间以及BAR的读取都会返回全1。
+//! 驱动可以使用这里的函数来判断设备是否还存在，从而避免继续访问已经失效的MMIO区域。
+
+use alloc::sync::Arc;
+use intertrait::cast::CastArc;
+
+use crate::driver::base::device::Device;
+
+use super::{device::PciDevice, pci::BusDeviceFunction, root::pci_root_0};
+
+/// 判断一次pci读取的结果是否表示设备已经不存在
+///
+/// 设备被拔出后，总线会以全1响应所有读取
+#[inline]
+pub fn pci_read_is_all_ones(value: u32) -> bool {
+    value == u32::MAX
+}
+
+/// # 函数的功能
+/// 通过读取vendor id，判断指定地址上的pci设备是否存在
+///
+/// ## 参数
+/// - `bdf`: 设备在pci总线上的地址
+///
+/// ## 返回值
+/// - true: 设备存在
+/// - false: 设备已经被拔出
+pub fn pci_device_is_present(bdf: BusDeviceFunction) -> bool {
+    let id = pci_root_0().read_config(bdf, 0x00);
+    if pci_read_is_all_ones(id) {
+        return false;
+    }
+    (id & 0xffff) as u16 != 0xffff
+}
+
+/// # 函数的功能
+/// 判断设备（或者它所在的pci设备）是否已经被拔出
+///
+/// 会沿着设备的父设备向上查找，直到找到一个pci设备为止，
+/// 因此virtio等挂在pci设备下面的设备也可以直接使用这个函数
+///
+/// ## 返回值
+/// - true: 设备所在的pci设备已经不存在
+/// - false: 设备存在，或者它不在pci总线上
+pub fn pci_device_gone(dev: &Arc<dyn Device>) -> bool {
+    let mut cur = Some(dev.clone());
+    while let Some(d) = cur {
+        if let Ok(pci_dev) = d.clone().cast::<dyn PciDevice>() {
+            return match pci_dev.bus_device_function() {
+                Some(bdf) => !pci_device_is_present(bdf),
+                None => false,
+            };
+        }
+        cur = d.dev_parent().and_then(|p| p.upgrade());
+    }
+    false
+}
@@ -0,0 +1,153 @@
+//! pci厂商、设备名称的查询表
+//!
+//! 表项的格式参考 pci.ids（https://pci-ids.ucw.cz/），只收录了常见的（主要是虚拟化平台上的）设备。
+//! 查询表只在开启`pci-ids`特性时编译进内核，否则所有的查询都返回None，调用者应当回退到十六进制的id。
+
+use alloc::{format, string::String};
+
+/// 厂商名称表，按厂商id升序排列
+#[cfg(feature = "pci-ids")]
+static PCI_VENDORS: &[(u16, &str)] = &[
+    (0x1002, "Advanced Micro Devices, Inc. [AMD/ATI]"),
+    (0x1022, "Advanced Micro Devices, Inc. [AMD]"),
+    (0x10de, "NVIDIA Corporation"),
+    (0x10ec, "Realtek Semiconductor Co., Ltd."),
+    (0x1234, "Technical Corp."),
+    (0x15ad, "VMware"),
+    (0x1af4, "Red Hat, Inc."),
+    (0x1b36, "Red Hat, Inc."),
+    (0x8086, "Intel Corporation"),
+];
+
+/// 设备名称表，按(厂商id, 设备id)升序排列
+#[cfg(feature = "pci-ids")]
+static PCI_DEVICES: &[(u16, u16, &str)] = &[
+    (
+        0x10ec,
+        0x8139,
+        "RTL-8100/8101L/8139 PCI Fast Ethernet Adapter",
+    ),
+    (0x1234, 0x1111, "QEMU Virtual Video Controller"),
+    (0x15ad, 0x0405, "SVGA II Adapter"),
+    (0x1af4, 0x1000, "Virtio network device"),
+    (0x1af4, 0x1001, "Virtio block device"),
+    (0x1af4, 0x1002, "Virtio memory balloon"),
+    (0x1af4, 0x1003, "Virtio console"),
+    (0x1af4, 0x1004, "Virtio SCSI"),
+    (0x1af4, 0x1005, "Virtio RNG"),
+    (0x1af4, 0x1009, "Virtio filesystem"),
+    (0x1af4, 0x1041, "Virtio 1.0 network device"),
+    (0x1af4, 0x1042, "Virtio 1.0 block device"),
+    (0x1af4, 0x1043, "Virtio 1.0 console"),
+    (0x1af4, 0x1044, "Virtio 1.0 RNG"),
+    (0x1af4, 0x1045, "Virtio 1.0 balloon"),
+    (0x1af4, 0x1048, "Virtio 1.0 SCSI"),
+    (0x1af4, 0x1049, "Virtio 1.0 filesystem"),
+    (0x1af4, 0x1050, "Virtio 1.0 GPU"),
+    (0x1af4, 0x1052, "Virtio 1.0 input"),
+    (0x1b36, 0x0001, "QEMU PCI-PCI bridge"),
+    (0x1b36, 0x0008, "QEMU PCIe Host bridge"),
+    (0x1b36, 0x000c, "QEMU PCIe Root port"),
+    (0x1b36, 0x000d, "QEMU XHCI Host Controller"),
+    (0x1b36, 0x0010, "QEMU NVM Express Controller"),
+    (0x8086, 0x100e, "82540EM Gigabit Ethernet Controller"),
+    (0x8086, 0x10d3, "82574L Gigabit Network Connection"),
+    (0x8086, 0x1237, "440FX - 82441FX PMC [Natoma]"),
+    (0x8086, 0x2918, "82801IB (ICH9) LPC Interface Controller"),
+    (
+        0x8086,
+        0x2922,
+        "82801IR/IO/IH (ICH9R/DO/DH) 6 port SATA Controller [AHCI mode]",
+    ),
+    (0x8086, 0x2930, "82801I (ICH9 Family) SMBus Controller"),
+    (0x8086, 0x29c0, "82G33/G31/P35/P31 Express DRAM Controller"),
+    (0x8086, 0x7000, "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    (0x8086, 0x7010, "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    (0x8086, 0x7113, "82371AB/EB/MB PIIX4 ACPI"),
+];
+
+/// # 函数的功能
+/// 查询厂商的名称
+///
+/// ## 返回值
+/// - Some(name): 厂商名称
+/// - None: 未知的厂商，或者没有开启`pci-ids`特性
+#[allow(unused_variables)]
+pub fn pci_vendor_name(vendor: u16) -> Option<&'static str> {
+    #[cfg(feature = "pci-ids")]
+    {
+        if let Ok(i) = PCI_VENDORS.binary_search_by_key(&vendor, |(v, _)| *v) {
+            return Some(PCI_VENDORS[i].1);
+        }
+    }
+    None
+}
+
+/// # 函数的功能
+/// 查询设备的名称（不包含厂商名称）
+///
+/// ## 返回值
+/// - Some(name): 设备名称
+/// - None: 未知的设备，或者没有开启`pci-ids`特性
+#[allow(unused_variables)]
+pub fn pci_device_name(vendor: u16, device: u16) -> Option<&'static str> {
+    #[cfg(feature = "pci-ids")]
+    {
+        if let Ok(i) = PCI_DEVICES.binary_search_by_key(&(vendor, device), |(v, d, _)| (*v, *d)) {
+            return Some(PCI_DEVICES[i].2);
+        }
+    }
+    None
+}
+
+/// # 函数的功能
+/// 生成设备的可读描述，例如"Red Hat, Inc. Virtio network device"
+///
+/// 查询不到名称的部分使用十六进制的id代替：
+/// - 厂商和设备都未知: "1af4:1000"
+/// - 只有厂商已知: "Red Hat, Inc. Device 1000"
+pub fn pci_device_description(vendor: u16, device: u16) -> String {
+    match (pci_vendor_name(vendor), pci_device_name(vendor, device)) {
+        (Some(v), Some(d)) => format!("{} {}", v, d),
+        (Some(v), None) => format!("{} Device {:04x}", v, device),
+        _ => format!("{:04x}:{:04x}", vendor, device),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "pci-ids")]
+    #[test]
+    fn test_tables_sorted() {
+        assert!(PCI_VENDORS.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(PCI_DEVICES
+            .windows(2)
+            .all(|w| (w[0].0, w[0].1) < (w[1].0, w[1].1)));
+    }
+
+    #[cfg(feature = "pci-ids")]
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            pci_device_name(0x1af4, 0x1000),
+            Some("Virtio network device")
+        );
+        assert_eq!(
+            pci_device_description(0x1af4, 0x1000),
+            "Red Hat, Inc. Virtio network device"
+        );
+        assert_eq!(
+            pci_device_description(0x1af4, 0xffff),
+            "Red Hat, Inc. Device ffff"
+        );
+    }
+
+    #[test]
+    fn test_unknown_falls_back_to_hex() {
+        assert_eq!(pci_vendor_name(0xffff), None);
+        assert_eq!(pci_device_name(0xffff, 0x0001), None);
+        assert_eq!(pci_device_description(0xffff, 0x0001), "ffff:0001");
+    }
+}
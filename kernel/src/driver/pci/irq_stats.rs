@@ -0,0 +1,119 @@
+//! PCI设备每个中断向量的中断次数
+//!
+//! 计数由中断处理流程维护(见[`crate::exception::irqdesc::IrqDesc::kstat_irqs`])，这里按照设备的中断向量汇总，
+//! 通过设备的`msi_irqs`目录输出，用于诊断中断风暴或者丢失的中断。
+
+use alloc::{format, string::String, vec::Vec};
+use system_error::SystemError;
+
+use crate::exception::{irqdesc::irq_desc_manager, IrqNumber};
+
+use super::pci::{BusDeviceFunction, PCI_DEVICE_LINKEDLIST};
+
+/// 一个中断向量的中断次数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciIrqVectorStat {
+    /// 中断在设备中断向量中的位置(MSI-X表项号)
+    pub index: u16,
+    pub irq: IrqNumber,
+    pub count: u64,
+}
+
+/// # 函数的功能
+/// 统计每个中断向量的中断次数
+///
+/// ## 参数
+/// - `vectors`: 设备安装的中断，下标为中断在设备中断向量中的位置
+/// - `count_of`: 查询一个中断发生的次数
+pub fn pci_irq_vector_stats_from(
+    vectors: &[IrqNumber],
+    count_of: impl Fn(IrqNumber) -> u64,
+) -> Vec<PciIrqVectorStat> {
+    vectors
+        .iter()
+        .enumerate()
+        .map(|(index, irq)| PciIrqVectorStat {
+            index: index as u16,
+            irq: *irq,
+            count: count_of(*irq),
+        })
+        .collect()
+}
+
+/// # 函数的功能
+/// 获取pci设备已安装的每个中断向量的中断次数
+///
+/// 注意：调用者不能持有PCI_DEVICE_LINKEDLIST的锁
+///
+/// ## 返回值
+/// - Ok(stats): 按照中断向量的位置排列，设备没有安装中断时为空
+/// - Err(SystemError::ENODEV): 设备不存在
+pub fn pci_irq_vector_stats(bdf: BusDeviceFunction) -> Result<Vec<PciIrqVectorStat>, SystemError> {
+    let vectors = PCI_DEVICE_LINKEDLIST
+        .with_device_mut(bdf, |d| d.irq_vector_mut().map(|v| v.clone()))
+        .ok_or(SystemError::ENODEV)?
+        .unwrap_or_default();
+    Ok(pci_irq_vector_stats_from(&vectors, |irq| {
+        irq_desc_manager()
+            .lookup(irq)
+            .map(|desc| desc.kstat_irqs())
+            .unwrap_or(0)
+    }))
+}
+
+/// 所有中断向量的中断次数之和
+pub fn pci_irq_stats_total(stats: &[PciIrqVectorStat]) -> u64 {
+    stats.iter().map(|s| s.count).sum()
+}
+
+/// # 函数的功能
+/// 格式化为`msi_irqs/counts`的内容，每行为`<位置> <中断号> <次数>`
+pub fn format_pci_irq_vector_stats(stats: &[PciIrqVectorStat]) -> String {
+    let mut s = String::new();
+    for stat in stats {
+        s.push_str(&format!(
+            "{} {} {}\n",
+            stat.index,
+            stat.irq.data(),
+            stat.count
+        ));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_vector_stats() {
+        // 模拟中断：56发生3次，57发生1次，58没有发生
+        let mut counts = BTreeMap::new();
+        for irq in [56, 57, 56, 56] {
+            *counts.entry(IrqNumber::new(irq)).or_insert(0u64) += 1;
+        }
+        let vectors = vec![IrqNumber::new(56), IrqNumber::new(57), IrqNumber::new(58)];
+        let stats =
+            pci_irq_vector_stats_from(&vectors, |irq| counts.get(&irq).copied().unwrap_or(0));
+
+        assert_eq!(stats.len(), 3);
+        assert_eq!((stats[0].index, stats[0].count), (0, 3));
+        assert_eq!((stats[1].index, stats[1].count), (1, 1));
+        assert_eq!((stats[2].index, stats[2].count), (2, 0));
+        assert_eq!(pci_irq_stats_total(&stats), 4);
+        assert_eq!(
+            format_pci_irq_vector_stats(&stats),
+            "0 56 3\n1 57 1\n2 58 0\n"
+        );
+    }
+
+    #[test]
+    fn test_no_vectors() {
+        let stats = pci_irq_vector_stats_from(&[], |_| unreachable!());
+        assert!(stats.is_empty());
+        assert_eq!(pci_irq_stats_total(&stats), 0);
+        assert_eq!(format_pci_irq_vector_stats(&stats), "");
+    }
+}
@@ -1,12 +1,28 @@
+pub mod address;
 pub mod attr;
+pub mod bridge;
+pub mod config_space;
+pub mod deferred;
 pub mod dev_id;
 pub mod device;
 pub mod driver;
 pub mod ecam;
+pub mod hotplug;
+pub mod ids;
+pub mod irq_stats;
+pub mod notifier;
+pub mod numa;
 #[allow(clippy::module_inception)]
 pub mod pci;
 pub mod pci_irq;
+pub mod pcie;
+pub mod pm;
+pub mod probe;
+pub mod probe_policy;
 pub mod raw_device;
 pub mod root;
+pub mod stats;
 pub mod subsys;
+pub mod synthetic;
 pub mod test;
+pub mod tree;
@@ -0,0 +1,27 @@
+//! pci总线上设备增删的通知
+//!
+//! 其他子系统（例如块设备层）可以在pci总线上注册[`PciBusNotifier`]，
+//! 在设备被添加到总线、从总线上移除时直接得到回调，而不需要轮询总线上的设备列表。
+
+use core::fmt::Debug;
+
+use alloc::sync::Arc;
+
+use super::device::PciDevice;
+
+/// # trait功能
+/// pci总线上设备增删的通知接收者
+///
+/// 回调在对应的操作中同步执行，执行时不持有总线的锁，因此回调中可以访问总线
+/// （例如遍历总线上的设备、注册或注销通知接收者）
+pub trait PciBusNotifier: Debug + Send + Sync {
+    /// 设备已经被添加到总线上
+    ///
+    /// 调用时设备已经出现在sysfs中，并且已经尝试过为它匹配驱动
+    fn on_device_add(&self, device: &Arc<dyn PciDevice>);
+
+    /// 设备将要从总线上移除
+    ///
+    /// 调用时设备仍然在sysfs中，回调返回后才会解除驱动的绑定、删除sysfs中的目录
+    fn on_device_remove(&self, device: &Arc<dyn PciDevice>);
+}
@@ -0,0 +1,39 @@
+//! PCI设备的NUMA亲和性
+//!
+//! 设备所在的NUMA节点决定了哪些CPU离它最近(local_cpus)，
+//! 设备的中断默认只分散到这些CPU上，避免跨节点访问。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/pci-sysfs.c#108
+
+use crate::{libs::cpumask::CpuMask, smp::cpu::smp_cpu_manager};
+
+use super::pci::BusDeviceFunction;
+
+/// 设备不属于任何已知的NUMA节点
+pub const NUMA_NO_NODE: i32 = -1;
+
+/// # 函数的功能
+/// 获取PCI设备所在的NUMA节点
+///
+/// todo: 解析ACPI SRAT以及设备的_PXM，目前所有设备都返回NUMA_NO_NODE
+pub fn pci_numa_node(_bdf: BusDeviceFunction) -> i32 {
+    NUMA_NO_NODE
+}
+
+/// # 函数的功能
+/// 获取NUMA节点上的CPU
+///
+/// 节点未知(NUMA_NO_NODE)，或者系统没有NUMA信息时，返回所有CPU
+pub fn cpumask_of_node(_node: i32) -> CpuMask {
+    // todo: 目前没有NUMA拓扑信息，任何节点都视为包含所有CPU
+    smp_cpu_manager().present_cpus().clone()
+}
+
+/// # 函数的功能
+/// 获取与PCI设备位于同一NUMA节点的CPU
+///
+/// ## 参数
+/// - `bdf`: 设备的位置，为None时(例如不在PCI总线上的测试设备)视为节点未知
+pub fn pci_local_cpus(bdf: Option<BusDeviceFunction>) -> CpuMask {
+    cpumask_of_node(bdf.map(pci_numa_node).unwrap_or(NUMA_NO_NODE))
+}
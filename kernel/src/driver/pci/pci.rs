@@ -1,8 +1,15 @@
 #![allow(dead_code)]
 // 目前仅支持单主桥单Segment
 
+use super::address::PciAddress;
+use super::bridge::PciBridgeDevice;
+use super::config_space::{
+    pci_capability_fits, pci_msi_capability_len, PciCapabilityWalk, PciExtCapabilityWalk,
+    PciFunctionConfig, PciRootConfig, PCI_MSIX_CAP_LEN,
+};
+use super::deferred::pci_async_probe_enabled;
 use super::device::pci_device_manager;
-use super::pci_irq::{IrqType, PciIrqError};
+use super::pci_irq::{pci_irq_cpuhp_init, IrqType, PciIrqError};
 use super::raw_device::PciGeneralDevice;
 use super::root::{pci_root_0, PciRoot};
 
@@ -15,7 +22,7 @@ use crate::mm::mmio_buddy::{mmio_pool, MMIOSpaceGuard};
 
 use crate::mm::VirtAddr;
 
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use alloc::{boxed::Box, collections::LinkedList};
@@ -67,13 +74,22 @@ impl Debug for PciAddr {
 }
 
 /// 添加了读写锁的链表，存储PCI设备结构体
+///
+/// # 锁的约定
+///
+/// - 链表的增删（扫描总线、热插拔、rescan）都需要持有写锁。
+/// - 通过[`PciDeviceLinkedList::read`]/[`PciDeviceLinkedList::write`]得到的引用只在持有锁期间有效，
+///   持有锁期间不能调用可能再次获取该锁的函数（例如设置中断、初始化设备驱动），否则会死锁。
+/// - 需要在遍历过程中执行耗时操作的调用者，应当先用[`PciDeviceLinkedList::bus_device_functions`]
+///   取得设备位置的快照，再通过[`PciDeviceLinkedList::with_device`]/[`PciDeviceLinkedList::with_device_mut`]
+///   逐个短暂地访问设备。两次访问之间设备可能已经被移除，此时返回None，调用者应当跳过该设备。
 pub struct PciDeviceLinkedList {
     list: RwLock<LinkedList<Box<dyn PciDeviceStructure>>>,
 }
 
 impl PciDeviceLinkedList {
     /// @brief 初始化结构体
-    fn new() -> Self {
+    pub(super) fn new() -> Self {
         PciDeviceLinkedList {
             list: RwLock::new(LinkedList::new()),
         }
@@ -94,10 +110,98 @@ impl PciDeviceLinkedList {
         let list = self.list.read();
         list.len()
     }
-    /// @brief 添加Pci设备结构体到链表中
-    pub fn add(&self, device: Box<dyn PciDeviceStructure>) {
+    /// # 函数的功能
+    /// 添加Pci设备结构体到链表中
+    ///
+    /// 同一个地址只能有一个设备，rescan时已经在链表中的设备不会被重复添加
+    ///
+    /// ## 返回值
+    /// - true: 添加成功
+    /// - false: 链表中已经有该地址的设备
+    pub fn add(&self, device: Box<dyn PciDeviceStructure>) -> bool {
         let mut list = self.list.write();
+        let address = device.address();
+        if list.iter().any(|d| d.address() == address) {
+            return false;
+        }
         list.push_back(device);
+        true
+    }
+
+    /// 链表中是否有该地址的设备
+    pub fn contains(&self, address: PciAddress) -> bool {
+        self.list.read().iter().any(|d| d.address() == address)
+    }
+
+    /// # 函数的功能
+    /// 从链表中移除指定位置的设备
+    ///
+    /// ## 返回值
+    /// - Some(device): 被移除的设备
+    /// - None: 链表中没有该设备
+    pub fn remove(&self, bdf: BusDeviceFunction) -> Option<Box<dyn PciDeviceStructure>> {
+        let mut list = self.list.write();
+        let index = list
+            .iter()
+            .position(|d| d.common_header().bus_device_function == bdf)?;
+        let mut tail = list.split_off(index);
+        let device = tail.pop_front();
+        list.append(&mut tail);
+        device
+    }
+
+    /// # 函数的功能
+    /// 取得满足条件的设备的位置的快照
+    ///
+    /// 返回之后锁已经被释放，调用者可以在遍历快照的过程中修改链表
+    pub fn bus_device_functions(
+        &self,
+        filter: impl Fn(&dyn PciDeviceStructure) -> bool,
+    ) -> Vec<BusDeviceFunction> {
+        self.list
+            .read()
+            .iter()
+            .filter(|d| filter(&***d))
+            .map(|d| d.common_header().bus_device_function)
+            .collect()
+    }
+
+    /// # 函数的功能
+    /// 持有读锁，对指定位置的设备执行`f`
+    ///
+    /// ## 返回值
+    /// - Some(r): `f`的返回值
+    /// - None: 链表中没有该设备（可能已经被移除）
+    pub fn with_device<R>(
+        &self,
+        bdf: BusDeviceFunction,
+        f: impl FnOnce(&dyn PciDeviceStructure) -> R,
+    ) -> Option<R> {
+        let list = self.list.read();
+        let device = list
+            .iter()
+            .find(|d| d.common_header().bus_device_function == bdf)?;
+        Some(f(&**device))
+    }
+
+    /// # 函数的功能
+    /// 持有写锁，对指定位置的设备执行`f`
+    ///
+    /// `f`中不能再次访问链表
+    ///
+    /// ## 返回值
+    /// - Some(r): `f`的返回值
+    /// - None: 链表中没有该设备（可能已经被移除）
+    pub fn with_device_mut<R>(
+        &self,
+        bdf: BusDeviceFunction,
+        f: impl FnOnce(&mut dyn PciDeviceStructure) -> R,
+    ) -> Option<R> {
+        let mut list = self.list.write();
+        let device = list
+            .iter_mut()
+            .find(|d| d.common_header().bus_device_function == bdf)?;
+        Some(f(&mut **device))
     }
 }
 
@@ -113,6 +217,7 @@ impl PciDeviceLinkedList {
 /// ## 返回值
 ///
 /// - 返回匹配的供应商ID的PCI设备结构的引用。
+#[allow(dead_code)]
 pub fn get_pci_device_structures_mut_by_vendor_id<'a>(
     list: &'a mut RwLockWriteGuard<'_, LinkedList<Box<dyn PciDeviceStructure>>>,
     vendor_id: u16,
@@ -333,6 +438,10 @@ pub trait PciDeviceStructure: Send + Sync {
     /// @brief 获取Pci设备共有的common_header
     /// @return 返回其不可变引用
     fn common_header(&self) -> &PciDeviceStructureHeader;
+    /// 设备的地址，即设备的唯一标识以及在sysfs中的名称
+    fn address(&self) -> PciAddress {
+        self.common_header().bus_device_function.into()
+    }
     /// @brief 当其为standard设备时返回&mut Pci_Device_Structure_General_Device，其余情况返回None
     #[inline(always)]
     fn as_standard_device_mut(&mut self) -> Option<&mut PciDeviceStructureGeneralDevice> {
@@ -392,19 +501,26 @@ pub trait PciDeviceStructure: Send + Sync {
     fn enable_master(&mut self) {
         self.set_command(Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER);
     }
-    /// @brief 寻找设备的msix空间的offset
+    /// @brief 寻找设备的msix空间的offset，超出配置空间的capability被忽略
     fn msix_capability_offset(&self) -> Option<u8> {
         for capability in self.capabilities()? {
-            if capability.id == PCI_CAP_ID_MSIX {
+            if capability.id == PCI_CAP_ID_MSIX
+                && pci_capability_fits(capability.offset, PCI_MSIX_CAP_LEN)
+            {
                 return Some(capability.offset);
             }
         }
         None
     }
-    /// @brief 寻找设备的msi空间的offset
+    /// @brief 寻找设备的msi空间的offset，超出配置空间的capability被忽略
     fn msi_capability_offset(&self) -> Option<u8> {
         for capability in self.capabilities()? {
-            if capability.id == PCI_CAP_ID_MSI {
+            if capability.id == PCI_CAP_ID_MSI
+                && pci_capability_fits(
+                    capability.offset,
+                    pci_msi_capability_len(capability.private_header),
+                )
+            {
                 return Some(capability.offset);
             }
         }
@@ -483,10 +599,11 @@ impl PciDeviceStructure for PciDeviceStructureGeneralDevice {
         &mut self.common_header
     }
     fn capabilities(&self) -> Option<CapabilityIterator> {
-        Some(CapabilityIterator {
-            bus_device_function: self.common_header.bus_device_function,
-            next_capability_offset: Some(self.capabilities_pointer),
-        })
+        let (status, _) = self.status_command();
+        Some(CapabilityIterator::new(
+            self.common_header.bus_device_function,
+            pci_first_capability(status, self.capabilities_pointer),
+        ))
     }
     fn bar_ioremap(&mut self) -> Option<Result<u8, PciError>> {
         let common_header = &self.common_header;
@@ -501,6 +618,11 @@ impl PciDeviceStructure for PciDeviceStructureGeneralDevice {
     fn bar(&mut self) -> Option<&PciStandardDeviceBar> {
         Some(&self.standard_device_bar)
     }
+    /// @brief 只打开设备的BAR实际使用的地址空间，以及总线主控
+    fn enable_master(&mut self) {
+        let command = self.standard_device_bar.space_command() | Command::BUS_MASTER;
+        self.set_command(command);
+    }
     #[inline(always)]
     fn irq_type_mut(&mut self) -> Option<&mut IrqType> {
         Some(&mut self.irq_type)
@@ -662,6 +784,22 @@ impl PciCam {
     }
 }
 
+/// # 函数的功能
+/// 根据Status寄存器和配置空间0x34处的指针得到第一个capability的位置
+///
+/// Status寄存器的Capabilities List位没有置位时，0x34处的值没有意义(例如legacy设备)，
+/// 此时认为设备没有capability，不会去遍历一个无效的指针
+///
+/// ## 返回值
+/// - Some(offset): 第一个capability的位置
+/// - None: 设备没有capability
+pub fn pci_first_capability(status: Status, capabilities_pointer: u8) -> Option<u8> {
+    if !status.contains(Status::CAPABILITIES_LIST) {
+        return None;
+    }
+    Some(capabilities_pointer & 0xfc).filter(|p| *p != 0)
+}
+
 /// Gets the capabilities 'pointer' for the device function, if any.
 /// @brief 获取第一个capability 的offset
 /// @param bus_device_function PCI设备的唯一标识
@@ -681,7 +819,7 @@ pub fn capabilities_offset(bus_device_function: BusDeviceFunction) -> Option<u8>
 /// @param bus_device_function PCI设备的唯一标识
 /// @param add_to_list 是否添加到链表
 /// @return 返回的header(trait 类型)
-fn pci_read_header(
+pub(super) fn pci_read_header(
     bus_device_function: BusDeviceFunction,
     add_to_list: bool,
 ) -> Result<Box<dyn PciDeviceStructure>, PciError> {
@@ -729,14 +867,18 @@ fn pci_read_header(
                 pci_read_general_device_header(header, &bus_device_function);
             let box_general_device = Box::new(general_device.clone());
             let box_general_device_clone = box_general_device.clone();
-            if add_to_list {
-                PCI_DEVICE_LINKEDLIST.add(box_general_device);
+            // 已经在链表中的设备(例如rescan时)已经添加到了sysfs，不能再次添加
+            if add_to_list && PCI_DEVICE_LINKEDLIST.add(box_general_device) {
                 //这里实际上不应该使用clone，因为raw是用于sysfs的结构，但是实际上pci设备是在PCI_DEVICE_LINKEDLIST链表上的，
                 //这就导致sysfs呈现的对pci设备的操控接口实际上操控的是pci设备描述符是一个副本
                 //但是无奈这里没有使用Arc
                 //todo：修改pci设备描述符在静态链表中存在的方式，并修改这里的clone操作
-                let raw = PciGeneralDevice::from(&general_device);
-                let _ = pci_device_manager().device_add(Arc::new(raw));
+                let raw = Arc::new(PciGeneralDevice::from(&general_device));
+                if pci_async_probe_enabled() {
+                    pci_device_manager().device_add_deferred(raw);
+                } else {
+                    let _ = pci_device_manager().device_add(raw);
+                }
             }
             Ok(box_general_device_clone)
         }
@@ -744,8 +886,14 @@ fn pci_read_header(
             let pci_to_pci_bridge = pci_read_pci_to_pci_bridge_header(header, &bus_device_function);
             let box_pci_to_pci_bridge = Box::new(pci_to_pci_bridge);
             let box_pci_to_pci_bridge_clone = box_pci_to_pci_bridge.clone();
-            if add_to_list {
-                PCI_DEVICE_LINKEDLIST.add(box_pci_to_pci_bridge);
+            // 桥需要先于它下游的设备加入设备模型，这些设备才能以桥为父设备
+            if add_to_list && PCI_DEVICE_LINKEDLIST.add(box_pci_to_pci_bridge) {
+                let bridge = Arc::new(PciBridgeDevice::from(&*box_pci_to_pci_bridge_clone));
+                if pci_async_probe_enabled() {
+                    pci_device_manager().device_add_deferred(bridge);
+                } else {
+                    let _ = pci_device_manager().device_add(bridge);
+                }
             }
             Ok(box_pci_to_pci_bridge_clone)
         }
@@ -1061,6 +1209,7 @@ fn pci_check_bus(bus: u8) -> Result<u8, PciError> {
 pub fn pci_init() {
     info!("Initializing PCI bus...");
     pci_bus_subsys_init().expect("Failed to init pci bus subsystem");
+    pci_irq_cpuhp_init().expect("Failed to register pci irq cpu hotplug notifier");
     if let Err(e) = pci_check_all_buses() {
         error!("pci init failed when checking bus because of error: {}", e);
         return;
@@ -1129,13 +1278,10 @@ impl BusDeviceFunction {
 impl From<BusDeviceFunction> for String {
     /// # 函数的功能
     /// 这里提供一个由BusDeviceFunction到dddd:bb:vv.f字符串的转换函数，主要用于转换成设备的名称（pci设备的名称一般是诸如0000:00:00.1这种)
+    ///
+    /// 格式见[`PciAddress`]
     fn from(value: BusDeviceFunction) -> Self {
-        //需要注意，这里的0000应该是所谓的“域号”（Domain ID），但是尚不知道是如何获得的，故硬编码在这里
-        //todo：实现域号的获取
-        format!(
-            "0000:{:02x}:{:02x}.{}",
-            value.bus, value.device, value.function
-        )
+        PciAddress::from(value).to_string()
     }
 }
 ///实现BusDeviceFunction的Display trait，使其可以直接输出
@@ -1196,7 +1342,7 @@ pub enum BarInfo {
         /// The memory address, always 16-byte aligned.
         address: u64,
         /// The size of the BAR in bytes.
-        size: u32,
+        size: u64,
         /// The virtaddress for a memory bar(mapped).
         mmio_guard: Arc<MMIOSpaceGuard>,
     },
@@ -1215,14 +1361,38 @@ impl BarInfo {
     /// BAR.
     ///@brief 得到某个bar的memory_address与size(前提是他的类型为Memory Bar)
     ///@param self
-    ///@return Option<(u64, u32) 是Memory Bar返回内存地址与大小，不是则返回None
-    pub fn memory_address_size(&self) -> Option<(u64, u32)> {
+    ///@return Option<(u64, u64) 是Memory Bar返回内存地址与大小，不是则返回None
+    pub fn memory_address_size(&self) -> Option<(u64, u64)> {
         if let Self::Memory { address, size, .. } = self {
             Some((*address, *size))
         } else {
             None
         }
     }
+    ///@brief 得到某个bar对应的地址空间
+    ///@param self
+    ///@return Option<PciBarRegion> 未使用的bar返回None
+    pub fn region(&self) -> Option<PciBarRegion> {
+        match self {
+            Self::Memory {
+                address_type,
+                prefetchable,
+                address,
+                size,
+                ..
+            } => Some(PciBarRegion::Memory {
+                address_type: *address_type,
+                prefetchable: *prefetchable,
+                address: *address,
+                size: *size,
+            }),
+            Self::IO { address, size } => Some(PciBarRegion::IO {
+                address: *address,
+                size: *size,
+            }),
+            Self::Unused => None,
+        }
+    }
     ///@brief 得到某个bar的virtaddress(前提是他的类型为Memory Bar)
     ///@param self
     ///@return Option<(u64) 是Memory Bar返回映射的虚拟地址，不是则返回None
@@ -1287,6 +1457,55 @@ impl PciStandardDeviceBar {
             _ => Err(PciError::InvalidBarType),
         }
     }
+
+    ///@brief 得到全部bar对应的地址空间
+    ///@param self
+    ///@return 下标为bar的编号，未使用的bar为None
+    pub fn regions(&self) -> [Option<PciBarRegion>; 6] {
+        [
+            self.bar0.region(),
+            self.bar1.region(),
+            self.bar2.region(),
+            self.bar3.region(),
+            self.bar4.region(),
+            self.bar5.region(),
+        ]
+    }
+
+    ///@brief 根据bar的类型得到需要在Command寄存器中打开的地址空间
+    ///@param self
+    ///@return Command 还没有读取bar时，同时打开I/O与memory地址空间
+    pub fn space_command(&self) -> Command {
+        let mut command = Command::empty();
+        for bar_index in 0..6 {
+            match self.get_bar(bar_index) {
+                Ok(BarInfo::IO { .. }) => command |= Command::IO_SPACE,
+                Ok(BarInfo::Memory { .. }) => command |= Command::MEMORY_SPACE,
+                _ => {}
+            }
+        }
+        if command.is_empty() {
+            command = Command::IO_SPACE | Command::MEMORY_SPACE;
+        }
+        command
+    }
+
+    ///@brief 设置某个bar的barinfo
+    ///@param self ，bar_index(0-5)，bar 新的barinfo
+    ///@return Result<(), PciError> bar_index超出范围则返回错误
+    pub fn set_bar(&mut self, bar_index: u8, bar: BarInfo) -> Result<(), PciError> {
+        let slot = match bar_index {
+            0 => &mut self.bar0,
+            1 => &mut self.bar1,
+            2 => &mut self.bar2,
+            3 => &mut self.bar3,
+            4 => &mut self.bar4,
+            5 => &mut self.bar5,
+            _ => return Err(PciError::InvalidBarType),
+        };
+        *slot = bar;
+        Ok(())
+    }
 }
 ///实现PciStandardDeviceBar的Display trait，使其可以直接输出
 impl Display for PciStandardDeviceBar {
@@ -1312,6 +1531,187 @@ impl Default for PciStandardDeviceBar {
     }
 }
 
+/// BAR寄存器的bit0：为1时是I/O BAR
+const BAR_IO_SPACE: u32 = 0x1;
+/// memory BAR的类型位
+const BAR_MEM_TYPE_MASK: u32 = 0x6;
+/// memory BAR的可预取位
+const BAR_MEM_PREFETCH: u32 = 0x8;
+const BAR_IO_ADDR_MASK: u32 = !0x3;
+const BAR_MEM_ADDR_MASK: u32 = !0xf;
+
+/// linux的resource文件中使用的标志位
+const IORESOURCE_IO: u64 = 0x100;
+const IORESOURCE_MEM: u64 = 0x200;
+const IORESOURCE_PREFETCH: u64 = 0x2000;
+const IORESOURCE_MEM_64: u64 = 0x0010_0000;
+
+/// 从BAR寄存器中解码出的一段地址空间(还没有映射)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PciBarRegion {
+    Memory {
+        address_type: MemoryBarType,
+        prefetchable: bool,
+        address: u64,
+        size: u64,
+    },
+    IO {
+        address: u32,
+        size: u32,
+    },
+}
+
+impl PciBarRegion {
+    /// 地址空间的起始地址
+    pub fn start(&self) -> u64 {
+        match self {
+            Self::Memory { address, .. } => *address,
+            Self::IO { address, .. } => u64::from(*address),
+        }
+    }
+
+    /// 地址空间的大小
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::Memory { size, .. } => *size,
+            Self::IO { size, .. } => u64::from(*size),
+        }
+    }
+
+    /// sysfs中resource文件使用的标志位
+    pub fn resource_flags(&self) -> u64 {
+        match self {
+            Self::Memory {
+                address_type,
+                prefetchable,
+                ..
+            } => {
+                let mut flags = IORESOURCE_MEM;
+                if *prefetchable {
+                    flags |= IORESOURCE_PREFETCH;
+                }
+                if *address_type == MemoryBarType::Width64 {
+                    flags |= IORESOURCE_MEM_64;
+                }
+                flags
+            }
+            Self::IO { .. } => IORESOURCE_IO,
+        }
+    }
+}
+
+/// # 函数的功能
+/// 格式化为sysfs中resource文件的内容
+///
+/// 每个BAR一行，格式为`<起始地址> <结束地址> <标志位>`，未使用的BAR为全0
+pub fn format_pci_resource(bars: &[Option<PciBarRegion>; 6]) -> String {
+    let mut s = String::new();
+    for bar in bars.iter() {
+        let (start, end, flags) = match bar {
+            Some(region) => (
+                region.start(),
+                region.start() + region.size() - 1,
+                region.resource_flags(),
+            ),
+            None => (0, 0, 0),
+        };
+        s.push_str(&format!(
+            "0x{:016x} 0x{:016x} 0x{:016x}\n",
+            start, end, flags
+        ));
+    }
+    s
+}
+
+/// 根据向BAR写入全1之后读回的地址位计算BAR的大小：大小为最低的可写地址位
+#[inline]
+fn pci_bar_size(mask: u64) -> u64 {
+    mask & mask.wrapping_neg()
+}
+
+/// # 函数的功能
+/// 解码一个标准设备的6个BAR寄存器
+///
+/// 64位的memory BAR占用两个连续的BAR寄存器，高32位所在的寄存器不是一个单独的BAR，
+/// 它在解码结果中为None
+///
+/// ## 参数
+/// - `orig`: 每个BAR寄存器原来的值
+/// - `size_mask`: 向每个BAR寄存器写入全1之后读回的值
+///
+/// ## 返回值
+/// - Ok(bars): 下标为BAR的编号，未使用的BAR为None
+/// - Err(PciError::InvalidBarType): BAR的类型是保留值，或者BAR5是64位的memory BAR
+pub fn pci_decode_bars(
+    orig: &[u32; 6],
+    size_mask: &[u32; 6],
+) -> Result<[Option<PciBarRegion>; 6], PciError> {
+    let mut bars = [None; 6];
+    let mut index = 0;
+    while index < 6 {
+        let bar = orig[index];
+        if bar & BAR_IO_SPACE != 0 {
+            // I/O space，部分设备不实现地址的高16位，它们读回为0
+            let size = pci_bar_size(u64::from(size_mask[index] & BAR_IO_ADDR_MASK));
+            if size != 0 {
+                bars[index] = Some(PciBarRegion::IO {
+                    address: bar & BAR_IO_ADDR_MASK,
+                    size: size as u32,
+                });
+            }
+            index += 1;
+            continue;
+        }
+        // Memory space
+        let prefetchable = bar & BAR_MEM_PREFETCH != 0;
+        let address_type = MemoryBarType::try_from(((bar & BAR_MEM_TYPE_MASK) >> 1) as u8)?;
+        let mut address = u64::from(bar & BAR_MEM_ADDR_MASK);
+        let mut mask = u64::from(size_mask[index] & BAR_MEM_ADDR_MASK);
+        let mut next = index + 1;
+        if address_type == MemoryBarType::Width64 {
+            if index >= 5 {
+                return Err(PciError::InvalidBarType);
+            }
+            // 下一个BAR寄存器是地址的高32位
+            address |= u64::from(orig[index + 1]) << 32;
+            mask |= u64::from(size_mask[index + 1]) << 32;
+            next = index + 2;
+        }
+        let size = pci_bar_size(mask);
+        if size != 0 {
+            bars[index] = Some(PciBarRegion::Memory {
+                address_type,
+                prefetchable,
+                address,
+                size,
+            });
+        }
+        index = next;
+    }
+    Ok(bars)
+}
+
+/// # 函数的功能
+/// 读取并解码pci设备的BAR，不映射它们
+///
+/// 为了得到BAR的大小，会向每个BAR寄存器写入全1，读回之后再写回原来的值
+pub fn pci_read_bars(
+    bus_device_function: BusDeviceFunction,
+) -> Result<[Option<PciBarRegion>; 6], PciError> {
+    let mut orig = [0u32; 6];
+    let mut size_mask = [0u32; 6];
+    for bar_index in 0..6u8 {
+        let offset = (BAR0_OFFSET + 4 * bar_index).into();
+        let i = bar_index as usize;
+        orig[i] = pci_root_0().read_config(bus_device_function, offset);
+        pci_root_0().write_config(bus_device_function, offset, 0xffffffff);
+        size_mask[i] = pci_root_0().read_config(bus_device_function, offset);
+        // Restore the original value.
+        pci_root_0().write_config(bus_device_function, offset, orig[i]);
+    }
+    pci_decode_bars(&orig, &size_mask)
+}
+
 ///@brief 将某个pci设备的bar寄存器读取值后映射到虚拟地址
 ///@param self ，bus_device_function PCI设备的唯一标识符
 ///@return Result<PciStandardDeviceBar, PciError> 成功则返回对应的PciStandardDeviceBar结构体，失败则返回错误类型
@@ -1319,99 +1719,49 @@ pub fn pci_bar_init(
     bus_device_function: BusDeviceFunction,
 ) -> Result<PciStandardDeviceBar, PciError> {
     let mut device_bar: PciStandardDeviceBar = PciStandardDeviceBar::default();
-    let mut bar_index_ignore: u8 = 255;
-    for bar_index in 0..6 {
-        if bar_index == bar_index_ignore {
-            continue;
-        }
-        let bar_info;
-        let bar_orig =
-            pci_root_0().read_config(bus_device_function, (BAR0_OFFSET + 4 * bar_index).into());
-        pci_root_0().write_config(
-            bus_device_function,
-            (BAR0_OFFSET + 4 * bar_index).into(),
-            0xffffffff,
-        );
-        let size_mask =
-            pci_root_0().read_config(bus_device_function, (BAR0_OFFSET + 4 * bar_index).into());
-        // A wrapping add is necessary to correctly handle the case of unused BARs, which read back
-        // as 0, and should be treated as size 0.
-        let size = (!(size_mask & 0xfffffff0)).wrapping_add(1);
-        //debug!("bar_orig:{:#x},size: {:#x}", bar_orig,size);
-        // Restore the original value.
-        pci_root_0().write_config(
-            bus_device_function,
-            (BAR0_OFFSET + 4 * bar_index).into(),
-            bar_orig,
-        );
-        if size == 0 {
-            continue;
-        }
-        if bar_orig & 0x00000001 == 0x00000001 {
-            // I/O space
-            let address = bar_orig & 0xfffffffc;
-            bar_info = BarInfo::IO { address, size };
-        } else {
-            // Memory space
-            let mut address = u64::from(bar_orig & 0xfffffff0);
-            let prefetchable = bar_orig & 0x00000008 != 0;
-            let address_type = MemoryBarType::try_from(((bar_orig & 0x00000006) >> 1) as u8)?;
-            if address_type == MemoryBarType::Width64 {
-                if bar_index >= 5 {
-                    return Err(PciError::InvalidBarType);
-                }
-                let address_top = pci_root_0().read_config(
-                    bus_device_function,
-                    (BAR0_OFFSET + 4 * (bar_index + 1)).into(),
-                );
-                address |= u64::from(address_top) << 32;
-                bar_index_ignore = bar_index + 1; //下个bar跳过，因为64位的memory bar覆盖了两个bar
-            }
-            let pci_address = PciAddr::new(address as usize);
-            let paddr = PciArch::address_pci_to_physical(pci_address); //PCI总线域物理地址转换为存储器域物理地址
-
-            let space_guard: Arc<MMIOSpaceGuard>;
-            unsafe {
-                let size_want = size as usize;
-                let tmp = mmio_pool()
-                    .create_mmio(size_want)
-                    .map_err(|_| PciError::CreateMmioError)?;
-                space_guard = Arc::new(tmp);
-                //debug!("Pci bar init: mmio space: {space_guard:?}, paddr={paddr:?}, size_want={size_want}");
-                assert!(
-                    space_guard.map_phys(paddr, size_want).is_ok(),
-                    "pci_bar_init: map_phys failed"
-                );
-            }
-            bar_info = BarInfo::Memory {
+    for (bar_index, region) in pci_read_bars(bus_device_function)?.iter().enumerate() {
+        let bar_info = match *region {
+            None => continue,
+            Some(PciBarRegion::IO { address, size }) => BarInfo::IO { address, size },
+            Some(PciBarRegion::Memory {
                 address_type,
                 prefetchable,
                 address,
                 size,
-                mmio_guard: space_guard,
-            };
-        }
-        match bar_index {
-            0 => {
-                device_bar.bar0 = bar_info;
-            }
-            1 => {
-                device_bar.bar1 = bar_info;
-            }
-            2 => {
-                device_bar.bar2 = bar_info;
-            }
-            3 => {
-                device_bar.bar3 = bar_info;
-            }
-            4 => {
-                device_bar.bar4 = bar_info;
-            }
-            5 => {
-                device_bar.bar5 = bar_info;
+            }) => {
+                let pci_address = PciAddr::new(address as usize);
+                let paddr = PciArch::address_pci_to_physical(pci_address); //PCI总线域物理地址转换为存储器域物理地址
+
+                let space_guard: Arc<MMIOSpaceGuard>;
+                unsafe {
+                    let size_want = size as usize;
+                    // 超过mmio地址空间的BAR无法映射，设备初始化失败，而不是跳过这个BAR
+                    let tmp = mmio_pool().create_mmio(size_want).map_err(|_| {
+                        warn!(
+                            "pci_bar_init: {} bar{} is too large to map, size {:#x}",
+                            PciAddress::from(bus_device_function),
+                            bar_index,
+                            size
+                        );
+                        PciError::CreateMmioError
+                    })?;
+                    space_guard = Arc::new(tmp);
+                    //debug!("Pci bar init: mmio space: {space_guard:?}, paddr={paddr:?}, size_want={size_want}");
+                    assert!(
+                        space_guard.map_phys(paddr, size_want).is_ok(),
+                        "pci_bar_init: map_phys failed"
+                    );
+                }
+                BarInfo::Memory {
+                    address_type,
+                    prefetchable,
+                    address,
+                    size,
+                    mmio_guard: space_guard,
+                }
             }
-            _ => {}
-        }
+        };
+        device_bar.set_bar(bar_index as u8, bar_info)?;
     }
     //debug!("pci_device_bar:{}", device_bar);
     return Ok(device_bar);
@@ -1431,37 +1781,33 @@ pub struct CapabilityInfo {
 
 /// Iterator over capabilities for a device.
 /// 创建迭代器以遍历PCI设备的capability
+///
+/// 链表中有环或者指针无效时打印警告并结束遍历，见[`PciCapabilityWalk`]
 #[derive(Debug)]
 pub struct CapabilityIterator {
-    pub bus_device_function: BusDeviceFunction,
-    pub next_capability_offset: Option<u8>,
+    walk: PciCapabilityWalk<PciFunctionConfig>,
+}
+
+impl CapabilityIterator {
+    /// # 函数的功能
+    /// 从`first`开始遍历设备的capability链表，`first`通常由[`capabilities_offset`]获得
+    pub fn new(bus_device_function: BusDeviceFunction, first: Option<u8>) -> Self {
+        Self {
+            walk: PciCapabilityWalk::new(PciFunctionConfig::new(bus_device_function), first),
+        }
+    }
 }
 
 impl Iterator for CapabilityIterator {
     type Item = CapabilityInfo;
     fn next(&mut self) -> Option<Self::Item> {
-        let offset = self.next_capability_offset?;
-
-        // Read the first 4 bytes of the capability.
-        let capability_header = pci_root_0().read_config(self.bus_device_function, offset.into());
-        let id = capability_header as u8;
-        let next_offset = (capability_header >> 8) as u8;
-        let private_header = (capability_header >> 16) as u16;
-
-        self.next_capability_offset = if next_offset == 0 {
-            None
-        } else if next_offset < 64 || next_offset & 0x3 != 0 {
-            warn!("Invalid next capability offset {:#04x}", next_offset);
-            None
-        } else {
-            Some(next_offset)
-        };
-
-        Some(CapabilityInfo {
-            offset,
-            id,
-            private_header,
-        })
+        match self.walk.next()? {
+            Ok(capability) => Some(capability),
+            Err(e) => {
+                warn!("Invalid capability list: {:?}", e);
+                None
+            }
+        }
     }
 }
 
@@ -1481,34 +1827,146 @@ pub struct ExternalCapabilityInfo {
 /// 创建迭代器以遍历PCIe设备的external capability
 #[derive(Debug)]
 pub struct ExternalCapabilityIterator<'a> {
-    pub root: &'a PciRoot,
-    pub bus_device_function: BusDeviceFunction,
-    pub next_capability_offset: Option<u16>,
+    walk: PciExtCapabilityWalk<PciRootConfig<'a>>,
+}
+
+impl<'a> ExternalCapabilityIterator<'a> {
+    pub fn new(root: &'a PciRoot, bus_device_function: BusDeviceFunction) -> Self {
+        Self {
+            walk: PciExtCapabilityWalk::new(PciRootConfig::new(root, bus_device_function)),
+        }
+    }
 }
+
 impl<'a> Iterator for ExternalCapabilityIterator<'a> {
     type Item = ExternalCapabilityInfo;
     fn next(&mut self) -> Option<Self::Item> {
-        let offset = self.next_capability_offset?;
+        match self.walk.next()? {
+            Ok(capability) => Some(capability),
+            Err(e) => {
+                warn!("Invalid external capability list: {:?}", e);
+                None
+            }
+        }
+    }
+}
 
-        // Read the first 4 bytes of the capability.
-        let capability_header = self.root.read_config(self.bus_device_function, offset);
-        let id = capability_header as u16;
-        let next_offset = (capability_header >> 20) as u16;
-        let capability_version = ((capability_header >> 16) & 0xf) as u8;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        self.next_capability_offset = if next_offset == 0 {
-            None
-        } else if next_offset < 0x100 || next_offset & 0x3 != 0 {
-            warn!("Invalid next capability offset {:#04x}", next_offset);
+    #[test]
+    fn test_first_capability() {
+        assert_eq!(
+            pci_first_capability(Status::CAPABILITIES_LIST, 0x40),
+            Some(0x40)
+        );
+        // 最低两位是保留位
+        assert_eq!(
+            pci_first_capability(Status::CAPABILITIES_LIST, 0x43),
+            Some(0x40)
+        );
+        assert_eq!(pci_first_capability(Status::CAPABILITIES_LIST, 0), None);
+        // 没有Capabilities List位时忽略指针
+        assert_eq!(pci_first_capability(Status::empty(), 0x40), None);
+        assert_eq!(
+            pci_first_capability(Status::MHZ_66_CAPABLE | Status::INTERRUPT_STATUS, 0x98),
             None
-        } else {
-            Some(next_offset)
-        };
+        );
+    }
 
-        Some(ExternalCapabilityInfo {
-            offset,
-            id,
-            capability_version,
-        })
+    #[test]
+    fn test_decode_32bit_bars() {
+        // BAR0: 32位不可预取的memory BAR，4KiB；BAR1: I/O BAR，32字节，高16位读回为0
+        let orig = [0xfebd_1000, 0x0000_c041, 0, 0, 0, 0];
+        let mask = [0xffff_f000, 0x0000_ffe1, 0, 0, 0, 0];
+        let bars = pci_decode_bars(&orig, &mask).unwrap();
+        assert_eq!(
+            bars[0],
+            Some(PciBarRegion::Memory {
+                address_type: MemoryBarType::Width32,
+                prefetchable: false,
+                address: 0xfebd_1000,
+                size: 0x1000,
+            })
+        );
+        assert_eq!(
+            bars[1],
+            Some(PciBarRegion::IO {
+                address: 0xc040,
+                size: 0x20,
+            })
+        );
+        assert!(bars[2..].iter().all(|b| b.is_none()));
+        assert_eq!(bars[0].unwrap().resource_flags(), IORESOURCE_MEM);
+        assert_eq!(bars[1].unwrap().resource_flags(), IORESOURCE_IO);
+    }
+
+    #[test]
+    fn test_decode_64bit_bar() {
+        // BAR1: I/O BAR；BAR4+BAR5: 64位可预取的memory BAR，8GiB，位于0x8_0000_0000
+        let orig = [0, 0x0000_c001, 0, 0, 0x0000_000c, 0x0000_0008];
+        let mask = [0, 0xffff_ffc1, 0, 0, 0x0000_000c, 0xffff_fffe];
+        let bars = pci_decode_bars(&orig, &mask).unwrap();
+        assert_eq!(
+            bars[4],
+            Some(PciBarRegion::Memory {
+                address_type: MemoryBarType::Width64,
+                prefetchable: true,
+                address: 0x8_0000_0000,
+                size: 0x2_0000_0000,
+            })
+        );
+        // 高32位所在的BAR寄存器不是单独的BAR
+        assert_eq!(bars[5], None);
+        assert_eq!(
+            bars[4].unwrap().resource_flags(),
+            IORESOURCE_MEM | IORESOURCE_PREFETCH | IORESOURCE_MEM_64
+        );
+        assert_eq!(bars[1].unwrap().size(), 0x40);
+    }
+
+    #[test]
+    fn test_decode_64bit_bar_upper_half_not_misread() {
+        // 地址高32位为0x1(会被误认为I/O BAR)的64位BAR
+        let orig = [0xfe00_0004, 0x0000_0001, 0xfeb0_0000, 0, 0, 0];
+        let mask = [0xffe0_0004, 0xffff_ffff, 0xfff0_0000, 0, 0, 0];
+        let bars = pci_decode_bars(&orig, &mask).unwrap();
+        assert_eq!(bars[0].unwrap().start(), 0x1_fe00_0000);
+        assert_eq!(bars[0].unwrap().size(), 0x20_0000);
+        assert_eq!(bars[1], None);
+        assert_eq!(bars[2].unwrap().size(), 0x10_0000);
+    }
+
+    #[test]
+    fn test_format_resource() {
+        let orig = [0, 0x0000_c001, 0, 0, 0x0000_000c, 0x0000_0008];
+        let mask = [0, 0xffff_ffc1, 0, 0, 0x0000_000c, 0xffff_fffe];
+        let bars = pci_decode_bars(&orig, &mask).unwrap();
+        let zero = "0x0000000000000000 0x0000000000000000 0x0000000000000000\n";
+        let expected = [
+            zero,
+            "0x000000000000c000 0x000000000000c03f 0x0000000000000100\n",
+            zero,
+            zero,
+            "0x0000000800000000 0x00000009ffffffff 0x0000000000102200\n",
+            zero,
+        ]
+        .concat();
+        assert_eq!(format_pci_resource(&bars), expected);
+    }
+
+    #[test]
+    fn test_decode_invalid_bars() {
+        // BAR5不能是64位BAR
+        let orig = [0, 0, 0, 0, 0, 0xfe00_0004];
+        let mask = [0, 0, 0, 0, 0, 0xffe0_0004];
+        assert_eq!(pci_decode_bars(&orig, &mask), Err(PciError::InvalidBarType));
+        // 保留的memory BAR类型
+        let orig = [0xfe00_0006, 0, 0, 0, 0, 0];
+        assert_eq!(
+            pci_decode_bars(&orig, &[0; 6]),
+            Err(PciError::InvalidBarType)
+        );
     }
 }
@@ -6,18 +6,29 @@ use core::ptr::NonNull;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use log::error;
+use log::{error, warn};
 use system_error::SystemError;
 
-use super::pci::{PciDeviceStructure, PciDeviceStructureGeneralDevice, PciError};
+use super::config_space::{pci_read_msix_layout, PciFunctionConfig};
+use super::numa::pci_local_cpus;
+use super::pci::{
+    BusDeviceFunction, Command, PciBarRegion, PciDeviceStructure, PciDeviceStructureGeneralDevice,
+    PciError, PCI_DEVICE_LINKEDLIST,
+};
 use super::root::pci_root_0;
-use crate::arch::msi::{arch_msi_message_address, arch_msi_message_data};
+use crate::arch::msi::{
+    arch_msi_message_address, arch_msi_message_data, arch_msi_message_processor,
+    arch_msi_message_trigger, arch_pci_legacy_irq,
+};
 
 use crate::driver::base::device::DeviceId;
 use crate::exception::irqdesc::{IrqHandleFlags, IrqHandler};
 use crate::exception::manage::irq_manager;
 use crate::exception::IrqNumber;
+use crate::libs::cpumask::CpuMask;
+use crate::libs::notifier::NotifierBlock;
 use crate::libs::volatile::{volread, volwrite, Volatile};
+use crate::smp::cpu::{smp_cpu_manager, CpuHpEvent, ProcessorId};
 
 /// MSIX表的一项
 #[repr(C)]
@@ -28,6 +39,123 @@ struct MsixEntry {
     vector_control: Volatile<u32>,
 }
 
+/// MSI-X表项Vector Control中的Mask Bit
+const PCI_MSIX_ENTRY_CTRL_MASKBIT: u32 = 1;
+
+/// # trait功能
+/// 访问MSI-X表的一项，测试时用模拟的表项代替设备的MMIO
+trait MsixEntryAccess {
+    fn read_control(&self) -> u32;
+    fn write_control(&self, control: u32);
+    /// 读取表项的(Message Address, Message Data)
+    fn read_message(&self) -> (u32, u32);
+    fn write_message(&self, msg_addr: u32, msg_data: u32);
+}
+
+impl MsixEntryAccess for NonNull<MsixEntry> {
+    fn read_control(&self) -> u32 {
+        unsafe { volread!(self, vector_control) }
+    }
+
+    fn write_control(&self, control: u32) {
+        unsafe { volwrite!(self, vector_control, control) }
+    }
+
+    fn read_message(&self) -> (u32, u32) {
+        unsafe { (volread!(self, msg_addr), volread!(self, msg_data)) }
+    }
+
+    // 这里的操作并不适用于所有架构，msg_upper_addr并不一定为0
+    fn write_message(&self, msg_addr: u32, msg_data: u32) {
+        unsafe {
+            volwrite!(self, msg_upper_addr, 0);
+            volwrite!(self, msg_addr, msg_addr);
+            volwrite!(self, msg_data, msg_data);
+        }
+    }
+}
+
+/// # 函数的功能
+/// 重写MSI-X表项的Message Address和Message Data
+///
+/// 表项没有被屏蔽时先屏蔽，写入之后再解除屏蔽，设备不会用写了一半的地址和数据发送中断。
+/// 屏蔽期间产生的中断由设备记录在PBA中，解除屏蔽后按照新的地址和数据发送，不会丢失。
+/// 最后读回Vector Control，确保写入已经到达设备
+///
+/// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/msi/msi.c#306
+fn msix_entry_retarget(entry: &impl MsixEntryAccess, msg_addr: u32, msg_data: u32) {
+    let control = entry.read_control();
+    let unmasked = control & PCI_MSIX_ENTRY_CTRL_MASKBIT == 0;
+    if unmasked {
+        entry.write_control(control | PCI_MSIX_ENTRY_CTRL_MASKBIT);
+    }
+    entry.write_message(msg_addr, msg_data);
+    if unmasked {
+        entry.write_control(control);
+    }
+    entry.read_control();
+}
+
+/// 把MSI-X表项投递到`cpu`上，中断向量为`vector`，触发方式保持不变
+fn msix_entry_migrate(entry: &impl MsixEntryAccess, vector: u16, cpu: ProcessorId) {
+    let (_, msg_data) = entry.read_message();
+    let processor = cpu.data() as u16;
+    msix_entry_retarget(
+        entry,
+        arch_msi_message_address(processor),
+        arch_msi_message_data(vector, processor, arch_msi_message_trigger(msg_data)),
+    );
+}
+
+/// # 函数的功能
+/// 把投递到`departing`上的MSI-X表项依次迁移到`targets`中的CPU上
+///
+/// ## 参数
+/// - `entries`: 设备已安装的中断的(表项, 中断向量)
+/// - `departing`: 即将下线的CPU
+/// - `targets`: 可以迁移到的CPU，不能包含`departing`
+///
+/// ## 返回值
+/// - Ok(n): 被迁移的表项的数量
+/// - Err(PciIrqError::InvalidCpu): 有需要迁移的表项，但是`targets`为空
+fn msix_migrate_entries<E: MsixEntryAccess>(
+    entries: &[(E, u16)],
+    departing: ProcessorId,
+    targets: &[ProcessorId],
+) -> Result<usize, PciIrqError> {
+    let mut n = 0;
+    for (entry, vector) in entries {
+        let (msg_addr, _) = entry.read_message();
+        if arch_msi_message_processor(msg_addr) as u32 != departing.data() {
+            continue;
+        }
+        let target = *targets
+            .get(n % targets.len().max(1))
+            .ok_or(PciIrqError::InvalidCpu(departing))?;
+        msix_entry_migrate(entry, *vector, target);
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// 下线的CPU上的中断可以迁移到的CPU：优先选择与设备位于同一NUMA节点的CPU
+fn msix_migration_targets(
+    local_cpus: &CpuMask,
+    present_cpus: &CpuMask,
+    departing: ProcessorId,
+) -> Vec<ProcessorId> {
+    let pick = |mask: &CpuMask| {
+        mask.iter_cpu()
+            .filter(|cpu| *cpu != departing)
+            .collect::<Vec<_>>()
+    };
+    let local = pick(&(local_cpus & present_cpus));
+    if !local.is_empty() {
+        return local;
+    }
+    pick(present_cpus)
+}
+
 /// Pending表的一项
 #[repr(C)]
 struct PendingEntry {
@@ -49,6 +177,76 @@ pub enum PciIrqError {
     BarGetVaddrFailed,
     MaskNotSupported,
     IrqNotInited,
+    InvalidCpu(ProcessorId),
+    /// MSI-X表或PBA指向的BAR不存在、不是内存空间，或者放不下
+    InvalidMsixLayout,
+}
+
+/// # 结构功能
+/// MSI-X capability中描述的MSI-X表与Pending Bit Array(PBA)的位置
+///
+/// 参考 PCI Local Bus Specification 3.0, 6.8.2 MSI-X Capability and Table Structure
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciMsixLayout {
+    /// MSI-X表的项数
+    pub table_size: u16,
+    /// MSI-X表所在的BAR(BIR)
+    pub table_bir: u8,
+    /// MSI-X表在BAR中的偏移
+    pub table_offset: u32,
+    /// PBA所在的BAR(BIR)
+    pub pba_bir: u8,
+    /// PBA在BAR中的偏移
+    pub pba_offset: u32,
+}
+
+impl PciMsixLayout {
+    /// # 函数的功能
+    /// 从MSI-X capability的前3个dword解析
+    ///
+    /// ## 参数
+    /// - `cap`: capability的第0个dword(高16位为Message Control)、Table Offset/BIR、PBA Offset/BIR
+    pub fn parse(cap: [u32; 3]) -> Self {
+        Self {
+            table_size: ((cap[0] >> 16) & 0x7ff) as u16 + 1,
+            table_bir: (cap[1] & 0x07) as u8,
+            table_offset: cap[1] & !0x07,
+            pba_bir: (cap[2] & 0x07) as u8,
+            pba_offset: cap[2] & !0x07,
+        }
+    }
+
+    /// MSI-X表占用的字节数
+    pub fn table_len(&self) -> u64 {
+        u64::from(self.table_size) * size_of::<MsixEntry>() as u64
+    }
+
+    /// PBA占用的字节数，每个表项占一位，按照qword对齐
+    pub fn pba_len(&self) -> u64 {
+        u64::from(self.table_size).div_ceil(64) * size_of::<PendingEntry>() as u64
+    }
+
+    /// # 函数的功能
+    /// 检查MSI-X表与PBA是否完整地位于设备的内存BAR中
+    ///
+    /// ## 参数
+    /// - `regions`: 设备的BAR，下标为BAR的编号
+    ///
+    /// ## 返回值
+    /// - Err(PciIrqError::InvalidMsixLayout): BIR是保留值，BAR没有使用或者不是内存空间，或者BAR放不下
+    pub fn validate(&self, regions: &[Option<PciBarRegion>; 6]) -> Result<(), PciIrqError> {
+        let fits =
+            |bir: u8, offset: u32, len: u64| match regions.get(bir as usize).copied().flatten() {
+                Some(PciBarRegion::Memory { size, .. }) => u64::from(offset) + len <= size,
+                _ => false,
+            };
+        if !fits(self.table_bir, self.table_offset, self.table_len())
+            || !fits(self.pba_bir, self.pba_offset, self.pba_len())
+        {
+            return Err(PciIrqError::InvalidMsixLayout);
+        }
+        Ok(())
+    }
 }
 
 /// PCI设备的中断类型
@@ -159,37 +357,41 @@ pub trait PciInterrupt: PciDeviceStructure {
         // MSIX中断优先
         if flag.contains(IRQ::PCI_IRQ_MSIX) {
             if let Some(cap_offset) = self.msix_capability_offset() {
-                let data = pci_root_0()
-                    .read_config(self.common_header().bus_device_function, cap_offset.into());
-                let irq_max_num = ((data >> 16) & 0x7ff) as u16 + 1;
-                let data = pci_root_0().read_config(
-                    self.common_header().bus_device_function,
-                    (cap_offset + 4).into(),
-                );
-                let msix_table_bar = (data & 0x07) as u8;
-                let msix_table_offset = data & (!0x07);
-                let data = pci_root_0().read_config(
-                    self.common_header().bus_device_function,
-                    (cap_offset + 8).into(),
-                );
-                let pending_table_bar = (data & 0x07) as u8;
-                let pending_table_offset = data & (!0x07);
-                *self.irq_type_mut()? = IrqType::Msix {
-                    msix_table_bar,
-                    msix_table_offset,
-                    pending_table_bar,
-                    pending_table_offset,
-                    irq_max_num,
-                    cap_offset,
+                let bdf = self.common_header().bus_device_function;
+                let layout = match pci_read_msix_layout(&PciFunctionConfig::new(bdf), cap_offset) {
+                    Ok(layout) => Some(layout),
+                    Err(e) => {
+                        warn!("pci device {}: invalid msi-x capability: {:?}", bdf, e);
+                        None
+                    }
                 };
-                return Some(IrqType::Msix {
-                    msix_table_bar,
-                    msix_table_offset,
-                    pending_table_bar,
-                    pending_table_offset,
-                    irq_max_num,
-                    cap_offset,
-                });
+                if let Some(layout) = layout {
+                    // MSI-X表位置不对时，写入表项会写到其他的MMIO上，此时改用其他中断类型
+                    let valid = match self.bar() {
+                        Some(bar) => layout.validate(&bar.regions()),
+                        None => Err(PciIrqError::PciBarNotInited),
+                    };
+                    match valid {
+                        Ok(()) => {
+                            let irq_type = IrqType::Msix {
+                                msix_table_bar: layout.table_bir,
+                                msix_table_offset: layout.table_offset,
+                                pending_table_bar: layout.pba_bir,
+                                pending_table_offset: layout.pba_offset,
+                                irq_max_num: layout.table_size,
+                                cap_offset,
+                            };
+                            *self.irq_type_mut()? = irq_type;
+                            return Some(irq_type);
+                        }
+                        Err(e) => {
+                            warn!(
+                                "pci device {}: ignoring msi-x capability {:?}: {:?}",
+                                bdf, layout, e
+                            );
+                        }
+                    }
+                }
             }
         }
         // 其次MSI
@@ -236,7 +438,7 @@ pub trait PciInterrupt: PciDeviceStructure {
                     return self.msi_enable(enable);
                 }
                 IrqType::Legacy => {
-                    return Err(PciError::PciIrqError(PciIrqError::IrqTypeNotSupported));
+                    return self.legacy_enable(enable);
                 }
                 IrqType::Unused => {
                     return Err(PciError::PciIrqError(PciIrqError::IrqNotInited));
@@ -245,6 +447,28 @@ pub trait PciInterrupt: PciDeviceStructure {
         }
         return Err(PciError::PciIrqError(PciIrqError::PciDeviceNotSupportIrq));
     }
+    /// @brief 启动/关闭设备的INTx中断（通过Command寄存器的INTERRUPT_DISABLE位）
+    /// @param self PCI设备的可变引用
+    /// @param enable 开启/关闭
+    fn legacy_enable(&mut self, enable: bool) -> Result<u8, PciError> {
+        let (_, mut command) = self.status_command();
+        command.set(Command::INTERRUPT_DISABLE, !enable);
+        self.set_command(command);
+        return Ok(0);
+    }
+    /// @brief 获取设备INTx中断对应的中断号
+    /// @param self PCI设备的可变引用
+    /// @return 设备没有连接中断引脚，或者当前架构不支持时返回None
+    fn legacy_irq_number(&mut self) -> Option<IrqNumber> {
+        // Interrupt Line位于配置空间0x3c处的低8位，Interrupt Pin位于其后8位
+        let data = pci_root_0().read_config(self.common_header().bus_device_function, 0x3c);
+        let interrupt_line = data as u8;
+        let interrupt_pin = (data >> 8) as u8;
+        if interrupt_pin == 0 || interrupt_line == 0xff {
+            return None;
+        }
+        return arch_pci_legacy_irq(interrupt_line);
+    }
     /// @brief 启动/关闭设备MSIX中断
     /// @param self PCI设备的可变引用
     /// @param enable 开启/关闭
@@ -332,6 +556,9 @@ pub trait PciInterrupt: PciDeviceStructure {
                 IrqType::Msi { .. } => {
                     return self.msi_install(msg);
                 }
+                IrqType::Legacy => {
+                    return self.legacy_install(msg);
+                }
                 IrqType::Unused => {
                     return Err(PciError::PciIrqError(PciIrqError::IrqNotInited));
                 }
@@ -342,6 +569,42 @@ pub trait PciInterrupt: PciDeviceStructure {
         }
         return Err(PciError::PciIrqError(PciIrqError::PciDeviceNotSupportIrq));
     }
+    /// @brief 进行PCI设备中断的安装(INTx)
+    /// @param self PCI设备的可变引用
+    /// @param msg PCI设备install中断时需要传递的共同参数
+    /// @return 一切正常返回Ok(0),有错误返回对应错误原因
+    fn legacy_install(&mut self, msg: PciIrqMsg) -> Result<u8, PciError> {
+        if !matches!(self.irq_type_mut(), Some(IrqType::Legacy)) {
+            return Err(PciError::PciIrqError(PciIrqError::IrqTypeUnmatch));
+        }
+        let irq_num = *self
+            .irq_vector_mut()
+            .and_then(|v| v.get(msg.irq_common_message.irq_index as usize))
+            .ok_or(PciError::PciIrqError(PciIrqError::InvalidIrqIndex(
+                msg.irq_common_message.irq_index,
+            )))?;
+        let common_msg = &msg.irq_common_message;
+
+        // INTx为低电平有效的电平触发中断，且可能与其他设备共享
+        irq_manager()
+            .request_irq(
+                irq_num,
+                common_msg.irq_name.clone(),
+                common_msg.irq_hander,
+                IrqHandleFlags::IRQF_SHARED | IrqHandleFlags::IRQF_TRIGGER_LOW,
+                Some(common_msg.dev_id.clone()),
+            )
+            .map_err(|e| {
+                error!(
+                    "Failed to request pci legacy irq {} for device {}, error: {:?}",
+                    irq_num.data(),
+                    &common_msg.irq_name,
+                    e
+                );
+                PciError::PciIrqError(PciIrqError::IrqNumOccupied(irq_num))
+            })?;
+        return Ok(0);
+    }
     /// @brief 进行PCI设备中断的安装(MSI)
     /// @param self PCI设备的可变引用
     /// @param msg PCI设备install中断时需要传递的共同参数
@@ -523,6 +786,12 @@ pub trait PciInterrupt: PciDeviceStructure {
                     if self.irq_vector_mut().unwrap().len() > irq_max_num as usize {
                         return Err(PciError::PciIrqError(PciIrqError::DeviceIrqOverflow));
                     }
+                    // MSI-X表只有irq_max_num项，超出的表项会写到表后面的MMIO上
+                    if msg.irq_common_message.irq_index >= irq_max_num {
+                        return Err(PciError::PciIrqError(PciIrqError::InvalidIrqIndex(
+                            msg.irq_common_message.irq_index,
+                        )));
+                    }
                     let irq_num =
                         self.irq_vector_mut().unwrap()[msg.irq_common_message.irq_index as usize];
 
@@ -610,6 +879,12 @@ pub trait PciInterrupt: PciDeviceStructure {
                 IrqType::Msi { .. } => {
                     return self.msi_uninstall();
                 }
+                IrqType::Legacy => {
+                    for irq in self.irq_vector_mut().unwrap() {
+                        irq_manager().free_irq(*irq, None);
+                    }
+                    return Ok(0);
+                }
                 IrqType::Unused => {
                     return Err(PciError::PciIrqError(PciIrqError::IrqNotInited));
                 }
@@ -962,6 +1237,141 @@ pub trait PciInterrupt: PciDeviceStructure {
         }
         return Err(PciError::PciIrqError(PciIrqError::PciDeviceNotSupportIrq));
     }
+    /// @brief 获取MSI-X表中某一项的虚拟地址
+    /// @param self PCI设备的可变引用
+    /// @param irq_index 中断的位置（在vec中的index和安装的index相同）
+    /// @return MSI-X表项的地址
+    fn msix_entry_vaddr(&mut self, irq_index: u16) -> Result<crate::mm::VirtAddr, PciError> {
+        let irq_type = *self
+            .irq_type_mut()
+            .ok_or(PciError::PciIrqError(PciIrqError::PciDeviceNotSupportIrq))?;
+        match irq_type {
+            IrqType::Msix {
+                msix_table_bar,
+                msix_table_offset,
+                ..
+            } => {
+                let installed = self.irq_vector_mut().map(|v| v.len()).unwrap_or(0);
+                if irq_index as usize >= installed {
+                    return Err(PciError::PciIrqError(PciIrqError::InvalidIrqIndex(
+                        irq_index,
+                    )));
+                }
+                let pcistandardbar = self
+                    .bar()
+                    .ok_or(PciError::PciIrqError(PciIrqError::PciBarNotInited))?;
+                let msix_bar = pcistandardbar.get_bar(msix_table_bar)?;
+                let vaddr = msix_bar
+                    .virtual_address()
+                    .ok_or(PciError::PciIrqError(PciIrqError::BarGetVaddrFailed))?
+                    + msix_table_offset as usize
+                    + irq_index as usize * size_of::<MsixEntry>();
+                return Ok(vaddr);
+            }
+            IrqType::Unused => Err(PciError::PciIrqError(PciIrqError::IrqNotInited)),
+            _ => Err(PciError::PciIrqError(PciIrqError::IrqTypeUnmatch)),
+        }
+    }
+    /// @brief 将MSI-X中断投递到指定的CPU上
+    /// @param self PCI设备的可变引用
+    /// @param irq_index 中断的位置（在vec中的index和安装的index相同）
+    /// @param cpu 目标CPU，必须是存在的CPU
+    /// @return 设置成功返回Ok(0)。系统中只有一个CPU时不做任何修改
+    fn irq_set_affinity(&mut self, irq_index: u16, cpu: ProcessorId) -> Result<u8, PciError> {
+        if smp_cpu_manager().present_cpus().get(cpu) != Some(true) {
+            return Err(PciError::PciIrqError(PciIrqError::InvalidCpu(cpu)));
+        }
+        let vaddr = self.msix_entry_vaddr(irq_index)?;
+        if smp_cpu_manager().present_cpus_count() <= 1 {
+            return Ok(0);
+        }
+
+        let msix_entry = NonNull::new(vaddr.data() as *mut MsixEntry).unwrap();
+        let msg_address = arch_msi_message_address(cpu.data() as u16);
+        let (_, msg_data) = msix_entry.read_message();
+        msix_entry_retarget(&msix_entry, msg_address, msg_data);
+        return Ok(0);
+    }
+    /// @brief 把MSI-X中断迁移到另一个CPU上，重写表项的Message Address和Message Data
+    /// @param self PCI设备的可变引用
+    /// @param irq_index 中断的位置（在vec中的index和安装的index相同）
+    /// @param new_cpu 目标CPU，必须是存在的CPU
+    /// @return 迁移成功返回Ok(0)。之后该中断只会投递到new_cpu上
+    fn migrate_irq(&mut self, irq_index: u16, new_cpu: ProcessorId) -> Result<u8, PciError> {
+        if smp_cpu_manager().present_cpus().get(new_cpu) != Some(true) {
+            return Err(PciError::PciIrqError(PciIrqError::InvalidCpu(new_cpu)));
+        }
+        let vaddr = self.msix_entry_vaddr(irq_index)?;
+        // msix_entry_vaddr已经检查过irq_index对应的中断已经安装
+        let irq_num = self.irq_vector_mut().unwrap()[irq_index as usize];
+
+        let msix_entry = NonNull::new(vaddr.data() as *mut MsixEntry).unwrap();
+        msix_entry_migrate(&msix_entry, irq_num.data() as u16, new_cpu);
+        return Ok(0);
+    }
+    /// @brief 把投递到departing上的MSI-X中断依次迁移到targets中的CPU上
+    /// @param self PCI设备的可变引用
+    /// @param departing 即将下线的CPU
+    /// @param targets 可以迁移到的CPU，不能包含departing
+    /// @return 被迁移的中断的数量
+    fn migrate_irqs_from(
+        &mut self,
+        departing: ProcessorId,
+        targets: &[ProcessorId],
+    ) -> Result<usize, PciError> {
+        let installed = self.irq_vector_mut().map(|v| v.len()).unwrap_or(0);
+        let mut entries = Vec::with_capacity(installed);
+        for irq_index in 0..installed as u16 {
+            let vaddr = self.msix_entry_vaddr(irq_index)?;
+            let irq_num = self.irq_vector_mut().unwrap()[irq_index as usize];
+            entries.push((
+                NonNull::new(vaddr.data() as *mut MsixEntry).unwrap(),
+                irq_num.data() as u16,
+            ));
+        }
+        return msix_migrate_entries(&entries, departing, targets).map_err(PciError::PciIrqError);
+    }
+    /// @brief 获取MSI-X中断当前被投递到的CPU
+    /// @param self PCI设备的可变引用
+    /// @param irq_index 中断的位置（在vec中的index和安装的index相同）
+    /// @return 目标CPU
+    fn irq_affinity(&mut self, irq_index: u16) -> Result<ProcessorId, PciError> {
+        let vaddr = self.msix_entry_vaddr(irq_index)?;
+        let msix_entry = NonNull::new(vaddr.data() as *mut MsixEntry).unwrap();
+        let msg_address = unsafe { volread!(msix_entry, msg_addr) };
+        return Ok(ProcessorId::new(
+            arch_msi_message_processor(msg_address) as u32
+        ));
+    }
+    /// @brief 把设备已安装的MSI-X中断依次分散到与设备位于同一NUMA节点的CPU上
+    /// @param self PCI设备的可变引用
+    /// @return 设置成功返回Ok(0)
+    fn irq_spread_affinity(&mut self) -> Result<u8, PciError> {
+        let local_cpus = pci_local_cpus(Some(self.common_header().bus_device_function));
+        return self.irq_spread_affinity_in(&local_cpus);
+    }
+    /// @brief 把设备已安装的MSI-X中断依次分散到指定的CPU上
+    /// @param self PCI设备的可变引用
+    /// @param cpus 可以使用的CPU，其中不存在的CPU会被忽略
+    /// @return 设置成功返回Ok(0)
+    fn irq_spread_affinity_in(&mut self, cpus: &CpuMask) -> Result<u8, PciError> {
+        let cpus: Vec<ProcessorId> = (cpus & smp_cpu_manager().present_cpus())
+            .iter_cpu()
+            .collect();
+        if cpus.is_empty() {
+            return Err(PciError::PciIrqError(PciIrqError::InvalidCpu(
+                ProcessorId::INVALID,
+            )));
+        }
+        if cpus.len() <= 1 {
+            return Ok(0);
+        }
+        let installed = self.irq_vector_mut().map(|v| v.len()).unwrap_or(0);
+        for irq_index in 0..installed {
+            self.irq_set_affinity(irq_index as u16, cpus[irq_index % cpus.len()])?;
+        }
+        return Ok(0);
+    }
     /// @brief 检查被挂起的中断是否在挂起的时候产生了
     /// @param self PCI设备的可变引用
     /// @param irq_index 中断的位置（在vec中的index和安装的index相同）
@@ -1077,3 +1487,450 @@ pub trait PciInterrupt: PciDeviceStructure {
 }
 /// PCI标准设备的msi/msix中断相关函数块
 impl PciInterrupt for PciDeviceStructureGeneralDevice {}
+
+/// 在全局的PCI设备链表中找到指定的标准设备，并对其执行`f`
+fn with_general_device<T>(
+    bdf: BusDeviceFunction,
+    f: impl FnOnce(&mut PciDeviceStructureGeneralDevice) -> Result<T, PciError>,
+) -> Result<T, SystemError> {
+    let r = PCI_DEVICE_LINKEDLIST
+        .with_device_mut(bdf, |d| d.as_standard_device_mut().map(f))
+        .flatten()
+        .ok_or(SystemError::ENODEV)?;
+    r.map_err(|e| match e {
+        PciError::PciIrqError(PciIrqError::InvalidCpu(_))
+        | PciError::PciIrqError(PciIrqError::InvalidIrqIndex(_)) => SystemError::EINVAL,
+        PciError::PciIrqError(PciIrqError::IrqTypeUnmatch)
+        | PciError::PciIrqError(PciIrqError::IrqNotInited)
+        | PciError::PciIrqError(PciIrqError::PciDeviceNotSupportIrq) => {
+            SystemError::EOPNOTSUPP_OR_ENOTSUP
+        }
+        _ => SystemError::EIO,
+    })
+}
+
+/// # 函数的功能
+/// 将pci设备的某个MSI-X中断投递到指定的CPU上
+///
+/// 注意：调用者不能持有PCI_DEVICE_LINKEDLIST的锁
+///
+/// ## 参数
+/// - `bdf`: 设备在pci总线上的地址
+/// - `irq_index`: 中断在设备MSI-X表中的位置
+/// - `cpu`: 目标CPU
+///
+/// ## 返回值
+/// - Ok(()): 设置成功（单CPU系统上不做任何修改）
+/// - Err(SystemError::EINVAL): CPU不存在，或者中断尚未安装
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 设备没有使用MSI-X中断
+/// - Err(SystemError::ENODEV): 设备不存在
+#[allow(dead_code)]
+pub fn pci_irq_set_affinity(
+    bdf: BusDeviceFunction,
+    irq_index: u16,
+    cpu: ProcessorId,
+) -> Result<(), SystemError> {
+    with_general_device(bdf, |dev| dev.irq_set_affinity(irq_index, cpu)).map(|_| ())
+}
+
+/// # 函数的功能
+/// 把pci设备已安装的MSI-X中断依次分散到多个CPU上
+///
+/// 注意：调用者不能持有PCI_DEVICE_LINKEDLIST的锁
+///
+/// ## 参数
+/// - `bdf`: 设备在pci总线上的地址
+/// - `cpus`: 可以使用的CPU，为None时使用与设备位于同一NUMA节点的CPU(local_cpus)
+///
+/// ## 返回值
+/// - Err(SystemError::EINVAL): `cpus`中没有存在的CPU
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 设备没有使用MSI-X中断
+/// - Err(SystemError::ENODEV): 设备不存在
+#[allow(dead_code)]
+pub fn pci_irq_spread_affinity(
+    bdf: BusDeviceFunction,
+    cpus: Option<&CpuMask>,
+) -> Result<(), SystemError> {
+    with_general_device(bdf, |dev| match cpus {
+        Some(cpus) => dev.irq_spread_affinity_in(cpus),
+        None => dev.irq_spread_affinity(),
+    })
+    .map(|_| ())
+}
+
+/// # 函数的功能
+/// 把所有pci设备投递到`cpu`上的MSI-X中断迁移到其他存在的CPU上
+///
+/// CPU下线时，需要在该CPU停止处理中断之前调用，否则投递到它上面的中断会丢失。
+/// 新的目标优先选择与设备位于同一NUMA节点的CPU，同一设备的多个中断依次分散到这些CPU上
+///
+/// 注意：调用者不能持有PCI_DEVICE_LINKEDLIST的锁
+///
+/// ## 参数
+/// - `cpu`: 即将下线的CPU
+///
+/// ## 返回值
+/// - Ok(n): 被迁移的中断的数量
+/// - Err(SystemError::EINVAL): 除了`cpu`之外没有其他存在的CPU
+pub fn pci_irq_migrate_from_cpu(cpu: ProcessorId) -> Result<usize, SystemError> {
+    let present_cpus = smp_cpu_manager().present_cpus();
+    let bdfs = PCI_DEVICE_LINKEDLIST.bus_device_functions(|d| d.as_standard_device().is_some());
+    let mut migrated = 0;
+    for bdf in bdfs {
+        let targets = msix_migration_targets(&pci_local_cpus(Some(bdf)), present_cpus, cpu);
+        let r = with_general_device(bdf, |dev| {
+            if !matches!(dev.irq_type_mut(), Some(IrqType::Msix { .. })) {
+                return Ok(0);
+            }
+            dev.migrate_irqs_from(cpu, &targets)
+        });
+        match r {
+            Ok(n) => migrated += n,
+            // 设备已经被移除
+            Err(SystemError::ENODEV) => {}
+            Err(e) => {
+                error!(
+                    "Failed to migrate msix irqs of pci device {} away from cpu {}: {:?}",
+                    bdf,
+                    cpu.data(),
+                    e
+                );
+                return Err(e);
+            }
+        }
+    }
+    return Ok(migrated);
+}
+
+/// CPU下线之前把投递到它上面的MSI-X中断迁移走
+#[derive(Debug)]
+struct PciIrqCpuHpNotifier;
+
+impl NotifierBlock<CpuHpEvent, ProcessorId> for PciIrqCpuHpNotifier {
+    fn notifier_call(&self, action: CpuHpEvent, data: Option<&ProcessorId>) -> i32 {
+        match (action, data) {
+            (CpuHpEvent::DownPrepare, Some(cpu)) => match pci_irq_migrate_from_cpu(*cpu) {
+                Ok(_) => 0,
+                Err(e) => e.to_posix_errno(),
+            },
+            _ => 0,
+        }
+    }
+
+    fn priority(&self) -> i32 {
+        0
+    }
+}
+
+/// 注册CPU热插拔的通知，CPU下线时迁移pci设备的MSI-X中断
+pub fn pci_irq_cpuhp_init() -> Result<(), SystemError> {
+    smp_cpu_manager().register_cpuhp_notifier(Arc::new(PciIrqCpuHpNotifier))
+}
+
+/// # 函数的功能
+/// 获取pci设备已安装的每个MSI-X中断当前被投递到的CPU
+///
+/// ## 返回值
+/// - Ok(Vec<ProcessorId>): 下标为中断在MSI-X表中的位置
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 设备没有使用MSI-X中断
+/// - Err(SystemError::ENODEV): 设备不存在
+pub fn pci_irq_affinity(bdf: BusDeviceFunction) -> Result<Vec<ProcessorId>, SystemError> {
+    with_general_device(bdf, |dev| {
+        let installed = dev.irq_vector_mut().map(|v| v.len()).unwrap_or(0);
+        (0..installed as u16)
+            .map(|i| dev.irq_affinity(i))
+            .collect::<Result<Vec<_>, _>>()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::{Cell, RefCell};
+
+    use super::*;
+    use crate::driver::pci::pci::MemoryBarType;
+
+    fn mem_bar(size: u64) -> Option<PciBarRegion> {
+        Some(PciBarRegion::Memory {
+            address_type: MemoryBarType::Width32,
+            prefetchable: false,
+            address: 0xfe00_0000,
+            size,
+        })
+    }
+
+    #[test]
+    fn test_parse_msix_capability() {
+        // 8个表项，表在BAR1的0x2000处，PBA在BAR1的0x3000处
+        let layout = PciMsixLayout::parse([0x0007_0011, 0x2000 | 1, 0x3000 | 1]);
+        assert_eq!(
+            layout,
+            PciMsixLayout {
+                table_size: 8,
+                table_bir: 1,
+                table_offset: 0x2000,
+                pba_bir: 1,
+                pba_offset: 0x3000,
+            }
+        );
+        assert_eq!(layout.table_len(), 8 * 16);
+        assert_eq!(layout.pba_len(), 8);
+
+        let mut regions = [None; 6];
+        regions[0] = Some(PciBarRegion::IO {
+            address: 0xc000,
+            size: 0x40,
+        });
+        regions[1] = mem_bar(0x4000);
+        assert_eq!(layout.validate(&regions), Ok(()));
+
+        // 65个表项的PBA需要两个qword
+        let layout = PciMsixLayout::parse([0x0040_0011, 4, 0x800 | 4]);
+        assert_eq!(layout.table_size, 65);
+        assert_eq!(layout.pba_len(), 16);
+    }
+
+    #[test]
+    fn test_invalid_msix_layout() {
+        let mut regions = [None; 6];
+        regions[0] = Some(PciBarRegion::IO {
+            address: 0xc000,
+            size: 0x1000,
+        });
+        regions[1] = mem_bar(0x2000);
+
+        // 表的末尾超出了BAR
+        let layout = PciMsixLayout::parse([0x0007_0011, 0x1f90 | 1, 0x1000 | 1]);
+        assert_eq!(
+            layout.validate(&regions),
+            Err(PciIrqError::InvalidMsixLayout)
+        );
+        // 表在I/O BAR中
+        let layout = PciMsixLayout::parse([0x0007_0011, 0, 0x1000 | 1]);
+        assert_eq!(
+            layout.validate(&regions),
+            Err(PciIrqError::InvalidMsixLayout)
+        );
+        // PBA所在的BAR没有使用
+        let layout = PciMsixLayout::parse([0x0007_0011, 1, 0x1000 | 2]);
+        assert_eq!(
+            layout.validate(&regions),
+            Err(PciIrqError::InvalidMsixLayout)
+        );
+        // BIR 6和7是保留值
+        let layout = PciMsixLayout::parse([0x0007_0011, 6, 0x1000 | 1]);
+        assert_eq!(
+            layout.validate(&regions),
+            Err(PciIrqError::InvalidMsixLayout)
+        );
+    }
+
+    /// 模拟的MSI-X表项以及发送中断的设备
+    ///
+    /// 没有被屏蔽时，中断按照表项当前的地址投递，记录(目标CPU, Message Data)；
+    /// 被屏蔽时设置Pending位，解除屏蔽后再投递
+    struct MockMsixEntry {
+        control: Cell<u32>,
+        msg_addr: Cell<u32>,
+        msg_data: Cell<u32>,
+        pending: Cell<bool>,
+        /// 写入地址之后、写入数据之前设备发送一次中断
+        raise_during_write: Cell<bool>,
+        delivered: RefCell<Vec<(u16, u32)>>,
+    }
+
+    impl MockMsixEntry {
+        fn new(cpu: u16, vector: u16) -> Self {
+            Self {
+                control: Cell::new(0),
+                msg_addr: Cell::new(arch_msi_message_address(cpu)),
+                msg_data: Cell::new(arch_msi_message_data(vector, cpu, TriggerMode::EdgeTrigger)),
+                pending: Cell::new(false),
+                raise_during_write: Cell::new(false),
+                delivered: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn raise(&self) {
+            if self.control.get() & PCI_MSIX_ENTRY_CTRL_MASKBIT != 0 {
+                self.pending.set(true);
+                return;
+            }
+            let cpu = arch_msi_message_processor(self.msg_addr.get());
+            self.delivered.borrow_mut().push((cpu, self.msg_data.get()));
+        }
+
+        fn take_delivered(&self) -> Vec<(u16, u32)> {
+            core::mem::take(&mut *self.delivered.borrow_mut())
+        }
+    }
+
+    impl MsixEntryAccess for MockMsixEntry {
+        fn read_control(&self) -> u32 {
+            self.control.get()
+        }
+
+        fn write_control(&self, control: u32) {
+            self.control.set(control);
+            if control & PCI_MSIX_ENTRY_CTRL_MASKBIT == 0 && self.pending.replace(false) {
+                self.raise();
+            }
+        }
+
+        fn read_message(&self) -> (u32, u32) {
+            (self.msg_addr.get(), self.msg_data.get())
+        }
+
+        fn write_message(&self, msg_addr: u32, msg_data: u32) {
+            self.msg_addr.set(msg_addr);
+            if self.raise_during_write.get() {
+                self.raise();
+            }
+            self.msg_data.set(msg_data);
+        }
+    }
+
+    fn retarget(entry: &MockMsixEntry, cpu: u16, vector: u16) {
+        let trigger = arch_msi_message_trigger(entry.read_message().1);
+        msix_entry_retarget(
+            entry,
+            arch_msi_message_address(cpu),
+            arch_msi_message_data(vector, cpu, trigger),
+        );
+    }
+
+    #[test]
+    fn test_migrate_dispatches_on_new_cpu() {
+        let entry = MockMsixEntry::new(1, 0x40);
+        let data = entry.msg_data.get();
+        entry.raise();
+        assert_eq!(entry.take_delivered(), [(1, data)]);
+
+        retarget(&entry, 2, 0x40);
+        // 表项恢复为没有屏蔽的状态，之后的中断投递到新的CPU
+        assert_eq!(entry.control.get(), 0);
+        entry.raise();
+        entry.raise();
+        assert_eq!(entry.take_delivered(), [(2, data), (2, data)]);
+    }
+
+    #[test]
+    fn test_migrate_no_lost_or_torn_irq() {
+        let entry = MockMsixEntry::new(1, 0x41);
+        let data = entry.msg_data.get();
+        // 重写过程中产生的中断被挂起，解除屏蔽后按照新的地址投递，
+        // 不会用新地址和旧数据的组合投递，也不会丢失
+        entry.raise_during_write.set(true);
+        retarget(&entry, 3, 0x41);
+        assert_eq!(entry.take_delivered(), [(3, data)]);
+        assert!(!entry.pending.get());
+    }
+
+    #[test]
+    fn test_migrate_keeps_masked_entry_masked() {
+        let entry = MockMsixEntry::new(1, 0x42);
+        entry.control.set(PCI_MSIX_ENTRY_CTRL_MASKBIT);
+        entry.raise();
+        retarget(&entry, 2, 0x42);
+        // 驱动屏蔽的中断仍然处于屏蔽状态，直到驱动解除屏蔽
+        assert_eq!(entry.control.get(), PCI_MSIX_ENTRY_CTRL_MASKBIT);
+        assert!(entry.take_delivered().is_empty());
+        entry.write_control(0);
+        assert_eq!(entry.take_delivered(), [(2, entry.msg_data.get())]);
+    }
+
+    #[test]
+    fn test_migrate_keeps_trigger_mode() {
+        let entry = MockMsixEntry::new(0, 0x43);
+        entry
+            .msg_data
+            .set(arch_msi_message_data(0x43, 0, TriggerMode::AssertLow));
+        retarget(&entry, 1, 0x43);
+        assert!(matches!(
+            arch_msi_message_trigger(entry.msg_data.get()),
+            TriggerMode::AssertLow
+        ));
+        assert_eq!(arch_msi_message_processor(entry.msg_addr.get()), 1);
+    }
+
+    #[test]
+    fn test_migration_targets() {
+        let mut present = CpuMask::new();
+        let mut local = CpuMask::new();
+        for cpu in 0..4 {
+            present.set(ProcessorId::new(cpu), true);
+        }
+        local.set(ProcessorId::new(2), true);
+        local.set(ProcessorId::new(3), true);
+        let ids = |v: Vec<ProcessorId>| v.iter().map(|c| c.data()).collect::<Vec<_>>();
+
+        // 优先选择同一节点上的其他CPU
+        assert_eq!(
+            ids(msix_migration_targets(
+                &local,
+                &present,
+                ProcessorId::new(2)
+            )),
+            [3]
+        );
+        // 同一节点上没有其他CPU时，选择其他存在的CPU
+        local.set(ProcessorId::new(2), false);
+        assert_eq!(
+            ids(msix_migration_targets(
+                &local,
+                &present,
+                ProcessorId::new(3)
+            )),
+            [0, 1, 2]
+        );
+        let only = CpuMask::from_cpu(ProcessorId::new(0));
+        assert!(msix_migration_targets(&local, &only, ProcessorId::new(0)).is_empty());
+    }
+
+    #[test]
+    fn test_migrate_from_cpu_dispatches_on_new_cpu() {
+        let mut present = CpuMask::new();
+        for cpu in 0..4 {
+            present.set(ProcessorId::new(cpu), true);
+        }
+        let mut local = CpuMask::new();
+        local.set(ProcessorId::new(1), true);
+        local.set(ProcessorId::new(2), true);
+        local.set(ProcessorId::new(3), true);
+        let departing = ProcessorId::new(1);
+        let entries = [
+            (MockMsixEntry::new(1, 0x50), 0x50),
+            (MockMsixEntry::new(0, 0x51), 0x51),
+            (MockMsixEntry::new(1, 0x52), 0x52),
+            (MockMsixEntry::new(1, 0x53), 0x53),
+        ];
+        for (entry, _) in entries.iter() {
+            entry.raise();
+            entry.take_delivered();
+        }
+
+        let targets = msix_migration_targets(&local, &present, departing);
+        assert_eq!(msix_migrate_entries(&entries, departing, &targets), Ok(3));
+
+        // 下线CPU上的中断依次分散到同一节点上的其他CPU，其他中断不受影响
+        for ((entry, vector), cpu) in entries.iter().zip([2, 0, 3, 2]) {
+            entry.raise();
+            assert_eq!(
+                entry.take_delivered(),
+                [(
+                    cpu,
+                    arch_msi_message_data(*vector, cpu, TriggerMode::EdgeTrigger)
+                )]
+            );
+        }
+        // 再次迁移时没有需要迁移的中断
+        assert_eq!(msix_migrate_entries(&entries, departing, &targets), Ok(0));
+        // 没有其他CPU可以迁移到时返回错误，表项保持不变
+        let departing = ProcessorId::new(2);
+        assert_eq!(
+            msix_migrate_entries(&entries, departing, &[]),
+            Err(PciIrqError::InvalidCpu(departing))
+        );
+        assert_eq!(arch_msi_message_processor(entries[0].0.msg_addr.get()), 2);
+    }
+}
@@ -0,0 +1,297 @@
+//! PCI Express Capability中的Device Control寄存器
+//!
+//! 用于调整设备发起DMA时的行为：Relaxed Ordering、No Snoop，以及Max Payload Size(MPS)
+//! 和Max Read Request Size(MRRS)。不是PCIe的设备没有这个capability，相关操作返回EOPNOTSUPP。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/pci.c#5914
+
+use system_error::SystemError;
+
+use super::{
+    config_space::{
+        pci_capability_fits, PciCapabilityWalk, PciConfigAccess, PciConfigSource, PciFunctionConfig,
+    },
+    pci::{pci_first_capability, BusDeviceFunction, Status},
+};
+
+/// PCI Express Capability ID
+pub const PCI_CAP_ID_EXP: u8 = 0x10;
+
+/// PCI Express Capabilities寄存器：capability的版本
+const PCI_EXP_FLAGS_VERS: u16 = 0x000f;
+/// PCI Express Capabilities寄存器：设备/端口的类型
+const PCI_EXP_FLAGS_TYPE: u16 = 0x00f0;
+/// Device Capabilities寄存器相对于capability的偏移
+const PCI_EXP_DEVCAP: u8 = 4;
+/// Device Capabilities寄存器：支持的最大payload
+const PCI_EXP_DEVCAP_PAYLOAD: u32 = 0x7;
+/// Device Control寄存器相对于capability的偏移，它的高16位是Device Status寄存器
+const PCI_EXP_DEVCTL: u8 = 8;
+/// 需要访问的capability的长度，到Device Control/Device Status为止
+const PCI_EXP_CAP_LEN: u16 = 12;
+/// Device Control寄存器：允许Relaxed Ordering
+pub const PCI_EXP_DEVCTL_RELAX_EN: u16 = 0x0010;
+/// Device Control寄存器：Max Payload Size
+const PCI_EXP_DEVCTL_PAYLOAD: u16 = 0x00e0;
+const PCI_EXP_DEVCTL_PAYLOAD_SHIFT: u16 = 5;
+/// Device Control寄存器：允许No Snoop
+pub const PCI_EXP_DEVCTL_NOSNOOP_EN: u16 = 0x0800;
+/// Device Control寄存器：Max Read Request Size
+pub const PCI_EXP_DEVCTL_READRQ: u16 = 0x7000;
+const PCI_EXP_DEVCTL_READRQ_SHIFT: u16 = 12;
+
+/// MPS/MRRS的编码n表示128 << n字节，合法的编码为0~5
+const PCIE_SIZE_MIN: u16 = 128;
+const PCIE_SIZE_MAX_ENCODING: u16 = 5;
+
+/// 把MPS/MRRS的编码转换为字节数，保留的编码按最大值处理
+fn pcie_size_decode(encoding: u16) -> u16 {
+    PCIE_SIZE_MIN << encoding.min(PCIE_SIZE_MAX_ENCODING)
+}
+
+/// 把字节数转换为MPS/MRRS的编码
+///
+/// ## 返回值
+/// - Err(SystemError::EINVAL): 不是128~4096之间的2的幂
+fn pcie_size_encode(bytes: u16) -> Result<u16, SystemError> {
+    if !bytes.is_power_of_two() || bytes < PCIE_SIZE_MIN {
+        return Err(SystemError::EINVAL);
+    }
+    let encoding = (bytes / PCIE_SIZE_MIN).trailing_zeros() as u16;
+    if encoding > PCIE_SIZE_MAX_ENCODING {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(encoding)
+}
+
+/// # 结构功能
+/// 从PCI Express Capability的前三个双字中解析出的信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcieDeviceControl {
+    /// PCI Express Capabilities寄存器
+    pub flags: u16,
+    /// Device Capabilities寄存器
+    pub devcap: u32,
+    /// Device Control寄存器
+    pub devctl: u16,
+}
+
+impl PcieDeviceControl {
+    /// # 函数的功能
+    /// 从capability的前三个双字解析
+    ///
+    /// ## 参数
+    /// - `regs`: 依次为capability头(包含PCI Express Capabilities寄存器)、Device Capabilities、
+    ///   Device Control/Device Status
+    pub fn decode(regs: [u32; 3]) -> Self {
+        Self {
+            flags: (regs[0] >> 16) as u16,
+            devcap: regs[1],
+            devctl: regs[2] as u16,
+        }
+    }
+
+    /// capability的版本
+    pub fn version(&self) -> u8 {
+        (self.flags & PCI_EXP_FLAGS_VERS) as u8
+    }
+
+    /// 设备/端口的类型，例如0表示PCIe端点，4表示根端口
+    pub fn port_type(&self) -> u8 {
+        ((self.flags & PCI_EXP_FLAGS_TYPE) >> 4) as u8
+    }
+
+    /// 设备支持的最大payload(字节)
+    pub fn max_payload_supported(&self) -> u16 {
+        pcie_size_decode((self.devcap & PCI_EXP_DEVCAP_PAYLOAD) as u16)
+    }
+
+    /// 当前的Max Payload Size(字节)
+    pub fn max_payload(&self) -> u16 {
+        pcie_size_decode((self.devctl & PCI_EXP_DEVCTL_PAYLOAD) >> PCI_EXP_DEVCTL_PAYLOAD_SHIFT)
+    }
+
+    /// 当前的Max Read Request Size(字节)
+    pub fn max_read_request(&self) -> u16 {
+        pcie_size_decode((self.devctl & PCI_EXP_DEVCTL_READRQ) >> PCI_EXP_DEVCTL_READRQ_SHIFT)
+    }
+
+    pub fn relaxed_ordering(&self) -> bool {
+        self.devctl & PCI_EXP_DEVCTL_RELAX_EN != 0
+    }
+
+    pub fn no_snoop(&self) -> bool {
+        self.devctl & PCI_EXP_DEVCTL_NOSNOOP_EN != 0
+    }
+}
+
+/// 配置空间中Status寄存器的偏移
+const PCI_STATUS: u16 = 0x06;
+/// 配置空间中第一个capability的指针的偏移
+const PCI_CAPABILITY_LIST: u16 = 0x34;
+
+/// 查找配置空间中的PCI Express Capability
+///
+/// ## 返回值
+/// - Err(EOPNOTSUPP_OR_ENOTSUP): 设备不是PCIe设备
+fn pcie_capability(cfg: &dyn PciConfigSource) -> Result<u8, SystemError> {
+    let status = Status::from_bits_truncate(cfg.read_u16(PCI_STATUS).unwrap_or(0));
+    let first = pci_first_capability(status, cfg.read_u8(PCI_CAPABILITY_LIST).unwrap_or(0));
+    PciCapabilityWalk::new(cfg, first)
+        .map_while(Result::ok)
+        .find(|cap| cap.id == PCI_CAP_ID_EXP && pci_capability_fits(cap.offset, PCI_EXP_CAP_LEN))
+        .map(|cap| cap.offset)
+        .ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+}
+
+/// 读取配置空间中偏移为`offset`的dword
+fn pcie_read_dword(cfg: &dyn PciConfigSource, offset: u8) -> Result<u32, SystemError> {
+    cfg.read_u32(offset.into()).map_err(|_| SystemError::EIO)
+}
+
+/// # 函数的功能
+/// 读取设备的PCI Express Capability
+///
+/// ## 返回值
+/// - Err(EOPNOTSUPP_OR_ENOTSUP): 设备不是PCIe设备
+pub fn pcie_device_control(
+    bus_device_function: BusDeviceFunction,
+) -> Result<PcieDeviceControl, SystemError> {
+    pcie_device_control_in(&PciFunctionConfig::new(bus_device_function))
+}
+
+/// # 函数的功能
+/// 从配置空间`cfg`中读取PCI Express Capability
+///
+/// ## 返回值
+/// - Err(EOPNOTSUPP_OR_ENOTSUP): 设备不是PCIe设备
+pub fn pcie_device_control_in(cfg: &dyn PciConfigSource) -> Result<PcieDeviceControl, SystemError> {
+    let cap = pcie_capability(cfg)?;
+    let regs = [
+        pcie_read_dword(cfg, cap)?,
+        pcie_read_dword(cfg, cap + PCI_EXP_DEVCAP)?,
+        pcie_read_dword(cfg, cap + PCI_EXP_DEVCTL)?,
+    ];
+    Ok(PcieDeviceControl::decode(regs))
+}
+
+/// 修改Device Control寄存器：先清除`clear`中的位，再设置`set`中的位
+fn pcie_devctl_update(cfg: &dyn PciConfigAccess, clear: u16, set: u16) -> Result<(), SystemError> {
+    let offset = pcie_capability(cfg)? + PCI_EXP_DEVCTL;
+    let devctl = pcie_read_dword(cfg, offset)? as u16;
+    let new = (devctl & !clear) | set;
+    if new != devctl {
+        // 高16位是Device Status寄存器，其中的位写1清除，因此写回0
+        cfg.write_dword(offset.into(), new as u32);
+    }
+    Ok(())
+}
+
+/// # 函数的功能
+/// 允许或禁止设备发起Relaxed Ordering的请求
+///
+/// ## 参数
+/// - `cfg`: 设备的配置空间，例如[`PciFunctionConfig`]
+///
+/// ## 返回值
+/// - Err(EOPNOTSUPP_OR_ENOTSUP): 设备不是PCIe设备
+pub fn pcie_set_relaxed_ordering(
+    cfg: &dyn PciConfigAccess,
+    enable: bool,
+) -> Result<(), SystemError> {
+    if enable {
+        pcie_devctl_update(cfg, 0, PCI_EXP_DEVCTL_RELAX_EN)
+    } else {
+        pcie_devctl_update(cfg, PCI_EXP_DEVCTL_RELAX_EN, 0)
+    }
+}
+
+/// # 函数的功能
+/// 允许或禁止设备发起No Snoop的请求
+///
+/// ## 参数
+/// - `cfg`: 设备的配置空间，例如[`PciFunctionConfig`]
+///
+/// ## 返回值
+/// - Err(EOPNOTSUPP_OR_ENOTSUP): 设备不是PCIe设备
+pub fn pcie_set_no_snoop(cfg: &dyn PciConfigAccess, enable: bool) -> Result<(), SystemError> {
+    if enable {
+        pcie_devctl_update(cfg, 0, PCI_EXP_DEVCTL_NOSNOOP_EN)
+    } else {
+        pcie_devctl_update(cfg, PCI_EXP_DEVCTL_NOSNOOP_EN, 0)
+    }
+}
+
+/// # 函数的功能
+/// 设置设备的Max Read Request Size
+///
+/// ## 参数
+/// - `cfg`: 设备的配置空间，例如[`PciFunctionConfig`]
+/// - `bytes`: 128~4096之间的2的幂
+///
+/// ## 返回值
+/// - Err(EINVAL): `bytes`不合法
+/// - Err(EOPNOTSUPP_OR_ENOTSUP): 设备不是PCIe设备
+pub fn pcie_set_max_read_request(cfg: &dyn PciConfigAccess, bytes: u16) -> Result<(), SystemError> {
+    let encoding = pcie_size_encode(bytes)?;
+    pcie_devctl_update(
+        cfg,
+        PCI_EXP_DEVCTL_READRQ,
+        encoding << PCI_EXP_DEVCTL_READRQ_SHIFT,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 合成的配置空间：capability链为 PM(0x40) -> PCIe(0x50)
+    fn synthetic_config_space() -> [u32; 64] {
+        let mut cfg = [0u32; 64];
+        // PM capability，next指向0x50
+        cfg[0x40 / 4] = 0x0003_5001;
+        // PCIe capability v2，端点，没有下一个capability
+        cfg[0x50 / 4] = 0x0002_0010;
+        // 支持最大512字节的payload
+        cfg[0x54 / 4] = 0x0000_0002;
+        // MRRS 512，MPS 256，允许Relaxed Ordering；Device Status中有一个待清除的位
+        cfg[0x58 / 4] = 0x0004_2030;
+        cfg
+    }
+
+    #[test]
+    fn test_decode_synthetic_capability() {
+        let cfg = synthetic_config_space();
+        // 沿着capability链找到PCIe capability
+        let mut offset = 0x40usize;
+        while cfg[offset / 4] as u8 != PCI_CAP_ID_EXP {
+            offset = ((cfg[offset / 4] >> 8) & 0xff) as usize;
+            assert_ne!(offset, 0);
+        }
+        assert_eq!(offset, 0x50);
+
+        let ctl =
+            PcieDeviceControl::decode([cfg[offset / 4], cfg[offset / 4 + 1], cfg[offset / 4 + 2]]);
+        assert_eq!(ctl.version(), 2);
+        assert_eq!(ctl.port_type(), 0);
+        assert_eq!(ctl.max_payload_supported(), 512);
+        assert_eq!(ctl.max_payload(), 256);
+        assert_eq!(ctl.max_read_request(), 512);
+        assert!(ctl.relaxed_ordering());
+        assert!(!ctl.no_snoop());
+    }
+
+    #[test]
+    fn test_size_encoding() {
+        assert_eq!(pcie_size_encode(128), Ok(0));
+        assert_eq!(pcie_size_encode(4096), Ok(5));
+        assert_eq!(pcie_size_encode(64), Err(SystemError::EINVAL));
+        assert_eq!(pcie_size_encode(8192), Err(SystemError::EINVAL));
+        assert_eq!(pcie_size_encode(384), Err(SystemError::EINVAL));
+        for encoding in 0..=PCIE_SIZE_MAX_ENCODING {
+            assert_eq!(pcie_size_encode(pcie_size_decode(encoding)), Ok(encoding));
+        }
+        // 保留的编码按4096处理
+        assert_eq!(pcie_size_decode(7), 4096);
+    }
+}
@@ -0,0 +1,329 @@
+//! PCI电源管理
+//!
+//! 通过PCI Power Management Capability查询和切换设备的电源状态(D0-D3hot)。
+//! 设备进入D3hot之后可能丢失配置(例如BAR和命令寄存器)，因此挂起之前保存配置空间的header，
+//! 恢复之后写回(见[`PciPmState`])
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/pci.c#1299
+
+use core::fmt::Display;
+
+use log::warn;
+use system_error::SystemError;
+
+use crate::{
+    libs::spinlock::SpinLock,
+    time::{sleep::nanosleep, PosixTimeSpec},
+};
+
+use super::{
+    config_space::{pci_capability_fits, PciConfigAccess, PciConfigSource},
+    pci::{capabilities_offset, BusDeviceFunction, CapabilityInfo, CapabilityIterator},
+    root::pci_root_0,
+};
+
+/// PCI Power Management Capability ID
+pub const PCI_CAP_ID_PM: u8 = 0x01;
+
+/// PMC寄存器：支持D1状态
+const PCI_PM_CAP_D1: u16 = 1 << 9;
+/// PMC寄存器：支持D2状态
+const PCI_PM_CAP_D2: u16 = 1 << 10;
+/// PMCSR寄存器相对于capability的偏移
+const PCI_PM_CTRL: u8 = 4;
+/// capability的长度
+const PCI_PM_SIZEOF: u16 = 8;
+/// PMCSR寄存器中电源状态的掩码
+const PCI_PM_CTRL_STATE_MASK: u32 = 0x3;
+/// PMCSR寄存器中的PME_Status位，写1清除
+const PCI_PM_CTRL_PME_STATUS: u32 = 0x8000;
+
+/// 挂起时保存的配置空间header的dword数（前64字节）
+const PCI_PM_SAVED_DWORDS: usize = 16;
+
+/// 进入或离开D3hot状态后需要等待的时间（纳秒）
+const PCI_PM_D3HOT_WAIT_NS: i64 = 10_000_000;
+/// 进入或离开D2状态后需要等待的时间（纳秒）
+const PCI_PM_D2_DELAY_NS: i64 = 200_000;
+
+/// PCI设备的电源状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PciPowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+impl PciPowerState {
+    fn from_pmcsr(pmcsr: u32) -> Self {
+        match pmcsr & PCI_PM_CTRL_STATE_MASK {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+
+    /// 切换到这个状态时写入PMCSR的值
+    ///
+    /// PME_Status是写1清除的，不能把读到的值原样写回，否则会清除设备挂起的PME
+    fn to_pmcsr(self, pmcsr: u32) -> u32 {
+        (pmcsr & !(PCI_PM_CTRL_STATE_MASK | PCI_PM_CTRL_PME_STATUS)) | self as u32
+    }
+}
+
+impl Display for PciPowerState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let s = match self {
+            PciPowerState::D0 => "D0",
+            PciPowerState::D1 => "D1",
+            PciPowerState::D2 => "D2",
+            PciPowerState::D3Hot => "D3hot",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 查找设备的Power Management Capability
+fn pci_pm_capability(bus_device_function: BusDeviceFunction) -> Option<CapabilityInfo> {
+    CapabilityIterator::new(
+        bus_device_function,
+        capabilities_offset(bus_device_function),
+    )
+    .find(|cap| cap.id == PCI_CAP_ID_PM && pci_capability_fits(cap.offset, PCI_PM_SIZEOF))
+}
+
+/// 获取设备当前的电源状态
+///
+/// 没有Power Management Capability的设备总是处于D0状态
+pub fn pci_power_state(bus_device_function: BusDeviceFunction) -> PciPowerState {
+    match pci_pm_capability(bus_device_function) {
+        Some(cap) => {
+            let pmcsr =
+                pci_root_0().read_config(bus_device_function, (cap.offset + PCI_PM_CTRL).into());
+            PciPowerState::from_pmcsr(pmcsr)
+        }
+        None => PciPowerState::D0,
+    }
+}
+
+/// 切换设备的电源状态
+///
+/// 可以从任意状态回到D0，但是处于低功耗状态时只能切换到更深的状态。
+/// 例如可以从D1切换到D3hot，但不能从D3hot直接切换到D1。
+///
+/// ## 返回值
+///
+/// - Ok(()): 切换成功（或设备已经处于该状态）
+/// - Err(EOPNOTSUPP_OR_ENOTSUP): 设备没有Power Management Capability
+/// - Err(EIO): 设备不支持目标状态
+/// - Err(EINVAL): 不允许的状态转换
+pub fn pci_set_power_state(
+    bus_device_function: BusDeviceFunction,
+    state: PciPowerState,
+) -> Result<(), SystemError> {
+    let cap = pci_pm_capability(bus_device_function).ok_or(SystemError::EOPNOTSUPP_OR_ENOTSUP)?;
+    let pmc = cap.private_header;
+    if (state == PciPowerState::D1 && pmc & PCI_PM_CAP_D1 == 0)
+        || (state == PciPowerState::D2 && pmc & PCI_PM_CAP_D2 == 0)
+    {
+        return Err(SystemError::EIO);
+    }
+
+    let ctrl_offset: u16 = (cap.offset + PCI_PM_CTRL).into();
+    let pmcsr = pci_root_0().read_config(bus_device_function, ctrl_offset);
+    let current = PciPowerState::from_pmcsr(pmcsr);
+    if current == state {
+        return Ok(());
+    }
+
+    if state != PciPowerState::D0 && current != PciPowerState::D0 && current > state {
+        warn!(
+            "pci {}: invalid power transition (from {} to {})",
+            bus_device_function, current, state
+        );
+        return Err(SystemError::EINVAL);
+    }
+
+    pci_root_0().write_config(bus_device_function, ctrl_offset, state.to_pmcsr(pmcsr));
+
+    // 状态切换后，设备需要一段时间才能恢复可访问
+    let delay = if state == PciPowerState::D3Hot || current == PciPowerState::D3Hot {
+        PCI_PM_D3HOT_WAIT_NS
+    } else if state == PciPowerState::D2 || current == PciPowerState::D2 {
+        PCI_PM_D2_DELAY_NS
+    } else {
+        0
+    };
+    if delay != 0 {
+        nanosleep(PosixTimeSpec::new(0, delay)).ok();
+    }
+
+    let new_state = pci_power_state(bus_device_function);
+    if new_state != state {
+        warn!(
+            "pci {}: refused to change power state from {} to {}, now in {}",
+            bus_device_function, current, state, new_state
+        );
+        return Err(SystemError::EIO);
+    }
+
+    return Ok(());
+}
+
+/// # 结构功能
+/// 设备挂起时保存的配置空间
+///
+/// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/pci.c#1659
+#[derive(Debug)]
+pub struct PciPmState {
+    saved: SpinLock<Option<[u32; PCI_PM_SAVED_DWORDS]>>,
+}
+
+impl PciPmState {
+    pub const fn new() -> Self {
+        Self {
+            saved: SpinLock::new(None),
+        }
+    }
+
+    /// # 函数的功能
+    /// 保存配置空间的header，覆盖之前保存的内容
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENODEV): 设备没有响应(厂商号为0xffff)
+    /// - Err(SystemError::EINVAL): 配置空间比header短
+    pub fn save(&self, cfg: &dyn PciConfigAccess) -> Result<(), SystemError> {
+        if cfg.read_u16(0).map_err(|_| SystemError::EINVAL)? == 0xffff {
+            return Err(SystemError::ENODEV);
+        }
+        let mut saved = [0u32; PCI_PM_SAVED_DWORDS];
+        for (i, dword) in saved.iter_mut().enumerate() {
+            *dword = cfg
+                .read_u32((i * 4) as u16)
+                .map_err(|_| SystemError::EINVAL)?;
+        }
+        *self.saved.lock_irqsave() = Some(saved);
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 写回保存的配置空间，然后丢弃保存的内容
+    ///
+    /// 与Linux一致，从后往前写回，最后写命令寄存器，并且只写入发生了变化的dword
+    ///
+    /// ## 返回值
+    /// 是否写回了配置空间。没有保存过时返回false
+    pub fn restore(&self, cfg: &dyn PciConfigAccess) -> bool {
+        let Some(saved) = self.saved.lock_irqsave().take() else {
+            return false;
+        };
+        for (i, &dword) in saved.iter().enumerate().rev() {
+            let offset = (i * 4) as u16;
+            if cfg.read_u32(offset).is_ok_and(|current| current != dword) {
+                cfg.write_dword(offset, dword);
+            }
+        }
+        true
+    }
+
+    /// 保存的配置空间，没有保存过时为None
+    pub fn saved(&self) -> Option<[u32; PCI_PM_SAVED_DWORDS]> {
+        *self.saved.lock_irqsave()
+    }
+}
+
+impl Default for PciPmState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::*;
+
+    /// 内存中的配置空间，记录写入的偏移
+    struct MockConfig {
+        dwords: RefCell<[u32; 64]>,
+        writes: RefCell<Vec<u16>>,
+    }
+
+    impl MockConfig {
+        fn new() -> Self {
+            let mut dwords = [0u32; 64];
+            for (i, dword) in dwords.iter_mut().enumerate() {
+                *dword = 0x1000_0000 + i as u32;
+            }
+            dwords[0] = 0x1001_1af4;
+            Self {
+                dwords: RefCell::new(dwords),
+                writes: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl PciConfigSource for MockConfig {
+        fn config_len(&self) -> u16 {
+            256
+        }
+
+        fn read_dword(&self, offset: u16) -> u32 {
+            self.dwords.borrow()[offset as usize / 4]
+        }
+    }
+
+    impl PciConfigAccess for MockConfig {
+        fn write_dword(&self, offset: u16, value: u32) {
+            self.dwords.borrow_mut()[offset as usize / 4] = value;
+            self.writes.borrow_mut().push(offset);
+        }
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let cfg = MockConfig::new();
+        let state = PciPmState::new();
+        assert!(!state.restore(&cfg));
+        state.save(&cfg).unwrap();
+        let before = *cfg.dwords.borrow();
+
+        // 模拟进入D3hot之后丢失了命令寄存器和BAR，header之外的内容不被保存
+        cfg.dwords.borrow_mut()[1] = 0;
+        cfg.dwords.borrow_mut()[4] = 0;
+        cfg.dwords.borrow_mut()[20] = 0;
+        cfg.writes.borrow_mut().clear();
+        assert!(state.restore(&cfg));
+        assert_eq!(cfg.dwords.borrow()[..16], before[..16]);
+        assert_eq!(cfg.dwords.borrow()[20], 0);
+        // 只写回变化的dword，命令寄存器最后写回
+        assert_eq!(*cfg.writes.borrow(), [16, 4]);
+        // 保存的内容只写回一次
+        assert_eq!(state.saved(), None);
+        assert!(!state.restore(&cfg));
+    }
+
+    #[test]
+    fn test_pmcsr_keeps_pme_status() {
+        // PME_En置位，设备处于D3hot并且有挂起的PME
+        let pmcsr = 0x0000_8103;
+        let written = PciPowerState::D0.to_pmcsr(pmcsr);
+        assert_eq!(written & PCI_PM_CTRL_PME_STATUS, 0);
+        assert_eq!(written, 0x0000_0100);
+        assert_eq!(PciPowerState::from_pmcsr(written), PciPowerState::D0);
+        assert_eq!(PciPowerState::D3Hot.to_pmcsr(0x0000_0100), 0x0000_0103);
+    }
+
+    #[test]
+    fn test_save_absent_device() {
+        let cfg = MockConfig::new();
+        cfg.dwords.borrow_mut()[0] = u32::MAX;
+        let state = PciPmState::new();
+        assert_eq!(state.save(&cfg), Err(SystemError::ENODEV));
+        assert_eq!(state.saved(), None);
+    }
+}
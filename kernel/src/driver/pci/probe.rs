@@ -0,0 +1,118 @@
+//! 按地址探测单个pci设备
+//!
+//! 已知哪个插槽发生了变化时（例如测试或者恢复设备），不需要重新扫描整条总线：
+//! 重新读取该地址的配置空间，设备还没有注册时把它注册到设备模型，然后为它匹配驱动。
+//!
+//! 向/sys/bus/pci/probe_device写入设备地址(例如`0000:00:04.0`)可以触发探测。
+
+use alloc::{boxed::Box, string::ToString, sync::Arc};
+use core::ffi::CStr;
+use system_error::SystemError;
+
+use crate::{
+    driver::base::{device::bus::bus_manager, kobject::KObject},
+    filesystem::{
+        sysfs::{Attribute, SysFSOpsSupport, SYSFS_ATTR_MODE_WO},
+        vfs::syscall::ModeType,
+    },
+    libs::mutex::Mutex,
+};
+
+use super::{
+    address::PciAddress,
+    bridge::PciBridgeDevice,
+    device::PciDevice,
+    pci::{pci_read_header, BusDeviceFunction, PciDeviceStructure, PCI_DEVICE_LINKEDLIST},
+    raw_device::PciGeneralDevice,
+    subsys::pci_bus,
+};
+
+/// 串行化探测，避免同一个地址被并发地注册两次
+static PCI_PROBE_LOCK: Mutex<()> = Mutex::new(());
+
+/// # 函数的功能
+/// 探测指定地址上的pci设备
+///
+/// 重新读取设备的配置空间，设备没有注册时把它加入设备链表和设备模型，然后为它匹配驱动。
+/// 对已经注册的设备重复调用是安全的：只会为尚未绑定驱动的设备重新匹配驱动
+///
+/// ## 参数
+/// - `address`: 设备的地址
+///
+/// ## 返回值
+/// - Ok(()): 设备已经注册（没有匹配的驱动不算失败）
+/// - Err(SystemError::ENODEV): 该地址上没有设备响应
+pub fn pci_probe_device(address: PciAddress) -> Result<(), SystemError> {
+    // 目前只支持域0
+    if address.domain != 0 {
+        return Err(SystemError::ENODEV);
+    }
+    pci_probe_device_with(address, |bdf| pci_read_header(bdf, false).ok())
+}
+
+/// # 函数的功能
+/// 探测指定地址上的pci设备，设备的配置空间由`read_header`读取
+///
+/// ## 参数
+/// - `address`: 设备的地址
+/// - `read_header`: 读取设备的配置空间，没有设备响应时返回None
+pub(super) fn pci_probe_device_with<F>(
+    address: PciAddress,
+    read_header: F,
+) -> Result<(), SystemError>
+where
+    F: FnOnce(BusDeviceFunction) -> Option<Box<dyn PciDeviceStructure>>,
+{
+    let _guard = PCI_PROBE_LOCK.lock();
+    let header = read_header(address.bdf()).ok_or(SystemError::ENODEV)?;
+
+    let bus = pci_bus();
+    if let Some(dev) = bus.find_device_by_name(&address.to_string()) {
+        return bus_manager().attach_device(&dev);
+    }
+
+    let dev: Option<Arc<dyn PciDevice>> = if let Some(general) = header.as_standard_device() {
+        Some(Arc::new(PciGeneralDevice::from(general)))
+    } else {
+        header
+            .as_pci_to_pci_bridge_device()
+            .map(|bridge| Arc::new(PciBridgeDevice::from(bridge)) as Arc<dyn PciDevice>)
+    };
+    // 已经在链表中的设备(例如注册到设备模型失败)保留链表中的描述符，驱动可能已经修改过它
+    PCI_DEVICE_LINKEDLIST.add(header);
+    // cardbus桥不加入设备模型，与枚举时一致
+    let Some(dev) = dev else {
+        return Ok(());
+    };
+    bus.device_register(dev)
+}
+
+/// 写入设备地址，探测该地址上的设备
+#[derive(Debug)]
+pub struct PciProbeDeviceAttr;
+
+impl Attribute for PciProbeDeviceAttr {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_WO
+    }
+
+    fn name(&self) -> &str {
+        "probe_device"
+    }
+
+    fn store(&self, _kobj: Arc<dyn KObject>, buf: &[u8]) -> Result<usize, SystemError> {
+        let s = match CStr::from_bytes_until_nul(buf) {
+            Ok(s) => s.to_bytes(),
+            Err(_) => buf,
+        };
+        let address = core::str::from_utf8(s)
+            .map_err(|_| SystemError::EINVAL)?
+            .parse::<PciAddress>()?;
+        pci_probe_device(address)?;
+        return Ok(buf.len());
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_STORE
+    }
+}
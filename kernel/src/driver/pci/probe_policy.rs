@@ -0,0 +1,213 @@
+//! 按照vendor:device限制哪些pci设备可以被驱动绑定
+//!
+//! 调试时可以只让部分设备被探测，例如只探测virtio-net：
+//! - `pci_probe_allow=1af4:1000,1af4:1041`: 只有列出的设备可以被绑定
+//! - `pci_probe_block=8086:*`: 列出的设备仍然会被注册，但是不会被绑定
+//!
+//! 每一项的格式为`vvvv:dddd`(十六进制)，`*`表示任意值，多项之间用`,`分隔。
+//! 同时出现在两个列表中的设备不会被绑定。当前的策略可以从/sys/bus/pci/probe_policy读取。
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use log::warn;
+use system_error::SystemError;
+
+use crate::{
+    driver::base::kobject::KObject,
+    filesystem::{
+        sysfs::{file::sysfs_emit_str, Attribute, SysFSOpsSupport, SYSFS_ATTR_MODE_RO},
+        vfs::syscall::ModeType,
+    },
+};
+
+use super::dev_id::PciDeviceID;
+
+kernel_cmdline_param_kv!(PCI_PROBE_ALLOW_PARAM, pci_probe_allow, "");
+kernel_cmdline_param_kv!(PCI_PROBE_BLOCK_PARAM, pci_probe_block, "");
+
+const PCI_ANY_ID: u32 = 0xffff_ffff;
+
+lazy_static! {
+    static ref PCI_PROBE_POLICY: PciProbePolicy = PciProbePolicy::from_params(
+        PCI_PROBE_ALLOW_PARAM.value_str().unwrap_or(""),
+        PCI_PROBE_BLOCK_PARAM.value_str().unwrap_or(""),
+    );
+}
+
+/// 内核命令行指定的探测策略
+pub fn pci_probe_policy() -> &'static PciProbePolicy {
+    &PCI_PROBE_POLICY
+}
+
+/// # 函数的功能
+/// 解析一项`vvvv:dddd`，`*`表示任意值
+///
+/// ## 返回值
+/// - Err(SystemError::EINVAL): 格式错误
+pub fn pci_parse_probe_id(s: &str) -> Result<PciDeviceID, SystemError> {
+    let (vendor, device) = s.trim().split_once(':').ok_or(SystemError::EINVAL)?;
+    let parse = |s: &str| -> Result<Option<u16>, SystemError> {
+        if s == "*" {
+            return Ok(None);
+        }
+        u16::from_str_radix(s, 16)
+            .map(Some)
+            .map_err(|_| SystemError::EINVAL)
+    };
+    Ok(PciDeviceID::new_wildcard(parse(vendor)?, parse(device)?))
+}
+
+/// 解析以`,`分隔的多项，跳过格式错误的项
+fn pci_parse_probe_id_list(param: &str, s: &str) -> Vec<PciDeviceID> {
+    s.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| match pci_parse_probe_id(entry) {
+            Ok(id) => Some(id),
+            Err(_) => {
+                warn!("pci: ignoring invalid {} entry '{}'", param, entry);
+                None
+            }
+        })
+        .collect()
+}
+
+fn format_probe_id(id: &PciDeviceID) -> String {
+    let field = |v: u32| {
+        if v == PCI_ANY_ID {
+            String::from("*")
+        } else {
+            format!("{:04x}", v)
+        }
+    };
+    format!("{}:{}", field(id.vendor()), field(id.device_id()))
+}
+
+/// # 结构功能
+/// pci设备的探测策略
+#[derive(Debug, Default)]
+pub struct PciProbePolicy {
+    /// 为None时不限制，否则只有匹配其中一项的设备可以被绑定
+    allow: Option<Vec<PciDeviceID>>,
+    /// 匹配其中一项的设备不会被绑定
+    block: Vec<PciDeviceID>,
+}
+
+impl PciProbePolicy {
+    /// # 函数的功能
+    /// 根据内核命令行参数的值创建策略，参数为空表示不使用对应的列表
+    pub fn from_params(allow: &str, block: &str) -> Self {
+        let allow = if allow.trim().is_empty() {
+            None
+        } else {
+            Some(pci_parse_probe_id_list("pci_probe_allow", allow))
+        };
+        Self {
+            allow,
+            block: pci_parse_probe_id_list("pci_probe_block", block),
+        }
+    }
+
+    /// # 函数的功能
+    /// 指定的设备是否可以被驱动绑定
+    pub fn allows(&self, vendor: u16, device_id: u16) -> bool {
+        let id = PciDeviceID::new(vendor, device_id);
+        if self.block.iter().any(|b| b.general_match(id)) {
+            return false;
+        }
+        match &self.allow {
+            Some(allow) => allow.iter().any(|a| a.general_match(id)),
+            None => true,
+        }
+    }
+
+    /// 格式化为/sys/bus/pci/probe_policy的内容
+    pub fn describe(&self) -> String {
+        let list = |ids: &[PciDeviceID]| {
+            ids.iter()
+                .map(format_probe_id)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let allow = match &self.allow {
+            Some(allow) => list(allow),
+            None => String::from("all"),
+        };
+        let block = if self.block.is_empty() {
+            String::from("none")
+        } else {
+            list(&self.block)
+        };
+        format!("allow {}\nblock {}\n", allow, block)
+    }
+}
+
+/// /sys/bus/pci/probe_policy
+#[derive(Debug)]
+pub struct PciProbePolicyAttr;
+
+impl Attribute for PciProbePolicyAttr {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "probe_policy"
+    }
+
+    fn show(&self, _kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        return sysfs_emit_str(buf, &pci_probe_policy().describe());
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_allows_all() {
+        let policy = PciProbePolicy::from_params("", "");
+        assert!(policy.allows(0x1af4, 0x1000));
+        assert_eq!(policy.describe(), "allow all\nblock none\n");
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let policy = PciProbePolicy::from_params("1af4:1000, 1af4:1041", "");
+        assert!(policy.allows(0x1af4, 0x1000));
+        assert!(policy.allows(0x1af4, 0x1041));
+        assert!(!policy.allows(0x1af4, 0x1001));
+        assert!(!policy.allows(0x8086, 0x100e));
+        assert_eq!(policy.describe(), "allow 1af4:1000,1af4:1041\nblock none\n");
+    }
+
+    #[test]
+    fn test_blocklist_wildcard() {
+        let policy = PciProbePolicy::from_params("1af4:*", "1af4:1001,8086:*");
+        assert!(policy.allows(0x1af4, 0x1000));
+        // 同时在两个列表中的设备不会被绑定
+        assert!(!policy.allows(0x1af4, 0x1001));
+        assert!(!policy.allows(0x8086, 0x100e));
+        assert_eq!(policy.describe(), "allow 1af4:*\nblock 1af4:1001,8086:*\n");
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        assert_eq!(pci_parse_probe_id("1af4").err(), Some(SystemError::EINVAL));
+        assert_eq!(
+            pci_parse_probe_id("xyz:1000").err(),
+            Some(SystemError::EINVAL)
+        );
+        assert_eq!(
+            pci_parse_probe_id("1af4:10000").err(),
+            Some(SystemError::EINVAL)
+        );
+        // 格式错误的项被跳过
+        let policy = PciProbePolicy::from_params("", "bogus,*:100e");
+        assert!(!policy.allows(0x8086, 0x100e));
+        assert!(policy.allows(0x8086, 0x100f));
+        assert_eq!(policy.describe(), "allow all\nblock *:100e\n");
+    }
+}
@@ -17,8 +17,12 @@ use crate::{
 };
 
 use super::{
-    attr::BasicPciReadOnlyAttrs, dev_id::PciDeviceID, device::PciDevice,
-    pci::PciDeviceStructureGeneralDevice,
+    attr::{BasicPciReadOnlyAttrs, PciMsiIrqsAttrGroup},
+    dev_id::PciDeviceID,
+    device::PciDevice,
+    pci::{BusDeviceFunction, PciDeviceStructure, PciDeviceStructureGeneralDevice},
+    pm::PciPmState,
+    stats::PciMatchStats,
 };
 #[derive(Debug)]
 #[cast_to([sync] Device)]
@@ -28,6 +32,8 @@ pub struct PciGeneralDevice {
     kobj_state: LockedKObjectState,
     dev_id: PciDeviceID,
     header: Arc<PciDeviceStructureGeneralDevice>,
+    match_stats: PciMatchStats,
+    pm_state: PciPmState,
 }
 
 #[derive(Debug)]
@@ -40,7 +46,7 @@ struct InnerPciGeneralDevice {
 impl From<&PciDeviceStructureGeneralDevice> for PciGeneralDevice {
     fn from(value: &PciDeviceStructureGeneralDevice) -> Self {
         let value = Arc::new(value.clone());
-        let name: String = value.common_header.bus_device_function.into();
+        let name = value.address().to_string();
         let kobj_state = LockedKObjectState::new(None);
         let dev_id = PciDeviceID::dummpy();
 
@@ -54,6 +60,8 @@ impl From<&PciDeviceStructureGeneralDevice> for PciGeneralDevice {
             kobj_state,
             dev_id,
             header: value,
+            match_stats: PciMatchStats::new(),
+            pm_state: PciPmState::new(),
         };
         res.set_name(name);
         res
@@ -80,11 +88,23 @@ impl PciDevice for PciGeneralDevice {
     fn subsystem_device(&self) -> u16 {
         self.header.subsystem_id
     }
+
+    fn bus_device_function(&self) -> Option<BusDeviceFunction> {
+        Some(self.header.common_header.bus_device_function)
+    }
+
+    fn match_stats(&self) -> Option<&PciMatchStats> {
+        Some(&self.match_stats)
+    }
+
+    fn pm_state(&self) -> Option<&PciPmState> {
+        Some(&self.pm_state)
+    }
 }
 
 impl Device for PciGeneralDevice {
     fn attribute_groups(&self) -> Option<&'static [&'static dyn AttributeGroup]> {
-        Some(&[&BasicPciReadOnlyAttrs])
+        Some(&[&BasicPciReadOnlyAttrs, &PciMsiIrqsAttrGroup])
     }
 
     fn bus(&self) -> Option<Weak<dyn Bus>> {
@@ -118,7 +138,11 @@ impl Device for PciGeneralDevice {
     }
 
     fn is_dead(&self) -> bool {
-        false
+        self.inner.read().device_common.dead
+    }
+
+    fn set_dead(&self, dead: bool) {
+        self.inner.write().device_common.dead = dead;
     }
 
     fn set_bus(&self, bus: Option<Weak<dyn Bus>>) {
@@ -212,11 +212,7 @@ impl PciRoot {
         &self,
         bus_device_function: BusDeviceFunction,
     ) -> ExternalCapabilityIterator {
-        ExternalCapabilityIterator {
-            root: self,
-            bus_device_function,
-            next_capability_offset: Some(0x100),
-        }
+        ExternalCapabilityIterator::new(self, bus_device_function)
     }
 }
 
@@ -0,0 +1,172 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{format, string::String};
+use system_error::SystemError;
+
+use crate::libs::seqlock::SeqLock;
+
+/// # 结构功能
+/// 记录一个pci设备在驱动匹配过程中的统计信息，用于排查设备为什么没有绑定到驱动
+///
+/// 统计信息由顺序锁保护，读者通过[`PciMatchStats::snapshot`]总能得到一组一致的值，
+/// 例如不会看到增加了失败次数、却还没有记录错误码的中间状态
+#[derive(Debug, Default)]
+pub struct PciMatchStats {
+    inner: SeqLock<PciMatchStatsSnapshot>,
+}
+
+/// 某一时刻的匹配统计信息
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PciMatchStatsSnapshot {
+    /// 尝试与该设备进行匹配的驱动数量
+    pub drivers_tried: usize,
+    /// 匹配成功但probe失败的次数
+    pub bind_failures: usize,
+    /// 最近一次probe失败的错误码（posix errno，0表示没有错误）
+    pub last_errno: i32,
+    /// 最近一次probe花费的时间（微秒）
+    pub probe_time_us: u64,
+}
+
+impl PciMatchStatsSnapshot {
+    const EMPTY: Self = Self {
+        drivers_tried: 0,
+        bind_failures: 0,
+        last_errno: 0,
+        probe_time_us: 0,
+    };
+
+    /// 最近一次probe失败的错误
+    pub fn last_error(&self) -> Option<SystemError> {
+        SystemError::from_posix_errno(self.last_errno)
+    }
+
+    /// # 函数的功能
+    /// 格式化为设备`stats`文件的内容，每行为`<名称> <值>`
+    pub fn format(&self) -> String {
+        format!(
+            "drivers_tried {}\nbind_failures {}\nlast_error {}\nprobe_time_us {}\n",
+            self.drivers_tried, self.bind_failures, self.last_errno, self.probe_time_us
+        )
+    }
+}
+
+#[allow(dead_code)]
+impl PciMatchStats {
+    pub const fn new() -> Self {
+        Self {
+            inner: SeqLock::new(PciMatchStatsSnapshot::EMPTY),
+        }
+    }
+
+    pub fn record_try(&self) {
+        self.inner.write(|s| s.drivers_tried += 1);
+    }
+
+    pub fn record_failure(&self, err: &SystemError) {
+        self.inner.write(|s| {
+            s.bind_failures += 1;
+            s.last_errno = err.to_posix_errno();
+        });
+    }
+
+    pub fn record_probe_time(&self, micros: u64) {
+        self.inner.write(|s| s.probe_time_us = micros);
+    }
+
+    /// 读取所有统计信息，得到的各个值来自同一时刻
+    pub fn snapshot(&self) -> PciMatchStatsSnapshot {
+        self.inner.read()
+    }
+
+    pub fn drivers_tried(&self) -> usize {
+        self.snapshot().drivers_tried
+    }
+
+    pub fn bind_failures(&self) -> usize {
+        self.snapshot().bind_failures
+    }
+
+    pub fn last_error(&self) -> Option<SystemError> {
+        self.snapshot().last_error()
+    }
+
+    pub fn probe_time_us(&self) -> u64 {
+        self.snapshot().probe_time_us
+    }
+
+    /// 重新扫描总线前清空统计信息
+    pub fn reset(&self) {
+        self.inner.write(|s| *s = PciMatchStatsSnapshot::EMPTY);
+    }
+}
+
+/// # 结构功能
+/// 记录一个pci驱动probe成功/失败的次数
+#[derive(Debug, Default)]
+pub struct PciDriverProbeStats {
+    probe_ok: AtomicUsize,
+    probe_failed: AtomicUsize,
+}
+
+#[allow(dead_code)]
+impl PciDriverProbeStats {
+    pub const fn new() -> Self {
+        Self {
+            probe_ok: AtomicUsize::new(0),
+            probe_failed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record(&self, result: &Result<(), SystemError>) {
+        match result {
+            Ok(_) => self.probe_ok.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.probe_failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn probe_ok(&self) -> usize {
+        self.probe_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn probe_failed(&self) -> usize {
+        self.probe_failed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_stats_snapshot() {
+        let stats = PciMatchStats::new();
+        stats.record_try();
+        stats.record_try();
+        stats.record_failure(&SystemError::EIO);
+        stats.record_probe_time(42);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot,
+            PciMatchStatsSnapshot {
+                drivers_tried: 2,
+                bind_failures: 1,
+                last_errno: SystemError::EIO.to_posix_errno(),
+                probe_time_us: 42,
+            }
+        );
+        assert_eq!(snapshot.last_error(), Some(SystemError::EIO));
+        assert_eq!(
+            snapshot.format(),
+            format!(
+                "drivers_tried 2\nbind_failures 1\nlast_error {}\nprobe_time_us 42\n",
+                SystemError::EIO.to_posix_errno()
+            )
+        );
+
+        stats.reset();
+        assert_eq!(stats.snapshot(), PciMatchStatsSnapshot::default());
+        assert_eq!(stats.last_error(), None);
+    }
+}
@@ -1,15 +1,16 @@
 use alloc::{
     string::{String, ToString},
     sync::{Arc, Weak},
+    vec::Vec,
 };
 use intertrait::cast::CastArc;
-use log::error;
+use log::{error, warn};
 use system_error::SystemError;
 
 use crate::{
     driver::base::{
         device::{
-            bus::{bus_register, Bus},
+            bus::{bus_manager, bus_register, Bus},
             device_register,
             driver::Driver,
             sys_devices_kset, Device,
@@ -18,14 +19,25 @@ use crate::{
         subsys::SubSysPrivate,
     },
     filesystem::sysfs::AttributeGroup,
+    libs::rwlock::RwLock,
+    time::Instant,
 };
 
 use super::{
-    device::{PciBusDevice, PciDevice},
+    address::PciAddress,
+    device::{pci_device_manager, PciBusDevice, PciDevice},
     driver::PciDriver,
+    notifier::PciBusNotifier,
+    pm::{pci_set_power_state, PciPowerState},
+    probe::PciProbeDeviceAttr,
+    probe_policy::{pci_probe_policy, PciProbePolicyAttr},
     test::pt_init,
+    tree::PciDevicesTree,
 };
 
+/// probe花费的时间超过这个值(微秒)时打印警告，用于发现启动时很慢的驱动
+const PCI_SLOW_PROBE_US: u64 = 100_000;
+
 static mut PCI_BUS_DEVICE: Option<Arc<PciBusDevice>> = None;
 static mut PCI_BUS: Option<Arc<PciBus>> = None;
 
@@ -59,15 +71,118 @@ pub fn pci_bus() -> Arc<PciBus> {
 #[derive(Debug)]
 pub struct PciBus {
     private: SubSysPrivate,
+    /// 设备增删的通知接收者
+    notifiers: RwLock<Vec<Arc<dyn PciBusNotifier>>>,
 }
 
 impl PciBus {
     pub fn new() -> Arc<Self> {
         let w: Weak<Self> = Weak::new();
         let private = SubSysPrivate::new("pci".to_string(), Some(w), None, &[]);
-        let bus = Arc::new(Self { private });
+        let bus = Arc::new(Self {
+            private,
+            notifiers: RwLock::new(Vec::new()),
+        });
         bus
     }
+
+    /// # 函数的功能
+    /// 注册一个设备增删的通知接收者
+    ///
+    /// ## 返回值
+    /// - Ok(()): 注册成功
+    /// - Err(SystemError::EEXIST): 该接收者已经注册过
+    pub fn register_notifier(&self, notifier: Arc<dyn PciBusNotifier>) -> Result<(), SystemError> {
+        let mut notifiers = self.notifiers.write();
+        if notifiers.iter().any(|n| Arc::ptr_eq(n, &notifier)) {
+            return Err(SystemError::EEXIST);
+        }
+        notifiers.push(notifier);
+        return Ok(());
+    }
+
+    /// # 函数的功能
+    /// 注销一个设备增删的通知接收者
+    ///
+    /// ## 返回值
+    /// - Ok(()): 注销成功
+    /// - Err(SystemError::ENOENT): 该接收者没有注册
+    pub fn unregister_notifier(
+        &self,
+        notifier: &Arc<dyn PciBusNotifier>,
+    ) -> Result<(), SystemError> {
+        let mut notifiers = self.notifiers.write();
+        let index = notifiers
+            .iter()
+            .position(|n| Arc::ptr_eq(n, notifier))
+            .ok_or(SystemError::ENOENT)?;
+        notifiers.remove(index);
+        return Ok(());
+    }
+
+    /// 复制一份通知接收者的列表，使回调在锁外执行
+    fn notifiers(&self) -> Vec<Arc<dyn PciBusNotifier>> {
+        self.notifiers.read().clone()
+    }
+
+    pub(super) fn notify_device_add(&self, dev: &Arc<dyn PciDevice>) {
+        for notifier in self.notifiers() {
+            notifier.on_device_add(dev);
+        }
+    }
+
+    pub(super) fn notify_device_remove(&self, dev: &Arc<dyn PciDevice>) {
+        for notifier in self.notifiers() {
+            notifier.on_device_remove(dev);
+        }
+    }
+
+    /// # 函数的功能
+    /// 在pci总线上注册一个驱动，并立即为总线上尚未绑定驱动的设备尝试匹配该驱动
+    ///
+    /// 与[`PciBus::device_register`]配合使用时，驱动与设备的注册顺序不会影响它们的绑定
+    ///
+    /// ## 参数
+    /// - `driver`: 要注册的pci驱动
+    ///
+    /// ## 返回值
+    /// - Ok(()): 驱动注册成功（单个设备probe失败不会导致注册失败）
+    /// - Err(e): 驱动注册失败
+    pub fn driver_register(&self, driver: Arc<dyn PciDriver>) -> Result<(), SystemError> {
+        bus_manager().register_driver(&(pci_bus() as Arc<dyn Bus>), driver as Arc<dyn Driver>)
+    }
+
+    /// # 函数的功能
+    /// 从pci总线上注销一个驱动
+    ///
+    /// 驱动绑定的所有设备都会先经过驱动的remove()解除绑定，然后删除驱动在sysfs中的目录，
+    /// 并把驱动从总线的驱动列表中移除。之后这些设备处于未绑定状态，可以重新与其他驱动绑定
+    ///
+    /// ## 参数
+    /// - `driver`: 要注销的pci驱动
+    ///
+    /// ## 返回值
+    /// - Ok(()): 注销成功
+    /// - Err(SystemError::ENODEV): 驱动没有注册在pci总线上
+    pub fn driver_unregister(&self, driver: &Arc<dyn PciDriver>) -> Result<(), SystemError> {
+        let driver = driver.clone() as Arc<dyn Driver>;
+        bus_manager().unregister_driver(&(pci_bus() as Arc<dyn Bus>), &driver)
+    }
+
+    /// # 函数的功能
+    /// 在pci总线上注册一个设备，并为它尝试所有已经注册的驱动
+    ///
+    /// ## 参数
+    /// - `dev`: 要注册的pci设备
+    ///
+    /// ## 返回值
+    /// - Ok(()): 设备注册成功（没有匹配的驱动不算失败）
+    /// - Err(e): 设备注册失败
+    pub fn device_register(&self, dev: Arc<dyn PciDevice>) -> Result<(), SystemError> {
+        // pci设备需要设置默认的父设备并发出通知，因此由pci_device_manager添加到总线上
+        pci_device_manager().device_add(dev.clone())?;
+        bus_manager().attach_device(&(dev as Arc<dyn Device>))
+    }
 }
 
 impl Bus for PciBus {
@@ -83,6 +198,10 @@ impl Bus for PciBus {
         return &[&PciDeviceAttrGroup];
     }
 
+    fn bus_groups(&self) -> &'static [&'static dyn AttributeGroup] {
+        return &[&PciBusAttrGroup];
+    }
+
     fn subsystem(&self) -> &SubSysPrivate {
         return &self.private;
     }
@@ -104,12 +223,53 @@ impl Bus for PciBus {
             SystemError::EINVAL
         })?;
         //见https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/pci-driver.c#324
-        let id = pci_drv.match_dev(&pci_dev).ok_or(SystemError::EINVAL)?;
-        pci_drv.probe(&pci_dev, &id)
+        let start = Instant::now();
+        let r = pci_drv
+            .match_dev(&pci_dev)
+            .ok_or(SystemError::EINVAL)
+            .and_then(|id| pci_drv.probe(&pci_dev, &id));
+        let probe_time_us = (Instant::now() - start).total_micros();
+        if probe_time_us >= PCI_SLOW_PROBE_US {
+            warn!(
+                "PciBus::probe(): driver '{}' took {} us to probe device '{}'",
+                pci_drv.name(),
+                probe_time_us,
+                pci_dev.name()
+            );
+        }
+
+        if let Some(stats) = pci_dev.match_stats() {
+            stats.record_probe_time(probe_time_us);
+        }
+
+        if let Some(stats) = pci_drv.probe_stats() {
+            stats.record(&r);
+        }
+        if let Err(e) = &r {
+            if let Some(stats) = pci_dev.match_stats() {
+                stats.record_failure(e);
+            }
+        }
+        r
     }
 
-    fn remove(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
-        todo!()
+    fn remove(&self, device: &Arc<dyn Device>) -> Result<(), SystemError> {
+        let drv = device.driver().ok_or(SystemError::EINVAL)?;
+        let pci_drv = drv.cast::<dyn PciDriver>().map_err(|_| {
+            error!(
+                "PciBus::remove() failed: device.driver() is not a PciDriver. Device: '{:?}'",
+                device.name()
+            );
+            SystemError::EINVAL
+        })?;
+        let pci_dev = device.clone().cast::<dyn PciDevice>().map_err(|_| {
+            error!(
+                "PciBus::remove() failed: device is not a PciDevice. Device: '{:?}'",
+                device.name()
+            );
+            SystemError::EINVAL
+        })?;
+        pci_drv.remove(&pci_dev)
     }
 
     fn sync_state(&self, _device: &Arc<dyn Device>) {
@@ -120,8 +280,75 @@ impl Bus for PciBus {
         todo!()
     }
 
-    fn resume(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
-        todo!()
+    fn suspend(&self, device: &Arc<dyn Device>) {
+        let pci_dev = match device.clone().cast::<dyn PciDevice>() {
+            Ok(pci_dev) => pci_dev,
+            Err(_) => return,
+        };
+
+        if let Some(pci_drv) = device
+            .driver()
+            .and_then(|drv| drv.cast::<dyn PciDriver>().ok())
+        {
+            if let Err(e) = pci_drv.suspend(&pci_dev) {
+                warn!(
+                    "PciBus::suspend(): driver failed to suspend device '{}': {:?}",
+                    device.name(),
+                    e
+                );
+                return;
+            }
+        }
+
+        // 进入D3hot之后设备可能丢失配置，恢复时写回。没有响应的设备(例如合成的设备)不需要保存
+        if let (Some(pm_state), Some(cfg)) = (pci_dev.pm_state(), pci_dev.config_space()) {
+            match pm_state.save(cfg.as_ref()) {
+                Ok(_) | Err(SystemError::ENODEV) => {}
+                Err(e) => warn!(
+                    "PciBus::suspend(): failed to save config space of device '{}': {:?}",
+                    device.name(),
+                    e
+                ),
+            }
+        }
+
+        // 设备（未绑定驱动，或驱动已完成suspend）进入D3hot
+        if let Some(bdf) = pci_dev.bus_device_function() {
+            match pci_set_power_state(bdf, PciPowerState::D3Hot) {
+                Ok(_) | Err(SystemError::EOPNOTSUPP_OR_ENOTSUP) => {}
+                Err(e) => warn!(
+                    "PciBus::suspend(): failed to put device '{}' into D3hot: {:?}",
+                    device.name(),
+                    e
+                ),
+            }
+        }
+    }
+
+    fn resume(&self, device: &Arc<dyn Device>) -> Result<(), SystemError> {
+        let pci_dev = device
+            .clone()
+            .cast::<dyn PciDevice>()
+            .map_err(|_| SystemError::EINVAL)?;
+
+        if let Some(bdf) = pci_dev.bus_device_function() {
+            match pci_set_power_state(bdf, PciPowerState::D0) {
+                Ok(_) | Err(SystemError::EOPNOTSUPP_OR_ENOTSUP) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if let (Some(pm_state), Some(cfg)) = (pci_dev.pm_state(), pci_dev.config_space()) {
+            pm_state.restore(cfg.as_ref());
+        }
+
+        if let Some(pci_drv) = device
+            .driver()
+            .and_then(|drv| drv.cast::<dyn PciDriver>().ok())
+        {
+            pci_drv.resume(&pci_dev)?;
+        }
+
+        return Ok(());
     }
 
     fn match_device(
@@ -136,6 +363,13 @@ impl Bus for PciBus {
         let pci_dev = device.clone().cast::<dyn PciDevice>().map_err(|_| {
             return SystemError::EINVAL;
         })?;
+        // 被探测策略排除的设备不绑定任何驱动
+        if !pci_probe_policy().allows(pci_dev.vendor(), pci_dev.device_id()) {
+            return Ok(false);
+        }
+        if let Some(stats) = pci_dev.match_stats() {
+            stats.record_try();
+        }
         //pci_driver需要实现一个match_dev函数，即driver需要识别是否支持给定的pci设备
         //这是主要的match方式
         if pci_driver.match_dev(&pci_dev).is_some() {
@@ -145,19 +379,48 @@ impl Bus for PciBus {
         //todo:这里似乎需要一个driver_override_only的支持，但是目前不清楚driver_override_only 的用途，故暂时参考platform总线的match方法
         //override_only相关代码在 https://code.dragonos.org.cn/xref/linux-6.1.9/drivers/pci/pci-driver.c#159
         if let Some(driver_id_table) = driver.id_table() {
-            if driver_id_table.name().eq(&pci_dev.name()) {
+            if self.canonical_device_name(&driver_id_table.name()) == pci_dev.name() {
                 return Ok(true);
             }
         };
         return Ok(pci_dev.name().eq(&pci_driver.name()));
     }
 
+    /// pci设备以地址命名，地址的各种写法(省略域号、大写的十六进制数)都统一为[`PciAddress`]的格式。
+    /// 不是地址的名称(例如测试设备)保持不变
+    fn canonical_device_name(&self, name: &str) -> String {
+        match name.parse::<PciAddress>() {
+            Ok(address) => address.to_string(),
+            Err(_) => name.to_string(),
+        }
+    }
+
     fn root_device(&self) -> Option<Weak<dyn Device>> {
         let root_device = pci_bus_device() as Arc<dyn Device>;
         return Some(Arc::downgrade(&root_device));
     }
 }
 
+/// # 函数的功能
+/// 重新为pci总线上尚未绑定驱动的设备匹配驱动
+///
+/// 在匹配之前，会清空这些设备的匹配统计信息
+#[allow(dead_code)]
+pub fn pci_rescan_bus() -> Result<(), SystemError> {
+    let bus = pci_bus();
+    for dev in bus.subsystem().devices().iter() {
+        if dev.driver().is_some() {
+            continue;
+        }
+        if let Ok(pci_dev) = dev.clone().cast::<dyn PciDevice>() {
+            if let Some(stats) = pci_dev.match_stats() {
+                stats.reset();
+            }
+        }
+    }
+    bus_manager().rescan_devices(&(bus as Arc<dyn Bus>))
+}
+
 #[derive(Debug)]
 pub struct PciDeviceAttrGroup;
 
@@ -179,6 +442,28 @@ impl AttributeGroup for PciDeviceAttrGroup {
     }
 }
 
+/// /sys/bus/pci下的属性
+#[derive(Debug)]
+pub struct PciBusAttrGroup;
+
+impl AttributeGroup for PciBusAttrGroup {
+    fn name(&self) -> Option<&str> {
+        return None;
+    }
+
+    fn attrs(&self) -> &[&'static dyn crate::filesystem::sysfs::Attribute] {
+        return &[&PciDevicesTree, &PciProbePolicyAttr, &PciProbeDeviceAttr];
+    }
+
+    fn is_visible(
+        &self,
+        _kobj: Arc<dyn crate::driver::base::kobject::KObject>,
+        attr: &'static dyn crate::filesystem::sysfs::Attribute,
+    ) -> Option<crate::filesystem::vfs::syscall::ModeType> {
+        return Some(attr.mode());
+    }
+}
+
 pub(super) fn pci_bus_subsys_init() -> Result<(), SystemError> {
     let pci_bus_device: Arc<PciBusDevice> = PciBusDevice::new(Some(Arc::downgrade(
         &(sys_devices_kset() as Arc<dyn KObject>),
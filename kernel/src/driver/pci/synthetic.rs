@@ -0,0 +1,215 @@
+//! 手动注册的(合成的)pci设备
+//!
+//! 测试设备模型时不能依赖真实的pci枚举。这里的设备不读取配置空间，而是直接由调用者给出
+//! 厂商号、设备号、类代码以及BAR，然后像枚举到的设备一样加入设备链表，并通过
+//! [`super::device::PciDeviceManager::device_add`]加入设备模型。
+//!
+//! 合成的设备位于总线[`SYNTHETIC_PCI_BUS`]上，它们的配置空间并不存在，
+//! 因此使用它们的驱动不能访问配置空间或者BAR。
+
+use alloc::{boxed::Box, collections::BTreeSet, sync::Arc, vec::Vec};
+use system_error::SystemError;
+
+use crate::libs::spinlock::SpinLock;
+
+use super::{
+    address::PciAddress,
+    device::{pci_device_manager, PciDevice},
+    pci::{
+        BarInfo, BusDeviceFunction, PciDeviceStructureGeneralDevice, PciDeviceStructureHeader,
+        PciStandardDeviceBar, PCI_DEVICE_LINKEDLIST,
+    },
+    pci_irq::IrqType,
+    raw_device::PciGeneralDevice,
+};
+
+/// 合成的设备所在的总线号
+pub const SYNTHETIC_PCI_BUS: u8 = 0xff;
+
+/// 已经注册的合成设备
+static SYNTHETIC_PCI_DEVICES: SpinLock<BTreeSet<PciAddress>> = SpinLock::new(BTreeSet::new());
+
+/// # 函数的功能
+/// 构造一个合成的标准pci设备(header type 0)
+///
+/// ## 参数
+/// - `bdf`: 设备的地址
+/// - `vendor`: 厂商号
+/// - `device`: 设备号
+/// - `class`: 类代码，格式为`0xCCSSPP`(类、子类、编程接口)
+/// - `bars`: 设备的BAR，最多6个，按照顺序对应BAR0~BAR5
+///
+/// ## 返回值
+/// - Err(SystemError::EINVAL): BAR多于6个
+pub fn synthetic_pci_structure(
+    bdf: BusDeviceFunction,
+    vendor: u16,
+    device: u16,
+    class: u32,
+    bars: &[BarInfo],
+) -> Result<PciDeviceStructureGeneralDevice, SystemError> {
+    let mut standard_device_bar = PciStandardDeviceBar::default();
+    for (index, bar) in bars.iter().enumerate() {
+        standard_device_bar
+            .set_bar(index as u8, bar.clone())
+            .map_err(|_| SystemError::EINVAL)?;
+    }
+    let common_header = PciDeviceStructureHeader {
+        bus_device_function: bdf,
+        vendor_id: vendor,
+        device_id: device,
+        command: 0,
+        status: 0,
+        revision_id: 0,
+        prog_if: class as u8,
+        subclass: (class >> 8) as u8,
+        class_code: (class >> 16) as u8,
+        cache_line_size: 0,
+        latency_timer: 0,
+        header_type: 0,
+        bist: 0,
+    };
+    Ok(PciDeviceStructureGeneralDevice {
+        common_header,
+        irq_type: IrqType::Unused,
+        irq_vector: Vec::new(),
+        standard_device_bar,
+        cardbus_cis_pointer: 0,
+        subsystem_vendor_id: 0,
+        subsystem_id: 0,
+        expansion_rom_base_address: 0,
+        capabilities_pointer: 0,
+        reserved0: 0,
+        reserved1: 0,
+        reserved2: 0,
+        interrupt_line: 0xff,
+        interrupt_pin: 0,
+        min_grant: 0,
+        max_latency: 0,
+    })
+}
+
+/// 在合成设备的总线上找一个没有被使用的地址
+fn synthetic_pci_alloc_bdf() -> Result<BusDeviceFunction, SystemError> {
+    (0..32)
+        .map(|device| BusDeviceFunction {
+            bus: SYNTHETIC_PCI_BUS,
+            device,
+            function: 0,
+        })
+        .find(|bdf| !PCI_DEVICE_LINKEDLIST.contains(PciAddress::from(*bdf)))
+        .ok_or(SystemError::ENOSPC)
+}
+
+/// # 函数的功能
+/// 注册一个合成的pci设备，就像在枚举时发现了这个设备一样
+///
+/// 设备会被加入pci设备链表，然后加入设备模型，总线会为它匹配驱动
+///
+/// ## 参数
+/// - `vendor`: 厂商号
+/// - `device`: 设备号
+/// - `class`: 类代码，格式为`0xCCSSPP`
+/// - `bars`: 设备的BAR，最多6个
+///
+/// ## 返回值
+/// - Ok(dev): 注册的设备，使用[`unregister_synthetic_pci_device`]注销
+/// - Err(SystemError::EINVAL): BAR多于6个
+/// - Err(SystemError::ENOSPC): 合成设备的总线上没有空闲的地址
+pub fn register_synthetic_pci_device(
+    vendor: u16,
+    device: u16,
+    class: u32,
+    bars: &[BarInfo],
+) -> Result<Arc<PciGeneralDevice>, SystemError> {
+    let mut registered = SYNTHETIC_PCI_DEVICES.lock();
+    let bdf = synthetic_pci_alloc_bdf()?;
+    let structure = synthetic_pci_structure(bdf, vendor, device, class, bars)?;
+    if !PCI_DEVICE_LINKEDLIST.add(Box::new(structure.clone())) {
+        return Err(SystemError::EEXIST);
+    }
+    registered.insert(PciAddress::from(bdf));
+    drop(registered);
+
+    let dev = Arc::new(PciGeneralDevice::from(&structure));
+    if let Err(e) = pci_device_manager().device_add(dev.clone()) {
+        SYNTHETIC_PCI_DEVICES.lock().remove(&PciAddress::from(bdf));
+        PCI_DEVICE_LINKEDLIST.remove(bdf);
+        return Err(e);
+    }
+    Ok(dev)
+}
+
+/// # 函数的功能
+/// 注销使用[`register_synthetic_pci_device`]注册的设备
+///
+/// 解除设备与驱动的绑定，把设备从设备模型以及pci设备链表中移除
+///
+/// ## 返回值
+/// - Err(SystemError::ENOENT): 设备不是已经注册的合成设备
+pub fn unregister_synthetic_pci_device(dev: &Arc<PciGeneralDevice>) -> Result<(), SystemError> {
+    let bdf = dev.bus_device_function().ok_or(SystemError::ENOENT)?;
+    if !SYNTHETIC_PCI_DEVICES.lock().remove(&PciAddress::from(bdf)) {
+        return Err(SystemError::ENOENT);
+    }
+    pci_device_manager().device_remove(&(dev.clone() as Arc<dyn PciDevice>));
+    PCI_DEVICE_LINKEDLIST.remove(bdf);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthetic_structure() {
+        let bdf = BusDeviceFunction {
+            bus: SYNTHETIC_PCI_BUS,
+            device: 3,
+            function: 0,
+        };
+        let bars = [
+            BarInfo::IO {
+                address: 0xc000,
+                size: 0x40,
+            },
+            BarInfo::Unused,
+        ];
+        let s = synthetic_pci_structure(bdf, 0x1234, 0x5678, 0x020000, &bars).unwrap();
+        assert_eq!(s.common_header.vendor_id, 0x1234);
+        assert_eq!(s.common_header.device_id, 0x5678);
+        assert_eq!(
+            (
+                s.common_header.class_code,
+                s.common_header.subclass,
+                s.common_header.prog_if
+            ),
+            (0x02, 0x00, 0x00)
+        );
+        assert!(matches!(
+            s.standard_device_bar.get_bar(0),
+            Ok(BarInfo::IO {
+                address: 0xc000,
+                size: 0x40
+            })
+        ));
+        assert!(matches!(
+            s.standard_device_bar.get_bar(5),
+            Ok(BarInfo::Unused)
+        ));
+    }
+
+    #[test]
+    fn test_too_many_bars() {
+        let bdf = BusDeviceFunction {
+            bus: SYNTHETIC_PCI_BUS,
+            device: 0,
+            function: 0,
+        };
+        let bars = vec![BarInfo::Unused; 7];
+        assert_eq!(
+            synthetic_pci_structure(bdf, 0x1234, 0x5678, 0, &bars).err(),
+            Some(SystemError::EINVAL)
+        );
+    }
+}
@@ -1,30 +1,1833 @@
-use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use intertrait::cast::CastArc;
+use log::{error, info};
 use system_error::SystemError;
 
-use self::{pt_device::TestDevice, pt_driver::TestDriver};
+use crate::{
+    arch::CurrentIrqArch,
+    driver::{
+        base::{
+            class::{class_manager, Class},
+            device::{
+                bus::{bus_manager, bus_register, bus_unregister, sys_bus_kset, Bus},
+                deferred_probe::deferred_probe_manager,
+                device_manager,
+                device_number::{DeviceNumber, Major},
+                driver::Driver,
+                registry::device_registry,
+                sys_devices_kset, Device, DeviceId, IdTable,
+            },
+            kobject::{KObject, KObjectState},
+            power::{
+                runtime::{pm_runtime_idle, pm_runtime_resume, pm_runtime_set_control, RpmControl},
+                sleep::dpm_manager,
+            },
+        },
+        virtio::{
+            sysfs::virtio_device_manager,
+            worker::{virtio_worker_manager, VirtIOWorker},
+            VirtIODevice,
+        },
+    },
+    exception::InterruptArch,
+    filesystem::{
+        kernfs::{callback::KernInodePrivateData, KernFSInode, KernInodeType},
+        sysfs::{Attribute, SysFSKernPrivateData},
+        vfs::{IndexNode, ROOT_INODE, VFS_MAX_FOLLOW_SYMLINK_TIMES},
+    },
+    libs::{casting::DowncastArc, spinlock::SpinLock},
+    process::{
+        kthread::{KernelThreadClosure, KernelThreadMechanism},
+        Pid, ProcessManager,
+    },
+    smp::cpu::smp_cpu_manager,
+    time::{sleep::nanosleep, Duration, Instant, PosixTimeSpec},
+};
+
+use self::{
+    pt_bus::TestBus,
+    pt_class::TestClass,
+    pt_device::TestDevice,
+    pt_driver::{TestDriver, TestDriverAttrGroup},
+    pt_pm::{PtConfigSpace, PtPmLog, PtVirtIODevice},
+};
 
 use super::{
+    address::PciAddress,
+    attr::{LocalCpus, NumaNode},
+    config_space::PciConfigAccess,
     dev_id::PciDeviceID,
-    device::pci_device_manager,
-    driver::{pci_driver_manager, PciDriver},
+    device::{pci_device_manager, PciDevice},
+    driver::{register_pci_driver, unregister_pci_driver, PciDriver},
+    notifier::PciBusNotifier,
+    numa::NUMA_NO_NODE,
+    pci::{
+        BarInfo, BusDeviceFunction, HeaderType, PciDeviceLinkedList, PciDeviceStructure,
+        PciDeviceStructureHeader, PCI_DEVICE_LINKEDLIST,
+    },
+    pci_irq::IrqType,
+    pcie::{
+        pcie_device_control, pcie_device_control_in, pcie_set_max_read_request, pcie_set_no_snoop,
+        pcie_set_relaxed_ordering, PCI_EXP_DEVCTL_NOSNOOP_EN, PCI_EXP_DEVCTL_READRQ,
+        PCI_EXP_DEVCTL_RELAX_EN,
+    },
+    probe::{pci_probe_device, pci_probe_device_with},
+    stats::PciMatchStats,
+    subsys::pci_bus,
+    synthetic::{
+        register_synthetic_pci_device, synthetic_pci_structure, unregister_synthetic_pci_device,
+        SYNTHETIC_PCI_BUS,
+    },
 };
 
+pub mod pt_bus;
+pub mod pt_class;
 pub mod pt_device;
 pub mod pt_driver;
+pub mod pt_pm;
 
 static mut TEST_DRIVER: Option<Arc<TestDriver>> = None;
 static mut TEST_DEVICE: Option<Arc<TestDevice>> = None;
+
+kernel_cmdline_param_arg!(PCI_SELFTEST_PARAM, pci_selftest, false, false);
+
 pub fn pt_init() -> Result<(), SystemError> {
+    // 自测会注册并删除大量测试设备和驱动，只在内核命令行指定`pci_selftest`时运行
+    if PCI_SELFTEST_PARAM.value_bool().unwrap_or(false) {
+        pt_selftests();
+    }
+
     let tdev = Arc::new(TestDevice::new());
     let mut drv = TestDriver::new();
     drv.add_dynid(PciDeviceID::dummpy())?;
     let tdrv = Arc::new(drv);
 
     let _ = pci_device_manager().device_add(tdev.clone());
-    let _ = pci_driver_manager().register(tdrv.clone());
+    let _ = register_pci_driver(tdrv.clone());
     unsafe {
         TEST_DEVICE = Some(tdev);
         TEST_DRIVER = Some(tdrv);
     }
     Ok(())
 }
+
+/// 驱动模型与pci子系统的启动自测，结果通过日志报告
+fn pt_selftests() {
+    // 必须在注册能够匹配任意设备的测试驱动之前进行，否则测试设备会被它绑定
+    if let Err(e) = pt_bind_order_test() {
+        error!("pci bind order test failed: {:?}", e);
+    }
+    if let Err(e) = pt_probe_priority_test() {
+        error!("pci probe priority test failed: {:?}", e);
+    }
+    if let Err(e) = pt_probe_failure_test() {
+        error!("pci probe failure test failed: {:?}", e);
+    }
+    if let Err(e) = pt_unregister_test() {
+        error!("pci driver unregister test failed: {:?}", e);
+    }
+    if let Err(e) = pt_notifier_test() {
+        error!("pci bus notifier test failed: {:?}", e);
+    }
+    if let Err(e) = pt_lifecycle_test() {
+        error!("pci device lifecycle test failed: {:?}", e);
+    }
+    if let Err(e) = pt_driver_link_test() {
+        error!("driver link resolve test failed: {:?}", e);
+    }
+    if let Err(e) = pt_generic_bus_test() {
+        error!("generic bus test failed: {:?}", e);
+    }
+    if let Err(e) = pt_no_bus_subsystem_test() {
+        error!("no bus subsystem link test failed: {:?}", e);
+    }
+    if let Err(e) = pt_dup_id_table_test() {
+        error!("duplicate id table test failed: {:?}", e);
+    }
+    if let Err(e) = pt_local_cpus_test() {
+        error!("pci local_cpus test failed: {:?}", e);
+    }
+    if let Err(e) = pt_device_list_test() {
+        error!("pci device list test failed: {:?}", e);
+    }
+    if let Err(e) = pt_synthetic_device_test() {
+        error!("pci synthetic device test failed: {:?}", e);
+    }
+    if let Err(e) = pt_deferred_add_test() {
+        error!("pci deferred add test failed: {:?}", e);
+    }
+    if let Err(e) = pt_probe_time_test() {
+        error!("pci probe time test failed: {:?}", e);
+    }
+    if let Err(e) = pt_stats_snapshot_test() {
+        error!("pci stats snapshot test failed: {:?}", e);
+    }
+    if let Err(e) = pt_remove_while_iterating_test() {
+        error!("pci remove while iterating test failed: {:?}", e);
+    }
+    if let Err(e) = pt_many_devices_test() {
+        error!("driver many devices test failed: {:?}", e);
+    }
+    if let Err(e) = pt_runtime_pm_control_test() {
+        error!("pci runtime pm control test failed: {:?}", e);
+    }
+    if let Err(e) = pt_public_api_test() {
+        error!("pci driver public api test failed: {:?}", e);
+    }
+    if let Err(e) = pt_debug_locked_test() {
+        error!("pci debug locked test failed: {:?}", e);
+    }
+    if let Err(e) = pt_class_test() {
+        error!("class directory test failed: {:?}", e);
+    }
+    if let Err(e) = pt_pcie_devctl_test() {
+        error!("pcie device control test failed: {:?}", e);
+    }
+    if let Err(e) = pt_deferred_probe_test() {
+        error!("deferred probe test failed: {:?}", e);
+    }
+    if let Err(e) = pt_bridge_topology_test() {
+        error!("bridge topology test failed: {:?}", e);
+    }
+    if let Err(e) = pt_probe_device_test() {
+        error!("pci probe device test failed: {:?}", e);
+    }
+    if let Err(e) = pt_driver_dev_groups_test() {
+        error!("driver dev groups test failed: {:?}", e);
+    }
+    if let Err(e) = pt_device_registry_test() {
+        error!("device registry test failed: {:?}", e);
+    }
+    if let Err(e) = pt_suspend_resume_test() {
+        error!("suspend/resume test failed: {:?}", e);
+    }
+    if let Err(e) = pt_virtio_worker_test() {
+        error!("virtio worker test failed: {:?}", e);
+    }
+    if let Err(e) = pt_duplicate_address_test() {
+        error!("pci duplicate address test failed: {:?}", e);
+    }
+}
+
+/// 检查设备是否已经绑定到了指定的驱动上
+fn pt_check_bound(dev: &Arc<TestDevice>, drv: &Arc<TestDriver>) -> Result<(), SystemError> {
+    let bound = dev.driver().ok_or(SystemError::ENODEV)?;
+    if !Arc::ptr_eq(&bound, &(drv.clone() as Arc<dyn Driver>)) {
+        return Err(SystemError::EINVAL);
+    }
+    if drv.probe_stats().map(|s| s.probe_ok()) != Some(1) {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试驱动与设备的注册顺序不影响它们的绑定
+///
+/// 分别按照“先驱动后设备”和“先设备后驱动”的顺序注册一对只能互相匹配的设备和驱动，
+/// 然后检查设备都绑定到了对应的驱动上
+fn pt_bind_order_test() -> Result<(), SystemError> {
+    // 先注册驱动，再注册设备
+    let mut drv = TestDriver::with_name("PciTestDrvFirst");
+    drv.add_dynid(PciDeviceID::new(0x1234, 0x0001))?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+    let dev = Arc::new(TestDevice::with_id(
+        "PciTestDrvFirstDev",
+        PciDeviceID::new(0x1234, 0x0001),
+    ));
+    pci_bus().device_register(dev.clone())?;
+    pt_check_bound(&dev, &drv)?;
+
+    // 先注册设备，再注册驱动
+    let dev = Arc::new(TestDevice::with_id(
+        "PciTestDevFirstDev",
+        PciDeviceID::new(0x1234, 0x0002),
+    ));
+    pci_bus().device_register(dev.clone())?;
+    if dev.driver().is_some() {
+        return Err(SystemError::EINVAL);
+    }
+    let mut drv = TestDriver::with_name("PciTestDevFirst");
+    drv.add_dynid(PciDeviceID::new(0x1234, 0x0002))?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+    pt_check_bound(&dev, &drv)?;
+
+    Ok(())
+}
+
+/// 测试多个驱动都能匹配同一个设备时，优先级高的驱动先探测，优先级相同时先注册的驱动先探测
+fn pt_probe_priority_test() -> Result<(), SystemError> {
+    let cases = [
+        // (设备ID, 先注册的驱动的优先级, 后注册的驱动的优先级, 期望绑定后注册的驱动)
+        (0x0005, 0, 10, true),
+        (0x0006, 10, 0, false),
+        (0x0007, 0, 0, false),
+    ];
+    for (device, first_priority, second_priority, expect_second) in cases {
+        let id = PciDeviceID::new(0x1234, device);
+        let mut drivers = Vec::new();
+        for (i, priority) in [first_priority, second_priority].into_iter().enumerate() {
+            let mut drv = TestDriver::with_name(&format!("PciTestPrio{:04x}_{}", device, i));
+            drv.add_dynid(id)?;
+            drv.set_probe_priority(priority);
+            let drv = Arc::new(drv);
+            pci_bus().driver_register(drv.clone())?;
+            drivers.push(drv);
+        }
+
+        // 驱动都注册之后再注册设备，此时两个驱动都可以匹配该设备
+        let dev = Arc::new(TestDevice::with_id(
+            &format!("PciTestPrioDev{:04x}", device),
+            id,
+        ));
+        pci_bus().device_register(dev.clone())?;
+        let expected = &drivers[expect_second as usize];
+        pt_check_bound(&dev, expected)?;
+    }
+    Ok(())
+}
+
+/// 测试驱动探测失败之后，设备核心会继续尝试下一个能够匹配的驱动
+///
+/// 探测失败的驱动优先级更高，因此会先被尝试。失败之后设备不能留下它的任何痕迹
+fn pt_probe_failure_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0009);
+    let mut failing = TestDriver::with_name("PciTestProbeFail");
+    failing.add_dynid(id)?;
+    failing.set_probe_priority(10);
+    failing.set_fail_probe(true);
+    let failing = Arc::new(failing);
+    pci_bus().driver_register(failing.clone())?;
+
+    let mut drv = TestDriver::with_name("PciTestProbeFallback");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+
+    let dev = Arc::new(TestDevice::with_id("PciTestProbeFailDev", id));
+    pci_bus().device_register(dev.clone())?;
+    pt_check_bound(&dev, &drv)?;
+
+    if failing.probe_calls() != 1 || !failing.devices().is_empty() {
+        return Err(SystemError::EINVAL);
+    }
+    if failing
+        .probe_stats()
+        .map(|s| (s.probe_ok(), s.probe_failed()))
+        != Some((0, 1))
+    {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试注销驱动之后，它绑定的设备都被解除绑定，并且可以重新绑定到新注册的驱动上
+fn pt_unregister_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0003);
+    let devs = [
+        Arc::new(TestDevice::with_id("PciTestUnregDev0", id)),
+        Arc::new(TestDevice::with_id("PciTestUnregDev1", id)),
+    ];
+    for dev in devs.iter() {
+        pci_bus().device_register(dev.clone())?;
+    }
+
+    let mut drv = TestDriver::with_name("PciTestUnreg");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+    if drv.device_count() != devs.len() {
+        return Err(SystemError::EINVAL);
+    }
+
+    pci_bus().driver_unregister(&(drv.clone() as Arc<dyn PciDriver>))?;
+    // 测试仍然持有驱动的引用，如果设备还保留着指向驱动的Weak，driver()会返回Some
+    if devs.iter().any(|dev| dev.driver().is_some()) || drv.device_count() != 0 {
+        return Err(SystemError::EINVAL);
+    }
+    let bus = pci_bus() as Arc<dyn Bus>;
+    if bus.find_driver_by_name("PciTestUnreg").is_some() || drv.bus().is_some() {
+        return Err(SystemError::EINVAL);
+    }
+    // 重复注销应当失败
+    if pci_bus().driver_unregister(&(drv.clone() as Arc<dyn PciDriver>)) != Err(SystemError::ENODEV)
+    {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 同名驱动可以重新注册，并重新绑定这些设备
+    let mut drv = TestDriver::with_name("PciTestUnreg");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+    for dev in devs.iter() {
+        let bound = dev.driver().ok_or(SystemError::ENODEV)?;
+        if !Arc::ptr_eq(&bound, &(drv.clone() as Arc<dyn Driver>)) {
+            return Err(SystemError::EINVAL);
+        }
+    }
+
+    Ok(())
+}
+
+/// 记录收到的通知，以及收到通知时设备是否在sysfs中
+#[derive(Debug)]
+struct RecordingNotifier {
+    events: SpinLock<Vec<(&'static str, String, bool)>>,
+}
+
+impl RecordingNotifier {
+    fn new() -> Self {
+        Self {
+            events: SpinLock::new(Vec::new()),
+        }
+    }
+
+    fn record(&self, event: &'static str, device: &Arc<dyn PciDevice>) {
+        let in_sysfs = device.inode().is_some();
+        self.events
+            .lock_irqsave()
+            .push((event, device.name(), in_sysfs));
+    }
+}
+
+impl PciBusNotifier for RecordingNotifier {
+    fn on_device_add(&self, device: &Arc<dyn PciDevice>) {
+        self.record("add", device);
+    }
+
+    fn on_device_remove(&self, device: &Arc<dyn PciDevice>) {
+        self.record("remove", device);
+    }
+}
+
+/// 测试设备增删的通知：添加的通知在sysfs目录创建之后发出，移除的通知在sysfs目录删除之前发出
+fn pt_notifier_test() -> Result<(), SystemError> {
+    let notifier = Arc::new(RecordingNotifier::new());
+    pci_bus().register_notifier(notifier.clone())?;
+
+    let dev = Arc::new(TestDevice::with_id(
+        "PciTestNotifierDev",
+        PciDeviceID::new(0x1234, 0x0004),
+    ));
+    let pci_dev = dev.clone() as Arc<dyn PciDevice>;
+    let r = pci_bus().device_register(pci_dev.clone()).map(|_| {
+        pci_device_manager().device_remove(&pci_dev);
+    });
+    pci_bus().unregister_notifier(&(notifier.clone() as Arc<dyn PciBusNotifier>))?;
+    r?;
+
+    let name = dev.name();
+    let expected = [("add", name.clone(), true), ("remove", name, true)];
+    if *notifier.events.lock_irqsave() != expected || dev.inode().is_some() {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 检查`dir`目录下名为`name`的符号链接指向`target`
+fn pt_check_link(
+    dir: &Arc<KernFSInode>,
+    name: &str,
+    target: &Arc<KernFSInode>,
+) -> Result<(), SystemError> {
+    let link = dir.find_child(name).ok_or(SystemError::ENOENT)?;
+    if link.inode_type() != KernInodeType::SymLink {
+        return Err(SystemError::EINVAL);
+    }
+    let link_target = link.symlink_target().ok_or(SystemError::ENOENT)?;
+    if !Arc::ptr_eq(&link_target, target) {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试设备从探测、绑定到移除的完整流程，并检查每一步之后的sysfs状态
+///
+/// 绑定之后：驱动的probe()被调用一次，设备的sysfs目录存在，
+/// 设备目录下的driver和subsystem、驱动目录下的设备、/sys/bus/pci/devices下的设备这些链接都指向正确的目录。
+/// 移除之后：驱动的remove()被调用一次，上述目录和链接都被删除，驱动本身不受影响
+fn pt_lifecycle_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0008);
+    let mut drv = TestDriver::with_name("PciTestLifecycle");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+    let drv_dir = drv.inode().ok_or(SystemError::ENOENT)?;
+    let bus_dir = pci_bus()
+        .subsystem()
+        .subsys()
+        .inode()
+        .ok_or(SystemError::ENOENT)?;
+    let bus_devices_dir = pci_bus()
+        .subsystem()
+        .devices_kset()
+        .and_then(|kset| kset.inode())
+        .ok_or(SystemError::ENOENT)?;
+
+    let dev = Arc::new(TestDevice::with_id("PciTestLifecycleDev", id));
+    let pci_dev = dev.clone() as Arc<dyn PciDevice>;
+    pci_bus().device_register(pci_dev.clone())?;
+    let name = dev.name();
+
+    pt_check_bound(&dev, &drv)?;
+    if drv.probe_calls() != 1 || drv.remove_calls() != 0 || drv.device_count() != 1 {
+        return Err(SystemError::EINVAL);
+    }
+    if !dev
+        .kobj_state()
+        .contains(KObjectState::INITIALIZED | KObjectState::IN_SYSFS)
+    {
+        return Err(SystemError::EINVAL);
+    }
+    let dev_dir = dev.inode().ok_or(SystemError::ENOENT)?;
+    let parent_dir = dev_dir.parent().ok_or(SystemError::ENOENT)?;
+    let listed = parent_dir.find_child(&name).ok_or(SystemError::ENOENT)?;
+    if !Arc::ptr_eq(&listed, &dev_dir) || dev_dir.inode_type() != KernInodeType::Dir {
+        return Err(SystemError::EINVAL);
+    }
+    pt_check_link(&dev_dir, "driver", &drv_dir)?;
+    pt_check_link(&dev_dir, "subsystem", &bus_dir)?;
+    pt_check_link(&drv_dir, &name, &dev_dir)?;
+    pt_check_link(&bus_devices_dir, &name, &dev_dir)?;
+
+    pci_device_manager().device_remove(&pci_dev);
+    if drv.remove_calls() != 1 || dev.driver().is_some() || drv.device_count() != 0 {
+        return Err(SystemError::EINVAL);
+    }
+    if dev.kobj_state().contains(KObjectState::IN_SYSFS) {
+        return Err(SystemError::EINVAL);
+    }
+    if dev.inode().is_some()
+        || parent_dir.find_child(&name).is_some()
+        || dev_dir.find_child("subsystem").is_some()
+        || drv_dir.find_child(&name).is_some()
+        || bus_devices_dir.find_child(&name).is_some()
+        || (pci_bus() as Arc<dyn Bus>)
+            .find_device_by_name(&name)
+            .is_some()
+    {
+        return Err(SystemError::EEXIST);
+    }
+    // 驱动仍然注册在总线上
+    if drv.inode().is_none() || drv.bus().is_none() {
+        return Err(SystemError::EINVAL);
+    }
+
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))
+}
+
+/// 从`dir`开始查找`name`并解析其中的符号链接，返回最终的sysfs目录对应的kobject
+fn pt_resolve_link(dir: &Arc<KernFSInode>, name: &str) -> Result<Arc<dyn KObject>, SystemError> {
+    let inode = (dir.clone() as Arc<dyn IndexNode>)
+        .lookup_follow_symlink(name, VFS_MAX_FOLLOW_SYMLINK_TIMES)?
+        .downcast_arc::<KernFSInode>()
+        .ok_or(SystemError::EINVAL)?;
+    let kobj = match inode.private_data_mut().as_ref() {
+        Some(KernInodePrivateData::SysFS(SysFSKernPrivateData::Dir(dir_priv))) => dir_priv.kobj(),
+        _ => None,
+    };
+    kobj.ok_or(SystemError::ENOENT)
+}
+
+/// 测试绑定之后，driver<->device之间的符号链接经过路径查找解析到正确的kobject
+///
+/// 设备目录下的driver链接解析到驱动，驱动目录下以设备命名的链接解析到设备；
+/// 从/sys开始、经过多个链接的完整路径也解析到同一个目录
+fn pt_driver_link_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0016);
+    let mut drv = TestDriver::with_name("PciTestDriverLink");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+    let dev = Arc::new(TestDevice::with_id("PciTestDriverLinkDev", id));
+    let pci_dev = dev.clone() as Arc<dyn PciDevice>;
+    pci_bus().device_register(pci_dev.clone())?;
+
+    let check = || -> Result<(), SystemError> {
+        pt_check_bound(&dev, &drv)?;
+        let dev_dir = dev.inode().ok_or(SystemError::ENOENT)?;
+        let drv_dir = drv.inode().ok_or(SystemError::ENOENT)?;
+        let drv_kobj = pt_resolve_link(&dev_dir, "driver")?;
+        let dev_kobj = pt_resolve_link(&drv_dir, &dev.name())?;
+        if !Arc::ptr_eq(&drv_kobj, &(drv.clone() as Arc<dyn KObject>))
+            || !Arc::ptr_eq(&dev_kobj, &(dev.clone() as Arc<dyn KObject>))
+        {
+            return Err(SystemError::EINVAL);
+        }
+
+        // /sys/bus/pci/devices/<dev> -> 设备目录，driver -> 驱动目录，<dev> -> 设备目录
+        let path = format!("/sys/bus/pci/devices/{0}/driver/{0}", dev.name());
+        let resolved = ROOT_INODE().lookup_follow_symlink(&path, VFS_MAX_FOLLOW_SYMLINK_TIMES)?;
+        if resolved.metadata()?.inode_id != dev_dir.metadata()?.inode_id {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(())
+    };
+    let result = check();
+
+    pci_device_manager().device_remove(&pci_dev);
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))?;
+    result
+}
+
+/// 测试总线无关的注册流程可以被其他总线复用
+///
+/// 在一条只实现了自己的match_device()的测试总线上注册驱动和设备，检查绑定和解除绑定的结果，
+/// 测试结束之后移除设备并注销总线
+fn pt_generic_bus_test() -> Result<(), SystemError> {
+    let bus = TestBus::new() as Arc<dyn Bus>;
+    bus_register(bus.clone())?;
+
+    let drv = Arc::new(TestDriver::with_name("BusTest"));
+    bus_manager().register_driver(&bus, drv.clone() as Arc<dyn Driver>)?;
+
+    let matched = Arc::new(TestDevice::with_id("BusTestDev", PciDeviceID::dummpy()));
+    let unmatched = Arc::new(TestDevice::with_id("OtherBusDev", PciDeviceID::dummpy()));
+    for dev in [&matched, &unmatched] {
+        // 测试总线没有根设备，把设备放在/sys/devices下
+        dev.set_parent(Some(Arc::downgrade(
+            &(sys_devices_kset() as Arc<dyn KObject>),
+        )));
+        bus_manager().register_device(&bus, dev.clone() as Arc<dyn Device>)?;
+    }
+
+    let bound = matched.driver().ok_or(SystemError::ENODEV)?;
+    if !Arc::ptr_eq(&bound, &(drv.clone() as Arc<dyn Driver>)) || unmatched.driver().is_some() {
+        return Err(SystemError::EINVAL);
+    }
+    if bus.find_device_by_name("BusTestDev").is_none() {
+        return Err(SystemError::EINVAL);
+    }
+    // subsystem链接指向测试总线在/sys/bus下的目录
+    let bus_dir = bus
+        .subsystem()
+        .subsys()
+        .inode()
+        .ok_or(SystemError::ENOENT)?;
+    for dev in [&matched, &unmatched] {
+        let dev_dir = dev.inode().ok_or(SystemError::ENOENT)?;
+        pt_check_link(&dev_dir, "subsystem", &bus_dir)?;
+    }
+
+    let drv = drv as Arc<dyn Driver>;
+    // 设备和驱动还在总线上时不能注销总线
+    if bus_unregister(bus.clone()) != Err(SystemError::EBUSY) {
+        return Err(SystemError::EINVAL);
+    }
+    bus_manager().unregister_driver(&bus, &drv)?;
+    if matched.driver().is_some() || drv.device_count() != 0 {
+        return Err(SystemError::EINVAL);
+    }
+    for dev in [matched, unmatched] {
+        device_manager().remove(&(dev as Arc<dyn Device>));
+    }
+    bus_unregister(bus.clone())?;
+
+    let bus_root = sys_bus_kset().inode().ok_or(SystemError::ENOENT)?;
+    if bus_root.find_child("pci_test_bus").is_some() || bus.subsystem().bus().is_some() {
+        return Err(SystemError::EEXIST);
+    }
+    Ok(())
+}
+
+/// 测试不在任何总线上的设备可以正常添加和移除，并且没有subsystem链接
+fn pt_no_bus_subsystem_test() -> Result<(), SystemError> {
+    let dev = Arc::new(TestDevice::with_id(
+        "PciTestNoBusDev",
+        PciDeviceID::dummpy(),
+    ));
+    let dev = dev as Arc<dyn Device>;
+    device_manager().device_default_initialize(&dev);
+    device_manager().add_device(dev.clone())?;
+
+    let dev_dir = dev.inode().ok_or(SystemError::ENOENT)?;
+    let r = if dev_dir.find_child("subsystem").is_some() {
+        Err(SystemError::EEXIST)
+    } else {
+        Ok(())
+    };
+    device_manager().remove(&dev);
+    if dev.inode().is_some() {
+        return Err(SystemError::EEXIST);
+    }
+    r
+}
+
+/// 测试两个设备使用相同的带设备号的标识符时，后添加的设备会被拒绝
+fn pt_dup_id_table_test() -> Result<(), SystemError> {
+    let id_table = |minor| {
+        IdTable::new(
+            "testPci".to_string(),
+            Some(DeviceNumber::new(Major::new(240), minor)),
+        )
+    };
+    let new_dev = |name, minor| {
+        let dev = Arc::new(
+            TestDevice::with_id(name, PciDeviceID::dummpy()).with_id_table(id_table(minor)),
+        ) as Arc<dyn Device>;
+        device_manager().device_default_initialize(&dev);
+        dev
+    };
+    let first = new_dev("PciTestIdDev0", 0);
+    let dup = new_dev("PciTestIdDev1", 0);
+    let other = new_dev("PciTestIdDev2", 1);
+
+    device_manager().add_device(first.clone())?;
+    let r = match device_manager().add_device(dup.clone()) {
+        Err(SystemError::EEXIST) if dup.inode().is_none() => {
+            // 同一个驱动的另一个实例使用不同的设备号，可以正常添加
+            device_manager().add_device(other.clone()).map(|_| {
+                device_manager().remove(&other);
+            })
+        }
+        Ok(_) => {
+            device_manager().remove(&dup);
+            Err(SystemError::EINVAL)
+        }
+        _ => Err(SystemError::EINVAL),
+    };
+    device_manager().remove(&first);
+    r?;
+
+    // 移除之后标识符被释放，可以再次使用
+    device_manager().add_device(dup.clone())?;
+    device_manager().remove(&dup);
+    Ok(())
+}
+
+/// 测试没有NUMA信息的设备的local_cpus包含所有CPU，numa_node为-1
+fn pt_local_cpus_test() -> Result<(), SystemError> {
+    let dev = Arc::new(TestDevice::new()) as Arc<dyn KObject>;
+    let nr_cpus = smp_cpu_manager().possible_cpus_count() as usize;
+    let expected = smp_cpu_manager().present_cpus().to_hex_string(nr_cpus);
+
+    let mut buf = [0u8; 64];
+    let len = LocalCpus.show(dev.clone(), &mut buf)?;
+    if &buf[..len] != format!("{}\n", expected).as_bytes() {
+        return Err(SystemError::EINVAL);
+    }
+    let len = NumaNode.show(dev, &mut buf)?;
+    if &buf[..len] != format!("{}\n", NUMA_NO_NODE).as_bytes() {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 只有头部的PCI设备结构体，用于测试PCI设备链表
+struct MockPciStructure {
+    header: PciDeviceStructureHeader,
+}
+
+impl MockPciStructure {
+    fn new(device: u8) -> Box<Self> {
+        Box::new(Self {
+            header: PciDeviceStructureHeader {
+                bus_device_function: BusDeviceFunction {
+                    bus: 0xff,
+                    device,
+                    function: 0,
+                },
+                vendor_id: 0x1234,
+                device_id: device as u16,
+                command: 0,
+                status: 0,
+                revision_id: 0,
+                prog_if: 0,
+                subclass: 0,
+                class_code: 0xff,
+                cache_line_size: 0,
+                latency_timer: 0,
+                header_type: 0x7f,
+                bist: 0,
+            },
+        })
+    }
+}
+
+impl PciDeviceStructure for MockPciStructure {
+    fn header_type(&self) -> HeaderType {
+        HeaderType::Unrecognised(self.header.header_type)
+    }
+
+    fn common_header(&self) -> &PciDeviceStructureHeader {
+        &self.header
+    }
+
+    fn common_header_mut(&mut self) -> &mut PciDeviceStructureHeader {
+        &mut self.header
+    }
+
+    fn irq_type_mut(&mut self) -> Option<&mut IrqType> {
+        None
+    }
+
+    fn irq_vector_mut(&mut self) -> Option<&mut Vec<crate::exception::IrqNumber>> {
+        None
+    }
+}
+
+/// 测试在遍历PCI设备链表的快照期间增删设备
+///
+/// 模拟rescan与驱动初始化交替进行：每访问一个设备之后都修改链表，
+/// 已经被移除的设备应当返回None，而不是访问到失效的引用
+fn pt_device_list_test() -> Result<(), SystemError> {
+    let list = PciDeviceLinkedList::new();
+    for device in 1..=3 {
+        list.add(MockPciStructure::new(device));
+    }
+
+    let snapshot = list.bus_device_functions(|d| d.common_header().vendor_id == 0x1234);
+    if snapshot.len() != 3 {
+        return Err(SystemError::EINVAL);
+    }
+
+    let mut visited = Vec::new();
+    for (i, bdf) in snapshot.iter().enumerate() {
+        let r = list.with_device_mut(*bdf, |d| {
+            d.common_header_mut().command = 1;
+            d.common_header().device_id
+        });
+        if let Some(device_id) = r {
+            visited.push(device_id);
+        }
+        if i == 0 {
+            // 在两次访问之间移除下一个设备，并添加一个新的设备
+            list.remove(snapshot[1]).ok_or(SystemError::ENOENT)?;
+            list.add(MockPciStructure::new(4));
+        }
+    }
+    if visited != [1, 3] {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 新添加的设备不在快照中，也不会被上面的遍历修改
+    let new_bdf = BusDeviceFunction {
+        bus: 0xff,
+        device: 4,
+        function: 0,
+    };
+    if list.with_device(new_bdf, |d| d.common_header().command) != Some(0)
+        || list.remove(snapshot[1]).is_some()
+        || list.num() != 3
+    {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试手动注册的pci设备与枚举到的设备一样加入设备模型，并且可以被注销
+fn pt_synthetic_device_test() -> Result<(), SystemError> {
+    let bars = [BarInfo::IO {
+        address: 0xc000,
+        size: 0x40,
+    }];
+    let dev = register_synthetic_pci_device(0x1234, 0x0010, 0x020000, &bars)?;
+    let bdf = dev.bus_device_function().ok_or(SystemError::EINVAL)?;
+    let name = dev.name();
+    if bdf.bus != SYNTHETIC_PCI_BUS
+        || name != PciAddress::from(bdf).to_string()
+        || dev.vendor() != 0x1234
+        || dev.device_id() != 0x0010
+    {
+        return Err(SystemError::EINVAL);
+    }
+    let class = PCI_DEVICE_LINKEDLIST
+        .with_device(bdf, |d| {
+            let h = d.common_header();
+            (h.class_code, h.subclass, h.prog_if)
+        })
+        .ok_or(SystemError::ENOENT)?;
+    if class != (0x02, 0x00, 0x00) || pci_bus().find_device_by_name(&name).is_none() {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 以设备地址命名的驱动可以匹配这个设备
+    let drv = Arc::new(TestDriver::with_name(&name));
+    pci_bus().driver_register(drv.clone())?;
+    let bound = dev.driver().ok_or(SystemError::ENODEV)?;
+    if !Arc::ptr_eq(&bound, &(drv.clone() as Arc<dyn Driver>)) || drv.probe_calls() != 1 {
+        return Err(SystemError::EINVAL);
+    }
+
+    unregister_synthetic_pci_device(&dev)?;
+    if drv.remove_calls() != 1
+        || dev.driver().is_some()
+        || PCI_DEVICE_LINKEDLIST.contains(PciAddress::from(bdf))
+        || pci_bus().find_device_by_name(&name).is_some()
+    {
+        return Err(SystemError::EINVAL);
+    }
+    if unregister_synthetic_pci_device(&dev) != Err(SystemError::ENOENT) {
+        return Err(SystemError::EINVAL);
+    }
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))?;
+    Ok(())
+}
+
+/// 测试延迟添加的设备最终会被添加并绑定到驱动上
+fn pt_deferred_add_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0011);
+    let mut drv = TestDriver::with_name("PciTestDeferred");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+
+    let dev = Arc::new(TestDevice::with_id("PciTestDeferredDev", id));
+    let pci_dev = dev.clone() as Arc<dyn PciDevice>;
+    pci_device_manager().device_add_deferred(pci_dev.clone());
+    // 添加线程可能已经添加了这个设备，此时设备必须已经完整地加入了sysfs
+    if dev.driver().is_some() && !dev.kobj_state().contains(KObjectState::IN_SYSFS) {
+        return Err(SystemError::EINVAL);
+    }
+
+    pci_device_manager().flush_deferred_adds();
+    pt_check_bound(&dev, &drv)?;
+    if !dev.kobj_state().contains(KObjectState::IN_SYSFS) {
+        return Err(SystemError::EINVAL);
+    }
+
+    pci_device_manager().device_remove(&pci_dev);
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))?;
+    Ok(())
+}
+
+/// 测试驱动probe花费的时间被记录在设备的匹配统计中
+fn pt_probe_time_test() -> Result<(), SystemError> {
+    let dev = register_synthetic_pci_device(0x1234, 0x0012, 0, &[])?;
+    let stats = dev.match_stats().ok_or(SystemError::EINVAL)?;
+    if stats.probe_time_us() != 0 {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 以设备地址命名的驱动可以匹配这个设备，它的probe()会睡眠10ms
+    let mut drv = TestDriver::with_name(&dev.name());
+    drv.set_probe_delay_ms(10);
+    let drv = Arc::new(drv);
+    let r = pci_bus().driver_register(drv.clone());
+    let bound = dev.driver().is_some();
+    let probe_time_us = stats.probe_time_us();
+    unregister_synthetic_pci_device(&dev)?;
+    r?;
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))?;
+
+    if !bound || probe_time_us == 0 {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试在统计信息被另一个线程并发更新时，读到的快照总是一致的
+fn pt_stats_snapshot_test() -> Result<(), SystemError> {
+    const ROUNDS: usize = 10000;
+    let stats = Arc::new(PciMatchStats::new());
+    let done = Arc::new(AtomicBool::new(false));
+
+    let (wstats, wdone) = (stats.clone(), done.clone());
+    let writer = move || {
+        for i in 0..ROUNDS {
+            wstats.record_try();
+            if i % 2 == 0 {
+                wstats.record_failure(&SystemError::EIO);
+            } else {
+                wstats.record_probe_time(i as u64);
+            }
+        }
+        wdone.store(true, Ordering::SeqCst);
+        0
+    };
+    KernelThreadMechanism::create_and_run(
+        KernelThreadClosure::EmptyClosure((Box::new(writer), ())),
+        "pt_stats_writer".to_string(),
+    )
+    .ok_or(SystemError::ENOMEM)?;
+
+    let mut polls = 0;
+    loop {
+        let finished = done.load(Ordering::SeqCst);
+        let snapshot = stats.snapshot();
+        // 失败次数不会超过尝试次数，有失败时一定记录了错误码
+        if snapshot.bind_failures > snapshot.drivers_tried
+            || (snapshot.bind_failures > 0) != snapshot.last_error().is_some()
+        {
+            error!("inconsistent pci match stats: {:?}", snapshot);
+            return Err(SystemError::EINVAL);
+        }
+        if finished {
+            if snapshot.drivers_tried != ROUNDS || snapshot.bind_failures != ROUNDS / 2 {
+                return Err(SystemError::EINVAL);
+            }
+            return Ok(());
+        }
+        if polls >= 1000 {
+            return Err(SystemError::ETIMEDOUT);
+        }
+        // 睡眠让出CPU，让写者线程在单核上也能运行
+        nanosleep(PosixTimeSpec::new(0, 1_000_000)).ok();
+        polls += 1;
+    }
+}
+
+/// 测试在另一个线程遍历驱动的设备时移除设备：遍历只会看到仍然绑定到驱动的设备，
+/// 设备被移除之后不会再被遍历到
+fn pt_remove_while_iterating_test() -> Result<(), SystemError> {
+    const DEVICES: usize = 8;
+    let id = PciDeviceID::new(0x1234, 0x0013);
+    let mut drv = TestDriver::with_name("PciTestIterate");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+
+    let mut devices = Vec::new();
+    for i in 0..DEVICES {
+        let dev = Arc::new(TestDevice::with_id(&format!("PciTestIterateDev{}", i), id));
+        pci_bus().device_register(dev.clone() as Arc<dyn PciDevice>)?;
+        devices.push(dev);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new(AtomicBool::new(false));
+    let detached = Arc::new(AtomicBool::new(false));
+    let (idrv, istop, ifinished, idetached) = (
+        drv.clone() as Arc<dyn Driver>,
+        stop.clone(),
+        finished.clone(),
+        detached.clone(),
+    );
+    let iterator = move || {
+        while !istop.load(Ordering::SeqCst) {
+            idrv.for_each_device(|dev| {
+                if dev.driver().is_none() {
+                    idetached.store(true, Ordering::SeqCst);
+                }
+            });
+        }
+        ifinished.store(true, Ordering::SeqCst);
+        0
+    };
+    let spawned = KernelThreadMechanism::create_and_run(
+        KernelThreadClosure::EmptyClosure((Box::new(iterator), ())),
+        "pt_iterate_devices".to_string(),
+    );
+
+    let mut result = Ok(());
+    let dyn_drv = drv.clone() as Arc<dyn Driver>;
+    for dev in devices.iter() {
+        let dev = dev.clone() as Arc<dyn Device>;
+        pci_device_manager().device_remove(&(dev.clone() as Arc<dyn PciDevice>));
+        if !dev.is_dead() || dyn_drv.find_device(|d| Arc::ptr_eq(d, &dev)).is_some() {
+            result = Err(SystemError::EINVAL);
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    if spawned.is_some() {
+        while !finished.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    }
+    pci_bus().driver_unregister(&(drv.clone() as Arc<dyn PciDriver>))?;
+
+    result?;
+    if spawned.is_none() || detached.load(Ordering::SeqCst) || drv.device_count() != 0 {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试驱动管理大量设备时，for_each_device()、find_device()和device_count()的结果与耗时
+///
+/// 向一个不注册到总线的测试驱动直接加入`DEVICES`个设备，检查遍历的顺序、查找提前结束以及计数，
+/// 然后把每种方法重复`ROUNDS`次的平均耗时与克隆整个列表的devices()一起通过日志报告
+fn pt_many_devices_test() -> Result<(), SystemError> {
+    const DEVICES: usize = 512;
+    const ROUNDS: usize = 100;
+    let id = PciDeviceID::new(0x1234, 0x0015);
+    let drv = Arc::new(TestDriver::with_name("PciTestManyDevices")) as Arc<dyn Driver>;
+    let devices: Vec<Arc<dyn Device>> = (0..DEVICES)
+        .map(|i| {
+            Arc::new(TestDevice::with_id(&format!("PciTestManyDev{}", i), id)) as Arc<dyn Device>
+        })
+        .collect();
+    for dev in devices.iter() {
+        drv.add_device(dev.clone());
+    }
+    // 重复加入的设备被忽略
+    drv.add_device(devices[0].clone());
+    if drv.device_count() != DEVICES {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 按照加入的顺序遍历所有设备
+    let mut visited = 0;
+    let mut in_order = true;
+    drv.for_each_device(|dev| {
+        in_order &= devices.get(visited).is_some_and(|d| Arc::ptr_eq(d, dev));
+        visited += 1;
+    });
+    if visited != DEVICES || !in_order {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 找到之后立即停止遍历
+    let mut checked = 0;
+    let found = drv.find_device(|dev| {
+        checked += 1;
+        dev.name() == "PciTestManyDev9"
+    });
+    if !found.is_some_and(|d| Arc::ptr_eq(&d, &devices[9])) || checked != 10 {
+        return Err(SystemError::EINVAL);
+    }
+    if drv.find_device(|_| false).is_some() {
+        return Err(SystemError::EINVAL);
+    }
+
+    let last = devices[DEVICES - 1].name();
+    let bench = |f: &mut dyn FnMut() -> usize| {
+        let start = Instant::now();
+        let mut sum = 0;
+        for _ in 0..ROUNDS {
+            sum += f();
+        }
+        ((Instant::now() - start).total_micros() / ROUNDS as u64, sum)
+    };
+    let (clone_us, clone_sum) = bench(&mut || drv.devices().len());
+    let (count_us, count_sum) = bench(&mut || drv.device_count());
+    let (each_us, each_sum) = bench(&mut || {
+        let mut n = 0;
+        drv.for_each_device(|_| n += 1);
+        n
+    });
+    let (find_us, find_sum) =
+        bench(&mut || drv.find_device(|dev| dev.name() == last).iter().count());
+    info!(
+        "driver with {} devices: devices() {}us, device_count() {}us, for_each_device() {}us, find_device() {}us",
+        DEVICES, clone_us, count_us, each_us, find_us
+    );
+    if clone_sum != DEVICES * ROUNDS
+        || count_sum != DEVICES * ROUNDS
+        || each_sum != DEVICES * ROUNDS
+        || find_sum != ROUNDS
+    {
+        return Err(SystemError::EINVAL);
+    }
+
+    for dev in devices.iter() {
+        drv.delete_device(dev);
+    }
+    if drv.device_count() != 0 {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试通过power/control切换运行时电源管理的模式：
+/// 为on时空闲的设备不会被挂起，为auto时空闲的设备被挂起，有操作或者改回on时设备被恢复
+fn pt_runtime_pm_control_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0014);
+    let mut drv = TestDriver::with_name("PciTestRuntimePm");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+    let dev = Arc::new(TestDevice::with_id("PciTestRuntimePmDev", id));
+    let pci_dev = dev.clone() as Arc<dyn PciDevice>;
+    pci_bus().device_register(pci_dev.clone())?;
+    let d = dev.clone() as Arc<dyn Device>;
+
+    let check = || -> Result<(), SystemError> {
+        pt_check_bound(&dev, &drv)?;
+        if pm_runtime_idle(&d)? || drv.suspend_calls() != 0 {
+            return Err(SystemError::EINVAL);
+        }
+        pm_runtime_set_control(&d, RpmControl::Auto)?;
+        if !pm_runtime_idle(&d)? || drv.suspend_calls() != 1 {
+            return Err(SystemError::EINVAL);
+        }
+        if !pm_runtime_resume(&d)? || drv.resume_calls() != 1 {
+            return Err(SystemError::EINVAL);
+        }
+        pm_runtime_idle(&d)?;
+        pm_runtime_set_control(&d, RpmControl::On)?;
+        if drv.suspend_calls() != 2 || drv.resume_calls() != 2 {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(())
+    };
+    let r = check();
+
+    pci_device_manager().device_remove(&pci_dev);
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))?;
+    r
+}
+
+/// 测试驱动模块通过`register_pci_driver`/`unregister_pci_driver`注册的驱动能够参与匹配，
+/// 注销之后设备被解除绑定
+fn pt_public_api_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0020);
+    let mut drv = TestDriver::with_name("PciTestPublicApi");
+    drv.add_dynid(id)?;
+    let drv = Arc::new(drv);
+    register_pci_driver(drv.clone())?;
+
+    let dev = Arc::new(TestDevice::with_id("PciTestPublicApiDev", id));
+    pci_bus().device_register(dev.clone())?;
+    let r = pt_check_bound(&dev, &drv);
+
+    unregister_pci_driver(&(drv.clone() as Arc<dyn PciDriver>))?;
+    r?;
+    if dev.driver().is_some() || drv.device_count() != 0 {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试在持有设备和驱动内部的锁时格式化它们不会死锁，被占用的锁输出为"<locked>"
+fn pt_debug_locked_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0021);
+    let dev = TestDevice::with_id("PciTestDebugDev", id);
+    let mut drv = TestDriver::with_name("PciTestDebug");
+    drv.add_dynid(id)?;
+
+    let state = dev.kobj_state_mut();
+    let dev_locked = format!("{:?}", dev);
+    drop(state);
+    let ids = drv.locked_dynid_list.write();
+    let drv_locked = format!("{:?}", drv);
+    drop(ids);
+    if !dev_locked.contains("<locked>") || !drv_locked.contains("<locked>") {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 锁被释放之后输出完整的内容
+    let dev_unlocked = format!("{:?}", dev);
+    let drv_unlocked = format!("{:?}", drv);
+    if dev_unlocked.contains("<locked>")
+        || drv_unlocked.contains("<locked>")
+        || !drv_unlocked.contains("dynids")
+    {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试属于某个class的设备出现在`/sys/class/<class>`下，包括在class注册之前就已经添加的设备
+fn pt_class_test() -> Result<(), SystemError> {
+    let class = TestClass::new() as Arc<dyn Class>;
+    let add = |name: &str| -> Result<Arc<dyn Device>, SystemError> {
+        let dev = Arc::new(TestDevice::with_id(name, PciDeviceID::dummpy())) as Arc<dyn Device>;
+        dev.set_class(Some(Arc::downgrade(&class)));
+        device_manager().device_default_initialize(&dev);
+        device_manager().add_device(dev.clone())?;
+        Ok(dev)
+    };
+
+    // 先添加设备，再注册class，设备在class注册时被链接到class下
+    let early = add("PciTestClassEarly")?;
+    if class.find_device_by_name("PciTestClassEarly").is_some() {
+        device_manager().remove(&early);
+        return Err(SystemError::EINVAL);
+    }
+    class_manager().class_register(&class)?;
+    let late = add("PciTestClassLate")?;
+
+    let class_dir = class
+        .subsystem()
+        .subsys()
+        .inode()
+        .ok_or(SystemError::ENOENT)?;
+    let check = || -> Result<(), SystemError> {
+        for dev in [&early, &late] {
+            let dev_dir = dev.inode().ok_or(SystemError::ENOENT)?;
+            pt_check_link(&class_dir, &dev.name(), &dev_dir)?;
+            if class.find_device_by_name(&dev.name()).is_none() {
+                return Err(SystemError::EINVAL);
+            }
+        }
+        Ok(())
+    };
+    let r = check();
+
+    for dev in [&early, &late] {
+        device_manager().remove(dev);
+    }
+    let r = r.and_then(|_| {
+        if class_dir.find_child("PciTestClassEarly").is_some()
+            || class.find_device_by_name("PciTestClassLate").is_some()
+        {
+            return Err(SystemError::EEXIST);
+        }
+        Ok(())
+    });
+    class_manager().class_unregister(&class);
+    r
+}
+
+/// 测试PCIe Device Control寄存器的读取和修改
+///
+/// 真实的设备只读取，要么成功，要么返回EOPNOTSUPP。修改只在合成的配置空间上进行，
+/// 不会改变真实设备发起DMA时的行为
+fn pt_pcie_devctl_test() -> Result<(), SystemError> {
+    let bdfs: Vec<BusDeviceFunction> = PCI_DEVICE_LINKEDLIST
+        .read()
+        .iter()
+        .map(|d| d.common_header().bus_device_function)
+        .filter(|bdf| bdf.bus != SYNTHETIC_PCI_BUS)
+        .collect();
+    for bdf in bdfs {
+        match pcie_device_control(bdf) {
+            Ok(ctl) => {
+                if ctl.max_payload() > ctl.max_payload_supported() {
+                    return Err(SystemError::EINVAL);
+                }
+            }
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    // 合成的PCIe端点：Status中有Capabilities List，PCIe capability位于0x50，
+    // 支持最大512字节的payload，MRRS 512，MPS 256，允许Relaxed Ordering
+    let space = PtConfigSpace::new(0x1234, 0x0030, 0);
+    space.write_dword(0x04, 0x0010_0000);
+    space.write_dword(0x34, 0x50);
+    space.write_dword(0x50, 0x0002_0010);
+    space.write_dword(0x54, 0x0000_0002);
+    space.write_dword(0x58, 0x0000_2030);
+    let cfg: &dyn PciConfigAccess = &*space;
+    let orig = pcie_device_control_in(cfg)?;
+    if orig.max_payload() != 256 || orig.max_read_request() != 512 || !orig.relaxed_ordering() {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 修改不会影响寄存器中的其他位
+    let unchanged_except = |mask: u16| -> Result<(), SystemError> {
+        let devctl = pcie_device_control_in(cfg)?.devctl;
+        if (devctl ^ orig.devctl) & !mask != 0 {
+            return Err(SystemError::EIO);
+        }
+        Ok(())
+    };
+    pcie_set_relaxed_ordering(cfg, false)?;
+    unchanged_except(PCI_EXP_DEVCTL_RELAX_EN)?;
+    pcie_set_no_snoop(cfg, true)?;
+    unchanged_except(PCI_EXP_DEVCTL_RELAX_EN | PCI_EXP_DEVCTL_NOSNOOP_EN)?;
+    pcie_set_max_read_request(cfg, 128)?;
+    unchanged_except(PCI_EXP_DEVCTL_RELAX_EN | PCI_EXP_DEVCTL_NOSNOOP_EN | PCI_EXP_DEVCTL_READRQ)?;
+    let ctl = pcie_device_control_in(cfg)?;
+    if ctl.relaxed_ordering() || !ctl.no_snoop() || ctl.max_read_request() != 128 {
+        return Err(SystemError::EIO);
+    }
+    if pcie_set_max_read_request(cfg, 100) != Err(SystemError::EINVAL) {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 没有PCIe capability的配置空间
+    let legacy = PtConfigSpace::new(0x1234, 0x0031, 0);
+    if pcie_set_no_snoop(&*legacy, true) != Err(SystemError::EOPNOTSUPP_OR_ENOTSUP) {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试依赖一直没有就绪时，延迟探测会重试设备，超时之后放弃并报告设备以及最后一次的错误
+fn pt_deferred_probe_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0022);
+    let mut drv = TestDriver::with_name("PciTestDeferProbe");
+    drv.add_dynid(id)?;
+    drv.set_defer_probe(true);
+    let drv = Arc::new(drv);
+    register_pci_driver(drv.clone())?;
+
+    let dev = Arc::new(TestDevice::with_id("PciTestDeferProbeDev", id));
+    let d = dev.clone() as Arc<dyn Device>;
+    let r = (|| {
+        pci_bus().device_register(dev.clone())?;
+        if dev.driver().is_some() || !deferred_probe_manager().is_pending(&d) {
+            return Err(SystemError::EINVAL);
+        }
+
+        // 还没有超时，设备被重试，但是依赖仍然没有就绪
+        let calls = drv.probe_calls();
+        deferred_probe_manager().retry_pending(Instant::now());
+        if drv.probe_calls() <= calls || !deferred_probe_manager().is_pending(&d) {
+            return Err(SystemError::EINVAL);
+        }
+
+        // 模拟超时之后的一轮重试
+        deferred_probe_manager().retry_pending(Instant::now() + Duration::from_secs(3600));
+        let report = deferred_probe_manager()
+            .never_bound()
+            .into_iter()
+            .find(|r| r.device == d.name())
+            .ok_or(SystemError::ENOENT)?;
+        if deferred_probe_manager().is_pending(&d)
+            || report.driver != drv.name()
+            || report.error != SystemError::EPROBE_DEFER
+        {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(())
+    })();
+
+    pci_device_manager().device_remove(&(dev.clone() as Arc<dyn PciDevice>));
+    unregister_pci_driver(&(drv.clone() as Arc<dyn PciDriver>))?;
+    r?;
+    if deferred_probe_manager()
+        .never_bound()
+        .iter()
+        .any(|r| r.device == d.name())
+    {
+        return Err(SystemError::EEXIST);
+    }
+    Ok(())
+}
+
+/// 测试桥下游的设备以桥为父设备，在sysfs中位于桥的目录下，并且移除桥时下游的设备先被移除
+fn pt_bridge_topology_test() -> Result<(), SystemError> {
+    let bridge_bdf = BusDeviceFunction {
+        bus: 0xfe,
+        device: 0,
+        function: 0,
+    };
+    let child_bdf = BusDeviceFunction {
+        bus: 0xfd,
+        device: 0,
+        function: 0,
+    };
+    let bridge = Arc::new(
+        TestDevice::with_id("PciTestBridge", PciDeviceID::new(0x1234, 0x0023))
+            .with_topology(bridge_bdf, Some(child_bdf.bus)),
+    );
+    let child = Arc::new(
+        TestDevice::with_id("PciTestBridgeChild", PciDeviceID::new(0x1234, 0x0024))
+            .with_topology(child_bdf, None),
+    );
+    pci_bus().device_register(bridge.clone())?;
+    let r = (|| {
+        pci_bus().device_register(child.clone())?;
+        let parent = child
+            .dev_parent()
+            .and_then(|p| p.upgrade())
+            .ok_or(SystemError::ENOENT)?;
+        if !Arc::ptr_eq(&parent, &(bridge.clone() as Arc<dyn Device>)) {
+            return Err(SystemError::EINVAL);
+        }
+        let kobj_parent = child
+            .parent()
+            .and_then(|p| p.upgrade())
+            .ok_or(SystemError::ENOENT)?;
+        if !Arc::ptr_eq(&kobj_parent, &(bridge.clone() as Arc<dyn KObject>)) {
+            return Err(SystemError::EINVAL);
+        }
+        // 设备的目录位于桥的目录下
+        let dir = child
+            .inode()
+            .and_then(|inode| inode.parent())
+            .ok_or(SystemError::ENOENT)?;
+        if !Arc::ptr_eq(&dir, &bridge.inode().ok_or(SystemError::ENOENT)?) {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(())
+    })();
+
+    // 只移除桥，下游的设备随之被移除
+    pci_device_manager().device_remove(&(bridge.clone() as Arc<dyn PciDevice>));
+    let child_dev = child.clone() as Arc<dyn Device>;
+    let child_left = pci_bus()
+        .subsystem()
+        .devices()
+        .iter()
+        .any(|d| Arc::ptr_eq(d, &child_dev));
+    if child_left {
+        pci_device_manager().device_remove(&(child.clone() as Arc<dyn PciDevice>));
+    }
+    r?;
+    if child_left || !child.is_dead() {
+        return Err(SystemError::EEXIST);
+    }
+    Ok(())
+}
+
+/// 测试按地址探测单个设备：没有设备响应时返回ENODEV，新设备被注册并绑定驱动，
+/// 重复探测已经注册的设备不会再次注册或者probe
+fn pt_probe_device_test() -> Result<(), SystemError> {
+    let address = PciAddress::new(0, SYNTHETIC_PCI_BUS, 31, 7)?;
+    let name = address.to_string();
+    if pci_probe_device_with(address, |_| None) != Err(SystemError::ENODEV)
+        || pci_probe_device(PciAddress::new(1, 0, 0, 0)?) != Err(SystemError::ENODEV)
+        || PCI_DEVICE_LINKEDLIST.contains(address)
+        || pci_bus().find_device_by_name(&name).is_some()
+    {
+        return Err(SystemError::EINVAL);
+    }
+
+    // 合成的配置空间，每次探测都重新“读取”
+    let read_header = |bdf: BusDeviceFunction| -> Option<Box<dyn PciDeviceStructure>> {
+        synthetic_pci_structure(bdf, 0x1234, 0x0025, 0x020000, &[])
+            .ok()
+            .map(|s| Box::new(s) as Box<dyn PciDeviceStructure>)
+    };
+    // 以设备地址命名的驱动可以匹配这个设备
+    let drv = Arc::new(TestDriver::with_name(&name));
+    pci_bus().driver_register(drv.clone())?;
+    let num = PCI_DEVICE_LINKEDLIST.num();
+
+    let r = (|| {
+        pci_probe_device_with(address, read_header)?;
+        let dev = pci_bus()
+            .find_device_by_name(&name)
+            .ok_or(SystemError::ENOENT)?;
+        let bound = dev.driver().ok_or(SystemError::ENODEV)?;
+        if !Arc::ptr_eq(&bound, &(drv.clone() as Arc<dyn Driver>))
+            || drv.probe_calls() != 1
+            || PCI_DEVICE_LINKEDLIST.num() != num + 1
+        {
+            return Err(SystemError::EINVAL);
+        }
+
+        // 再次探测同一个地址
+        pci_probe_device_with(address, read_header)?;
+        let again = pci_bus()
+            .find_device_by_name(&name)
+            .ok_or(SystemError::ENOENT)?;
+        if !Arc::ptr_eq(&again, &dev)
+            || drv.probe_calls() != 1
+            || PCI_DEVICE_LINKEDLIST.num() != num + 1
+        {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(())
+    })();
+
+    if let Some(dev) = pci_bus().find_device_by_name(&name) {
+        if let Ok(pci_dev) = dev.cast::<dyn PciDevice>() {
+            pci_device_manager().device_remove(&pci_dev);
+        }
+    }
+    PCI_DEVICE_LINKEDLIST.remove(address.bdf());
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))?;
+    r
+}
+
+/// 测试驱动提供的属性组在绑定时出现在设备的目录中，解除绑定时被删除
+fn pt_driver_dev_groups_test() -> Result<(), SystemError> {
+    const ATTR: &str = "pt_driver_attr";
+    let has_attr = |dev: &Arc<TestDevice>| {
+        dev.inode()
+            .map(|inode| inode.child_names().iter().any(|n| n == ATTR))
+            .unwrap_or(false)
+    };
+
+    let id = PciDeviceID::new(0x1234, 0x0026);
+    let dev = Arc::new(TestDevice::with_id("PciTestDevGroupsDev", id));
+    pci_bus().device_register(dev.clone())?;
+    let mut drv = TestDriver::with_name("PciTestDevGroups");
+    drv.add_dynid(id)?;
+    drv.set_dev_groups(&[&TestDriverAttrGroup]);
+    let drv = Arc::new(drv);
+
+    let r = (|| {
+        if has_attr(&dev) {
+            return Err(SystemError::EINVAL);
+        }
+        pci_bus().driver_register(drv.clone())?;
+        pt_check_bound(&dev, &drv)?;
+        if !has_attr(&dev) {
+            return Err(SystemError::ENOENT);
+        }
+
+        // 解除绑定后属性被删除，重新绑定后再次出现
+        device_manager().device_driver_detach(&(dev.clone() as Arc<dyn Device>));
+        if dev.driver().is_some() || has_attr(&dev) {
+            return Err(SystemError::EINVAL);
+        }
+        bus_manager().attach_device(&(dev.clone() as Arc<dyn Device>))?;
+        if dev.driver().is_none() || !has_attr(&dev) {
+            return Err(SystemError::EINVAL);
+        }
+
+        // 注销驱动同样解除绑定
+        pci_bus().driver_unregister(&(drv.clone() as Arc<dyn PciDriver>))?;
+        if dev.driver().is_some() || has_attr(&dev) {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(())
+    })();
+
+    // 测试中途失败时驱动可能仍然注册在总线上
+    match pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>)) {
+        Ok(()) | Err(SystemError::ENODEV) => {}
+        Err(e) => return Err(e),
+    }
+    pci_device_manager().device_remove(&(dev as Arc<dyn PciDevice>));
+    r
+}
+
+/// 通过DeviceId查找设备，设备移除后条目被删除
+fn pt_device_registry_test() -> Result<(), SystemError> {
+    let id = PciDeviceID::new(0x1234, 0x0027);
+    let devs: Vec<(Arc<DeviceId>, Arc<TestDevice>)> = [
+        "PciTestRegistryDev0",
+        "PciTestRegistryDev1",
+        "PciTestRegistryDev2",
+    ]
+    .into_iter()
+    .map(|name| {
+        (
+            DeviceId::new(Some(name), None).unwrap(),
+            Arc::new(TestDevice::with_id(name, id)),
+        )
+    })
+    .collect();
+    for (dev_id, dev) in devs.iter() {
+        pci_bus().device_register(dev.clone())?;
+        device_registry().register(dev_id.clone(), dev.clone() as Arc<dyn Device>)?;
+    }
+
+    let r = (|| {
+        for (dev_id, dev) in devs.iter() {
+            let found = device_registry()
+                .lookup(dev_id)
+                .ok_or(SystemError::ENOENT)?;
+            if !Arc::ptr_eq(&found, &(dev.clone() as Arc<dyn Device>)) {
+                return Err(SystemError::EINVAL);
+            }
+        }
+        // 同一个DeviceId不能注册两次
+        if device_registry().register(devs[0].0.clone(), devs[1].1.clone() as Arc<dyn Device>)
+            != Err(SystemError::EEXIST)
+        {
+            return Err(SystemError::EINVAL);
+        }
+        if device_registry()
+            .lookup_by_name("PciTestRegistryDev1")
+            .is_none()
+        {
+            return Err(SystemError::ENOENT);
+        }
+        Ok(())
+    })();
+
+    // 移除设备时删除它在注册表中的条目
+    for (_, dev) in devs.iter() {
+        pci_device_manager().device_remove(&(dev.clone() as Arc<dyn PciDevice>));
+    }
+    r?;
+    if devs
+        .iter()
+        .any(|(dev_id, _)| device_registry().lookup(dev_id).is_some())
+    {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试系统睡眠时设备的挂起与恢复
+///
+/// 注册一个桥、桥下游的设备以及挂在该设备上的virtio设备，然后挂起并恢复它们：
+/// - 子设备先于父设备被挂起，恢复时顺序相反；virtio设备在它的pci设备挂起之前被静默，在其恢复之后被重新启用
+/// - 挂起时保存了配置空间，设备在挂起期间丢失的配置在恢复时被写回
+/// - 恢复之后设备仍然绑定在驱动上
+fn pt_suspend_resume_test() -> Result<(), SystemError> {
+    let log = PtPmLog::new();
+    let mut drv = TestDriver::with_name("PciTestSleep");
+    drv.add_dynid(PciDeviceID::new(0x1234, 0x0030))?;
+    drv.add_dynid(PciDeviceID::new(0x1234, 0x0031))?;
+    drv.set_pm_log(log.clone());
+    let drv = Arc::new(drv);
+    pci_bus().driver_register(drv.clone())?;
+
+    let bridge_cfg = PtConfigSpace::new(0x1234, 0x0030, 0x0101_0101);
+    let dev_cfg = PtConfigSpace::new(0x1234, 0x0031, 0x0202_0202);
+    let bridge = Arc::new(
+        TestDevice::with_id("PciTestSleepBridge", PciDeviceID::new(0x1234, 0x0030))
+            .with_config(bridge_cfg.clone()),
+    );
+    let dev = Arc::new(
+        TestDevice::with_id("PciTestSleepDev", PciDeviceID::new(0x1234, 0x0031))
+            .with_config(dev_cfg.clone()),
+    );
+    let vdev = Arc::new(PtVirtIODevice::new("PciTestSleepVirtio", log.clone()));
+
+    let r = (|| {
+        pci_bus().device_register(bridge.clone())?;
+        dev.set_dev_parent(Some(Arc::downgrade(&(bridge.clone() as Arc<dyn Device>))));
+        pci_bus().device_register(dev.clone())?;
+        vdev.set_dev_parent(Some(Arc::downgrade(&(dev.clone() as Arc<dyn Device>))));
+        virtio_device_manager().device_add(vdev.clone())?;
+        let vname = vdev.device_name();
+
+        let ours = [
+            bridge.clone() as Arc<dyn Device>,
+            dev.clone() as Arc<dyn Device>,
+            vdev.clone() as Arc<dyn Device>,
+        ];
+        let before = [bridge_cfg.snapshot(), dev_cfg.snapshot()];
+        dpm_manager().suspend_matching(|d| ours.iter().any(|o| Arc::ptr_eq(o, d)))?;
+        // 已经处于挂起状态时不能再次挂起
+        let again = dpm_manager().suspend_matching(|_| false);
+
+        let suspended = log.take();
+        let saved = bridge.pm_state().and_then(|s| s.saved()).is_some()
+            && dev.pm_state().and_then(|s| s.saved()).is_some();
+        // 设备在挂起期间断电
+        bridge_cfg.power_loss();
+        dev_cfg.power_loss();
+
+        dpm_manager().resume()?;
+        let resumed = log.take();
+
+        if again != Err(SystemError::EBUSY) || !saved {
+            return Err(SystemError::EINVAL);
+        }
+        let expect_suspended = [
+            format!("quiesce+reset {}", vname),
+            "suspend PciTestSleepDev".to_string(),
+            "suspend PciTestSleepBridge".to_string(),
+        ];
+        let expect_resumed = [
+            "resume PciTestSleepBridge".to_string(),
+            "resume PciTestSleepDev".to_string(),
+            format!("unquiesce {}", vname),
+        ];
+        if suspended != expect_suspended || resumed != expect_resumed {
+            error!(
+                "pt_suspend_resume_test: suspended {:?}, resumed {:?}",
+                suspended, resumed
+            );
+            return Err(SystemError::EINVAL);
+        }
+        if [bridge_cfg.snapshot(), dev_cfg.snapshot()] != before {
+            return Err(SystemError::EIO);
+        }
+        // 保存的配置空间只写回一次，设备仍然绑定在驱动上
+        if bridge.pm_state().and_then(|s| s.saved()).is_some()
+            || dev.pm_state().and_then(|s| s.saved()).is_some()
+        {
+            return Err(SystemError::EINVAL);
+        }
+        for d in [&bridge, &dev] {
+            let bound = d.driver().ok_or(SystemError::ENODEV)?;
+            if !Arc::ptr_eq(&bound, &(drv.clone() as Arc<dyn Driver>)) {
+                return Err(SystemError::EINVAL);
+            }
+        }
+        Ok(())
+    })();
+
+    if vdev.virtio_device_index().is_some() {
+        virtio_device_manager().device_remove(&(vdev.clone() as Arc<dyn VirtIODevice>))?;
+    }
+    pci_device_manager().device_remove(&(dev.clone() as Arc<dyn PciDevice>));
+    pci_device_manager().device_remove(&(bridge.clone() as Arc<dyn PciDevice>));
+    pci_bus().driver_unregister(&(drv as Arc<dyn PciDriver>))?;
+    r
+}
+
+/// 测试virtio设备的工作线程：在关中断的上下文中(模拟中断处理函数)唤醒线程之后，
+/// 工作在开中断的工作线程中执行；移除设备时线程退出，之后的唤醒被忽略
+fn pt_virtio_worker_test() -> Result<(), SystemError> {
+    let vdev = Arc::new(PtVirtIODevice::new("PciTestWorkerVirtio", PtPmLog::new()));
+    virtio_device_manager().device_add(vdev.clone())?;
+    // 工作所在线程的pid，以及执行时是否开中断
+    let ran_in: Arc<SpinLock<Option<(Pid, bool)>>> = Arc::new(SpinLock::new(None));
+
+    let r = (|| -> Result<Arc<VirtIOWorker>, SystemError> {
+        let ran = ran_in.clone();
+        let worker =
+            virtio_worker_manager().spawn(&(vdev.clone() as Arc<dyn VirtIODevice>), move || {
+                *ran.lock_irqsave() = Some((
+                    ProcessManager::current_pcb().pid(),
+                    CurrentIrqArch::is_irq_enabled(),
+                ));
+            })?;
+        {
+            let _irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+            worker.signal();
+        }
+        let mut polls = 0;
+        while worker.runs() == 0 && polls < 1000 {
+            nanosleep(PosixTimeSpec::new(0, 1_000_000)).ok();
+            polls += 1;
+        }
+        let (pid, irq_enabled) = (*ran_in.lock_irqsave()).ok_or(SystemError::ETIMEDOUT)?;
+        if Some(pid) != worker.pid() || pid == ProcessManager::current_pcb().pid() || !irq_enabled {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(worker)
+    })();
+
+    virtio_device_manager().device_remove(&(vdev.clone() as Arc<dyn VirtIODevice>))?;
+    let worker = r?;
+    if !worker.is_stopped() {
+        return Err(SystemError::EINVAL);
+    }
+    let runs = worker.runs();
+    worker.signal();
+    if worker.runs() != runs {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 测试同一个地址上的设备不能被注册两次：第二个设备被拒绝并且没有加入设备模型，
+/// 第一个设备被移除之后地址可以再次使用
+fn pt_duplicate_address_test() -> Result<(), SystemError> {
+    let bdf = BusDeviceFunction {
+        bus: 0xfa,
+        device: 1,
+        function: 0,
+    };
+    let address = PciAddress::from(bdf);
+    let first = Arc::new(
+        TestDevice::with_id("PciTestDupAddr0", PciDeviceID::new(0x1234, 0x0032))
+            .with_topology(bdf, None),
+    );
+    let second = Arc::new(
+        TestDevice::with_id("PciTestDupAddr1", PciDeviceID::new(0x1234, 0x0032))
+            .with_topology(bdf, None),
+    );
+    pci_device_manager().device_add(first.clone())?;
+
+    let r = (|| {
+        if pci_device_manager().device_add(second.clone()) != Err(SystemError::EEXIST)
+            || pci_device_manager().device_add(first.clone()) != Err(SystemError::EEXIST)
+        {
+            return Err(SystemError::EINVAL);
+        }
+        // 被拒绝的设备没有sysfs目录，也不在总线上
+        let second_dev = second.clone() as Arc<dyn Device>;
+        if second.inode().is_some()
+            || pci_bus()
+                .subsystem()
+                .devices()
+                .iter()
+                .any(|d| Arc::ptr_eq(d, &second_dev))
+        {
+            return Err(SystemError::EINVAL);
+        }
+        let found = pci_device_manager()
+            .find_by_address(address)
+            .ok_or(SystemError::ENOENT)?;
+        if !Arc::ptr_eq(&found, &(first.clone() as Arc<dyn PciDevice>)) {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(())
+    })();
+
+    pci_device_manager().device_remove(&(first.clone() as Arc<dyn PciDevice>));
+    r?;
+    if pci_device_manager().find_by_address(address).is_some() {
+        return Err(SystemError::EINVAL);
+    }
+    pci_device_manager().device_add(second.clone())?;
+    pci_device_manager().device_remove(&(second.clone() as Arc<dyn PciDevice>));
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use alloc::{
+    string::{String, ToString},
+    sync::{Arc, Weak},
+};
+use system_error::SystemError;
+
+use crate::driver::base::{
+    device::{bus::Bus, driver::Driver, Device},
+    subsys::SubSysPrivate,
+};
+
+/// # 结构功能
+/// 测试用的总线，用于验证总线无关的注册、匹配和绑定流程可以被pci以外的总线复用
+///
+/// 它只实现了自己的匹配规则：设备名称以驱动名称开头时，二者匹配
+#[derive(Debug)]
+pub struct TestBus {
+    private: SubSysPrivate,
+}
+
+impl TestBus {
+    pub fn new() -> Arc<Self> {
+        Arc::new_cyclic(|w: &Weak<Self>| Self {
+            private: SubSysPrivate::new("pci_test_bus".to_string(), Some(w.clone()), None, &[]),
+        })
+    }
+}
+
+impl Bus for TestBus {
+    fn name(&self) -> String {
+        "pci_test_bus".to_string()
+    }
+
+    fn dev_name(&self) -> String {
+        self.name()
+    }
+
+    fn subsystem(&self) -> &SubSysPrivate {
+        &self.private
+    }
+
+    fn probe(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
+        Ok(())
+    }
+
+    fn remove(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
+        Ok(())
+    }
+
+    fn shutdown(&self, _device: &Arc<dyn Device>) {}
+
+    fn resume(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
+        Ok(())
+    }
+
+    fn match_device(
+        &self,
+        device: &Arc<dyn Device>,
+        driver: &Arc<dyn Driver>,
+    ) -> Result<bool, SystemError> {
+        Ok(device.name().starts_with(&driver.name()))
+    }
+}
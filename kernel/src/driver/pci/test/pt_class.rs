@@ -0,0 +1,44 @@
+use alloc::{
+    string::ToString,
+    sync::{Arc, Weak},
+};
+
+use crate::driver::base::{
+    class::Class, device::sys_dev_char_kset, kobject::KObject, subsys::SubSysPrivate,
+};
+
+/// # 结构功能
+/// 测试用的设备类，用于验证设备与`/sys/class`之间的链接，包括设备早于class添加的情况
+#[derive(Debug)]
+pub struct TestClass {
+    subsystem: SubSysPrivate,
+}
+
+impl TestClass {
+    const NAME: &'static str = "pci_test_class";
+
+    pub fn new() -> Arc<Self> {
+        let r = Arc::new(Self {
+            subsystem: SubSysPrivate::new(Self::NAME.to_string(), None, None, &[]),
+        });
+        r.subsystem()
+            .set_class(Some(Arc::downgrade(&r) as Weak<dyn Class>));
+        r
+    }
+}
+
+impl Class for TestClass {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn dev_kobj(&self) -> Option<Arc<dyn KObject>> {
+        Some(sys_dev_char_kset() as Arc<dyn KObject>)
+    }
+
+    fn set_dev_kobj(&self, _kobj: Arc<dyn KObject>) {}
+
+    fn subsystem(&self) -> &SubSysPrivate {
+        &self.subsystem
+    }
+}
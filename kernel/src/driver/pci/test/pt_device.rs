@@ -1,6 +1,7 @@
-use core::any::Any;
+use core::{any::Any, fmt::Debug};
 
 use alloc::{
+    boxed::Box,
     string::{String, ToString},
     sync::{Arc, Weak},
 };
@@ -13,8 +14,15 @@ use crate::{
             device::{bus::Bus, driver::Driver, Device, DeviceCommonData, DeviceType, IdTable},
             kobject::{KObjType, KObject, KObjectCommonData, KObjectState, LockedKObjectState},
             kset::KSet,
+            power::runtime::DevPmRuntime,
+        },
+        pci::{
+            config_space::{PciConfigAccess, PciFunctionConfig},
+            dev_id::PciDeviceID,
+            device::PciDevice,
+            pci::BusDeviceFunction,
+            pm::PciPmState,
         },
-        pci::{dev_id::PciDeviceID, device::PciDevice},
     },
     filesystem::{
         kernfs::KernFSInode,
@@ -25,7 +33,8 @@ use crate::{
     },
     libs::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
-#[derive(Debug)]
+
+use super::pt_pm::PtConfigSpace;
 #[cast_to([sync] Device)]
 #[cast_to([sync] PciDevice)]
 /// # 结构功能
@@ -36,23 +45,87 @@ pub struct TestDevice {
     device_data: RwLock<DeviceCommonData>,
     kobj_data: RwLock<KObjectCommonData>,
     kobj_state: LockedKObjectState,
+    name: String,
+    dynid: PciDeviceID,
+    id_table: IdTable,
+    pm_runtime: DevPmRuntime,
+    bus_device_function: Option<BusDeviceFunction>,
+    secondary_bus: Option<u8>,
+    pm_state: PciPmState,
+    /// 内存中的配置空间，为None时通过设备的地址访问配置空间
+    config: Option<Arc<PtConfigSpace>>,
 }
 
 impl TestDevice {
     pub fn new() -> Self {
+        Self::with_id("PciTest", PciDeviceID::dummpy())
+    }
+
+    /// 创建一个指定名称和ID的测试设备，用于同时注册多个测试设备
+    pub fn with_id(name: &str, dynid: PciDeviceID) -> Self {
         let common_dev = RwLock::new(DeviceCommonData::default());
         let common_kobj = RwLock::new(KObjectCommonData::default());
         Self {
             device_data: common_dev,
             kobj_data: common_kobj,
             kobj_state: LockedKObjectState::new(None),
+            name: name.to_string(),
+            dynid,
+            id_table: IdTable::new("testPci".to_string(), None),
+            pm_runtime: DevPmRuntime::new(),
+            bus_device_function: None,
+            secondary_bus: None,
+            pm_state: PciPmState::new(),
+            config: None,
         }
     }
+
+    /// 指定设备的标识符，用于测试带设备号的标识符
+    pub fn with_id_table(mut self, id_table: IdTable) -> Self {
+        self.id_table = id_table;
+        self
+    }
+
+    /// 指定设备在总线上的地址，`secondary_bus`不为None时设备是一个下游总线为`secondary_bus`的桥
+    pub fn with_topology(
+        mut self,
+        bus_device_function: BusDeviceFunction,
+        secondary_bus: Option<u8>,
+    ) -> Self {
+        self.bus_device_function = Some(bus_device_function);
+        self.secondary_bus = secondary_bus;
+        self
+    }
+
+    /// 让设备使用内存中的配置空间，用于测试挂起时保存、恢复时写回配置空间
+    pub fn with_config(mut self, config: Arc<PtConfigSpace>) -> Self {
+        self.config = Some(config);
+        self
+    }
+}
+
+/// 调试输出不能等待内部的锁，否则在持有锁的地方打印设备会死锁，锁被占用时输出"<locked>"
+impl Debug for TestDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut d = f.debug_struct("TestDevice");
+        d.field("name", &self.name).field("dynid", &self.dynid);
+        match self.device_data.try_read() {
+            Some(data) => d
+                .field("bound", &data.driver.is_some())
+                .field("dead", &data.dead),
+            None => d.field("device_data", &format_args!("<locked>")),
+        };
+        match self.kobj_state.try_read() {
+            Some(state) => d.field("kobj_state", &*state),
+            None => d.field("kobj_state", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
 }
 
 impl PciDevice for TestDevice {
     fn dynid(&self) -> PciDeviceID {
-        PciDeviceID::dummpy()
+        self.dynid
     }
 
     fn vendor(&self) -> u16 {
@@ -70,6 +143,27 @@ impl PciDevice for TestDevice {
     fn subsystem_device(&self) -> u16 {
         return 0xffff;
     }
+
+    fn bus_device_function(&self) -> Option<BusDeviceFunction> {
+        self.bus_device_function
+    }
+
+    fn secondary_bus(&self) -> Option<u8> {
+        self.secondary_bus
+    }
+
+    fn pm_state(&self) -> Option<&PciPmState> {
+        Some(&self.pm_state)
+    }
+
+    fn config_space(&self) -> Option<Box<dyn PciConfigAccess>> {
+        match self.config.as_ref() {
+            Some(config) => Some(Box::new(config.clone()) as Box<dyn PciConfigAccess>),
+            None => self
+                .bus_device_function
+                .map(|bdf| Box::new(PciFunctionConfig::new(bdf)) as Box<dyn PciConfigAccess>),
+        }
+    }
 }
 
 impl Device for TestDevice {
@@ -100,7 +194,7 @@ impl Device for TestDevice {
     }
 
     fn id_table(&self) -> IdTable {
-        IdTable::new("testPci".to_string(), None)
+        self.id_table.clone()
     }
 
     fn can_match(&self) -> bool {
@@ -108,7 +202,15 @@ impl Device for TestDevice {
     }
 
     fn is_dead(&self) -> bool {
-        false
+        self.device_data.read().dead
+    }
+
+    fn set_dead(&self, dead: bool) {
+        self.device_data.write().dead = dead;
+    }
+
+    fn pm_runtime(&self) -> Option<&DevPmRuntime> {
+        Some(&self.pm_runtime)
     }
 
     fn set_bus(&self, bus: Option<Weak<dyn Bus>>) {
@@ -178,7 +280,7 @@ impl KObject for TestDevice {
     }
 
     fn name(&self) -> String {
-        "PciTest".to_string()
+        self.name.clone()
     }
 
     fn set_name(&self, _name: String) {
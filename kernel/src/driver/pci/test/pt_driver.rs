@@ -1,32 +1,89 @@
+use core::{
+    fmt::Debug,
+    ops::ControlFlow,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use alloc::{
     string::{String, ToString},
     sync::{Arc, Weak},
     vec::Vec,
 };
 
+use system_error::SystemError;
+
 use crate::{
     driver::{
         base::{
             device::{
                 bus::Bus,
-                driver::{Driver, DriverCommonData},
+                driver::{Driver, DriverCommonData, DRIVER_PROBE_PRIORITY_DEFAULT},
                 Device, IdTable,
             },
             kobject::{KObjType, KObject, KObjectCommonData, KObjectState, LockedKObjectState},
             kset::KSet,
         },
-        pci::{dev_id::PciDeviceID, device::PciDevice, driver::PciDriver},
+        pci::{
+            dev_id::PciDeviceID, device::PciDevice, driver::PciDriver, stats::PciDriverProbeStats,
+        },
+    },
+    filesystem::{
+        kernfs::KernFSInode,
+        sysfs::{
+            file::sysfs_emit_str, Attribute, AttributeGroup, SysFSOpsSupport, SYSFS_ATTR_MODE_RO,
+        },
+        vfs::syscall::ModeType,
     },
-    filesystem::kernfs::KernFSInode,
     libs::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{sleep::nanosleep, PosixTimeSpec},
 };
-#[derive(Debug)]
+
+use super::pt_pm::PtPmLog;
 #[cast_to([sync] PciDriver)]
 pub struct TestDriver {
     driver_data: RwLock<DriverCommonData>,
     kobj_data: RwLock<KObjectCommonData>,
     kobj_state: LockedKObjectState,
     pub locked_dynid_list: RwLock<Vec<Arc<PciDeviceID>>>,
+    probe_stats: PciDriverProbeStats,
+    name: String,
+    probe_priority: i32,
+    /// probe()被调用的次数
+    probe_calls: AtomicUsize,
+    /// remove()被调用的次数
+    remove_calls: AtomicUsize,
+    /// suspend()被调用的次数
+    suspend_calls: AtomicUsize,
+    /// resume()被调用的次数
+    resume_calls: AtomicUsize,
+    /// 为true时probe()总是失败
+    fail_probe: bool,
+    /// 为true时probe()总是要求延迟探测，模拟一直没有就绪的依赖
+    defer_probe: bool,
+    /// probe()中睡眠的时间（毫秒），用于模拟很慢的驱动
+    probe_delay_ms: i64,
+    /// 驱动为绑定的设备添加的属性组
+    dev_groups: &'static [&'static dyn AttributeGroup],
+    /// 记录suspend()/resume()的调用顺序
+    pm_log: Option<Arc<PtPmLog>>,
+}
+
+/// 与TestDevice一样，调试输出只尝试获取内部的锁，锁被占用时输出"<locked>"
+impl Debug for TestDriver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut d = f.debug_struct("TestDriver");
+        d.field("name", &self.name)
+            .field("probe_priority", &self.probe_priority);
+        match self.locked_dynid_list.try_read() {
+            Some(ids) => d.field("dynids", &*ids),
+            None => d.field("dynids", &format_args!("<locked>")),
+        };
+        match self.driver_data.try_read() {
+            Some(data) => d.field("devices", &data.devices.len()),
+            None => d.field("devices", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
 }
 
 /// # 结构功能
@@ -34,13 +91,76 @@ pub struct TestDriver {
 /// 在编写了实际的pci驱动后，可将该驱动删除
 impl TestDriver {
     pub fn new() -> Self {
+        Self::with_name("PciTestDriver")
+    }
+
+    /// 创建一个指定名称的测试驱动，用于同时注册多个测试驱动
+    pub fn with_name(name: &str) -> Self {
         Self {
             driver_data: RwLock::new(DriverCommonData::default()),
             kobj_data: RwLock::new(KObjectCommonData::default()),
             kobj_state: LockedKObjectState::new(None),
             locked_dynid_list: RwLock::new(vec![]),
+            probe_stats: PciDriverProbeStats::new(),
+            name: name.to_string(),
+            probe_priority: DRIVER_PROBE_PRIORITY_DEFAULT,
+            probe_calls: AtomicUsize::new(0),
+            remove_calls: AtomicUsize::new(0),
+            suspend_calls: AtomicUsize::new(0),
+            resume_calls: AtomicUsize::new(0),
+            fail_probe: false,
+            defer_probe: false,
+            probe_delay_ms: 0,
+            dev_groups: &[],
+            pm_log: None,
         }
     }
+
+    /// 设置驱动的探测优先级，需要在注册驱动之前设置
+    pub fn set_probe_priority(&mut self, priority: i32) {
+        self.probe_priority = priority;
+    }
+
+    /// 让驱动的probe()总是失败，需要在注册驱动之前设置
+    pub fn set_fail_probe(&mut self, fail: bool) {
+        self.fail_probe = fail;
+    }
+
+    /// 让驱动的probe()总是返回EPROBE_DEFER，需要在注册驱动之前设置
+    pub fn set_defer_probe(&mut self, defer: bool) {
+        self.defer_probe = defer;
+    }
+
+    /// 让驱动的probe()睡眠指定的时间，需要在注册驱动之前设置
+    pub fn set_probe_delay_ms(&mut self, ms: i64) {
+        self.probe_delay_ms = ms;
+    }
+
+    /// 设置驱动为绑定的设备添加的属性组，需要在注册驱动之前设置
+    pub fn set_dev_groups(&mut self, groups: &'static [&'static dyn AttributeGroup]) {
+        self.dev_groups = groups;
+    }
+
+    /// 在`log`中记录suspend()/resume()，需要在注册驱动之前设置
+    pub fn set_pm_log(&mut self, log: Arc<PtPmLog>) {
+        self.pm_log = Some(log);
+    }
+
+    pub fn probe_calls(&self) -> usize {
+        self.probe_calls.load(Ordering::SeqCst)
+    }
+
+    pub fn remove_calls(&self) -> usize {
+        self.remove_calls.load(Ordering::SeqCst)
+    }
+
+    pub fn suspend_calls(&self) -> usize {
+        self.suspend_calls.load(Ordering::SeqCst)
+    }
+
+    pub fn resume_calls(&self) -> usize {
+        self.resume_calls.load(Ordering::SeqCst)
+    }
 }
 
 impl PciDriver for TestDriver {
@@ -59,14 +179,29 @@ impl PciDriver for TestDriver {
         _device: &Arc<dyn PciDevice>,
         _id: &PciDeviceID,
     ) -> Result<(), system_error::SystemError> {
+        self.probe_calls.fetch_add(1, Ordering::SeqCst);
+        if self.probe_delay_ms > 0 {
+            nanosleep(PosixTimeSpec::new(0, self.probe_delay_ms * 1_000_000)).ok();
+        }
+        if self.fail_probe {
+            return Err(system_error::SystemError::EIO);
+        }
+        if self.defer_probe {
+            return Err(system_error::SystemError::EPROBE_DEFER);
+        }
         Ok(())
     }
 
     fn remove(&self, _device: &Arc<dyn PciDevice>) -> Result<(), system_error::SystemError> {
+        self.remove_calls.fetch_add(1, Ordering::SeqCst);
         Ok(())
     }
 
-    fn resume(&self, _device: &Arc<dyn PciDevice>) -> Result<(), system_error::SystemError> {
+    fn resume(&self, device: &Arc<dyn PciDevice>) -> Result<(), system_error::SystemError> {
+        self.resume_calls.fetch_add(1, Ordering::SeqCst);
+        if let Some(log) = self.pm_log.as_ref() {
+            log.record("resume", &device.name());
+        }
         Ok(())
     }
 
@@ -74,20 +209,36 @@ impl PciDriver for TestDriver {
         Ok(())
     }
 
-    fn suspend(&self, _device: &Arc<dyn PciDevice>) -> Result<(), system_error::SystemError> {
+    fn suspend(&self, device: &Arc<dyn PciDevice>) -> Result<(), system_error::SystemError> {
+        self.suspend_calls.fetch_add(1, Ordering::SeqCst);
+        if let Some(log) = self.pm_log.as_ref() {
+            log.record("suspend", &device.name());
+        }
         Ok(())
     }
+
+    fn probe_stats(&self) -> Option<&PciDriverProbeStats> {
+        Some(&self.probe_stats)
+    }
 }
 
 impl Driver for TestDriver {
     fn id_table(&self) -> Option<IdTable> {
-        Some(IdTable::new("PciTestDriver".to_string(), None))
+        Some(IdTable::new(self.name.clone(), None))
     }
 
     fn devices(&self) -> Vec<Arc<dyn Device>> {
         self.driver_data.read().devices.clone()
     }
 
+    fn __for_each_device(&self, f: &mut dyn FnMut(&Arc<dyn Device>) -> ControlFlow<()>) {
+        self.driver_data.read().for_each_device(f);
+    }
+
+    fn device_count(&self) -> usize {
+        self.driver_data.read().devices.len()
+    }
+
     fn add_device(&self, device: Arc<dyn Device>) {
         let mut guard = self.driver_data.write();
         // check if the device is already in the list
@@ -107,6 +258,14 @@ impl Driver for TestDriver {
         self.driver_data.write().bus = bus;
     }
 
+    fn probe_priority(&self) -> i32 {
+        self.probe_priority
+    }
+
+    fn dev_groups(&self) -> &'static [&'static dyn AttributeGroup] {
+        self.dev_groups
+    }
+
     fn bus(&self) -> Option<Weak<dyn Bus>> {
         self.driver_data.read().bus.clone()
     }
@@ -150,7 +309,7 @@ impl KObject for TestDriver {
     }
 
     fn name(&self) -> String {
-        "PciTestDriver".to_string()
+        self.name.clone()
     }
 
     fn set_name(&self, _name: String) {
@@ -169,3 +328,46 @@ impl KObject for TestDriver {
         *self.kobj_state.write() = state;
     }
 }
+
+/// 测试驱动为绑定的设备添加的属性组
+#[derive(Debug)]
+pub struct TestDriverAttrGroup;
+
+impl AttributeGroup for TestDriverAttrGroup {
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    fn attrs(&self) -> &[&'static dyn Attribute] {
+        &[&TestDriverAttr]
+    }
+
+    fn is_visible(
+        &self,
+        _kobj: Arc<dyn KObject>,
+        attr: &'static dyn Attribute,
+    ) -> Option<ModeType> {
+        Some(attr.mode())
+    }
+}
+
+#[derive(Debug)]
+pub struct TestDriverAttr;
+
+impl Attribute for TestDriverAttr {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "pt_driver_attr"
+    }
+
+    fn show(&self, _kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        sysfs_emit_str(buf, "bound\n")
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
@@ -0,0 +1,326 @@
+//! 测试系统睡眠用到的设备
+//!
+//! - [`PtPmLog`]按顺序记录挂起与恢复的回调
+//! - [`PtConfigSpace`]是内存中的配置空间，测试可以模拟设备在挂起期间丢失配置
+//! - [`PtVirtIODevice`]是没有transport的virtio设备，只记录quiesce/unquiesce
+
+use core::any::Any;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use system_error::SystemError;
+
+use crate::{
+    driver::{
+        base::{
+            class::Class,
+            device::{
+                bus::Bus, driver::Driver, Device, DeviceCommonData, DeviceId, DeviceType, IdTable,
+            },
+            kobject::{KObjType, KObject, KObjectCommonData, KObjectState, LockedKObjectState},
+            kset::KSet,
+        },
+        pci::config_space::{PciConfigAccess, PciConfigSource},
+        virtio::{VirtIODevice, VirtIODeviceIndex},
+    },
+    exception::{irqdesc::IrqReturn, IrqNumber},
+    filesystem::kernfs::KernFSInode,
+    libs::{
+        rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+        spinlock::SpinLock,
+    },
+};
+
+/// # 结构功能
+/// 按顺序记录挂起与恢复的回调，每一项形如`suspend PciTestSleepDev`
+#[derive(Debug)]
+pub struct PtPmLog {
+    events: SpinLock<Vec<String>>,
+}
+
+impl PtPmLog {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            events: SpinLock::new(Vec::new()),
+        })
+    }
+
+    pub fn record(&self, event: &str, dev: &str) {
+        self.events
+            .lock_irqsave()
+            .push(format!("{} {}", event, dev));
+    }
+
+    /// 取出已经记录的回调
+    pub fn take(&self) -> Vec<String> {
+        core::mem::take(&mut *self.events.lock_irqsave())
+    }
+}
+
+/// # 结构功能
+/// 内存中的配置空间(256字节)
+#[derive(Debug)]
+pub struct PtConfigSpace {
+    dwords: SpinLock<[u32; 64]>,
+}
+
+impl PtConfigSpace {
+    /// 创建厂商号、设备号为`vendor`、`device`的配置空间，其余内容由`seed`生成
+    pub fn new(vendor: u16, device: u16, seed: u32) -> Arc<Self> {
+        let mut dwords = [0u32; 64];
+        for (i, dword) in dwords.iter_mut().enumerate() {
+            *dword = seed.wrapping_mul(i as u32 + 1);
+        }
+        dwords[0] = (device as u32) << 16 | vendor as u32;
+        Arc::new(Self {
+            dwords: SpinLock::new(dwords),
+        })
+    }
+
+    /// 配置空间的全部内容
+    pub fn snapshot(&self) -> [u32; 64] {
+        *self.dwords.lock_irqsave()
+    }
+
+    /// 模拟设备断电：除了厂商号和设备号，header被清零
+    pub fn power_loss(&self) {
+        self.dwords.lock_irqsave()[1..16].fill(0);
+    }
+}
+
+impl PciConfigSource for PtConfigSpace {
+    fn config_len(&self) -> u16 {
+        256
+    }
+
+    fn read_dword(&self, offset: u16) -> u32 {
+        self.dwords.lock_irqsave()[offset as usize / 4]
+    }
+}
+
+impl PciConfigAccess for PtConfigSpace {
+    fn write_dword(&self, offset: u16, value: u32) {
+        self.dwords.lock_irqsave()[offset as usize / 4] = value;
+    }
+}
+
+/// 设备与测试共用同一个配置空间
+impl PciConfigSource for Arc<PtConfigSpace> {
+    fn config_len(&self) -> u16 {
+        (**self).config_len()
+    }
+
+    fn read_dword(&self, offset: u16) -> u32 {
+        (**self).read_dword(offset)
+    }
+}
+
+impl PciConfigAccess for Arc<PtConfigSpace> {
+    fn write_dword(&self, offset: u16, value: u32) {
+        (**self).write_dword(offset, value)
+    }
+}
+
+/// # 结构功能
+/// 测试用的virtio设备，quiesce/unquiesce只在[`PtPmLog`]中留下记录
+#[derive(Debug)]
+#[cast_to([sync] Device)]
+#[cast_to([sync] VirtIODevice)]
+pub struct PtVirtIODevice {
+    device_data: RwLock<DeviceCommonData>,
+    kobj_data: RwLock<KObjectCommonData>,
+    kobj_state: LockedKObjectState,
+    dev_id: Arc<DeviceId>,
+    name: RwLock<Option<String>>,
+    virtio_index: RwLock<Option<VirtIODeviceIndex>>,
+    log: Arc<PtPmLog>,
+}
+
+impl PtVirtIODevice {
+    pub fn new(dev_id: &str, log: Arc<PtPmLog>) -> Self {
+        Self {
+            device_data: RwLock::new(DeviceCommonData::default()),
+            kobj_data: RwLock::new(KObjectCommonData::default()),
+            kobj_state: LockedKObjectState::new(None),
+            dev_id: DeviceId::new(None, Some(dev_id.to_string())).unwrap(),
+            name: RwLock::new(None),
+            virtio_index: RwLock::new(None),
+            log,
+        }
+    }
+}
+
+impl VirtIODevice for PtVirtIODevice {
+    fn handle_irq(&self, _irq: IrqNumber) -> Result<IrqReturn, SystemError> {
+        Ok(IrqReturn::NotHandled)
+    }
+
+    fn dev_id(&self) -> &Arc<DeviceId> {
+        &self.dev_id
+    }
+
+    fn set_device_name(&self, name: String) {
+        *self.name.write() = Some(name);
+    }
+
+    fn device_name(&self) -> String {
+        self.name
+            .read()
+            .clone()
+            .unwrap_or_else(|| "pt_virtio".to_string())
+    }
+
+    fn set_virtio_device_index(&self, index: VirtIODeviceIndex) {
+        *self.virtio_index.write() = Some(index);
+    }
+
+    fn virtio_device_index(&self) -> Option<VirtIODeviceIndex> {
+        *self.virtio_index.read()
+    }
+
+    /// 保留的设备类型，不会有驱动与它匹配
+    fn device_type_id(&self) -> u32 {
+        0
+    }
+
+    fn vendor(&self) -> u32 {
+        0xffff
+    }
+
+    fn irq(&self) -> Option<IrqNumber> {
+        None
+    }
+
+    fn quiesce(&self, reset: bool) -> Result<(), SystemError> {
+        let event = if reset { "quiesce+reset" } else { "quiesce" };
+        self.log.record(event, &self.device_name());
+        Ok(())
+    }
+
+    fn unquiesce(&self) -> Result<(), SystemError> {
+        self.log.record("unquiesce", &self.device_name());
+        Ok(())
+    }
+}
+
+impl Device for PtVirtIODevice {
+    fn bus(&self) -> Option<Weak<dyn Bus>> {
+        self.device_data.read().bus.clone()
+    }
+
+    fn class(&self) -> Option<Arc<dyn Class>> {
+        None
+    }
+
+    fn driver(&self) -> Option<Arc<dyn Driver>> {
+        self.device_data.read().driver.clone()?.upgrade()
+    }
+
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::Char
+    }
+
+    fn id_table(&self) -> IdTable {
+        IdTable::new("pt_virtio".to_string(), None)
+    }
+
+    fn can_match(&self) -> bool {
+        true
+    }
+
+    fn is_dead(&self) -> bool {
+        self.device_data.read().dead
+    }
+
+    fn set_dead(&self, dead: bool) {
+        self.device_data.write().dead = dead;
+    }
+
+    fn set_bus(&self, bus: Option<Weak<dyn Bus>>) {
+        self.device_data.write().bus = bus
+    }
+
+    fn set_can_match(&self, _can_match: bool) {}
+
+    fn set_class(&self, class: Option<Weak<dyn Class>>) {
+        self.device_data.write().class = class
+    }
+
+    fn set_driver(&self, driver: Option<Weak<dyn Driver>>) {
+        self.device_data.write().driver = driver
+    }
+
+    fn state_synced(&self) -> bool {
+        true
+    }
+
+    fn dev_parent(&self) -> Option<Weak<dyn Device>> {
+        self.device_data.read().parent.clone()
+    }
+
+    fn set_dev_parent(&self, dev_parent: Option<Weak<dyn Device>>) {
+        self.device_data.write().parent = dev_parent
+    }
+}
+
+impl KObject for PtVirtIODevice {
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_inode(&self, inode: Option<Arc<KernFSInode>>) {
+        self.kobj_data.write().kern_inode = inode;
+    }
+
+    fn inode(&self) -> Option<Arc<KernFSInode>> {
+        self.kobj_data.read().kern_inode.clone()
+    }
+
+    fn parent(&self) -> Option<Weak<dyn KObject>> {
+        self.kobj_data.read().parent.clone()
+    }
+
+    fn set_parent(&self, parent: Option<Weak<dyn KObject>>) {
+        self.kobj_data.write().parent = parent;
+    }
+
+    fn kset(&self) -> Option<Arc<KSet>> {
+        self.kobj_data.read().kset.clone()
+    }
+
+    fn set_kset(&self, kset: Option<Arc<KSet>>) {
+        self.kobj_data.write().kset = kset;
+    }
+
+    fn kobj_type(&self) -> Option<&'static dyn KObjType> {
+        self.kobj_data.read().kobj_type
+    }
+
+    fn set_kobj_type(&self, ktype: Option<&'static dyn KObjType>) {
+        self.kobj_data.write().kobj_type = ktype;
+    }
+
+    fn name(&self) -> String {
+        self.device_name()
+    }
+
+    fn set_name(&self, _name: String) {
+        // do nothing
+    }
+
+    fn kobj_state(&self) -> RwLockReadGuard<KObjectState> {
+        self.kobj_state.read()
+    }
+
+    fn kobj_state_mut(&self) -> RwLockWriteGuard<KObjectState> {
+        self.kobj_state.write()
+    }
+
+    fn set_kobj_state(&self, state: KObjectState) {
+        *self.kobj_state.write() = state;
+    }
+}
@@ -0,0 +1,257 @@
+//! 以树的形式导出所有PCI设备，用于调试设备的层次结构
+//!
+//! 读取`/sys/bus/pci/devices_tree`，每一行是一个设备：地址、vendor:device以及绑定的驱动(没有时为`-`)。
+//! PCI-PCI桥还会显示它的下游总线号，桥下游的设备相对于桥缩进两个空格，例如：
+//!
+//! ```text
+//! 0000:00:00.0 8086:29c0 -
+//! 0000:00:1c.0 8086:2918 [bus 01] -
+//!   0000:01:00.0 1af4:1041 virtio_net
+//! ```
+
+use alloc::{
+    collections::BTreeSet,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use system_error::SystemError;
+
+use crate::{
+    driver::base::{device::bus::Bus, kobject::KObject},
+    filesystem::{
+        sysfs::{file::sysfs_emit_str, Attribute, SysFSOpsSupport, SYSFS_ATTR_MODE_RO},
+        vfs::syscall::ModeType,
+    },
+};
+
+use super::{
+    address::PciAddress,
+    pci::{BusDeviceFunction, PCI_DEVICE_LINKEDLIST},
+    subsys::pci_bus,
+};
+
+/// 树中的一个设备
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciTreeEntry {
+    pub bdf: BusDeviceFunction,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// PCI-PCI桥的下游总线号，其他设备为None
+    pub secondary_bus: Option<u8>,
+    /// 绑定的驱动的名称
+    pub driver: Option<String>,
+}
+
+/// # 函数的功能
+/// 取得所有PCI设备的快照，按照地址排序
+///
+/// 只在复制设备信息时短暂地持有设备链表的读锁，因此可以与rescan、热插拔并发执行。
+/// 快照之后被移除的设备仍然会出现在结果中，但不会访问到已经释放的设备
+pub fn pci_tree_snapshot() -> Vec<PciTreeEntry> {
+    let mut entries: Vec<PciTreeEntry> = PCI_DEVICE_LINKEDLIST
+        .read()
+        .iter()
+        .map(|device| {
+            let header = device.common_header();
+            PciTreeEntry {
+                bdf: header.bus_device_function,
+                vendor_id: header.vendor_id,
+                device_id: header.device_id,
+                secondary_bus: device
+                    .as_pci_to_pci_bridge_device()
+                    .map(|bridge| bridge.secondary_bus_number),
+                driver: None,
+            }
+        })
+        .collect();
+
+    // 查找驱动时会获取设备模型的锁，因此在释放链表的锁之后进行
+    let bus = pci_bus() as Arc<dyn Bus>;
+    for entry in entries.iter_mut() {
+        let name = PciAddress::from(entry.bdf).to_string();
+        entry.driver = bus
+            .find_device_by_name(&name)
+            .and_then(|dev| dev.driver())
+            .map(|drv| drv.name());
+    }
+
+    entries.sort_by_key(|e| (e.bdf.bus, e.bdf.device, e.bdf.function));
+    entries
+}
+
+/// # 函数的功能
+/// 把设备按照层次结构排列
+///
+/// 总线号不是任何桥的下游总线的设备作为根，桥的下游总线上的设备是桥的子节点。
+/// 每条总线只展开一次，因此配置错误的桥(例如下游总线指向自己)不会导致无限递归
+///
+/// ## 返回值
+/// (深度, 设备)的列表，顺序与输出的行一致
+fn pci_tree_walk(entries: &[PciTreeEntry]) -> Vec<(usize, &PciTreeEntry)> {
+    let bridged: BTreeSet<u8> = entries.iter().filter_map(|e| e.secondary_bus).collect();
+    let mut expanded = BTreeSet::new();
+    let mut out = Vec::with_capacity(entries.len());
+
+    fn walk<'a>(
+        entries: &'a [PciTreeEntry],
+        bus: u8,
+        depth: usize,
+        expanded: &mut BTreeSet<u8>,
+        out: &mut Vec<(usize, &'a PciTreeEntry)>,
+    ) {
+        if !expanded.insert(bus) {
+            return;
+        }
+        for entry in entries.iter().filter(|e| e.bdf.bus == bus) {
+            out.push((depth, entry));
+            if let Some(secondary) = entry.secondary_bus {
+                walk(entries, secondary, depth + 1, expanded, out);
+            }
+        }
+    }
+
+    let roots: BTreeSet<u8> = entries
+        .iter()
+        .map(|e| e.bdf.bus)
+        .filter(|bus| !bridged.contains(bus))
+        .collect();
+    for bus in roots {
+        walk(entries, bus, 0, &mut expanded, &mut out);
+    }
+    // 桥之间形成环时，环上的总线没有根，按照普通的根处理
+    for entry in entries {
+        walk(entries, entry.bdf.bus, 0, &mut expanded, &mut out);
+    }
+    out
+}
+
+/// # 函数的功能
+/// 把设备格式化为缩进的树
+///
+/// ## 参数
+/// - `entries`: 按照地址排序的设备
+/// - `limit`: 输出的最大长度。超出时省略之后的设备，并在最后一行说明省略了多少个设备
+pub fn format_pci_tree(entries: &[PciTreeEntry], limit: usize) -> String {
+    let lines = pci_tree_walk(entries);
+    let mut out = String::new();
+    for (i, (depth, entry)) in lines.iter().enumerate() {
+        let bdf = PciAddress::from(entry.bdf);
+        let bridge = entry
+            .secondary_bus
+            .map(|bus| format!(" [bus {:02x}]", bus))
+            .unwrap_or_default();
+        let line = format!(
+            "{:indent$}{} {:04x}:{:04x}{} {}\n",
+            "",
+            bdf,
+            entry.vendor_id,
+            entry.device_id,
+            bridge,
+            entry.driver.as_deref().unwrap_or("-"),
+            indent = depth * 2
+        );
+
+        let remaining = lines.len() - i;
+        let trailer = format!("... {} more device(s)\n", remaining);
+        // 除了最后一个设备，都要为省略提示预留空间
+        let reserve = if remaining > 1 { trailer.len() } else { 0 };
+        if out.len() + line.len() + reserve > limit {
+            if out.len() + trailer.len() <= limit {
+                out.push_str(&trailer);
+            }
+            break;
+        }
+        out.push_str(&line);
+    }
+    out
+}
+
+/// /sys/bus/pci/devices_tree
+#[derive(Debug)]
+pub struct PciDevicesTree;
+
+impl Attribute for PciDevicesTree {
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn name(&self) -> &str {
+        "devices_tree"
+    }
+
+    fn show(&self, _kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        // 为结尾的'\0'预留一个字节
+        let limit = buf.len().saturating_sub(1);
+        let tree = format_pci_tree(&pci_tree_snapshot(), limit);
+        return sysfs_emit_str(buf, &tree);
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(bus: u8, device: u8, secondary_bus: Option<u8>, driver: Option<&str>) -> PciTreeEntry {
+        PciTreeEntry {
+            bdf: BusDeviceFunction {
+                bus,
+                device,
+                function: 0,
+            },
+            vendor_id: 0x1af4,
+            device_id: 0x1000 + device as u16,
+            secondary_bus,
+            driver: driver.map(|d| d.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_nested_bridges() {
+        let entries = [
+            entry(0, 0, None, None),
+            entry(0, 1, Some(1), None),
+            entry(0, 2, None, Some("virtio_blk")),
+            entry(1, 0, Some(2), None),
+            entry(1, 3, None, Some("virtio_net")),
+            entry(2, 5, None, None),
+        ];
+        assert_eq!(
+            format_pci_tree(&entries, 4096),
+            "0000:00:00.0 1af4:1000 -\n\
+             0000:00:01.0 1af4:1001 [bus 01] -\n\
+             \x20 0000:01:00.0 1af4:1000 [bus 02] -\n\
+             \x20   0000:02:05.0 1af4:1005 -\n\
+             \x20 0000:01:03.0 1af4:1003 virtio_net\n\
+             0000:00:02.0 1af4:1002 virtio_blk\n"
+        );
+    }
+
+    #[test]
+    fn test_bridge_loop() {
+        // 桥的下游总线指向它自己所在的总线
+        let entries = [entry(3, 0, Some(3), None), entry(3, 1, None, None)];
+        let tree = format_pci_tree(&entries, 4096);
+        assert_eq!(tree.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_output_bounded() {
+        let entries: Vec<PciTreeEntry> = (0..32).map(|d| entry(0, d, None, None)).collect();
+        let line_len = "0000:00:00.0 1af4:1000 -\n".len();
+        let tree = format_pci_tree(&entries, line_len * 4);
+        assert!(tree.len() <= line_len * 4);
+        assert!(tree.ends_with(" more device(s)\n"));
+        let shown = tree.lines().count() - 1;
+        assert!(tree.ends_with(&format!("... {} more device(s)\n", 32 - shown)));
+
+        // 刚好放得下所有设备时不需要省略提示
+        let entries = &entries[..4];
+        assert_eq!(format_pci_tree(entries, line_len * 4).lines().count(), 4);
+    }
+}
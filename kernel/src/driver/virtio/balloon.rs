@@ -0,0 +1,65 @@
+//! virtio-balloon的页面选择
+//!
+//! balloon膨胀时把物理页交还给宿主机，之后客户机不能再访问这些页。
+//! 被固定的页(例如交给设备做DMA的页，见[`crate::mm::pin`])必须跳过。
+
+use alloc::vec::Vec;
+
+use crate::mm::{allocator::page_frame::PhysPageFrame, pin::page_pin_set_lock_irqsave};
+
+/// # 函数的功能
+/// 从候选的物理页中选出最多`nr_pages`个可以交给balloon的页，跳过被固定的页
+///
+/// ## 参数
+/// - `candidates`: 候选的物理页
+/// - `nr_pages`: 最多选出的页数
+/// - `is_pinned`: 判断一页是否被固定
+pub fn virtio_balloon_inflate_pages_with(
+    candidates: impl IntoIterator<Item = PhysPageFrame>,
+    nr_pages: usize,
+    is_pinned: impl Fn(PhysPageFrame) -> bool,
+) -> Vec<PhysPageFrame> {
+    candidates
+        .into_iter()
+        .filter(|frame| !is_pinned(*frame))
+        .take(nr_pages)
+        .collect()
+}
+
+/// # 函数的功能
+/// 从候选的物理页中选出最多`nr_pages`个可以交给balloon的页
+///
+/// 选出之后页仍然可能被固定，调用者需要在把页交给宿主机之前把它们从页分配器中取出
+#[allow(dead_code)]
+pub fn virtio_balloon_inflate_pages(
+    candidates: impl IntoIterator<Item = PhysPageFrame>,
+    nr_pages: usize,
+) -> Vec<PhysPageFrame> {
+    let pins = page_pin_set_lock_irqsave();
+    virtio_balloon_inflate_pages_with(candidates, nr_pages, |frame| pins.is_pinned(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::{allocator::page_frame::PageFrameCount, pin::PagePinSet};
+
+    #[test]
+    fn test_inflate_skips_dma_pages() {
+        let mut pins = PagePinSet::new();
+        // 模拟dma_alloc：分配的4页被固定
+        let dma = PhysPageFrame::from_ppn(0x12);
+        pins.pin(dma, PageFrameCount::new(4));
+
+        let candidates = (0x10..0x20).map(PhysPageFrame::from_ppn);
+        let pages = virtio_balloon_inflate_pages_with(candidates.clone(), 8, |f| pins.is_pinned(f));
+        let ppns: Vec<usize> = pages.iter().map(|f| f.ppn()).collect();
+        assert_eq!(ppns, [0x10, 0x11, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b]);
+
+        // dma_dealloc解除固定之后，这些页可以被balloon回收
+        pins.unpin(dma, PageFrameCount::new(4)).unwrap();
+        let pages = virtio_balloon_inflate_pages_with(candidates, 4, |f| pins.is_pinned(f));
+        let ppns: Vec<usize> = pages.iter().map(|f| f.ppn()).collect();
+        assert_eq!(ppns, [0x10, 0x11, 0x12, 0x13]);
+    }
+}
@@ -0,0 +1,379 @@
+//! 一致地读取virtio设备的配置空间
+//!
+//! 参考 virtio spec 2.5.1 Driver Requirements: Device Configuration Space：
+//! 设备配置空间中超过32位的字段（例如virtio-blk的capacity）以及由多个字段组成的值，
+//! 无法通过一次访问原子地读取。驱动需要在读取前后分别读取config_generation，
+//! 如果两次的值不同，说明读取期间配置发生了变化，需要重新读取。
+//!
+//! 写入配置空间时同样需要按字段的宽度访问：规范只保证对齐的1、2、4字节访问，
+//! 更宽的字段需要拆分成多次32位访问。
+//!
+//! 配置空间中的多字节字段使用设备的字节序(见[`super::endian`])，
+//! [`virtio_config_read`]和[`virtio_config_write`]负责与本机字节序之间的转换。
+
+use core::mem::{align_of, size_of, MaybeUninit};
+
+use log::error;
+use system_error::SystemError;
+
+use super::{
+    endian::{VirtIOEndian, VirtIOEndianField},
+    mmio_region::MmioRegion,
+};
+
+/// 配置空间持续变化时，最多重新读取的次数
+const VIRTIO_CONFIG_MAX_RETRIES: usize = 1000;
+
+/// # trait功能
+/// 读取设备配置空间的generation
+///
+/// 不提供config_generation的transport（例如legacy mmio）应当返回一个固定值
+pub trait VirtIOConfigGeneration {
+    fn config_generation(&self) -> u32;
+}
+
+/// # 函数的功能
+/// 读取配置空间，直到读取期间config_generation没有发生变化
+///
+/// ## 参数
+/// - `dev`: 设备
+/// - `reader`: 读取配置空间的函数，可能被调用多次
+///
+/// ## 返回值
+/// - Ok(value): 一次完整的、没有被配置变化打断的读取结果
+/// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): 配置空间一直在变化，重试多次仍然无法得到一致的结果
+pub fn virtio_read_config_consistent<D, T, F>(dev: &D, mut reader: F) -> Result<T, SystemError>
+where
+    D: VirtIOConfigGeneration + ?Sized,
+    F: FnMut(&D) -> T,
+{
+    for _ in 0..VIRTIO_CONFIG_MAX_RETRIES {
+        let before = dev.config_generation();
+        let value = reader(dev);
+        if dev.config_generation() == before {
+            return Ok(value);
+        }
+    }
+    Err(SystemError::EAGAIN_OR_EWOULDBLOCK)
+}
+
+/// # trait功能
+/// 按照指定的宽度访问设备配置空间
+pub trait VirtIOConfigAccess: VirtIOConfigGeneration {
+    /// 配置空间的长度（字节），没有配置空间时为0
+    fn config_space_len(&self) -> usize;
+
+    /// 配置空间中多字节字段的字节序，legacy设备使用本机字节序
+    fn config_endian(&self) -> VirtIOEndian {
+        VirtIOEndian::Little
+    }
+
+    /// # 函数的功能
+    /// 以`width`字节的宽度读取配置空间，`width`为1、2或4
+    ///
+    /// ## Safety
+    ///
+    /// 调用者需要保证访问没有越界，并且`offset`按`width`对齐
+    unsafe fn config_read_raw(&self, offset: usize, width: usize) -> u32;
+
+    /// # 函数的功能
+    /// 以`width`字节的宽度写入配置空间，`width`为1、2或4
+    ///
+    /// ## Safety
+    ///
+    /// 调用者需要保证访问没有越界，并且`offset`按`width`对齐
+    unsafe fn config_write_raw(&self, offset: usize, width: usize, value: u32);
+}
+
+/// # 函数的功能
+/// 计算访问配置空间中类型为`T`的字段时，每次访问的宽度
+///
+/// ## 返回值
+/// - Ok(width): 每次访问的宽度，为`T`的对齐与4中的较小值
+/// - Err(SystemError::EINVAL): 访问越界，或者`offset`没有按`T`的对齐方式对齐
+fn config_access_width<D, T>(dev: &D, offset: usize) -> Result<usize, SystemError>
+where
+    D: VirtIOConfigAccess + ?Sized,
+{
+    let end = offset
+        .checked_add(size_of::<T>())
+        .ok_or(SystemError::EINVAL)?;
+    if end > dev.config_space_len() {
+        return Err(SystemError::EINVAL);
+    }
+    let width = align_of::<T>().min(size_of::<u32>());
+    if offset % width != 0 {
+        return Err(SystemError::EINVAL);
+    }
+    Ok(width)
+}
+
+/// # 函数的功能
+/// 从配置空间的`offset`处读取一个`T`，保证读取期间配置没有发生变化
+///
+/// 字段按照`T`的对齐方式拆分成多次1、2或4字节的访问，按地址从低到高读取，
+/// 然后从设备的字节序转换为本机字节序
+///
+/// ## 返回值
+/// - Err(SystemError::EINVAL): 访问越界，或者`offset`没有对齐
+/// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): 见[`virtio_read_config_consistent`]
+pub fn virtio_config_read<D, T>(dev: &D, offset: usize) -> Result<T, SystemError>
+where
+    D: VirtIOConfigAccess + ?Sized,
+    T: VirtIOEndianField,
+{
+    let width = config_access_width::<D, T>(dev, offset)?;
+    virtio_read_config_consistent(dev, |dev| {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = value.as_mut_ptr() as *mut u8;
+        for pos in (0..size_of::<T>()).step_by(width) {
+            let v = unsafe { dev.config_read_raw(offset + pos, width) }.to_ne_bytes();
+            unsafe { core::ptr::copy_nonoverlapping(v.as_ptr(), bytes.add(pos), width) };
+        }
+        unsafe { value.assume_init() }.from_dev(dev.config_endian())
+    })
+}
+
+/// # 函数的功能
+/// 把`value`写入配置空间的`offset`处
+///
+/// `value`先被转换为设备的字节序，然后按照`T`的对齐方式拆分成多次1、2或4字节的访问，
+/// 按地址从低到高写入，例如64位的字段先写低32位，再写高32位。
+///
+/// 多次访问组成的写入不是原子的，config_generation也不会因为驱动的写入而变化。
+/// 需要读回写入结果的调用者应当使用[`virtio_config_read`]
+///
+/// ## 返回值
+/// - Err(SystemError::EINVAL): 访问越界，或者`offset`没有对齐
+pub fn virtio_config_write<D, T>(dev: &D, offset: usize, value: T) -> Result<(), SystemError>
+where
+    D: VirtIOConfigAccess + ?Sized,
+    T: VirtIOEndianField,
+{
+    let width = config_access_width::<D, T>(dev, offset)?;
+    let value = value.to_dev(dev.config_endian());
+    let bytes = &value as *const T as *const u8;
+    for pos in (0..size_of::<T>()).step_by(width) {
+        let mut v = [0u8; 4];
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.add(pos), v.as_mut_ptr(), width);
+            dev.config_write_raw(offset + pos, width, u32::from_ne_bytes(v));
+        }
+    }
+    Ok(())
+}
+
+/// # 函数的功能
+/// 以`width`字节的宽度读取MMIO映射的配置空间，供transport实现[`VirtIOConfigAccess`]
+///
+/// 访问越界或者没有对齐时读到全1，与读取不存在的设备一致
+pub(super) fn config_mmio_read(region: &MmioRegion, offset: usize, width: usize) -> u32 {
+    let value = match width {
+        1 => region.read::<u8>(offset).map(|v| v as u32),
+        2 => region.read::<u16>(offset).map(|v| v as u32),
+        4 => region.read::<u32>(offset),
+        _ => unreachable!("invalid config space access width: {}", width),
+    };
+    value.unwrap_or_else(|e| {
+        error!(
+            "virtio: invalid config space read at {:#x}, width {}: {:?}",
+            offset, width, e
+        );
+        u32::MAX
+    })
+}
+
+/// # 函数的功能
+/// 以`width`字节的宽度写入MMIO映射的配置空间，供transport实现[`VirtIOConfigAccess`]
+///
+/// 写入的是`value`在内存中的前`width`个字节，访问越界或者没有对齐时忽略这次写入
+pub(super) fn config_mmio_write(region: &MmioRegion, offset: usize, width: usize, value: u32) {
+    let bytes = value.to_ne_bytes();
+    let r = match width {
+        1 => region.write(offset, bytes[0]),
+        2 => region.write(offset, u16::from_ne_bytes([bytes[0], bytes[1]])),
+        4 => region.write(offset, value),
+        _ => unreachable!("invalid config space access width: {}", width),
+    };
+    if let Err(e) = r {
+        error!(
+            "virtio: invalid config space write at {:#x}, width {}: {:?}",
+            offset, width, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use core::cell::{Cell, RefCell};
+
+    use super::*;
+
+    /// 模拟一个8字节的配置字段，在第一次读取两个半字之间被设备修改
+    struct MockConfig {
+        generation: Cell<u32>,
+        value: Cell<u64>,
+        /// 剩余需要在读取中途修改配置的次数
+        changes: Cell<usize>,
+        reads: Cell<usize>,
+    }
+
+    impl MockConfig {
+        fn new(value: u64, changes: usize) -> Self {
+            Self {
+                generation: Cell::new(0),
+                value: Cell::new(value),
+                changes: Cell::new(changes),
+                reads: Cell::new(0),
+            }
+        }
+
+        fn read_u64(&self) -> u64 {
+            self.reads.set(self.reads.get() + 1);
+            let low = self.value.get() as u32;
+            if self.changes.get() > 0 {
+                self.changes.set(self.changes.get() - 1);
+                self.value.set(self.value.get() + 1);
+                self.generation.set(self.generation.get() + 1);
+            }
+            let high = (self.value.get() >> 32) as u32;
+            ((high as u64) << 32) | low as u64
+        }
+    }
+
+    impl VirtIOConfigGeneration for MockConfig {
+        fn config_generation(&self) -> u32 {
+            self.generation.get()
+        }
+    }
+
+    #[test]
+    fn test_stable_read() {
+        let dev = MockConfig::new(0x1_0000_0010, 0);
+        let v = virtio_read_config_consistent(&dev, |d| d.read_u64());
+        assert_eq!(v, Ok(0x1_0000_0010));
+        assert_eq!(dev.reads.get(), 1);
+    }
+
+    #[test]
+    fn test_retry_after_generation_change() {
+        let dev = MockConfig::new(0x1_ffff_ffff, 1);
+        // 不检查generation时会读到新旧两个值拼接而成的错误结果
+        assert_eq!(dev.read_u64(), 0x2_ffff_ffff);
+
+        let dev = MockConfig::new(0x1_ffff_ffff, 1);
+        let v = virtio_read_config_consistent(&dev, |d| d.read_u64());
+        assert_eq!(v, Ok(0x2_0000_0000));
+        assert_eq!(dev.reads.get(), 2);
+    }
+
+    #[test]
+    fn test_never_stable() {
+        let dev = MockConfig::new(0, usize::MAX);
+        let v = virtio_read_config_consistent(&dev, |d| d.read_u64());
+        assert_eq!(v, Err(SystemError::EAGAIN_OR_EWOULDBLOCK));
+        assert_eq!(dev.reads.get(), VIRTIO_CONFIG_MAX_RETRIES);
+    }
+
+    /// 可写的配置空间，记录每次写入的位置和宽度
+    struct MockConfigSpace {
+        bytes: RefCell<Vec<u8>>,
+        writes: RefCell<Vec<(usize, usize)>>,
+    }
+
+    impl MockConfigSpace {
+        fn new(len: usize) -> Self {
+            Self {
+                bytes: RefCell::new(vec![0; len]),
+                writes: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl VirtIOConfigGeneration for MockConfigSpace {
+        fn config_generation(&self) -> u32 {
+            0
+        }
+    }
+
+    impl VirtIOConfigAccess for MockConfigSpace {
+        fn config_space_len(&self) -> usize {
+            self.bytes.borrow().len()
+        }
+
+        unsafe fn config_read_raw(&self, offset: usize, width: usize) -> u32 {
+            assert_eq!(offset % width, 0);
+            let mut v = [0u8; 4];
+            v[..width].copy_from_slice(&self.bytes.borrow()[offset..offset + width]);
+            u32::from_ne_bytes(v)
+        }
+
+        unsafe fn config_write_raw(&self, offset: usize, width: usize, value: u32) {
+            assert_eq!(offset % width, 0);
+            self.writes.borrow_mut().push((offset, width));
+            self.bytes.borrow_mut()[offset..offset + width]
+                .copy_from_slice(&value.to_ne_bytes()[..width]);
+        }
+    }
+
+    #[test]
+    fn test_write_u64_as_two_dwords() {
+        let dev = MockConfigSpace::new(16);
+        virtio_config_write(&dev, 8, 0x1122_3344_5566_7788u64).unwrap();
+        assert_eq!(*dev.writes.borrow(), [(8, 4), (12, 4)]);
+        assert_eq!(
+            &dev.bytes.borrow()[8..],
+            &0x1122_3344_5566_7788u64.to_le_bytes()
+        );
+        assert_eq!(
+            virtio_config_read::<_, u64>(&dev, 8),
+            Ok(0x1122_3344_5566_7788)
+        );
+    }
+
+    #[test]
+    fn test_little_endian_fields() {
+        let dev = MockConfigSpace::new(16);
+        dev.bytes.borrow_mut()[..4].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(virtio_config_read::<_, u32>(&dev, 0), Ok(0x1234_5678));
+        assert_eq!(virtio_config_read::<_, u16>(&dev, 2), Ok(0x1234));
+
+        virtio_config_write(&dev, 8, 0x0102_0304_0506_0708u64).unwrap();
+        assert_eq!(&dev.bytes.borrow()[8..], &[8, 7, 6, 5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn test_write_mac_bytewise() {
+        let dev = MockConfigSpace::new(8);
+        let mac = [0x52u8, 0x54, 0x00, 0x12, 0x34, 0x56];
+        virtio_config_write(&dev, 0, mac).unwrap();
+        assert_eq!(
+            *dev.writes.borrow(),
+            [(0, 1), (1, 1), (2, 1), (3, 1), (4, 1), (5, 1)]
+        );
+        assert_eq!(virtio_config_read::<_, [u8; 6]>(&dev, 0), Ok(mac));
+        // 没有写入的部分保持不变
+        assert_eq!(virtio_config_read::<_, u16>(&dev, 6), Ok(0));
+    }
+
+    #[test]
+    fn test_bounds_and_alignment() {
+        let dev = MockConfigSpace::new(8);
+        assert_eq!(virtio_config_write(&dev, 6, 0u32), Err(SystemError::EINVAL));
+        assert_eq!(
+            virtio_config_write(&dev, usize::MAX, 0u8),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(virtio_config_write(&dev, 2, 0u32), Err(SystemError::EINVAL));
+        assert_eq!(
+            virtio_config_read::<_, u16>(&dev, 7),
+            Err(SystemError::EINVAL)
+        );
+        assert!(dev.writes.borrow().is_empty());
+
+        // 没有配置空间的设备
+        let dev = MockConfigSpace::new(0);
+        assert_eq!(virtio_config_write(&dev, 0, 0u8), Err(SystemError::EINVAL));
+    }
+}
@@ -0,0 +1,239 @@
+//! virtio设备的DMA地址掩码
+//!
+//! 设备能访问的物理地址范围分为两类：
+//! - 一致性(coherent)DMA：驱动与设备长期共享的内存，例如virtqueue的ring，通过`Hal::dma_alloc`分配
+//! - 流式(streaming)DMA：驱动临时交给设备的缓冲区，例如块设备请求的数据，通过`Hal::share`共享
+//!
+//! 一些平台上设备的流式DMA可以使用64位地址，一致性DMA却只能使用32位地址。
+//! 设备通过[`super::virtio_impl::DmaMaskedHal`]的两个参数分别声明这两个掩码，默认都是64位。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/include/linux/dma-mapping.h#540
+
+use alloc::vec::Vec;
+
+/// 分配一致性DMA内存时，为了得到掩码范围内的内存最多尝试的次数
+///
+/// 页分配器不区分地址范围，只能反复分配直到得到满足要求的内存
+pub const VIRTIO_DMA_ALLOC_MAX_ATTEMPTS: usize = 8;
+
+/// # 结构功能
+/// 设备的DMA地址掩码，设备能访问的地址都不超过掩码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtIODmaMask {
+    /// 一致性DMA内存的地址掩码
+    pub coherent: u64,
+    /// 流式DMA缓冲区的地址掩码
+    pub streaming: u64,
+}
+
+impl VirtIODmaMask {
+    /// 默认设备可以访问64位地址空间
+    pub const DEFAULT: Self = Self::new(64, 64);
+
+    /// # 函数的功能
+    /// 根据设备能使用的地址位数构造掩码
+    ///
+    /// ## 参数
+    /// - `coherent_bits`: 一致性DMA内存能使用的地址位数
+    /// - `streaming_bits`: 流式DMA缓冲区能使用的地址位数
+    pub const fn new(coherent_bits: u32, streaming_bits: u32) -> Self {
+        Self {
+            coherent: dma_bit_mask(coherent_bits),
+            streaming: dma_bit_mask(streaming_bits),
+        }
+    }
+
+    /// 从`paddr`开始的`size`字节的一致性DMA内存设备能否访问
+    pub fn coherent_fits(&self, paddr: u64, size: usize) -> bool {
+        dma_range_within(paddr, size, self.coherent)
+    }
+
+    /// 从`paddr`开始的`size`字节的流式DMA缓冲区设备能否访问
+    pub fn streaming_fits(&self, paddr: u64, size: usize) -> bool {
+        dma_range_within(paddr, size, self.streaming)
+    }
+}
+
+impl Default for VirtIODmaMask {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// 低`bits`位全为1的掩码
+pub const fn dma_bit_mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    }
+}
+
+/// 从`paddr`开始的`size`字节是否都不超过`mask`
+fn dma_range_within(paddr: u64, size: usize, mask: u64) -> bool {
+    match paddr.checked_add((size as u64).saturating_sub(1)) {
+        Some(last) => last <= mask,
+        None => false,
+    }
+}
+
+/// # 函数的功能
+/// 分配位于`mask`范围内的DMA内存
+///
+/// 超出范围的内存先不释放，避免再次分配得到同一块内存，结束时统一释放
+///
+/// ## 参数
+/// - `mask`: 地址掩码
+/// - `size`: 每次分配得到的字节数
+/// - `alloc`: 分配一次内存，返回物理地址以及释放时需要的信息
+/// - `free`: 释放`alloc`分配的内存
+///
+/// ## 返回值
+/// - Some((paddr, T)): 分配成功
+/// - None: 内存不足，或者尝试[`VIRTIO_DMA_ALLOC_MAX_ATTEMPTS`]次之后仍然没有得到掩码范围内的内存
+pub fn dma_alloc_within<T>(
+    mask: u64,
+    size: usize,
+    mut alloc: impl FnMut() -> Option<(u64, T)>,
+    mut free: impl FnMut(u64, T),
+) -> Option<(u64, T)> {
+    let mut rejected = Vec::new();
+    let mut result = None;
+    for _ in 0..VIRTIO_DMA_ALLOC_MAX_ATTEMPTS {
+        let Some((paddr, t)) = alloc() else {
+            break;
+        };
+        if dma_range_within(paddr, size, mask) {
+            result = Some((paddr, t));
+            break;
+        }
+        rejected.push((paddr, t));
+    }
+    for (paddr, t) in rejected {
+        free(paddr, t);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use virtio_drivers::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
+
+    use super::*;
+    use crate::driver::virtio::{mock::MockHal, ring::VirtqRingMemory};
+
+    #[test]
+    fn test_bit_mask() {
+        assert_eq!(dma_bit_mask(32), 0xffff_ffff);
+        assert_eq!(dma_bit_mask(64), u64::MAX);
+        assert_eq!(VirtIODmaMask::default(), VirtIODmaMask::new(64, 64));
+
+        let mask = VirtIODmaMask::new(32, 64);
+        assert!(mask.coherent_fits(0xffff_f000, 0x1000));
+        assert!(!mask.coherent_fits(0xffff_f000, 0x1001));
+        assert!(!mask.coherent_fits(0x1_0000_0000, 1));
+        assert!(mask.streaming_fits(0x1_0000_0000, 0x1000));
+        assert!(!mask.streaming_fits(u64::MAX, 2));
+    }
+
+    #[test]
+    fn test_alloc_within_retries() {
+        let mut next = [0x1_0000_0000u64, 0xffff_f000, 0x4000_0000].into_iter();
+        let mut freed = Vec::new();
+        let r = dma_alloc_within(
+            dma_bit_mask(32),
+            0x2000,
+            || next.next().map(|p| (p, ())),
+            |p, _| freed.push(p),
+        );
+        assert_eq!(r, Some((0x4000_0000, ())));
+        // 超出范围的两块内存都被释放
+        assert_eq!(freed, [0x1_0000_0000, 0xffff_f000]);
+
+        // 一直得不到范围内的内存
+        let mut freed = 0;
+        let r = dma_alloc_within(
+            dma_bit_mask(32),
+            0x1000,
+            || Some((0x1_0000_0000u64, ())),
+            |_, _| freed += 1,
+        );
+        assert_eq!(r, None);
+        assert_eq!(freed, VIRTIO_DMA_ALLOC_MAX_ATTEMPTS);
+    }
+
+    /// 一致性DMA只能使用32位地址、流式DMA可以使用64位地址的设备
+    ///
+    /// 内存由[`MockHal`]分配，物理地址由`PHYS_POOL`依次给出，前两块位于4G以上
+    struct Dma32Hal;
+
+    const PHYS_POOL: [u64; 3] = [0x2_0000_0000, 0x1_0000_0000, 0x8000_0000];
+    static NEXT_PHYS: AtomicUsize = AtomicUsize::new(0);
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+    impl Dma32Hal {
+        const DMA_MASK: VirtIODmaMask = VirtIODmaMask::new(32, 64);
+    }
+
+    unsafe impl Hal for Dma32Hal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            let alloc = || {
+                let paddr = *PHYS_POOL.get(NEXT_PHYS.fetch_add(1, Ordering::SeqCst))?;
+                let (_, vaddr) = MockHal::dma_alloc(pages, direction);
+                LIVE.fetch_add(1, Ordering::SeqCst);
+                Some((paddr, vaddr))
+            };
+            let free = |paddr, vaddr| unsafe {
+                LIVE.fetch_sub(1, Ordering::SeqCst);
+                MockHal::dma_dealloc(paddr as PhysAddr, vaddr, pages);
+            };
+            match dma_alloc_within(Self::DMA_MASK.coherent, pages * PAGE_SIZE, alloc, free) {
+                Some((paddr, vaddr)) => (paddr as PhysAddr, vaddr),
+                None => (0, NonNull::dangling()),
+            }
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            LIVE.fetch_sub(1, Ordering::SeqCst);
+            MockHal::dma_dealloc(paddr, vaddr, pages)
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            MockHal::mmio_phys_to_virt(paddr, size)
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            MockHal::share(buffer, direction)
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            MockHal::unshare(paddr, buffer, direction)
+        }
+    }
+
+    #[test]
+    fn test_ring_alloc_honors_coherent_mask() {
+        let mem = VirtqRingMemory::<Dma32Hal>::alloc(256, false).unwrap();
+        let end = (mem.desc_paddr() + mem.layout().total_size) as u64;
+        assert!(end - 1 <= Dma32Hal::DMA_MASK.coherent);
+        assert_eq!(mem.desc_paddr() as u64, 0x8000_0000);
+        // 4G以上的两块内存已经被释放
+        assert_eq!(LIVE.load(Ordering::SeqCst), 1);
+
+        // 流式DMA的掩码更宽，4G以上的缓冲区设备仍然可以访问
+        assert!(Dma32Hal::DMA_MASK.streaming_fits(0x2_0000_0000, 4096));
+
+        drop(mem);
+        assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+
+        // 再也分配不到4G以下的内存
+        assert_eq!(
+            VirtqRingMemory::<Dma32Hal>::alloc(256, false).err(),
+            Some(system_error::SystemError::ENOMEM)
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! virtio中多字节字段的字节序
+//!
+//! virtio 1.0起，设备配置空间以及virtqueue中的多字节字段都是小端的，与客户机的字节序无关；
+//! legacy接口则使用客户机本机的字节序。驱动访问这些字段时需要经过这里的转换，不能直接按本机布局读写。
+//!
+//! 参考 virtio spec 1.4 Structure Size and Alignment、2.5 Device Configuration Space
+
+/// # 结构功能
+/// 设备使用的字节序
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtIOEndian {
+    /// virtio 1.0及以后的设备
+    Little,
+    /// legacy设备，与客户机相同
+    Native,
+}
+
+impl VirtIOEndian {
+    /// 根据设备是否为legacy设备得到字节序
+    pub const fn new(legacy: bool) -> Self {
+        if legacy {
+            Self::Native
+        } else {
+            Self::Little
+        }
+    }
+}
+
+/// # trait功能
+/// 可以在设备的字节序与本机字节序之间转换的字段
+pub trait VirtIOEndianField: Copy {
+    /// 把设备字节序的值转换为本机字节序
+    fn from_dev(self, endian: VirtIOEndian) -> Self;
+
+    /// 把本机字节序的值转换为设备字节序
+    fn to_dev(self, endian: VirtIOEndian) -> Self;
+}
+
+macro_rules! impl_endian_field {
+    ($($t:ty),*) => {
+        $(
+            impl VirtIOEndianField for $t {
+                #[inline(always)]
+                fn from_dev(self, endian: VirtIOEndian) -> Self {
+                    match endian {
+                        VirtIOEndian::Little => <$t>::from_le(self),
+                        VirtIOEndian::Native => self,
+                    }
+                }
+
+                #[inline(always)]
+                fn to_dev(self, endian: VirtIOEndian) -> Self {
+                    match endian {
+                        VirtIOEndian::Little => self.to_le(),
+                        VirtIOEndian::Native => self,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_endian_field!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// 字节数组(例如MAC地址、virtio-fs的tag)没有字节序
+impl<const N: usize> VirtIOEndianField for [u8; N] {
+    #[inline(always)]
+    fn from_dev(self, _endian: VirtIOEndian) -> Self {
+        self
+    }
+
+    #[inline(always)]
+    fn to_dev(self, _endian: VirtIOEndian) -> Self {
+        self
+    }
+}
+
+/// # 函数的功能
+/// 读取设备可见内存(例如virtqueue的ring)中的一个字段，并转换为本机字节序
+///
+/// ## Safety
+///
+/// `ptr`必须有效并且按`T`对齐
+#[inline(always)]
+pub unsafe fn virtio_read_field<T: VirtIOEndianField>(ptr: *const T, endian: VirtIOEndian) -> T {
+    ptr.read_volatile().from_dev(endian)
+}
+
+/// # 函数的功能
+/// 把一个本机字节序的值按设备的字节序写入设备可见内存
+///
+/// ## Safety
+///
+/// `ptr`必须有效并且按`T`对齐
+#[inline(always)]
+pub unsafe fn virtio_write_field<T: VirtIOEndianField>(
+    ptr: *mut T,
+    value: T,
+    endian: VirtIOEndian,
+) {
+    ptr.write_volatile(value.to_dev(endian))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_little_endian_byte_order() {
+        let mut v = 0u32;
+        unsafe { virtio_write_field(&mut v, 0x1234_5678, VirtIOEndian::Little) };
+        assert_eq!(v.to_ne_bytes(), [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(
+            unsafe { virtio_read_field(&v, VirtIOEndian::Little) },
+            0x1234_5678
+        );
+
+        let mut v = 0u64;
+        unsafe { virtio_write_field(&mut v, 0x0102_0304_0506_0708, VirtIOEndian::Little) };
+        assert_eq!(v.to_ne_bytes(), [8, 7, 6, 5, 4, 3, 2, 1]);
+
+        let v = u16::from_ne_bytes([0x34, 0x12]);
+        assert_eq!(
+            unsafe { virtio_read_field(&v, VirtIOEndian::Little) },
+            0x1234
+        );
+    }
+
+    #[test]
+    fn test_native_is_unchanged() {
+        assert_eq!(0x1234u16.to_dev(VirtIOEndian::Native), 0x1234);
+        assert_eq!(0x1234_5678u32.from_dev(VirtIOEndian::Native), 0x1234_5678);
+        assert_eq!(VirtIOEndian::new(true), VirtIOEndian::Native);
+        assert_eq!(VirtIOEndian::new(false), VirtIOEndian::Little);
+        let mac = [0x52u8, 0x54, 0, 0x12, 0x34, 0x56];
+        assert_eq!(mac.from_dev(VirtIOEndian::Little), mac);
+    }
+}
@@ -0,0 +1,164 @@
+//! 把virtio特性位解码为可读的名称，用于打印日志
+//!
+//! 参考 virtio spec 6 Reserved Feature Bits，以及各设备类型的Feature bits章节
+
+use alloc::{format, string::String, vec::Vec};
+
+use super::VirtioDeviceType;
+
+/// 与设备类型无关的特性位（第24~41位）
+static VIRTIO_COMMON_FEATURES: &[(u32, &str)] = &[
+    (24, "NOTIFY_ON_EMPTY"),
+    (27, "ANY_LAYOUT"),
+    (28, "INDIRECT_DESC"),
+    (29, "EVENT_IDX"),
+    (32, "VERSION_1"),
+    (33, "ACCESS_PLATFORM"),
+    (34, "RING_PACKED"),
+    (35, "IN_ORDER"),
+    (36, "ORDER_PLATFORM"),
+    (37, "SR_IOV"),
+    (38, "NOTIFICATION_DATA"),
+    (39, "NOTIF_CONFIG_DATA"),
+    (40, "RING_RESET"),
+    (41, "ADMIN_VQ"),
+];
+
+/// virtio-blk的特性位，参考 virtio spec 5.2.3
+static VIRTIO_BLK_FEATURES: &[(u32, &str)] = &[
+    (0, "BARRIER"),
+    (1, "SIZE_MAX"),
+    (2, "SEG_MAX"),
+    (4, "GEOMETRY"),
+    (5, "RO"),
+    (6, "BLK_SIZE"),
+    (7, "SCSI"),
+    (9, "FLUSH"),
+    (10, "TOPOLOGY"),
+    (11, "CONFIG_WCE"),
+    (12, "MQ"),
+    (13, "DISCARD"),
+    (14, "WRITE_ZEROES"),
+    (15, "LIFETIME"),
+    (16, "SECURE_ERASE"),
+    (17, "ZONED"),
+];
+
+/// virtio-net的特性位，参考 virtio spec 5.1.3
+static VIRTIO_NET_FEATURES: &[(u32, &str)] = &[
+    (0, "CSUM"),
+    (1, "GUEST_CSUM"),
+    (2, "CTRL_GUEST_OFFLOADS"),
+    (3, "MTU"),
+    (5, "MAC"),
+    (6, "GSO"),
+    (7, "GUEST_TSO4"),
+    (8, "GUEST_TSO6"),
+    (9, "GUEST_ECN"),
+    (10, "GUEST_UFO"),
+    (11, "HOST_TSO4"),
+    (12, "HOST_TSO6"),
+    (13, "HOST_ECN"),
+    (14, "HOST_UFO"),
+    (15, "MRG_RXBUF"),
+    (16, "STATUS"),
+    (17, "CTRL_VQ"),
+    (18, "CTRL_RX"),
+    (19, "CTRL_VLAN"),
+    (20, "CTRL_RX_EXTRA"),
+    (21, "GUEST_ANNOUNCE"),
+    (22, "MQ"),
+    (23, "CTRL_MAC_ADDR"),
+    (52, "VQ_NOTF_COAL"),
+    (53, "NOTF_COAL"),
+    (54, "GUEST_USO4"),
+    (55, "GUEST_USO6"),
+    (56, "HOST_USO"),
+    (57, "HASH_REPORT"),
+    (59, "GUEST_HDRLEN"),
+    (60, "RSS"),
+    (61, "RSC_EXT"),
+    (62, "STANDBY"),
+    (63, "SPEED_DUPLEX"),
+];
+
+/// 设备类型特有的特性位表，没有收录的设备类型返回空表
+fn device_features(device_type: VirtioDeviceType) -> &'static [(u32, &'static str)] {
+    match device_type {
+        VirtioDeviceType::Block => VIRTIO_BLK_FEATURES,
+        VirtioDeviceType::Network => VIRTIO_NET_FEATURES,
+        _ => &[],
+    }
+}
+
+/// # 函数的功能
+/// 把特性位解码为可读的列表，例如"MAC | STATUS | VERSION_1 | bit(45)"
+///
+/// ## 参数
+/// - `device_type`: 设备类型，用于解码设备特有的特性位
+/// - `bits`: 特性位
+///
+/// ## 返回值
+/// 按位从低到高排列、用" | "分隔的特性名称，未知的特性位打印为其位置。没有任何特性时返回"(none)"
+pub fn format_virtio_features(device_type: VirtioDeviceType, bits: u64) -> String {
+    let names: Vec<String> = (0..u64::BITS)
+        .filter(|bit| bits & (1 << bit) != 0)
+        .map(|bit| {
+            device_features(device_type)
+                .iter()
+                .chain(VIRTIO_COMMON_FEATURES.iter())
+                .find(|(b, _)| *b == bit)
+                .map(|(_, name)| String::from(*name))
+                .unwrap_or_else(|| format!("bit({})", bit))
+        })
+        .collect();
+
+    if names.is_empty() {
+        return String::from("(none)");
+    }
+    names.join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables_sorted() {
+        for table in [
+            VIRTIO_COMMON_FEATURES,
+            VIRTIO_BLK_FEATURES,
+            VIRTIO_NET_FEATURES,
+        ] {
+            assert!(table.windows(2).all(|w| w[0].0 < w[1].0));
+        }
+    }
+
+    #[test]
+    fn test_format_blk() {
+        let bits = (1 << 2) | (1 << 9) | (1 << 13) | (1 << 28) | (1 << 32) | (1 << 34);
+        assert_eq!(
+            format_virtio_features(VirtioDeviceType::Block, bits),
+            "SEG_MAX | FLUSH | DISCARD | INDIRECT_DESC | VERSION_1 | RING_PACKED"
+        );
+    }
+
+    #[test]
+    fn test_format_net() {
+        let bits = (1 << 0) | (1 << 5) | (1 << 22) | (1 << 29) | (1 << 32);
+        assert_eq!(
+            format_virtio_features(VirtioDeviceType::Network, bits),
+            "CSUM | MAC | MQ | EVENT_IDX | VERSION_1"
+        );
+    }
+
+    #[test]
+    fn test_unknown_bits() {
+        // 设备特有的位对于没有收录特性表的设备类型是未知的
+        assert_eq!(
+            format_virtio_features(VirtioDeviceType::Gpu, (1 << 5) | (1 << 45) | (1 << 32)),
+            "bit(5) | VERSION_1 | bit(45)"
+        );
+        assert_eq!(format_virtio_features(VirtioDeviceType::Block, 0), "(none)");
+    }
+}
@@ -0,0 +1,145 @@
+//! 设备初始化过程中的资源回滚
+//!
+//! 初始化一个virtio设备需要依次申请多种资源（中断向量、virtqueue的环形缓冲区、设备索引等），
+//! 其中任何一步失败都需要释放之前已经申请的资源，否则探测不支持或者行为异常的设备时会造成泄漏。
+//!
+//! virtqueue的环形缓冲区由virtio-drivers中的Dma持有，它在被drop时会通过[`super::virtio_impl::HalImpl`]
+//! 释放内存，因此这里主要处理不会自动释放的资源。
+
+use core::ops::{Deref, DerefMut};
+
+/// # 结构功能
+/// 持有初始化过程中申请到的资源，如果在调用[`SetupGuard::commit`]之前被drop，
+/// 就调用`cleanup`释放它
+///
+/// 守卫可以通过Deref访问被持有的值，因此初始化的后续步骤可以继续使用它
+#[must_use = "资源会在守卫被drop时立即释放"]
+pub struct SetupGuard<T, F: FnOnce(&mut T)> {
+    value: T,
+    cleanup: Option<F>,
+}
+
+impl<T, F: FnOnce(&mut T)> SetupGuard<T, F> {
+    pub fn new(value: T, cleanup: F) -> Self {
+        Self {
+            value,
+            cleanup: Some(cleanup),
+        }
+    }
+
+    /// 初始化成功，资源转交给调用者，不再释放
+    pub fn commit(mut self) -> T {
+        self.cleanup = None;
+        // 不能直接移出value，因为Self实现了Drop
+        let this = core::mem::ManuallyDrop::new(self);
+        unsafe { core::ptr::read(&this.value) }
+    }
+}
+
+impl<T, F: FnOnce(&mut T)> Deref for SetupGuard<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, F: FnOnce(&mut T)> DerefMut for SetupGuard<T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T, F: FnOnce(&mut T)> Drop for SetupGuard<T, F> {
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            cleanup(&mut self.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::Cell;
+
+    use super::*;
+
+    /// 记录尚未释放的中断向量和环形缓冲区的数量
+    #[derive(Default)]
+    struct MockDevice {
+        vectors: Cell<usize>,
+        rings: Cell<usize>,
+    }
+
+    /// 模拟的环形缓冲区，与virtio-drivers中的Dma一样，在drop时释放内存
+    struct MockRing<'a>(&'a MockDevice);
+
+    impl<'a> MockRing<'a> {
+        fn new(dev: &'a MockDevice) -> Self {
+            dev.rings.set(dev.rings.get() + 1);
+            Self(dev)
+        }
+    }
+
+    impl Drop for MockRing<'_> {
+        fn drop(&mut self) {
+            self.0.rings.set(self.0.rings.get() - 1);
+        }
+    }
+
+    /// 模拟设备的初始化：申请中断向量，创建`num_queues`个队列，在创建第`fail_at`个队列时失败
+    fn mock_setup(
+        dev: &MockDevice,
+        num_queues: usize,
+        fail_at: Option<usize>,
+    ) -> Result<Vec<MockRing<'_>>, ()> {
+        dev.vectors.set(dev.vectors.get() + 1);
+        let vector = SetupGuard::new(dev, |dev| dev.vectors.set(dev.vectors.get() - 1));
+
+        let mut rings = Vec::new();
+        for i in 0..num_queues {
+            if fail_at == Some(i) {
+                return Err(());
+            }
+            rings.push(MockRing::new(dev));
+        }
+        vector.commit();
+        Ok(rings)
+    }
+
+    #[test]
+    fn test_failure_mid_setup_releases_all() {
+        let dev = MockDevice::default();
+        for fail_at in 0..3 {
+            assert!(mock_setup(&dev, 3, Some(fail_at)).is_err());
+            assert_eq!(dev.vectors.get(), 0);
+            assert_eq!(dev.rings.get(), 0);
+        }
+    }
+
+    #[test]
+    fn test_commit_keeps_resources() {
+        let dev = MockDevice::default();
+        let rings = mock_setup(&dev, 3, None).unwrap();
+        assert_eq!(dev.vectors.get(), 1);
+        assert_eq!(dev.rings.get(), 3);
+        drop(rings);
+        assert_eq!(dev.rings.get(), 0);
+    }
+
+    #[test]
+    fn test_guard_deref() {
+        let released = Cell::new(false);
+        {
+            let mut guard = SetupGuard::new(Vec::new(), |v: &mut Vec<u32>| {
+                assert_eq!(v.as_slice(), [1, 2]);
+                released.set(true);
+            });
+            guard.push(1);
+            guard.push(2);
+            assert_eq!(guard.len(), 2);
+        }
+        assert!(released.get());
+    }
+}
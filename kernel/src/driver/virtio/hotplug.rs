@@ -0,0 +1,67 @@
+//! virtio-pci设备的热拔出（surprise removal）检测
+//!
+//! QEMU执行`device_del`之后，virtio pci设备会直接消失，对它的所有读取都会返回全1。
+//! 这里启动一个内核线程定期检查virtio总线上的设备是否还存在，
+//! 如果设备已经不存在，就走正常的设备移除流程（驱动remove、sysfs清理），避免驱动继续访问失效的MMIO。
+
+use alloc::{string::ToString, sync::Arc, vec::Vec};
+use intertrait::cast::CastArc;
+use log::warn;
+use system_error::SystemError;
+use unified_init::macros::unified_init;
+
+use crate::{
+    driver::{base::device::Device, pci::hotplug::pci_device_gone},
+    init::initcall::INITCALL_LATE,
+    process::kthread::{KernelThreadClosure, KernelThreadMechanism},
+    time::{sleep::nanosleep, PosixTimeSpec},
+};
+
+use super::{
+    sysfs::{virtio_bus, virtio_device_manager},
+    VirtIODevice,
+};
+
+/// 两次检查之间的间隔（秒）
+const VIRTIO_PRESENCE_CHECK_INTERVAL_SEC: i64 = 1;
+
+#[unified_init(INITCALL_LATE)]
+fn virtio_hotplug_init() -> Result<(), SystemError> {
+    let closure = KernelThreadClosure::StaticEmptyClosure((
+        &(virtio_presence_check_thread as fn() -> i32),
+        (),
+    ));
+    KernelThreadMechanism::create_and_run(closure, "virtio_hotplug".to_string())
+        .ok_or(SystemError::EPERM)?;
+    return Ok(());
+}
+
+fn virtio_presence_check_thread() -> i32 {
+    loop {
+        nanosleep(PosixTimeSpec::new(VIRTIO_PRESENCE_CHECK_INTERVAL_SEC, 0)).ok();
+        virtio_check_presence();
+    }
+}
+
+/// 检查virtio总线上的所有设备，移除已经被拔出的设备
+pub fn virtio_check_presence() {
+    // 先收集，避免在持有总线设备列表锁的情况下移除设备
+    let gone: Vec<Arc<dyn Device>> = virtio_bus()
+        .subsystem()
+        .devices()
+        .iter()
+        .filter(|dev| pci_device_gone(dev))
+        .cloned()
+        .collect();
+
+    for dev in gone {
+        let Ok(virtio_dev) = dev.clone().cast::<dyn VirtIODevice>() else {
+            continue;
+        };
+        warn!(
+            "virtio device '{}' is gone (surprise removal), removing it",
+            virtio_dev.device_name()
+        );
+        virtio_device_manager().device_remove(&virtio_dev).ok();
+    }
+}
@@ -1,20 +1,30 @@
-use alloc::sync::Arc;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
 use hashbrown::HashMap;
+use intertrait::cast::CastArc;
 use system_error::SystemError;
 use unified_init::macros::unified_init;
 
 use crate::{
-    driver::base::device::DeviceId,
+    driver::{
+        base::device::DeviceId,
+        pci::{
+            device::PciDevice,
+            irq_stats::{pci_irq_stats_total, pci_irq_vector_stats, PciIrqVectorStat},
+        },
+    },
     exception::{
         irqdata::IrqHandlerData,
-        irqdesc::{IrqHandler, IrqReturn},
+        irqdesc::{irq_desc_manager, IrqHandler, IrqReturn},
         IrqNumber,
     },
     init::initcall::INITCALL_CORE,
     libs::rwlock::RwLock,
 };
 
-use super::VirtIODevice;
+use super::{
+    transport::{VirtIOIrqType, VirtIOIsrStatus},
+    VirtIODevice,
+};
 
 static mut VIRTIO_IRQ_MANAGER: Option<VirtIOIrqManager> = None;
 
@@ -63,7 +73,6 @@ impl VirtIOIrqManager {
     /// # 参数
     ///
     /// - `device` - 需要被取消注册的设备，它是一个实现了 `VirtIODevice` trait 的智能指针。
-    #[allow(dead_code)]
     pub fn unregister_device(&self, dev_id: &Arc<DeviceId>) {
         let mut map = self.map.write_irqsave();
         map.remove(dev_id);
@@ -117,7 +126,14 @@ impl IrqHandler for DefaultVirtioIrqHandler {
             .map_err(|_| SystemError::EINVAL)?;
 
         if let Some(dev) = virtio_irq_manager().lookup_device(&dev_id) {
-            return dev.handle_irq(irq);
+            let isr = dev.isr();
+            let status = match virtio_irq_decode(dev.irq_type(), isr.map(|isr| move || isr.read()))
+            {
+                Some(status) => status,
+                // 共享中断线上其他设备产生的中断
+                None => return Ok(IrqReturn::NotHandled),
+            };
+            return virtio_irq_dispatch(dev.as_ref(), irq, status);
         } else {
             // 未绑定具体设备，因此无法处理中断
             // warn!("No device found for IRQ: {:?}", irq);
@@ -125,3 +141,280 @@ impl IrqHandler for DefaultVirtioIrqHandler {
         }
     }
 }
+
+/// # 函数的功能
+/// 判断中断是否由设备产生，以及中断的原因
+///
+/// MSI-X中断不与其他设备共享，并且每个中断向量对应固定的原因，因此不读取ISR状态寄存器。
+/// 其他情况下中断线可能与其他设备共享，需要读取(同时清零)ISR状态寄存器来判断。
+///
+/// ## 参数
+/// - `irq_type`: 设备使用的中断类型
+/// - `read_isr`: 读取并清零ISR状态寄存器，transport没有ISR状态寄存器时为None
+///
+/// ## 返回值
+/// - Some(status): 中断由设备产生，`status`为中断的原因
+/// - None: 中断不是由设备产生的
+pub fn virtio_irq_decode(
+    irq_type: VirtIOIrqType,
+    read_isr: Option<impl FnOnce() -> VirtIOIsrStatus>,
+) -> Option<VirtIOIsrStatus> {
+    if matches!(irq_type, VirtIOIrqType::Msix { .. }) {
+        return Some(VirtIOIsrStatus::QUEUE);
+    }
+    match read_isr {
+        Some(read_isr) => {
+            let status = read_isr();
+            if status.is_empty() {
+                None
+            } else {
+                Some(status)
+            }
+        }
+        // 无法区分中断的原因，交给设备检查virtqueue
+        None => Some(VirtIOIsrStatus::QUEUE),
+    }
+}
+
+/// # 函数的功能
+/// 按照中断的原因调用设备的中断处理函数
+fn virtio_irq_dispatch(
+    dev: &dyn VirtIODevice,
+    irq: IrqNumber,
+    status: VirtIOIsrStatus,
+) -> Result<IrqReturn, SystemError> {
+    if status.contains(VirtIOIsrStatus::CONFIG) {
+        dev.handle_config_change();
+    }
+    if status.contains(VirtIOIsrStatus::QUEUE) {
+        return dev.handle_irq(irq);
+    }
+    Ok(IrqReturn::Handled)
+}
+
+/// virtio设备的中断次数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtIOIrqStats {
+    /// 设备所有中断向量的中断次数之和
+    pub total: u64,
+    /// 每个virtqueue的中断次数，队列不产生中断时为None。
+    /// 共用一个中断的队列的次数相同
+    pub queues: Vec<(u16, Option<u64>)>,
+}
+
+impl VirtIOIrqStats {
+    /// # 函数的功能
+    /// 按照队列统计中断次数
+    ///
+    /// ## 参数
+    /// - `vectors`: 设备每个中断向量的中断次数
+    /// - `irq_type`: 设备使用的中断类型，决定队列使用哪个中断向量
+    /// - `queues`: 设备使用的virtqueue
+    pub fn new(vectors: &[PciIrqVectorStat], irq_type: VirtIOIrqType, queues: &[u16]) -> Self {
+        let queues = queues
+            .iter()
+            .map(|&queue| {
+                let count = irq_type
+                    .queue_vector(queue)
+                    .and_then(|index| vectors.iter().find(|v| v.index == index))
+                    .map(|v| v.count);
+                (queue, count)
+            })
+            .collect();
+        Self {
+            total: pci_irq_stats_total(vectors),
+            queues,
+        }
+    }
+
+    /// 格式化为`irq_stats`属性的内容
+    pub fn describe(&self) -> String {
+        let mut s = format!("total {}\n", self.total);
+        for (queue, count) in self.queues.iter() {
+            match count {
+                Some(count) => s.push_str(&format!("queue{} {}\n", queue, count)),
+                None => s.push_str(&format!("queue{} none\n", queue)),
+            }
+        }
+        s
+    }
+}
+
+/// # 函数的功能
+/// 统计virtio设备的中断次数
+///
+/// PCI设备按照设备的中断向量统计，MMIO设备只有一个中断
+pub fn virtio_irq_stats(dev: &Arc<dyn VirtIODevice>) -> VirtIOIrqStats {
+    let bdf = dev
+        .dev_parent()
+        .and_then(|p| p.upgrade())
+        .and_then(|p| p.cast::<dyn PciDevice>().ok())
+        .and_then(|p| p.bus_device_function());
+    let vectors = match (bdf, dev.irq()) {
+        (Some(bdf), _) => pci_irq_vector_stats(bdf).unwrap_or_default(),
+        (None, Some(irq)) => vec![PciIrqVectorStat {
+            index: 0,
+            irq,
+            count: irq_desc_manager()
+                .lookup(irq)
+                .map(|desc| desc.kstat_irqs())
+                .unwrap_or(0),
+        }],
+        (None, None) => Vec::new(),
+    };
+    VirtIOIrqStats::new(&vectors, dev.irq_type(), &dev.queues())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+    use core::cell::Cell;
+
+    use super::*;
+    use crate::driver::{
+        pci::irq_stats::pci_irq_vector_stats_from,
+        virtio::transport::{virtio_msix_queue_vector, virtio_msix_vectors_granted},
+    };
+
+    /// 模拟设备产生中断，返回每个中断向量的中断次数
+    fn simulate(vectors: &[IrqNumber], irqs: &[u32]) -> Vec<PciIrqVectorStat> {
+        let mut counts = BTreeMap::new();
+        for irq in irqs {
+            *counts.entry(IrqNumber::new(*irq)).or_insert(0u64) += 1;
+        }
+        pci_irq_vector_stats_from(vectors, |irq| counts.get(&irq).copied().unwrap_or(0))
+    }
+
+    /// 模拟设备的ISR状态寄存器，读取之后清零
+    struct MockIsr {
+        status: Cell<u8>,
+        reads: Cell<usize>,
+    }
+
+    impl MockIsr {
+        fn new(status: u8) -> Self {
+            Self {
+                status: Cell::new(status),
+                reads: Cell::new(0),
+            }
+        }
+
+        fn read(&self) -> VirtIOIsrStatus {
+            self.reads.set(self.reads.get() + 1);
+            VirtIOIsrStatus::from_bits_truncate(self.status.replace(0))
+        }
+    }
+
+    #[test]
+    fn test_decode_msix_skips_isr() {
+        let isr = MockIsr::new(0);
+        let status = virtio_irq_decode(VirtIOIrqType::Msix { vectors: 1 }, Some(|| isr.read()));
+        assert_eq!(status, Some(VirtIOIsrStatus::QUEUE));
+        assert_eq!(isr.reads.get(), 0);
+    }
+
+    #[test]
+    fn test_decode_shared_irq() {
+        // 中断来自共享中断线上的其他设备
+        let isr = MockIsr::new(0);
+        assert_eq!(
+            virtio_irq_decode(VirtIOIrqType::Intx, Some(|| isr.read())),
+            None
+        );
+
+        let isr = MockIsr::new(0x1);
+        assert_eq!(
+            virtio_irq_decode(VirtIOIrqType::Intx, Some(|| isr.read())),
+            Some(VirtIOIsrStatus::QUEUE)
+        );
+        // 读取之后ISR被清零，同一个中断不会被处理两次
+        assert_eq!(isr.status.get(), 0);
+        assert_eq!(
+            virtio_irq_decode(VirtIOIrqType::Intx, Some(|| isr.read())),
+            None
+        );
+
+        let isr = MockIsr::new(0x2);
+        assert_eq!(
+            virtio_irq_decode(VirtIOIrqType::Intx, Some(|| isr.read())),
+            Some(VirtIOIsrStatus::CONFIG)
+        );
+
+        let isr = MockIsr::new(0x3);
+        assert_eq!(
+            virtio_irq_decode(VirtIOIrqType::Msi, Some(|| isr.read())),
+            Some(VirtIOIsrStatus::QUEUE | VirtIOIsrStatus::CONFIG)
+        );
+    }
+
+    #[test]
+    fn test_decode_without_isr() {
+        let status = virtio_irq_decode(VirtIOIrqType::Platform, None::<fn() -> VirtIOIsrStatus>);
+        assert_eq!(status, Some(VirtIOIsrStatus::QUEUE));
+    }
+
+    #[test]
+    fn test_msix_per_queue() {
+        let vectors = simulate(&[IrqNumber::new(56), IrqNumber::new(57)], &[56, 57, 57]);
+        let irq_type = VirtIOIrqType::Msix { vectors: 2 };
+        let stats = VirtIOIrqStats::new(&vectors, irq_type, &[0, 1]);
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.queues, [(0, Some(1)), (1, Some(2))]);
+        assert_eq!(stats.describe(), "total 3\nqueue0 1\nqueue1 2\n");
+        assert_eq!(irq_type.sharing_factor(&[0, 1]), 1);
+    }
+
+    #[test]
+    fn test_msix_shared_vectors() {
+        // 设备只提供2个MSI-X向量，驱动需要4个队列
+        let vectors = virtio_msix_vectors_granted(2, 4, 4);
+        assert_eq!(vectors, 2);
+        let irq_type = VirtIOIrqType::Msix { vectors };
+        let queues = [0, 1, 2, 3];
+        let mapping: Vec<_> = queues.iter().map(|&q| irq_type.queue_vector(q)).collect();
+        assert_eq!(mapping, [Some(0), Some(1), Some(0), Some(1)]);
+        assert_eq!(irq_type.sharing_factor(&queues), 2);
+
+        // 共用一个向量的队列的中断次数相同
+        let stats = VirtIOIrqStats::new(
+            &simulate(&[IrqNumber::new(56), IrqNumber::new(57)], &[56, 56, 56, 57]),
+            irq_type,
+            &queues,
+        );
+        assert_eq!(
+            stats.queues,
+            [(0, Some(3)), (1, Some(1)), (2, Some(3)), (3, Some(1))]
+        );
+
+        // 可用的中断号比向量少时，按中断号分配，所有队列共用一个向量
+        let irq_type = VirtIOIrqType::Msix {
+            vectors: virtio_msix_vectors_granted(8, 1, 4),
+        };
+        assert_eq!(irq_type.sharing_factor(&queues), 4);
+        assert!(queues.iter().all(|&q| irq_type.queue_vector(q) == Some(0)));
+
+        // 没有分配到向量的队列不产生中断
+        assert_eq!(virtio_msix_queue_vector(3, 0), None);
+        assert_eq!(
+            VirtIOIrqType::Msix { vectors: 0 }.sharing_factor(&queues),
+            0
+        );
+        // 其他中断类型下所有队列共用一个中断
+        assert_eq!(VirtIOIrqType::Intx.sharing_factor(&queues), 4);
+    }
+
+    #[test]
+    fn test_shared_irq() {
+        let vectors = simulate(&[IrqNumber::new(11)], &[11, 11, 12]);
+        let stats = VirtIOIrqStats::new(&vectors, VirtIOIrqType::Intx, &[0, 1]);
+        assert_eq!(stats.total, 2);
+        assert_eq!(stats.queues, [(0, Some(2)), (1, Some(2))]);
+    }
+
+    #[test]
+    fn test_no_irq() {
+        let stats = VirtIOIrqStats::new(&[], VirtIOIrqType::None, &[0]);
+        assert_eq!(stats.total, 0);
+        assert_eq!(stats.describe(), "total 0\nqueue0 none\n");
+    }
+}
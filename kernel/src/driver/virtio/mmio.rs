@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use fdt::node::FdtNode;
 use log::error;
 use system_error::SystemError;
@@ -6,26 +7,41 @@ use crate::driver::{
     open_firmware::fdt::open_firmware_fdt_driver, virtio::transport_mmio::VirtIOMmioTransport,
 };
 
-use super::{transport::VirtIOTransport, virtio::virtio_device_init};
+use super::{
+    transport::VirtIOTransport,
+    virtio::{virtio_device_init, VirtioProbeResult},
+    VirtioDeviceType,
+};
 
-pub(super) fn virtio_probe_mmio() {
-    if let Err(e) = do_probe_virtio_mmio() {
-        error!("virtio_probe_mmio failed: {:?}", e);
+pub(super) fn virtio_probe_mmio() -> Vec<VirtioProbeResult> {
+    match do_probe_virtio_mmio() {
+        Ok(results) => results,
+        Err(e) => {
+            error!("virtio_probe_mmio failed: {:?}", e);
+            Vec::new()
+        }
     }
 }
 
-fn do_probe_virtio_mmio() -> Result<(), SystemError> {
+fn do_probe_virtio_mmio() -> Result<Vec<VirtioProbeResult>, SystemError> {
     let fdt = open_firmware_fdt_driver().fdt_ref()?;
 
-    let do_check = |node: FdtNode| -> Result<(), SystemError> {
+    // 创建transport失败的节点不是可用的virtio设备（例如没有挂载设备的virtio-mmio槽位），不计入结果
+    let do_check = |node: FdtNode| -> Result<VirtioProbeResult, SystemError> {
         let mmio_transport = VirtIOMmioTransport::new(node)?;
         let device_id = mmio_transport.device_id();
-        virtio_device_init(VirtIOTransport::Mmio(mmio_transport), device_id, None);
-        Ok(())
+        let transport = VirtIOTransport::Mmio(mmio_transport);
+        let device_type = VirtioDeviceType::from_transport(&transport);
+        Ok(VirtioProbeResult {
+            dev_id: device_id.clone(),
+            device_type: Some(device_type),
+            result: virtio_device_init(transport, device_id, None),
+        })
     };
 
-    for node in open_firmware_fdt_driver().find_node_by_compatible(&fdt, "virtio,mmio") {
-        do_check(node).ok();
-    }
-    Ok(())
+    let results = open_firmware_fdt_driver()
+        .find_node_by_compatible(&fdt, "virtio,mmio")
+        .filter_map(|node| do_check(node).ok())
+        .collect();
+    Ok(results)
 }
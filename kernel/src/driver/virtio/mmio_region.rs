@@ -0,0 +1,236 @@
+//! MMIO映射的设备寄存器的访问
+//!
+//! transport通过[`MmioRegion`]描述一段映射好的寄存器区域，访问前检查偏移是否越界、是否按宽度对齐，
+//! 由[`MmioRegister`]完成实际的volatile读写。
+//!
+//! 读写的顺序与Linux的readl/writel一致：
+//! - 写寄存器之前，之前对普通内存的写入已经完成（例如通知设备之前，描述符已经写入ring）
+//! - 读寄存器之后，之后对普通内存的读取不会提前到读寄存器之前（例如读取中断状态之后再读used ring）
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/include/asm-generic/io.h#180
+
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ptr::NonNull,
+    sync::atomic::{fence, Ordering},
+};
+
+use system_error::SystemError;
+
+/// # trait功能
+/// 可以作为一次MMIO访问的数据类型
+pub trait MmioValue: Copy + private::Sealed {}
+
+impl MmioValue for u8 {}
+impl MmioValue for u16 {}
+impl MmioValue for u32 {}
+impl MmioValue for u64 {}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// # 结构功能
+/// MMIO映射的单个寄存器，生命周期不超过所属的[`MmioRegion`]
+#[derive(Debug, Clone, Copy)]
+pub struct MmioRegister<'a, T: MmioValue> {
+    ptr: NonNull<T>,
+    _region: PhantomData<&'a MmioRegion>,
+}
+
+impl<'a, T: MmioValue> MmioRegister<'a, T> {
+    /// # 函数的功能
+    /// 读取寄存器，之后对普通内存的读取不会越过这次读取
+    pub fn read(&self) -> T {
+        // Safety: 构造时已经检查地址位于映射的区域内并且按T对齐
+        let value = unsafe { self.ptr.as_ptr().read_volatile() };
+        fence(Ordering::Acquire);
+        value
+    }
+
+    /// # 函数的功能
+    /// 写入寄存器，之前对普通内存的写入在这次写入之前完成
+    pub fn write(&self, value: T) {
+        fence(Ordering::Release);
+        // Safety: 同read
+        unsafe { self.ptr.as_ptr().write_volatile(value) }
+    }
+}
+
+/// # 结构功能
+/// 一段MMIO映射的寄存器区域，所有访问都检查偏移是否越界
+///
+/// 复制得到的区域与原区域使用同一份映射
+#[derive(Debug, Clone)]
+pub struct MmioRegion {
+    base: NonNull<u8>,
+    len: usize,
+}
+
+// 区域本身只是地址范围，并发访问的语义由设备决定
+unsafe impl Send for MmioRegion {}
+unsafe impl Sync for MmioRegion {}
+
+impl MmioRegion {
+    /// # 函数的功能
+    /// 根据映射好的起始地址和长度创建区域
+    ///
+    /// ## Safety
+    ///
+    /// `base`开始的`len`字节必须是已经映射的MMIO地址，并且在区域存在期间保持映射
+    pub unsafe fn new(base: NonNull<u8>, len: usize) -> Self {
+        Self { base, len }
+    }
+
+    /// 区域的长度(字节)
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// 区域的起始地址，供需要直接访问整个区域的场景使用
+    pub fn as_ptr(&self) -> NonNull<u8> {
+        self.base
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// # 函数的功能
+    /// 获取区域内从`offset`开始的`len`字节作为新的区域
+    ///
+    /// ## 返回值
+    /// - Ok(MmioRegion): 子区域，与当前区域使用同一份映射
+    /// - Err(SystemError::EINVAL): 子区域超出了当前区域
+    pub fn subregion(&self, offset: usize, len: usize) -> Result<MmioRegion, SystemError> {
+        self.check_range(offset, len)?;
+        // Safety: 子区域位于当前区域内
+        Ok(unsafe { Self::new(self.base.add(offset), len) })
+    }
+
+    /// # 函数的功能
+    /// 获取位于`offset`处、类型为`T`的寄存器
+    ///
+    /// ## 返回值
+    /// - Ok(MmioRegister): 寄存器
+    /// - Err(SystemError::EINVAL): 寄存器超出了区域，或者地址没有按`T`对齐
+    pub fn register<T: MmioValue>(
+        &self,
+        offset: usize,
+    ) -> Result<MmioRegister<'_, T>, SystemError> {
+        self.check_range(offset, size_of::<T>())?;
+        let ptr = unsafe { self.base.add(offset) };
+        if ptr.as_ptr() as usize % align_of::<T>() != 0 {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(MmioRegister {
+            ptr: ptr.cast(),
+            _region: PhantomData,
+        })
+    }
+
+    /// 读取位于`offset`处、类型为`T`的寄存器
+    pub fn read<T: MmioValue>(&self, offset: usize) -> Result<T, SystemError> {
+        Ok(self.register::<T>(offset)?.read())
+    }
+
+    /// 写入位于`offset`处、类型为`T`的寄存器
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) -> Result<(), SystemError> {
+        self.register::<T>(offset)?.write(value);
+        Ok(())
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), SystemError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(SystemError::EINVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 用按8字节对齐的普通内存模拟32字节的寄存器区域，返回`f`访问之后内存中的内容
+    fn with_region(f: impl FnOnce(&MmioRegion)) -> [u8; 32] {
+        let mut buf = [0u64; 4];
+        let region =
+            unsafe { MmioRegion::new(NonNull::new(buf.as_mut_ptr() as *mut u8).unwrap(), 32) };
+        f(&region);
+        drop(region);
+
+        let mut out = [0u8; 32];
+        for (i, v) in buf.iter().enumerate() {
+            out[i * 8..i * 8 + 8].copy_from_slice(&v.to_ne_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn test_access_width() {
+        let b = with_region(|region| {
+            region.write::<u32>(4, 0x1122_3344).unwrap();
+            region.write::<u8>(1, 0xaa).unwrap();
+            region.write::<u16>(10, 0xbbcc).unwrap();
+            region.write::<u64>(24, u64::MAX).unwrap();
+
+            assert_eq!(region.read::<u32>(4).unwrap(), 0x1122_3344);
+            assert_eq!(region.read::<u8>(1).unwrap(), 0xaa);
+            assert_eq!(region.read::<u16>(10).unwrap(), 0xbbcc);
+            assert_eq!(region.read::<u64>(24).unwrap(), u64::MAX);
+        });
+        assert_eq!(b[0..4], [0, 0xaa, 0, 0]);
+        assert_eq!(b[4..8], 0x1122_3344u32.to_ne_bytes());
+        assert_eq!(b[8..10], [0, 0]);
+        assert_eq!(b[10..12], 0xbbccu16.to_ne_bytes());
+        // 没有写到的字节保持不变
+        assert_eq!(b[12..24], [0; 12]);
+        assert_eq!(b[24..32], [0xff; 8]);
+    }
+
+    #[test]
+    fn test_reject_out_of_bounds() {
+        let b = with_region(|region| {
+            assert_eq!(region.read::<u32>(28).unwrap(), 0);
+            assert_eq!(region.read::<u32>(32).err(), Some(SystemError::EINVAL));
+            assert_eq!(region.read::<u64>(28).err(), Some(SystemError::EINVAL));
+            assert_eq!(region.write::<u8>(32, 1).err(), Some(SystemError::EINVAL));
+            assert_eq!(
+                region.register::<u16>(usize::MAX).err(),
+                Some(SystemError::EINVAL)
+            );
+            // 没有按宽度对齐
+            assert_eq!(region.write::<u32>(2, 1).err(), Some(SystemError::EINVAL));
+            assert_eq!(region.read::<u64>(4).err(), Some(SystemError::EINVAL));
+        });
+        // 被拒绝的写入没有修改内存
+        assert_eq!(b, [0; 32]);
+    }
+
+    #[test]
+    fn test_subregion() {
+        let b = with_region(|region| {
+            let sub = region.subregion(16, 16).unwrap();
+            assert_eq!(sub.len(), 16);
+            sub.write::<u32>(0, 0xdead_beef).unwrap();
+            assert_eq!(region.read::<u32>(16).unwrap(), 0xdead_beef);
+            assert_eq!(sub.read::<u8>(16).err(), Some(SystemError::EINVAL));
+
+            assert_eq!(region.subregion(16, 17).err(), Some(SystemError::EINVAL));
+            assert_eq!(
+                region.subregion(usize::MAX, 2).err(),
+                Some(SystemError::EINVAL)
+            );
+            assert!(region.subregion(32, 0).unwrap().is_empty());
+        });
+        assert_eq!(b[16..20], 0xdead_beefu32.to_ne_bytes());
+    }
+}
@@ -0,0 +1,217 @@
+//! 单元测试中使用的模拟DMA以及模拟设备
+//!
+//! [`MockHal`]用普通的堆内存模拟DMA内存，物理地址与虚拟地址相同，因此测试可以直接访问设备看到的地址。
+//! [`MockVirtqDevice`]模拟设备一侧对split virtqueue的访问：从available ring中取出请求，
+//! 处理完之后写入used ring。[`MockTransport`]代替设备的寄存器，只记录驱动对设备的通知
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+use virtio_drivers::{
+    transport::{DeviceStatus, DeviceType, Transport},
+    BufferDirection, Hal, PhysAddr, PAGE_SIZE,
+};
+
+use super::{queue::VirtqSplit, sg::VirtqDescFlags};
+
+/// 描述符表中一项的大小
+const DESC_SIZE: usize = 16;
+/// available ring和used ring中，flags与idx之后才是ring
+const RING_HEADER_SIZE: usize = 4;
+/// used ring中一项的大小
+const USED_ELEM_SIZE: usize = 8;
+
+/// 用普通的堆内存模拟DMA内存，物理地址与虚拟地址相同
+pub struct MockHal;
+
+impl MockHal {
+    pub fn layout(pages: usize) -> Layout {
+        Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+}
+
+unsafe impl Hal for MockHal {
+    fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        let ptr = NonNull::new(unsafe { alloc_zeroed(Self::layout(pages)) }).unwrap();
+        (ptr.as_ptr() as PhysAddr, ptr)
+    }
+
+    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        dealloc(vaddr.as_ptr(), Self::layout(pages));
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        NonNull::new(paddr as *mut u8).unwrap()
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
+        buffer.as_ptr() as *mut u8 as PhysAddr
+    }
+
+    unsafe fn unshare(_paddr: PhysAddr, _buffer: NonNull<[u8]>, _direction: BufferDirection) {}
+}
+
+/// 设备看到的一个描述符
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: VirtqDescFlags,
+}
+
+impl MockDesc {
+    pub fn writable(&self) -> bool {
+        self.flags.contains(VirtqDescFlags::WRITE)
+    }
+
+    /// 描述符指向的缓冲区，[`MockHal`]的物理地址就是虚拟地址
+    ///
+    /// ## Safety
+    /// 请求还没有被驱动回收，缓冲区仍然有效
+    pub unsafe fn buf<'a>(&self) -> &'a mut [u8] {
+        core::slice::from_raw_parts_mut(self.addr as *mut u8, self.len as usize)
+    }
+}
+
+/// # 结构功能
+/// 模拟设备一侧的split virtqueue状态，ring中的字段按照modern设备的小端序访问
+#[derive(Debug, Default)]
+pub struct MockVirtqDevice {
+    /// 下一次从available ring中读取的位置
+    last_avail: u16,
+    /// 下一次写入used ring的位置
+    used_idx: u16,
+}
+
+impl MockVirtqDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # 函数的功能
+    /// 从available ring中取出下一个请求
+    ///
+    /// ## 返回值
+    /// - Some((head, descs)): 描述符链的链头以及链上的所有描述符
+    /// - None: 驱动没有提交新的请求
+    pub fn pop_avail<H: Hal>(&mut self, q: &VirtqSplit<H>) -> Option<(u16, Vec<MockDesc>)> {
+        let ring = q.ring();
+        let avail = ring.avail_vaddr().as_ptr();
+        let avail_idx = u16::from_le(unsafe { (avail.add(2) as *const u16).read_volatile() });
+        if avail_idx == self.last_avail {
+            return None;
+        }
+        let slot = (self.last_avail % q.size()) as usize;
+        let head = u16::from_le(unsafe {
+            (avail.add(RING_HEADER_SIZE + 2 * slot) as *const u16).read_volatile()
+        });
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descs = Vec::new();
+        let mut index = head;
+        loop {
+            let p = unsafe { ring.desc_vaddr().as_ptr().add(index as usize * DESC_SIZE) };
+            let (addr, len, flags, next) = unsafe {
+                (
+                    u64::from_le((p as *const u64).read_volatile()),
+                    u32::from_le((p.add(8) as *const u32).read_volatile()),
+                    u16::from_le((p.add(12) as *const u16).read_volatile()),
+                    u16::from_le((p.add(14) as *const u16).read_volatile()),
+                )
+            };
+            let flags = VirtqDescFlags::from_bits_truncate(flags);
+            descs.push(MockDesc { addr, len, flags });
+            if !flags.contains(VirtqDescFlags::NEXT) {
+                break;
+            }
+            assert!(descs.len() <= q.size() as usize, "descriptor loop");
+            index = next;
+        }
+        Some((head, descs))
+    }
+
+    /// 把一个已经完成的请求放入used ring，`len`为设备写入的字节数
+    pub fn push_used<H: Hal>(&mut self, q: &VirtqSplit<H>, head: u16, len: u32) {
+        let used = q.ring().used_vaddr().as_ptr();
+        let slot = (self.used_idx % q.size()) as usize;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        unsafe {
+            let elem = used.add(RING_HEADER_SIZE + USED_ELEM_SIZE * slot);
+            (elem as *mut u32).write_volatile((head as u32).to_le());
+            (elem.add(4) as *mut u32).write_volatile(len.to_le());
+            (used.add(2) as *mut u16).write_volatile(self.used_idx.to_le());
+        }
+    }
+
+    /// 驱动是否要求设备在完成请求时不产生中断
+    pub fn interrupts_suppressed<H: Hal>(&self, q: &VirtqSplit<H>) -> bool {
+        let flags = unsafe { (q.ring().avail_vaddr().as_ptr() as *const u16).read_volatile() };
+        u16::from_le(flags) & 1 != 0
+    }
+}
+
+/// # 结构功能
+/// 模拟的transport：设备没有任何特性和配置空间，只记录驱动通知设备的次数
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    /// 驱动通知设备有新请求的次数
+    pub notifications: usize,
+}
+
+impl Transport for MockTransport {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        0
+    }
+
+    fn write_driver_features(&mut self, _driver_features: u64) {}
+
+    fn max_queue_size(&mut self, _queue: u16) -> u32 {
+        u16::MAX as u32
+    }
+
+    fn notify(&mut self, _queue: u16) {
+        self.notifications += 1;
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        DeviceStatus::empty()
+    }
+
+    fn set_status(&mut self, _status: DeviceStatus) {}
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {}
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_set(
+        &mut self,
+        _queue: u16,
+        _size: u32,
+        _descriptors: PhysAddr,
+        _driver_area: PhysAddr,
+        _device_area: PhysAddr,
+    ) {
+    }
+
+    fn queue_unset(&mut self, _queue: u16) {}
+
+    fn queue_used(&mut self, _queue: u16) -> bool {
+        true
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        true
+    }
+
+    fn config_space<T: 'static>(&self) -> virtio_drivers::Result<NonNull<T>> {
+        Err(virtio_drivers::Error::ConfigSpaceMissing)
+    }
+}
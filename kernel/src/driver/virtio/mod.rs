@@ -1,25 +1,64 @@
-use alloc::{collections::LinkedList, string::String, sync::Arc};
+use alloc::{collections::LinkedList, string::String, sync::Arc, vec::Vec};
+use log::debug;
 use system_error::SystemError;
 
 use crate::exception::{irqdesc::IrqReturn, IrqNumber};
 
-use super::base::device::{driver::Driver, Device, DeviceId};
+use super::{
+    base::device::{driver::Driver, Device, DeviceId},
+    pci::dev_id::PciDeviceID,
+};
+use queue_full::VirtQueueFullPolicy;
+use transport::{VirtIOIrqType, VirtIOIsr};
 
+pub mod balloon;
+pub mod config;
+pub mod dma;
+pub mod endian;
+pub mod features;
+pub mod guard;
+pub mod hotplug;
 pub(super) mod irq;
 pub mod mmio;
+pub mod mmio_region;
+#[cfg(test)]
+pub mod mock;
+pub mod notify;
+pub mod queue;
+pub mod queue_full;
+pub mod reset;
+pub mod ring;
+pub mod router;
+pub mod selftest;
+pub mod sg;
+pub mod shm;
 pub mod sysfs;
+pub mod teardown;
 pub mod transport;
 pub mod transport_mmio;
 pub mod transport_pci;
 #[allow(clippy::module_inception)]
 pub mod virtio;
 pub mod virtio_impl;
+pub mod worker;
 
 /// virtio 设备厂商ID
 pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 // 参考：https://code.dragonos.org.cn/xref/linux-6.6.21/include/linux/mod_devicetable.h?fi=VIRTIO_DEV_ANY_ID#453
 pub const VIRTIO_DEV_ANY_ID: u32 = 0xffffffff;
 
+/// virtio设备的PCI ID：transitional设备使用0x1000~0x103f，modern设备使用0x1040~0x107f
+///
+/// 参考 virtio spec 4.1.2.1 Device Requirements: PCI Device Discovery
+pub const VIRTIO_PCI_DEVICE_IDS: [PciDeviceID; 2] = [
+    PciDeviceID::new_range(VIRTIO_VENDOR_ID, 0x1000, 0x103f),
+    PciDeviceID::new_range(
+        VIRTIO_VENDOR_ID,
+        VirtioDeviceType::PCI_DEVICE_ID_OFFSET,
+        0x107f,
+    ),
+];
+
 #[allow(dead_code)]
 pub trait VirtIODevice: Device {
     fn handle_irq(&self, _irq: IrqNumber) -> Result<IrqReturn, SystemError>;
@@ -43,14 +82,147 @@ pub trait VirtIODevice: Device {
     /// virtio设备的中断号
     fn irq(&self) -> Option<IrqNumber>;
 
+    /// virtio设备的modalias，见[`VirtioDeviceId::modalias`]
+    fn modalias(&self) -> String {
+        VirtioDeviceId::new(self.device_type_id(), self.vendor()).modalias()
+    }
+
     fn set_irq_number(&self, _irq: IrqNumber) -> Result<(), SystemError> {
         Err(SystemError::ENOSYS)
     }
+
+    /// virtio设备实际使用的中断类型
+    fn irq_type(&self) -> VirtIOIrqType {
+        VirtIOIrqType::None
+    }
+
+    /// 设备的ISR状态寄存器，不使用MSI-X时中断处理程序通过它判断中断的原因
+    fn isr(&self) -> Option<VirtIOIsr> {
+        None
+    }
+
+    /// 设备的配置空间发生了变化
+    fn handle_config_change(&self) {
+        debug!("virtio device '{:?}': config changed", self.dev_id());
+    }
+
+    /// 设备使用的virtqueue的编号，用于按队列统计中断次数
+    fn queues(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// # 函数的功能
+    /// 设备是否处于DRIVER_OK状态
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOSYS): 设备不支持查询
+    fn enabled(&self) -> Result<bool, SystemError> {
+        Err(SystemError::ENOSYS)
+    }
+
+    /// # 函数的功能
+    /// 复位（enable为false）或者重新初始化（enable为true）设备
+    ///
+    /// 复位之前需要先让正在处理的请求结束，复位后virtqueue会被释放
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOSYS): 设备不支持复位
+    fn set_enabled(&self, _enable: bool) -> Result<(), SystemError> {
+        Err(SystemError::ENOSYS)
+    }
+
+    /// # 函数的功能
+    /// 运行驱动实现的自检，例如让请求在virtqueue中完成一次提交与完成的往返
+    ///
+    /// 自检不能修改设备上的数据，也不能改变设备的状态
+    ///
+    /// ## 返回值
+    /// - Ok(()): 自检通过
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 驱动不支持自检
+    /// - Err(e): 自检失败的原因
+    fn selftest(&self) -> Result<(), SystemError> {
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+
+    /// # 函数的功能
+    /// 设备与驱动解除绑定时，停止设备对所有virtqueue的访问并释放它们，然后释放设备的中断向量
+    ///
+    /// 见[`teardown::virtio_teardown_queues`]。之后设备不会再通过DMA访问驱动的内存
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ETIMEDOUT): 等待设备交还描述符超时，队列仍然已经释放
+    /// - Err(SystemError::ENOSYS): 设备不支持
+    fn teardown_queues(&self) -> Result<(), SystemError> {
+        Err(SystemError::ENOSYS)
+    }
+
+    /// # 函数的功能
+    /// 挂起之前让设备停止工作：停止接收新的请求，等待已经提交的请求完成，`reset`为true时再复位设备
+    ///
+    /// 见[`teardown::virtio_quiesce_queues`]。之后设备不会再通过DMA访问驱动的内存，
+    /// 直到调用[`Self::unquiesce`]
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ETIMEDOUT): 等待请求完成超时
+    /// - Err(SystemError::ENOSYS): 设备不支持
+    fn quiesce(&self, _reset: bool) -> Result<(), SystemError> {
+        Err(SystemError::ENOSYS)
+    }
+
+    /// # 函数的功能
+    /// 恢复之后重新接收请求，设备在[`Self::quiesce`]中被复位时重新初始化设备
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOSYS): 设备不支持
+    fn unquiesce(&self) -> Result<(), SystemError> {
+        Err(SystemError::ENOSYS)
+    }
+
+    /// # 函数的功能
+    /// 以文本形式导出某个virtqueue的状态（available/used ring的idx、空闲描述符数、正在处理的请求），
+    /// 用于调试卡住的队列。设备可以正在工作，调用不会等待正在处理的请求
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOENT): 设备没有这个队列
+    /// - Err(SystemError::EBUSY): 队列正在被使用，暂时无法得到一致的状态
+    /// - Err(SystemError::ENOSYS): 驱动不支持（virtio-drivers管理的队列不暴露内部状态）
+    fn ring_debug_dump(&self, _queue_index: u16) -> Result<String, SystemError> {
+        Err(SystemError::ENOSYS)
+    }
+
+    /// # 函数的功能
+    /// 把virtqueue中驱动读取used ring的位置同步到设备写入的idx，用于从两者不一致的状态中恢复
+    ///
+    /// ## 返回值
+    /// - Ok(n): 被跳过的used ring表项的数量
+    /// - Err(e): 与[`Self::ring_debug_dump`]相同
+    fn ring_reset_last_used(&self, _queue_index: u16) -> Result<u16, SystemError> {
+        Err(SystemError::ENOSYS)
+    }
+
+    /// virtqueue已满时阻塞的提交者如何等待，见[`queue_full`]
+    fn queue_full_policy(&self) -> VirtQueueFullPolicy {
+        VirtQueueFullPolicy::default()
+    }
+
+    /// # 函数的功能
+    /// 设置virtqueue已满时阻塞的提交者如何等待，对之后开始等待的提交生效
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::ENOSYS): 驱动的提交路径不会阻塞，或者不支持修改
+    fn set_queue_full_policy(&self, _policy: VirtQueueFullPolicy) -> Result<(), SystemError> {
+        Err(SystemError::ENOSYS)
+    }
 }
 
 pub trait VirtIODriver: Driver {
     fn probe(&self, device: &Arc<dyn VirtIODevice>) -> Result<(), SystemError>;
 
+    /// 设备与驱动解除绑定时调用
+    fn remove(&self, _device: &Arc<dyn VirtIODevice>) -> Result<(), SystemError> {
+        Ok(())
+    }
+
     fn virtio_id_table(&self) -> LinkedList<VirtioDeviceId>;
 
     fn add_virtio_id(&self, id: VirtioDeviceId);
@@ -74,4 +246,200 @@ impl VirtioDeviceId {
     pub fn new(device: u32, vendor: u32) -> Self {
         Self { device, vendor }
     }
+
+    /// # 函数的功能
+    /// 生成virtio设备的modalias，格式为"virtio:d{设备类型}v{厂商}"，两者均为8位大写十六进制数
+    ///
+    /// 它与virtio设备的父设备（例如pci设备）的modalias不同，用户态可以据此按照设备类型匹配virtio驱动
+    ///
+    /// 参考：https://code.dragonos.org.cn/xref/linux-6.6.21/drivers/virtio/virtio.c#45
+    pub fn modalias(&self) -> String {
+        format!("virtio:d{:08X}v{:08X}", self.device, self.vendor)
+    }
+}
+
+/// DragonOS侧的virtio设备类型
+///
+/// 数值与virtio规范第5章中的Device ID一致。未知的类型会保留其数值，便于打印日志。
+///
+/// 参考：https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-1930005
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VirtioDeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    NineP,
+    Gpu,
+    Input,
+    Socket,
+    Crypto,
+    Iommu,
+    Memory,
+    Sound,
+    FileSystem,
+    Unknown(u32),
+}
+
+#[allow(dead_code)]
+impl VirtioDeviceType {
+    /// PCI Virtio设备的modern DEVICE_ID相对于virtio Device ID的偏移
+    pub const PCI_DEVICE_ID_OFFSET: u16 = 0x1040;
+
+    /// transitional设备的PCI DEVICE_ID到设备类型的映射
+    ///
+    /// 参考：virtio规范 4.1.2.1 Device Requirements: PCI Device Discovery
+    const TRANSITIONAL_TABLE: [(u16, VirtioDeviceType); 7] = [
+        (0x1000, VirtioDeviceType::Network),
+        (0x1001, VirtioDeviceType::Block),
+        (0x1002, VirtioDeviceType::Balloon),
+        (0x1003, VirtioDeviceType::Console),
+        (0x1004, VirtioDeviceType::Scsi),
+        (0x1005, VirtioDeviceType::Entropy),
+        (0x1009, VirtioDeviceType::NineP),
+    ];
+
+    /// 根据virtio规范中的Device ID获取设备类型
+    pub fn from_device_id(id: u32) -> Self {
+        match id {
+            1 => Self::Network,
+            2 => Self::Block,
+            3 => Self::Console,
+            4 => Self::Entropy,
+            5 => Self::Balloon,
+            8 => Self::Scsi,
+            9 => Self::NineP,
+            16 => Self::Gpu,
+            18 => Self::Input,
+            19 => Self::Socket,
+            20 => Self::Crypto,
+            23 => Self::Iommu,
+            24 => Self::Memory,
+            25 => Self::Sound,
+            26 => Self::FileSystem,
+            id => Self::Unknown(id),
+        }
+    }
+
+    /// 根据PCI配置空间中的DEVICE_ID获取设备类型
+    ///
+    /// - 0x1000..=0x103f: transitional设备，查表得到类型
+    /// - 0x1040..=0x107f: modern设备，DEVICE_ID减去0x1040即为virtio Device ID
+    ///
+    /// 无法识别的DEVICE_ID保存在[`Self::Unknown`]中
+    pub fn from_pci_device_id(pci_device_id: u16) -> Self {
+        if pci_device_id >= Self::PCI_DEVICE_ID_OFFSET {
+            return Self::from_device_id((pci_device_id - Self::PCI_DEVICE_ID_OFFSET) as u32);
+        }
+
+        Self::TRANSITIONAL_TABLE
+            .iter()
+            .find(|(id, _)| *id == pci_device_id)
+            .map(|(_, t)| *t)
+            .unwrap_or(Self::Unknown(pci_device_id as u32))
+    }
+
+    /// 根据transport报告的设备类型获取DragonOS侧的设备类型
+    pub fn from_transport(transport: &transport::VirtIOTransport) -> Self {
+        use virtio_drivers::transport::Transport;
+        Self::from(transport.device_type())
+    }
+
+    /// virtio规范中的Device ID
+    pub fn device_id(&self) -> u32 {
+        match self {
+            Self::Network => 1,
+            Self::Block => 2,
+            Self::Console => 3,
+            Self::Entropy => 4,
+            Self::Balloon => 5,
+            Self::Scsi => 8,
+            Self::NineP => 9,
+            Self::Gpu => 16,
+            Self::Input => 18,
+            Self::Socket => 19,
+            Self::Crypto => 20,
+            Self::Iommu => 23,
+            Self::Memory => 24,
+            Self::Sound => 25,
+            Self::FileSystem => 26,
+            Self::Unknown(id) => *id,
+        }
+    }
+}
+
+impl From<virtio_drivers::transport::DeviceType> for VirtioDeviceType {
+    fn from(value: virtio_drivers::transport::DeviceType) -> Self {
+        Self::from_device_id(value as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modalias() {
+        let net = VirtioDeviceId::new(
+            VirtioDeviceType::Network.device_id(),
+            VIRTIO_VENDOR_ID.into(),
+        );
+        assert_eq!(net.modalias(), "virtio:d00000001v00001AF4");
+
+        let blk = VirtioDeviceId::new(VirtioDeviceType::Block.device_id(), VIRTIO_VENDOR_ID.into());
+        assert_eq!(blk.modalias(), "virtio:d00000002v00001AF4");
+    }
+
+    #[test]
+    fn test_from_pci_device_id_transitional() {
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x1000),
+            VirtioDeviceType::Network
+        );
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x1001),
+            VirtioDeviceType::Block
+        );
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x1009),
+            VirtioDeviceType::NineP
+        );
+    }
+
+    #[test]
+    fn test_from_pci_device_id_modern() {
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x1041),
+            VirtioDeviceType::Network
+        );
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x1042),
+            VirtioDeviceType::Block
+        );
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x105a),
+            VirtioDeviceType::FileSystem
+        );
+        // 规范中没有定义的Device ID
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x107f),
+            VirtioDeviceType::Unknown(0x3f)
+        );
+    }
+
+    #[test]
+    fn test_from_pci_device_id_unknown() {
+        // transitional范围内没有定义的DEVICE_ID保留原来的值
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x1006),
+            VirtioDeviceType::Unknown(0x1006)
+        );
+        assert_eq!(
+            VirtioDeviceType::from_pci_device_id(0x103f),
+            VirtioDeviceType::Unknown(0x103f)
+        );
+    }
 }
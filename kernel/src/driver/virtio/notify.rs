@@ -0,0 +1,325 @@
+//! virtqueue的通知(kick)
+//!
+//! 协商了VIRTIO_F_NOTIFICATION_DATA之后，驱动通知设备时除了队列号之外，
+//! 还要告诉设备下一个available ring表项的位置，设备因此不必读取available ring就能知道有多少新的请求。
+//!
+//! 此时写入通知区域的是一个32位的值(小端)，参考 virtio spec 2.9 Driver Notifications：
+//!
+//! | 位     | 字段      | 含义(split virtqueue)           |
+//! |--------|-----------|---------------------------------|
+//! | 0~15   | vqn       | 队列号                          |
+//! | 16~30  | next_off  | available ring的idx的低15位     |
+//! | 31     | next_wrap | available ring的idx的第15位     |
+//!
+//! 对split virtqueue来说，next_off和next_wrap合起来正好是完整的16位idx。
+//! 没有协商该特性的设备仍然只写入16位的队列号。
+//!
+//! 特性的协商以及通知都在transport中完成，virtio-drivers中的设备驱动不需要感知这个特性。
+
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr::NonNull};
+
+use system_error::SystemError;
+
+use super::endian::{virtio_read_field, VirtIOEndian};
+
+/// 驱动在通知中携带下一个available ring表项的位置
+///
+/// 参考 virtio spec 6 Reserved Feature Bits
+pub const VIRTIO_F_NOTIFICATION_DATA: u64 = 1 << 38;
+
+/// available ring中idx字段的偏移量(在flags字段之后)
+const VIRTQ_AVAIL_IDX_OFFSET: usize = size_of::<u16>();
+
+/// # 函数的功能
+/// 计算协商了VIRTIO_F_NOTIFICATION_DATA时写入通知区域的值
+///
+/// ## 参数
+/// - `queue`: 队列号
+/// - `avail_idx`: available ring的idx，即下一个available ring表项的位置
+pub const fn virtio_notification_data(queue: u16, avail_idx: u16) -> u32 {
+    (queue as u32) | ((avail_idx as u32) << 16)
+}
+
+/// # trait功能
+/// 向设备的通知区域写入数据
+///
+/// 抽象出来之后可以在没有真实设备的情况下检查写入的值
+pub trait VirtIONotifyRegion {
+    /// 在通知区域中偏移为`offset`字节处写入16位的值
+    fn write_notify_u16(&mut self, offset: usize, value: u16);
+
+    /// 在通知区域中偏移为`offset`字节处写入32位的值
+    fn write_notify_u32(&mut self, offset: usize, value: u32);
+}
+
+/// # 函数的功能
+/// 计算modern virtio-pci设备中队列的通知地址在通知区域中的偏移量
+///
+/// 参考 virtio spec 4.1.4.4 Notification structure layout
+///
+/// ## 参数
+/// - `queue_notify_off`: common config中该队列的queue_notify_off
+/// - `notify_off_multiplier`: notify capability中的notify_off_multiplier，为0时所有队列共用同一个地址
+pub const fn virtio_pci_notify_offset(queue_notify_off: u16, notify_off_multiplier: u32) -> usize {
+    queue_notify_off as usize * notify_off_multiplier as usize
+}
+
+/// 一个已经设置好的队列
+#[derive(Debug, Clone, Copy)]
+struct NotifyQueue {
+    /// 队列的通知地址在通知区域中的偏移量
+    offset: usize,
+    /// available ring中的idx字段
+    avail_idx: NonNull<u16>,
+}
+
+/// # 结构功能
+/// 缓存每个队列的通知地址以及available ring，按照特性的协商结果通知设备
+///
+/// 通知地址在队列设置时计算一次，之后通知设备只需要一次写入
+#[derive(Debug, Clone, Default)]
+pub struct VirtQueueNotifier {
+    /// 是否协商了VIRTIO_F_NOTIFICATION_DATA
+    notification_data: bool,
+    /// 按照队列号索引
+    queues: Vec<Option<NotifyQueue>>,
+}
+
+impl VirtQueueNotifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # 函数的功能
+    /// 在驱动写入特性之前调用，如果设备支持，就额外协商VIRTIO_F_NOTIFICATION_DATA
+    ///
+    /// ## 参数
+    /// - `device_features`: 设备提供的特性
+    /// - `driver_features`: 驱动选择的特性
+    ///
+    /// ## 返回值
+    /// 实际需要写入设备的驱动特性
+    pub fn negotiate(&mut self, device_features: u64, driver_features: u64) -> u64 {
+        self.notification_data = device_features & VIRTIO_F_NOTIFICATION_DATA != 0;
+        if self.notification_data {
+            driver_features | VIRTIO_F_NOTIFICATION_DATA
+        } else {
+            driver_features & !VIRTIO_F_NOTIFICATION_DATA
+        }
+    }
+
+    /// 是否协商了VIRTIO_F_NOTIFICATION_DATA
+    #[allow(dead_code)]
+    pub fn notification_data(&self) -> bool {
+        self.notification_data
+    }
+
+    /// # 函数的功能
+    /// 记录队列的通知地址以及available ring
+    ///
+    /// ## 参数
+    /// - `queue`: 队列号
+    /// - `offset`: 队列的通知地址在通知区域中的偏移量
+    /// - `avail_ring`: available ring的起始位置，协商了VIRTIO_F_NOTIFICATION_DATA时从中读取idx
+    ///
+    /// ## Safety
+    /// `avail_ring`必须在调用[`Self::remove_queue`]之前一直有效
+    pub unsafe fn set_queue(&mut self, queue: u16, offset: usize, avail_ring: NonNull<u8>) {
+        let idx = avail_ring
+            .as_ptr()
+            .add(VIRTQ_AVAIL_IDX_OFFSET)
+            .cast::<u16>();
+        let queue = queue as usize;
+        if self.queues.len() <= queue {
+            self.queues.resize(queue + 1, None);
+        }
+        self.queues[queue] = Some(NotifyQueue {
+            offset,
+            avail_idx: NonNull::new_unchecked(idx),
+        });
+    }
+
+    /// 队列被销毁，不再通知它
+    pub fn remove_queue(&mut self, queue: u16) {
+        if let Some(q) = self.queues.get_mut(queue as usize) {
+            *q = None;
+        }
+    }
+
+    /// 队列的通知地址在通知区域中的偏移量，队列没有设置时返回None
+    #[allow(dead_code)]
+    pub fn queue_offset(&self, queue: u16) -> Option<usize> {
+        self.queues
+            .get(queue as usize)
+            .copied()
+            .flatten()
+            .map(|q| q.offset)
+    }
+
+    /// # 函数的功能
+    /// 通知设备队列中有新的请求
+    ///
+    /// 调用者需要保证available ring的idx已经更新，并且在此之前执行了内存屏障
+    ///
+    /// ## 参数
+    /// - `region`: 通知区域
+    /// - `queue`: 队列号
+    ///
+    /// ## 返回值
+    /// - Ok(()): 已经通知设备
+    /// - Err(SystemError::ENOENT): 队列没有通过[`Self::set_queue`]设置
+    pub fn notify<R: VirtIONotifyRegion>(
+        &self,
+        region: &mut R,
+        queue: u16,
+    ) -> Result<(), SystemError> {
+        let q = self
+            .queues
+            .get(queue as usize)
+            .copied()
+            .flatten()
+            .ok_or(SystemError::ENOENT)?;
+
+        if self.notification_data {
+            // Safety: set_queue()的调用者保证了available ring有效
+            // VIRTIO_F_NOTIFICATION_DATA只存在于virtio 1.0以后的设备，ring总是小端的
+            let avail_idx =
+                unsafe { virtio_read_field(q.avail_idx.as_ptr(), VirtIOEndian::Little) };
+            region.write_notify_u32(q.offset, virtio_notification_data(queue, avail_idx));
+        } else {
+            region.write_notify_u16(q.offset, queue);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// 模拟的通知区域，记录每一次写入的(偏移量, 宽度, 值)
+    #[derive(Default)]
+    struct MockNotifyRegion {
+        writes: Vec<(usize, usize, u32)>,
+    }
+
+    impl VirtIONotifyRegion for MockNotifyRegion {
+        fn write_notify_u16(&mut self, offset: usize, value: u16) {
+            self.writes.push((offset, 2, value as u32));
+        }
+
+        fn write_notify_u32(&mut self, offset: usize, value: u32) {
+            self.writes.push((offset, 4, value));
+        }
+    }
+
+    /// available ring的开头部分：flags, idx, ring[0..2]
+    fn avail_ring(idx: u16) -> [u16; 4] {
+        [0, idx.to_le(), 0, 0]
+    }
+
+    #[test]
+    fn test_plain_notify() {
+        let mut notifier = VirtQueueNotifier::new();
+        assert_eq!(notifier.negotiate(0, 1 << 5), 1 << 5);
+        let mut ring = avail_ring(7);
+        unsafe { notifier.set_queue(1, 8, NonNull::from(&mut ring).cast()) };
+
+        let mut region = MockNotifyRegion::default();
+        notifier.notify(&mut region, 1).unwrap();
+        assert_eq!(region.writes, [(8, 2, 1)]);
+    }
+
+    #[test]
+    fn test_notification_data() {
+        let mut notifier = VirtQueueNotifier::new();
+        let features = notifier.negotiate(VIRTIO_F_NOTIFICATION_DATA | 1 << 5, 1 << 5);
+        assert_eq!(features, VIRTIO_F_NOTIFICATION_DATA | 1 << 5);
+        assert!(notifier.notification_data());
+
+        let mut rx = avail_ring(3);
+        let mut tx = avail_ring(0x8005);
+        let tx = NonNull::from(&mut tx).cast::<u8>();
+        unsafe {
+            notifier.set_queue(0, 0, NonNull::from(&mut rx).cast());
+            notifier.set_queue(1, 4, tx);
+        }
+
+        let mut region = MockNotifyRegion::default();
+        notifier.notify(&mut region, 0).unwrap();
+        notifier.notify(&mut region, 1).unwrap();
+        // 驱动更新了idx之后，通知中携带新的idx
+        unsafe {
+            tx.as_ptr()
+                .add(VIRTQ_AVAIL_IDX_OFFSET)
+                .cast::<u16>()
+                .write_volatile(0x8006u16.to_le())
+        };
+        notifier.notify(&mut region, 1).unwrap();
+        assert_eq!(
+            region.writes,
+            [
+                (0, 4, 0x0003_0000),
+                (4, 4, 0x8005_0001),
+                (4, 4, 0x8006_0001)
+            ]
+        );
+    }
+
+    /// 模拟notify_off_multiplier不为0的设备：每个队列有自己的通知地址
+    #[test]
+    fn test_cached_notify_offsets() {
+        // (队列号, queue_notify_off)
+        let queues = [(0u16, 0u16), (1, 1), (2, 5)];
+        for multiplier in [0u32, 4, 16] {
+            let mut notifier = VirtQueueNotifier::new();
+            notifier.negotiate(0, 0);
+            let mut ring = avail_ring(0);
+            for (queue, notify_off) in queues {
+                let offset = virtio_pci_notify_offset(notify_off, multiplier);
+                unsafe { notifier.set_queue(queue, offset, NonNull::from(&mut ring).cast()) };
+            }
+
+            let mut region = MockNotifyRegion::default();
+            for (queue, _) in queues.iter().rev() {
+                notifier.notify(&mut region, *queue).unwrap();
+            }
+            let m = multiplier as usize;
+            assert_eq!(region.writes, [(5 * m, 2, 2), (m, 2, 1), (0, 2, 0)]);
+        }
+    }
+
+    #[test]
+    fn test_unset_queue() {
+        let mut notifier = VirtQueueNotifier::new();
+        let mut ring = avail_ring(0);
+        unsafe { notifier.set_queue(3, 12, NonNull::from(&mut ring).cast()) };
+        assert_eq!(notifier.queue_offset(3), Some(12));
+        assert_eq!(notifier.queue_offset(0), None);
+
+        let mut region = MockNotifyRegion::default();
+        assert_eq!(notifier.notify(&mut region, 0), Err(SystemError::ENOENT));
+        notifier.remove_queue(3);
+        assert_eq!(notifier.notify(&mut region, 3), Err(SystemError::ENOENT));
+        assert!(region.writes.is_empty());
+    }
+
+    #[test]
+    fn test_device_without_feature() {
+        let mut notifier = VirtQueueNotifier::new();
+        // 设备没有提供该特性时，不能写入设备
+        assert_eq!(notifier.negotiate(1 << 5, VIRTIO_F_NOTIFICATION_DATA), 0);
+        assert!(!notifier.notification_data());
+    }
+
+    #[test]
+    fn test_payload_layout() {
+        assert_eq!(virtio_notification_data(0x1234, 0), 0x1234);
+        assert_eq!(virtio_notification_data(2, 0x7fff), 0x7fff_0002);
+        // next_wrap位于第31位
+        assert_eq!(virtio_notification_data(2, 0x8000), 0x8000_0002);
+    }
+}
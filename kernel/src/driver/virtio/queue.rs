@@ -0,0 +1,642 @@
+//! 由驱动直接管理的split virtqueue
+//!
+//! virtio-blk、virtio-net和virtio-fs都使用这里的实现，而不是virtio-drivers中的VirtQueue：
+//! - 我们使用的virtio-drivers版本没有导出VirtQueue，只能通过它自己的设备驱动间接使用，
+//!   而它没有virtio-fs的驱动
+//! - VirtQueue的大小是编译期的常量，不能使用与设备协商得到的队列大小
+//! - VirtQueue的内部状态无法复位，不能支持VIRTIO_F_RING_RESET
+//!
+//! 这里在[`VirtqRingMemory`]之上实现一个最简单的split virtqueue：不使用间接描述符，也不使用事件索引，
+//! 请求由[`VirtioSgList`]描述，设备完成请求后由驱动从used ring中回收。
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    fmt::Display,
+    mem::offset_of,
+    ptr::NonNull,
+    sync::atomic::{AtomicU16, AtomicUsize, Ordering},
+};
+
+use system_error::SystemError;
+use virtio_drivers::{transport::Transport, BufferDirection, Hal, PhysAddr};
+
+use crate::libs::align::{CachePadded, CACHE_LINE_SIZE};
+
+use super::{
+    endian::{virtio_read_field, virtio_write_field, VirtIOEndian},
+    ring::{virtio_mb, virtio_rmb, virtio_wmb, VirtqRingMemory},
+    sg::VirtioSgList,
+};
+
+/// 设备不需要驱动在提交请求后通知它
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+/// 驱动不需要设备在完成请求后产生中断
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// 描述符表中一项的大小
+const VIRTQ_DESC_SIZE: usize = 16;
+/// available ring和used ring中，flags与idx之后才是ring
+const VIRTQ_RING_HEADER_SIZE: usize = 4;
+/// used ring中一项的大小(id: u32, len: u32)
+const VIRTQ_USED_ELEM_SIZE: usize = 8;
+
+/// # 结构功能
+/// 设备使用一个virtqueue所需的配置：队列大小以及三个区域的物理地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtqConfig {
+    pub size: u16,
+    /// 描述符表
+    pub desc: PhysAddr,
+    /// available ring
+    pub driver: PhysAddr,
+    /// used ring
+    pub device: PhysAddr,
+}
+
+/// 提交请求时访问的状态
+struct VirtqAvailState {
+    /// 空闲的描述符，从前往后使用
+    free: Vec<u16>,
+    /// 空闲描述符的数量，提交时减少，回收路径归还描述符时增加
+    num_free: AtomicUsize,
+    /// 下一次写入available ring的位置
+    avail_idx: AtomicU16,
+}
+
+/// 回收请求时访问的状态
+struct VirtqUsedState {
+    /// 下一次从used ring中读取的位置
+    last_used_idx: AtomicU16,
+}
+
+/// # 结构功能
+/// 一个split virtqueue，以及驱动一侧的状态
+///
+/// 修改队列需要调用者保证互斥。提交路径与中断处理中的回收路径可能运行在不同的CPU上，
+/// 两者都会访问的空闲描述符数量以及ring的位置使用原子变量，不持有队列的锁也可以读取
+/// (见[`Self::num_free`]、[`Self::debug_state`])。
+/// 提交路径与回收路径的状态分别占用独立的缓存行，队列本身也按缓存行对齐，
+/// 多个队列相邻存放时不会共享缓存行。设备可见的ring内存由[`VirtqRingMemory`]单独分配，不受影响
+#[repr(C)]
+pub struct VirtqSplit<H: Hal> {
+    avail: CachePadded<VirtqAvailState>,
+    used: CachePadded<VirtqUsedState>,
+    ring: VirtqRingMemory<H>,
+    queue_idx: u16,
+    /// 链头 -> 这条链占用的描述符
+    chains: BTreeMap<u16, Vec<u16>>,
+}
+
+// 提交路径与回收路径的状态不能落在同一个缓存行中
+const _: () = {
+    type Q = VirtqSplit<super::virtio_impl::HalImpl>;
+    assert!(core::mem::align_of::<Q>() >= CACHE_LINE_SIZE);
+    assert!(offset_of!(Q, avail) % CACHE_LINE_SIZE == 0);
+    assert!(offset_of!(Q, used) % CACHE_LINE_SIZE == 0);
+    assert!(offset_of!(Q, used) - offset_of!(Q, avail) >= CACHE_LINE_SIZE);
+    assert!(offset_of!(Q, ring) - offset_of!(Q, used) >= CACHE_LINE_SIZE);
+};
+
+unsafe impl<H: Hal> Send for VirtqSplit<H> {}
+unsafe impl<H: Hal> Sync for VirtqSplit<H> {}
+
+impl<H: Hal> VirtqSplit<H> {
+    /// # 函数的功能
+    /// 为队列分配内存
+    ///
+    /// ## 参数
+    /// - `queue_idx`: 队列在设备中的编号
+    /// - `size`: 队列大小，一般由[`super::transport::VirtIOTransport::negotiate_queue_size`]得到
+    /// - `legacy`: transport是否使用legacy布局
+    pub fn new(queue_idx: u16, size: u16, legacy: bool) -> Result<Self, SystemError> {
+        Ok(Self {
+            ring: VirtqRingMemory::alloc(size, legacy)?,
+            queue_idx,
+            avail: CachePadded::new(VirtqAvailState {
+                free: (0..size).collect(),
+                num_free: AtomicUsize::new(size as usize),
+                avail_idx: AtomicU16::new(0),
+            }),
+            used: CachePadded::new(VirtqUsedState {
+                last_used_idx: AtomicU16::new(0),
+            }),
+            chains: BTreeMap::new(),
+        })
+    }
+
+    pub fn queue_idx(&self) -> u16 {
+        self.queue_idx
+    }
+
+    pub fn size(&self) -> u16 {
+        self.ring.layout().queue_size
+    }
+
+    /// ring中多字节字段的字节序
+    fn endian(&self) -> VirtIOEndian {
+        VirtIOEndian::new(self.ring.layout().legacy)
+    }
+
+    /// 空闲描述符的数量
+    pub fn num_free(&self) -> usize {
+        self.avail.num_free.load(Ordering::Acquire)
+    }
+
+    /// 队列的内存，测试中的模拟设备通过它访问ring
+    #[cfg(test)]
+    pub(super) fn ring(&self) -> &VirtqRingMemory<H> {
+        &self.ring
+    }
+
+    /// # 函数的功能
+    /// 把队列的地址告诉设备，并启用队列
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EBUSY): 设备的这个队列已经在使用中
+    pub fn attach(&self, transport: &mut impl Transport) -> Result<(), SystemError> {
+        if transport.queue_used(self.queue_idx) {
+            return Err(SystemError::EBUSY);
+        }
+        let config = self.config();
+        transport.queue_set(
+            self.queue_idx,
+            config.size as u32,
+            config.desc,
+            config.driver,
+            config.device,
+        );
+        Ok(())
+    }
+
+    /// 设备使用这个队列所需的配置，设置队列以及复位队列之后重新设置时写入设备
+    pub fn config(&self) -> VirtqConfig {
+        VirtqConfig {
+            size: self.size(),
+            desc: self.ring.desc_paddr(),
+            driver: self.ring.avail_paddr(),
+            device: self.ring.used_paddr(),
+        }
+    }
+
+    /// # 函数的功能
+    /// 设备停止使用这个队列之后，丢弃其中所有的请求，让队列回到刚创建时的状态
+    ///
+    /// 用于单独复位队列(见[`super::reset::virtio_reset_queue`])：设备重新启用队列之后，
+    /// 从头开始读取available ring。ring的flags也被清零，之前关闭的中断需要重新关闭
+    ///
+    /// ## 返回值
+    /// 被丢弃的请求的链头，调用者需要以错误结束这些请求
+    pub fn reclaim_all(&mut self) -> Vec<u16> {
+        let heads = self.chains.keys().copied().collect();
+        self.chains.clear();
+        self.avail.free = (0..self.size()).collect();
+        self.avail
+            .num_free
+            .store(self.avail.free.len(), Ordering::Release);
+        self.avail.avail_idx.store(0, Ordering::Release);
+        self.used.last_used_idx.store(0, Ordering::Release);
+        self.ring.clear();
+        heads
+    }
+
+    /// # 函数的功能
+    /// 把一个请求放入available ring，设备此后就可以处理它，队列已满时不等待
+    ///
+    /// 在请求完成(被[`Self::pop_used`]返回)之前，调用者需要保证`sg`中的缓冲区一直有效
+    ///
+    /// ## 返回值
+    /// - Ok(head): 描述符链的链头，请求完成时used ring中的id
+    /// - Err(SystemError::EAGAIN_OR_EWOULDBLOCK): 空闲的描述符暂时不足，有请求完成之后可以重试
+    /// - Err(SystemError::EINVAL): 分段列表不合法，或者分段数超过了队列大小，永远无法提交
+    pub fn try_add(&mut self, sg: &VirtioSgList) -> Result<u16, SystemError> {
+        if sg.len() > self.size() as usize {
+            return Err(SystemError::EINVAL);
+        }
+        if sg.len() > self.avail.free.len() {
+            return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+        }
+        let chain = sg.build_chain(&self.avail.free, |addr| {
+            let buf = NonNull::slice_from_raw_parts(NonNull::new(addr as *mut u8)?, 1);
+            Some(unsafe { H::share(buf, BufferDirection::Both) } as u64)
+        })?;
+
+        let endian = self.endian();
+        let desc = self.ring.desc_vaddr().as_ptr();
+        for (index, d) in chain.iter() {
+            unsafe {
+                let p = desc.add(*index as usize * VIRTQ_DESC_SIZE);
+                virtio_write_field(p as *mut u64, d.addr, endian);
+                virtio_write_field(p.add(8) as *mut u32, d.len, endian);
+                virtio_write_field(p.add(12) as *mut u16, d.flags.bits(), endian);
+                virtio_write_field(p.add(14) as *mut u16, d.next, endian);
+            }
+        }
+        let used: Vec<u16> = self.avail.free.drain(..chain.len()).collect();
+        self.avail
+            .num_free
+            .store(self.avail.free.len(), Ordering::Release);
+        let head = used[0];
+        self.chains.insert(head, used);
+
+        let avail = self.ring.avail_vaddr().as_ptr();
+        let avail_idx = self.avail.avail_idx.load(Ordering::Relaxed);
+        let slot = (avail_idx % self.size()) as usize;
+        unsafe {
+            virtio_write_field(
+                avail.add(VIRTQ_RING_HEADER_SIZE + 2 * slot) as *mut u16,
+                head,
+                endian,
+            );
+        }
+        let avail_idx = avail_idx.wrapping_add(1);
+        self.avail.avail_idx.store(avail_idx, Ordering::Release);
+        // 描述符以及available ring的表项要先于idx对设备可见
+        virtio_wmb();
+        unsafe { virtio_write_field(avail.add(2) as *mut u16, avail_idx, endian) };
+        Ok(head)
+    }
+
+    /// # 函数的功能
+    /// 提交请求之后，判断是否需要通知设备
+    pub fn should_notify(&self) -> bool {
+        virtio_mb();
+        let flags = unsafe {
+            virtio_read_field(self.ring.used_vaddr().as_ptr() as *const u16, self.endian())
+        };
+        flags & VIRTQ_USED_F_NO_NOTIFY == 0
+    }
+
+    /// # 函数的功能
+    /// 设置设备在完成请求时是否需要产生中断
+    ///
+    /// 没有协商事件索引，因此通过available ring的flags告诉设备。这只是给设备的提示，
+    /// 关闭之后仍然可能收到中断；重新打开之后，调用者需要再检查一次used ring，
+    /// 在两者之间完成的请求不会产生中断
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        let flags = if enabled {
+            0
+        } else {
+            VIRTQ_AVAIL_F_NO_INTERRUPT
+        };
+        unsafe {
+            virtio_write_field(
+                self.ring.avail_vaddr().as_ptr() as *mut u16,
+                flags,
+                self.endian(),
+            )
+        };
+        // 之后对used ring的检查不能早于flags对设备可见
+        virtio_mb();
+    }
+
+    /// 设备写入used ring的idx
+    fn device_used_idx(&self) -> u16 {
+        unsafe {
+            virtio_read_field(
+                self.ring.used_vaddr().as_ptr().add(2) as *const u16,
+                self.endian(),
+            )
+        }
+    }
+
+    /// # 函数的功能
+    /// 查看used ring中下一个已经完成的请求，不取出它
+    ///
+    /// ## 返回值
+    /// - Some(head): 请求的链头
+    /// - None: 没有新完成的请求
+    pub fn peek_used(&self) -> Option<u16> {
+        let last_used = self.used.last_used_idx.load(Ordering::Acquire);
+        if self.device_used_idx() == last_used {
+            return None;
+        }
+        virtio_rmb();
+        let slot = (last_used % self.size()) as usize;
+        let id = unsafe {
+            let elem = self
+                .ring
+                .used_vaddr()
+                .as_ptr()
+                .add(VIRTQ_RING_HEADER_SIZE + VIRTQ_USED_ELEM_SIZE * slot);
+            virtio_read_field(elem as *const u32, self.endian())
+        };
+        Some(id as u16)
+    }
+
+    /// # 函数的功能
+    /// 从used ring中取出一个已经完成的请求，并回收它的描述符
+    ///
+    /// ## 返回值
+    /// - Some((head, len)): 请求的链头，以及设备写入的字节数
+    /// - None: 没有新完成的请求
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = self.ring.used_vaddr().as_ptr();
+        let used_idx = self.device_used_idx();
+        let last_used = self.used.last_used_idx.load(Ordering::Relaxed);
+        if used_idx == last_used {
+            return None;
+        }
+        // 读取used ring的表项不能早于读取idx
+        virtio_rmb();
+        let slot = (last_used % self.size()) as usize;
+        let (id, len) = unsafe {
+            let elem = used.add(VIRTQ_RING_HEADER_SIZE + VIRTQ_USED_ELEM_SIZE * slot);
+            (
+                virtio_read_field(elem as *const u32, self.endian()),
+                virtio_read_field(elem.add(4) as *const u32, self.endian()),
+            )
+        };
+        self.used
+            .last_used_idx
+            .store(last_used.wrapping_add(1), Ordering::Release);
+
+        let head = id as u16;
+        match self.chains.remove(&head) {
+            Some(descs) => {
+                self.avail.free.extend(descs);
+                self.avail
+                    .num_free
+                    .store(self.avail.free.len(), Ordering::Release);
+            }
+            None => log::warn!(
+                "virtqueue {}: device returned unknown descriptor {}",
+                self.queue_idx,
+                id
+            ),
+        }
+        Some((head, len))
+    }
+
+    /// # 函数的功能
+    /// 获取队列当前的状态，调用者持有队列的锁，因此驱动一侧的状态是一致的
+    pub fn debug_state(&self) -> VirtqDebugState {
+        VirtqDebugState {
+            queue_idx: self.queue_idx,
+            size: self.size(),
+            avail_idx: self.avail.avail_idx.load(Ordering::Acquire),
+            used_idx: self.device_used_idx(),
+            last_used: self.used.last_used_idx.load(Ordering::Acquire),
+            num_free: self.num_free(),
+            inflight: self.chains.keys().copied().collect(),
+        }
+    }
+
+    /// # 函数的功能
+    /// 以文本形式导出队列当前的状态，用于调试卡住的队列
+    pub fn ring_debug_dump(&self) -> String {
+        self.debug_state().to_string()
+    }
+
+    /// # 函数的功能
+    /// 把驱动读取used ring的位置同步到设备写入的idx，用于从两者不一致的状态中恢复
+    ///
+    /// 被跳过的完成不会再被[`Self::pop_used`]返回，它们的描述符链仍然被视为在设备中
+    ///
+    /// ## 返回值
+    /// 被跳过的used ring表项的数量
+    pub fn reset_last_used(&mut self) -> u16 {
+        let used_idx = self.device_used_idx();
+        let last_used = self.used.last_used_idx.swap(used_idx, Ordering::AcqRel);
+        used_idx.wrapping_sub(last_used)
+    }
+}
+
+/// # 结构功能
+/// 某一时刻virtqueue的状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtqDebugState {
+    pub queue_idx: u16,
+    pub size: u16,
+    /// 驱动写入available ring的idx
+    pub avail_idx: u16,
+    /// 设备写入used ring的idx
+    pub used_idx: u16,
+    /// 驱动下一次从used ring中读取的位置
+    pub last_used: u16,
+    pub num_free: usize,
+    /// 已经提交、还没有被回收的描述符链的链头
+    pub inflight: Vec<u16>,
+}
+
+impl Display for VirtqDebugState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "queue {}: size {} avail_idx {} used_idx {} last_used {} free {} inflight {:?}",
+            self.queue_idx,
+            self.size,
+            self.avail_idx,
+            self.used_idx,
+            self.last_used,
+            self.num_free,
+            self.inflight
+        )
+    }
+}
+
+impl<H: Hal> core::fmt::Debug for VirtqSplit<H> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VirtqSplit")
+            .field("queue_idx", &self.queue_idx)
+            .field("ring", &self.ring)
+            .field("free", &self.num_free())
+            .field("pending", &self.chains.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::virtio::mock::MockHal;
+
+    use super::*;
+
+    /// 模拟设备完成一个请求
+    fn complete(q: &VirtqSplit<MockHal>, used_idx: u16, head: u16, len: u32) {
+        let used = q.ring.used_vaddr().as_ptr();
+        let slot = (used_idx % q.size()) as usize;
+        unsafe {
+            let elem = used.add(VIRTQ_RING_HEADER_SIZE + VIRTQ_USED_ELEM_SIZE * slot);
+            (elem as *mut u32).write((head as u32).to_le());
+            (elem.add(4) as *mut u32).write(len.to_le());
+            (used.add(2) as *mut u16).write((used_idx + 1).to_le());
+        }
+    }
+
+    fn read_desc(q: &VirtqSplit<MockHal>, index: u16) -> (u64, u32, u16, u16) {
+        let p = unsafe {
+            q.ring
+                .desc_vaddr()
+                .as_ptr()
+                .add(index as usize * VIRTQ_DESC_SIZE)
+        };
+        unsafe {
+            (
+                u64::from_le((p as *const u64).read()),
+                u32::from_le((p.add(8) as *const u32).read()),
+                u16::from_le((p.add(12) as *const u16).read()),
+                u16::from_le((p.add(14) as *const u16).read()),
+            )
+        }
+    }
+
+    #[test]
+    fn test_debug_state() {
+        let mut q = VirtqSplit::<MockHal>::new(0, 4, false).unwrap();
+        let req = [1u8; 16];
+        let mut reply = [0u8; 16];
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&req);
+        sg.push_writable(&mut reply);
+        let head = q.try_add(&sg).unwrap();
+
+        // 请求已经提交，设备还没有完成
+        let state = q.debug_state();
+        assert_eq!(
+            state,
+            VirtqDebugState {
+                queue_idx: 0,
+                size: 4,
+                avail_idx: 1,
+                used_idx: 0,
+                last_used: 0,
+                num_free: 2,
+                inflight: vec![head],
+            }
+        );
+        assert_eq!(
+            q.ring_debug_dump(),
+            "queue 0: size 4 avail_idx 1 used_idx 0 last_used 0 free 2 inflight [0]"
+        );
+
+        complete(&q, 0, head, 16);
+        assert_eq!(q.debug_state().used_idx, 1);
+        assert_eq!(q.pop_used(), Some((head, 16)));
+        let state = q.debug_state();
+        assert_eq!(state.last_used, 1);
+        assert_eq!(state.num_free, 4);
+        assert!(state.inflight.is_empty());
+    }
+
+    #[test]
+    fn test_reset_last_used() {
+        let mut q = VirtqSplit::<MockHal>::new(0, 4, false).unwrap();
+        let req = [1u8; 16];
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&req);
+        let a = q.try_add(&sg).unwrap();
+        let b = q.try_add(&sg).unwrap();
+        complete(&q, 0, a, 0);
+        complete(&q, 1, b, 0);
+
+        assert_eq!(q.reset_last_used(), 2);
+        assert_eq!(q.debug_state().last_used, 2);
+        assert_eq!(q.pop_used(), None);
+        // 被跳过的请求的描述符没有被回收
+        assert_eq!(q.debug_state().inflight, vec![a, b]);
+        assert_eq!(q.reset_last_used(), 0);
+    }
+
+    #[test]
+    fn test_add_and_pop() {
+        let mut q = VirtqSplit::<MockHal>::new(1, 4, false).unwrap();
+        let req = [1u8; 40];
+        let mut reply = [0u8; 16];
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&req).push_writable(&mut reply);
+        let head = q.try_add(&sg).unwrap();
+        assert_eq!(head, 0);
+        assert_eq!(q.num_free(), 2);
+        assert_ne!(q.num_free(), q.size() as usize);
+
+        // 两个描述符相互链接，第二个设备可写
+        assert_eq!(read_desc(&q, 0), (req.as_ptr() as u64, 40, 1, 1));
+        assert_eq!(read_desc(&q, 1).1, 16);
+        assert_eq!(read_desc(&q, 1).2, 2);
+        // ring中的字段是小端的
+        let desc = q.ring.desc_vaddr().as_ptr();
+        assert_eq!(
+            unsafe { core::slice::from_raw_parts(desc.add(8), 4) },
+            [40, 0, 0, 0]
+        );
+        let avail = q.ring.avail_vaddr().as_ptr();
+        assert_eq!(
+            unsafe { core::slice::from_raw_parts(avail.add(2), 2) },
+            [1, 0]
+        );
+        assert!(q.should_notify());
+
+        assert_eq!(q.pop_used(), None);
+        complete(&q, 0, head, 16);
+        assert_eq!(q.pop_used(), Some((0, 16)));
+        assert_eq!(q.pop_used(), None);
+        assert_eq!(q.num_free(), 4);
+        assert_eq!(q.num_free(), q.size() as usize);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let mut q = VirtqSplit::<MockHal>::new(0, 2, false).unwrap();
+        let a = [0u8; 8];
+        let b = [0u8; 8];
+        let c = [0u8; 8];
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&a).push_readable(&b).push_readable(&c);
+        // 分段比整个队列还多，等待也没有用
+        assert_eq!(q.try_add(&sg), Err(SystemError::EINVAL));
+
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&a);
+        assert_eq!(q.try_add(&sg), Ok(0));
+        assert_eq!(q.try_add(&sg), Ok(1));
+        assert_eq!(q.try_add(&sg), Err(SystemError::EAGAIN_OR_EWOULDBLOCK));
+        // 请求完成之后描述符可以被再次使用
+        complete(&q, 0, 1, 0);
+        assert_eq!(q.pop_used(), Some((1, 0)));
+        assert_eq!(q.try_add(&sg), Ok(1));
+        assert_eq!(q.try_add(&sg), Err(SystemError::EAGAIN_OR_EWOULDBLOCK));
+    }
+
+    #[test]
+    fn test_reclaim_all_resets_counters() {
+        let mut q = VirtqSplit::<MockHal>::new(0, 4, false).unwrap();
+        let req = [1u8; 16];
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&req).push_readable(&req);
+        let head = q.try_add(&sg).unwrap();
+        complete(&q, 0, head, 0);
+        assert_eq!(q.pop_used(), Some((head, 0)));
+        // 归还的描述符排在后面
+        assert_eq!(q.try_add(&sg), Ok(2));
+        assert_eq!(q.num_free(), 2);
+
+        assert_eq!(q.reclaim_all(), vec![2]);
+        let state = q.debug_state();
+        assert_eq!((state.avail_idx, state.last_used), (0, 0));
+        assert_eq!(q.num_free(), 4);
+        assert_eq!(q.pop_used(), None);
+    }
+
+    #[test]
+    fn test_hot_state_cache_lines() {
+        let queues = [
+            VirtqSplit::<MockHal>::new(0, 4, false).unwrap(),
+            VirtqSplit::<MockHal>::new(1, 4, false).unwrap(),
+        ];
+        let line = |p: *const u8| p as usize / CACHE_LINE_SIZE;
+        for q in queues.iter() {
+            let avail = &*q.avail as *const VirtqAvailState as *const u8;
+            let used = &*q.used as *const VirtqUsedState as *const u8;
+            assert_eq!(avail as usize % CACHE_LINE_SIZE, 0);
+            assert_ne!(line(avail), line(used));
+        }
+        // 相邻的两个队列也不共享缓存行
+        let first_used = &*queues[0].used as *const VirtqUsedState as *const u8;
+        let second_avail = &*queues[1].avail as *const VirtqAvailState as *const u8;
+        assert_ne!(line(first_used), line(second_avail));
+    }
+}
@@ -0,0 +1,194 @@
+//! virtqueue已满时阻塞提交者的等待方式
+//!
+//! 提交者等待描述符被释放时可以：
+//! - 睡眠(`sleep`)：让出CPU，直到完成中断唤醒，CPU开销小，但唤醒有调度延迟
+//! - 先自旋(`spin <max_us>`)：在`max_us`微秒内忙等描述符被释放，超时之后再睡眠，延迟低但占用CPU
+//!
+//! 策略按设备设置，默认为睡眠，可以通过设备的`queue_full_policy`属性修改。
+//! 只影响等待空间的提交（例如[`crate::driver::base::block::io_request::BlockIoQueueFull::Wait`]），
+//! 不等待的提交仍然立即返回EAGAIN
+
+use core::{fmt::Display, str::FromStr};
+
+use system_error::SystemError;
+
+/// `spin`没有指定时间时自旋的微秒数
+pub const VIRTQ_FULL_SPIN_DEFAULT_US: u32 = 100;
+/// 允许设置的最长自旋时间(微秒)，避免提交者长时间占用CPU
+pub const VIRTQ_FULL_SPIN_MAX_US: u32 = 10_000;
+
+/// virtqueue已满时阻塞提交者的等待方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VirtQueueFullPolicy {
+    /// 先自旋最多`max_us`微秒，之后睡眠
+    Spin { max_us: u32 },
+    /// 直接睡眠，等待完成中断唤醒
+    #[default]
+    Sleep,
+}
+
+impl Display for VirtQueueFullPolicy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            VirtQueueFullPolicy::Spin { max_us } => write!(f, "spin {}", max_us),
+            VirtQueueFullPolicy::Sleep => write!(f, "sleep"),
+        }
+    }
+}
+
+impl FromStr for VirtQueueFullPolicy {
+    type Err = SystemError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let policy = match (words.next(), words.next()) {
+            (Some("sleep"), None) => VirtQueueFullPolicy::Sleep,
+            (Some("spin"), None) => VirtQueueFullPolicy::Spin {
+                max_us: VIRTQ_FULL_SPIN_DEFAULT_US,
+            },
+            (Some("spin"), Some(us)) => {
+                let max_us = us.parse::<u32>().map_err(|_| SystemError::EINVAL)?;
+                if max_us > VIRTQ_FULL_SPIN_MAX_US {
+                    return Err(SystemError::EINVAL);
+                }
+                VirtQueueFullPolicy::Spin { max_us }
+            }
+            _ => return Err(SystemError::EINVAL),
+        };
+        if words.next().is_some() {
+            return Err(SystemError::EINVAL);
+        }
+        Ok(policy)
+    }
+}
+
+/// # 结构功能
+/// 一次阻塞提交的等待状态，记录自旋开始的时间
+///
+/// 提交者每次发现virtqueue已满时调用[`Self::should_spin`]，返回false之后应当睡眠。
+/// 自旋时间用完之后，即使被唤醒后队列仍然是满的，也不会再次自旋
+#[derive(Debug)]
+pub struct VirtQueueFullWait {
+    policy: VirtQueueFullPolicy,
+    spin_start_us: Option<u64>,
+    exhausted: bool,
+}
+
+impl VirtQueueFullWait {
+    pub fn new(policy: VirtQueueFullPolicy) -> Self {
+        Self {
+            policy,
+            spin_start_us: None,
+            exhausted: false,
+        }
+    }
+
+    /// # 函数的功能
+    /// virtqueue已满时，判断提交者应当继续自旋还是睡眠
+    ///
+    /// ## 参数
+    /// - `now_us`: 当前时间(微秒)
+    ///
+    /// ## 返回值
+    /// - true: 仍在自旋时间内，提交者自旋之后重试
+    /// - false: 策略为睡眠，或者自旋时间已经用完
+    pub fn should_spin(&mut self, now_us: u64) -> bool {
+        let VirtQueueFullPolicy::Spin { max_us } = self.policy else {
+            return false;
+        };
+        if self.exhausted {
+            return false;
+        }
+        let start = *self.spin_start_us.get_or_insert(now_us);
+        if now_us.saturating_sub(start) < max_us as u64 {
+            return true;
+        }
+        self.exhausted = true;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(VirtQueueFullPolicy::default(), VirtQueueFullPolicy::Sleep);
+        assert_eq!("sleep\n".parse(), Ok(VirtQueueFullPolicy::Sleep));
+        assert_eq!(
+            "spin 20".parse(),
+            Ok(VirtQueueFullPolicy::Spin { max_us: 20 })
+        );
+        assert_eq!(
+            "spin".parse(),
+            Ok(VirtQueueFullPolicy::Spin {
+                max_us: VIRTQ_FULL_SPIN_DEFAULT_US
+            })
+        );
+        assert_eq!(
+            "spin 10001".parse::<VirtQueueFullPolicy>(),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            "spin 1 2".parse::<VirtQueueFullPolicy>(),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            "poll".parse::<VirtQueueFullPolicy>(),
+            Err(SystemError::EINVAL)
+        );
+
+        for policy in [
+            VirtQueueFullPolicy::Sleep,
+            VirtQueueFullPolicy::Spin { max_us: 7 },
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+    }
+
+    /// 模拟一直满的virtqueue：时钟每次重试前进`step_us`，返回睡眠之前自旋的总时间
+    fn spin_time_until_sleep(policy: VirtQueueFullPolicy, step_us: u64) -> (u64, usize) {
+        let mut wait = VirtQueueFullWait::new(policy);
+        let mut now = 1_000;
+        let start = now;
+        let mut spins = 0;
+        while wait.should_spin(now) {
+            spins += 1;
+            now += step_us;
+            assert!(spins < 1_000_000, "spin never stopped");
+        }
+        (now - start, spins)
+    }
+
+    #[test]
+    fn test_spin_respects_max_us() {
+        let (elapsed, spins) = spin_time_until_sleep(VirtQueueFullPolicy::Spin { max_us: 50 }, 10);
+        assert_eq!(spins, 5);
+        assert_eq!(elapsed, 50);
+
+        // 步长不能整除时，最后一次自旋开始时仍在时限内，睡眠不晚于一个步长
+        let (elapsed, spins) = spin_time_until_sleep(VirtQueueFullPolicy::Spin { max_us: 50 }, 7);
+        assert_eq!(spins, 8);
+        assert!((50..57).contains(&elapsed));
+
+        // 睡眠策略和0微秒的自旋都直接睡眠
+        assert_eq!(spin_time_until_sleep(VirtQueueFullPolicy::Sleep, 1), (0, 0));
+        assert_eq!(
+            spin_time_until_sleep(VirtQueueFullPolicy::Spin { max_us: 0 }, 1),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn test_no_spin_after_exhausted() {
+        let mut wait = VirtQueueFullWait::new(VirtQueueFullPolicy::Spin { max_us: 10 });
+        assert!(wait.should_spin(100));
+        assert!(wait.should_spin(109));
+        assert!(!wait.should_spin(110));
+        // 睡眠被唤醒之后队列仍然是满的，继续睡眠
+        assert!(!wait.should_spin(500));
+    }
+}
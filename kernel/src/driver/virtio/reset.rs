@@ -0,0 +1,405 @@
+//! virtio设备的复位流程
+//!
+//! 参考 virtio spec 2.1.1 Driver Requirements: Device Status Field 以及 4.1.4.3.2:
+//! 驱动向device status写0来复位设备，并且在重新初始化设备之前，
+//! 必须等待device status读回0，以确认设备已经完成复位。
+
+use core::hint::spin_loop;
+
+use system_error::SystemError;
+use virtio_drivers::transport::{DeviceStatus, Transport};
+
+use super::{queue::VirtqConfig, transport::VirtIOTransport};
+
+/// 等待设备完成复位时最多轮询的次数
+const VIRTIO_RESET_MAX_POLLS: usize = 1_000_000;
+
+/// 支持单独复位某一个virtqueue
+///
+/// 参考 virtio spec 6 Reserved Feature Bits
+pub const VIRTIO_F_RING_RESET: u64 = 1 << 40;
+
+/// # trait功能
+/// 访问设备状态寄存器的接口
+///
+/// 复位流程只需要读写device status，抽象出来之后可以在没有真实设备的情况下进行测试
+pub trait VirtIOStatusRegister {
+    fn read_status(&self) -> DeviceStatus;
+
+    fn write_status(&mut self, status: DeviceStatus);
+}
+
+impl VirtIOStatusRegister for VirtIOTransport {
+    fn read_status(&self) -> DeviceStatus {
+        self.get_status()
+    }
+
+    fn write_status(&mut self, status: DeviceStatus) {
+        self.set_status(status)
+    }
+}
+
+/// # trait功能
+/// 单独复位virtqueue所需访问的寄存器（virtio-pci common config中的queue_reset等字段）
+pub trait VirtIOQueueResetRegister {
+    /// 驱动与设备协商得到的特性
+    fn negotiated_features(&mut self) -> u64;
+
+    /// 设备是否提供了queue_reset寄存器
+    fn has_queue_reset(&self) -> bool;
+
+    fn select_queue(&mut self, queue: u16);
+
+    /// 读取当前选中队列的queue_reset
+    fn read_queue_reset(&self) -> u16;
+
+    fn write_queue_reset(&mut self, value: u16);
+
+    /// 读取当前选中队列的queue_enable
+    fn read_queue_enable(&self) -> u16;
+
+    fn write_queue_enable(&mut self, value: u16);
+
+    /// 选中队列`queue`，写入它的queue_size以及queue_desc、queue_driver、queue_device
+    fn write_queue_config(&mut self, queue: u16, config: &VirtqConfig);
+}
+
+/// # 函数的功能
+/// 在不复位整个设备的前提下，复位单个virtqueue
+///
+/// 参考 virtio spec 2.6.1 Virtqueue Reset：
+/// 1. 向queue_reset写1，并等待其读回1，此时设备已经停止使用该队列
+/// 2. 回收队列中的缓冲区（由`reclaim`完成），设备此时已经清空了队列的配置
+/// 3. 重新写入队列的大小和三个区域的地址，然后向queue_enable写1，重新启用队列
+///
+/// 其他队列在整个过程中保持可用
+///
+/// ## 参数
+/// - `dev`: 设备的寄存器
+/// - `queue`: 要复位的队列号
+/// - `reclaim`: 队列停止后调用，释放队列中的缓冲区，并返回重新启用队列时使用的配置，
+///   一般为[`super::queue::VirtqSplit::reclaim_all`]之后的[`super::queue::VirtqSplit::config`]
+///
+/// ## 返回值
+/// - Ok(()): 队列已经被复位并重新启用
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商VIRTIO_F_RING_RESET特性
+/// - Err(SystemError::ETIMEDOUT): 设备在规定时间内没有完成队列复位
+pub fn virtio_reset_queue(
+    dev: &mut dyn VirtIOQueueResetRegister,
+    queue: u16,
+    reclaim: &mut dyn FnMut() -> VirtqConfig,
+) -> Result<(), SystemError> {
+    if dev.negotiated_features() & VIRTIO_F_RING_RESET == 0 || !dev.has_queue_reset() {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+
+    dev.select_queue(queue);
+    dev.write_queue_reset(1);
+    let mut done = false;
+    for _ in 0..VIRTIO_RESET_MAX_POLLS {
+        if dev.read_queue_reset() == 1 {
+            done = true;
+            break;
+        }
+        spin_loop();
+    }
+    if !done {
+        return Err(SystemError::ETIMEDOUT);
+    }
+
+    let config = reclaim();
+
+    // reclaim可能访问了其他队列，这里需要重新选择
+    dev.write_queue_config(queue, &config);
+    dev.write_queue_enable(1);
+    if dev.read_queue_enable() != 1 {
+        return Err(SystemError::EIO);
+    }
+    Ok(())
+}
+
+/// # 函数的功能
+/// 按照virtio规范复位设备：向device status写0，然后等待其读回0
+///
+/// 复位完成后设备不会再访问virtqueue，调用者可以安全地释放virtqueue的内存
+///
+/// ## 返回值
+/// - Ok(()): 复位完成
+/// - Err(SystemError::ETIMEDOUT): 设备在规定时间内没有完成复位
+pub fn virtio_reset_device(dev: &mut dyn VirtIOStatusRegister) -> Result<(), SystemError> {
+    dev.write_status(DeviceStatus::empty());
+    for _ in 0..VIRTIO_RESET_MAX_POLLS {
+        if dev.read_status().is_empty() {
+            return Ok(());
+        }
+        spin_loop();
+    }
+    Err(SystemError::ETIMEDOUT)
+}
+
+/// 设备是否已经完成初始化（处于DRIVER_OK状态，且没有置位FAILED或DEVICE_NEEDS_RESET）
+pub fn virtio_status_driver_ok(dev: &dyn VirtIOStatusRegister) -> bool {
+    let status = dev.read_status();
+    status.contains(DeviceStatus::DRIVER_OK)
+        && !status.intersects(DeviceStatus::FAILED | DeviceStatus::DEVICE_NEEDS_RESET)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use core::cell::Cell;
+
+    use crate::driver::virtio::{
+        mock::{MockHal, MockVirtqDevice},
+        queue::VirtqSplit,
+        sg::VirtioSgList,
+    };
+
+    use super::*;
+
+    /// 模拟设备的状态寄存器：写0之后，需要再读若干次才会真正变为0
+    struct MockStatus {
+        status: Cell<DeviceStatus>,
+        reset_delay: usize,
+        /// 尚未完成的复位还需要被读取的次数
+        pending_reset: Cell<Option<usize>>,
+        /// 为true时设备永远不会完成复位
+        stuck: bool,
+    }
+
+    impl MockStatus {
+        fn new(reset_delay: usize) -> Self {
+            Self {
+                status: Cell::new(DeviceStatus::empty()),
+                reset_delay,
+                pending_reset: Cell::new(None),
+                stuck: false,
+            }
+        }
+    }
+
+    impl VirtIOStatusRegister for MockStatus {
+        fn read_status(&self) -> DeviceStatus {
+            match self.pending_reset.get() {
+                Some(0) => {
+                    self.status.set(DeviceStatus::empty());
+                    self.pending_reset.set(None);
+                }
+                Some(n) => self.pending_reset.set(Some(n - 1)),
+                None => {}
+            }
+            self.status.get()
+        }
+
+        fn write_status(&mut self, status: DeviceStatus) {
+            if !status.is_empty() {
+                self.status.set(status);
+            } else if !self.stuck {
+                self.pending_reset.set(Some(self.reset_delay));
+            }
+        }
+    }
+
+    fn init(dev: &mut MockStatus) {
+        for s in [
+            DeviceStatus::ACKNOWLEDGE,
+            DeviceStatus::DRIVER,
+            DeviceStatus::FEATURES_OK,
+            DeviceStatus::DRIVER_OK,
+        ] {
+            let cur = dev.read_status();
+            dev.write_status(cur | s);
+        }
+    }
+
+    #[test]
+    fn test_reset_cycle() {
+        let mut dev = MockStatus::new(3);
+        init(&mut dev);
+        assert!(virtio_status_driver_ok(&dev));
+
+        assert_eq!(virtio_reset_device(&mut dev), Ok(()));
+        assert!(dev.read_status().is_empty());
+        assert!(!virtio_status_driver_ok(&dev));
+
+        // 复位之后可以重新初始化
+        init(&mut dev);
+        assert!(virtio_status_driver_ok(&dev));
+    }
+
+    #[test]
+    fn test_reset_timeout() {
+        let mut dev = MockStatus::new(0);
+        init(&mut dev);
+        dev.stuck = true;
+        assert_eq!(virtio_reset_device(&mut dev), Err(SystemError::ETIMEDOUT));
+        assert!(virtio_status_driver_ok(&dev));
+    }
+
+    #[test]
+    fn test_needs_reset_is_not_ok() {
+        let mut dev = MockStatus::new(0);
+        init(&mut dev);
+        let cur = dev.read_status();
+        dev.write_status(cur | DeviceStatus::DEVICE_NEEDS_RESET);
+        assert!(!virtio_status_driver_ok(&dev));
+    }
+
+    /// 模拟带有多个队列的设备，记录每个队列被写入的次数
+    struct MockQueues {
+        features: u64,
+        selected: usize,
+        reset: [u16; 3],
+        enable: [u16; 3],
+        /// 每个队列的大小和地址，复位队列时被清空
+        config: [Option<VirtqConfig>; 3],
+        writes: [usize; 3],
+    }
+
+    impl MockQueues {
+        fn new(features: u64) -> Self {
+            Self {
+                features,
+                selected: 0,
+                reset: [0; 3],
+                enable: [1; 3],
+                config: [None; 3],
+                writes: [0; 3],
+            }
+        }
+    }
+
+    impl VirtIOQueueResetRegister for MockQueues {
+        fn negotiated_features(&mut self) -> u64 {
+            self.features
+        }
+
+        fn has_queue_reset(&self) -> bool {
+            true
+        }
+
+        fn select_queue(&mut self, queue: u16) {
+            self.selected = queue as usize;
+        }
+
+        fn read_queue_reset(&self) -> u16 {
+            self.reset[self.selected]
+        }
+
+        fn write_queue_reset(&mut self, value: u16) {
+            self.writes[self.selected] += 1;
+            if value == 1 {
+                self.reset[self.selected] = 1;
+                self.enable[self.selected] = 0;
+                self.config[self.selected] = None;
+            }
+        }
+
+        fn read_queue_enable(&self) -> u16 {
+            self.enable[self.selected]
+        }
+
+        fn write_queue_enable(&mut self, value: u16) {
+            self.writes[self.selected] += 1;
+            if value == 1 {
+                self.enable[self.selected] = 1;
+                self.reset[self.selected] = 0;
+            }
+        }
+
+        fn write_queue_config(&mut self, queue: u16, config: &VirtqConfig) {
+            self.select_queue(queue);
+            self.writes[self.selected] += 1;
+            self.config[self.selected] = Some(*config);
+        }
+    }
+
+    const CONFIG: VirtqConfig = VirtqConfig {
+        size: 8,
+        desc: 0x1000,
+        driver: 0x1080,
+        device: 0x2000,
+    };
+
+    #[test]
+    fn test_reset_one_queue() {
+        let mut dev = MockQueues::new(VIRTIO_F_RING_RESET);
+        let mut reclaimed = false;
+        let r = virtio_reset_queue(&mut dev, 1, &mut || {
+            reclaimed = true;
+            CONFIG
+        });
+        assert_eq!(r, Ok(()));
+        assert!(reclaimed);
+
+        // 被复位的队列重新设置并启用，其他队列没有被触碰
+        assert_eq!(dev.enable, [1, 1, 1]);
+        assert_eq!(dev.reset, [0, 0, 0]);
+        assert_eq!(dev.config, [None, Some(CONFIG), None]);
+        assert_eq!(dev.writes, [0, 3, 0]);
+    }
+
+    #[test]
+    fn test_reset_queue_keeps_others_live() {
+        let mut queues: Vec<VirtqSplit<MockHal>> = (0..3)
+            .map(|i| VirtqSplit::new(i, 4, false).unwrap())
+            .collect();
+        let mut devs: Vec<MockVirtqDevice> = (0..3).map(|_| MockVirtqDevice::new()).collect();
+        let mut dev = MockQueues::new(VIRTIO_F_RING_RESET);
+        for (i, q) in queues.iter().enumerate() {
+            dev.config[i] = Some(q.config());
+        }
+
+        // 每个队列中都有一个设备正在处理的请求
+        let mut bufs = [[0u8; 8]; 3];
+        let mut heads = Vec::new();
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            let mut sg = VirtioSgList::new();
+            sg.push_writable(buf);
+            heads.push(queues[i].try_add(&sg).unwrap());
+            assert!(devs[i].pop_avail(&queues[i]).is_some());
+        }
+
+        let q1 = &mut queues[1];
+        let mut dropped = Vec::new();
+        let r = virtio_reset_queue(&mut dev, 1, &mut || {
+            dropped = q1.reclaim_all();
+            q1.config()
+        });
+        assert_eq!(r, Ok(()));
+        assert_eq!(dropped, vec![heads[1]]);
+        assert_eq!(dev.config[1], Some(queues[1].config()));
+        assert_eq!(dev.enable, [1, 1, 1]);
+        assert_eq!(dev.writes, [0, 3, 0]);
+
+        // 其他队列中的请求照常完成
+        for i in [0, 2] {
+            devs[i].push_used(&queues[i], heads[i], 8);
+            assert_eq!(queues[i].pop_used(), Some((heads[i], 8)));
+        }
+
+        // 复位之后的队列回到初始状态，设备也从头开始读取available ring
+        assert_eq!(queues[1].num_free(), 4);
+        assert_eq!(queues[1].pop_used(), None);
+        let mut dev1 = MockVirtqDevice::new();
+        let mut sg = VirtioSgList::new();
+        sg.push_writable(&mut bufs[1]);
+        let head = queues[1].try_add(&sg).unwrap();
+        assert_eq!(dev1.pop_avail(&queues[1]).map(|(h, _)| h), Some(head));
+        dev1.push_used(&queues[1], head, 1);
+        assert_eq!(queues[1].pop_used(), Some((head, 1)));
+    }
+
+    #[test]
+    fn test_reset_queue_without_feature() {
+        let mut dev = MockQueues::new(0);
+        let mut reclaimed = false;
+        let r = virtio_reset_queue(&mut dev, 0, &mut || {
+            reclaimed = true;
+            CONFIG
+        });
+        assert_eq!(r, Err(SystemError::EOPNOTSUPP_OR_ENOTSUP));
+        assert!(!reclaimed);
+        assert_eq!(dev.writes, [0, 0, 0]);
+    }
+}
@@ -0,0 +1,446 @@
+//! split virtqueue的内存布局以及内存屏障
+//!
+//! 参考 virtio spec 2.7 Split Virtqueues：
+//! - 描述符表需要16字节对齐
+//! - available ring需要2字节对齐
+//! - used ring需要4字节对齐（legacy接口要求按页对齐）
+//!
+//! 驱动与设备通过共享内存通信，因此对ring的修改需要配合内存屏障：
+//! - 驱动在更新available ring的idx之前，需要调用[`virtio_wmb`]，
+//!   保证描述符以及available ring中的表项先于idx对设备可见
+//! - 驱动在读取used ring的idx之后，需要调用[`virtio_rmb`]，
+//!   保证之后读取到的used ring表项不早于idx
+//! - 驱动在通知设备（写notify寄存器）之前，以及在读取设备的通知抑制标志之前，需要调用[`virtio_mb`]
+
+use core::{
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::atomic::{fence, Ordering},
+};
+
+use log::error;
+use system_error::SystemError;
+use virtio_drivers::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
+
+/// 描述符表的对齐要求
+pub const VIRTQ_DESC_ALIGN: usize = 16;
+/// available ring的对齐要求
+pub const VIRTQ_AVAIL_ALIGN: usize = 2;
+/// used ring的对齐要求
+pub const VIRTQ_USED_ALIGN: usize = 4;
+/// legacy接口中used ring的对齐要求
+pub const VIRTQ_LEGACY_USED_ALIGN: usize = 4096;
+/// 队列大小的上限
+pub const VIRTQ_MAX_SIZE: u16 = 32768;
+
+/// 描述符表中一项的大小
+const VIRTQ_DESC_SIZE: usize = 16;
+
+/// 写屏障：更新available ring的idx之前调用
+#[inline(always)]
+pub fn virtio_wmb() {
+    fence(Ordering::Release);
+}
+
+/// 读屏障：读取used ring的idx之后、读取used ring表项之前调用
+#[inline(always)]
+pub fn virtio_rmb() {
+    fence(Ordering::Acquire);
+}
+
+/// 全屏障：通知设备之前、读取设备的通知抑制标志之前调用
+#[inline(always)]
+pub fn virtio_mb() {
+    fence(Ordering::SeqCst);
+}
+
+#[inline(always)]
+const fn used_align(legacy: bool) -> usize {
+    if legacy {
+        VIRTQ_LEGACY_USED_ALIGN
+    } else {
+        VIRTQ_USED_ALIGN
+    }
+}
+
+#[inline(always)]
+const fn align_up(x: usize, align: usize) -> usize {
+    (x + align - 1) & !(align - 1)
+}
+
+/// # 结构功能
+/// 驱动对队列大小的要求
+///
+/// 实际使用的队列大小为min(preferred, cap, 设备支持的最大值)向下取整到2的幂，
+/// 因此设备支持大队列时使用驱动希望的大小，只支持小队列的设备也能正常工作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtQueueSizePolicy {
+    /// 驱动希望使用的队列大小
+    pub preferred: u16,
+    /// 驱动能够使用的最大队列大小
+    pub cap: u16,
+}
+
+impl VirtQueueSizePolicy {
+    pub const fn new(preferred: u16, cap: u16) -> Self {
+        Self { preferred, cap }
+    }
+
+    /// # 函数的功能
+    /// 根据设备支持的最大队列大小(queue_num_max / queue_size)，选择实际使用的队列大小
+    ///
+    /// ## 参数
+    /// - `device_max`: 设备支持的最大队列大小
+    ///
+    /// ## 返回值
+    /// - Ok(size): 实际使用的队列大小，是2的幂
+    /// - Err(SystemError::ENOENT): 设备的最大队列大小为0，即该队列不可用
+    /// - Err(SystemError::EINVAL): 设备的最大队列大小不是2的幂，或者驱动要求的队列大小为0
+    pub fn negotiate(&self, device_max: u32) -> Result<u16, SystemError> {
+        if device_max == 0 {
+            return Err(SystemError::ENOENT);
+        }
+        if !device_max.is_power_of_two() {
+            return Err(SystemError::EINVAL);
+        }
+        let requested = self.preferred.min(self.cap).min(VIRTQ_MAX_SIZE);
+        if requested == 0 {
+            return Err(SystemError::EINVAL);
+        }
+
+        let size = (requested as u32).min(device_max);
+        Ok((1u32 << (u32::BITS - 1 - size.leading_zeros())) as u16)
+    }
+}
+
+/// # 结构功能
+/// 一个split virtqueue中三个区域在同一块内存中的布局
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtqRingLayout {
+    pub queue_size: u16,
+    /// legacy接口的布局（used ring按页对齐）
+    pub legacy: bool,
+    pub desc_offset: usize,
+    pub desc_size: usize,
+    pub avail_offset: usize,
+    pub avail_size: usize,
+    pub used_offset: usize,
+    pub used_size: usize,
+    /// 整个区域的大小
+    pub total_size: usize,
+}
+
+impl VirtqRingLayout {
+    /// # 函数的功能
+    /// 计算队列的内存布局
+    ///
+    /// ## 参数
+    /// - `queue_size`: 队列大小，必须是2的幂
+    /// - `legacy`: 是否为legacy接口（used ring需要按页对齐）
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): 队列大小为0、超过上限或者不是2的幂
+    pub fn new(queue_size: u16, legacy: bool) -> Result<Self, SystemError> {
+        if queue_size == 0 || queue_size > VIRTQ_MAX_SIZE || !queue_size.is_power_of_two() {
+            return Err(SystemError::EINVAL);
+        }
+        let n = queue_size as usize;
+
+        let desc_offset = 0;
+        let desc_size = VIRTQ_DESC_SIZE * n;
+        // flags + idx + ring[n] + used_event
+        let avail_offset = align_up(desc_offset + desc_size, VIRTQ_AVAIL_ALIGN);
+        let avail_size = 2 * (3 + n);
+        // flags + idx + ring[n](id: u32, len: u32) + avail_event
+        let used_offset = align_up(avail_offset + avail_size, used_align(legacy));
+        let used_size = 2 * 3 + 8 * n;
+
+        Ok(Self {
+            queue_size,
+            legacy,
+            desc_offset,
+            desc_size,
+            avail_offset,
+            avail_size,
+            used_offset,
+            used_size,
+            total_size: used_offset + used_size,
+        })
+    }
+
+    /// # 函数的功能
+    /// 检查三个区域的起始地址是否满足对齐要求
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EFAULT): 存在不满足对齐要求的区域
+    pub fn check_alignment(&self, base: usize) -> Result<(), SystemError> {
+        let ok = (base + self.desc_offset) % VIRTQ_DESC_ALIGN == 0
+            && (base + self.avail_offset) % VIRTQ_AVAIL_ALIGN == 0
+            && (base + self.used_offset) % used_align(self.legacy) == 0;
+        if ok {
+            Ok(())
+        } else {
+            Err(SystemError::EFAULT)
+        }
+    }
+}
+
+/// # 结构功能
+/// 通过Hal分配的virtqueue内存，分配时已经清零并检查过对齐
+///
+/// 被drop时释放内存，调用者需要保证此时设备已经不再使用该队列（例如已经复位了设备或队列）
+pub struct VirtqRingMemory<H: Hal> {
+    layout: VirtqRingLayout,
+    paddr: PhysAddr,
+    vaddr: NonNull<u8>,
+    pages: usize,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> VirtqRingMemory<H> {
+    /// # 函数的功能
+    /// 为队列分配内存
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): 队列大小不合法
+    /// - Err(SystemError::ENOMEM): 分配内存失败
+    /// - Err(SystemError::EFAULT): 分配得到的内存不满足对齐要求
+    pub fn alloc(queue_size: u16, legacy: bool) -> Result<Self, SystemError> {
+        let layout = VirtqRingLayout::new(queue_size, legacy)?;
+        let pages = layout.total_size.div_ceil(PAGE_SIZE);
+        let (paddr, vaddr) = H::dma_alloc(pages, BufferDirection::Both);
+        // 与virtio-drivers的约定一致，物理地址为0表示分配失败，此时没有需要释放的内存
+        if paddr == 0 {
+            return Err(SystemError::ENOMEM);
+        }
+
+        // 构造之后，出错返回时也会通过drop释放内存
+        let mut mem = Self {
+            layout,
+            paddr,
+            vaddr,
+            pages,
+            _hal: PhantomData,
+        };
+        // 设备使用物理地址访问，驱动使用虚拟地址访问，两者都需要满足对齐要求
+        if let Err(e) = layout
+            .check_alignment(paddr)
+            .and_then(|_| layout.check_alignment(vaddr.as_ptr() as usize))
+        {
+            error!(
+                "virtio ring: misaligned queue memory, paddr: {:#x}, vaddr: {:p}",
+                paddr, vaddr
+            );
+            return Err(e);
+        }
+
+        mem.clear();
+        Ok(mem)
+    }
+
+    /// 清零队列的内存，调用者需要保证设备此时没有在使用该队列
+    pub fn clear(&mut self) {
+        unsafe { core::ptr::write_bytes(self.vaddr.as_ptr(), 0, self.pages * PAGE_SIZE) };
+    }
+
+    pub fn layout(&self) -> &VirtqRingLayout {
+        &self.layout
+    }
+
+    pub fn desc_paddr(&self) -> PhysAddr {
+        self.paddr + self.layout.desc_offset
+    }
+
+    pub fn avail_paddr(&self) -> PhysAddr {
+        self.paddr + self.layout.avail_offset
+    }
+
+    pub fn used_paddr(&self) -> PhysAddr {
+        self.paddr + self.layout.used_offset
+    }
+
+    pub fn desc_vaddr(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.vaddr.as_ptr().add(self.layout.desc_offset)) }
+    }
+
+    pub fn avail_vaddr(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.vaddr.as_ptr().add(self.layout.avail_offset)) }
+    }
+
+    pub fn used_vaddr(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.vaddr.as_ptr().add(self.layout.used_offset)) }
+    }
+}
+
+impl<H: Hal> Drop for VirtqRingMemory<H> {
+    fn drop(&mut self) {
+        unsafe {
+            H::dma_dealloc(self.paddr, self.vaddr, self.pages);
+        }
+    }
+}
+
+impl<H: Hal> core::fmt::Debug for VirtqRingMemory<H> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VirtqRingMemory")
+            .field("layout", &self.layout)
+            .field("paddr", &self.paddr)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::driver::virtio::mock::MockHal;
+
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let l = VirtqRingLayout::new(256, false).unwrap();
+        assert_eq!(l.desc_offset, 0);
+        assert_eq!(l.desc_size, 4096);
+        assert_eq!(l.avail_offset, 4096);
+        assert_eq!(l.avail_size, 518);
+        assert_eq!(l.used_offset, 4616);
+        assert_eq!(l.used_size, 2054);
+
+        let legacy = VirtqRingLayout::new(256, true).unwrap();
+        assert_eq!(legacy.used_offset, 8192);
+        assert_eq!(legacy.total_size, 8192 + 2054);
+    }
+
+    #[test]
+    fn test_invalid_queue_size() {
+        assert_eq!(VirtqRingLayout::new(0, false), Err(SystemError::EINVAL));
+        assert_eq!(VirtqRingLayout::new(3, false), Err(SystemError::EINVAL));
+    }
+
+    #[test]
+    fn test_queue_size_negotiate() {
+        let policy = VirtQueueSizePolicy::new(256, 1024);
+        // (设备支持的最大值, 实际使用的大小)
+        let cases = [
+            (1, 1),
+            (2, 2),
+            (64, 64),
+            (256, 256),
+            (1024, 256),
+            (32768, 256),
+        ];
+        for (device_max, size) in cases {
+            assert_eq!(policy.negotiate(device_max), Ok(size), "max {}", device_max);
+        }
+        // 设备支持的最大值超过了split virtqueue的上限
+        assert_eq!(policy.negotiate(1 << 20), Ok(256));
+        assert_eq!(policy.negotiate(0), Err(SystemError::ENOENT));
+        assert_eq!(policy.negotiate(3), Err(SystemError::EINVAL));
+        assert_eq!(policy.negotiate(1000), Err(SystemError::EINVAL));
+    }
+
+    #[test]
+    fn test_queue_size_policy_cap() {
+        // 驱动希望的大小超过上限时使用上限，上限不是2的幂时向下取整
+        assert_eq!(VirtQueueSizePolicy::new(512, 128).negotiate(4096), Ok(128));
+        assert_eq!(VirtQueueSizePolicy::new(512, 100).negotiate(4096), Ok(64));
+        assert_eq!(
+            VirtQueueSizePolicy::new(u16::MAX, u16::MAX).negotiate(1 << 16),
+            Ok(VIRTQ_MAX_SIZE)
+        );
+        assert_eq!(
+            VirtQueueSizePolicy::new(0, 16).negotiate(16),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            VirtQueueSizePolicy::new(16, 0).negotiate(16),
+            Err(SystemError::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_check_alignment() {
+        let l = VirtqRingLayout::new(8, false).unwrap();
+        assert_eq!(l.check_alignment(0x1000), Ok(()));
+        assert_eq!(l.check_alignment(0x1008), Err(SystemError::EFAULT));
+
+        let legacy = VirtqRingLayout::new(8, true).unwrap();
+        assert_eq!(legacy.check_alignment(0x1010), Err(SystemError::EFAULT));
+    }
+
+    #[test]
+    fn test_alloc_alignment() {
+        for (size, legacy) in [
+            (1, false),
+            (16, false),
+            (256, false),
+            (256, true),
+            (1024, true),
+        ] {
+            let mem = VirtqRingMemory::<MockHal>::alloc(size, legacy).unwrap();
+            assert_eq!(mem.desc_paddr() % VIRTQ_DESC_ALIGN, 0);
+            assert_eq!(mem.avail_paddr() % VIRTQ_AVAIL_ALIGN, 0);
+            assert_eq!(mem.used_paddr() % used_align(legacy), 0);
+            assert_eq!(mem.desc_vaddr().as_ptr() as usize % VIRTQ_DESC_ALIGN, 0);
+            assert_eq!(mem.used_vaddr().as_ptr() as usize % used_align(legacy), 0);
+
+            let total = mem.layout().total_size;
+            let bytes = unsafe { core::slice::from_raw_parts(mem.desc_vaddr().as_ptr(), total) };
+            assert!(bytes.iter().all(|b| *b == 0));
+        }
+    }
+
+    /// 模拟内存不足：在第`FAIL_AT`次分配时失败，并记录尚未释放的分配
+    struct FailingHal;
+
+    static ALLOCS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+    static LIVE: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+    const FAIL_AT: usize = 2;
+
+    unsafe impl Hal for FailingHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            if ALLOCS.fetch_add(1, Ordering::SeqCst) == FAIL_AT {
+                return (0, NonNull::dangling());
+            }
+            LIVE.fetch_add(1, Ordering::SeqCst);
+            MockHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            assert_ne!(paddr, 0, "freeing a failed allocation");
+            LIVE.fetch_sub(1, Ordering::SeqCst);
+            MockHal::dma_dealloc(paddr, vaddr, pages)
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            MockHal::mmio_phys_to_virt(paddr, size)
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            MockHal::share(buffer, direction)
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            MockHal::unshare(paddr, buffer, direction)
+        }
+    }
+
+    #[test]
+    fn test_alloc_failure() {
+        // 依次为4个队列分配内存，第3个队列分配失败时，之前的队列随着初始化失败被释放
+        let setup = || -> Result<Vec<VirtqRingMemory<FailingHal>>, SystemError> {
+            (0..4)
+                .map(|_| VirtqRingMemory::<FailingHal>::alloc(256, false))
+                .collect()
+        };
+        assert_eq!(setup().err(), Some(SystemError::ENOMEM));
+        assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+
+        // 之后的分配不受影响
+        let rings = setup().unwrap();
+        assert_eq!(LIVE.load(Ordering::SeqCst), 4);
+        drop(rings);
+        assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+    }
+}
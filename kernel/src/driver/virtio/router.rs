@@ -0,0 +1,137 @@
+//! virtio设备类型到驱动初始化函数的路由
+//!
+//! 每个virtio驱动在初始化时通过[`virtio_device_router`]为它支持的设备类型注册初始化函数，
+//! 探测到设备之后由[`super::virtio::virtio_device_init`]按照设备类型找到对应的函数。
+//! 新增一种设备驱动只需要在驱动自己的初始化函数中注册，不需要修改探测的代码。
+
+use alloc::sync::Arc;
+use hashbrown::HashMap;
+use system_error::SystemError;
+
+use crate::{
+    driver::base::device::{Device, DeviceId},
+    libs::rwlock::RwLock,
+};
+
+use super::{transport::VirtIOTransport, VirtioDeviceType};
+
+/// virtio设备的初始化函数
+pub type VirtioDeviceInitFn = fn(
+    VirtIOTransport,
+    Arc<DeviceId>,
+    Option<Arc<dyn Device>>,
+) -> Result<Arc<dyn Device>, SystemError>;
+
+lazy_static! {
+    static ref VIRTIO_DEVICE_ROUTER: VirtIODeviceRouter<VirtioDeviceInitFn> =
+        VirtIODeviceRouter::new();
+}
+
+#[inline(always)]
+pub fn virtio_device_router() -> &'static VirtIODeviceRouter<VirtioDeviceInitFn> {
+    &VIRTIO_DEVICE_ROUTER
+}
+
+/// # 结构功能
+/// virtio设备类型到处理函数的映射，每种设备类型最多有一个处理函数
+#[derive(Debug)]
+pub struct VirtIODeviceRouter<F: Copy> {
+    handlers: RwLock<HashMap<VirtioDeviceType, F>>,
+}
+
+impl<F: Copy> VirtIODeviceRouter<F> {
+    pub fn new() -> Self {
+        Self {
+            handlers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// # 函数的功能
+    /// 为`device_type`注册处理函数
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EEXIST): 该设备类型已经有处理函数
+    pub fn register(&self, device_type: VirtioDeviceType, handler: F) -> Result<(), SystemError> {
+        let mut handlers = self.handlers.write();
+        if handlers.contains_key(&device_type) {
+            return Err(SystemError::EEXIST);
+        }
+        handlers.insert(device_type, handler);
+        Ok(())
+    }
+
+    /// 删除`device_type`的处理函数，之后探测到的该类型设备不会再被初始化
+    #[allow(dead_code)]
+    pub fn unregister(&self, device_type: VirtioDeviceType) -> Option<F> {
+        self.handlers.write().remove(&device_type)
+    }
+
+    /// # 函数的功能
+    /// 查找`device_type`的处理函数
+    ///
+    /// ## 返回值
+    /// - Ok(handler): 处理函数
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 已识别该设备类型，但是没有驱动注册
+    /// - Err(SystemError::ENODEV): 无法识别的设备类型
+    pub fn route(&self, device_type: VirtioDeviceType) -> Result<F, SystemError> {
+        if let Some(handler) = self.handlers.read().get(&device_type) {
+            return Ok(*handler);
+        }
+        match device_type {
+            VirtioDeviceType::Unknown(_) => Err(SystemError::ENODEV),
+            _ => Err(SystemError::EOPNOTSUPP_OR_ENOTSUP),
+        }
+    }
+}
+
+impl<F: Copy> Default for VirtIODeviceRouter<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn double(x: u32) -> u32 {
+        x * 2
+    }
+
+    fn square(x: u32) -> u32 {
+        x * x
+    }
+
+    #[test]
+    fn test_route_custom_type() {
+        let router: VirtIODeviceRouter<fn(u32) -> u32> = VirtIODeviceRouter::new();
+        let custom = VirtioDeviceType::Unknown(0x7f);
+        assert_eq!(router.route(custom).err(), Some(SystemError::ENODEV));
+
+        router.register(custom, double).unwrap();
+        router.register(VirtioDeviceType::Block, square).unwrap();
+        assert_eq!(router.route(custom).map(|f| f(21)), Ok(42));
+        assert_eq!(router.route(VirtioDeviceType::Block).map(|f| f(5)), Ok(25));
+        // 其他未知的类型不受影响
+        assert_eq!(
+            router.route(VirtioDeviceType::Unknown(0x7e)).err(),
+            Some(SystemError::ENODEV)
+        );
+
+        // 每种类型只能有一个处理函数
+        assert_eq!(router.register(custom, square), Err(SystemError::EEXIST));
+        assert_eq!(router.route(custom).map(|f| f(3)), Ok(6));
+
+        assert!(router.unregister(custom).is_some());
+        assert_eq!(router.route(custom).err(), Some(SystemError::ENODEV));
+    }
+
+    #[test]
+    fn test_route_known_type_without_driver() {
+        let router: VirtIODeviceRouter<fn(u32) -> u32> = VirtIODeviceRouter::default();
+        assert_eq!(
+            router.route(VirtioDeviceType::Gpu).err(),
+            Some(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
+    }
+}
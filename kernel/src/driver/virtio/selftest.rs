@@ -0,0 +1,143 @@
+//! virtio设备的自检
+//!
+//! 向设备的`selftest`属性写入1时运行驱动实现的[`VirtIODevice::selftest`]，
+//! 结果记录在这里，通过`selftest_result`属性读取。自检不能破坏设备上的数据以及设备的状态。
+
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc};
+use system_error::SystemError;
+
+use crate::libs::spinlock::SpinLock;
+
+use super::{VirtIODevice, VirtIODeviceIndex};
+
+static VIRTIO_SELFTEST_MANAGER: VirtIOSelftestManager = VirtIOSelftestManager::new();
+
+#[inline(always)]
+pub fn virtio_selftest_manager() -> &'static VirtIOSelftestManager {
+    &VIRTIO_SELFTEST_MANAGER
+}
+
+/// 设备最近一次自检的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VirtIOSelftestResult {
+    /// 还没有运行过自检
+    NotRun,
+    /// 自检正在运行
+    Running,
+    /// 驱动不支持自检
+    Unsupported,
+    Pass,
+    Fail(SystemError),
+}
+
+impl VirtIOSelftestResult {
+    /// # 函数的功能
+    /// 把结果格式化为`selftest_result`属性的内容
+    ///
+    /// 失败时附带错误码，例如`fail: EIO (-5)`
+    pub fn describe(&self) -> String {
+        match self {
+            VirtIOSelftestResult::NotRun => String::from("not run\n"),
+            VirtIOSelftestResult::Running => String::from("running\n"),
+            VirtIOSelftestResult::Unsupported => String::from("unsupported\n"),
+            VirtIOSelftestResult::Pass => String::from("pass\n"),
+            VirtIOSelftestResult::Fail(e) => format!("fail: {:?} ({})\n", e, e.to_posix_errno()),
+        }
+    }
+}
+
+/// # 函数的功能
+/// 解析写入`selftest`属性的内容，只接受"1"
+pub fn virtio_selftest_parse(buf: &[u8]) -> Result<(), SystemError> {
+    let s = core::str::from_utf8(buf).map_err(|_| SystemError::EINVAL)?;
+    match s.trim() {
+        "1" => Ok(()),
+        _ => Err(SystemError::EINVAL),
+    }
+}
+
+/// # 结构功能
+/// 记录每个virtio设备最近一次自检的结果，并保证同一个设备同时只运行一个自检
+pub struct VirtIOSelftestManager {
+    results: SpinLock<BTreeMap<VirtIODeviceIndex, VirtIOSelftestResult>>,
+}
+
+impl VirtIOSelftestManager {
+    const fn new() -> Self {
+        Self {
+            results: SpinLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// # 函数的功能
+    /// 运行设备的自检并记录结果
+    ///
+    /// 自检运行期间不持有锁，驱动的自检可以睡眠等待设备完成请求
+    ///
+    /// ## 返回值
+    /// - Ok(()): 自检已经运行，结果(通过或失败)通过[`Self::result`]查询
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 驱动不支持自检
+    /// - Err(SystemError::EBUSY): 该设备的自检正在运行
+    /// - Err(SystemError::ENODEV): 设备还没有添加到virtio总线
+    pub fn run(&self, dev: &Arc<dyn VirtIODevice>) -> Result<(), SystemError> {
+        let index = dev.virtio_device_index().ok_or(SystemError::ENODEV)?;
+        {
+            let mut results = self.results.lock();
+            if results.get(&index) == Some(&VirtIOSelftestResult::Running) {
+                return Err(SystemError::EBUSY);
+            }
+            results.insert(index, VirtIOSelftestResult::Running);
+        }
+
+        let r = dev.selftest();
+        let result = match &r {
+            Ok(()) => VirtIOSelftestResult::Pass,
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP) => VirtIOSelftestResult::Unsupported,
+            Err(e) => VirtIOSelftestResult::Fail(e.clone()),
+        };
+        self.results.lock().insert(index, result.clone());
+
+        if result == VirtIOSelftestResult::Unsupported {
+            return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+        }
+        return Ok(());
+    }
+
+    /// 设备最近一次自检的结果
+    pub fn result(&self, index: VirtIODeviceIndex) -> VirtIOSelftestResult {
+        self.results
+            .lock()
+            .get(&index)
+            .cloned()
+            .unwrap_or(VirtIOSelftestResult::NotRun)
+    }
+
+    /// 设备被移除，它的索引可能被新的设备复用，因此要清除结果
+    pub fn forget(&self, index: VirtIODeviceIndex) {
+        self.results.lock().remove(&index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(VirtIOSelftestResult::NotRun.describe(), "not run\n");
+        assert_eq!(VirtIOSelftestResult::Pass.describe(), "pass\n");
+        assert_eq!(
+            VirtIOSelftestResult::Fail(SystemError::EIO).describe(),
+            "fail: EIO (-5)\n"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(virtio_selftest_parse(b"1"), Ok(()));
+        assert_eq!(virtio_selftest_parse(b"1\n"), Ok(()));
+        assert_eq!(virtio_selftest_parse(b"0"), Err(SystemError::EINVAL));
+        assert_eq!(virtio_selftest_parse(b""), Err(SystemError::EINVAL));
+        assert_eq!(virtio_selftest_parse(&[0xff]), Err(SystemError::EINVAL));
+    }
+}
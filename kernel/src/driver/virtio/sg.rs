@@ -0,0 +1,251 @@
+//! virtqueue的scatter-gather请求描述
+//!
+//! 块设备、网络设备的请求通常由位于不同地址的多个缓冲区组成（例如请求头+数据+状态字节）。
+//! 这里允许调用者一次性给出所有的分段以及它们的方向，由[`VirtioSgList`]负责校验分段的顺序，
+//! 并生成相互链接的描述符，而不需要调用者手动构造描述符链。
+
+use core::marker::PhantomData;
+
+use alloc::vec::Vec;
+use system_error::SystemError;
+
+bitflags! {
+    /// 描述符标志位
+    ///
+    /// 参考 virtio spec 2.7.5 The Virtqueue Descriptor Table
+    pub struct VirtqDescFlags: u16 {
+        /// 该描述符后面还有下一个描述符（由next字段指定）
+        const NEXT = 1;
+        /// 设备可写（否则为设备只读）
+        const WRITE = 2;
+        /// 该描述符指向一个间接描述符表
+        const INDIRECT = 4;
+    }
+}
+
+/// 分段的数据传输方向（从设备的角度描述）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioSgDirection {
+    /// 设备只读：数据由驱动发往设备
+    DeviceReadable,
+    /// 设备可写：数据由设备写回驱动
+    DeviceWritable,
+}
+
+/// 一个scatter-gather分段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioSgSegment {
+    /// 缓冲区的虚拟地址
+    pub addr: usize,
+    pub len: usize,
+    pub direction: VirtioSgDirection,
+}
+
+/// 描述符表中的一个表项
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtqDesc {
+    /// 缓冲区的物理地址
+    pub addr: u64,
+    pub len: u32,
+    pub flags: VirtqDescFlags,
+    pub next: u16,
+}
+
+/// # 结构功能
+/// 一次virtqueue请求的所有分段
+///
+/// 分段的生命周期与`'a`绑定，保证在请求被提交期间缓冲区不会被释放
+#[derive(Debug, Default)]
+pub struct VirtioSgList<'a> {
+    segments: Vec<VirtioSgSegment>,
+    _marker: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> VirtioSgList<'a> {
+    pub fn new() -> Self {
+        Self {
+            segments: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// 添加一个设备只读的分段
+    pub fn push_readable(&mut self, buf: &'a [u8]) -> &mut Self {
+        self.segments.push(VirtioSgSegment {
+            addr: buf.as_ptr() as usize,
+            len: buf.len(),
+            direction: VirtioSgDirection::DeviceReadable,
+        });
+        self
+    }
+
+    /// 添加一个设备可写的分段
+    pub fn push_writable(&mut self, buf: &'a mut [u8]) -> &mut Self {
+        self.segments.push(VirtioSgSegment {
+            addr: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+            direction: VirtioSgDirection::DeviceWritable,
+        });
+        self
+    }
+
+    /// 以(地址, 长度, 方向)的形式添加一个分段
+    ///
+    /// ## Safety
+    ///
+    /// 调用者需要保证`[addr, addr+len)`在请求完成之前一直有效，
+    /// 并且设备可写的分段在此期间不会被其他人访问
+    pub unsafe fn push_raw(&mut self, addr: usize, len: usize, direction: VirtioSgDirection) {
+        self.segments.push(VirtioSgSegment {
+            addr,
+            len,
+            direction,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// # 函数的功能
+    /// 检查分段列表是否能被提交到virtqueue
+    ///
+    /// virtio规范要求设备可写的描述符必须位于所有设备只读的描述符之后
+    ///
+    /// ## 返回值
+    /// - Ok(()): 分段列表合法
+    /// - Err(SystemError::EINVAL): 列表为空、存在长度为0或超过u32的分段，或者分段方向的顺序不合法
+    pub fn validate(&self) -> Result<(), SystemError> {
+        if self.is_empty() {
+            return Err(SystemError::EINVAL);
+        }
+
+        let mut seen_writable = false;
+        for seg in self.segments.iter() {
+            if seg.len == 0 || seg.len > u32::MAX as usize {
+                return Err(SystemError::EINVAL);
+            }
+            match seg.direction {
+                VirtioSgDirection::DeviceReadable if seen_writable => {
+                    return Err(SystemError::EINVAL);
+                }
+                VirtioSgDirection::DeviceReadable => {}
+                VirtioSgDirection::DeviceWritable => seen_writable = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 为分段列表生成一条描述符链
+    ///
+    /// ## 参数
+    /// - `free`: 可用的描述符索引，按顺序使用，链的头部为`free[0]`
+    /// - `to_phys`: 把缓冲区的虚拟地址转换为设备可见的物理地址
+    ///
+    /// ## 返回值
+    /// - Ok(Vec<(index, desc)>): 每个描述符的索引以及内容，除最后一个外都带有NEXT标志
+    /// - Err(SystemError::EINVAL): 分段列表不合法
+    /// - Err(SystemError::ENOSPC): 可用的描述符不足
+    pub fn build_chain(
+        &self,
+        free: &[u16],
+        to_phys: impl Fn(usize) -> Option<u64>,
+    ) -> Result<Vec<(u16, VirtqDesc)>, SystemError> {
+        self.validate()?;
+        if free.len() < self.segments.len() {
+            return Err(SystemError::ENOSPC);
+        }
+
+        let mut chain = Vec::with_capacity(self.segments.len());
+        for (i, seg) in self.segments.iter().enumerate() {
+            let mut flags = VirtqDescFlags::empty();
+            if seg.direction == VirtioSgDirection::DeviceWritable {
+                flags |= VirtqDescFlags::WRITE;
+            }
+
+            let is_last = i + 1 == self.segments.len();
+            let next = if is_last {
+                0
+            } else {
+                flags |= VirtqDescFlags::NEXT;
+                free[i + 1]
+            };
+
+            chain.push((
+                free[i],
+                VirtqDesc {
+                    addr: to_phys(seg.addr).ok_or(SystemError::EFAULT)?,
+                    len: seg.len as u32,
+                    flags,
+                    next,
+                },
+            ));
+        }
+        Ok(chain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_flags() {
+        let header = [0u8; 16];
+        let mut data = [0u8; 512];
+        let mut status = [0u8; 1];
+
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&header)
+            .push_writable(&mut data)
+            .push_writable(&mut status);
+
+        let chain = sg.build_chain(&[3, 7, 1, 9], |a| Some(a as u64)).unwrap();
+        assert_eq!(chain.len(), 3);
+
+        assert_eq!(chain[0].0, 3);
+        assert_eq!(chain[0].1.flags, VirtqDescFlags::NEXT);
+        assert_eq!(chain[0].1.next, 7);
+        assert_eq!(chain[0].1.len, 16);
+        assert_eq!(chain[0].1.addr, header.as_ptr() as u64);
+
+        assert_eq!(chain[1].0, 7);
+        assert_eq!(
+            chain[1].1.flags,
+            VirtqDescFlags::NEXT | VirtqDescFlags::WRITE
+        );
+        assert_eq!(chain[1].1.next, 1);
+        assert_eq!(chain[1].1.len, 512);
+
+        assert_eq!(chain[2].0, 1);
+        assert_eq!(chain[2].1.flags, VirtqDescFlags::WRITE);
+        assert_eq!(chain[2].1.len, 1);
+    }
+
+    #[test]
+    fn test_readable_after_writable() {
+        let mut data = [0u8; 8];
+        let status = [0u8; 1];
+        let mut sg = VirtioSgList::new();
+        sg.push_writable(&mut data).push_readable(&status);
+        assert_eq!(sg.validate(), Err(SystemError::EINVAL));
+    }
+
+    #[test]
+    fn test_not_enough_descriptors() {
+        let a = [0u8; 4];
+        let b = [0u8; 4];
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(&a).push_readable(&b);
+        assert_eq!(
+            sg.build_chain(&[0], |a| Some(a as u64)),
+            Err(SystemError::ENOSPC)
+        );
+    }
+}
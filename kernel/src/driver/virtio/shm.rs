@@ -0,0 +1,313 @@
+//! virtio共享内存区域
+//!
+//! 设备可以通过共享内存区域把主机上的内存暴露给驱动，例如virtio-gpu的主机端资源、virtio-fs的DAX窗口。
+//! 每个区域由一个id区分，同一设备可以有多个区域。PCI设备通过类型为`VIRTIO_PCI_CAP_SHARED_MEMORY_CFG`的
+//! `virtio_pci_cap64`描述区域在哪个BAR的什么位置：
+//!
+//! | 偏移 | 字段                                           |
+//! |------|------------------------------------------------|
+//! | 0    | cap_vndr, cap_next, cap_len, cfg_type          |
+//! | 4    | bar, id, padding[2]                            |
+//! | 8    | offset (低32位)                                |
+//! | 12   | length (低32位)                                |
+//! | 16   | offset_hi                                      |
+//! | 20   | length_hi                                      |
+//!
+//! 参考 virtio spec 2.10 Shared Memory Regions、4.1.4.7 Shared memory capability
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt::Debug;
+use system_error::SystemError;
+
+use crate::{
+    arch::MMArch,
+    driver::pci::pci::PCI_CAP_ID_VNDR,
+    libs::{align::page_align_up, mutex::Mutex},
+    mm::{
+        mmio_buddy::{mmio_pool, MMIOSpaceGuard},
+        MemoryManagementArch, PhysAddr, VirtAddr,
+    },
+};
+
+/// 共享内存区域的capability类型
+pub const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
+/// `virtio_pci_cap64`的长度
+const VIRTIO_PCI_CAP64_LEN: u8 = 24;
+
+/// # 结构功能
+/// 从一个`virtio_pci_cap64`中解析出的共享内存区域的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtIOShmCap {
+    /// 区域的id，由设备类型定义
+    pub id: u8,
+    pub bar: u8,
+    /// 区域在BAR中的偏移
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl VirtIOShmCap {
+    /// # 函数的功能
+    /// 解析配置空间中位于`cap_offset`的capability
+    ///
+    /// ## 参数
+    /// - `cap_offset`: capability在配置空间中的偏移
+    /// - `read`: 读取配置空间中某个偏移处的32位值
+    ///
+    /// ## 返回值
+    /// 不是共享内存区域的capability，或者capability的长度不够时返回None
+    pub fn parse(cap_offset: u8, read: impl Fn(u8) -> u32) -> Option<Self> {
+        let header = read(cap_offset);
+        let cap_len = (header >> 16) as u8;
+        let cfg_type = (header >> 24) as u8;
+        if header as u8 != PCI_CAP_ID_VNDR
+            || cfg_type != VIRTIO_PCI_CAP_SHARED_MEMORY_CFG
+            || cap_len < VIRTIO_PCI_CAP64_LEN
+        {
+            return None;
+        }
+        // capability不能超出配置空间的前256字节
+        cap_offset.checked_add(VIRTIO_PCI_CAP64_LEN - 1)?;
+
+        let bar_id = read(cap_offset + 4);
+        let dword = |off: u8| read(cap_offset + off) as u64;
+        Some(Self {
+            bar: bar_id as u8,
+            id: (bar_id >> 8) as u8,
+            offset: dword(8) | (dword(16) << 32),
+            length: dword(12) | (dword(20) << 32),
+        })
+    }
+}
+
+/// # 结构功能
+/// 共享内存区域的物理地址范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtIOShmRegion {
+    pub id: u8,
+    pub paddr: PhysAddr,
+    pub len: usize,
+}
+
+/// # 结构功能
+/// 设备的所有共享内存区域，以及已经被映射的区域
+#[derive(Default)]
+pub struct VirtIOShmRegions {
+    regions: Vec<VirtIOShmRegion>,
+    /// 区域id -> 区域的映射，区域只在驱动请求时映射，并且只映射一次
+    mappings: Mutex<BTreeMap<u8, Arc<MMIOSpaceGuard>>>,
+}
+
+impl VirtIOShmRegions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # 函数的功能
+    /// 根据capability以及BAR的位置，记录一个共享内存区域
+    ///
+    /// ## 参数
+    /// - `cap`: 共享内存区域的capability
+    /// - `bar`: 返回BAR的物理地址和长度，IO BAR或者没有分配地址的BAR返回None
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EEXIST): 已经有相同id的区域，规范要求id不能重复
+    /// - Err(SystemError::EINVAL): BAR不可用，或者区域超出了BAR的范围
+    pub fn add(
+        &mut self,
+        cap: &VirtIOShmCap,
+        bar: impl Fn(u8) -> Option<(u64, u64)>,
+    ) -> Result<(), SystemError> {
+        if self.get(cap.id).is_some() {
+            return Err(SystemError::EEXIST);
+        }
+        let (bar_address, bar_size) = bar(cap.bar).ok_or(SystemError::EINVAL)?;
+        if bar_address == 0 || cap.length == 0 {
+            return Err(SystemError::EINVAL);
+        }
+        let end = cap
+            .offset
+            .checked_add(cap.length)
+            .ok_or(SystemError::EINVAL)?;
+        if end > bar_size {
+            return Err(SystemError::EINVAL);
+        }
+        let paddr = usize::try_from(bar_address + cap.offset).map_err(|_| SystemError::EINVAL)?;
+        let len = usize::try_from(cap.length).map_err(|_| SystemError::EINVAL)?;
+        self.regions.push(VirtIOShmRegion {
+            id: cap.id,
+            paddr: PhysAddr::new(paddr),
+            len,
+        });
+        Ok(())
+    }
+
+    /// # 函数的功能
+    /// 获取id对应的共享内存区域的物理地址和长度
+    pub fn get(&self, id: u8) -> Option<(PhysAddr, usize)> {
+        self.regions
+            .iter()
+            .find(|r| r.id == id)
+            .map(|r| (r.paddr, r.len))
+    }
+
+    #[allow(dead_code)]
+    pub fn regions(&self) -> &[VirtIOShmRegion] {
+        &self.regions
+    }
+
+    /// # 函数的功能
+    /// 把id对应的共享内存区域映射到内核的MMIO空间，多次调用返回同一个映射
+    ///
+    /// ## 返回值
+    /// - Ok(vaddr): 区域起始处的虚拟地址，映射一直保留到设备被释放
+    /// - Err(SystemError::ENOENT): 设备没有这个区域
+    /// - Err(SystemError::EINVAL): 区域的起始地址没有按页对齐
+    /// - Err(SystemError::ENOMEM): 区域太大，无法分配MMIO空间
+    pub fn map(&self, id: u8) -> Result<VirtAddr, SystemError> {
+        let (paddr, len) = self.get(id).ok_or(SystemError::ENOENT)?;
+        if !paddr.check_aligned(MMArch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+
+        let mut mappings = self.mappings.lock();
+        if let Some(guard) = mappings.get(&id) {
+            return Ok(guard.vaddr());
+        }
+        let len = page_align_up(len);
+        let guard = mmio_pool()
+            .create_mmio(len)
+            .map_err(|_| SystemError::ENOMEM)?;
+        // 区域在设备的BAR中，是设备的物理地址
+        unsafe { guard.map_phys(paddr, len) }?;
+        let vaddr = guard.vaddr();
+        mappings.insert(id, Arc::new(guard));
+        Ok(vaddr)
+    }
+}
+
+impl Debug for VirtIOShmRegions {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VirtIOShmRegions")
+            .field("regions", &self.regions)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造一个配置空间，capability链表从0x40开始
+    fn config_space(caps: &[(u8, [u32; 6])]) -> [u32; 64] {
+        let mut space = [0u32; 64];
+        for (i, (offset, cap)) in caps.iter().enumerate() {
+            let next = caps.get(i + 1).map_or(0, |(o, _)| *o) as u32;
+            let base = *offset as usize / 4;
+            space[base..base + 6].copy_from_slice(cap);
+            space[base] |= next << 8;
+        }
+        space
+    }
+
+    fn shm_cap(bar: u8, id: u8, offset: u64, length: u64) -> [u32; 6] {
+        [
+            PCI_CAP_ID_VNDR as u32
+                | ((VIRTIO_PCI_CAP64_LEN as u32) << 16)
+                | ((VIRTIO_PCI_CAP_SHARED_MEMORY_CFG as u32) << 24),
+            bar as u32 | ((id as u32) << 8),
+            offset as u32,
+            length as u32,
+            (offset >> 32) as u32,
+            (length >> 32) as u32,
+        ]
+    }
+
+    #[test]
+    fn test_parse_two_regions() {
+        // common config capability(cfg_type=1)
+        let common = [
+            PCI_CAP_ID_VNDR as u32 | (16 << 16) | (1 << 24),
+            4,
+            0,
+            0x38,
+            0,
+            0,
+        ];
+        let space = config_space(&[
+            (0x40, common),
+            (0x60, shm_cap(2, 0, 0, 0x1_0000_0000)),
+            (0x80, shm_cap(2, 1, 0x1_0000_0000, 0x20_0000)),
+        ]);
+        let read = |off: u8| space[off as usize / 4];
+
+        let mut caps = Vec::new();
+        let mut offset = 0x40u8;
+        while offset != 0 {
+            caps.extend(VirtIOShmCap::parse(offset, read));
+            offset = (read(offset) >> 8) as u8;
+        }
+        assert_eq!(
+            caps,
+            [
+                VirtIOShmCap {
+                    id: 0,
+                    bar: 2,
+                    offset: 0,
+                    length: 0x1_0000_0000
+                },
+                VirtIOShmCap {
+                    id: 1,
+                    bar: 2,
+                    offset: 0x1_0000_0000,
+                    length: 0x20_0000
+                },
+            ]
+        );
+
+        let bar = |bar: u8| (bar == 2).then_some((0x80_0000_0000u64, 0x2_0000_0000u64));
+        let mut regions = VirtIOShmRegions::new();
+        for cap in caps.iter() {
+            regions.add(cap, bar).unwrap();
+        }
+        assert_eq!(
+            regions.get(0),
+            Some((PhysAddr::new(0x80_0000_0000), 0x1_0000_0000))
+        );
+        assert_eq!(
+            regions.get(1),
+            Some((PhysAddr::new(0x81_0000_0000), 0x20_0000))
+        );
+        assert_eq!(regions.get(2), None);
+        // id不能重复
+        assert_eq!(regions.add(&caps[0], bar), Err(SystemError::EEXIST));
+    }
+
+    #[test]
+    fn test_invalid_regions() {
+        let bar = |bar: u8| (bar == 0).then_some((0xfe00_0000u64, 0x4000u64));
+        let mut regions = VirtIOShmRegions::new();
+        let cap = |bar: u8, offset: u64, length: u64| VirtIOShmCap {
+            id: 0,
+            bar,
+            offset,
+            length,
+        };
+        // 超出BAR的范围
+        assert_eq!(
+            regions.add(&cap(0, 0x2000, 0x3000), bar),
+            Err(SystemError::EINVAL)
+        );
+        // BAR不可用
+        assert_eq!(
+            regions.add(&cap(1, 0, 0x1000), bar),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(regions.add(&cap(0, 0x1000, 0x3000), bar), Ok(()));
+
+        // 长度不够的capability被忽略
+        let mut short = shm_cap(0, 0, 0, 0x1000);
+        short[0] = (short[0] & !(0xff << 16)) | (16 << 16);
+        assert_eq!(VirtIOShmCap::parse(0, |off| short[off as usize / 4]), None);
+    }
+}
@@ -4,7 +4,7 @@ use alloc::{
 };
 use ida::IdAllocator;
 use intertrait::cast::CastArc;
-use log::error;
+use log::{error, warn};
 use system_error::SystemError;
 use unified_init::macros::unified_init;
 
@@ -15,17 +15,23 @@ use crate::{
                 bus::{bus_manager, Bus},
                 device_manager,
                 driver::{driver_manager, Driver},
+                registry::device_registry,
                 Device,
             },
             kobject::KObject,
             subsys::SubSysPrivate,
         },
-        virtio::irq::{virtio_irq_manager, DefaultVirtioIrqHandler},
+        virtio::{
+            irq::{virtio_irq_manager, virtio_irq_stats, DefaultVirtioIrqHandler},
+            selftest::{virtio_selftest_manager, virtio_selftest_parse},
+            worker::virtio_worker_manager,
+        },
     },
     exception::{irqdesc::IrqHandleFlags, manage::irq_manager},
     filesystem::{
         sysfs::{
             file::sysfs_emit_str, Attribute, AttributeGroup, SysFSOpsSupport, SYSFS_ATTR_MODE_RO,
+            SYSFS_ATTR_MODE_RW, SYSFS_ATTR_MODE_WO,
         },
         vfs::syscall::ModeType,
     },
@@ -33,7 +39,10 @@ use crate::{
     libs::spinlock::SpinLock,
 };
 
-use super::{VirtIODevice, VirtIODeviceIndex, VirtIODriver, VIRTIO_DEV_ANY_ID};
+use super::{
+    queue_full::VirtQueueFullPolicy, VirtIODevice, VirtIODeviceIndex, VirtIODriver,
+    VIRTIO_DEV_ANY_ID,
+};
 
 static mut VIRTIO_BUS: Option<Arc<VirtIOBus>> = None;
 
@@ -98,8 +107,47 @@ impl Bus for VirtIOBus {
         return virtio_drv.probe(&virtio_dev);
     }
 
-    fn remove(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
-        todo!()
+    fn probe_failed(&self, device: &Arc<dyn Device>) {
+        let Ok(virtio_dev) = device.clone().cast::<dyn VirtIODevice>() else {
+            return;
+        };
+        // 驱动可能已经修改了设备的状态，重新使能一次，让下一个驱动从复位之后的状态开始
+        if virtio_dev.enabled() != Ok(true) {
+            return;
+        }
+        if let Err(e) = virtio_dev
+            .set_enabled(false)
+            .and_then(|_| virtio_dev.set_enabled(true))
+        {
+            if e != SystemError::ENOSYS {
+                error!(
+                    "VirtIOBus::probe_failed() failed to reset device '{}': {:?}",
+                    device.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn remove(&self, device: &Arc<dyn Device>) -> Result<(), SystemError> {
+        let drv = device.driver().ok_or(SystemError::EINVAL)?;
+        let virtio_drv = drv.cast::<dyn VirtIODriver>().map_err(|_| {
+            error!(
+                "VirtIOBus::remove() failed: device.driver() is not a VirtioDriver. Device: '{:?}'",
+                device.name()
+            );
+            SystemError::EINVAL
+        })?;
+
+        let virtio_dev = device.clone().cast::<dyn VirtIODevice>().map_err(|_| {
+            error!(
+                "VirtIOBus::remove() failed: device is not a VirtIODevice. Device: '{:?}'",
+                device.name()
+            );
+            SystemError::EINVAL
+        })?;
+
+        return virtio_drv.remove(&virtio_dev);
     }
 
     fn sync_state(&self, _device: &Arc<dyn Device>) {
@@ -110,8 +158,31 @@ impl Bus for VirtIOBus {
         todo!()
     }
 
-    fn resume(&self, _device: &Arc<dyn Device>) -> Result<(), SystemError> {
-        todo!()
+    /// 挂起之前让设备停止访问内存，设备的电源状态由传输层（例如PCI总线）管理
+    ///
+    /// 设备在挂起期间会丢失状态，因此静默之后复位设备，恢复时重新初始化
+    fn suspend(&self, device: &Arc<dyn Device>) {
+        let Ok(virtio_dev) = device.clone().cast::<dyn VirtIODevice>() else {
+            return;
+        };
+        match virtio_dev.quiesce(true) {
+            Ok(_) | Err(SystemError::ENOSYS) => {}
+            Err(e) => warn!(
+                "VirtIOBus::suspend(): failed to quiesce device '{}': {:?}",
+                device.name(),
+                e
+            ),
+        }
+    }
+
+    fn resume(&self, device: &Arc<dyn Device>) -> Result<(), SystemError> {
+        let Ok(virtio_dev) = device.clone().cast::<dyn VirtIODevice>() else {
+            return Ok(());
+        };
+        match virtio_dev.unquiesce() {
+            Ok(_) | Err(SystemError::ENOSYS) => Ok(()),
+            Err(e) => Err(e),
+        }
     }
 
     // 参考：https://code.dragonos.org.cn/xref/linux-6.6.21/drivers/virtio/virtio.c#85
@@ -196,13 +267,33 @@ impl VirtIODeviceManager {
         dev.set_virtio_device_index(virtio_index);
         dev.set_device_name(format!("virtio{}", virtio_index.data()));
 
-        device_manager().add_device(dev.clone() as Arc<dyn Device>)?;
-        let r = device_manager()
-            .add_groups(&(dev.clone() as Arc<dyn Device>), &[&VirtIODeviceAttrGroup]);
+        if let Err(e) = device_manager().add_device(dev.clone() as Arc<dyn Device>) {
+            VIRTIO_DEVICE_INDEX_MANAGER.free(virtio_index);
+            return Err(e);
+        }
+        if let Err(e) = device_manager()
+            .add_groups(&(dev.clone() as Arc<dyn Device>), &[&VirtIODeviceAttrGroup])
+        {
+            // 初始化失败的设备不能留在总线上
+            device_manager().remove(&(dev.clone() as Arc<dyn Device>));
+            VIRTIO_DEVICE_INDEX_MANAGER.free(virtio_index);
+            return Err(e);
+        }
+
+        // DeviceId重复时设备仍然可用，只是无法通过DeviceId找到它
+        if let Err(e) =
+            device_registry().register(dev.dev_id().clone(), dev.clone() as Arc<dyn Device>)
+        {
+            warn!(
+                "virtio device '{:?}': failed to register device id: {:?}",
+                dev.dev_id(),
+                e
+            );
+        }
 
         self.setup_irq(&dev).ok();
 
-        return r;
+        return Ok(());
     }
 
     /// # setup_irq - 设置中断
@@ -240,9 +331,23 @@ impl VirtIODeviceManager {
         return Ok(());
     }
 
-    #[allow(dead_code)]
+    /// # device_remove - 移除virtio设备
+    ///
+    /// 解除设备与驱动的绑定，删除设备在sysfs中的目录，并释放设备的中断以及索引。
+    /// 设备的工作线程在中断被释放之后停止，返回时它们已经退出
     pub fn device_remove(&self, dev: &Arc<dyn VirtIODevice>) -> Result<(), SystemError> {
+        virtio_irq_manager().unregister_device(dev.dev_id());
+        if let Some(irq) = dev.irq() {
+            irq_manager().free_irq(irq, Some(dev.dev_id().clone()));
+        }
+        virtio_worker_manager().stop_device(dev.dev_id());
+
         device_manager().remove(&(dev.clone() as Arc<dyn Device>));
+
+        if let Some(index) = dev.virtio_device_index() {
+            virtio_selftest_manager().forget(index);
+            VIRTIO_DEVICE_INDEX_MANAGER.free(index);
+        }
         return Ok(());
     }
 }
@@ -280,7 +385,6 @@ impl VirtIODeviceIndexManager {
     // 释放一个VirtIO设备索引
     ///
     /// 释放之前分配的VirtIO设备索引，使其可以被重新使用。
-    #[allow(dead_code)]
     pub fn free(&self, index: VirtIODeviceIndex) {
         self.ida.lock().free(index.0);
     }
@@ -298,7 +402,20 @@ impl AttributeGroup for VirtIODeviceAttrGroup {
     }
 
     fn attrs(&self) -> &[&'static dyn Attribute] {
-        &[&AttrDevice, &AttrVendor]
+        &[
+            &AttrDevice,
+            &AttrVendor,
+            &AttrModalias,
+            &AttrUevent,
+            &AttrIrqType,
+            &AttrIrqStats,
+            &AttrIrqVectorSharing,
+            &AttrEnable,
+            &AttrSelftest,
+            &AttrSelftestResult,
+            &AttrRingDebug,
+            &AttrQueueFullPolicy,
+        ]
     }
 }
 
@@ -355,3 +472,339 @@ impl Attribute for AttrVendor {
         return sysfs_emit_str(buf, &format!("0x{:04x}\n", vendor));
     }
 }
+
+#[derive(Debug)]
+struct AttrModalias;
+
+impl Attribute for AttrModalias {
+    fn name(&self) -> &str {
+        "modalias"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrModalias::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        return sysfs_emit_str(buf, &format!("{}\n", dev.modalias()));
+    }
+}
+
+/// 设备的uevent环境变量，添加设备时发出的uevent会携带这些变量
+///
+/// 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/drivers/virtio/virtio.c#235
+#[derive(Debug)]
+struct AttrUevent;
+
+impl Attribute for AttrUevent {
+    fn name(&self) -> &str {
+        "uevent"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrUevent::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        return sysfs_emit_str(buf, &format!("MODALIAS={}\n", dev.modalias()));
+    }
+}
+
+/// 设备实际使用的中断类型，取值为msix/msi/intx/platform/none
+#[derive(Debug)]
+struct AttrIrqType;
+
+impl Attribute for AttrIrqType {
+    fn name(&self) -> &str {
+        "irq_type"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrIrqType::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        return sysfs_emit_str(buf, &format!("{}\n", dev.irq_type().as_str()));
+    }
+}
+
+/// 设备的中断次数，第一行为总数，之后每行为一个virtqueue的中断次数
+#[derive(Debug)]
+struct AttrIrqStats;
+
+impl Attribute for AttrIrqStats {
+    fn name(&self) -> &str {
+        "irq_stats"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrIrqStats::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        return sysfs_emit_str(buf, &virtio_irq_stats(&dev).describe());
+    }
+}
+
+/// 共用同一个中断向量的virtqueue数的最大值，设备的MSI-X向量比队列少时大于1
+#[derive(Debug)]
+struct AttrIrqVectorSharing;
+
+impl Attribute for AttrIrqVectorSharing {
+    fn name(&self) -> &str {
+        "irq_vector_sharing"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrIrqVectorSharing::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        let sharing = dev.irq_type().sharing_factor(&dev.queues());
+        return sysfs_emit_str(buf, &format!("{}\n", sharing));
+    }
+}
+
+/// 设备每个virtqueue的状态，用于调试卡住的队列。写入"reset <队列编号>"同步该队列的last_used
+#[derive(Debug)]
+struct AttrRingDebug;
+
+impl Attribute for AttrRingDebug {
+    fn name(&self) -> &str {
+        "ring_debug"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RW
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW | SysFSOpsSupport::ATTR_STORE
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrRingDebug::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        let mut s = String::new();
+        for queue in dev.queues() {
+            match dev.ring_debug_dump(queue) {
+                Ok(dump) => s.push_str(&dump),
+                Err(e) => s.push_str(&format!("queue {}: {:?}", queue, e)),
+            }
+            s.push('\n');
+        }
+        return sysfs_emit_str(buf, &s);
+    }
+
+    fn store(&self, kobj: Arc<dyn KObject>, buf: &[u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrRingDebug::store() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        let s = core::str::from_utf8(buf).map_err(|_| SystemError::EINVAL)?;
+        let queue = s
+            .trim()
+            .strip_prefix("reset ")
+            .and_then(|q| q.trim().parse::<u16>().ok())
+            .ok_or(SystemError::EINVAL)?;
+        let skipped = dev.ring_reset_last_used(queue)?;
+        if skipped != 0 {
+            log::warn!(
+                "virtio device '{}': queue {}: skipped {} used ring entries",
+                dev.device_name(),
+                queue,
+                skipped
+            );
+        }
+        return Ok(buf.len());
+    }
+}
+
+/// virtqueue已满时阻塞的提交者如何等待："sleep"或者"spin <最长自旋的微秒数>"
+#[derive(Debug)]
+struct AttrQueueFullPolicy;
+
+impl Attribute for AttrQueueFullPolicy {
+    fn name(&self) -> &str {
+        "queue_full_policy"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RW
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW | SysFSOpsSupport::ATTR_STORE
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrQueueFullPolicy::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        return sysfs_emit_str(buf, &format!("{}\n", dev.queue_full_policy()));
+    }
+
+    fn store(&self, kobj: Arc<dyn KObject>, buf: &[u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrQueueFullPolicy::store() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        let s = core::str::from_utf8(buf).map_err(|_| SystemError::EINVAL)?;
+        dev.set_queue_full_policy(s.parse::<VirtQueueFullPolicy>()?)?;
+        return Ok(buf.len());
+    }
+}
+
+/// 设备是否处于DRIVER_OK状态。写入0复位设备，写入1重新初始化设备
+#[derive(Debug)]
+struct AttrEnable;
+
+impl Attribute for AttrEnable {
+    fn name(&self) -> &str {
+        "enable"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RW
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW | SysFSOpsSupport::ATTR_STORE
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrEnable::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        let enabled = dev.enabled()?;
+        return sysfs_emit_str(buf, &format!("{}\n", enabled as u8));
+    }
+
+    fn store(&self, kobj: Arc<dyn KObject>, buf: &[u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrEnable::store() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        let s = core::str::from_utf8(buf).map_err(|_| SystemError::EINVAL)?;
+        let enable = match s.trim() {
+            "0" => false,
+            "1" => true,
+            _ => return Err(SystemError::EINVAL),
+        };
+        dev.set_enabled(enable)?;
+        return Ok(buf.len());
+    }
+}
+
+/// 写入1运行设备的自检，结果通过selftest_result读取
+#[derive(Debug)]
+struct AttrSelftest;
+
+impl Attribute for AttrSelftest {
+    fn name(&self) -> &str {
+        "selftest"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_WO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_STORE
+    }
+
+    fn store(&self, kobj: Arc<dyn KObject>, buf: &[u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrSelftest::store() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        virtio_selftest_parse(buf)?;
+        virtio_selftest_manager().run(&dev)?;
+        return Ok(buf.len());
+    }
+}
+
+/// 设备最近一次自检的结果
+#[derive(Debug)]
+struct AttrSelftestResult;
+
+impl Attribute for AttrSelftestResult {
+    fn name(&self) -> &str {
+        "selftest_result"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj.cast::<dyn VirtIODevice>().map_err(|_| {
+            error!("AttrSelftestResult::show() failed: kobj is not a VirtIODevice");
+            SystemError::EINVAL
+        })?;
+
+        let index = dev.virtio_device_index().ok_or(SystemError::ENODEV)?;
+        let result = virtio_selftest_manager().result(index);
+        return sysfs_emit_str(buf, &result.describe());
+    }
+}
@@ -0,0 +1,259 @@
+//! 设备与驱动解除绑定时停止并释放virtqueue
+//!
+//! 驱动被移除之后，设备不能再通过DMA访问已经释放的内存，因此需要按照以下顺序拆除队列：
+//! 1. 等待设备交还已经提交的描述符，超时后不再等待
+//! 2. 复位设备，之后设备不会再访问任何队列
+//! 3. 释放队列的内存
+//!
+//! 最后由调用者释放设备的中断向量（见[`super::transport::VirtIOTransport::release_irq_vectors`]）。
+//!
+//! 挂起之前的静默（见[`virtio_quiesce_queues`]）使用相同的步骤，只是可以不复位设备，保留队列在恢复之后继续使用。
+
+use core::hint::spin_loop;
+
+use system_error::SystemError;
+
+/// 等待设备交还描述符时最多轮询的次数
+pub const VIRTIO_TEARDOWN_MAX_POLLS: usize = 1_000_000;
+
+/// # trait功能
+/// 驱动持有的一组virtqueue，由[`virtio_teardown_queues`]按顺序拆除
+pub trait VirtQueueOwner {
+    /// 回收设备已经完成的请求，返回仍未完成的请求数量
+    fn reap(&mut self) -> usize;
+
+    /// 复位设备，复位之后设备不会再访问任何队列
+    fn reset(&mut self) -> Result<(), SystemError>;
+
+    /// 释放所有队列的内存，调用时设备已经复位。仍未完成的请求需要以错误结束
+    fn free_queues(&mut self);
+}
+
+/// # 函数的功能
+/// 等待设备交还描述符，然后复位设备并释放所有队列的内存
+///
+/// 复位失败时(例如设备已经被拔出)仍然会释放队列的内存：此时设备已经无法再访问内存
+///
+/// ## 参数
+/// - `owner`: 驱动持有的virtqueue
+/// - `max_polls`: 等待描述符时最多轮询的次数
+///
+/// ## 返回值
+/// - Ok(()): 所有描述符都已经交还，队列已经释放
+/// - Err(SystemError::ETIMEDOUT): 等待超时，队列仍然已经释放，未完成的请求以错误结束
+/// - Err(e): 复位设备失败，队列仍然已经释放
+pub fn virtio_teardown_queues(
+    owner: &mut dyn VirtQueueOwner,
+    max_polls: usize,
+) -> Result<(), SystemError> {
+    let outstanding = virtio_drain_queues(owner, max_polls);
+
+    let reset = owner.reset();
+    owner.free_queues();
+    reset?;
+
+    if outstanding > 0 {
+        return Err(SystemError::ETIMEDOUT);
+    }
+    return Ok(());
+}
+
+/// 等待设备交还描述符，返回超时之后仍未完成的请求数量
+fn virtio_drain_queues(owner: &mut dyn VirtQueueOwner, max_polls: usize) -> usize {
+    let mut outstanding = owner.reap();
+    let mut polls = 0;
+    while outstanding > 0 && polls < max_polls {
+        spin_loop();
+        outstanding = owner.reap();
+        polls += 1;
+    }
+    outstanding
+}
+
+/// # 函数的功能
+/// 挂起之前让设备停止访问内存：等待已经提交的请求完成，`reset`为true时再复位设备并释放队列
+///
+/// 调用之前，驱动需要已经停止提交新的请求
+///
+/// ## 参数
+/// - `owner`: 驱动持有的virtqueue
+/// - `max_polls`: 等待描述符时最多轮询的次数
+/// - `reset`: 是否复位设备。不复位时队列保留，恢复之后可以直接使用
+///
+/// ## 返回值
+/// - Ok(()): 设备不会再通过DMA访问内存
+/// - Err(SystemError::ETIMEDOUT): 等待超时。`reset`为true时设备仍然已经复位，未完成的请求以错误结束；
+///   否则设备可能仍在访问内存，调用者不能继续挂起
+/// - Err(e): 复位设备失败
+pub fn virtio_quiesce_queues(
+    owner: &mut dyn VirtQueueOwner,
+    max_polls: usize,
+    reset: bool,
+) -> Result<(), SystemError> {
+    if reset {
+        return virtio_teardown_queues(owner, max_polls);
+    }
+    if virtio_drain_queues(owner, max_polls) > 0 {
+        return Err(SystemError::ETIMEDOUT);
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::{
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use virtio_drivers::{BufferDirection, Hal, PhysAddr};
+
+    use super::*;
+    use crate::driver::virtio::{mock::MockHal, ring::VirtqRingMemory};
+
+    /// 在[`MockHal`]之上记录尚未释放的DMA内存页数
+    struct CountingHal;
+
+    static LIVE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl Hal for CountingHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            LIVE_PAGES.fetch_add(pages, Ordering::SeqCst);
+            MockHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            LIVE_PAGES.fetch_sub(pages, Ordering::SeqCst);
+            MockHal::dma_dealloc(paddr, vaddr, pages)
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            MockHal::mmio_phys_to_virt(paddr, size)
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            MockHal::share(buffer, direction)
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            MockHal::unshare(paddr, buffer, direction)
+        }
+    }
+
+    /// 模拟的设备：每次回收完成一个请求，`stuck`个请求永远不会完成
+    struct MockQueues {
+        rings: Vec<VirtqRingMemory<CountingHal>>,
+        outstanding: usize,
+        stuck: usize,
+        reset_result: Result<(), SystemError>,
+        /// 复位设备时仍未完成的请求数量
+        outstanding_at_reset: Option<usize>,
+        /// 按顺序记录调用：'p'回收，'r'复位，'f'释放
+        calls: Vec<char>,
+        failed_requests: usize,
+    }
+
+    impl MockQueues {
+        fn new(queues: usize, outstanding: usize, stuck: usize) -> Self {
+            Self {
+                rings: (0..queues)
+                    .map(|_| VirtqRingMemory::alloc(256, false).unwrap())
+                    .collect(),
+                outstanding,
+                stuck,
+                reset_result: Ok(()),
+                outstanding_at_reset: None,
+                calls: Vec::new(),
+                failed_requests: 0,
+            }
+        }
+    }
+
+    impl VirtQueueOwner for MockQueues {
+        fn reap(&mut self) -> usize {
+            if self.calls.last() != Some(&'p') {
+                self.calls.push('p');
+            }
+            if self.outstanding > self.stuck {
+                self.outstanding -= 1;
+            }
+            self.outstanding
+        }
+
+        fn reset(&mut self) -> Result<(), SystemError> {
+            self.calls.push('r');
+            self.outstanding_at_reset = Some(self.outstanding);
+            self.reset_result.clone()
+        }
+
+        fn free_queues(&mut self) {
+            self.calls.push('f');
+            self.failed_requests += core::mem::take(&mut self.outstanding);
+            self.rings.clear();
+        }
+    }
+
+    // 这些测试共用LIVE_PAGES，因此放在同一个测试中顺序执行
+    #[test]
+    fn test_teardown_frees_rings() {
+        let mut queues = MockQueues::new(3, 5, 0);
+        assert!(LIVE_PAGES.load(Ordering::SeqCst) > 0);
+        assert_eq!(virtio_teardown_queues(&mut queues, 100), Ok(()));
+        assert_eq!(queues.calls, ['p', 'r', 'f']);
+        assert_eq!(queues.failed_requests, 0);
+        assert_eq!(LIVE_PAGES.load(Ordering::SeqCst), 0);
+
+        // 设备一直不交还描述符：超时后仍然复位设备并释放内存
+        let mut queues = MockQueues::new(2, 4, 2);
+        assert_eq!(
+            virtio_teardown_queues(&mut queues, 100),
+            Err(SystemError::ETIMEDOUT)
+        );
+        assert_eq!(queues.calls, ['p', 'r', 'f']);
+        assert_eq!(queues.failed_requests, 2);
+        assert_eq!(LIVE_PAGES.load(Ordering::SeqCst), 0);
+
+        // 设备已经被拔出，复位失败
+        let mut queues = MockQueues::new(2, 0, 0);
+        queues.reset_result = Err(SystemError::ENODEV);
+        assert_eq!(
+            virtio_teardown_queues(&mut queues, 100),
+            Err(SystemError::ENODEV)
+        );
+        assert_eq!(LIVE_PAGES.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_quiesce_drains_first() {
+        // 不分配ring，避免与上面的测试共用LIVE_PAGES
+        let mut queues = MockQueues::new(0, 3, 0);
+        assert_eq!(virtio_quiesce_queues(&mut queues, 100, false), Ok(()));
+        assert_eq!(queues.outstanding, 0);
+        assert_eq!(queues.calls, ['p']);
+
+        // 复位之前已经等待所有请求完成
+        let mut queues = MockQueues::new(0, 3, 0);
+        assert_eq!(virtio_quiesce_queues(&mut queues, 100, true), Ok(()));
+        assert_eq!(queues.calls, ['p', 'r', 'f']);
+        assert_eq!(queues.outstanding_at_reset, Some(0));
+        assert_eq!(queues.failed_requests, 0);
+
+        // 请求一直没有完成：不复位时设备可能仍在访问内存，不能继续挂起
+        let mut queues = MockQueues::new(0, 3, 1);
+        assert_eq!(
+            virtio_quiesce_queues(&mut queues, 100, false),
+            Err(SystemError::ETIMEDOUT)
+        );
+        assert_eq!(queues.calls, ['p']);
+        assert_eq!(queues.outstanding, 1);
+
+        let mut queues = MockQueues::new(0, 3, 1);
+        assert_eq!(
+            virtio_quiesce_queues(&mut queues, 100, true),
+            Err(SystemError::ETIMEDOUT)
+        );
+        assert_eq!(queues.outstanding_at_reset, Some(1));
+        assert_eq!(queues.failed_requests, 1);
+    }
+}
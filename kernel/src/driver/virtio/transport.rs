@@ -1,8 +1,35 @@
-use virtio_drivers::transport::Transport;
+use core::ptr::NonNull;
 
-use crate::exception::HardwareIrqNumber;
+use alloc::vec::Vec;
+use log::warn;
+use system_error::SystemError;
+use virtio_drivers::{
+    transport::{DeviceStatus, Transport},
+    PAGE_SIZE,
+};
 
-use super::{transport_mmio::VirtIOMmioTransport, transport_pci::PciTransport};
+use crate::{
+    driver::pci::pci_irq::IrqType,
+    exception::HardwareIrqNumber,
+    libs::volatile::{Volatile, VolatileReadable},
+    mm::{PhysAddr, VirtAddr},
+};
+
+use super::{
+    config::{
+        virtio_config_read, virtio_config_write, virtio_read_config_consistent, VirtIOConfigAccess,
+        VirtIOConfigGeneration,
+    },
+    endian::{VirtIOEndian, VirtIOEndianField},
+    queue::VirtqConfig,
+    reset::{virtio_reset_device, VirtIOQueueResetRegister},
+    ring::VirtQueueSizePolicy,
+    transport_mmio::VirtIOMmioTransport,
+    transport_pci::PciTransport,
+};
+
+/// 设备遵循virtio 1.0及之后的规范
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
 
 pub enum VirtIOTransport {
     Pci(PciTransport),
@@ -16,6 +43,391 @@ impl VirtIOTransport {
             _ => None,
         }
     }
+
+    /// # 函数的功能
+    /// 复制一个指向同一设备的transport，用于在设备驱动持有原transport期间访问设备状态、重新初始化设备
+    ///
+    /// 注意：pci transport被drop时会复位设备，因此复制出来的transport应当与设备的生命周期一致
+    ///
+    /// ## 返回值
+    /// - Some: 复制得到的transport
+    /// - None: 该transport不支持复制（mmio transport独占其映射的MMIO空间）
+    pub fn try_clone(&self) -> Option<Self> {
+        match self {
+            VirtIOTransport::Pci(transport) => Some(VirtIOTransport::Pci(transport.clone())),
+            VirtIOTransport::Mmio(_) => None,
+        }
+    }
+
+    /// # 函数的功能
+    /// 单独复位一个virtqueue，其他队列保持可用，详见[`super::reset::virtio_reset_queue`]
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 没有协商VIRTIO_F_RING_RESET特性，或者transport不支持
+    pub fn reset_queue(
+        &mut self,
+        queue: u16,
+        reclaim: &mut dyn FnMut() -> VirtqConfig,
+    ) -> Result<(), SystemError> {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.reset_queue(queue, reclaim),
+            VirtIOTransport::Mmio(_) => Err(SystemError::EOPNOTSUPP_OR_ENOTSUP),
+        }
+    }
+
+    /// # 函数的功能
+    /// 读取配置空间，保证读取期间配置没有发生变化
+    ///
+    /// 见[`virtio_read_config_consistent`]。`reader`读到的是设备字节序的原始值，
+    /// 读取单个字段时应当使用会转换字节序的[`Self::config_read`]
+    #[allow(dead_code)]
+    pub fn read_config_consistent<T>(
+        &self,
+        reader: impl FnMut(&Self) -> T,
+    ) -> Result<T, SystemError> {
+        virtio_read_config_consistent(self, reader)
+    }
+
+    /// # 函数的功能
+    /// 从配置空间的`offset`处读取一个`T`，见[`virtio_config_read`]
+    #[allow(dead_code)]
+    pub fn config_read<T: VirtIOEndianField>(&self, offset: usize) -> Result<T, SystemError> {
+        virtio_config_read(self, offset)
+    }
+
+    /// # 函数的功能
+    /// 把`value`写入配置空间的`offset`处，见[`virtio_config_write`]
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): 访问越界，或者`offset`没有按`T`的对齐方式对齐
+    #[allow(dead_code)]
+    pub fn config_write<T: VirtIOEndianField>(
+        &mut self,
+        offset: usize,
+        value: T,
+    ) -> Result<(), SystemError> {
+        virtio_config_write(self, offset, value)
+    }
+
+    /// # 函数的功能
+    /// 复位设备并协商特性，即virtio spec 3.1.1 Driver Requirements: Device Initialization的第1到6步
+    ///
+    /// 返回之后设备处于FEATURES_OK状态，驱动接着设置virtqueue，最后调用[`Transport::finish_init`]。
+    /// 之后的步骤失败时，驱动应当调用[`Self::fail_init`]
+    ///
+    /// ## 参数
+    /// - `supported`: 驱动支持的特性
+    /// - `required`: 驱动必须的特性，设备没有提供时放弃这个设备
+    ///
+    /// ## 返回值
+    /// - Ok(features): 协商得到的特性
+    /// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 设备没有提供`required`中的特性
+    /// - Err(SystemError::EIO): 设备不接受协商得到的特性
+    /// - Err(SystemError::ETIMEDOUT): 设备没有完成复位
+    pub fn negotiate_features(
+        &mut self,
+        supported: u64,
+        required: u64,
+    ) -> Result<u64, SystemError> {
+        virtio_reset_device(self)?;
+        let status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER;
+        self.set_status(status);
+
+        let features = self.read_device_features() & (supported | required);
+        if features & required != required {
+            self.fail_init();
+            return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+        }
+        self.write_driver_features(features);
+        self.set_status(status | DeviceStatus::FEATURES_OK);
+        if !self.get_status().contains(DeviceStatus::FEATURES_OK) {
+            self.fail_init();
+            return Err(SystemError::EIO);
+        }
+        // legacy mmio设备需要知道页的大小才能计算队列的地址，其他transport忽略它
+        self.set_guest_page_size(PAGE_SIZE as u32);
+        Ok(features)
+    }
+
+    /// 初始化设备失败，告诉设备驱动已经放弃了它
+    pub fn fail_init(&mut self) {
+        let status = self.get_status();
+        self.set_status(status | DeviceStatus::FAILED);
+    }
+
+    /// # 函数的功能
+    /// 读取设备支持的最大队列大小，按照驱动的要求选择实际使用的队列大小并写回设备
+    ///
+    /// mmio transport的队列大小在设置队列时写入QueueNum，这里只进行协商
+    ///
+    /// ## 返回值
+    /// - Ok(size): 实际使用的队列大小
+    /// - Err(e): 见[`VirtQueueSizePolicy::negotiate`]
+    pub fn negotiate_queue_size(
+        &mut self,
+        queue: u16,
+        policy: &VirtQueueSizePolicy,
+    ) -> Result<u16, SystemError> {
+        let device_max = self.max_queue_size(queue);
+        let size = policy.negotiate(device_max).inspect_err(|e| {
+            warn!(
+                "virtio: queue {} has invalid max size {}: {:?}",
+                queue, device_max, e
+            );
+        })?;
+        if let VirtIOTransport::Pci(transport) = self {
+            transport.set_queue_size(queue, size);
+        }
+        Ok(size)
+    }
+
+    /// # 函数的功能
+    /// 释放设备的中断向量，在驱动拆除了所有队列之后调用
+    ///
+    /// mmio transport使用平台描述的中断，没有需要释放的向量
+    pub fn release_irq_vectors(&self) {
+        if let VirtIOTransport::Pci(transport) = self {
+            transport.release_irq_vectors();
+        }
+    }
+
+    /// # 函数的功能
+    /// 读取并清零ISR状态寄存器
+    ///
+    /// ## 返回值
+    /// - Some(status): ISR状态
+    /// - None: transport没有ISR状态寄存器(MMIO transport由virtio-drivers在ack_interrupt中处理)
+    #[allow(dead_code)]
+    pub fn read_interrupt_status(&self) -> Option<VirtIOIsrStatus> {
+        self.isr().map(|isr| isr.read())
+    }
+
+    /// 设备的ISR状态寄存器，见[`VirtIOIsr`]
+    pub fn isr(&self) -> Option<VirtIOIsr> {
+        match self {
+            VirtIOTransport::Pci(transport) => Some(transport.isr()),
+            VirtIOTransport::Mmio(_) => None,
+        }
+    }
+
+    /// # 函数的功能
+    /// 读取驱动与设备协商得到的特性
+    ///
+    /// ## 返回值
+    /// - None: transport无法读回协商的结果(MMIO transport)
+    pub fn negotiated_features(&mut self) -> Option<u64> {
+        match self {
+            VirtIOTransport::Pci(transport) => Some(transport.negotiated_features()),
+            VirtIOTransport::Mmio(_) => None,
+        }
+    }
+
+    /// # 函数的功能
+    /// 获取设备的共享内存区域的物理地址和长度
+    ///
+    /// mmio transport暂不支持共享内存区域，总是返回None
+    #[allow(dead_code)]
+    pub fn get_shared_memory_region(&self, id: u8) -> Option<(PhysAddr, usize)> {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.get_shared_memory_region(id),
+            VirtIOTransport::Mmio(_) => None,
+        }
+    }
+
+    /// # 函数的功能
+    /// 把设备的共享内存区域映射到内核中，多次调用返回同一个地址
+    ///
+    /// ## 返回值
+    /// - Ok(vaddr): 区域起始处的虚拟地址
+    /// - Err(SystemError::ENOENT): 设备没有这个区域
+    #[allow(dead_code)]
+    pub fn map_shared_memory_region(&self, id: u8) -> Result<VirtAddr, SystemError> {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.map_shared_memory_region(id),
+            VirtIOTransport::Mmio(_) => Err(SystemError::ENOENT),
+        }
+    }
+
+    /// 设备实际使用的中断类型
+    pub fn irq_type(&self) -> VirtIOIrqType {
+        match self {
+            VirtIOTransport::Pci(transport) => match transport.irq_type() {
+                IrqType::Msix { .. } => VirtIOIrqType::Msix {
+                    vectors: transport.msix_vectors(),
+                },
+                IrqType::Msi { .. } => VirtIOIrqType::Msi,
+                IrqType::Legacy => VirtIOIrqType::Intx,
+                IrqType::Unused => VirtIOIrqType::None,
+            },
+            VirtIOTransport::Mmio(_) => VirtIOIrqType::Platform,
+        }
+    }
+}
+
+bitflags! {
+    /// ISR状态寄存器
+    ///
+    /// 参考 virtio spec 4.1.4.5 ISR status capability
+    pub struct VirtIOIsrStatus: u8 {
+        /// 有virtqueue需要处理
+        const QUEUE = 1 << 0;
+        /// 设备的配置空间发生了变化
+        const CONFIG = 1 << 1;
+    }
+}
+
+/// # 结构功能
+/// 设备的ISR状态寄存器
+///
+/// 不使用MSI-X时，设备通过INTx(可能与其他设备共享)发出中断，
+/// 驱动需要读取这个寄存器来判断中断是否来自自己的设备以及中断的原因。
+/// 读取寄存器会把它清零，并让设备撤销中断
+#[derive(Debug, Clone, Copy)]
+pub struct VirtIOIsr(NonNull<Volatile<u8>>);
+
+unsafe impl Send for VirtIOIsr {}
+unsafe impl Sync for VirtIOIsr {}
+
+impl VirtIOIsr {
+    /// ## Safety
+    ///
+    /// `isr`必须指向设备的ISR状态寄存器，并且在使用期间保持有效
+    pub unsafe fn new(isr: NonNull<Volatile<u8>>) -> Self {
+        Self(isr)
+    }
+
+    /// 读取并清零ISR状态寄存器
+    pub fn read(&self) -> VirtIOIsrStatus {
+        let status = unsafe { self.0.as_ptr().vread() };
+        VirtIOIsrStatus::from_bits_truncate(status)
+    }
+}
+
+/// virtio设备使用的中断类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtIOIrqType {
+    /// PCI MSI-X，`vectors`为给队列使用的中断向量数，见[`virtio_msix_queue_vector`]
+    Msix {
+        vectors: u16,
+    },
+    /// PCI MSI
+    Msi,
+    /// PCI INTx（需要读取ISR状态寄存器来清除中断）
+    Intx,
+    /// 由平台（如设备树）描述的中断，用于MMIO transport
+    Platform,
+    None,
+}
+
+impl VirtIOIrqType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VirtIOIrqType::Msix { .. } => "msix",
+            VirtIOIrqType::Msi => "msi",
+            VirtIOIrqType::Intx => "intx",
+            VirtIOIrqType::Platform => "platform",
+            VirtIOIrqType::None => "none",
+        }
+    }
+
+    /// # 函数的功能
+    /// virtqueue的中断在设备中断向量中的位置
+    ///
+    /// 使用MSI-X时队列按编号轮流使用分配到的中断向量，向量比队列少时多个队列共用一个向量；
+    /// 其他中断类型下所有队列共用一个中断
+    ///
+    /// ## 返回值
+    /// - Some(index): 中断在设备中断向量中的位置
+    /// - None: 该队列不产生中断
+    pub fn queue_vector(&self, queue: u16) -> Option<u16> {
+        match self {
+            VirtIOIrqType::Msix { vectors } => virtio_msix_queue_vector(queue, *vectors),
+            VirtIOIrqType::Msi | VirtIOIrqType::Intx | VirtIOIrqType::Platform => Some(0),
+            VirtIOIrqType::None => None,
+        }
+    }
+
+    /// # 函数的功能
+    /// 计算中断向量的共用程度，即共用同一个中断向量的队列数的最大值
+    ///
+    /// 共用中断向量的队列，中断处理函数需要检查其中的每一个队列
+    ///
+    /// ## 返回值
+    /// 没有队列产生中断时为0
+    pub fn sharing_factor(&self, queues: &[u16]) -> usize {
+        let vectors: Vec<u16> = queues
+            .iter()
+            .filter_map(|&queue| self.queue_vector(queue))
+            .collect();
+        vectors
+            .iter()
+            .map(|v| vectors.iter().filter(|other| *other == v).count())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// # 函数的功能
+/// 计算实际分配给队列的MSI-X中断向量数
+///
+/// 设备的MSI-X表可能比驱动需要的小，此时分配较少的向量，让多个队列共用
+///
+/// ## 参数
+/// - `table_size`: 设备MSI-X表的项数
+/// - `irqs`: 可以使用的中断号的个数
+/// - `wanted`: 驱动希望的向量数，通常为队列数
+pub fn virtio_msix_vectors_granted(table_size: u16, irqs: usize, wanted: u16) -> u16 {
+    let irqs = u16::try_from(irqs).unwrap_or(u16::MAX);
+    table_size.min(irqs).min(wanted)
+}
+
+/// # 函数的功能
+/// 队列使用的MSI-X中断向量，队列按编号轮流使用`vectors`个向量
+///
+/// ## 返回值
+/// - Some(index): 中断向量在设备中断向量中的位置
+/// - None: 没有分配到中断向量
+pub fn virtio_msix_queue_vector(queue: u16, vectors: u16) -> Option<u16> {
+    (vectors != 0).then(|| queue % vectors)
+}
+
+impl VirtIOConfigGeneration for VirtIOTransport {
+    fn config_generation(&self) -> u32 {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.config_generation(),
+            VirtIOTransport::Mmio(transport) => transport.config_generation(),
+        }
+    }
+}
+
+impl VirtIOConfigAccess for VirtIOTransport {
+    fn config_space_len(&self) -> usize {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.config_space_len(),
+            VirtIOTransport::Mmio(transport) => transport.config_space_len(),
+        }
+    }
+
+    fn config_endian(&self) -> VirtIOEndian {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.config_endian(),
+            VirtIOTransport::Mmio(transport) => transport.config_endian(),
+        }
+    }
+
+    unsafe fn config_read_raw(&self, offset: usize, width: usize) -> u32 {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.config_read_raw(offset, width),
+            VirtIOTransport::Mmio(transport) => transport.config_read_raw(offset, width),
+        }
+    }
+
+    unsafe fn config_write_raw(&self, offset: usize, width: usize, value: u32) {
+        match self {
+            VirtIOTransport::Pci(transport) => transport.config_write_raw(offset, width, value),
+            VirtIOTransport::Mmio(transport) => transport.config_write_raw(offset, width, value),
+        }
+    }
 }
 
 impl core::fmt::Debug for VirtIOTransport {
@@ -2,13 +2,19 @@ use core::ptr::NonNull;
 
 use alloc::sync::Arc;
 use fdt::node::FdtNode;
-use log::info;
+use log::{debug, info};
 use system_error::SystemError;
 use virtio_drivers::transport::{
-    mmio::{MmioTransport, VirtIOHeader},
+    mmio::{MmioTransport, MmioVersion, VirtIOHeader},
     Transport,
 };
 
+use super::{
+    config::{config_mmio_read, config_mmio_write, VirtIOConfigAccess, VirtIOConfigGeneration},
+    endian::VirtIOEndian,
+    features::format_virtio_features,
+    mmio_region::MmioRegion,
+};
 use crate::{
     arch::MMArch,
     driver::base::device::DeviceId,
@@ -20,9 +26,22 @@ use crate::{
     },
 };
 
+/// ConfigGeneration寄存器在设备寄存器中的偏移
+///
+/// 参考 virtio spec 4.2.2 MMIO Device Register Layout
+const VIRTIO_MMIO_CONFIG_GENERATION_OFFSET: usize = 0xfc;
+/// 设备配置空间在设备寄存器中的偏移
+const VIRTIO_MMIO_CONFIG_OFFSET: usize = 0x100;
+
 pub struct VirtIOMmioTransport {
     mmio_transport: MmioTransport,
     _mmio_guard: MMIOSpaceGuard,
+    /// 设备树中描述的整个寄存器区域
+    registers: MmioRegion,
+    /// 设备配置空间，长度为寄存器区域的长度减去配置空间的偏移
+    config_space: MmioRegion,
+    /// legacy设备没有ConfigGeneration寄存器
+    has_config_generation: bool,
     irq: HardwareIrqNumber,
     device_id: Arc<DeviceId>,
 }
@@ -35,10 +54,10 @@ impl VirtIOMmioTransport {
             .next()
             .ok_or(SystemError::EINVAL)?;
         let paddr = reg.starting_address as usize;
-        let size = reg.size.unwrap_or(0);
+        let reg_size = reg.size.unwrap_or(0);
         let page_offset = paddr % MMArch::PAGE_SIZE;
         let paddr = paddr - page_offset;
-        let size = page_align_up(size + page_offset);
+        let size = page_align_up(reg_size + page_offset);
         let irq = node
             .interrupts()
             .ok_or(SystemError::EINVAL)?
@@ -54,17 +73,38 @@ impl VirtIOMmioTransport {
         let header = NonNull::new(vaddr.data() as *mut VirtIOHeader).unwrap();
 
         match unsafe { MmioTransport::new(header) } {
-            Ok(mmio_transport) => {
+            Ok(mut mmio_transport) => {
                 info!( "Detected virtio MMIO device with vendor id {:#X}, device type {:?}, version {:?}, hw irq: {}",
                     mmio_transport.vendor_id(),
                     mmio_transport.device_type(),
                     mmio_transport.version(),
                     irq as u32
                 );
+                let features = mmio_transport.read_device_features();
+                debug!(
+                    "virtio MMIO device features {:#018x}: {}",
+                    features,
+                    format_virtio_features(mmio_transport.device_type().into(), features)
+                );
+
+                // Safety: 寄存器区域已经映射，映射由_mmio_guard持有，与transport的生命周期相同
+                let registers = unsafe {
+                    MmioRegion::new(NonNull::new(vaddr.data() as *mut u8).unwrap(), reg_size)
+                };
+                let config_space = registers
+                    .subregion(
+                        VIRTIO_MMIO_CONFIG_OFFSET.min(reg_size),
+                        reg_size.saturating_sub(VIRTIO_MMIO_CONFIG_OFFSET),
+                    )
+                    .unwrap();
+                let has_config_generation = matches!(mmio_transport.version(), MmioVersion::Modern);
 
                 Ok(Self {
                     mmio_transport,
                     _mmio_guard: mmio_guard,
+                    registers,
+                    config_space,
+                    has_config_generation,
                     irq: HardwareIrqNumber::new(irq as u32),
                     device_id,
                 })
@@ -87,6 +127,35 @@ impl VirtIOMmioTransport {
     }
 }
 
+impl VirtIOConfigGeneration for VirtIOMmioTransport {
+    fn config_generation(&self) -> u32 {
+        if !self.has_config_generation {
+            return 0;
+        }
+        self.registers
+            .read::<u32>(VIRTIO_MMIO_CONFIG_GENERATION_OFFSET)
+            .unwrap_or(0)
+    }
+}
+
+impl VirtIOConfigAccess for VirtIOMmioTransport {
+    fn config_space_len(&self) -> usize {
+        self.config_space.len()
+    }
+
+    fn config_endian(&self) -> VirtIOEndian {
+        VirtIOEndian::new(self.mmio_transport.requires_legacy_layout())
+    }
+
+    unsafe fn config_read_raw(&self, offset: usize, width: usize) -> u32 {
+        config_mmio_read(&self.config_space, offset, width)
+    }
+
+    unsafe fn config_write_raw(&self, offset: usize, width: usize, value: u32) {
+        config_mmio_write(&self.config_space, offset, width, value)
+    }
+}
+
 impl Transport for VirtIOMmioTransport {
     fn device_type(&self) -> virtio_drivers::transport::DeviceType {
         self.mmio_transport.device_type()
@@ -1,47 +1,56 @@
 //! PCI transport for VirtIO.
 
 use crate::driver::base::device::DeviceId;
+use crate::driver::pci::config_space::pci_capability_fits;
 use crate::driver::pci::pci::{
     BusDeviceFunction, PciDeviceStructure, PciDeviceStructureGeneralDevice, PciError,
-    PciStandardDeviceBar, PCI_CAP_ID_VNDR,
+    PciStandardDeviceBar, PCI_CAP_ID_VNDR, PCI_DEVICE_LINKEDLIST,
 };
 
-use crate::driver::pci::pci_irq::{IrqCommonMsg, IrqSpecificMsg, PciInterrupt, PciIrqMsg, IRQ};
+use crate::driver::pci::pci_irq::{
+    IrqCommonMsg, IrqSpecificMsg, IrqType, PciInterrupt, PciIrqMsg, IRQ,
+};
 use crate::driver::pci::root::pci_root_0;
 
+use crate::arch::MMArch;
 use crate::exception::IrqNumber;
 
 use crate::libs::volatile::{
     volread, volwrite, ReadOnly, Volatile, VolatileReadable, VolatileWritable, WriteOnly,
 };
-use crate::mm::VirtAddr;
+use crate::mm::{MemoryManagementArch, VirtAddr};
 
 use alloc::string::ToString;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::{
     fmt::{self, Display, Formatter},
     mem::{align_of, size_of},
     ptr::{self, addr_of_mut, NonNull},
 };
+use log::{error, info, warn};
+use system_error::SystemError;
 use virtio_drivers::{
     transport::{DeviceStatus, DeviceType, Transport},
     Error, Hal, PhysAddr,
 };
 
+use super::config::{
+    config_mmio_read, config_mmio_write, VirtIOConfigAccess, VirtIOConfigGeneration,
+};
+use super::guard::SetupGuard;
 use super::irq::DefaultVirtioIrqHandler;
-use super::VIRTIO_VENDOR_ID;
-
-/// The offset to add to a VirtIO device ID to get the corresponding PCI device ID.
-/// PCI Virtio设备的DEVICE_ID 的offset
-const PCI_DEVICE_ID_OFFSET: u16 = 0x1040;
-/// PCI Virtio 设备的DEVICE_ID及其对应的设备类型
-const TRANSITIONAL_NETWORK: u16 = 0x1000;
-const TRANSITIONAL_BLOCK: u16 = 0x1001;
-const TRANSITIONAL_MEMORY_BALLOONING: u16 = 0x1002;
-const TRANSITIONAL_CONSOLE: u16 = 0x1003;
-const TRANSITIONAL_SCSI_HOST: u16 = 0x1004;
-const TRANSITIONAL_ENTROPY_SOURCE: u16 = 0x1005;
-const TRANSITIONAL_9P_TRANSPORT: u16 = 0x1009;
+use super::mmio_region::MmioRegion;
+use super::notify::{
+    virtio_pci_notify_offset, VirtIONotifyRegion, VirtQueueNotifier, VIRTIO_F_NOTIFICATION_DATA,
+};
+use super::queue::VirtqConfig;
+use super::reset::{virtio_reset_queue, VirtIOQueueResetRegister};
+use super::shm::{VirtIOShmCap, VirtIOShmRegions, VIRTIO_PCI_CAP_SHARED_MEMORY_CFG};
+use super::transport::{
+    virtio_msix_queue_vector, virtio_msix_vectors_granted, VirtIOIsr, VirtIOIsrStatus,
+};
+use super::{VirtioDeviceType, VIRTIO_VENDOR_ID};
 
 /// The offset of the bar field within `virtio_pci_cap`.
 const CAP_BAR_OFFSET: u8 = 4;
@@ -61,27 +70,18 @@ const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
 
-/// Virtio设备接收中断的设备号
-const VIRTIO_RECV_VECTOR: IrqNumber = IrqNumber::new(56);
-/// Virtio设备接收中断的设备号的表项号
-const VIRTIO_RECV_VECTOR_INDEX: u16 = 0;
-// 接收的queue号
-const QUEUE_RECEIVE: u16 = 0;
+/// Virtio设备的MSI/MSI-X中断可以使用的中断号
+///
+/// 目前缺少对PCI设备中断号的统一管理，只能使用固定的中断号，因此可以分配的MSI-X向量数不超过这里的个数
+const VIRTIO_PCI_MSI_IRQS: [IrqNumber; 1] = [IrqNumber::new(56)];
+/// 设备没有为队列或配置变化分配MSI-X向量
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
 ///@brief device id 转换为设备类型
 ///@param pci_device_id，device_id
 ///@return DeviceType 对应的设备类型
 fn device_type(pci_device_id: u16) -> DeviceType {
-    match pci_device_id {
-        TRANSITIONAL_NETWORK => DeviceType::Network,
-        TRANSITIONAL_BLOCK => DeviceType::Block,
-        TRANSITIONAL_MEMORY_BALLOONING => DeviceType::MemoryBalloon,
-        TRANSITIONAL_CONSOLE => DeviceType::Console,
-        TRANSITIONAL_SCSI_HOST => DeviceType::ScsiHost,
-        TRANSITIONAL_ENTROPY_SOURCE => DeviceType::EntropySource,
-        TRANSITIONAL_9P_TRANSPORT => DeviceType::_9P,
-        id if id >= PCI_DEVICE_ID_OFFSET => DeviceType::from(id - PCI_DEVICE_ID_OFFSET),
-        _ => DeviceType::Invalid,
-    }
+    let t = VirtioDeviceType::from_pci_device_id(pci_device_id);
+    DeviceType::from(t.device_id() as u16)
 }
 
 /// PCI transport for VirtIO.
@@ -92,17 +92,27 @@ fn device_type(pci_device_id: u16) -> DeviceType {
 pub struct PciTransport {
     device_type: DeviceType,
     /// The bus, device and function identifier for the VirtIO device.
-    _bus_device_function: BusDeviceFunction,
+    bus_device_function: BusDeviceFunction,
     /// The common configuration structure within some BAR.
     common_cfg: NonNull<CommonCfg>,
+    /// common config中用于单独复位队列的字段，旧设备的common config中没有这部分
+    queue_reset_cfg: Option<NonNull<QueueResetCfg>>,
     /// The start of the queue notification region within some BAR.
     notify_region: NonNull<[WriteOnly<u16>]>,
     notify_off_multiplier: u32,
+    /// 按照是否协商了VIRTIO_F_NOTIFICATION_DATA，决定通知设备时写入的数据
+    notifier: VirtQueueNotifier,
     /// The ISR status register within some BAR.
     isr_status: NonNull<Volatile<u8>>,
     /// The VirtIO device-specific configuration within some BAR.
-    config_space: Option<NonNull<[u32]>>,
+    config_space: Option<MmioRegion>,
+    /// 设备的共享内存区域，transport的副本共享同一份映射
+    shm_regions: Arc<VirtIOShmRegions>,
     irq: IrqNumber,
+    /// 设备实际使用的中断类型
+    irq_type: IrqType,
+    /// 分配给队列的MSI-X中断向量数，不使用MSI-X时为0
+    msix_vectors: u16,
     dev_id: Arc<DeviceId>,
 }
 
@@ -121,48 +131,52 @@ impl PciTransport {
         device: &mut PciDeviceStructureGeneralDevice,
         dev_id: Arc<DeviceId>,
     ) -> Result<Self, VirtioPciError> {
-        let irq = VIRTIO_RECV_VECTOR;
         let header = &device.common_header;
         let bus_device_function = header.bus_device_function;
         if header.vendor_id != VIRTIO_VENDOR_ID {
             return Err(VirtioPciError::InvalidVendorId(header.vendor_id));
         }
         let device_type = device_type(header.device_id);
+        // 没有capability链表的设备(例如只实现了legacy接口的设备)无法通过modern接口访问
+        if device
+            .capabilities()
+            .map_or(true, |mut capabilities| capabilities.next().is_none())
+        {
+            return Err(VirtioPciError::MissingCapabilities);
+        }
         // Find the PCI capabilities we need.
         let mut common_cfg: Option<VirtioCapabilityInfo> = None;
         let mut notify_cfg: Option<VirtioCapabilityInfo> = None;
         let mut notify_off_multiplier = 0;
         let mut isr_cfg = None;
         let mut device_cfg = None;
+        let mut shm_caps = Vec::new();
         device.bar_ioremap().unwrap()?;
         device.enable_master();
-        let standard_device = device.as_standard_device_mut().unwrap();
-        // 目前缺少对PCI设备中断号的统一管理，所以这里需要指定一个中断号。不能与其他中断重复
-        let irq_vector = standard_device.irq_vector_mut().unwrap();
-        irq_vector.push(irq);
-        standard_device
-            .irq_init(IRQ::PCI_IRQ_MSIX | IRQ::PCI_IRQ_MSI)
-            .ok_or(VirtioPciError::UnableToInitIrq)?;
-        // 中断相关信息
-        let msg = PciIrqMsg {
-            irq_common_message: IrqCommonMsg::init_from(
-                0,
-                "Virtio_IRQ".to_string(),
-                &DefaultVirtioIrqHandler,
-                dev_id.clone(),
-            ),
-            irq_specific_message: IrqSpecificMsg::msi_default(),
-        };
-        standard_device.irq_install(msg)?;
-        standard_device.irq_enable(true)?;
+        let (irq_type, irq, msix_vectors) = Self::setup_irq(device, &dev_id)?;
+        // 之后的步骤失败时，释放已经安装的中断，否则中断向量会一直被占用
+        let mut device = SetupGuard::new(device, |device| {
+            device.irq_uninstall().ok();
+            device.irq_vector_mut().unwrap().clear();
+        });
+        if matches!(device_type, DeviceType::Network) && matches!(irq_type, IrqType::Msix { .. }) {
+            // 让网卡各个队列的中断在不同的CPU上处理，使收发包的处理保持在本地
+            if let Err(e) = device.irq_spread_affinity() {
+                warn!(
+                    "virtio pci: failed to spread irq affinity for device {:?}: {}",
+                    dev_id, e
+                );
+            }
+        }
         //device_capability为迭代器，遍历其相当于遍历所有的cap空间
-        for capability in device.capabilities().unwrap() {
+        for capability in device.capabilities().into_iter().flatten() {
             if capability.id != PCI_CAP_ID_VNDR {
                 continue;
             }
             let cap_len = capability.private_header as u8;
             let cfg_type = (capability.private_header >> 8) as u8;
-            if cap_len < 16 {
+            // capability超出配置空间时，读取其中的字段会越界
+            if cap_len < 16 || !pci_capability_fits(capability.offset, cap_len.into()) {
                 continue;
             }
             let struct_info = VirtioCapabilityInfo {
@@ -197,14 +211,34 @@ impl PciTransport {
                 VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
                     device_cfg = Some(struct_info);
                 }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG => {
+                    shm_caps.extend(VirtIOShmCap::parse(capability.offset, |offset| {
+                        pci_root_0().read_config(bus_device_function, offset.into())
+                    }));
+                }
                 _ => {}
             }
         }
 
-        let common_cfg = get_bar_region::<_>(
-            &device.standard_device_bar,
-            &common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?,
-        )?;
+        let common_cfg = common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?;
+        let common_cfg_len = common_cfg.length as usize;
+        let common_cfg: NonNull<CommonCfg> =
+            get_bar_region::<_>(&device.standard_device_bar, &common_cfg)?;
+        let queue_reset_cfg =
+            if common_cfg_len >= size_of::<CommonCfg>() + size_of::<QueueResetCfg>() {
+                // Safe because the region was checked to be large enough above.
+                Some(unsafe {
+                    NonNull::new_unchecked(
+                        common_cfg
+                            .as_ptr()
+                            .cast::<u8>()
+                            .add(size_of::<CommonCfg>())
+                            .cast::<QueueResetCfg>(),
+                    )
+                })
+            } else {
+                None
+            };
 
         let notify_cfg = notify_cfg.ok_or(VirtioPciError::MissingNotifyConfig)?;
         if notify_off_multiplier % 2 != 0 {
@@ -219,25 +253,180 @@ impl PciTransport {
             &isr_cfg.ok_or(VirtioPciError::MissingIsrConfig)?,
         )?;
         let config_space = if let Some(device_cfg) = device_cfg {
-            Some(get_bar_region_slice::<_>(
-                &device.standard_device_bar,
-                &device_cfg,
-            )?)
+            let base = get_bar_region::<u8>(&device.standard_device_bar, &device_cfg)?;
+            // Safety: get_bar_region已经检查区域位于映射好的BAR内
+            Some(unsafe { MmioRegion::new(base, device_cfg.length as usize) })
         } else {
             None
         };
+        let mut shm_regions = VirtIOShmRegions::new();
+        for cap in shm_caps.iter() {
+            let bar = |bar: u8| {
+                device
+                    .standard_device_bar
+                    .get_bar(bar)
+                    .ok()?
+                    .memory_address_size()
+            };
+            if let Err(e) = shm_regions.add(cap, bar) {
+                warn!(
+                    "virtio pci: ignore invalid shared memory region {:?} of device {:?}: {:?}",
+                    cap, dev_id, e
+                );
+            }
+        }
+        device.commit();
         Ok(Self {
             device_type,
-            _bus_device_function: bus_device_function,
+            bus_device_function: bus_device_function,
             common_cfg,
+            queue_reset_cfg,
             notify_region,
             notify_off_multiplier,
+            notifier: VirtQueueNotifier::new(),
             isr_status,
             config_space,
+            shm_regions: Arc::new(shm_regions),
             irq,
+            irq_type,
+            msix_vectors,
             dev_id,
         })
     }
+
+    /// 为设备配置中断
+    ///
+    /// 依次尝试MSI-X、MSI和INTx，使用第一个能够成功安装的中断类型。
+    ///
+    /// 使用MSI-X时尽量为每个队列分配一个向量。设备的MSI-X表或者可用的中断号不够时，
+    /// 分配较少的向量，让多个队列共用（见[`virtio_msix_queue_vector`]），而不是放弃MSI-X
+    ///
+    /// ## 返回值
+    ///
+    /// 成功时返回选中的中断类型、第一个中断号，以及实际分配的MSI-X向量数(不使用MSI-X时为0)
+    fn setup_irq(
+        device: &mut PciDeviceStructureGeneralDevice,
+        dev_id: &Arc<DeviceId>,
+    ) -> Result<(IrqType, IrqNumber, u16), VirtioPciError> {
+        for flag in [IRQ::PCI_IRQ_MSIX, IRQ::PCI_IRQ_MSI, IRQ::PCI_IRQ_LEGACY] {
+            let irq_type = match device.irq_init(flag) {
+                Some(irq_type) => irq_type,
+                None => continue,
+            };
+
+            // 目前缺少对PCI设备中断号的统一管理，所以MSI/MSI-X需要指定中断号。不能与其他中断重复
+            let (irqs, msix_vectors) = match irq_type {
+                IrqType::Legacy => match device.legacy_irq_number() {
+                    Some(irq) => (vec![irq], 0),
+                    None => continue,
+                },
+                IrqType::Msix { irq_max_num, .. } => {
+                    let vectors = virtio_msix_vectors_granted(
+                        irq_max_num,
+                        VIRTIO_PCI_MSI_IRQS.len(),
+                        irq_max_num,
+                    );
+                    if vectors == 0 {
+                        continue;
+                    }
+                    (VIRTIO_PCI_MSI_IRQS[..vectors as usize].to_vec(), vectors)
+                }
+                _ => (vec![VIRTIO_PCI_MSI_IRQS[0]], 0),
+            };
+            let irq = irqs[0];
+            *device.irq_vector_mut().unwrap() = irqs.clone();
+
+            // 中断相关信息
+            let irq_specific_message = match irq_type {
+                IrqType::Legacy => IrqSpecificMsg::Legacy,
+                _ => IrqSpecificMsg::msi_default(),
+            };
+            // 共用一个向量的队列由同一次中断处理，设备的handle_irq需要检查它的所有队列
+            let r = (0..irqs.len() as u16)
+                .try_for_each(|index| {
+                    let msg = PciIrqMsg {
+                        irq_common_message: IrqCommonMsg::init_from(
+                            index,
+                            "Virtio_IRQ".to_string(),
+                            &DefaultVirtioIrqHandler,
+                            dev_id.clone(),
+                        ),
+                        irq_specific_message: irq_specific_message.clone(),
+                    };
+                    device.irq_install(msg).map(|_| ())
+                })
+                .and_then(|_| device.irq_enable(true));
+            match r {
+                Ok(_) => {
+                    if let IrqType::Msix { irq_max_num, .. } = irq_type {
+                        if msix_vectors < irq_max_num {
+                            info!(
+                                "virtio pci: device {:?} offers {} msi-x vectors, using {}; queues will share vectors",
+                                dev_id, irq_max_num, msix_vectors
+                            );
+                        }
+                    }
+                    return Ok((irq_type, irq, msix_vectors));
+                }
+                Err(e) => {
+                    warn!(
+                        "virtio pci: failed to setup irq with type {:?} for device {:?}: {}, trying next one",
+                        irq_type, dev_id, e
+                    );
+                    device.irq_uninstall().ok();
+                }
+            }
+        }
+
+        device.irq_vector_mut().unwrap().clear();
+        return Err(VirtioPciError::UnableToInitIrq);
+    }
+
+    /// 设备实际使用的中断类型
+    pub fn irq_type(&self) -> IrqType {
+        self.irq_type
+    }
+
+    /// 分配给队列的MSI-X中断向量数，不使用MSI-X时为0
+    pub fn msix_vectors(&self) -> u16 {
+        self.msix_vectors
+    }
+
+    /// 读取ISR状态寄存器，读取之后寄存器被清零，设备撤销INTx中断
+    pub fn read_interrupt_status(&self) -> VirtIOIsrStatus {
+        self.isr().read()
+    }
+
+    /// 设备的ISR状态寄存器，中断处理时不需要持有transport就可以读取
+    pub fn isr(&self) -> VirtIOIsr {
+        // Safe because the isr pointer is valid and we checked in get_bar_region that it was aligned.
+        unsafe { VirtIOIsr::new(self.isr_status) }
+    }
+
+    /// # 函数的功能
+    /// 获取设备的共享内存区域
+    ///
+    /// ## 返回值
+    /// 区域的物理地址和长度，设备没有这个id的区域时返回None
+    pub fn get_shared_memory_region(&self, id: u8) -> Option<(crate::mm::PhysAddr, usize)> {
+        self.shm_regions.get(id)
+    }
+
+    /// 把设备的共享内存区域映射到内核中，见[`VirtIOShmRegions::map`]
+    pub fn map_shared_memory_region(&self, id: u8) -> Result<VirtAddr, SystemError> {
+        self.shm_regions.map(id)
+    }
+
+    /// 从common config中读取队列的通知地址在通知区域中的偏移量
+    fn queue_notify_offset(&mut self, queue: u16) -> usize {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        let queue_notify_off = unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            volread!(self.common_cfg, queue_notify_off)
+        };
+        virtio_pci_notify_offset(queue_notify_off, self.notify_off_multiplier)
+    }
 }
 
 impl Transport for PciTransport {
@@ -258,6 +447,12 @@ impl Transport for PciTransport {
     }
 
     fn write_driver_features(&mut self, driver_features: u64) {
+        // 通知数据是32位的，每个队列的通知地址都需要4字节对齐才能协商VIRTIO_F_NOTIFICATION_DATA
+        let mut device_features = self.read_device_features();
+        if self.notify_off_multiplier % 4 != 0 {
+            device_features &= !VIRTIO_F_NOTIFICATION_DATA;
+        }
+        let driver_features = self.notifier.negotiate(device_features, driver_features);
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         unsafe {
@@ -280,16 +475,11 @@ impl Transport for PciTransport {
     }
 
     fn notify(&mut self, queue: u16) {
-        // Safe because the common config and notify region pointers are valid and we checked in
-        // get_bar_region that they were aligned.
-        unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            // TODO: Consider caching this somewhere (per queue).
-            let queue_notify_off = volread!(self.common_cfg, queue_notify_off);
-
-            let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
-            let index = offset_bytes / size_of::<u16>();
-            addr_of_mut!((*self.notify_region.as_ptr())[index]).vwrite(queue);
+        let mut region = PciNotifyRegion(self.notify_region);
+        if self.notifier.notify(&mut region, queue).is_err() {
+            // 队列没有通过queue_set()设置，没有缓存通知地址
+            let offset = self.queue_notify_offset(queue);
+            region.write_notify_u16(offset, queue);
         }
     }
 
@@ -321,24 +511,31 @@ impl Transport for PciTransport {
         driver_area: PhysAddr,
         device_area: PhysAddr,
     ) {
+        self.write_queue_config(
+            queue,
+            &VirtqConfig {
+                size: size as u16,
+                desc: descriptors,
+                driver: driver_area,
+                device: device_area,
+            },
+        );
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volwrite!(self.common_cfg, queue_size, size as u16);
-            volwrite!(self.common_cfg, queue_desc, descriptors as u64);
-            volwrite!(self.common_cfg, queue_driver, driver_area as u64);
-            volwrite!(self.common_cfg, queue_device, device_area as u64);
-            // 这里设置队列中断对应的中断项
-            if queue == QUEUE_RECEIVE {
-                volwrite!(self.common_cfg, queue_msix_vector, VIRTIO_RECV_VECTOR_INDEX);
-                let vector = volread!(self.common_cfg, queue_msix_vector);
-                if vector != VIRTIO_RECV_VECTOR_INDEX {
-                    panic!("Vector set failed");
-                }
-            }
             volwrite!(self.common_cfg, queue_enable, 1);
         }
+
+        // 缓存队列的通知地址，之后通知设备时不必再访问common config
+        let offset = self.queue_notify_offset(queue);
+        let avail_ring = MMArch::phys_2_virt(crate::mm::PhysAddr::new(driver_area)).unwrap();
+        unsafe {
+            self.notifier.set_queue(
+                queue,
+                offset,
+                NonNull::new(avail_ring.data() as *mut u8).unwrap(),
+            )
+        };
     }
 
     fn queue_unset(&mut self, queue: u16) {
@@ -351,6 +548,7 @@ impl Transport for PciTransport {
             volwrite!(self.common_cfg, queue_driver, 0);
             volwrite!(self.common_cfg, queue_device, 0);
         }
+        self.notifier.remove_queue(queue);
     }
 
     fn queue_used(&mut self, queue: u16) -> bool {
@@ -363,17 +561,12 @@ impl Transport for PciTransport {
     }
 
     fn ack_interrupt(&mut self) -> bool {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
-        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
-        let isr_status = unsafe { self.isr_status.as_ptr().vread() };
-        // TODO: Distinguish between queue interrupt and device configuration interrupt.
-        isr_status & 0x3 != 0
+        !self.read_interrupt_status().is_empty()
     }
 
     fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
-        if let Some(config_space) = self.config_space {
-            if size_of::<T>() > config_space.len() * size_of::<u32>() {
+        if let Some(config_space) = &self.config_space {
+            if size_of::<T>() > config_space.len() {
                 Err(Error::ConfigSpaceTooSmall)
             } else if align_of::<T>() > 4 {
                 // Panic as this should only happen if the driver is written incorrectly.
@@ -382,9 +575,7 @@ impl Transport for PciTransport {
                     align_of::<T>()
                 );
             } else {
-                // TODO: Use NonNull::as_non_null_ptr once it is stable.
-                let config_space_ptr = NonNull::new(config_space.as_ptr() as *mut u32).unwrap();
-                Ok(config_space_ptr.cast())
+                Ok(config_space.as_ptr().cast())
             }
         } else {
             Err(Error::ConfigSpaceMissing)
@@ -392,6 +583,29 @@ impl Transport for PciTransport {
     }
 }
 
+/// PCI设备的通知区域
+struct PciNotifyRegion(NonNull<[WriteOnly<u16>]>);
+
+impl VirtIONotifyRegion for PciNotifyRegion {
+    fn write_notify_u16(&mut self, offset: usize, value: u16) {
+        let index = offset / size_of::<u16>();
+        // Safe because the notify region pointer is valid and the index is bounds-checked.
+        unsafe { addr_of_mut!((*self.0.as_ptr())[index]).vwrite(value) };
+    }
+
+    fn write_notify_u32(&mut self, offset: usize, value: u32) {
+        assert!(offset % size_of::<u32>() == 0);
+        assert!(offset + size_of::<u32>() <= self.0.len() * size_of::<u16>());
+        // Safe because the write is aligned and within the notify region.
+        unsafe {
+            (self.0.as_ptr() as *mut u8)
+                .add(offset)
+                .cast::<u32>()
+                .write_volatile(value)
+        };
+    }
+}
+
 impl Drop for PciTransport {
     fn drop(&mut self) {
         // Reset the device when the transport is dropped.
@@ -421,6 +635,159 @@ struct CommonCfg {
     queue_device: Volatile<u64>,
 }
 
+impl VirtIOConfigGeneration for PciTransport {
+    fn config_generation(&self) -> u32 {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        unsafe { volread!(self.common_cfg, config_generation).into() }
+    }
+}
+
+impl VirtIOConfigAccess for PciTransport {
+    fn config_space_len(&self) -> usize {
+        self.config_space.as_ref().map(|c| c.len()).unwrap_or(0)
+    }
+
+    unsafe fn config_read_raw(&self, offset: usize, width: usize) -> u32 {
+        // config_space_len()为0时调用者不会访问配置空间
+        config_mmio_read(self.config_space.as_ref().unwrap(), offset, width)
+    }
+
+    unsafe fn config_write_raw(&self, offset: usize, width: usize, value: u32) {
+        config_mmio_write(self.config_space.as_ref().unwrap(), offset, width, value)
+    }
+}
+
+/// virtio 1.2在common config末尾新增的字段，紧跟在[`CommonCfg`]之后
+#[allow(dead_code)]
+#[repr(C)]
+struct QueueResetCfg {
+    queue_notify_data: ReadOnly<u16>,
+    queue_reset: Volatile<u16>,
+}
+
+impl VirtIOQueueResetRegister for PciTransport {
+    fn negotiated_features(&mut self) -> u64 {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        unsafe {
+            volwrite!(self.common_cfg, driver_feature_select, 0);
+            let mut features = volread!(self.common_cfg, driver_feature) as u64;
+            volwrite!(self.common_cfg, driver_feature_select, 1);
+            features |= (volread!(self.common_cfg, driver_feature) as u64) << 32;
+            features
+        }
+    }
+
+    fn has_queue_reset(&self) -> bool {
+        self.queue_reset_cfg.is_some()
+    }
+
+    fn select_queue(&mut self, queue: u16) {
+        unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+        }
+    }
+
+    fn read_queue_reset(&self) -> u16 {
+        match self.queue_reset_cfg {
+            Some(cfg) => unsafe { volread!(cfg, queue_reset) },
+            None => 0,
+        }
+    }
+
+    fn write_queue_reset(&mut self, value: u16) {
+        if let Some(cfg) = self.queue_reset_cfg {
+            unsafe {
+                volwrite!(cfg, queue_reset, value);
+            }
+        }
+    }
+
+    fn read_queue_enable(&self) -> u16 {
+        unsafe { volread!(self.common_cfg, queue_enable) }
+    }
+
+    fn write_queue_enable(&mut self, value: u16) {
+        unsafe {
+            volwrite!(self.common_cfg, queue_enable, value);
+        }
+    }
+
+    fn write_queue_config(&mut self, queue: u16, config: &VirtqConfig) {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            volwrite!(self.common_cfg, queue_size, config.size);
+            volwrite!(self.common_cfg, queue_desc, config.desc as u64);
+            volwrite!(self.common_cfg, queue_driver, config.driver as u64);
+            volwrite!(self.common_cfg, queue_device, config.device as u64);
+            // 这里设置队列中断对应的中断项。只有使用MSI-X时才需要设置，
+            // 使用MSI或INTx时，设备通过ISR状态寄存器告知中断原因
+            if let Some(vector) = virtio_msix_queue_vector(queue, self.msix_vectors) {
+                volwrite!(self.common_cfg, queue_msix_vector, vector);
+                let read_back = volread!(self.common_cfg, queue_msix_vector);
+                if read_back != vector {
+                    // 设备无法为队列分配这个向量，队列不会产生中断
+                    error!(
+                        "virtio pci: device {:?} rejected msi-x vector {} for queue {}",
+                        self.dev_id, vector, queue
+                    );
+                    volwrite!(self.common_cfg, queue_msix_vector, VIRTIO_MSI_NO_VECTOR);
+                }
+            }
+        }
+    }
+}
+
+impl PciTransport {
+    /// # 函数的功能
+    /// 单独复位一个virtqueue，其他队列保持可用
+    ///
+    /// 需要设备与驱动协商了VIRTIO_F_RING_RESET特性，详见[`virtio_reset_queue`]
+    pub fn reset_queue(
+        &mut self,
+        queue: u16,
+        reclaim: &mut dyn FnMut() -> VirtqConfig,
+    ) -> Result<(), SystemError> {
+        virtio_reset_queue(self, queue, reclaim)
+    }
+
+    /// 释放设备安装的中断向量(MSI-X/MSI)，见[`virtio_pci_release_irq_vectors`]
+    pub fn release_irq_vectors(&self) {
+        virtio_pci_release_irq_vectors(self.bus_device_function);
+    }
+
+    /// # 函数的功能
+    /// 把协商得到的队列大小写回设备
+    ///
+    /// 写入之后读取queue_size得到的是写入的值，而不再是设备支持的最大值，直到设备被复位
+    pub fn set_queue_size(&mut self, queue: u16, size: u16) {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            volwrite!(self.common_cfg, queue_size, size);
+        }
+    }
+}
+
+/// # 函数的功能
+/// 释放virtio pci设备在创建transport时安装的中断向量
+///
+/// 调用之前设备需要已经复位，并且中断处理函数已经被释放。设备已经被移除时什么也不做
+pub fn virtio_pci_release_irq_vectors(bdf: BusDeviceFunction) {
+    PCI_DEVICE_LINKEDLIST.with_device_mut(bdf, |device| {
+        if let Some(device) = device.as_standard_device_mut() {
+            device.irq_uninstall().ok();
+            if let Some(vectors) = device.irq_vector_mut() {
+                vectors.clear();
+            }
+        }
+    });
+}
+
 /// Information about a VirtIO structure within some BAR, as provided by a `virtio_pci_cap`.
 /// cfg空间在哪个bar的多少偏移处，长度多少
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -439,6 +806,8 @@ struct VirtioCapabilityInfo {
 pub enum VirtioPciError {
     /// PCI device vender ID was not the VirtIO vendor ID.
     InvalidVendorId(u16),
+    /// The device has no capability list (e.g. a legacy-only device).
+    MissingCapabilities,
     /// No valid `VIRTIO_PCI_CAP_COMMON_CFG` capability was found.
     MissingCommonConfig,
     /// No valid `VIRTIO_PCI_CAP_NOTIFY_CFG` capability was found.
@@ -477,6 +846,7 @@ impl Display for VirtioPciError {
                 "PCI device vender ID {:#06x} was not the VirtIO vendor ID {:#06x}.",
                 vendor_id, VIRTIO_VENDOR_ID
             ),
+            Self::MissingCapabilities => write!(f, "The device has no capability list."),
             Self::UnableToInitIrq => write!(f, "Unable to find capability such as MSIX or MSI."),
             Self::MissingCommonConfig => write!(
                 f,
@@ -517,6 +887,19 @@ impl From<PciError> for VirtioPciError {
     }
 }
 
+/// VirtioPciError到SystemError的转换，用于向设备探测的调用者报告错误
+impl From<VirtioPciError> for SystemError {
+    fn from(error: VirtioPciError) -> Self {
+        match error {
+            VirtioPciError::InvalidVendorId(_) => SystemError::ENODEV,
+            VirtioPciError::MissingCapabilities => SystemError::EOPNOTSUPP_OR_ENOTSUP,
+            VirtioPciError::UnableToInitIrq => SystemError::ENOSYS,
+            VirtioPciError::BarGetVaddrFailed => SystemError::ENOMEM,
+            _ => SystemError::EINVAL,
+        }
+    }
+}
+
 /// @brief 获取虚拟地址并将其转化为对应类型的指针
 /// @param device_bar 存储bar信息的结构体 struct_info 存储cfg空间的位置信息
 /// @return Result<NonNull<T>, VirtioPciError> 成功则返回对应类型的指针，失败则返回Error
@@ -531,7 +914,7 @@ fn get_bar_region<T>(
     if bar_address == 0 {
         return Err(VirtioPciError::BarNotAllocated(struct_info.bar));
     }
-    if struct_info.offset + struct_info.length > bar_size
+    if u64::from(struct_info.offset) + u64::from(struct_info.length) > bar_size
         || size_of::<T>() > struct_info.length as usize
     {
         return Err(VirtioPciError::BarOffsetOutOfRange);
@@ -570,3 +953,51 @@ fn get_bar_region_slice<T>(
 fn nonnull_slice_from_raw_parts<T>(data: NonNull<T>, len: usize) -> NonNull<[T]> {
     NonNull::new(ptr::slice_from_raw_parts_mut(data.as_ptr(), len)).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::{
+        pci::{pci::Status, synthetic::synthetic_pci_structure},
+        virtio::virtio_impl::HalImpl,
+    };
+
+    /// 构造一个virtio-net设备，Status寄存器和capability指针由调用者给出
+    fn virtio_structure(
+        status: Status,
+        capabilities_pointer: u8,
+    ) -> PciDeviceStructureGeneralDevice {
+        let bdf = BusDeviceFunction {
+            bus: 0xff,
+            device: 0,
+            function: 0,
+        };
+        let mut device =
+            synthetic_pci_structure(bdf, VIRTIO_VENDOR_ID, 0x1041, 0x020000, &[]).unwrap();
+        device.common_header.status = status.bits();
+        device.capabilities_pointer = capabilities_pointer;
+        device
+    }
+
+    #[test]
+    fn test_device_without_capabilities() {
+        let cases = [
+            // Capabilities List位没有置位，指针是无效的值
+            (Status::empty(), 0x40),
+            (Status::empty(), 0),
+            (Status::CAPABILITIES_LIST, 0),
+        ];
+        for (status, capabilities_pointer) in cases {
+            let mut device = virtio_structure(status, capabilities_pointer);
+            assert_eq!(device.capabilities().map(|c| c.count()), Some(0));
+
+            let dev_id = DeviceId::new(None, Some("virtio-nocap".to_string())).unwrap();
+            let r = PciTransport::new::<HalImpl>(&mut device, dev_id);
+            let Err(e) = r else {
+                panic!("transport created for a device without capabilities");
+            };
+            assert!(matches!(e, VirtioPciError::MissingCapabilities));
+            assert_eq!(SystemError::from(e), SystemError::EOPNOTSUPP_OR_ENOTSUP);
+        }
+    }
+}
@@ -1,105 +1,235 @@
 use super::mmio::virtio_probe_mmio;
-use super::transport_pci::PciTransport;
+use super::router::virtio_device_router;
+use super::transport_pci::{virtio_pci_release_irq_vectors, PciTransport};
 use super::virtio_impl::HalImpl;
+use crate::dev_err_ratelimited;
+use crate::driver::base::dev_log::DevLogCategory;
 use crate::driver::base::device::bus::Bus;
 use crate::driver::base::device::{Device, DeviceId};
-use crate::driver::block::virtio_blk::virtio_blk;
-use crate::driver::net::virtio_net::virtio_net;
-use crate::driver::pci::pci::{
-    get_pci_device_structures_mut_by_vendor_id, PciDeviceStructure,
-    PciDeviceStructureGeneralDevice, PCI_DEVICE_LINKEDLIST,
-};
+use crate::driver::pci::address::PciAddress;
+use crate::driver::pci::dev_id::PciDeviceID;
+use crate::driver::pci::pci::{BusDeviceFunction, PciDeviceStructure, PCI_DEVICE_LINKEDLIST};
 use crate::driver::pci::subsys::pci_bus;
 use crate::driver::virtio::transport::VirtIOTransport;
-use crate::libs::rwlock::RwLockWriteGuard;
+use crate::driver::virtio::{VirtioDeviceType, VIRTIO_PCI_DEVICE_IDS};
 
-use alloc::string::String;
+use alloc::string::ToString;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use alloc::{boxed::Box, collections::LinkedList};
-use log::{debug, error, warn};
-use virtio_drivers::transport::{DeviceType, Transport};
+use log::{error, info, warn};
+use system_error::SystemError;
 
-///@brief 寻找并加载所有virtio设备的驱动（目前只有virtio-net，但其他virtio设备也可添加）
-pub fn virtio_probe() {
+/// 一个virtio设备的探测结果
+#[derive(Debug)]
+pub struct VirtioProbeResult {
+    pub dev_id: Arc<DeviceId>,
+    /// 设备类型，创建transport失败时无法得知
+    pub device_type: Option<VirtioDeviceType>,
+    /// 设备初始化的结果
+    pub result: Result<Arc<dyn Device>, SystemError>,
+}
+
+///@brief 寻找并加载所有virtio设备的驱动
+///
+/// @return 每个被发现的virtio设备的初始化结果
+pub fn virtio_probe() -> Vec<VirtioProbeResult> {
+    let mut results = Vec::new();
     #[cfg(not(target_arch = "riscv64"))]
-    virtio_probe_pci();
-    virtio_probe_mmio();
+    results.extend(virtio_probe_pci());
+    results.extend(virtio_probe_mmio());
+
+    for (device_type, failed, total) in virtio_probe_summary(&results) {
+        if failed == 0 {
+            info!("virtio: {} {:?} device(s) initialized", total, device_type);
+        } else {
+            warn!(
+                "virtio: {} of {} {:?} device(s) failed to init",
+                failed, total, device_type
+            );
+        }
+    }
+    results
 }
 
 #[allow(dead_code)]
-fn virtio_probe_pci() {
-    let mut list = PCI_DEVICE_LINKEDLIST.write();
-    let virtio_list = virtio_device_search(&mut list);
-    for virtio_device in virtio_list {
-        let dev_id = virtio_device.common_header.device_id;
-        let dev_id = DeviceId::new(None, Some(format!("{dev_id}"))).unwrap();
-        match PciTransport::new::<HalImpl>(virtio_device, dev_id.clone()) {
-            Ok(mut transport) => {
-                debug!(
-                    "Detected virtio PCI device with device type {:?}, features {:#018x}",
-                    transport.device_type(),
-                    transport.read_device_features(),
-                );
+fn virtio_probe_pci() -> Vec<VirtioProbeResult> {
+    let mut results = Vec::new();
+    // 不持有PCI设备链表的锁初始化设备：驱动初始化过程中可能需要再次访问链表（例如设置中断）
+    for bdf in virtio_device_search() {
+        let created = PCI_DEVICE_LINKEDLIST
+            .with_device_mut(bdf, |device| {
+                let device = device.as_standard_device_mut()?;
+                // 用设备的地址作为设备ID：同一种virtio设备可能有多个
+                let dev_id = DeviceId::new(None, Some(device.address().to_string())).unwrap();
+                Some((dev_id.clone(), PciTransport::new::<HalImpl>(device, dev_id)))
+            })
+            .flatten();
+        // 设备在搜索之后被移除了
+        let (dev_id, transport) = match created {
+            Some(created) => created,
+            None => continue,
+        };
+        match transport {
+            Ok(transport) => {
                 let transport = VirtIOTransport::Pci(transport);
-                // 这里暂时通过设备名称在sysfs中查找设备，但是我感觉用设备ID更好
+                // pci设备在sysfs中以地址命名
                 let bus = pci_bus() as Arc<dyn Bus>;
-                let name: String = virtio_device.common_header.bus_device_function.into();
-                let pci_raw_device = bus.find_device_by_name(name.as_str());
-                virtio_device_init(transport, dev_id, pci_raw_device);
+                let name = PciAddress::from(bdf).to_string();
+                let pci_raw_device = bus.find_device_by_name(&name);
+                let device_type = VirtioDeviceType::from_transport(&transport);
+                let result = virtio_device_init(transport, dev_id.clone(), pci_raw_device);
+                if result.is_err() {
+                    // transport已经随着失败的初始化被释放(设备已复位)，这里释放它安装的中断
+                    virtio_pci_release_irq_vectors(bdf);
+                }
+                results.push(VirtioProbeResult {
+                    dev_id,
+                    device_type: Some(device_type),
+                    result,
+                });
             }
             Err(err) => {
                 error!("Pci transport create failed because of error: {}", err);
+                results.push(VirtioProbeResult {
+                    dev_id,
+                    device_type: None,
+                    result: Err(err.into()),
+                });
             }
         }
     }
+    results
 }
 
-///@brief 为virtio设备寻找对应的驱动进行初始化
+/// # 函数的功能
+/// 为virtio设备寻找对应的驱动进行初始化
+///
+/// 初始化函数由驱动通过[`virtio_device_router`]按照设备类型注册
+///
+/// ## 返回值
+/// - Ok(device): 初始化完成的设备
+/// - Err(SystemError::EOPNOTSUPP_OR_ENOTSUP): 已识别该设备类型，但是没有驱动注册
+/// - Err(SystemError::ENODEV): 无法识别的设备类型
+/// - Err(e): 驱动初始化设备失败
 pub(super) fn virtio_device_init(
     transport: VirtIOTransport,
     dev_id: Arc<DeviceId>,
     dev_parent: Option<Arc<dyn Device>>,
-) {
-    match transport.device_type() {
-        DeviceType::Block => virtio_blk(transport, dev_id, dev_parent),
-        DeviceType::GPU => {
-            warn!("Not support virtio_gpu device for now");
+) -> Result<Arc<dyn Device>, SystemError> {
+    let device_type = VirtioDeviceType::from_transport(&transport);
+    match virtio_device_router().route(device_type) {
+        Ok(init) => {
+            let r = init(transport, dev_id.clone(), dev_parent.clone());
+            if let Err(e) = &r {
+                match &dev_parent {
+                    Some(parent) => dev_err_ratelimited!(
+                        DevLogCategory::Virtio,
+                        parent,
+                        "{:?} device init failed: {:?}",
+                        device_type,
+                        e
+                    ),
+                    None => error!(
+                        "virtio device {:?} ({:?}) init failed: {:?}",
+                        dev_id, device_type, e
+                    ),
+                }
+            }
+            r
         }
-        DeviceType::Input => {
-            warn!("Not support virtio_input device for now");
+        Err(SystemError::ENODEV) => {
+            warn!(
+                "Unrecognized virtio device: {:?} (device id: {})",
+                device_type,
+                device_type.device_id()
+            );
+            Err(SystemError::ENODEV)
         }
-        DeviceType::Network => virtio_net(transport, dev_id, dev_parent),
-        t => {
-            warn!("Unrecognized virtio device: {:?}", t);
+        Err(e) => {
+            warn!(
+                "Not support virtio device {:?} (device id: {}) for now",
+                device_type,
+                device_type.device_id()
+            );
+            Err(e)
         }
     }
 }
 
-/// # virtio_device_search - 在给定的PCI设备列表中搜索符合特定标准的virtio设备
-///
-/// 该函数搜索一个PCI设备列表，找到所有由特定厂商ID（0x1AF4）和设备ID范围（0x1000至0x103F）定义的virtio设备。
+/// # 函数的功能
+/// 按设备类型统计探测结果
 ///
-/// ## 参数
+/// ## 返回值
+/// 按设备类型首次出现的顺序排列的(设备类型, 失败数量, 总数量)，无法得知类型的设备不计入统计
+pub fn virtio_probe_summary(
+    results: &[VirtioProbeResult],
+) -> Vec<(VirtioDeviceType, usize, usize)> {
+    virtio_count_by_type(
+        results
+            .iter()
+            .filter_map(|r| r.device_type.map(|t| (t, r.result.is_ok()))),
+    )
+}
+
+fn virtio_count_by_type(
+    results: impl Iterator<Item = (VirtioDeviceType, bool)>,
+) -> Vec<(VirtioDeviceType, usize, usize)> {
+    let mut summary: Vec<(VirtioDeviceType, usize, usize)> = Vec::new();
+    for (device_type, ok) in results {
+        let index = match summary.iter().position(|(t, _, _)| *t == device_type) {
+            Some(index) => index,
+            None => {
+                summary.push((device_type, 0, 0));
+                summary.len() - 1
+            }
+        };
+        let entry = &mut summary[index];
+        if !ok {
+            entry.1 += 1;
+        }
+        entry.2 += 1;
+    }
+    summary
+}
+
+/// # virtio_device_search - 在PCI设备链表中搜索符合特定标准的virtio设备
 ///
-/// - list: &'a mut RwLockWriteGuard<'_, LinkedList<Box<dyn PciDeviceStructure>>> - 一个可写的PCI设备结构列表的互斥锁。
+/// 该函数搜索PCI设备链表，找到所有ID在[`VIRTIO_PCI_DEVICE_IDS`]中的virtio设备（包括transitional设备与modern设备）。
 ///
 /// ## 返回值
 ///
-/// 返回一个包含所有找到的virtio设备的数组
-fn virtio_device_search<'a>(
-    list: &'a mut RwLockWriteGuard<'_, LinkedList<Box<dyn PciDeviceStructure>>>,
-) -> Vec<&'a mut PciDeviceStructureGeneralDevice> {
-    let mut virtio_list = Vec::new();
-    let result = get_pci_device_structures_mut_by_vendor_id(list, 0x1AF4);
+/// 返回所有找到的virtio设备的位置。返回时链表的锁已经释放，访问设备需要通过
+/// [`crate::driver::pci::pci::PciDeviceLinkedList::with_device_mut`]
+fn virtio_device_search() -> Vec<BusDeviceFunction> {
+    PCI_DEVICE_LINKEDLIST.bus_device_functions(|device| {
+        let header = device.common_header();
+        let id = PciDeviceID::new(header.vendor_id, header.device_id);
+        device.as_standard_device().is_some()
+            && VIRTIO_PCI_DEVICE_IDS
+                .iter()
+                .any(|ids| ids.general_match(id))
+    })
+}
 
-    for device in result {
-        let standard_device = device.as_standard_device_mut().unwrap();
-        let header = &standard_device.common_header;
-        if header.device_id >= 0x1000 && header.device_id <= 0x103F {
-            virtio_list.push(standard_device);
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    return virtio_list;
+    #[test]
+    fn test_count_by_type() {
+        let results = [
+            (VirtioDeviceType::Block, true),
+            (VirtioDeviceType::Network, true),
+            (VirtioDeviceType::Block, false),
+            (VirtioDeviceType::Gpu, false),
+        ];
+        assert_eq!(
+            virtio_count_by_type(results.into_iter()),
+            [
+                (VirtioDeviceType::Block, 1, 2),
+                (VirtioDeviceType::Network, 0, 1),
+                (VirtioDeviceType::Gpu, 1, 1),
+            ]
+        );
+    }
 }
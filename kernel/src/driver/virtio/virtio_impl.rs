@@ -4,46 +4,156 @@ use crate::arch::MMArch;
 
 use crate::mm::kernel_mapper::KernelMapper;
 use crate::mm::page::{page_manager_lock_irqsave, EntryFlags};
+use crate::mm::pin::page_pin_set_lock_irqsave;
 use crate::mm::{
     allocator::page_frame::{
         allocate_page_frames, deallocate_page_frames, PageFrameCount, PhysPageFrame,
     },
     MemoryManagementArch, PhysAddr, VirtAddr,
 };
-use core::ptr::NonNull;
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use log::error;
+use system_error::SystemError;
 use virtio_drivers::{BufferDirection, Hal, PAGE_SIZE};
 
-pub struct HalImpl;
-unsafe impl Hal for HalImpl {
+use super::dma::{dma_alloc_within, VirtIODmaMask};
+
+/// 之后还有多少次DMA内存分配需要模拟失败
+static DMA_ALLOC_INJECTED_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// # 函数的功能
+/// 让之后的`count`次DMA内存分配失败，用于测试设备在内存不足时的初始化流程
+#[allow(dead_code)]
+pub fn virtio_dma_inject_alloc_failures(count: usize) {
+    DMA_ALLOC_INJECTED_FAILURES.store(count, Ordering::SeqCst);
+}
+
+/// 消耗一次注入的分配失败，返回本次分配是否应当失败
+fn dma_alloc_injected_failure() -> bool {
+    DMA_ALLOC_INJECTED_FAILURES
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+/// virtio-drivers使用的页数转换为实际分配的物理页数
+fn dma_page_count(pages: usize) -> PageFrameCount {
+    PageFrameCount::new(
+        ((pages * PAGE_SIZE + MMArch::PAGE_SIZE - 1) / MMArch::PAGE_SIZE).next_power_of_two(),
+    )
+}
+
+/// # 函数的功能
+/// 分配用于DMA的内存页，清零并映射为不可缓存
+///
+/// 分配得到的页位于`mask`范围内，会被固定，在释放之前不会被balloon回收或者迁移，见[`crate::mm::pin`]
+///
+/// ## 返回值
+/// - Ok((paddr, vaddr)): 内存的物理地址和虚拟地址
+/// - Err(SystemError::ENOMEM): 物理页不足，或者没有分配到`mask`范围内的物理页
+/// - Err(e): 修改页面属性失败，已经分配的物理页会被释放
+fn virtio_dma_alloc(pages: usize, mask: u64) -> Result<(PhysAddr, VirtAddr), SystemError> {
+    if dma_alloc_injected_failure() {
+        return Err(SystemError::ENOMEM);
+    }
+    let page_count = dma_page_count(pages);
+    let (paddr, count) = dma_alloc_within(
+        mask,
+        page_count.bytes(),
+        || {
+            unsafe { allocate_page_frames(page_count) }
+                .map(|(paddr, count)| (paddr.data() as u64, count))
+        },
+        |paddr, count| unsafe {
+            deallocate_page_frames(
+                PhysPageFrame::new(PhysAddr::new(paddr as usize)),
+                count,
+                &mut page_manager_lock_irqsave(),
+            )
+        },
+    )
+    .map(|(paddr, count)| (PhysAddr::new(paddr as usize), count))
+    .ok_or(SystemError::ENOMEM)?;
+    let free = || unsafe {
+        deallocate_page_frames(
+            PhysPageFrame::new(paddr),
+            count,
+            &mut page_manager_lock_irqsave(),
+        )
+    };
+
+    let virt = match unsafe { MMArch::phys_2_virt(paddr) } {
+        Some(virt) => virt,
+        None => {
+            free();
+            return Err(SystemError::EFAULT);
+        }
+    };
+    // 清空这块区域，防止出现脏数据
+    unsafe { core::ptr::write_bytes(virt.data() as *mut u8, 0, count.data() * MMArch::PAGE_SIZE) };
+
+    let dma_flags: EntryFlags<MMArch> = EntryFlags::mmio_flags();
+    let remapped = {
+        let mut kernel_mapper = KernelMapper::lock();
+        kernel_mapper
+            .as_mut()
+            .and_then(|mapper| unsafe { mapper.remap(virt, dma_flags) })
+    };
+    match remapped {
+        Some(flusher) => flusher.flush(),
+        None => {
+            free();
+            return Err(SystemError::EFAULT);
+        }
+    }
+    page_pin_set_lock_irqsave().pin(PhysPageFrame::new(paddr), count);
+    Ok((paddr, virt))
+}
+
+/// # 结构功能
+/// 按照设备声明的DMA掩码分配和共享内存的Hal
+///
+/// - `COHERENT_BITS`: 一致性DMA内存(virtqueue的ring等)能使用的地址位数
+/// - `STREAMING_BITS`: 流式DMA缓冲区能使用的地址位数
+///
+/// 设备通过选择这两个参数声明自己的DMA掩码，见[`super::dma`]
+pub struct DmaMaskedHal<const COHERENT_BITS: u32, const STREAMING_BITS: u32>;
+
+impl<const COHERENT_BITS: u32, const STREAMING_BITS: u32>
+    DmaMaskedHal<COHERENT_BITS, STREAMING_BITS>
+{
+    pub const DMA_MASK: VirtIODmaMask = VirtIODmaMask::new(COHERENT_BITS, STREAMING_BITS);
+}
+
+/// 默认的Hal，设备的两种DMA都可以使用64位地址
+pub type HalImpl = DmaMaskedHal<64, 64>;
+
+unsafe impl<const COHERENT_BITS: u32, const STREAMING_BITS: u32> Hal
+    for DmaMaskedHal<COHERENT_BITS, STREAMING_BITS>
+{
     /// @brief 申请用于DMA的内存页
     /// @param pages 页数（4k一页）
     /// @return PhysAddr 获得的内存页的初始物理地址
+    ///
+    /// 分配失败时返回物理地址0，virtio-drivers会把它当作Error::DmaError返回给设备的初始化流程，
+    /// 因此内存不足时只有当前设备初始化失败
+    ///
+    /// 这里分配的内存由驱动和设备长期共享，按照一致性DMA的掩码分配
     fn dma_alloc(
         pages: usize,
         _direction: BufferDirection,
     ) -> (virtio_drivers::PhysAddr, NonNull<u8>) {
-        let page_num = PageFrameCount::new(
-            ((pages * PAGE_SIZE + MMArch::PAGE_SIZE - 1) / MMArch::PAGE_SIZE).next_power_of_two(),
-        );
-        unsafe {
-            let (paddr, count) =
-                allocate_page_frames(page_num).expect("VirtIO Impl: alloc page failed");
-            let virt = MMArch::phys_2_virt(paddr).unwrap();
-            // 清空这块区域，防止出现脏数据
-            core::ptr::write_bytes(virt.data() as *mut u8, 0, count.data() * MMArch::PAGE_SIZE);
-
-            let dma_flags: EntryFlags<MMArch> = EntryFlags::mmio_flags();
-
-            let mut kernel_mapper = KernelMapper::lock();
-            let kernel_mapper = kernel_mapper.as_mut().unwrap();
-            let flusher = kernel_mapper
-                .remap(virt, dma_flags)
-                .expect("VirtIO Impl: remap failed");
-            flusher.flush();
-            return (
-                paddr.data(),
-                NonNull::new(MMArch::phys_2_virt(paddr).unwrap().data() as _).unwrap(),
-            );
+        match virtio_dma_alloc(pages, Self::DMA_MASK.coherent) {
+            Ok((paddr, vaddr)) => (paddr.data(), NonNull::new(vaddr.data() as *mut u8).unwrap()),
+            Err(e) => {
+                error!(
+                    "VirtIO Impl: failed to alloc {} dma page(s): {:?}",
+                    pages, e
+                );
+                (0, NonNull::dangling())
+            }
         }
     }
     /// @brief 释放用于DMA的内存页
@@ -54,33 +164,49 @@ unsafe impl Hal for HalImpl {
         vaddr: NonNull<u8>,
         pages: usize,
     ) -> i32 {
-        let page_count = PageFrameCount::new(
-            ((pages * PAGE_SIZE + MMArch::PAGE_SIZE - 1) / MMArch::PAGE_SIZE).next_power_of_two(),
-        );
+        let page_count = dma_page_count(pages);
+        let frame = PhysPageFrame::new(PhysAddr::new(paddr));
 
         // 恢复页面属性
         let vaddr = VirtAddr::new(vaddr.as_ptr() as usize);
-        let mut kernel_mapper = KernelMapper::lock();
-        let kernel_mapper = kernel_mapper.as_mut().unwrap();
-        let flusher = kernel_mapper
-            .remap(vaddr, kernel_page_flags(vaddr))
-            .expect("VirtIO Impl: remap failed");
-        flusher.flush();
+        let remapped = {
+            let mut kernel_mapper = KernelMapper::lock();
+            kernel_mapper
+                .as_mut()
+                .and_then(|mapper| mapper.remap(vaddr, kernel_page_flags(vaddr)))
+        };
+        match remapped {
+            Some(flusher) => flusher.flush(),
+            None => {
+                // 页面仍然是不可缓存的，不能还给页分配器，只能泄漏
+                error!(
+                    "VirtIO Impl: failed to restore page flags of dma memory at {:?}",
+                    vaddr
+                );
+                return -1;
+            }
+        }
 
-        unsafe {
-            deallocate_page_frames(
-                PhysPageFrame::new(PhysAddr::new(paddr)),
-                page_count,
-                &mut page_manager_lock_irqsave(),
+        // 设备已经不再使用这块内存，解除固定之后才能还给页分配器
+        if let Err(e) = page_pin_set_lock_irqsave().unpin(frame, page_count) {
+            error!(
+                "VirtIO Impl: dma memory at {:?} was not pinned: {:?}",
+                vaddr, e
             );
         }
+        unsafe {
+            deallocate_page_frames(frame, page_count, &mut page_manager_lock_irqsave());
+        }
         return 0;
     }
     /// @brief mmio物理地址转换为虚拟地址，不需要使用
     /// @param paddr 起始物理地址
     /// @return NonNull<u8> 虚拟地址的指针
     unsafe fn mmio_phys_to_virt(paddr: virtio_drivers::PhysAddr, _size: usize) -> NonNull<u8> {
-        NonNull::new((MMArch::phys_2_virt(PhysAddr::new(paddr))).unwrap().data() as _).unwrap()
+        // 这个接口无法返回错误，设备给出的地址不合法时只能panic
+        let vaddr = MMArch::phys_2_virt(PhysAddr::new(paddr))
+            .expect("VirtIO Impl: mmio address out of the linear mapping");
+        NonNull::new(vaddr.data() as _).unwrap()
     }
     /// @brief 与真实物理设备共享
     /// @param buffer 要共享的buffer _direction：设备到driver或driver到设备
@@ -92,7 +218,17 @@ unsafe impl Hal for HalImpl {
         let vaddr = VirtAddr::new(buffer.as_ptr() as *mut u8 as usize);
         //debug!("virt:{:x}", vaddr);
         // Nothing to do, as the host already has access to all memory.
-        return MMArch::virt_2_phys(vaddr).unwrap().data();
+        let paddr = MMArch::virt_2_phys(vaddr)
+            .expect("VirtIO Impl: shared buffer is not in the linear mapping")
+            .data();
+        // 没有bounce buffer可以复制，设备访问不到的缓冲区只能panic，不能让设备写到别的内存上
+        assert!(
+            Self::DMA_MASK.streaming_fits(paddr as u64, buffer.len()),
+            "VirtIO Impl: shared buffer at {:#x} is beyond the streaming dma mask {:#x}",
+            paddr,
+            Self::DMA_MASK.streaming
+        );
+        return paddr;
     }
     /// @brief 停止共享（让主机可以访问全部内存的话什么都不用做）
     unsafe fn unshare(
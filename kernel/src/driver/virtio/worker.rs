@@ -0,0 +1,186 @@
+//! virtio设备的工作线程
+//!
+//! 一些工作不适合在中断上下文中完成，例如超出中断预算的virtio-net接收处理、控制队列命令。
+//! 驱动可以通过[`VirtIOWorkerManager::spawn`]为设备创建一个内核线程并提供要执行的工作，
+//! 中断处理函数调用[`VirtIOWorker::signal`]唤醒线程。工作执行之前的多次唤醒会被合并为一次。
+//!
+//! 设备被移除时(见[`super::sysfs::VirtIODeviceManager::device_remove`])它的工作线程被停止，
+//! 移除操作等待线程退出之后才返回。
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{boxed::Box, format, sync::Arc, vec::Vec};
+use hashbrown::HashMap;
+use system_error::SystemError;
+
+use crate::{
+    driver::base::device::DeviceId,
+    libs::{spinlock::SpinLock, wait_queue::WaitQueue},
+    process::{
+        kthread::{KernelThreadClosure, KernelThreadMechanism},
+        Pid, ProcessControlBlock,
+    },
+};
+
+use super::VirtIODevice;
+
+lazy_static! {
+    static ref VIRTIO_WORKER_MANAGER: VirtIOWorkerManager = VirtIOWorkerManager::new();
+}
+
+#[inline(always)]
+pub fn virtio_worker_manager() -> &'static VirtIOWorkerManager {
+    &VIRTIO_WORKER_MANAGER
+}
+
+#[derive(Debug, Default)]
+struct VirtIOWorkerState {
+    /// 有还没有执行的唤醒
+    pending: bool,
+    /// 线程正在或者已经被停止
+    stopping: bool,
+}
+
+/// # 结构功能
+/// 设备的一个工作线程
+pub struct VirtIOWorker {
+    state: SpinLock<VirtIOWorkerState>,
+    wait_queue: WaitQueue,
+    work: Box<dyn Fn() + Send + Sync>,
+    /// 工作被执行的次数
+    runs: AtomicUsize,
+    /// 工作线程，线程退出之后为None
+    pcb: SpinLock<Option<Arc<ProcessControlBlock>>>,
+}
+
+impl core::fmt::Debug for VirtIOWorker {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VirtIOWorker")
+            .field("pid", &self.pid())
+            .field("runs", &self.runs())
+            .finish()
+    }
+}
+
+impl VirtIOWorker {
+    /// # 函数的功能
+    /// 唤醒工作线程执行一次工作，可以在中断上下文中调用
+    ///
+    /// 线程已经被停止时什么也不做
+    pub fn signal(&self) {
+        let mut state = self.state.lock_irqsave();
+        if state.stopping {
+            return;
+        }
+        state.pending = true;
+        drop(state);
+        self.wait_queue.wakeup(None);
+    }
+
+    /// 工作被执行的次数
+    pub fn runs(&self) -> usize {
+        self.runs.load(Ordering::SeqCst)
+    }
+
+    /// 工作线程的pid，线程退出之后为None
+    pub fn pid(&self) -> Option<Pid> {
+        self.pcb.lock_irqsave().as_ref().map(|pcb| pcb.pid())
+    }
+
+    /// 线程是否已经退出
+    pub fn is_stopped(&self) -> bool {
+        self.state.lock_irqsave().stopping && self.pcb.lock_irqsave().is_none()
+    }
+
+    /// # 函数的功能
+    /// 停止工作线程，并等待它退出。正在执行的工作会先完成，还没有执行的唤醒被丢弃
+    ///
+    /// 可以重复调用。不能在工作线程中调用，否则会一直等待自己退出
+    pub fn stop(&self) {
+        self.state.lock_irqsave().stopping = true;
+        self.wait_queue.wakeup_all(None);
+        let pcb = self.pcb.lock_irqsave().take();
+        if let Some(pcb) = pcb {
+            KernelThreadMechanism::stop(&pcb).ok();
+        }
+    }
+
+    fn thread_main(&self) -> i32 {
+        loop {
+            let mut state = self.state.lock_irqsave();
+            if state.stopping {
+                return 0;
+            }
+            if !state.pending {
+                self.wait_queue.sleep_unlock_spinlock(state);
+                continue;
+            }
+            state.pending = false;
+            drop(state);
+
+            (self.work)();
+            self.runs.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// # 结构功能
+/// 记录每个virtio设备的工作线程，设备被移除时停止它们
+pub struct VirtIOWorkerManager {
+    workers: SpinLock<HashMap<Arc<DeviceId>, Vec<Arc<VirtIOWorker>>>>,
+}
+
+impl VirtIOWorkerManager {
+    fn new() -> Self {
+        Self {
+            workers: SpinLock::new(HashMap::new()),
+        }
+    }
+
+    /// # 函数的功能
+    /// 为设备创建一个工作线程，线程名为`<设备名>-worker`
+    ///
+    /// ## 参数
+    /// - `dev`: 工作线程所属的设备
+    /// - `work`: 每次被唤醒时在线程中执行的工作
+    ///
+    /// ## 返回值
+    /// - Ok(worker): 中断处理函数通过`worker.signal()`唤醒线程
+    /// - Err(SystemError::EPERM): 创建内核线程失败
+    pub fn spawn(
+        &self,
+        dev: &Arc<dyn VirtIODevice>,
+        work: impl Fn() + Send + Sync + 'static,
+    ) -> Result<Arc<VirtIOWorker>, SystemError> {
+        let worker = Arc::new(VirtIOWorker {
+            state: SpinLock::new(VirtIOWorkerState::default()),
+            wait_queue: WaitQueue::default(),
+            work: Box::new(work),
+            runs: AtomicUsize::new(0),
+            pcb: SpinLock::new(None),
+        });
+        let w = worker.clone();
+        let pcb = KernelThreadMechanism::create_and_run(
+            KernelThreadClosure::EmptyClosure((Box::new(move || w.thread_main()), ())),
+            format!("{}-worker", dev.device_name()),
+        )
+        .ok_or(SystemError::EPERM)?;
+        *worker.pcb.lock_irqsave() = Some(pcb);
+
+        self.workers
+            .lock_irqsave()
+            .entry(dev.dev_id().clone())
+            .or_default()
+            .push(worker.clone());
+        Ok(worker)
+    }
+
+    /// # 函数的功能
+    /// 停止设备的所有工作线程，等待它们退出
+    pub fn stop_device(&self, dev_id: &Arc<DeviceId>) {
+        let workers = self.workers.lock_irqsave().remove(dev_id);
+        for worker in workers.into_iter().flatten() {
+            worker.stop();
+        }
+    }
+}
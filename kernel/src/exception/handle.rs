@@ -247,6 +247,7 @@ fn handle_irq_event<'a>(
 
     drop(desc_inner_guard);
 
+    irq_desc.kstat_incr_irqs();
     let _r = do_handle_irq_event(irq_desc);
 
     let desc_inner_guard = irq_desc.inner();
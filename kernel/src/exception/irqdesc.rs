@@ -1,7 +1,7 @@
 use core::{
     any::Any,
     fmt::Debug,
-    sync::atomic::{AtomicI64, Ordering},
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
 };
 
 use alloc::{
@@ -81,6 +81,8 @@ pub struct IrqDesc {
     kobj_state: LockedKObjectState,
     /// 当前描述符内正在运行的中断线程数
     threads_active: AtomicI64,
+    /// 中断发生的次数
+    kstat_irqs: AtomicU64,
 }
 
 impl IrqDesc {
@@ -120,6 +122,7 @@ impl IrqDesc {
             handler: RwLock::new(None),
             kobj_state: LockedKObjectState::new(Some(KObjectState::INITIALIZED)),
             threads_active: AtomicI64::new(0),
+            kstat_irqs: AtomicU64::new(0),
         };
         let irq_desc = Arc::new(irq_desc);
         irq_desc.irq_data().set_irq_desc(Arc::downgrade(&irq_desc));
@@ -146,6 +149,19 @@ impl IrqDesc {
         self.threads_active.fetch_sub(1, Ordering::SeqCst)
     }
 
+    /// 中断发生的次数
+    pub fn kstat_irqs(&self) -> u64 {
+        self.kstat_irqs.load(Ordering::Relaxed)
+    }
+
+    /// 中断发生时由中断处理流程调用，记录一次中断
+    ///
+    /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/kernel/irq/internals.h#263
+    #[inline(always)]
+    pub fn kstat_incr_irqs(&self) {
+        self.kstat_irqs.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn set_handler(&self, handler: &'static dyn IrqFlowHandler) {
         self.chip_bus_lock();
         let mut guard = self.handler.write_irqsave();
@@ -0,0 +1,637 @@
+//! 通过FUSE协议访问的文件系统
+//!
+//! 文件系统本身不关心请求如何到达服务端，只通过[`FuseConn`]收发请求。目前只支持只读访问：
+//! 查找、获取属性、读取文件以及列出目录。
+
+use core::{any::Any, mem::size_of};
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use log::warn;
+use system_error::SystemError;
+
+use crate::{
+    driver::base::device::device_number::DeviceNumber,
+    filesystem::vfs::{
+        core::generate_inode_id, file::FilePrivateData, syscall::ModeType, utils::DName,
+        FileSystem, FileType, FsInfo, IndexNode, InodeId, Magic, Metadata, SuperBlock,
+    },
+    libs::{mutex::Mutex, spinlock::SpinLockGuard},
+    time::PosixTimeSpec,
+};
+
+use super::protocol::{
+    fuse_build_request, fuse_init_in, fuse_parse_dirents, fuse_parse_init_reply, fuse_parse_reply,
+    FuseAttr, FuseAttrOut, FuseConnInfo, FuseEntryOut, FuseForgetIn, FuseGetattrIn, FuseOpcode,
+    FuseOpenIn, FuseOpenOut, FuseOutHeader, FusePod, FuseReadIn, FuseReleaseIn, FUSE_ROOT_ID,
+};
+
+/// 文件名的最大长度
+const FUSE_MAX_NAMELEN: usize = 255;
+const FUSE_BLOCK_SIZE: u64 = 4096;
+/// 驱动希望服务端预读的最大字节数
+const FUSE_MAX_READAHEAD: u32 = 128 * 1024;
+/// 一次READ请求最多读取的字节数
+const FUSE_MAX_READ: usize = 128 * 1024;
+/// 一次READDIR请求的回复缓冲区大小
+const FUSE_READDIR_BUF_SIZE: usize = 4096;
+
+/// # trait功能
+/// 到FUSE服务端的连接，例如virtio-fs设备的请求队列
+///
+/// 请求由调用者构造好，连接只负责把请求交给服务端，并等待回复
+pub trait FuseConn: Send + Sync + core::fmt::Debug {
+    /// # 函数的功能
+    /// 发送请求，并等待服务端把回复写入`reply`
+    ///
+    /// ## 返回值
+    /// - Ok(len): 服务端写入的字节数
+    fn request(&self, req: &[u8], reply: &mut [u8]) -> Result<usize, SystemError>;
+
+    /// # 函数的功能
+    /// 发送一个没有回复的请求(FUSE_FORGET)
+    fn request_noreply(&self, req: &[u8]) -> Result<(), SystemError>;
+
+    /// 为请求分配一个在这个连接上唯一的编号
+    fn next_unique(&self) -> u64;
+}
+
+/// # 函数的功能
+/// 发送一个请求并检查回复
+///
+/// ## 参数
+/// - `reply_len`: 回复中除了头部以外最多有多少字节
+///
+/// ## 返回值
+/// - Ok(payload): 回复中头部之后的数据
+fn fuse_call(
+    conn: &dyn FuseConn,
+    opcode: FuseOpcode,
+    nodeid: u64,
+    args: &[&[u8]],
+    reply_len: usize,
+) -> Result<Vec<u8>, SystemError> {
+    let unique = conn.next_unique();
+    let req = fuse_build_request(opcode, unique, nodeid, args);
+    let mut reply = vec![0u8; size_of::<FuseOutHeader>() + reply_len];
+    let len = conn.request(&req, &mut reply)?;
+    let payload = fuse_parse_reply(unique, &reply[..len.min(reply.len())])?;
+    Ok(payload.to_vec())
+}
+
+/// # 函数的功能
+/// 进行FUSE_INIT握手，协商协议版本。连接上的其他请求都必须在握手完成之后发送
+///
+/// ## 返回值
+/// - Ok(info): 协商得到的连接参数
+/// - Err(SystemError::EPROTONOSUPPORT): 服务端的协议版本不兼容
+pub fn fuse_handshake(conn: &dyn FuseConn) -> Result<FuseConnInfo, SystemError> {
+    let init = fuse_init_in(FUSE_MAX_READAHEAD);
+    let payload = fuse_call(
+        conn,
+        FuseOpcode::Init,
+        0,
+        &[init.as_bytes()],
+        size_of::<super::protocol::FuseInitOut>(),
+    )?;
+    fuse_parse_init_reply(&payload, FUSE_MAX_READAHEAD)
+}
+
+/// 把服务端返回的属性转换为VFS的元数据
+fn fuse_attr_to_metadata(attr: &FuseAttr, inode_id: InodeId) -> Metadata {
+    let mode = ModeType::from_bits_truncate(attr.mode);
+    let file_type = match mode & ModeType::S_IFMT {
+        ModeType::S_IFDIR => FileType::Dir,
+        ModeType::S_IFLNK => FileType::SymLink,
+        ModeType::S_IFCHR => FileType::CharDevice,
+        ModeType::S_IFBLK => FileType::BlockDevice,
+        ModeType::S_IFIFO => FileType::Pipe,
+        ModeType::S_IFSOCK => FileType::Socket,
+        _ => FileType::File,
+    };
+    Metadata {
+        dev_id: 0,
+        inode_id,
+        size: attr.size as i64,
+        blk_size: attr.blksize as usize,
+        blocks: attr.blocks as usize,
+        atime: PosixTimeSpec::new(attr.atime as i64, attr.atimensec as i64),
+        mtime: PosixTimeSpec::new(attr.mtime as i64, attr.mtimensec as i64),
+        ctime: PosixTimeSpec::new(attr.ctime as i64, attr.ctimensec as i64),
+        file_type,
+        mode,
+        nlinks: attr.nlink as usize,
+        uid: attr.uid as usize,
+        gid: attr.gid as usize,
+        raw_dev: DeviceNumber::from(attr.rdev),
+    }
+}
+
+/// # 结构功能
+/// 一个FUSE连接上挂载的文件系统
+#[derive(Debug)]
+pub struct FuseFS {
+    conn: Arc<dyn FuseConn>,
+    info: FuseConnInfo,
+    /// 文件系统类型的名称，例如"virtiofs"
+    name: &'static str,
+    root: Arc<FuseInode>,
+    super_block: SuperBlock,
+}
+
+impl FuseFS {
+    /// # 函数的功能
+    /// 在已经完成握手的连接上创建文件系统，并获取根目录的属性
+    ///
+    /// ## 参数
+    /// - `conn`: 到服务端的连接
+    /// - `info`: 握手得到的连接参数，见[`fuse_handshake`]
+    /// - `name`: 文件系统类型的名称
+    pub fn new(
+        conn: Arc<dyn FuseConn>,
+        info: FuseConnInfo,
+        name: &'static str,
+    ) -> Result<Arc<Self>, SystemError> {
+        let getattr = FuseGetattrIn::default();
+        let payload = fuse_call(
+            conn.as_ref(),
+            FuseOpcode::Getattr,
+            FUSE_ROOT_ID,
+            &[getattr.as_bytes()],
+            size_of::<FuseAttrOut>(),
+        )?;
+        let attr = FuseAttrOut::read_from(&payload)?.attr;
+        if ModeType::from_bits_truncate(attr.mode) & ModeType::S_IFMT != ModeType::S_IFDIR {
+            return Err(SystemError::ENOTDIR);
+        }
+
+        let fs = Arc::new_cyclic(|fs_ref: &Weak<FuseFS>| {
+            let root = Arc::new_cyclic(|self_ref| {
+                FuseInode::new(
+                    fs_ref.clone(),
+                    FUSE_ROOT_ID,
+                    self_ref.clone(),
+                    self_ref.clone(),
+                    DName::default(),
+                    &attr,
+                )
+            });
+            FuseFS {
+                conn,
+                info,
+                name,
+                root,
+                super_block: SuperBlock::new(
+                    Magic::FUSE_MAGIC,
+                    FUSE_BLOCK_SIZE,
+                    FUSE_MAX_NAMELEN as u64,
+                ),
+            }
+        });
+        Ok(fs)
+    }
+
+    #[allow(dead_code)]
+    pub fn conn_info(&self) -> &FuseConnInfo {
+        &self.info
+    }
+
+    fn call(
+        &self,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        args: &[&[u8]],
+        reply_len: usize,
+    ) -> Result<Vec<u8>, SystemError> {
+        fuse_call(self.conn.as_ref(), opcode, nodeid, args, reply_len)
+    }
+
+    fn forget(&self, nodeid: u64, nlookup: u64) {
+        let forget = FuseForgetIn { nlookup };
+        let unique = self.conn.next_unique();
+        let req = fuse_build_request(FuseOpcode::Forget, unique, nodeid, &[forget.as_bytes()]);
+        if let Err(e) = self.conn.request_noreply(&req) {
+            warn!("fuse: forget node {} failed: {:?}", nodeid, e);
+        }
+    }
+}
+
+impl FileSystem for FuseFS {
+    fn root_inode(&self) -> Arc<dyn IndexNode> {
+        self.root.clone()
+    }
+
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            blk_dev_id: 0,
+            max_name_len: FUSE_MAX_NAMELEN,
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn super_block(&self) -> SuperBlock {
+        self.super_block.clone()
+    }
+}
+
+/// # 结构功能
+/// 服务端的一个节点
+///
+/// 每个被LOOKUP到的节点都会被缓存在父目录中，节点被释放时通过FUSE_FORGET告诉服务端
+#[derive(Debug)]
+pub struct FuseInode {
+    fs: Weak<FuseFS>,
+    nodeid: u64,
+    parent: Weak<FuseInode>,
+    self_ref: Weak<FuseInode>,
+    name: DName,
+    inode_id: InodeId,
+    /// 访问服务端期间需要持有锁，因此使用可以睡眠的锁
+    inner: Mutex<InnerFuseInode>,
+}
+
+#[derive(Debug)]
+struct InnerFuseInode {
+    metadata: Metadata,
+    children: BTreeMap<DName, Arc<FuseInode>>,
+    /// 服务端对这个节点的LOOKUP计数
+    nlookup: u64,
+    /// 读取文件时使用的句柄，第一次读取时打开
+    fh: Option<u64>,
+}
+
+impl FuseInode {
+    fn new(
+        fs: Weak<FuseFS>,
+        nodeid: u64,
+        parent: Weak<FuseInode>,
+        self_ref: Weak<FuseInode>,
+        name: DName,
+        attr: &FuseAttr,
+    ) -> Self {
+        let inode_id = generate_inode_id();
+        Self {
+            fs,
+            nodeid,
+            parent,
+            self_ref,
+            name,
+            inode_id,
+            inner: Mutex::new(InnerFuseInode {
+                metadata: fuse_attr_to_metadata(attr, inode_id),
+                children: BTreeMap::new(),
+                nlookup: 1,
+                fh: None,
+            }),
+        }
+    }
+
+    fn fuse_fs(&self) -> Result<Arc<FuseFS>, SystemError> {
+        self.fs.upgrade().ok_or(SystemError::ENODEV)
+    }
+
+    /// 打开文件或者目录，返回服务端分配的句柄
+    fn open_handle(&self, fs: &FuseFS, opcode: FuseOpcode) -> Result<u64, SystemError> {
+        let open = FuseOpenIn::default();
+        let payload = fs.call(
+            opcode,
+            self.nodeid,
+            &[open.as_bytes()],
+            size_of::<FuseOpenOut>(),
+        )?;
+        Ok(FuseOpenOut::read_from(&payload)?.fh)
+    }
+
+    fn release_handle(&self, fs: &FuseFS, opcode: FuseOpcode, fh: u64) {
+        let release = FuseReleaseIn {
+            fh,
+            ..Default::default()
+        };
+        if let Err(e) = fs.call(opcode, self.nodeid, &[release.as_bytes()], 0) {
+            warn!("fuse: release node {} failed: {:?}", self.nodeid, e);
+        }
+    }
+
+    fn lookup(&self, fs: &FuseFS, name: &str) -> Result<Arc<FuseInode>, SystemError> {
+        let mut arg = Vec::with_capacity(name.len() + 1);
+        arg.extend_from_slice(name.as_bytes());
+        arg.push(0);
+        let payload = fs.call(
+            FuseOpcode::Lookup,
+            self.nodeid,
+            &[&arg],
+            size_of::<FuseEntryOut>(),
+        )?;
+        let entry = FuseEntryOut::read_from(&payload)?;
+        // 服务端可以用nodeid为0表示文件不存在，并缓存这个结果
+        if entry.nodeid == 0 {
+            return Err(SystemError::ENOENT);
+        }
+        Ok(Arc::new_cyclic(|self_ref| {
+            FuseInode::new(
+                self.fs.clone(),
+                entry.nodeid,
+                self.self_ref.clone(),
+                self_ref.clone(),
+                DName::from(name),
+                &entry.attr,
+            )
+        }))
+    }
+}
+
+impl Drop for FuseInode {
+    fn drop(&mut self) {
+        // 文件系统已经被卸载时，连接上不会再有请求
+        let fs = match self.fs.upgrade() {
+            Some(fs) => fs,
+            None => return,
+        };
+        let mut inner = self.inner.lock();
+        if let Some(fh) = inner.fh.take() {
+            self.release_handle(&fs, FuseOpcode::Release, fh);
+        }
+        if self.nodeid != FUSE_ROOT_ID {
+            fs.forget(self.nodeid, inner.nlookup);
+        }
+    }
+}
+
+impl IndexNode for FuseInode {
+    fn open(
+        &self,
+        _data: SpinLockGuard<FilePrivateData>,
+        _mode: &crate::filesystem::vfs::file::FileMode,
+    ) -> Result<(), SystemError> {
+        Ok(())
+    }
+
+    fn close(&self, _data: SpinLockGuard<FilePrivateData>) -> Result<(), SystemError> {
+        Ok(())
+    }
+
+    fn read_at(
+        &self,
+        offset: usize,
+        len: usize,
+        buf: &mut [u8],
+        _data: SpinLockGuard<FilePrivateData>,
+    ) -> Result<usize, SystemError> {
+        if buf.len() < len {
+            return Err(SystemError::EINVAL);
+        }
+        let fs = self.fuse_fs()?;
+        let mut inner = self.inner.lock();
+        if inner.metadata.file_type == FileType::Dir {
+            return Err(SystemError::EISDIR);
+        }
+        let fh = match inner.fh {
+            Some(fh) => fh,
+            None => {
+                let fh = self.open_handle(&fs, FuseOpcode::Open)?;
+                inner.fh = Some(fh);
+                fh
+            }
+        };
+
+        let mut done = 0;
+        while done < len {
+            let size = (len - done).min(FUSE_MAX_READ);
+            let read = FuseReadIn {
+                fh,
+                offset: (offset + done) as u64,
+                size: size as u32,
+                ..Default::default()
+            };
+            let data = fs.call(FuseOpcode::Read, self.nodeid, &[read.as_bytes()], size)?;
+            let n = data.len().min(size);
+            buf[done..done + n].copy_from_slice(&data[..n]);
+            done += n;
+            // 读到了文件末尾
+            if n < size {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write_at(
+        &self,
+        _offset: usize,
+        _len: usize,
+        _buf: &[u8],
+        _data: SpinLockGuard<FilePrivateData>,
+    ) -> Result<usize, SystemError> {
+        Err(SystemError::EROFS)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.upgrade().unwrap()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> Result<Metadata, SystemError> {
+        let fs = self.fuse_fs()?;
+        let getattr = FuseGetattrIn::default();
+        let payload = fs.call(
+            FuseOpcode::Getattr,
+            self.nodeid,
+            &[getattr.as_bytes()],
+            size_of::<FuseAttrOut>(),
+        )?;
+        let attr = FuseAttrOut::read_from(&payload)?.attr;
+        let metadata = fuse_attr_to_metadata(&attr, self.inode_id);
+        self.inner.lock().metadata = metadata.clone();
+        Ok(metadata)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn IndexNode>, SystemError> {
+        let fs = self.fuse_fs()?;
+        let mut inner = self.inner.lock();
+        if inner.metadata.file_type != FileType::Dir {
+            return Err(SystemError::ENOTDIR);
+        }
+
+        match name {
+            "" | "." => self
+                .self_ref
+                .upgrade()
+                .map(|i| i as Arc<dyn IndexNode>)
+                .ok_or(SystemError::ENOENT),
+            ".." => self
+                .parent
+                .upgrade()
+                .map(|i| i as Arc<dyn IndexNode>)
+                .ok_or(SystemError::ENOENT),
+            name => {
+                let dname = DName::from(name);
+                if let Some(child) = inner.children.get(&dname) {
+                    return Ok(child.clone());
+                }
+                let child = self.lookup(&fs, name)?;
+                inner.children.insert(dname, child.clone());
+                Ok(child)
+            }
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, SystemError> {
+        let fs = self.fuse_fs()?;
+        if self.inner.lock().metadata.file_type != FileType::Dir {
+            return Err(SystemError::ENOTDIR);
+        }
+
+        let fh = self.open_handle(&fs, FuseOpcode::Opendir)?;
+        let mut names = vec![String::from("."), String::from("..")];
+        let mut offset = 0;
+        let r = loop {
+            let read = FuseReadIn {
+                fh,
+                offset,
+                size: FUSE_READDIR_BUF_SIZE as u32,
+                ..Default::default()
+            };
+            let entries = fs
+                .call(
+                    FuseOpcode::Readdir,
+                    self.nodeid,
+                    &[read.as_bytes()],
+                    FUSE_READDIR_BUF_SIZE,
+                )
+                .and_then(|payload| fuse_parse_dirents(&payload));
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(e) => break Err(e),
+            };
+            // 目录已经读完
+            let last = match entries.last() {
+                Some(last) => last.off,
+                None => break Ok(()),
+            };
+            names.extend(
+                entries
+                    .into_iter()
+                    .map(|e| e.name)
+                    .filter(|name| name != "." && name != ".."),
+            );
+            offset = last;
+        };
+        self.release_handle(&fs, FuseOpcode::Releasedir, fh);
+        r.map(|_| names)
+    }
+
+    fn dname(&self) -> Result<DName, SystemError> {
+        Ok(self.name.clone())
+    }
+
+    fn parent(&self) -> Result<Arc<dyn IndexNode>, SystemError> {
+        self.parent
+            .upgrade()
+            .map(|i| i as Arc<dyn IndexNode>)
+            .ok_or(SystemError::EINVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::filesystem::fuse::protocol::{FuseInHeader, FuseInitOut};
+
+    /// 只回复FUSE_INIT的服务端
+    #[derive(Debug)]
+    struct MockConn {
+        minor: u32,
+        unique: AtomicU64,
+    }
+
+    impl FuseConn for MockConn {
+        fn request(&self, req: &[u8], reply: &mut [u8]) -> Result<usize, SystemError> {
+            let header = FuseInHeader::read_from(req)?;
+            assert_eq!(header.opcode, FuseOpcode::Init as u32);
+            assert_eq!(header.len as usize, req.len());
+            let out = FuseInitOut {
+                major: 7,
+                minor: self.minor,
+                max_readahead: 0x10000,
+                max_write: 0x20000,
+                ..Default::default()
+            };
+            let out_header = FuseOutHeader {
+                len: (size_of::<FuseOutHeader>() + size_of::<FuseInitOut>()) as u32,
+                error: 0,
+                unique: header.unique,
+            };
+            reply[..16].copy_from_slice(out_header.as_bytes());
+            reply[16..80].copy_from_slice(out.as_bytes());
+            Ok(80)
+        }
+
+        fn request_noreply(&self, _req: &[u8]) -> Result<(), SystemError> {
+            Ok(())
+        }
+
+        fn next_unique(&self) -> u64 {
+            self.unique.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let conn = MockConn {
+            minor: 36,
+            unique: AtomicU64::new(1),
+        };
+        let info = fuse_handshake(&conn).unwrap();
+        assert_eq!(info.minor, 31);
+        assert_eq!(info.max_readahead, 0x10000);
+        assert_eq!(info.max_write, 0x20000);
+
+        let old = MockConn {
+            minor: 9,
+            unique: AtomicU64::new(1),
+        };
+        assert_eq!(fuse_handshake(&old), Err(SystemError::EPROTONOSUPPORT));
+    }
+
+    #[test]
+    fn test_attr_to_metadata() {
+        let attr = FuseAttr {
+            size: 4097,
+            mode: 0o100644,
+            nlink: 1,
+            mtime: 100,
+            mtimensec: 5,
+            ..Default::default()
+        };
+        let md = fuse_attr_to_metadata(&attr, InodeId::new(3));
+        assert_eq!(md.file_type, FileType::File);
+        assert_eq!(md.size, 4097);
+        assert_eq!(md.mtime, PosixTimeSpec::new(100, 5));
+        assert_eq!(md.mode.bits() & 0o777, 0o644);
+
+        let dir = FuseAttr {
+            mode: 0o040755,
+            ..Default::default()
+        };
+        assert_eq!(
+            fuse_attr_to_metadata(&dir, InodeId::new(4)).file_type,
+            FileType::Dir
+        );
+    }
+}
@@ -0,0 +1,7 @@
+//! FUSE(Filesystem in Userspace)协议的客户端
+//!
+//! 文件系统的请求由服务端处理，目前服务端通过virtio-fs设备提供
+
+pub mod fs;
+pub mod protocol;
+pub mod virtio_fs;
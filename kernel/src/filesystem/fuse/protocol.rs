@@ -0,0 +1,470 @@
+//! FUSE协议中请求与回复的格式
+//!
+//! 每个请求由`fuse_in_header`以及操作的参数组成，每个回复由`fuse_out_header`以及操作的结果组成，
+//! 所有字段都是小端序。这里只包含只读文件系统需要的操作。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/include/uapi/linux/fuse.h
+
+use alloc::{string::String, vec::Vec};
+use core::mem::size_of;
+
+use system_error::SystemError;
+
+/// 协议的主版本号，主版本号不同的服务端无法通信
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+/// 驱动支持的次版本号
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+/// 服务端至少需要支持的次版本号，更早的版本中各个结构体的长度不同
+pub const FUSE_MIN_MINOR_VERSION: u32 = 12;
+
+/// 根目录的nodeid
+pub const FUSE_ROOT_ID: u64 = 1;
+
+/// FUSE的操作码
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum FuseOpcode {
+    Lookup = 1,
+    Forget = 2,
+    Getattr = 3,
+    Open = 14,
+    Read = 15,
+    Release = 18,
+    Init = 26,
+    Opendir = 27,
+    Readdir = 28,
+    Releasedir = 29,
+    Destroy = 38,
+}
+
+/// # trait功能
+/// 可以直接按字节读写的FUSE结构体
+///
+/// ## Safety
+/// 实现者必须是`#[repr(C)]`的、没有填充字节的结构体，并且任意字节序列都是合法的值
+pub unsafe trait FusePod: Copy + Default {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+
+    /// # 函数的功能
+    /// 从`buf`的开头读取结构体
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EIO): `buf`的长度不够
+    fn read_from(buf: &[u8]) -> Result<Self, SystemError> {
+        if buf.len() < size_of::<Self>() {
+            return Err(SystemError::EIO);
+        }
+        Ok(unsafe { (buf.as_ptr() as *const Self).read_unaligned() })
+    }
+
+    /// 从`buf`的开头读取结构体，`buf`不够长时，剩余的字段为0
+    ///
+    /// 旧版本的服务端回复的结构体可能比现在的短
+    fn read_from_prefix(buf: &[u8]) -> Self {
+        let mut v = Self::default();
+        let len = buf.len().min(size_of::<Self>());
+        unsafe {
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), &mut v as *mut Self as *mut u8, len)
+        };
+        v
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseInHeader {
+    /// 整个请求的长度
+    pub len: u32,
+    pub opcode: u32,
+    /// 请求的编号，回复中携带相同的编号
+    pub unique: u64,
+    pub nodeid: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    pub total_extlen: u16,
+    pub padding: u16,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseOutHeader {
+    /// 整个回复的长度
+    pub len: u32,
+    /// 0或者负的错误码
+    pub error: i32,
+    pub unique: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseInitIn {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseInitOut {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+    pub max_write: u32,
+    pub time_gran: u32,
+    pub max_pages: u16,
+    pub map_alignment: u16,
+    pub flags2: u32,
+    pub unused: [u32; 7],
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseAttr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atimensec: u32,
+    pub mtimensec: u32,
+    pub ctimensec: u32,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    pub flags: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseEntryOut {
+    /// 为0表示文件不存在
+    pub nodeid: u64,
+    pub generation: u64,
+    pub entry_valid: u64,
+    pub attr_valid: u64,
+    pub entry_valid_nsec: u32,
+    pub attr_valid_nsec: u32,
+    pub attr: FuseAttr,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseGetattrIn {
+    pub getattr_flags: u32,
+    pub dummy: u32,
+    pub fh: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseAttrOut {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub dummy: u32,
+    pub attr: FuseAttr,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseOpenIn {
+    pub flags: u32,
+    pub open_flags: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseOpenOut {
+    pub fh: u64,
+    pub open_flags: u32,
+    pub padding: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseReadIn {
+    pub fh: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub read_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseReleaseIn {
+    pub fh: u64,
+    pub flags: u32,
+    pub release_flags: u32,
+    pub lock_owner: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FuseForgetIn {
+    pub nlookup: u64,
+}
+
+/// READDIR的回复中每一项的头部，之后是文件名，整项按8字节对齐
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct FuseDirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    typ: u32,
+}
+
+unsafe impl FusePod for FuseInHeader {}
+unsafe impl FusePod for FuseOutHeader {}
+unsafe impl FusePod for FuseInitIn {}
+unsafe impl FusePod for FuseInitOut {}
+unsafe impl FusePod for FuseAttr {}
+unsafe impl FusePod for FuseEntryOut {}
+unsafe impl FusePod for FuseGetattrIn {}
+unsafe impl FusePod for FuseAttrOut {}
+unsafe impl FusePod for FuseOpenIn {}
+unsafe impl FusePod for FuseOpenOut {}
+unsafe impl FusePod for FuseReadIn {}
+unsafe impl FusePod for FuseReleaseIn {}
+unsafe impl FusePod for FuseForgetIn {}
+unsafe impl FusePod for FuseDirent {}
+
+/// # 函数的功能
+/// 构造一个请求：`fuse_in_header`之后依次是`args`
+pub fn fuse_build_request(opcode: FuseOpcode, unique: u64, nodeid: u64, args: &[&[u8]]) -> Vec<u8> {
+    let len = size_of::<FuseInHeader>() + args.iter().map(|a| a.len()).sum::<usize>();
+    let header = FuseInHeader {
+        len: len as u32,
+        opcode: opcode as u32,
+        unique,
+        nodeid,
+        ..Default::default()
+    };
+    let mut req = Vec::with_capacity(len);
+    req.extend_from_slice(header.as_bytes());
+    for arg in args {
+        req.extend_from_slice(arg);
+    }
+    req
+}
+
+/// # 函数的功能
+/// 检查回复的头部
+///
+/// ## 参数
+/// - `unique`: 请求的编号
+/// - `reply`: 设备写入的回复
+///
+/// ## 返回值
+/// - Ok(payload): 头部之后的数据
+/// - Err(SystemError::EIO): 回复不完整，或者编号与请求不一致
+/// - Err(e): 服务端返回的错误
+pub fn fuse_parse_reply(unique: u64, reply: &[u8]) -> Result<&[u8], SystemError> {
+    let header = FuseOutHeader::read_from(reply)?;
+    let len = header.len as usize;
+    if len < size_of::<FuseOutHeader>() || len > reply.len() || header.unique != unique {
+        return Err(SystemError::EIO);
+    }
+    if header.error != 0 {
+        return Err(SystemError::from_posix_errno(header.error).unwrap_or(SystemError::EIO));
+    }
+    Ok(&reply[size_of::<FuseOutHeader>()..len])
+}
+
+/// # 结构功能
+/// FUSE_INIT协商得到的连接参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuseConnInfo {
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub max_write: u32,
+}
+
+/// FUSE_INIT请求的参数
+pub fn fuse_init_in(max_readahead: u32) -> FuseInitIn {
+    FuseInitIn {
+        major: FUSE_KERNEL_VERSION,
+        minor: FUSE_KERNEL_MINOR_VERSION,
+        max_readahead,
+        flags: 0,
+    }
+}
+
+/// # 函数的功能
+/// 解析FUSE_INIT的回复
+///
+/// ## 返回值
+/// - Err(SystemError::EPROTONOSUPPORT): 服务端的协议版本不兼容
+pub fn fuse_parse_init_reply(
+    payload: &[u8],
+    max_readahead: u32,
+) -> Result<FuseConnInfo, SystemError> {
+    // 次版本号小于23的服务端只回复前24字节
+    if payload.len() < 24 {
+        return Err(SystemError::EIO);
+    }
+    let out = FuseInitOut::read_from_prefix(payload);
+    if out.major != FUSE_KERNEL_VERSION || out.minor < FUSE_MIN_MINOR_VERSION {
+        return Err(SystemError::EPROTONOSUPPORT);
+    }
+    Ok(FuseConnInfo {
+        minor: out.minor.min(FUSE_KERNEL_MINOR_VERSION),
+        max_readahead: out.max_readahead.min(max_readahead),
+        max_write: out.max_write,
+    })
+}
+
+/// # 结构功能
+/// 目录中的一项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuseDirEntry {
+    pub ino: u64,
+    /// 下一次READDIR从这里开始
+    pub off: u64,
+    pub name: String,
+}
+
+/// # 函数的功能
+/// 解析READDIR回复中的目录项
+///
+/// ## 返回值
+/// - Err(SystemError::EIO): 目录项不完整
+pub fn fuse_parse_dirents(mut buf: &[u8]) -> Result<Vec<FuseDirEntry>, SystemError> {
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        let dirent = FuseDirent::read_from(buf)?;
+        let name_start = size_of::<FuseDirent>();
+        let name_end = name_start + dirent.namelen as usize;
+        if dirent.namelen == 0 || name_end > buf.len() {
+            return Err(SystemError::EIO);
+        }
+        entries.push(FuseDirEntry {
+            ino: dirent.ino,
+            off: dirent.off,
+            name: String::from_utf8_lossy(&buf[name_start..name_end]).into_owned(),
+        });
+        let reclen = name_end.next_multiple_of(8).min(buf.len());
+        buf = &buf[reclen..];
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(unique: u64, error: i32, payload: &[u8]) -> Vec<u8> {
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + payload.len()) as u32,
+            error,
+            unique,
+        };
+        let mut r = header.as_bytes().to_vec();
+        r.extend_from_slice(payload);
+        r
+    }
+
+    #[test]
+    fn test_struct_sizes() {
+        assert_eq!(size_of::<FuseInHeader>(), 40);
+        assert_eq!(size_of::<FuseOutHeader>(), 16);
+        assert_eq!(size_of::<FuseInitOut>(), 64);
+        assert_eq!(size_of::<FuseAttr>(), 88);
+        assert_eq!(size_of::<FuseEntryOut>(), 128);
+        assert_eq!(size_of::<FuseAttrOut>(), 104);
+        assert_eq!(size_of::<FuseReadIn>(), 40);
+    }
+
+    #[test]
+    fn test_init_handshake() {
+        let init = fuse_init_in(0x20000);
+        let req = fuse_build_request(FuseOpcode::Init, 1, 0, &[init.as_bytes()]);
+        let header = FuseInHeader::read_from(&req).unwrap();
+        assert_eq!(header.len as usize, req.len());
+        assert_eq!(header.opcode, 26);
+        assert_eq!(FuseInitIn::read_from(&req[40..]).unwrap(), init);
+
+        let out = FuseInitOut {
+            major: 7,
+            minor: 38,
+            max_readahead: 0x100000,
+            max_write: 0x10000,
+            ..Default::default()
+        };
+        let r = reply(1, 0, out.as_bytes());
+        let payload = fuse_parse_reply(1, &r).unwrap();
+        assert_eq!(
+            fuse_parse_init_reply(payload, 0x20000),
+            Ok(FuseConnInfo {
+                minor: FUSE_KERNEL_MINOR_VERSION,
+                max_readahead: 0x20000,
+                max_write: 0x10000,
+            })
+        );
+
+        // 旧版本的服务端只回复前24字节
+        let r = reply(1, 0, &out.as_bytes()[..24]);
+        let info = fuse_parse_init_reply(fuse_parse_reply(1, &r).unwrap(), 0x20000).unwrap();
+        assert_eq!(info.max_write, 0);
+
+        // 主版本号不同
+        let old = FuseInitOut {
+            major: 6,
+            minor: 31,
+            ..Default::default()
+        };
+        assert_eq!(
+            fuse_parse_init_reply(old.as_bytes(), 0x20000),
+            Err(SystemError::EPROTONOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn test_parse_reply_errors() {
+        let r = reply(5, SystemError::ENOENT.to_posix_errno(), &[]);
+        assert_eq!(fuse_parse_reply(5, &r), Err(SystemError::ENOENT));
+        // 编号与请求不一致
+        let r = reply(6, 0, &[1, 2, 3]);
+        assert_eq!(fuse_parse_reply(5, &r), Err(SystemError::EIO));
+        // 回复被截断
+        assert_eq!(fuse_parse_reply(6, &r[..17]), Err(SystemError::EIO));
+        assert_eq!(fuse_parse_reply(6, &r), Ok(&[1u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn test_parse_dirents() {
+        let mut buf = Vec::new();
+        for (ino, name) in [(1u64, "."), (7, "hello.txt")] {
+            let d = FuseDirent {
+                ino,
+                off: ino * 10,
+                namelen: name.len() as u32,
+                typ: 0,
+            };
+            buf.extend_from_slice(d.as_bytes());
+            buf.extend_from_slice(name.as_bytes());
+            buf.resize(buf.len().next_multiple_of(8), 0);
+        }
+        let entries = fuse_parse_dirents(&buf).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].name, "hello.txt");
+        assert_eq!(entries[1].off, 70);
+
+        // 文件名被截断
+        assert_eq!(fuse_parse_dirents(&buf[..30]), Err(SystemError::EIO));
+    }
+}
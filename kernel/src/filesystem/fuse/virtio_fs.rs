@@ -0,0 +1,885 @@
+//! virtio-fs设备的驱动
+//!
+//! virtio-fs设备把主机上的一个目录通过FUSE协议共享给虚拟机。设备至少有两个队列：
+//! 队列0(hiprio)用于FUSE_FORGET等高优先级请求，队列1开始是请求队列。
+//! 这里只使用第一个请求队列，所有请求都同步完成：提交之后轮询used ring，直到设备完成请求。
+//!
+//! 挂载时使用文件系统类型"virtiofs"，挂载的是第一个virtio-fs设备。
+//!
+//! 参考 virtio spec 5.11 File System Device 以及
+//! https://code.dragonos.org.cn/xref/linux-6.6.21/fs/fuse/virtio_fs.c
+
+use core::{
+    any::Any,
+    fmt::Debug,
+    hint::spin_loop,
+    ops::ControlFlow,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::{
+    collections::LinkedList,
+    format,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use linkme::distributed_slice;
+use log::{error, info, warn};
+use system_error::SystemError;
+use unified_init::macros::unified_init;
+use virtio_drivers::transport::Transport;
+
+use crate::{
+    driver::{
+        base::{
+            class::Class,
+            device::{
+                bus::Bus,
+                driver::{Driver, DriverCommonData},
+                Device, DeviceCommonData, DeviceId, DeviceType, IdTable,
+            },
+            kobject::{KObjType, KObject, KObjectCommonData, KObjectState, LockedKObjectState},
+            kset::KSet,
+        },
+        virtio::{
+            queue::VirtqSplit,
+            reset::virtio_reset_device,
+            ring::VirtQueueSizePolicy,
+            router::virtio_device_router,
+            sg::VirtioSgList,
+            sysfs::{virtio_bus, virtio_device_manager, virtio_driver_manager},
+            transport::{VirtIOIrqType, VirtIOIsr, VirtIOTransport, VIRTIO_F_VERSION_1},
+            virtio_impl::HalImpl,
+            VirtIODevice, VirtIODeviceIndex, VirtIODriver, VirtIODriverCommonData, VirtioDeviceId,
+            VirtioDeviceType, VIRTIO_VENDOR_ID,
+        },
+    },
+    exception::{irqdesc::IrqReturn, IrqNumber},
+    filesystem::{
+        kernfs::KernFSInode,
+        sysfs::{
+            file::sysfs_emit_str, Attribute, AttributeGroup, SysFSOpsSupport, SYSFS_ATTR_MODE_RO,
+        },
+        vfs::{syscall::ModeType, FileSystem, FileSystemMaker, FSMAKER},
+    },
+    init::initcall::INITCALL_POSTCORE,
+    libs::{
+        mutex::Mutex,
+        rwlock::{RwLockReadGuard, RwLockWriteGuard},
+        spinlock::{SpinLock, SpinLockGuard},
+    },
+    time::Instant,
+};
+
+use super::{
+    fs::{fuse_handshake, FuseConn, FuseFS},
+    protocol::FuseConnInfo,
+};
+
+const VIRTIO_FS_BASENAME: &str = "virtio_fs";
+/// 挂载时使用的文件系统类型
+const VIRTIO_FS_FSTYPE: &str = "virtiofs";
+
+/// 高优先级队列
+const VIRTIO_FS_HIPRIO_QUEUE: u16 = 0;
+/// 第一个请求队列
+const VIRTIO_FS_REQUEST_QUEUE: u16 = 1;
+const VIRTIO_FS_QUEUE_POLICY: VirtQueueSizePolicy = VirtQueueSizePolicy::new(64, 128);
+
+/// 配置空间中tag的长度，tag不足这个长度时以0结尾
+const VIRTIO_FS_TAG_LEN: usize = 36;
+/// 配置空间中num_request_queues的偏移
+const VIRTIO_FS_NUM_REQUEST_QUEUES_OFFSET: usize = VIRTIO_FS_TAG_LEN;
+
+/// 等待设备完成一个请求的最长时间
+const VIRTIO_FS_REQUEST_TIMEOUT_US: i64 = 30_000_000;
+
+static mut VIRTIO_FS_DRIVER: Option<Arc<VirtIOFsDriver>> = None;
+
+#[inline(always)]
+fn virtio_fs_driver() -> Arc<VirtIOFsDriver> {
+    unsafe { VIRTIO_FS_DRIVER.as_ref().unwrap().clone() }
+}
+
+/// # 函数的功能
+/// 从配置空间中的tag字段得到文件系统的名称
+///
+/// ## 返回值
+/// - Err(SystemError::EINVAL): tag为空，或者不是合法的UTF-8
+fn virtio_fs_parse_tag(raw: &[u8; VIRTIO_FS_TAG_LEN]) -> Result<String, SystemError> {
+    let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    if len == 0 {
+        return Err(SystemError::EINVAL);
+    }
+    core::str::from_utf8(&raw[..len])
+        .map(|s| s.to_string())
+        .map_err(|_| SystemError::EINVAL)
+}
+
+/// # 函数的功能
+/// 初始化virtio-fs设备，并完成FUSE_INIT握手
+pub fn virtio_fs(
+    transport: VirtIOTransport,
+    dev_id: Arc<DeviceId>,
+    dev_parent: Option<Arc<dyn Device>>,
+) -> Result<Arc<dyn Device>, SystemError> {
+    let device = VirtIOFsDevice::new(transport, dev_id)?;
+    if let Some(dev_parent) = dev_parent {
+        device.set_dev_parent(Some(Arc::downgrade(&dev_parent)));
+    }
+    virtio_device_manager().device_add(device.clone() as Arc<dyn VirtIODevice>)?;
+    Ok(device)
+}
+
+/// virtio-fs使用的两个队列，以及通知设备所需的transport
+struct VirtIOFsQueues {
+    transport: VirtIOTransport,
+    hiprio: VirtqSplit<HalImpl>,
+    request: VirtqSplit<HalImpl>,
+}
+
+/// # 结构功能
+/// 通过virtio-fs设备的队列发送FUSE请求
+#[derive(Debug)]
+struct VirtIOFsConn {
+    /// 请求同步完成，同一时刻只有一个请求在队列中
+    queues: Mutex<VirtIOFsQueues>,
+    unique: AtomicU64,
+    /// 请求超时之后设备被复位，不能再使用
+    broken: AtomicBool,
+}
+
+// transport中保存的是设备寄存器的地址，只在持有锁时访问
+unsafe impl Send for VirtIOFsQueues {}
+
+impl VirtIOFsQueues {
+    fn queue_mut(&mut self, queue: u16) -> Option<&mut VirtqSplit<HalImpl>> {
+        match queue {
+            VIRTIO_FS_HIPRIO_QUEUE => Some(&mut self.hiprio),
+            VIRTIO_FS_REQUEST_QUEUE => Some(&mut self.request),
+            _ => None,
+        }
+    }
+}
+
+impl Debug for VirtIOFsQueues {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VirtIOFsQueues")
+            .field("hiprio", &self.hiprio)
+            .field("request", &self.request)
+            .finish()
+    }
+}
+
+impl VirtIOFsConn {
+    /// # 函数的功能
+    /// 按照virtio规范3.1初始化设备，并创建hiprio队列和第一个请求队列
+    fn new(mut transport: VirtIOTransport) -> Result<Self, SystemError> {
+        // 设备需要支持virtio 1.0，virtio-fs没有legacy接口
+        transport.negotiate_features(VIRTIO_F_VERSION_1, VIRTIO_F_VERSION_1)?;
+
+        let queues =
+            Self::create_queue(&mut transport, VIRTIO_FS_HIPRIO_QUEUE).and_then(|hiprio| {
+                let request = Self::create_queue(&mut transport, VIRTIO_FS_REQUEST_QUEUE)?;
+                Ok((hiprio, request))
+            });
+        let (hiprio, request) = match queues {
+            Ok(queues) => queues,
+            Err(e) => {
+                transport.fail_init();
+                return Err(e);
+            }
+        };
+        transport.finish_init();
+
+        Ok(Self {
+            queues: Mutex::new(VirtIOFsQueues {
+                transport,
+                hiprio,
+                request,
+            }),
+            unique: AtomicU64::new(1),
+            broken: AtomicBool::new(false),
+        })
+    }
+
+    fn create_queue(
+        transport: &mut VirtIOTransport,
+        queue: u16,
+    ) -> Result<VirtqSplit<HalImpl>, SystemError> {
+        let size = transport.negotiate_queue_size(queue, &VIRTIO_FS_QUEUE_POLICY)?;
+        let vq = VirtqSplit::new(queue, size, transport.requires_legacy_layout())?;
+        vq.attach(transport)?;
+        Ok(vq)
+    }
+
+    /// # 函数的功能
+    /// 把请求放入队列，并等待设备完成
+    ///
+    /// ## 返回值
+    /// - Ok(len): 设备写入`reply`的字节数
+    /// - Err(SystemError::ETIMEDOUT): 设备没有在规定时间内完成请求，设备已经被复位
+    fn submit(&self, queue: u16, req: &[u8], reply: &mut [u8]) -> Result<usize, SystemError> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(SystemError::EIO);
+        }
+        let mut guard = self.queues.lock();
+        let queues = &mut *guard;
+        let vq = if queue == VIRTIO_FS_HIPRIO_QUEUE {
+            &mut queues.hiprio
+        } else {
+            &mut queues.request
+        };
+
+        let mut sg = VirtioSgList::new();
+        sg.push_readable(req);
+        if !reply.is_empty() {
+            sg.push_writable(reply);
+        }
+        let head = vq.try_add(&sg)?;
+        if vq.should_notify() {
+            queues.transport.notify(queue);
+        }
+
+        // 请求队列没有分配中断向量，这里轮询设备
+        let start = Instant::now().total_micros();
+        loop {
+            if let Some((id, len)) = vq.pop_used() {
+                if id == head {
+                    return Ok(len as usize);
+                }
+                continue;
+            }
+            if Instant::now().total_micros() - start > VIRTIO_FS_REQUEST_TIMEOUT_US {
+                break;
+            }
+            spin_loop();
+        }
+
+        // 设备可能还会写入reply，复位之后它不会再访问队列
+        error!("virtio-fs: request on queue {} timed out", queue);
+        self.broken.store(true, Ordering::SeqCst);
+        if let Err(e) = virtio_reset_device(&mut queues.transport) {
+            error!("virtio-fs: reset device failed: {:?}", e);
+        }
+        Err(SystemError::ETIMEDOUT)
+    }
+
+    /// 复位设备，之后设备不会再访问队列
+    fn shutdown(&self) {
+        self.broken.store(true, Ordering::SeqCst);
+        if let Err(e) = virtio_reset_device(&mut self.queues.lock().transport) {
+            warn!("virtio-fs: reset device failed: {:?}", e);
+        }
+    }
+}
+
+impl FuseConn for VirtIOFsConn {
+    fn request(&self, req: &[u8], reply: &mut [u8]) -> Result<usize, SystemError> {
+        self.submit(VIRTIO_FS_REQUEST_QUEUE, req, reply)
+    }
+
+    fn request_noreply(&self, req: &[u8]) -> Result<(), SystemError> {
+        self.submit(VIRTIO_FS_HIPRIO_QUEUE, req, &mut [])
+            .map(|_| ())
+    }
+
+    fn next_unique(&self) -> u64 {
+        self.unique.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// virtio-fs设备
+#[derive(Debug)]
+#[cast_to([sync] VirtIODevice)]
+#[cast_to([sync] Device)]
+pub struct VirtIOFsDevice {
+    dev_id: Arc<DeviceId>,
+    /// 主机为共享目录设置的名称
+    tag: String,
+    conn: Arc<VirtIOFsConn>,
+    conn_info: FuseConnInfo,
+    irq_type: VirtIOIrqType,
+    isr: Option<VirtIOIsr>,
+    dead: AtomicBool,
+    inner: SpinLock<InnerVirtIOFsDevice>,
+    locked_kobj_state: LockedKObjectState,
+}
+
+#[derive(Debug)]
+struct InnerVirtIOFsDevice {
+    name: Option<String>,
+    virtio_index: Option<VirtIODeviceIndex>,
+    device_common: DeviceCommonData,
+    kobject_common: KObjectCommonData,
+    irq: Option<IrqNumber>,
+}
+
+impl VirtIOFsDevice {
+    fn new(transport: VirtIOTransport, dev_id: Arc<DeviceId>) -> Result<Arc<Self>, SystemError> {
+        let raw_tag = transport.config_read::<[u8; VIRTIO_FS_TAG_LEN]>(0)?;
+        let tag = virtio_fs_parse_tag(&raw_tag)?;
+        let num_request_queues =
+            transport.config_read::<u32>(VIRTIO_FS_NUM_REQUEST_QUEUES_OFFSET)?;
+        if num_request_queues == 0 {
+            return Err(SystemError::EINVAL);
+        }
+        let irq = transport.irq().map(|irq| IrqNumber::new(irq.data()));
+        let irq_type = transport.irq_type();
+        let isr = transport.isr();
+
+        let conn = Arc::new(VirtIOFsConn::new(transport)?);
+        let conn_info = fuse_handshake(conn.as_ref()).inspect_err(|e| {
+            error!("virtio-fs '{}': FUSE_INIT failed: {:?}", tag, e);
+            conn.shutdown();
+        })?;
+        info!(
+            "virtio-fs '{}': FUSE protocol 7.{}, {} request queue(s)",
+            tag, conn_info.minor, num_request_queues
+        );
+
+        Ok(Arc::new(Self {
+            dev_id,
+            tag,
+            conn,
+            conn_info,
+            irq_type,
+            isr,
+            dead: AtomicBool::new(false),
+            inner: SpinLock::new(InnerVirtIOFsDevice {
+                name: None,
+                virtio_index: None,
+                device_common: DeviceCommonData::default(),
+                kobject_common: KObjectCommonData::default(),
+                irq,
+            }),
+            locked_kobj_state: LockedKObjectState::default(),
+        }))
+    }
+
+    fn inner(&self) -> SpinLockGuard<InnerVirtIOFsDevice> {
+        self.inner.lock_irqsave()
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// # 函数的功能
+    /// 在这个设备上创建一个FUSE文件系统
+    pub fn mount(&self) -> Result<Arc<FuseFS>, SystemError> {
+        if self.is_dead() {
+            return Err(SystemError::ENODEV);
+        }
+        FuseFS::new(
+            self.conn.clone() as Arc<dyn FuseConn>,
+            self.conn_info,
+            VIRTIO_FS_FSTYPE,
+        )
+    }
+}
+
+/// 挂载第一个virtio-fs设备
+fn virtio_fs_make_fs() -> Result<Arc<dyn FileSystem + 'static>, SystemError> {
+    let device = virtio_fs_driver()
+        .devices()
+        .first()
+        .cloned()
+        .ok_or(SystemError::ENODEV)?
+        .arc_any()
+        .downcast::<VirtIOFsDevice>()
+        .map_err(|_| SystemError::EINVAL)?;
+    device.mount().map(|fs| fs as Arc<dyn FileSystem>)
+}
+
+#[distributed_slice(FSMAKER)]
+static VIRTIOFSMAKER: FileSystemMaker = FileSystemMaker::new(
+    VIRTIO_FS_FSTYPE,
+    &(virtio_fs_make_fs as fn() -> Result<Arc<dyn FileSystem + 'static>, SystemError>),
+);
+
+impl VirtIODevice for VirtIOFsDevice {
+    fn irq(&self) -> Option<IrqNumber> {
+        self.inner().irq
+    }
+
+    fn irq_type(&self) -> VirtIOIrqType {
+        self.irq_type
+    }
+
+    fn isr(&self) -> Option<VirtIOIsr> {
+        self.isr
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        vec![VIRTIO_FS_HIPRIO_QUEUE, VIRTIO_FS_REQUEST_QUEUE]
+    }
+
+    fn ring_debug_dump(&self, queue_index: u16) -> Result<String, SystemError> {
+        // 提交者在等待请求完成期间一直持有锁，这里不能等待
+        let mut queues = self
+            .conn
+            .queues
+            .try_lock()
+            .map_err(|_| SystemError::EBUSY)?;
+        let vq = queues.queue_mut(queue_index).ok_or(SystemError::ENOENT)?;
+        Ok(vq.ring_debug_dump())
+    }
+
+    fn ring_reset_last_used(&self, queue_index: u16) -> Result<u16, SystemError> {
+        let mut queues = self
+            .conn
+            .queues
+            .try_lock()
+            .map_err(|_| SystemError::EBUSY)?;
+        let vq = queues.queue_mut(queue_index).ok_or(SystemError::ENOENT)?;
+        Ok(vq.reset_last_used())
+    }
+
+    fn handle_irq(&self, _irq: IrqNumber) -> Result<IrqReturn, SystemError> {
+        // 请求在提交者的上下文中轮询完成
+        Ok(IrqReturn::Handled)
+    }
+
+    fn dev_id(&self) -> &Arc<DeviceId> {
+        &self.dev_id
+    }
+
+    fn set_device_name(&self, name: String) {
+        self.inner().name = Some(name);
+    }
+
+    fn device_name(&self) -> String {
+        self.inner()
+            .name
+            .clone()
+            .unwrap_or_else(|| VIRTIO_FS_BASENAME.to_string())
+    }
+
+    fn set_virtio_device_index(&self, index: VirtIODeviceIndex) {
+        self.inner().virtio_index = Some(index);
+    }
+
+    fn virtio_device_index(&self) -> Option<VirtIODeviceIndex> {
+        self.inner().virtio_index
+    }
+
+    fn device_type_id(&self) -> u32 {
+        VirtioDeviceType::FileSystem.device_id()
+    }
+
+    fn vendor(&self) -> u32 {
+        VIRTIO_VENDOR_ID.into()
+    }
+}
+
+impl Device for VirtIOFsDevice {
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::Char
+    }
+
+    fn id_table(&self) -> IdTable {
+        IdTable::new(VIRTIO_FS_BASENAME.to_string(), None)
+    }
+
+    fn bus(&self) -> Option<Weak<dyn Bus>> {
+        self.inner().device_common.bus.clone()
+    }
+
+    fn set_bus(&self, bus: Option<Weak<dyn Bus>>) {
+        self.inner().device_common.bus = bus;
+    }
+
+    fn class(&self) -> Option<Arc<dyn Class>> {
+        self.inner()
+            .device_common
+            .get_class_weak_or_clear()
+            .and_then(|x| x.upgrade())
+    }
+
+    fn set_class(&self, class: Option<Weak<dyn Class>>) {
+        self.inner().device_common.class = class;
+    }
+
+    fn driver(&self) -> Option<Arc<dyn Driver>> {
+        self.inner()
+            .device_common
+            .get_driver_weak_or_clear()
+            .and_then(|x| x.upgrade())
+    }
+
+    fn set_driver(&self, driver: Option<Weak<dyn Driver>>) {
+        self.inner().device_common.driver = driver;
+    }
+
+    fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::SeqCst)
+    }
+
+    fn can_match(&self) -> bool {
+        self.inner().device_common.can_match
+    }
+
+    fn set_can_match(&self, can_match: bool) {
+        self.inner().device_common.can_match = can_match;
+    }
+
+    fn state_synced(&self) -> bool {
+        true
+    }
+
+    fn attribute_groups(&self) -> Option<&'static [&'static dyn AttributeGroup]> {
+        Some(&[&VirtIOFsAttrGroup])
+    }
+
+    fn dev_parent(&self) -> Option<Weak<dyn Device>> {
+        self.inner().device_common.get_parent_weak_or_clear()
+    }
+
+    fn set_dev_parent(&self, parent: Option<Weak<dyn Device>>) {
+        self.inner().device_common.parent = parent;
+    }
+}
+
+impl KObject for VirtIOFsDevice {
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_inode(&self, inode: Option<Arc<KernFSInode>>) {
+        self.inner().kobject_common.kern_inode = inode;
+    }
+
+    fn inode(&self) -> Option<Arc<KernFSInode>> {
+        self.inner().kobject_common.kern_inode.clone()
+    }
+
+    fn parent(&self) -> Option<Weak<dyn KObject>> {
+        self.inner().kobject_common.parent.clone()
+    }
+
+    fn set_parent(&self, parent: Option<Weak<dyn KObject>>) {
+        self.inner().kobject_common.parent = parent;
+    }
+
+    fn kset(&self) -> Option<Arc<KSet>> {
+        self.inner().kobject_common.kset.clone()
+    }
+
+    fn set_kset(&self, kset: Option<Arc<KSet>>) {
+        self.inner().kobject_common.kset = kset;
+    }
+
+    fn kobj_type(&self) -> Option<&'static dyn KObjType> {
+        self.inner().kobject_common.kobj_type
+    }
+
+    fn name(&self) -> String {
+        self.device_name()
+    }
+
+    fn set_name(&self, _name: String) {
+        // do nothing
+    }
+
+    fn kobj_state(&self) -> RwLockReadGuard<KObjectState> {
+        self.locked_kobj_state.read()
+    }
+
+    fn kobj_state_mut(&self) -> RwLockWriteGuard<KObjectState> {
+        self.locked_kobj_state.write()
+    }
+
+    fn set_kobj_state(&self, state: KObjectState) {
+        *self.locked_kobj_state.write() = state;
+    }
+
+    fn set_kobj_type(&self, ktype: Option<&'static dyn KObjType>) {
+        self.inner().kobject_common.kobj_type = ktype;
+    }
+}
+
+#[derive(Debug)]
+struct VirtIOFsAttrGroup;
+
+impl AttributeGroup for VirtIOFsAttrGroup {
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    fn attrs(&self) -> &[&'static dyn Attribute] {
+        &[&AttrTag]
+    }
+
+    fn is_visible(
+        &self,
+        _kobj: Arc<dyn KObject>,
+        attr: &'static dyn Attribute,
+    ) -> Option<ModeType> {
+        Some(attr.mode())
+    }
+}
+
+/// 主机为共享目录设置的名称
+#[derive(Debug)]
+struct AttrTag;
+
+impl Attribute for AttrTag {
+    fn name(&self) -> &str {
+        "tag"
+    }
+
+    fn mode(&self) -> ModeType {
+        SYSFS_ATTR_MODE_RO
+    }
+
+    fn support(&self) -> SysFSOpsSupport {
+        SysFSOpsSupport::ATTR_SHOW
+    }
+
+    fn show(&self, kobj: Arc<dyn KObject>, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let dev = kobj
+            .arc_any()
+            .downcast::<VirtIOFsDevice>()
+            .map_err(|_| SystemError::EINVAL)?;
+        sysfs_emit_str(buf, &format!("{}\n", dev.tag()))
+    }
+}
+
+#[unified_init(INITCALL_POSTCORE)]
+fn virtio_fs_driver_init() -> Result<(), SystemError> {
+    let driver = VirtIOFsDriver::new();
+    virtio_driver_manager()
+        .register(driver.clone() as Arc<dyn VirtIODriver>)
+        .expect("Add virtio fs driver failed");
+    unsafe {
+        VIRTIO_FS_DRIVER = Some(driver);
+    }
+    virtio_device_router().register(VirtioDeviceType::FileSystem, virtio_fs)?;
+
+    return Ok(());
+}
+
+#[cast_to([sync] VirtIODriver)]
+#[cast_to([sync] Driver)]
+struct VirtIOFsDriver {
+    inner: SpinLock<InnerVirtIOFsDriver>,
+    kobj_state: LockedKObjectState,
+}
+
+impl VirtIOFsDriver {
+    pub fn new() -> Arc<Self> {
+        let inner = InnerVirtIOFsDriver {
+            virtio_driver_common: VirtIODriverCommonData::default(),
+            driver_common: DriverCommonData::default(),
+            kobj_common: KObjectCommonData::default(),
+        };
+
+        let id_table = VirtioDeviceId::new(
+            VirtioDeviceType::FileSystem.device_id(),
+            VIRTIO_VENDOR_ID.into(),
+        );
+        let result = VirtIOFsDriver {
+            inner: SpinLock::new(inner),
+            kobj_state: LockedKObjectState::default(),
+        };
+        result.add_virtio_id(id_table);
+
+        return Arc::new(result);
+    }
+
+    fn inner(&self) -> SpinLockGuard<InnerVirtIOFsDriver> {
+        return self.inner.lock();
+    }
+}
+
+/// 调试输出只尝试获取内部的锁，避免在持有锁时打印驱动而死锁
+impl Debug for VirtIOFsDriver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut d = f.debug_struct("VirtIOFsDriver");
+        match self.inner.try_lock() {
+            Ok(inner) => d
+                .field("id_table", &inner.virtio_driver_common.id_table)
+                .field("devices", &inner.driver_common.devices.len()),
+            Err(_) => d.field("inner", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+#[derive(Debug)]
+struct InnerVirtIOFsDriver {
+    virtio_driver_common: VirtIODriverCommonData,
+    driver_common: DriverCommonData,
+    kobj_common: KObjectCommonData,
+}
+
+impl VirtIODriver for VirtIOFsDriver {
+    fn probe(&self, device: &Arc<dyn VirtIODevice>) -> Result<(), SystemError> {
+        device
+            .clone()
+            .arc_any()
+            .downcast::<VirtIOFsDevice>()
+            .map_err(|_| {
+                error!(
+                    "VirtIOFsDriver::probe() failed: device is not a virtio-fs device. Device: '{:?}'",
+                    device.name()
+                );
+                SystemError::EINVAL
+            })?;
+        return Ok(());
+    }
+
+    fn remove(&self, device: &Arc<dyn VirtIODevice>) -> Result<(), SystemError> {
+        let dev = device
+            .clone()
+            .arc_any()
+            .downcast::<VirtIOFsDevice>()
+            .map_err(|_| SystemError::EINVAL)?;
+
+        // 已经挂载的文件系统之后的请求都会失败
+        dev.dead.store(true, Ordering::SeqCst);
+        dev.conn.shutdown();
+        return Ok(());
+    }
+
+    fn virtio_id_table(&self) -> LinkedList<VirtioDeviceId> {
+        self.inner().virtio_driver_common.id_table.clone()
+    }
+
+    fn add_virtio_id(&self, id: VirtioDeviceId) {
+        self.inner().virtio_driver_common.id_table.push_back(id);
+    }
+}
+
+impl Driver for VirtIOFsDriver {
+    fn id_table(&self) -> Option<IdTable> {
+        Some(IdTable::new(VIRTIO_FS_BASENAME.to_string(), None))
+    }
+
+    fn add_device(&self, device: Arc<dyn Device>) {
+        let iface = device
+            .arc_any()
+            .downcast::<VirtIOFsDevice>()
+            .expect("VirtIOFsDriver::add_device() failed: device is not a VirtIOFsDevice");
+
+        self.inner()
+            .driver_common
+            .devices
+            .push(iface as Arc<dyn Device>);
+    }
+
+    fn delete_device(&self, device: &Arc<dyn Device>) {
+        let mut guard = self.inner();
+        let index = guard
+            .driver_common
+            .devices
+            .iter()
+            .position(|dev| Arc::ptr_eq(device, dev))
+            .expect("VirtIOFsDriver::delete_device() failed: device not found");
+
+        guard.driver_common.devices.remove(index);
+    }
+
+    fn devices(&self) -> Vec<Arc<dyn Device>> {
+        self.inner().driver_common.devices.clone()
+    }
+
+    fn __for_each_device(&self, f: &mut dyn FnMut(&Arc<dyn Device>) -> ControlFlow<()>) {
+        self.inner().driver_common.for_each_device(f);
+    }
+
+    fn device_count(&self) -> usize {
+        self.inner().driver_common.devices.len()
+    }
+
+    fn bus(&self) -> Option<Weak<dyn Bus>> {
+        Some(Arc::downgrade(&virtio_bus()) as Weak<dyn Bus>)
+    }
+
+    fn set_bus(&self, _bus: Option<Weak<dyn Bus>>) {
+        // do nothing
+    }
+}
+
+impl KObject for VirtIOFsDriver {
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_inode(&self, inode: Option<Arc<KernFSInode>>) {
+        self.inner().kobj_common.kern_inode = inode;
+    }
+
+    fn inode(&self) -> Option<Arc<KernFSInode>> {
+        self.inner().kobj_common.kern_inode.clone()
+    }
+
+    fn parent(&self) -> Option<Weak<dyn KObject>> {
+        self.inner().kobj_common.parent.clone()
+    }
+
+    fn set_parent(&self, parent: Option<Weak<dyn KObject>>) {
+        self.inner().kobj_common.parent = parent;
+    }
+
+    fn kset(&self) -> Option<Arc<KSet>> {
+        self.inner().kobj_common.kset.clone()
+    }
+
+    fn set_kset(&self, kset: Option<Arc<KSet>>) {
+        self.inner().kobj_common.kset = kset;
+    }
+
+    fn kobj_type(&self) -> Option<&'static dyn KObjType> {
+        self.inner().kobj_common.kobj_type
+    }
+
+    fn set_kobj_type(&self, ktype: Option<&'static dyn KObjType>) {
+        self.inner().kobj_common.kobj_type = ktype;
+    }
+
+    fn name(&self) -> String {
+        VIRTIO_FS_BASENAME.to_string()
+    }
+
+    fn set_name(&self, _name: String) {
+        // do nothing
+    }
+
+    fn kobj_state(&self) -> RwLockReadGuard<KObjectState> {
+        self.kobj_state.read()
+    }
+
+    fn kobj_state_mut(&self) -> RwLockWriteGuard<KObjectState> {
+        self.kobj_state.write()
+    }
+
+    fn set_kobj_state(&self, state: KObjectState) {
+        *self.kobj_state.write() = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tag() {
+        let mut raw = [0u8; VIRTIO_FS_TAG_LEN];
+        raw[..6].copy_from_slice(b"shared");
+        assert_eq!(virtio_fs_parse_tag(&raw).as_deref(), Ok("shared"));
+
+        // tag占满整个字段时没有结尾的0
+        let full = [b'a'; VIRTIO_FS_TAG_LEN];
+        assert_eq!(virtio_fs_parse_tag(&full).unwrap().len(), VIRTIO_FS_TAG_LEN);
+
+        assert_eq!(
+            virtio_fs_parse_tag(&[0u8; VIRTIO_FS_TAG_LEN]),
+            Err(SystemError::EINVAL)
+        );
+        raw[0] = 0xff;
+        assert_eq!(virtio_fs_parse_tag(&raw), Err(SystemError::EINVAL));
+    }
+}
@@ -147,6 +147,7 @@ pub struct InnerKernFSInode {
     metadata: Metadata,
     /// 符号链接指向的inode（仅当inode_type为SymLink时有效）
     symlink_target: Option<Weak<KernFSInode>>,
+    /// 符号链接的内容，可以是绝对路径或相对路径（仅当inode_type为SymLink时有效）
     symlink_target_absolute_path: Option<String>,
 }
 
@@ -581,17 +582,28 @@ impl KernFSInode {
     /// - `parent`: directory to create the symlink in
     /// - `name`: name of the symlink
     /// - `target`: target node for the symlink to point to
+    /// - `target_path`: 符号链接的内容。可以是绝对路径，也可以是相对于当前目录的路径
     ///
     /// Returns the created node on success
     ///
+    /// - 如果当前inode不是目录，返回ENOTDIR
+    /// - 如果同名的目录项已经存在，返回EEXIST
+    ///
     /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/fs/kernfs/symlink.c#25
     pub fn add_link(
         &self,
         name: String,
         target: &Arc<KernFSInode>,
-        target_absolute_path: String,
+        target_path: String,
     ) -> Result<Arc<KernFSInode>, SystemError> {
-        // debug!("kernfs add link: name:{name}, target path={target_absolute_path}");
+        // debug!("kernfs add link: name:{name}, target path={target_path}");
+        if unlikely(self.inode_type != KernInodeType::Dir) {
+            return Err(SystemError::ENOTDIR);
+        }
+        if unlikely(self.children.lock().contains_key(&name)) {
+            return Err(SystemError::EEXIST);
+        }
+
         let inode = self.inner_create(
             name,
             KernInodeType::SymLink,
@@ -602,10 +614,83 @@ impl KernFSInode {
         )?;
 
         inode.inner.write().symlink_target = Some(Arc::downgrade(target));
-        inode.inner.write().symlink_target_absolute_path = Some(target_absolute_path);
+        inode.inner.write().symlink_target_absolute_path = Some(target_path);
         return Ok(inode);
     }
 
+    /// 在当前目录下创建一个指向`target`的符号链接，链接内容为从当前目录到`target`的相对路径
+    ///
+    /// 要求`target`与当前目录位于同一个kernfs中，否则返回EINVAL
+    ///
+    /// 参考 https://code.dragonos.org.cn/xref/linux-6.1.9/fs/kernfs/symlink.c#55
+    pub fn add_relative_link(
+        &self,
+        name: String,
+        target: &Arc<KernFSInode>,
+    ) -> Result<Arc<KernFSInode>, SystemError> {
+        let path = self.relative_path_to(target).ok_or(SystemError::EINVAL)?;
+        return self.add_link(name, target, path);
+    }
+
+    /// 计算从当前inode到`target`的相对路径，例如`../../devices/pci0000:00`
+    ///
+    /// 如果两者没有公共祖先（不在同一个kernfs中），返回None
+    fn relative_path_to(&self, target: &Arc<KernFSInode>) -> Option<String> {
+        let ancestors = |inode: Arc<KernFSInode>| {
+            let mut v = Vec::new();
+            let mut p = Some(inode);
+            while let Some(inode) = p {
+                // 根目录的parent指向它自己
+                p = inode.parent().filter(|parent| !Arc::ptr_eq(parent, &inode));
+                v.push(inode);
+            }
+            v.reverse();
+            v
+        };
+
+        let from = ancestors(self.self_ref.upgrade()?);
+        let to = ancestors(target.clone());
+        if from.is_empty() || to.is_empty() || !Arc::ptr_eq(&from[0], &to[0]) {
+            return None;
+        }
+
+        let common = from
+            .iter()
+            .zip(to.iter())
+            .take_while(|(a, b)| Arc::ptr_eq(a, b))
+            .count();
+
+        let mut parts: Vec<&str> = Vec::new();
+        parts.resize(from.len() - common, "..");
+        parts.extend(to[common..].iter().map(|inode| inode.name()));
+        if parts.is_empty() {
+            return Some(String::from("."));
+        }
+
+        return Some(parts.join("/"));
+    }
+
+    /// 删除当前目录下名为`name`的符号链接
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：()
+    /// - 目录项不存在：ENOENT
+    /// - 目录项不是符号链接：EINVAL
+    pub fn remove_link(&self, name: &str) -> Result<(), SystemError> {
+        if unlikely(self.inode_type != KernInodeType::Dir) {
+            return Err(SystemError::ENOTDIR);
+        }
+
+        let mut children = self.children.lock();
+        let inode = children.get(name).ok_or(SystemError::ENOENT)?;
+        if inode.inode_type != KernInodeType::SymLink {
+            return Err(SystemError::EINVAL);
+        }
+        children.remove(name);
+        return Ok(());
+    }
+
     pub fn name(&self) -> &str {
         return &self.name;
     }
@@ -623,6 +708,28 @@ impl KernFSInode {
         return self.inner.read().symlink_target.as_ref()?.upgrade();
     }
 
+    /// 获取符号链接的内容（仅当inode_type为SymLink时有效）
+    #[allow(dead_code)]
+    pub fn symlink_target_path(&self) -> Option<String> {
+        return self.inner.read().symlink_target_absolute_path.clone();
+    }
+
+    /// 查找名为`name`的子节点，没有找到时返回None
+    pub fn find_child(&self, name: &str) -> Option<Arc<KernFSInode>> {
+        return self.children.lock().get(name).cloned();
+    }
+
+    pub fn inode_type(&self) -> KernInodeType {
+        return self.inode_type;
+    }
+
+    /// 所有子节点的名称，按名称排序
+    pub fn child_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.children.lock().keys().cloned().collect();
+        names.sort();
+        return names;
+    }
+
     /// remove a kernfs_node recursively
     pub fn remove_recursive(&self) {
         let mut children = self.children.lock().drain().collect::<Vec<_>>();
@@ -657,3 +764,176 @@ impl From<KernInodeType> for FileType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::filesystem::vfs::VFS_MAX_FOLLOW_SYMLINK_TIMES;
+
+    fn mode() -> ModeType {
+        ModeType::from_bits_truncate(0o755)
+    }
+
+    fn inode_id(inode: &Arc<dyn IndexNode>) -> InodeId {
+        inode.metadata().unwrap().inode_id
+    }
+
+    /// 创建`devices/pci0/dev0/attr`和`class/net`
+    fn setup() -> (Arc<KernFS>, Arc<KernFSInode>, Arc<KernFSInode>) {
+        let fs = KernFS::new();
+        let root = fs.root_inode.clone();
+        let dev = root
+            .add_dir("devices".to_string(), mode(), None, None)
+            .unwrap()
+            .add_dir("pci0".to_string(), mode(), None, None)
+            .unwrap()
+            .add_dir("dev0".to_string(), mode(), None, None)
+            .unwrap();
+        dev.add_file("attr".to_string(), mode(), None, None, None)
+            .unwrap();
+        let net = root
+            .add_dir("class".to_string(), mode(), None, None)
+            .unwrap()
+            .add_dir("net".to_string(), mode(), None, None)
+            .unwrap();
+        (fs, dev, net)
+    }
+
+    #[test]
+    fn test_relative_link_resolves_to_target() {
+        let (fs, dev, net) = setup();
+        let link = net.add_relative_link("eth0".to_string(), &dev).unwrap();
+        assert_eq!(link.inode_type(), KernInodeType::SymLink);
+        assert_eq!(
+            link.symlink_target_path().as_deref(),
+            Some("../../devices/pci0/dev0")
+        );
+        assert!(Arc::ptr_eq(&link.symlink_target().unwrap(), &dev));
+
+        let root: Arc<dyn IndexNode> = fs.root_inode.clone();
+        let dev: Arc<dyn IndexNode> = dev;
+        let found = root.lookup("class/net/eth0").unwrap();
+        assert_eq!(found.metadata().unwrap().file_type, FileType::SymLink);
+        let found = root
+            .lookup_follow_symlink("class/net/eth0", VFS_MAX_FOLLOW_SYMLINK_TIMES)
+            .unwrap();
+        assert_eq!(inode_id(&found), inode_id(&dev));
+
+        // 路径中间的符号链接总是被解析
+        let attr = root.lookup("class/net/eth0/attr").unwrap();
+        assert_eq!(inode_id(&attr), inode_id(&dev.find("attr").unwrap()));
+
+        // 根目录下的链接不会越过根目录
+        let top = fs
+            .root_inode
+            .add_relative_link("dev0".to_string(), &link.symlink_target().unwrap())
+            .unwrap();
+        assert_eq!(
+            top.symlink_target_path().as_deref(),
+            Some("devices/pci0/dev0")
+        );
+        let found = root
+            .lookup_follow_symlink("dev0", VFS_MAX_FOLLOW_SYMLINK_TIMES)
+            .unwrap();
+        assert_eq!(inode_id(&found), inode_id(&dev));
+    }
+
+    #[test]
+    fn test_absolute_link_resolves_from_root() {
+        let (fs, dev, net) = setup();
+        net.add_link("eth0".to_string(), &dev, "/devices/pci0/dev0".to_string())
+            .unwrap();
+
+        let root: Arc<dyn IndexNode> = fs.root_inode.clone();
+        let root_fn = || root.clone();
+        let found = root
+            .lookup_walk(
+                &root_fn,
+                "class/net/eth0",
+                VFS_MAX_FOLLOW_SYMLINK_TIMES,
+                true,
+            )
+            .unwrap();
+        let dev: Arc<dyn IndexNode> = dev;
+        assert_eq!(inode_id(&found), inode_id(&dev));
+
+        // 从其它目录开始查找，绝对路径的链接仍然从根目录解析
+        let net: Arc<dyn IndexNode> = net;
+        let found = net
+            .lookup_walk(&root_fn, "eth0/attr", VFS_MAX_FOLLOW_SYMLINK_TIMES, true)
+            .unwrap();
+        assert_eq!(inode_id(&found), inode_id(&dev.find("attr").unwrap()));
+    }
+
+    #[test]
+    fn test_nofollow_resolves_intermediate_links() {
+        let (fs, dev, net) = setup();
+        net.add_relative_link("eth0".to_string(), &dev).unwrap();
+        // class/net/link -> eth0 -> ../../devices/pci0/dev0
+        net.add_link("link".to_string(), &dev, "eth0".to_string())
+            .unwrap();
+        let root: Arc<dyn IndexNode> = fs.root_inode.clone();
+        let dev: Arc<dyn IndexNode> = dev;
+
+        // O_NOFOLLOW：最后一级的符号链接不解析
+        let found = root
+            .lookup_follow_symlink2("class/net/link", VFS_MAX_FOLLOW_SYMLINK_TIMES, false)
+            .unwrap();
+        assert_eq!(found.metadata().unwrap().file_type, FileType::SymLink);
+
+        // 路径中间的两级符号链接仍然按照完整的次数限制解析
+        let attr = root
+            .lookup_follow_symlink2("class/net/link/attr", VFS_MAX_FOLLOW_SYMLINK_TIMES, false)
+            .unwrap();
+        assert_eq!(inode_id(&attr), inode_id(&dev.find("attr").unwrap()));
+
+        // 超过次数限制时返回ELOOP，而不是返回中间的符号链接
+        assert_eq!(
+            root.lookup_follow_symlink2("class/net/link/attr", 1, false)
+                .err(),
+            Some(SystemError::ELOOP)
+        );
+    }
+
+    #[test]
+    fn test_link_loop() {
+        let (fs, _, net) = setup();
+        net.add_link("loop".to_string(), &net, "loop".to_string())
+            .unwrap();
+        let root: Arc<dyn IndexNode> = fs.root_inode.clone();
+        assert_eq!(
+            root.lookup_follow_symlink("class/net/loop", VFS_MAX_FOLLOW_SYMLINK_TIMES)
+                .err(),
+            Some(SystemError::ELOOP)
+        );
+        assert_eq!(
+            root.lookup("class/net/loop/attr").err(),
+            Some(SystemError::ELOOP)
+        );
+    }
+
+    #[test]
+    fn test_remove_link() {
+        let (fs, dev, net) = setup();
+        net.add_relative_link("eth0".to_string(), &dev).unwrap();
+        assert_eq!(
+            net.add_relative_link("eth0".to_string(), &dev).err(),
+            Some(SystemError::EEXIST)
+        );
+
+        let class = fs.root_inode.find_child("class").unwrap();
+        assert_eq!(class.remove_link("net"), Err(SystemError::EINVAL));
+        net.remove_link("eth0").unwrap();
+        assert_eq!(net.remove_link("eth0"), Err(SystemError::ENOENT));
+
+        // 删除链接不影响它指向的目录
+        let root: Arc<dyn IndexNode> = fs.root_inode.clone();
+        assert_eq!(
+            root.lookup("class/net/eth0").err(),
+            Some(SystemError::ENOENT)
+        );
+        assert!(root.lookup("devices/pci0/dev0/attr").is_ok());
+    }
+}
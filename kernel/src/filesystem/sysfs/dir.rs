@@ -7,7 +7,7 @@ use alloc::{
 use system_error::SystemError;
 
 use crate::{
-    driver::base::kobject::KObject,
+    driver::base::kobject::{KObject, KObjectState},
     filesystem::{
         kernfs::{callback::KernInodePrivateData, KernFSInode},
         vfs::syscall::ModeType,
@@ -72,10 +72,27 @@ impl SysFS {
         )?;
 
         kobj.set_inode(Some(dir.clone()));
+        kobj.update_kobj_state(Some(KObjectState::IN_SYSFS), None);
 
         return Ok(dir);
     }
 
+    /// 获取kobject在sysfs中的目录
+    ///
+    /// 只能对已经加入sysfs的kobject调用，否则说明调用者在kobject加入sysfs之前
+    /// (或者移除之后)就在操作它的属性
+    pub(super) fn kobj_dir(
+        &self,
+        kobj: &Arc<dyn KObject>,
+    ) -> Result<Arc<KernFSInode>, SystemError> {
+        debug_assert!(
+            kobj.check_kobj_state(KObjectState::IN_SYSFS),
+            "kobject '{}' is not in sysfs",
+            kobj.name()
+        );
+        return kobj.inode().ok_or(SystemError::EINVAL);
+    }
+
     /// 获取指定的kernfs inode在sysfs中的路径（不包含`/sys`）
     ///
     /// ## 参数
@@ -118,6 +135,7 @@ impl SysFS {
     pub fn remove_dir(&self, kobj: &Arc<dyn KObject>) {
         let kobj_inode = kobj.inode();
         kobj.set_inode(None);
+        kobj.update_kobj_state(None, Some(KObjectState::IN_SYSFS));
 
         if let Some(inode) = kobj_inode {
             let parent = inode.parent().unwrap();
@@ -107,7 +107,7 @@ impl SysFS {
         kobj: &Arc<dyn KObject>,
         attr: &'static dyn Attribute,
     ) -> Result<(), SystemError> {
-        let inode = kobj.inode().ok_or(SystemError::EINVAL)?;
+        let inode = self.kobj_dir(kobj)?;
         return self.add_file_with_mode(&inode, attr, attr.mode());
     }
 
@@ -206,7 +206,7 @@ impl SysFS {
         kobj: &Arc<dyn KObject>,
         attr: &Arc<dyn BinAttribute>,
     ) -> Result<(), SystemError> {
-        let inode = kobj.inode().ok_or(SystemError::EINVAL)?;
+        let inode = self.kobj_dir(kobj)?;
         return self.add_bin_file_with_mode(&inode, attr, attr.mode());
     }
 
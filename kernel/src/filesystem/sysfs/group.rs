@@ -58,7 +58,7 @@ impl SysFS {
         update: bool,
     ) -> Result<(), SystemError> {
         // kobj的inode必须存在
-        let kobj_inode = kobj.inode().ok_or(SystemError::EINVAL)?;
+        let kobj_inode = self.kobj_dir(kobj)?;
 
         if group.attrs().is_empty() {
             return Err(SystemError::EINVAL);
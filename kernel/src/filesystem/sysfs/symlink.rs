@@ -1,8 +1,4 @@
-use alloc::{
-    borrow::ToOwned,
-    string::{String, ToString},
-    sync::Arc,
-};
+use alloc::{string::String, sync::Arc};
 use system_error::SystemError;
 
 use crate::{driver::base::kobject::KObject, filesystem::kernfs::KernFSInode};
@@ -38,8 +34,12 @@ impl SysFS {
     ///
     ///
     /// 参考：https://code.dragonos.org.cn/xref/linux-6.1.9/fs/sysfs/symlink.c#143
-    pub fn remove_link(&self, _kobj: &Arc<dyn KObject>, _name: String) {
-        todo!("sysfs remove link")
+    pub fn remove_link(&self, kobj: &Arc<dyn KObject>, name: String) {
+        let parent = kobj.inode();
+        if let Some(parent) = parent {
+            // 与Linux一致，符号链接不存在时静默返回
+            parent.remove_link(&name).ok();
+        }
     }
 
     fn do_create_link(
@@ -50,7 +50,7 @@ impl SysFS {
         warn: bool,
     ) -> Result<(), SystemError> {
         let parent = if let Some(kobj) = kobj {
-            kobj.inode()
+            self.kobj_dir(kobj).ok()
         } else {
             Some(self.root_inode().clone())
         };
@@ -70,11 +70,8 @@ impl SysFS {
     ) -> Result<(), SystemError> {
         let target_inode = target.inode().ok_or(SystemError::ENOENT)?;
 
-        let target_abs_path = "/sys".to_string() + &self.kernfs_path(&target_inode).to_owned();
-        // let current_path = self.kernfs_path(inode);
-        // debug!("sysfs: create link {} to {}", current_path, target_abs_path);
-
-        let kn = inode.add_link(name.clone(), &target_inode, target_abs_path);
+        // 与Linux一致，链接内容为相对路径，不依赖sysfs的挂载点
+        let kn = inode.add_relative_link(name.clone(), &target_inode);
         if kn.is_ok() {
             return Ok(());
         }
@@ -585,14 +585,14 @@ impl dyn IndexNode {
         return self.as_any_ref().downcast_ref::<T>();
     }
 
-    /// @brief 查找文件（不考虑符号链接）
+    /// @brief 查找文件（路径中间的符号链接会被解析，最后一级的符号链接不解析）
     ///
     /// @param path 文件路径
     ///
     /// @return Ok(Arc<dyn IndexNode>) 要寻找的目录项的inode
     /// @return Err(SystemError) 错误码
     pub fn lookup(&self, path: &str) -> Result<Arc<dyn IndexNode>, SystemError> {
-        return self.lookup_follow_symlink(path, 0);
+        return self.lookup_follow_symlink2(path, VFS_MAX_FOLLOW_SYMLINK_TIMES, false);
     }
 
     /// @brief 查找文件（考虑符号链接）
@@ -606,6 +606,42 @@ impl dyn IndexNode {
         &self,
         path: &str,
         max_follow_times: usize,
+    ) -> Result<Arc<dyn IndexNode>, SystemError> {
+        return self.lookup_follow_symlink2(path, max_follow_times, true);
+    }
+
+    /// @brief 查找文件，路径中间的符号链接总是被解析
+    ///
+    /// @param path 文件路径
+    /// @param max_follow_times 最多解析的符号链接数量，超过时返回ELOOP
+    /// @param follow_final_symlink 是否解析路径最后一级的符号链接，为false时返回符号链接本身(O_NOFOLLOW)
+    ///
+    /// @return Ok(Arc<dyn IndexNode>) 要寻找的目录项的inode
+    /// @return Err(SystemError) 错误码
+    pub fn lookup_follow_symlink2(
+        &self,
+        path: &str,
+        max_follow_times: usize,
+        follow_final_symlink: bool,
+    ) -> Result<Arc<dyn IndexNode>, SystemError> {
+        return self.lookup_walk(&ROOT_INODE, path, max_follow_times, follow_final_symlink);
+    }
+
+    /// @brief 逐级查找文件
+    ///
+    /// @param root 获取根目录的函数，绝对路径以及绝对路径的符号链接从这里开始查找
+    /// @param path 文件路径
+    /// @param max_follow_times 最多解析的符号链接数量，超过时返回ELOOP
+    /// @param follow_last 是否解析路径最后一级的符号链接
+    ///
+    /// @return Ok(Arc<dyn IndexNode>) 要寻找的目录项的inode
+    /// @return Err(SystemError) 错误码
+    pub(super) fn lookup_walk(
+        &self,
+        root: &dyn Fn() -> Arc<dyn IndexNode>,
+        path: &str,
+        max_follow_times: usize,
+        follow_last: bool,
     ) -> Result<Arc<dyn IndexNode>, SystemError> {
         if self.metadata()?.file_type != FileType::Dir {
             return Err(SystemError::ENOTDIR);
@@ -615,7 +651,7 @@ impl dyn IndexNode {
         // result: 上一个被找到的inode
         // rest_path: 还没有查找的路径
         let (mut result, mut rest_path) = if let Some(rest) = path.strip_prefix('/') {
-            (ROOT_INODE().clone(), String::from(rest))
+            (root(), String::from(rest))
         } else {
             // 是相对路径
             (self.find(".")?, String::from(path))
@@ -651,8 +687,13 @@ impl dyn IndexNode {
 
             let inode = result.find(&name)?;
 
-            // 处理符号链接的问题
-            if inode.metadata()?.file_type == FileType::SymLink && max_follow_times > 0 {
+            // 处理符号链接的问题：路径中间的符号链接总是需要解析，最后一级按照follow_last决定
+            let is_last = rest_path.trim_start_matches('/').is_empty();
+            if inode.metadata()?.file_type == FileType::SymLink && (!is_last || follow_last) {
+                if max_follow_times == 0 {
+                    return Err(SystemError::ELOOP);
+                }
+
                 let mut content = [0u8; 256];
                 // 读取符号链接
                 let len = inode.read_at(
@@ -668,8 +709,8 @@ impl dyn IndexNode {
                 );
 
                 let new_path = link_path + "/" + &rest_path;
-                // 继续查找符号链接
-                return result.lookup_follow_symlink(&new_path, max_follow_times - 1);
+                // 继续查找符号链接。相对路径的符号链接相对于它所在的目录
+                return result.lookup_walk(root, &new_path, max_follow_times - 1, follow_last);
             } else {
                 result = inode;
             }
@@ -797,6 +838,7 @@ bitflags! {
     pub struct Magic: u64 {
         const DEVFS_MAGIC = 0x1373;
         const FAT_MAGIC =  0xf2f52011;
+        const FUSE_MAGIC = 0x65735546;
         const KER_MAGIC = 0x3153464b;
         const PROC_MAGIC = 0x9fa0;
         const RAMFS_MAGIC = 0x858458f6;
@@ -839,11 +839,8 @@ impl Syscall {
             return Err(SystemError::EINVAL);
         }
         // TODO AT_EMPTY_PATH标志启用时，进行调用者CAP_DAC_READ_SEARCH或相似的检查
-        let symlink_times = if flags.contains(AtFlags::AT_SYMLINK_FOLLOW) {
-            0_usize
-        } else {
-            VFS_MAX_FOLLOW_SYMLINK_TIMES
-        };
+        // 只有指定了AT_SYMLINK_FOLLOW时才解析old最后一级的符号链接
+        let follow_symlink = flags.contains(AtFlags::AT_SYMLINK_FOLLOW);
         let pcb = ProcessManager::current_pcb();
 
         // 得到源路径的inode
@@ -862,7 +859,11 @@ impl Syscall {
             }
         } else {
             let (old_begin_inode, old_remain_path) = user_path_at(&pcb, oldfd, old)?;
-            old_begin_inode.lookup_follow_symlink(&old_remain_path, symlink_times)?
+            old_begin_inode.lookup_follow_symlink2(
+                &old_remain_path,
+                VFS_MAX_FOLLOW_SYMLINK_TIMES,
+                follow_symlink,
+            )?
         };
 
         // old_inode为目录时返回EPERM
@@ -873,8 +874,8 @@ impl Syscall {
         // 得到新创建节点的父节点
         let (new_begin_inode, new_remain_path) = user_path_at(&pcb, newfd, new)?;
         let (new_name, new_parent_path) = rsplit_path(&new_remain_path);
-        let new_parent =
-            new_begin_inode.lookup_follow_symlink(new_parent_path.unwrap_or("/"), symlink_times)?;
+        let new_parent = new_begin_inode
+            .lookup_follow_symlink(new_parent_path.unwrap_or("/"), VFS_MAX_FOLLOW_SYMLINK_TIMES)?;
 
         // 被调用者利用downcast_ref判断两inode是否为同一文件系统
         return new_parent.link(new_name, &old_inode).map(|_| 0);
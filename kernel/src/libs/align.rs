@@ -114,6 +114,52 @@ impl<T: Clone + SafeForZero, const ALIGN: usize> Clone for AlignedBox<T, ALIGN>
     }
 }
 
+/// 缓存行的大小
+pub const CACHE_LINE_SIZE: usize = 64;
+
+/// # CachePadded
+///
+/// 把值对齐并填充到一个缓存行，使得不同CPU频繁写入的数据不落在同一个缓存行中(避免伪共享)
+///
+/// 对齐要求与[`CACHE_LINE_SIZE`]保持一致
+#[repr(C, align(64))]
+#[derive(Default, Clone)]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+const _: () = assert!(core::mem::align_of::<CachePadded<u8>>() == CACHE_LINE_SIZE);
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> core::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T> core::ops::DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<T: Debug> Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
 /// 一个用于表明某个类型是安全的用于零初始化的 trait
 ///
 /// 该 trait 用于表明某个类型是安全的用于零初始化的，即该类型的所有位都可以被初始化为 0 而不会出现未定义行为。
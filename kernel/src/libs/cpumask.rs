@@ -1,5 +1,7 @@
 use core::ops::BitAnd;
 
+use alloc::string::String;
+
 use bitmap::{traits::BitMapOps, AllocBitmap};
 
 use crate::{mm::percpu::PerCpu, smp::cpu::ProcessorId};
@@ -105,6 +107,35 @@ impl CpuMask {
     pub fn bitand_assign(&mut self, rhs: &CpuMask) {
         self.bmp.bitand_assign(&rhs.bmp);
     }
+
+    /// # to_hex_string - 以Linux的cpumask格式输出
+    ///
+    /// 每32个CPU为一组，输出为8位十六进制数，组之间用逗号分隔，编号大的组在前。
+    /// 例如4个CPU时输出"f"，40个CPU时输出"ff,ffffffff"
+    ///
+    /// ## 参数
+    /// - `nr_cpus`: 输出的CPU数量，最高的一组只输出容纳这些CPU所需的位数
+    pub fn to_hex_string(&self, nr_cpus: usize) -> String {
+        let nr_cpus = nr_cpus.max(1);
+        let groups = nr_cpus.div_ceil(32);
+        let mut s = String::new();
+        for group in (0..groups).rev() {
+            let mut word: u32 = 0;
+            for bit in 0..32 {
+                let cpu = group * 32 + bit;
+                if cpu < nr_cpus && self.get(ProcessorId::new(cpu as u32)) == Some(true) {
+                    word |= 1 << bit;
+                }
+            }
+            if group == groups - 1 {
+                let width = (nr_cpus - group * 32).div_ceil(4);
+                s.push_str(&format!("{:0width$x}", word, width = width));
+            } else {
+                s.push_str(&format!(",{:08x}", word));
+            }
+        }
+        s
+    }
 }
 
 impl BitAnd for &CpuMask {
@@ -160,3 +191,30 @@ impl core::fmt::Debug for CpuMask {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn mask_of(cpus: &[u32]) -> CpuMask {
+        let mut mask = CpuMask::new();
+        for cpu in cpus {
+            mask.set(ProcessorId::new(*cpu), true);
+        }
+        mask
+    }
+
+    #[test]
+    fn test_to_hex_string() {
+        assert_eq!(mask_of(&[0, 1, 2, 3]).to_hex_string(4), "f");
+        assert_eq!(mask_of(&[0, 2]).to_hex_string(8), "05");
+        assert_eq!(mask_of(&[0]).to_hex_string(1), "1");
+        assert_eq!(mask_of(&[]).to_hex_string(4), "0");
+        assert_eq!(mask_of(&[0, 31]).to_hex_string(32), "80000001");
+        let all: Vec<u32> = (0..40).collect();
+        assert_eq!(mask_of(&all).to_hex_string(40), "ff,ffffffff");
+        assert_eq!(mask_of(&[33]).to_hex_string(40), "02,00000000");
+    }
+}
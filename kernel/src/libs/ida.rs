@@ -0,0 +1,149 @@
+use bitmap::{traits::BitMapOps, AllocBitmap};
+use system_error::SystemError;
+
+use super::spinlock::SpinLock;
+
+/// # 结构功能
+/// 基于位图的小整数id分配器，可以在多个子系统之间共享（例如设备编号、MSI-X中断向量、次设备号）
+///
+/// 分配的id范围为`[0, capacity)`，并且总是返回满足条件的最小id
+pub struct Ida {
+    inner: SpinLock<AllocBitmap>,
+}
+
+#[allow(dead_code)]
+impl Ida {
+    /// 创建一个能够分配`[0, capacity)`范围内id的分配器
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: SpinLock::new(AllocBitmap::new(capacity)),
+        }
+    }
+
+    /// 分配器能够分配的id数量
+    pub fn capacity(&self) -> usize {
+        self.inner.lock_irqsave().len()
+    }
+
+    /// # 函数的功能
+    /// 分配一个未使用的最小id
+    ///
+    /// ## 返回值
+    /// - Ok(id): 分配得到的id
+    /// - Err(SystemError::ENOSPC): 没有可用的id
+    pub fn alloc(&self) -> Result<usize, SystemError> {
+        self.alloc_range(0, usize::MAX)
+    }
+
+    /// # 函数的功能
+    /// 在`[min, max)`范围内分配一个未使用的最小id
+    ///
+    /// ## 参数
+    /// - `min`: 可分配的最小id
+    /// - `max`: 可分配id的上界（不包含），超出分配器容量的部分会被忽略
+    ///
+    /// ## 返回值
+    /// - Ok(id): 分配得到的id
+    /// - Err(SystemError::ENOSPC): 范围内没有可用的id
+    pub fn alloc_range(&self, min: usize, max: usize) -> Result<usize, SystemError> {
+        let mut bmp = self.inner.lock_irqsave();
+        let max = core::cmp::min(max, bmp.len());
+        if min >= max {
+            return Err(SystemError::ENOSPC);
+        }
+
+        let id = match bmp.get(min) {
+            Some(false) => Some(min),
+            _ => bmp.next_false_index(min),
+        };
+
+        match id {
+            Some(id) if id < max => {
+                bmp.set(id, true);
+                Ok(id)
+            }
+            _ => Err(SystemError::ENOSPC),
+        }
+    }
+
+    /// # 函数的功能
+    /// 释放一个id，使其可以被再次分配
+    ///
+    /// 释放一个未被分配（或超出范围）的id不会产生任何影响
+    pub fn free(&self, id: usize) {
+        self.inner.lock_irqsave().set(id, false);
+    }
+
+    /// 判断id是否已经被分配
+    pub fn is_allocated(&self, id: usize) -> bool {
+        self.inner.lock_irqsave().get(id).unwrap_or(false)
+    }
+}
+
+impl core::fmt::Debug for Ida {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Ida")
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ida;
+    use system_error::SystemError;
+
+    #[test]
+    fn test_alloc() {
+        let ida = Ida::new(4);
+        assert_eq!(ida.alloc(), Ok(0));
+        assert_eq!(ida.alloc(), Ok(1));
+        assert_eq!(ida.alloc(), Ok(2));
+        assert_eq!(ida.alloc(), Ok(3));
+        assert_eq!(ida.alloc(), Err(SystemError::ENOSPC));
+    }
+
+    #[test]
+    fn test_free_and_reuse() {
+        let ida = Ida::new(4);
+        for _ in 0..4 {
+            ida.alloc().unwrap();
+        }
+        ida.free(2);
+        assert!(!ida.is_allocated(2));
+        assert_eq!(ida.alloc(), Ok(2));
+        assert!(ida.is_allocated(2));
+
+        // 总是返回最小的空闲id
+        ida.free(3);
+        ida.free(1);
+        assert_eq!(ida.alloc(), Ok(1));
+        assert_eq!(ida.alloc(), Ok(3));
+        assert_eq!(ida.alloc(), Err(SystemError::ENOSPC));
+    }
+
+    #[test]
+    fn test_alloc_range() {
+        let ida = Ida::new(16);
+        assert_eq!(ida.alloc_range(4, 6), Ok(4));
+        assert_eq!(ida.alloc_range(4, 6), Ok(5));
+        assert_eq!(ida.alloc_range(4, 6), Err(SystemError::ENOSPC));
+        assert_eq!(ida.alloc(), Ok(0));
+
+        // 上界超出容量时被截断
+        assert_eq!(ida.alloc_range(15, 100), Ok(15));
+        assert_eq!(ida.alloc_range(15, 100), Err(SystemError::ENOSPC));
+
+        // 空范围
+        assert_eq!(ida.alloc_range(8, 8), Err(SystemError::ENOSPC));
+        assert_eq!(ida.alloc_range(20, 30), Err(SystemError::ENOSPC));
+    }
+
+    #[test]
+    fn test_free_out_of_range() {
+        let ida = Ida::new(2);
+        ida.free(10);
+        assert!(!ida.is_allocated(10));
+        assert_eq!(ida.alloc(), Ok(0));
+    }
+}
@@ -2,6 +2,7 @@ pub mod align;
 pub mod casting;
 pub mod cpumask;
 pub mod elf;
+pub mod ida;
 #[macro_use]
 pub mod int_like;
 pub mod keyboard_parser;
@@ -17,6 +18,7 @@ pub mod rbtree;
 #[macro_use]
 pub mod rwlock;
 pub mod semaphore;
+pub mod seqlock;
 pub mod spinlock;
 pub mod vec_cursor;
 #[macro_use]
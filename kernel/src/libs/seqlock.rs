@@ -0,0 +1,148 @@
+//! 顺序锁(seqlock)
+//!
+//! 适用于写者少、读者不希望阻塞写者的场景，例如设备的统计计数：写者之间通过自旋锁互斥，
+//! 读者不加锁，而是在读取前后比较序号，序号不同(或者读取时有写者正在写入)就重新读取，
+//! 因此读者总能得到某一时刻的完整数据，不会看到只更新了一半的字段。
+//!
+//! 参考 https://code.dragonos.org.cn/xref/linux-6.6.21/include/linux/seqlock.h
+
+use core::{
+    cell::UnsafeCell,
+    fmt::Debug,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+use super::spinlock::SpinLock;
+
+/// # 结构功能
+/// 保护一个可以按位复制的数据的顺序锁
+///
+/// 序号为奇数时表示有写者正在写入
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    /// 写者之间的互斥锁
+    writer: SpinLock<()>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+#[allow(dead_code)]
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            writer: SpinLock::new(()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// # 函数的功能
+    /// 修改数据。写者可能在中断上下文中，因此关中断加锁
+    pub fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _guard = self.writer.lock_irqsave();
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let r = f(unsafe { &mut *self.data.get() });
+        self.seq.fetch_add(1, Ordering::Release);
+        r
+    }
+
+    /// # 函数的功能
+    /// 读取数据，读取过程中数据被修改时会重新读取
+    pub fn read(&self) -> T {
+        loop {
+            let start = self.read_begin();
+            let data = unsafe { core::ptr::read_volatile(self.data.get()) };
+            if !self.read_retry(start) {
+                return data;
+            }
+        }
+    }
+
+    /// # 函数的功能
+    /// 开始一次读取，等待正在进行的写入完成
+    ///
+    /// ## 返回值
+    /// 开始读取时的序号，读取完成后交给[`Self::read_retry`]
+    pub fn read_begin(&self) -> usize {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                return seq;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// # 函数的功能
+    /// 检查读取期间数据是否被修改
+    ///
+    /// ## 返回值
+    /// - true: 数据被修改过，读到的数据可能不完整，需要重新读取
+    pub fn read_retry(&self, start: usize) -> bool {
+        fence(Ordering::Acquire);
+        self.seq.load(Ordering::Relaxed) != start
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy + Debug> Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SeqLock")
+            .field("data", &self.read())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    struct Pair {
+        packets: u64,
+        bytes: u64,
+    }
+
+    #[test]
+    fn test_read_write() {
+        let lock = SeqLock::new(Pair::default());
+        let r = lock.write(|p| {
+            p.packets += 1;
+            p.bytes += 60;
+            p.packets
+        });
+        assert_eq!(r, 1);
+        assert_eq!(
+            lock.read(),
+            Pair {
+                packets: 1,
+                bytes: 60
+            }
+        );
+    }
+
+    #[test]
+    fn test_retry_after_concurrent_write() {
+        let lock = SeqLock::new(Pair::default());
+        // 读者开始读取之后，写者修改了数据
+        let start = lock.read_begin();
+        lock.write(|p| {
+            p.packets = 2;
+            p.bytes = 120;
+        });
+        // 读者发现数据被修改，重新读取得到完整的数据
+        assert!(lock.read_retry(start));
+        let start = lock.read_begin();
+        let data = lock.read();
+        assert!(!lock.read_retry(start));
+        assert_eq!(data.packets * 60, data.bytes);
+    }
+}
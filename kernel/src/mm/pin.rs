@@ -0,0 +1,116 @@
+//! 固定(pin)的物理页
+//!
+//! 交给设备做DMA的页在设备使用期间不能被virtio-balloon回收，也不能被迁移，
+//! 否则设备会把数据写入已经回收的内存。这些页大多不在[`super::page::PageManager`]中，
+//! 因此按照物理页号单独记录固定计数：计数不为0的页不能被回收或迁移。
+
+use alloc::collections::BTreeMap;
+use system_error::SystemError;
+
+use crate::libs::spinlock::{SpinLock, SpinLockGuard};
+
+use super::allocator::page_frame::{PageFrameCount, PhysPageFrame};
+
+static PAGE_PIN_SET: SpinLock<PagePinSet> = SpinLock::new(PagePinSet::new());
+
+/// 获取被固定的物理页的集合
+///
+/// DMA内存的分配与释放可能发生在中断上下文中，因此需要关中断加锁
+pub fn page_pin_set_lock_irqsave() -> SpinLockGuard<'static, PagePinSet> {
+    PAGE_PIN_SET.lock_irqsave()
+}
+
+/// # 结构功能
+/// 被固定的物理页，以及每一页被固定的次数
+#[derive(Debug, Default)]
+pub struct PagePinSet {
+    /// 物理页号 -> 固定计数
+    pins: BTreeMap<usize, usize>,
+}
+
+impl PagePinSet {
+    pub const fn new() -> Self {
+        Self {
+            pins: BTreeMap::new(),
+        }
+    }
+
+    /// # 函数的功能
+    /// 固定从`start`开始的`count`个物理页，同一页可以被固定多次
+    pub fn pin(&mut self, start: PhysPageFrame, count: PageFrameCount) {
+        for ppn in start.ppn()..start.ppn() + count.data() {
+            *self.pins.entry(ppn).or_insert(0) += 1;
+        }
+    }
+
+    /// # 函数的功能
+    /// 解除一次对从`start`开始的`count`个物理页的固定
+    ///
+    /// ## 返回值
+    /// - Ok(()): 成功
+    /// - Err(SystemError::EINVAL): 其中有没有被固定的页，此时不修改任何页的计数
+    pub fn unpin(
+        &mut self,
+        start: PhysPageFrame,
+        count: PageFrameCount,
+    ) -> Result<(), SystemError> {
+        let range = start.ppn()..start.ppn() + count.data();
+        if range.clone().any(|ppn| !self.pins.contains_key(&ppn)) {
+            return Err(SystemError::EINVAL);
+        }
+        for ppn in range {
+            let pins = self.pins.get_mut(&ppn).unwrap();
+            *pins -= 1;
+            if *pins == 0 {
+                self.pins.remove(&ppn);
+            }
+        }
+        Ok(())
+    }
+
+    /// 物理页是否被固定，被固定的页不能被回收或迁移
+    pub fn is_pinned(&self, frame: PhysPageFrame) -> bool {
+        self.pins.contains_key(&frame.ppn())
+    }
+
+    /// 被固定的物理页的数量
+    pub fn pinned_pages(&self) -> usize {
+        self.pins.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_unpin() {
+        let mut set = PagePinSet::new();
+        let start = PhysPageFrame::from_ppn(0x100);
+        set.pin(start, PageFrameCount::new(4));
+        assert_eq!(set.pinned_pages(), 4);
+        assert!(set.is_pinned(PhysPageFrame::from_ppn(0x103)));
+        assert!(!set.is_pinned(PhysPageFrame::from_ppn(0x104)));
+
+        // 同一页被固定两次，需要解除两次
+        set.pin(PhysPageFrame::from_ppn(0x101), PageFrameCount::new(1));
+        set.unpin(start, PageFrameCount::new(4)).unwrap();
+        assert!(set.is_pinned(PhysPageFrame::from_ppn(0x101)));
+        assert!(!set.is_pinned(start));
+        set.unpin(PhysPageFrame::from_ppn(0x101), PageFrameCount::new(1))
+            .unwrap();
+        assert_eq!(set.pinned_pages(), 0);
+    }
+
+    #[test]
+    fn test_unpin_not_pinned() {
+        let mut set = PagePinSet::new();
+        set.pin(PhysPageFrame::from_ppn(0x10), PageFrameCount::new(1));
+        assert_eq!(
+            set.unpin(PhysPageFrame::from_ppn(0x10), PageFrameCount::new(2)),
+            Err(SystemError::EINVAL)
+        );
+        // 失败时不修改计数
+        assert!(set.is_pinned(PhysPageFrame::from_ppn(0x10)));
+    }
+}
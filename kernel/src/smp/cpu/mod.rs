@@ -6,7 +6,11 @@ use system_error::SystemError;
 
 use crate::{
     arch::CurrentSMPArch,
-    libs::cpumask::CpuMask,
+    libs::{
+        cpumask::CpuMask,
+        notifier::{NotifierBlock, RawNotifierChain},
+        rwlock::RwLock,
+    },
     mm::percpu::{PerCpu, PerCpuVar},
     process::{ProcessControlBlock, ProcessManager},
     sched::completion::Completion,
@@ -39,6 +43,13 @@ pub enum CpuHpState {
     Online,
 }
 
+/// CPU热插拔通知链上的事件，通知的数据为事件对应的CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuHpEvent {
+    /// CPU即将下线，此时它仍然在处理中断。订阅者需要把投递到该CPU上的中断迁移走
+    DownPrepare,
+}
+
 /// Per-Cpu Cpu的热插拔状态
 pub struct CpuHpCpuState {
     /// 当前状态
@@ -82,6 +93,8 @@ pub struct SmpCpuManager {
     possible_cnt: AtomicU32,
     /// CPU的状态
     cpuhp_state: PerCpuVar<CpuHpCpuState>,
+    /// CPU热插拔事件的通知链
+    cpuhp_notifier: RwLock<RawNotifierChain<CpuHpEvent, ProcessorId>>,
 }
 
 impl SmpCpuManager {
@@ -100,6 +113,7 @@ impl SmpCpuManager {
             possible_cpus,
             present_cpus,
             cpuhp_state,
+            cpuhp_notifier: RwLock::new(RawNotifierChain::new()),
             present_cnt: AtomicU32::new(0),
             possible_cnt: AtomicU32::new(0),
         }
@@ -196,6 +210,37 @@ impl SmpCpuManager {
         &self.present_cpus
     }
 
+    /// 注册CPU热插拔事件的通知
+    pub fn register_cpuhp_notifier(
+        &self,
+        block: Arc<dyn NotifierBlock<CpuHpEvent, ProcessorId>>,
+    ) -> Result<(), SystemError> {
+        self.cpuhp_notifier.write().register(block)
+    }
+
+    /// # 函数的功能
+    /// CPU下线流程的第一步：在CPU停止处理中断之前通知订阅者，把投递到它上面的中断迁移走
+    ///
+    /// 下线流程需要在本函数成功之后才能停止该CPU
+    ///
+    /// ## 返回值
+    /// - Err(SystemError::EINVAL): CPU不存在
+    /// - Err(e): 订阅者返回的错误，此时不能让该CPU下线
+    #[allow(dead_code)]
+    pub fn cpu_down_prepare(&self, cpu_id: ProcessorId) -> Result<(), SystemError> {
+        if self.present_cpus().get(cpu_id) != Some(true) {
+            return Err(SystemError::EINVAL);
+        }
+        let (ret, _) =
+            self.cpuhp_notifier
+                .read()
+                .call_chain(CpuHpEvent::DownPrepare, Some(&cpu_id), None);
+        if let Some(e) = SystemError::from_posix_errno(ret) {
+            return Err(e);
+        }
+        return Ok(());
+    }
+
     /// 启动bsp以外的CPU
     pub(super) fn bringup_nonboot_cpus(&self) {
         for cpu_id in self.present_cpus().iter_cpu() {